@@ -0,0 +1,138 @@
+//! Consumer payload handling: the old copy-and-parse-twice path against
+//! `RequestView`, over one second's worth of messages at 1k msg/s.
+//!
+//! Besides the criterion timings, prints the heap allocations each path
+//! makes per message.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use magicrune::engine::compute_run_id;
+use magicrune::schema::RequestView;
+use serde::Deserialize;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+struct Counting;
+
+static ALLOCS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const MESSAGES: usize = 1000;
+
+/// The request struct the consumer used to deserialize into.
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct OwnedRequest {
+    #[serde(default)]
+    cmd: String,
+    #[serde(default)]
+    stdin: String,
+    #[serde(default)]
+    env: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    files: Vec<OwnedFile>,
+    #[serde(default)]
+    allow_net: Vec<String>,
+    #[serde(default)]
+    allow_fs: Vec<String>,
+    #[serde(default)]
+    tenant: String,
+}
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct OwnedFile {
+    path: String,
+    #[serde(default)]
+    content_b64: String,
+}
+
+fn payloads() -> Vec<Vec<u8>> {
+    let blob = "QUJD".repeat(1024);
+    (0..MESSAGES)
+        .map(|i| {
+            serde_json::json!({
+                "cmd": format!("python3 /tmp/job_{i}.py"),
+                "stdin": "",
+                "env": {"LANG": "C.UTF-8", "JOB": i.to_string()},
+                "files": [{"path": format!("/tmp/job_{i}.py"), "content_b64": blob}],
+                "allow_fs": ["/tmp/**"],
+                "seed": i,
+            })
+            .to_string()
+            .into_bytes()
+        })
+        .collect()
+}
+
+fn old_path(payload: &[u8]) -> (String, usize) {
+    let payload = payload.to_vec();
+    let req_val: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+    let seed = req_val.get("seed").and_then(|x| x.as_u64()).unwrap_or(0);
+    let mut all = payload.clone();
+    all.extend_from_slice(&seed.to_le_bytes());
+    let run_id = compute_run_id(&all, None);
+    let req: OwnedRequest = serde_json::from_slice(&payload).unwrap();
+    (run_id, req.files.len())
+}
+
+fn new_path(payload: &[u8]) -> (String, usize) {
+    let req = RequestView::parse(payload).unwrap();
+    let run_id = compute_run_id(payload, Some(req.seed().unwrap_or(0)));
+    (run_id, req.files.len())
+}
+
+fn allocs_per_message(msgs: &[Vec<u8>], f: fn(&[u8]) -> (String, usize)) -> f64 {
+    let before = ALLOCS.load(Ordering::Relaxed);
+    for m in msgs {
+        black_box(f(m));
+    }
+    (ALLOCS.load(Ordering::Relaxed) - before) as f64 / msgs.len() as f64
+}
+
+fn bench_consumer_payload(c: &mut Criterion) {
+    let msgs = payloads();
+    for m in &msgs {
+        assert_eq!(old_path(m), new_path(m));
+    }
+    println!(
+        "allocations per message: old {:.1}, new {:.1}",
+        allocs_per_message(&msgs, old_path),
+        allocs_per_message(&msgs, new_path)
+    );
+
+    let mut group = c.benchmark_group("consumer_payload_1k");
+    group.bench_function("copy_and_parse_twice", |b| {
+        b.iter(|| {
+            for m in &msgs {
+                black_box(old_path(black_box(m)));
+            }
+        });
+    });
+    group.bench_function("request_view", |b| {
+        b.iter(|| {
+            for m in &msgs {
+                black_box(new_path(black_box(m)));
+            }
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_consumer_payload);
+criterion_main!(benches);
@@ -59,11 +59,7 @@ fn bench_grade_with_custom_policy(c: &mut Criterion) {
     let policy = PolicyDoc {
         version: 1,
         grading: Some(GradingCfg {
-            thresholds: GradingThresholds {
-                green: "<=10".to_string(),
-                yellow: "11..=50".to_string(),
-                red: ">=51".to_string(),
-            },
+            thresholds: GradingThresholds::new(10, 50).unwrap(),
         }),
     };
 
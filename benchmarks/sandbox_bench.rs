@@ -17,6 +17,7 @@ fn bench_exec_native(c: &mut Criterion) {
         cpu_ms: 100,
         memory_mb: 16,
         pids: 10,
+        ..Default::default()
     };
 
     c.bench_function("exec_native_echo", |b| {
@@ -39,6 +40,7 @@ fn bench_exec_wasm_placeholder(c: &mut Criterion) {
         cpu_ms: 100,
         memory_mb: 16,
         pids: 10,
+        ..Default::default()
     };
 
     c.bench_function("exec_wasm_placeholder", |b| {
@@ -0,0 +1,30 @@
+//! Strict-mode request validation: compiling the schema for every request
+//! (as `exec --strict` used to) against reusing one compiled validator (as
+//! the engine now does for every run in a process).
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use jsonschema::JSONSchema;
+
+const REQUEST_SCHEMA: &str = include_str!("../schemas/spell_request.schema.json");
+const REQUEST: &str = include_str!("../samples/ok.json");
+
+fn compile() -> JSONSchema {
+    let schema: serde_json::Value = serde_json::from_str(REQUEST_SCHEMA).unwrap();
+    JSONSchema::options().compile(&schema).unwrap()
+}
+
+fn bench_request_validation(c: &mut Criterion) {
+    let req: serde_json::Value = serde_json::from_str(REQUEST).unwrap();
+    let mut group = c.benchmark_group("request_schema");
+    group.bench_function("compile_per_request", |b| {
+        b.iter(|| black_box(compile().is_valid(black_box(&req))));
+    });
+    let compiled = compile();
+    group.bench_function("compiled_once", |b| {
+        b.iter(|| black_box(compiled.is_valid(black_box(&req))));
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_request_validation);
+criterion_main!(benches);
@@ -0,0 +1,19 @@
+"""MagicRune policy engine bindings.
+
+``run`` executes a spell request (a dict shaped like
+``schemas/spell_request.schema.json``) under a policy and returns the result
+dict. The native call releases the GIL, so ``run_async`` simply hands it to
+the event loop's default executor.
+"""
+
+import asyncio
+
+from ._magicrune import MagicruneError, run
+
+__all__ = ["MagicruneError", "run", "run_async"]
+
+
+async def run_async(request, policy):
+    """Awaitable variant of :func:`run`."""
+    loop = asyncio.get_running_loop()
+    return await loop.run_in_executor(None, run, request, policy)
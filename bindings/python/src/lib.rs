@@ -0,0 +1,92 @@
+//! Python bindings for the MagicRune engine (built with maturin, feature `python`).
+//!
+//! The Python package `magicrune` wraps the native `magicrune._magicrune`
+//! module defined here; see `python/magicrune/__init__.py`.
+
+use magicrune::engine::{execute, ExecOptions};
+use magicrune::policy::Policy;
+use magicrune::MagicruneError;
+use std::sync::OnceLock;
+
+fn runtime() -> &'static tokio::runtime::Runtime {
+    static RT: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    RT.get_or_init(|| tokio::runtime::Runtime::new().expect("tokio runtime"))
+}
+
+/// `policy` is a path to a policy file if one exists there, otherwise YAML text.
+pub fn load_policy(policy: &str) -> Result<Policy, MagicruneError> {
+    let policy = if !policy.contains('\n') && std::path::Path::new(policy).is_file() {
+        Policy::load(policy)
+    } else {
+        Policy::from_yaml(policy)
+    };
+    Ok(policy?)
+}
+
+/// Run a JSON request and return the result as JSON.
+pub fn run_json(request_json: &str, policy: &str) -> Result<String, MagicruneError> {
+    let policy = load_policy(policy)?;
+    let out = runtime().block_on(execute(
+        request_json.as_bytes(),
+        &policy,
+        &ExecOptions::default(),
+    ))?;
+    serde_json::to_string(&out.result).map_err(MagicruneError::internal)
+}
+
+#[cfg(feature = "python")]
+mod python {
+    use pyo3::create_exception;
+    use pyo3::exceptions::PyException;
+    use pyo3::prelude::*;
+    use pyo3::types::PyDict;
+
+    create_exception!(
+        _magicrune,
+        MagicruneError,
+        PyException,
+        "Raised when a request is rejected; args are (message, exit_code)."
+    );
+
+    /// run(request: dict, policy: str) -> dict
+    #[pyfunction]
+    fn run<'py>(
+        py: Python<'py>,
+        request: &Bound<'py, PyDict>,
+        policy: &str,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let json = py.import_bound("json")?;
+        let request_json: String = json.call_method1("dumps", (request,))?.extract()?;
+        let policy = policy.to_string();
+        let out = py.allow_threads(move || super::run_json(&request_json, &policy));
+        match out {
+            Ok(result) => json.call_method1("loads", (result,)),
+            Err(e) => Err(MagicruneError::new_err((e.to_string(), e.exit_code()))),
+        }
+    }
+
+    #[pymodule]
+    fn _magicrune(m: &Bound<'_, PyModule>) -> PyResult<()> {
+        m.add_function(wrap_pyfunction!(run, m)?)?;
+        m.add("MagicruneError", m.py().get_type_bound::<MagicruneError>())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_json_inline_policy() {
+        let out = run_json(r#"{"cmd":""}"#, "version: 1\n").unwrap();
+        let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(v["verdict"], "green");
+    }
+
+    #[test]
+    fn test_run_json_policy_error() {
+        let err = run_json(r#"{"cmd":"wget http://x.test/"}"#, "version: 1\n").unwrap_err();
+        assert_eq!(err.exit_code(), 3);
+    }
+}
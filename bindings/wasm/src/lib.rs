@@ -0,0 +1,92 @@
+//! Browser bindings for the MagicRune no_std core (built with wasm-pack).
+//!
+//! Web UIs call `validate` and `grade` to show the schema errors and the
+//! expected verdict of a request before it is published to the queue. Both
+//! run the same code the engine does (`magicrune::schema::request_errors`,
+//! `magicrune::grader::grade`); the engine can still score a run higher from
+//! what the command does at runtime.
+
+use magicrune::grader;
+use magicrune::policy::Thresholds;
+use magicrune::schema::{request_errors, GradingCfg, PolicyDoc, SpellRequest};
+use wasm_bindgen::prelude::*;
+
+/// Schema errors in a JSON request; empty when it is valid.
+pub fn validate_json(request_json: &str) -> Vec<String> {
+    match serde_json::from_str(request_json) {
+        Ok(req) => request_errors(&req),
+        Err(e) => vec![format!("invalid JSON: {}", e)],
+    }
+}
+
+/// Grade a JSON request against a policy's YAML text and return
+/// `{"risk_score": .., "verdict": ..}` as JSON.
+pub fn grade_json(request_json: &str, policy_yaml: &str) -> Result<String, String> {
+    let errors = validate_json(request_json);
+    if !errors.is_empty() {
+        return Err(format!("schema: {}", errors.join("\n")));
+    }
+    let req: SpellRequest =
+        serde_json::from_str(request_json).map_err(|e| format!("schema: {}", e))?;
+    let policy = PolicyDoc {
+        version: 1,
+        grading: Some(GradingCfg {
+            thresholds: Thresholds::from_yaml(policy_yaml).map_err(|e| e.to_string())?,
+        }),
+    };
+    let out = grader::grade(&req, &policy);
+    Ok(serde_json::json!({ "risk_score": out.risk_score, "verdict": out.verdict }).to_string())
+}
+
+/// validate(request_json: string) -> string[]
+#[wasm_bindgen]
+pub fn validate(request_json: &str) -> Vec<String> {
+    validate_json(request_json)
+}
+
+/// grade(request_json: string, policy_yaml: string) -> string (JSON);
+/// throws on an invalid request or policy.
+#[wasm_bindgen]
+pub fn grade(request_json: &str, policy_yaml: &str) -> Result<String, JsError> {
+    grade_json(request_json, policy_yaml).map_err(|e| JsError::new(&e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OK: &str = r#"{"cmd":"echo hi","stdin":"","env":{},"files":[],"policy_id":"default","timeout_sec":5,"allow_net":[],"allow_fs":["/tmp/**"]}"#;
+
+    #[test]
+    fn test_validate_json() {
+        assert!(validate_json(OK).is_empty());
+        let errors = validate_json(r#"{"cmd":1,"timeout_sec":90}"#);
+        assert!(errors.contains(&"missing key: stdin".to_string()));
+        assert!(errors.contains(&"cmd must be string".to_string()));
+        assert!(errors.contains(&"timeout_sec must be 0..=60".to_string()));
+        assert!(validate_json("{")[0].starts_with("invalid JSON"));
+    }
+
+    #[test]
+    fn test_grade_json() {
+        let out = grade_json(OK, "version: 1\n").unwrap();
+        let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(v["verdict"], "green");
+        assert_eq!(v["risk_score"], 0);
+
+        let net = OK.replace(r#""allow_net":[]"#, r#""allow_net":["example.com"]"#);
+        let policy = "version: 1\ngrading:\n  thresholds:\n    green_max: 20\n    yellow_max: 30\n";
+        let v: serde_json::Value =
+            serde_json::from_str(&grade_json(&net, policy).unwrap()).unwrap();
+        assert_eq!(v["verdict"], "red");
+    }
+
+    #[test]
+    fn test_grade_json_errors() {
+        assert!(grade_json(r#"{"cmd":""}"#, "version: 1\n")
+            .unwrap_err()
+            .starts_with("schema: missing key"));
+        let bad = "grading:\n  thresholds:\n    green_max: 50\n    yellow_max: 10\n";
+        assert!(grade_json(OK, bad).unwrap_err().contains("invalid policy"));
+    }
+}
@@ -0,0 +1,50 @@
+/*
+ * magicrune.h - C bindings for the magicrune engine.
+ *
+ * Build: cargo rustc --release --lib --features ffi --crate-type cdylib
+ *
+ * All strings are NUL-terminated UTF-8. Strings returned by the library
+ * belong to the caller and must be released with magicrune_free().
+ */
+#ifndef MAGICRUNE_H
+#define MAGICRUNE_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Library-level codes (negative). */
+#define MAGICRUNE_OK 0
+#define MAGICRUNE_E_NULL_ARG (-1)
+#define MAGICRUNE_E_UTF8 (-2)
+#define MAGICRUNE_E_PANIC (-3)
+
+/* Engine codes (positive) match the CLI exit codes. */
+#define MAGICRUNE_E_INVALID_REQUEST 1
+#define MAGICRUNE_E_INVALID_OUTPUT 2
+#define MAGICRUNE_E_POLICY 3
+#define MAGICRUNE_E_INTERNAL 4
+#define MAGICRUNE_E_READONLY_WRITE 20
+
+/*
+ * Run a spell request (JSON) under a policy document (YAML).
+ * Returns the SpellResult JSON, or NULL on error; see magicrune_last_error_*.
+ */
+char *magicrune_exec(const char *request_json, const char *policy_yaml);
+
+/* Error code of the last call on the calling thread. */
+int32_t magicrune_last_error_code(void);
+
+/* Message of the last error on the calling thread, or NULL. Free with magicrune_free. */
+char *magicrune_last_error_message(void);
+
+/* Release a string returned by this library. NULL is ignored. */
+void magicrune_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MAGICRUNE_H */
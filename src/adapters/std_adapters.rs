@@ -1,28 +1,37 @@
 use crate::ports::{env::EnvError, EnvironmentPort, TimePort};
 use core::time::Duration;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
+/// The system clock. `now_millis` counts on from the wall-clock time of its
+/// first call on a monotonic clock, so deadlines survive clock adjustments;
+/// `sleep` is tokio's.
 pub struct StdTimeAdapter;
 
+fn anchor() -> &'static (Instant, u64) {
+    static ANCHOR: OnceLock<(Instant, u64)> = OnceLock::new();
+    ANCHOR.get_or_init(|| {
+        let wall = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        (Instant::now(), wall)
+    })
+}
+
 #[async_trait::async_trait]
 impl TimePort for StdTimeAdapter {
     fn now_millis(&self) -> u64 {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u64
+        let (at, wall) = anchor();
+        wall + at.elapsed().as_millis() as u64
     }
 
     fn now_secs(&self) -> u64 {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
+        self.now_millis() / 1000
     }
 
     async fn sleep(&self, duration: Duration) {
-        use std::thread;
-        thread::sleep(duration);
+        tokio::time::sleep(duration).await
     }
 }
 
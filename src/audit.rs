@@ -0,0 +1,644 @@
+//! Audit export: policy decisions, verdicts and quarantines as events for a
+//! security team's collector.
+//!
+//! `MAGICRUNE_AUDIT_SINK` picks where they go:
+//!
+//! ```text
+//! file:/var/log/magicrune/audit.jsonl   one JSON object per line
+//! syslog://collector:514                RFC 5424 over UDP
+//! syslog+tcp://collector:601            RFC 5424 over TCP, octet-counted
+//! cef+tcp://collector:6514              CEF over TCP, one event per line
+//! ```
+//!
+//! An event is recorded for every request a policy refuses (`decision`),
+//! every graded result (`verdict`) and every red run set aside under
+//! `quarantine/` (`quarantine`). Events that cannot be delivered are logged
+//! and dropped; the run is not held up by its audit trail. (Seccomp records
+//! read back from the kernel audit log are `crate::sandbox::audit`.)
+
+use crate::messages::Message;
+use crate::schema::{SpellResult, REJECTED_VERDICT};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// Environment variable naming the sink.
+pub const AUDIT_SINK_ENV: &str = "MAGICRUNE_AUDIT_SINK";
+
+/// Syslog facility of every event: `log audit`.
+const FACILITY: u8 = 13;
+/// Structured-data id of the event fields (32473 is the example enterprise
+/// number of RFC 5612).
+const SD_ID: &str = "magicrune@32473";
+const NET_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("audit: {0}")]
+pub struct AuditError(pub String);
+
+impl AuditError {
+    pub fn new(e: impl std::fmt::Display) -> Self {
+        AuditError(e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditKind {
+    /// The policy refused the request before it ran.
+    Decision,
+    /// A result was graded.
+    Verdict,
+    /// A red run's result and output were kept under `quarantine/`.
+    Quarantine,
+}
+
+impl AuditKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditKind::Decision => "decision",
+            AuditKind::Verdict => "verdict",
+            AuditKind::Quarantine => "quarantine",
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            AuditKind::Decision => "Policy decision",
+            AuditKind::Verdict => "Verdict",
+            AuditKind::Quarantine => "Quarantine",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditEvent {
+    /// Unix time in milliseconds.
+    pub time_ms: u64,
+    pub kind: AuditKind,
+    pub run_id: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub tenant: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub instance_id: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub policy_digest: String,
+    /// `denied`, the verdict, or `quarantined`.
+    pub outcome: String,
+    pub risk_score: u32,
+    /// The catalog code of a denial (see `crate::messages`).
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub code: String,
+    /// Why the request was denied, or where it was quarantined.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub detail: String,
+}
+
+impl AuditEvent {
+    /// The grading of `res` under the policy with `policy_digest`.
+    pub fn verdict(res: &SpellResult, policy_digest: &str) -> Self {
+        Self {
+            tenant: res.tenant.clone(),
+            instance_id: res.instance_id.clone(),
+            ..Self::graded(&res.run_id, policy_digest, &res.verdict, res.risk_score)
+        }
+    }
+
+    /// A verdict a consumer reached; the `rejected` answer to an
+    /// unauthenticated request is a denial.
+    pub fn graded(run_id: &str, policy_digest: &str, verdict: &str, risk_score: u32) -> Self {
+        let rejected = verdict == REJECTED_VERDICT;
+        Self {
+            time_ms: now_ms(),
+            kind: if rejected {
+                AuditKind::Decision
+            } else {
+                AuditKind::Verdict
+            },
+            run_id: run_id.to_string(),
+            tenant: String::new(),
+            instance_id: String::new(),
+            policy_digest: policy_digest.to_string(),
+            outcome: if rejected { "denied" } else { verdict }.to_string(),
+            risk_score,
+            code: String::new(),
+            detail: String::new(),
+        }
+    }
+
+    /// A request the policy refused, with the reason.
+    pub fn denied(run_id: &str, tenant: &str, policy_digest: &str, reason: &Message) -> Self {
+        Self {
+            time_ms: now_ms(),
+            kind: AuditKind::Decision,
+            run_id: run_id.to_string(),
+            tenant: tenant.to_string(),
+            instance_id: String::new(),
+            policy_digest: policy_digest.to_string(),
+            outcome: "denied".to_string(),
+            risk_score: 0,
+            code: reason.code.as_str().to_string(),
+            detail: reason.text.clone(),
+        }
+    }
+
+    /// `res` kept in `dir`.
+    pub fn quarantine(res: &SpellResult, policy_digest: &str, dir: &Path) -> Self {
+        Self {
+            kind: AuditKind::Quarantine,
+            outcome: "quarantined".to_string(),
+            detail: dir.display().to_string(),
+            ..Self::verdict(res, policy_digest)
+        }
+    }
+
+    /// Syslog severity: informational for green, up to error for a
+    /// quarantine.
+    fn severity(&self) -> u8 {
+        match (self.kind, self.outcome.as_str()) {
+            (AuditKind::Quarantine, _) => 3,
+            (AuditKind::Decision, _) | (_, "red") => 4,
+            (_, "green") => 6,
+            _ => 5,
+        }
+    }
+
+    /// CEF severity, 0-10.
+    fn cef_severity(&self) -> u8 {
+        match self.severity() {
+            3 => 9,
+            4 => 7,
+            5 => 4,
+            _ => 1,
+        }
+    }
+
+    /// The event as an RFC 5424 message (without transport framing).
+    pub fn to_syslog(&self, hostname: &str) -> String {
+        let mut sd = format!("[{}", SD_ID);
+        for (k, v) in [
+            ("run_id", self.run_id.as_str()),
+            ("tenant", &self.tenant),
+            ("instance_id", &self.instance_id),
+            ("policy_digest", &self.policy_digest),
+            ("outcome", &self.outcome),
+            ("risk_score", &self.risk_score.to_string()),
+            ("code", &self.code),
+        ] {
+            if !v.is_empty() {
+                sd.push_str(&format!(" {}=\"{}\"", k, sd_escape(v)));
+            }
+        }
+        sd.push(']');
+        let mut msg = format!(
+            "<{}>1 {} {} magicrune {} {} {} {} {}",
+            FACILITY * 8 + self.severity(),
+            rfc3339(self.time_ms),
+            if hostname.is_empty() { "-" } else { hostname },
+            std::process::id(),
+            self.kind.as_str(),
+            sd,
+            self.kind.title(),
+            self.outcome
+        );
+        if !self.detail.is_empty() {
+            msg.push_str(": ");
+            msg.push_str(&self.detail);
+        }
+        msg
+    }
+
+    /// The event as a CEF line (without the newline).
+    pub fn to_cef(&self) -> String {
+        let mut ext = vec![
+            format!("rt={}", self.time_ms),
+            format!("externalId={}", cef_value(&self.run_id)),
+            format!("outcome={}", cef_value(&self.outcome)),
+            "cn1Label=riskScore".to_string(),
+            format!("cn1={}", self.risk_score),
+        ];
+        for (label, n, v) in [
+            ("tenant", 1, &self.tenant),
+            ("policyDigest", 2, &self.policy_digest),
+            ("errorCode", 3, &self.code),
+        ] {
+            if !v.is_empty() {
+                ext.push(format!("cs{}Label={}", n, label));
+                ext.push(format!("cs{}={}", n, cef_value(v)));
+            }
+        }
+        if !self.instance_id.is_empty() {
+            ext.push(format!("dvchost={}", cef_value(&self.instance_id)));
+        }
+        if !self.detail.is_empty() {
+            ext.push(format!("msg={}", cef_value(&self.detail)));
+        }
+        format!(
+            "CEF:0|MagicRune|magicrune|{}|{}|{}|{}|{}",
+            cef_header(env!("CARGO_PKG_VERSION")),
+            self.kind.as_str(),
+            self.kind.title(),
+            self.cef_severity(),
+            ext.join(" ")
+        )
+    }
+}
+
+pub trait AuditSink: Send + Sync + std::fmt::Debug {
+    fn emit(&self, event: &AuditEvent) -> Result<(), AuditError>;
+}
+
+/// JSON lines appended to a local file.
+#[derive(Debug)]
+pub struct FileSink {
+    path: PathBuf,
+    file: Mutex<Option<File>>,
+}
+
+impl FileSink {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            file: Mutex::new(None),
+        }
+    }
+}
+
+impl AuditSink for FileSink {
+    fn emit(&self, event: &AuditEvent) -> Result<(), AuditError> {
+        let mut line = serde_json::to_vec(event).map_err(AuditError::new)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if file.is_none() {
+            if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                std::fs::create_dir_all(dir).map_err(AuditError::new)?;
+            }
+            *file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+                    .map_err(|e| AuditError(format!("{}: {}", self.path.display(), e)))?,
+            );
+        }
+        file.as_mut()
+            .map(|f| f.write_all(&line))
+            .transpose()
+            .map_err(AuditError::new)?;
+        Ok(())
+    }
+}
+
+/// RFC 5424 messages to a syslog collector.
+#[derive(Debug)]
+pub struct SyslogSink {
+    addr: String,
+    hostname: String,
+    /// `None` for UDP.
+    tcp: Option<TcpLine>,
+}
+
+impl SyslogSink {
+    pub fn udp(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            hostname: hostname(),
+            tcp: None,
+        }
+    }
+
+    pub fn tcp(addr: impl Into<String>) -> Self {
+        let addr = addr.into();
+        Self {
+            tcp: Some(TcpLine::new(addr.clone())),
+            addr,
+            hostname: hostname(),
+        }
+    }
+}
+
+impl AuditSink for SyslogSink {
+    fn emit(&self, event: &AuditEvent) -> Result<(), AuditError> {
+        let msg = event.to_syslog(&self.hostname);
+        match &self.tcp {
+            // Octet counting (RFC 6587): the length, a space, the message.
+            Some(tcp) => tcp.send(format!("{} {}", msg.len(), msg).as_bytes()),
+            None => {
+                let socket = UdpSocket::bind("0.0.0.0:0").map_err(AuditError::new)?;
+                socket
+                    .send_to(msg.as_bytes(), resolve(&self.addr)?)
+                    .map_err(|e| AuditError(format!("{}: {}", self.addr, e)))?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// CEF lines over TCP.
+#[derive(Debug)]
+pub struct CefSink {
+    tcp: TcpLine,
+}
+
+impl CefSink {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            tcp: TcpLine::new(addr.into()),
+        }
+    }
+}
+
+impl AuditSink for CefSink {
+    fn emit(&self, event: &AuditEvent) -> Result<(), AuditError> {
+        self.tcp.send(format!("{}\n", event.to_cef()).as_bytes())
+    }
+}
+
+/// A TCP connection opened on first use and reopened once when a write
+/// fails.
+#[derive(Debug)]
+struct TcpLine {
+    addr: String,
+    stream: Mutex<Option<TcpStream>>,
+}
+
+impl TcpLine {
+    fn new(addr: String) -> Self {
+        Self {
+            addr,
+            stream: Mutex::new(None),
+        }
+    }
+
+    fn send(&self, bytes: &[u8]) -> Result<(), AuditError> {
+        let mut stream = self.stream.lock().unwrap_or_else(|e| e.into_inner());
+        let mut last = None;
+        for _ in 0..2 {
+            if stream.is_none() {
+                let s = TcpStream::connect_timeout(&resolve(&self.addr)?, NET_TIMEOUT)
+                    .map_err(|e| AuditError(format!("{}: {}", self.addr, e)))?;
+                let _ = s.set_write_timeout(Some(NET_TIMEOUT));
+                *stream = Some(s);
+            }
+            match stream.as_mut().map(|s| s.write_all(bytes)) {
+                Some(Ok(())) => return Ok(()),
+                Some(Err(e)) => last = Some(e),
+                None => {}
+            }
+            *stream = None;
+        }
+        Err(AuditError(format!(
+            "{}: {}",
+            self.addr,
+            last.map(|e| e.to_string()).unwrap_or_default()
+        )))
+    }
+}
+
+/// The sink `spec` names (see the module docs).
+pub fn connect(spec: &str) -> Result<Arc<dyn AuditSink>, AuditError> {
+    let (scheme, rest) = spec
+        .split_once(':')
+        .ok_or_else(|| AuditError(format!("{:?} is not file:, syslog:// or cef+tcp://", spec)))?;
+    let host = || {
+        rest.strip_prefix("//")
+            .filter(|h| !h.is_empty())
+            .ok_or_else(|| AuditError(format!("{:?} has no host:port", spec)))
+    };
+    Ok(match scheme {
+        "file" if !rest.is_empty() => Arc::new(FileSink::new(rest)),
+        "syslog" => Arc::new(SyslogSink::udp(with_port(host()?, 514))),
+        "syslog+tcp" => Arc::new(SyslogSink::tcp(with_port(host()?, 601))),
+        "cef+tcp" => Arc::new(CefSink::new(host()?)),
+        _ => {
+            return Err(AuditError(format!(
+                "{:?} is not file:, syslog://, syslog+tcp:// or cef+tcp://",
+                spec
+            )))
+        }
+    })
+}
+
+/// The sink at [`AUDIT_SINK_ENV`]; `None` when unset.
+pub fn from_env() -> Result<Option<Arc<dyn AuditSink>>, AuditError> {
+    match crate::ports::env::var(AUDIT_SINK_ENV) {
+        Ok(spec) if !spec.is_empty() => connect(&spec).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// The process-wide sink, read from the environment once; a bad setting is
+/// logged and disables the export.
+pub fn global() -> Option<&'static Arc<dyn AuditSink>> {
+    static SINK: OnceLock<Option<Arc<dyn AuditSink>>> = OnceLock::new();
+    SINK.get_or_init(|| {
+        from_env().unwrap_or_else(|e| {
+            tracing::warn!(error = %e, "audit export disabled");
+            None
+        })
+    })
+    .as_ref()
+}
+
+/// Send `event` to `sink`, logging a failure.
+pub fn record(sink: &dyn AuditSink, event: &AuditEvent) {
+    if let Err(e) = sink.emit(event) {
+        tracing::warn!(run_id = %event.run_id, kind = event.kind.as_str(), error = %e, "audit event dropped");
+    }
+}
+
+fn with_port(host: &str, port: u16) -> String {
+    if host
+        .rsplit_once(':')
+        .is_some_and(|(_, p)| p.parse::<u16>().is_ok())
+    {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+fn resolve(addr: &str) -> Result<std::net::SocketAddr, AuditError> {
+    addr.to_socket_addrs()
+        .map_err(|e| AuditError(format!("{}: {}", addr, e)))?
+        .next()
+        .ok_or_else(|| AuditError(format!("{}: no address", addr)))
+}
+
+fn hostname() -> String {
+    crate::ports::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty() && !h.contains(' '))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// RFC 5424 PARAM-VALUE escaping.
+fn sd_escape(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(']', "\\]")
+}
+
+fn cef_header(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+fn cef_value(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('=', "\\=")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+}
+
+/// `2024-01-01T00:00:00.000Z` for Unix milliseconds.
+pub(crate) fn rfc3339(ms: u64) -> String {
+    let days = (ms / 86_400_000) as i64;
+    let rem = ms % 86_400_000;
+    // Civil date from days since the epoch (H. Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3_600_000,
+        rem / 60_000 % 60,
+        rem / 1000 % 60,
+        rem % 1000
+    )
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{Code, Lang};
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+
+    fn event() -> AuditEvent {
+        AuditEvent {
+            time_ms: 1_704_067_200_123,
+            ..AuditEvent::denied(
+                "r_1",
+                "acme",
+                "sha256:ab",
+                &Message::in_lang(Code::EnvDenied, Lang::En, &[&"AWS_SECRET=1"]),
+            )
+        }
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(rfc3339(1_704_067_200_123), "2024-01-01T00:00:00.123Z");
+        assert_eq!(rfc3339(951_825_600_000), "2000-02-29T12:00:00.000Z");
+        let line = event().to_syslog("node-1");
+        assert!(line.starts_with("<108>1 2024-01-01T00:00:00.123Z node-1 magicrune "));
+        assert!(line.contains(
+            " decision [magicrune@32473 run_id=\"r_1\" tenant=\"acme\" policy_digest=\"sha256:ab\" outcome=\"denied\" risk_score=\"0\" code=\"MR2001\"] Policy decision denied: policy: env deny AWS_SECRET=1"
+        ));
+        assert_eq!(sd_escape("a\"]\\"), "a\\\"\\]\\\\");
+
+        let cef = event().to_cef();
+        assert!(cef.starts_with("CEF:0|MagicRune|magicrune|"));
+        assert!(cef.contains("|decision|Policy decision|7|rt=1704067200123 externalId=r_1 outcome=denied cn1Label=riskScore cn1=0 cs1Label=tenant cs1=acme"));
+        assert!(cef.contains(" cs3Label=errorCode cs3=MR2001 "));
+        assert!(cef.ends_with("msg=policy: env deny AWS_SECRET\\=1"));
+
+        let res = SpellResult {
+            run_id: "r_2".into(),
+            verdict: "red".into(),
+            risk_score: 80,
+            ..Default::default()
+        };
+        let q = AuditEvent::quarantine(&res, "d", Path::new("quarantine/default"));
+        assert_eq!(q.outcome, "quarantined");
+        assert_eq!(q.severity(), 3);
+        assert_eq!(AuditEvent::verdict(&res, "d").cef_severity(), 7);
+        let rejected = AuditEvent::graded("r_3", "d", REJECTED_VERDICT, 0);
+        assert_eq!(
+            (rejected.kind, rejected.outcome.as_str()),
+            (AuditKind::Decision, "denied")
+        );
+    }
+
+    #[test]
+    fn test_connect() {
+        assert!(connect("file:/tmp/a.jsonl").is_ok());
+        assert!(connect("syslog://collector").is_ok());
+        assert_eq!(with_port("collector", 514), "collector:514");
+        assert_eq!(with_port("[::1]:5514", 514), "[::1]:5514");
+        assert!(connect("cef+tcp://").is_err());
+        assert_eq!(
+            connect("kafka://x").unwrap_err().0,
+            "\"kafka://x\" is not file:, syslog://, syslog+tcp:// or cef+tcp://"
+        );
+    }
+
+    #[test]
+    fn test_file_sink() {
+        let path = std::env::temp_dir().join(format!("mr_audit_{}/a.jsonl", std::process::id()));
+        let sink = connect(&format!("file:{}", path.display())).unwrap();
+        sink.emit(&event()).unwrap();
+        sink.emit(&event()).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["kind"], "decision");
+        assert_eq!(lines[0]["outcome"], "denied");
+        assert_eq!(lines[0]["code"], "MR2001");
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn test_network_sinks() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cef = connect(&format!("cef+tcp://{}", addr)).unwrap();
+        cef.emit(&event()).unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        let mut line = String::new();
+        BufReader::new(conn).read_line(&mut line).unwrap();
+        assert!(line.starts_with("CEF:0|") && line.ends_with('\n'));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp = connect(&format!("syslog+tcp://{}", listener.local_addr().unwrap())).unwrap();
+        tcp.emit(&event()).unwrap();
+        let (mut conn, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 512];
+        let n = conn.read(&mut buf).unwrap();
+        let framed = String::from_utf8_lossy(&buf[..n]);
+        let (len, msg) = framed.split_once(' ').unwrap();
+        assert_eq!(len.parse::<usize>().unwrap(), msg.len());
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let udp = connect(&format!("syslog://{}", socket.local_addr().unwrap())).unwrap();
+        udp.emit(&event()).unwrap();
+        let n = socket.recv(&mut buf).unwrap();
+        assert!(buf[..n].starts_with(b"<108>1 "));
+    }
+}
@@ -0,0 +1,444 @@
+//! Kubernetes Job backend.
+//!
+//! Each run becomes three objects sharing the label `magicrune/run=<name>`:
+//! a ConfigMap holding the command, stdin and staged files; a NetworkPolicy
+//! restricting egress to the allowlist; and a Job (no retries, deadline from
+//! `wall_sec`, memory limit from `memory_mb`). They are created and inspected
+//! through `kubectl`, so the controller only needs a kubeconfig.
+//!
+//! The pod log is captured as stdout; Kubernetes does not keep stderr apart.
+
+use super::{wrapper_script, RemoteTask};
+use crate::sandbox::{oom, SandboxOutcome, TerminationReason};
+use crate::schema::Finding;
+use base64::Engine as _;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+const MOUNT: &str = "/magicrune";
+const RUN_LABEL: &str = "magicrune/run";
+const TENANT_LABEL: &str = "magicrune/tenant";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct K8sConfig {
+    pub namespace: String,
+    /// Container image; must provide bash.
+    pub image: String,
+    /// kubectl binary to invoke.
+    pub kubectl: String,
+    /// kubeconfig context, if not the current one.
+    pub context: Option<String>,
+    /// Extra time on top of `wall_sec` for scheduling and image pulls.
+    pub startup_grace_sec: u64,
+}
+
+impl Default for K8sConfig {
+    fn default() -> Self {
+        Self {
+            namespace: "default".into(),
+            image: "bash:5.2".into(),
+            kubectl: "kubectl".into(),
+            context: None,
+            startup_grace_sec: 60,
+        }
+    }
+}
+
+impl K8sConfig {
+    /// `MAGICRUNE_K8S_NAMESPACE`, `MAGICRUNE_K8S_IMAGE`, `MAGICRUNE_KUBECTL`,
+    /// `MAGICRUNE_K8S_CONTEXT`, `MAGICRUNE_K8S_GRACE_SEC`.
+    pub fn from_env() -> Self {
+        let d = Self::default();
+        let var = |k: &str| crate::ports::env::var(k).ok().filter(|v| !v.is_empty());
+        Self {
+            namespace: var("MAGICRUNE_K8S_NAMESPACE").unwrap_or(d.namespace),
+            image: var("MAGICRUNE_K8S_IMAGE").unwrap_or(d.image),
+            kubectl: var("MAGICRUNE_KUBECTL").unwrap_or(d.kubectl),
+            context: var("MAGICRUNE_K8S_CONTEXT"),
+            startup_grace_sec: var("MAGICRUNE_K8S_GRACE_SEC")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(d.startup_grace_sec),
+        }
+    }
+}
+
+/// Object name for a run: DNS-1123 safe and unique per run id.
+pub fn object_name(run_id: &str) -> String {
+    let hex: String = run_id
+        .trim_start_matches("r_")
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .take(16)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    format!("mr-{}", hex)
+}
+
+/// The ConfigMap, NetworkPolicy and Job for `task`, as a `v1/List`.
+pub fn manifests(cfg: &K8sConfig, task: &RemoteTask<'_>) -> Value {
+    let name = object_name(task.run_id);
+    let labels = json!({
+        "app.kubernetes.io/managed-by": "magicrune",
+        RUN_LABEL: name,
+        TENANT_LABEL: task.tenant,
+    });
+    let b64 = base64::engine::general_purpose::STANDARD;
+
+    let mut binary = serde_json::Map::new();
+    binary.insert("stdin".into(), Value::String(b64.encode(task.stdin)));
+    for (i, f) in task.files.iter().enumerate() {
+        binary.insert(format!("f{}", i), Value::String(b64.encode(&f.bytes)));
+    }
+    let config_map = json!({
+        "apiVersion": "v1",
+        "kind": "ConfigMap",
+        "metadata": { "name": name, "namespace": cfg.namespace, "labels": labels },
+        "data": { "run.sh": wrapper_script(task, MOUNT, &format!("{}/stdin", MOUNT)) },
+        "binaryData": binary,
+    });
+
+    let network_policy = json!({
+        "apiVersion": "networking.k8s.io/v1",
+        "kind": "NetworkPolicy",
+        "metadata": { "name": name, "namespace": cfg.namespace, "labels": labels },
+        "spec": {
+            "podSelector": { "matchLabels": { RUN_LABEL: name } },
+            "policyTypes": ["Ingress", "Egress"],
+            "ingress": [],
+            "egress": egress_rules(task.allow_net),
+        },
+    });
+
+    let job = json!({
+        "apiVersion": "batch/v1",
+        "kind": "Job",
+        "metadata": { "name": name, "namespace": cfg.namespace, "labels": labels },
+        "spec": {
+            "backoffLimit": 0,
+            "activeDeadlineSeconds": task.spec.wall_sec.max(1),
+            "ttlSecondsAfterFinished": 600,
+            "template": {
+                "metadata": { "labels": labels },
+                "spec": {
+                    "restartPolicy": "Never",
+                    "automountServiceAccountToken": false,
+                    "enableServiceLinks": false,
+                    "containers": [{
+                        "name": "spell",
+                        "image": cfg.image,
+                        "command": ["bash", format!("{}/run.sh", MOUNT)],
+                        "resources": {
+                            "limits": { "memory": format!("{}Mi", task.spec.memory_mb.max(1)) },
+                        },
+                        "securityContext": {
+                            "allowPrivilegeEscalation": false,
+                            "capabilities": { "drop": ["ALL"] },
+                        },
+                        "volumeMounts": [{ "name": "spell", "mountPath": MOUNT, "readOnly": true }],
+                    }],
+                    "volumes": [{ "name": "spell", "configMap": { "name": name } }],
+                },
+            },
+        },
+    });
+
+    json!({ "apiVersion": "v1", "kind": "List", "items": [config_map, network_policy, job] })
+}
+
+/// Egress rules for an allowlist. IPs and CIDRs map to `ipBlock`s directly;
+/// host names are resolved on the controller (NetworkPolicy cannot match
+/// names) and DNS is opened so the pod can resolve them too. An empty
+/// allowlist yields no rules, i.e. no egress at all.
+pub fn egress_rules(allow: &[String]) -> Vec<Value> {
+    use std::net::{IpAddr, ToSocketAddrs};
+    let mut rules = Vec::new();
+    let mut needs_dns = false;
+    for entry in allow {
+        let (host, port) = crate::policy::hostport_parts(entry);
+        let port = port.and_then(|p| p.parse::<u16>().ok());
+        let ports = match port {
+            Some(p) => json!([{ "protocol": "TCP", "port": p }]),
+            None => json!([]),
+        };
+        let cidrs: Vec<String> = if host.contains('/') {
+            vec![host.to_string()]
+        } else if let Ok(ip) = host.parse::<IpAddr>() {
+            vec![host_cidr(ip)]
+        } else {
+            needs_dns = true;
+            let host = host.trim_start_matches("*.");
+            (host, port.unwrap_or(443))
+                .to_socket_addrs()
+                .map(|addrs| addrs.map(|a| host_cidr(a.ip())).collect())
+                .unwrap_or_default()
+        };
+        for cidr in cidrs {
+            let mut rule = json!({ "to": [{ "ipBlock": { "cidr": cidr } }] });
+            if port.is_some() {
+                rule["ports"] = ports.clone();
+            }
+            rules.push(rule);
+        }
+    }
+    if needs_dns {
+        rules.push(json!({
+            "ports": [{ "protocol": "UDP", "port": 53 }, { "protocol": "TCP", "port": 53 }]
+        }));
+    }
+    rules
+}
+
+fn host_cidr(ip: std::net::IpAddr) -> String {
+    match ip {
+        std::net::IpAddr::V4(v4) => format!("{}/32", v4),
+        std::net::IpAddr::V6(v6) => format!("{}/128", v6),
+    }
+}
+
+async fn kubectl(cfg: &K8sConfig, args: &[&str], stdin: Option<&[u8]>) -> Result<Vec<u8>, String> {
+    use tokio::io::AsyncWriteExt;
+    let mut cmd = tokio::process::Command::new(&cfg.kubectl);
+    if let Some(ctx) = &cfg.context {
+        cmd.arg("--context").arg(ctx);
+    }
+    cmd.arg("-n").arg(&cfg.namespace).args(args);
+    cmd.stdin(if stdin.is_some() {
+        std::process::Stdio::piped()
+    } else {
+        std::process::Stdio::null()
+    });
+    cmd.stdout(std::process::Stdio::piped());
+    cmd.stderr(std::process::Stdio::piped());
+    let mut child = cmd.spawn().map_err(|e| format!("{}: {}", cfg.kubectl, e))?;
+    if let (Some(bytes), Some(mut sin)) = (stdin, child.stdin.take()) {
+        sin.write_all(bytes).await.map_err(|e| e.to_string())?;
+    }
+    let out = child.wait_with_output().await.map_err(|e| e.to_string())?;
+    if out.status.success() {
+        Ok(out.stdout)
+    } else {
+        Err(format!(
+            "kubectl {}: {}",
+            args.first().unwrap_or(&""),
+            String::from_utf8_lossy(&out.stderr).trim()
+        ))
+    }
+}
+
+enum JobState {
+    Running,
+    Succeeded,
+    Failed,
+    DeadlineExceeded,
+}
+
+fn job_state(job: &Value) -> JobState {
+    let status = &job["status"];
+    if let Some(conds) = status["conditions"].as_array() {
+        for c in conds {
+            if c["type"] == "Failed" && c["status"] == "True" {
+                return if c["reason"] == "DeadlineExceeded" {
+                    JobState::DeadlineExceeded
+                } else {
+                    JobState::Failed
+                };
+            }
+            if c["type"] == "Complete" && c["status"] == "True" {
+                return JobState::Succeeded;
+            }
+        }
+    }
+    if status["succeeded"].as_u64().unwrap_or(0) > 0 {
+        JobState::Succeeded
+    } else if status["failed"].as_u64().unwrap_or(0) > 0 {
+        JobState::Failed
+    } else {
+        JobState::Running
+    }
+}
+
+/// Exit code of the spell container from `kubectl get pods -o json` output,
+/// and whether the kubelet reports it `OOMKilled`.
+fn container_exit(pods: &Value) -> Option<(i32, bool)> {
+    pods["items"].as_array()?.iter().find_map(|p| {
+        p["status"]["containerStatuses"]
+            .as_array()?
+            .iter()
+            .find_map(|c| {
+                let terminated = &c["state"]["terminated"];
+                let code = terminated["exitCode"].as_i64()?;
+                Some((code as i32, terminated["reason"] == "OOMKilled"))
+            })
+    })
+}
+
+/// Run `task` as a Job and wait for it to finish.
+pub async fn run_job(cfg: &K8sConfig, task: &RemoteTask<'_>) -> SandboxOutcome {
+    let name = object_name(task.run_id);
+    let selector = format!("{}={}", RUN_LABEL, name);
+    let manifest = manifests(cfg, task).to_string();
+    if let Err(e) = kubectl(cfg, &["apply", "-f", "-"], Some(manifest.as_bytes())).await {
+        return SandboxOutcome::spawn_error(e);
+    }
+
+    let clock = &task.spec.clock;
+    let started = clock.now_millis();
+    let wall = Duration::from_secs(task.spec.wall_sec + cfg.startup_grace_sec);
+    let job_ref = format!("job/{}", name);
+    let state = loop {
+        let state = match kubectl(cfg, &["get", &job_ref, "-o", "json"], None).await {
+            Ok(out) => serde_json::from_slice(&out)
+                .map(|v: Value| job_state(&v))
+                .unwrap_or(JobState::Running),
+            Err(_) => JobState::Running,
+        };
+        if !matches!(state, JobState::Running) || clock.since(started) >= wall {
+            break state;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    };
+
+    let stdout = kubectl(cfg, &["logs", &job_ref, "--all-containers"], None)
+        .await
+        .unwrap_or_default();
+    let exit = kubectl(cfg, &["get", "pods", "-l", &selector, "-o", "json"], None)
+        .await
+        .ok()
+        .and_then(|out| serde_json::from_slice::<Value>(&out).ok())
+        .and_then(|v| container_exit(&v));
+    let oom_killed = exit.is_some_and(|(_, oom)| oom);
+    let exit = exit.map(|(code, _)| code);
+    let _ = kubectl(
+        cfg,
+        &[
+            "delete",
+            "job,configmap,networkpolicy",
+            "-l",
+            &selector,
+            "--ignore-not-found",
+            "--wait=false",
+            "--cascade=background",
+        ],
+        None,
+    )
+    .await;
+
+    let (exit_code, reason, stderr) = match state {
+        JobState::Failed if oom_killed => (
+            exit.unwrap_or(128 + 9),
+            TerminationReason::MemoryLimit,
+            Vec::new(),
+        ),
+        JobState::Succeeded => (exit.unwrap_or(0), TerminationReason::Completed, Vec::new()),
+        JobState::Failed => (exit.unwrap_or(1), TerminationReason::Completed, Vec::new()),
+        JobState::DeadlineExceeded | JobState::Running => (
+            exit.unwrap_or(128 + 9),
+            TerminationReason::WallTimeout,
+            b"timeout".to_vec(),
+        ),
+    };
+    SandboxOutcome {
+        exit_code,
+        stdout,
+        stderr,
+        findings: (reason == TerminationReason::MemoryLimit)
+            .then(|| Finding {
+                kind: oom::MEMORY_LIMIT.to_string(),
+                detail: "container OOMKilled".into(),
+                count: 1,
+            })
+            .into_iter()
+            .collect(),
+        reason,
+        syscalls: None,
+        net_log: Vec::new(),
+        manifest: Vec::new(),
+        pids_peak: None,
+        orphans_reaped: None,
+        usage: None,
+        core_dumps: Vec::new(),
+        output_timeline: None,
+        env: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::StagedFile;
+    use crate::sandbox::SandboxSpec;
+
+    fn spec() -> SandboxSpec {
+        SandboxSpec {
+            wall_sec: 15,
+            cpu_ms: 5000,
+            memory_mb: 256,
+            pids: 64,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_object_name_is_dns_safe() {
+        assert_eq!(object_name("r_ABCdef0123456789ffff"), "mr-abcdef0123456789");
+    }
+
+    #[test]
+    fn test_manifests_carry_limits_and_files() {
+        let spec = spec();
+        let files = vec![StagedFile {
+            path: "/tmp/a.txt".into(),
+            bytes: b"hello".to_vec(),
+        }];
+        let task = RemoteTask {
+            run_id: "r_0011",
+            tenant: "acme",
+            cmd: "cat /tmp/a.txt",
+            stdin: b"in",
+            files: &files,
+            allow_net: &[],
+            spec: &spec,
+        };
+        let list = manifests(&K8sConfig::default(), &task);
+        let items = list["items"].as_array().unwrap();
+        assert_eq!(items.len(), 3);
+        let (cm, np, job) = (&items[0], &items[1], &items[2]);
+        assert_eq!(cm["binaryData"]["f0"], "aGVsbG8=");
+        assert_eq!(cm["binaryData"]["stdin"], "aW4=");
+        assert_eq!(np["spec"]["egress"], json!([]));
+        assert_eq!(job["spec"]["activeDeadlineSeconds"], 15);
+        assert_eq!(job["spec"]["backoffLimit"], 0);
+        assert_eq!(job["metadata"]["labels"][TENANT_LABEL], "acme");
+        let c = &job["spec"]["template"]["spec"]["containers"][0];
+        assert_eq!(c["resources"]["limits"]["memory"], "256Mi");
+    }
+
+    #[test]
+    fn test_egress_rules_for_ips() {
+        let rules = egress_rules(&["10.0.0.0/8".into(), "192.168.1.5:8443".into()]);
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0]["to"][0]["ipBlock"]["cidr"], "10.0.0.0/8");
+        assert_eq!(rules[1]["to"][0]["ipBlock"]["cidr"], "192.168.1.5/32");
+        assert_eq!(rules[1]["ports"][0]["port"], 8443);
+    }
+
+    #[test]
+    fn test_job_state_and_exit_code() {
+        let failed = json!({"status": {"conditions": [
+            {"type": "Failed", "status": "True", "reason": "DeadlineExceeded"}
+        ]}});
+        assert!(matches!(job_state(&failed), JobState::DeadlineExceeded));
+        assert!(matches!(
+            job_state(&json!({"status": {"succeeded": 1}})),
+            JobState::Succeeded
+        ));
+        let pods = json!({"items": [{"status": {"containerStatuses": [
+            {"state": {"terminated": {"exitCode": 3}}}
+        ]}}]});
+        assert_eq!(container_exit(&pods), Some((3, false)));
+        let pods = json!({"items": [{"status": {"containerStatuses": [
+            {"state": {"terminated": {"exitCode": 137, "reason": "OOMKilled"}}}
+        ]}}]});
+        assert_eq!(container_exit(&pods), Some((137, true)));
+    }
+}
@@ -0,0 +1,152 @@
+//! Where a validated request actually runs.
+//!
+//! `Local` is the in-process sandbox (`crate::sandbox`). Remote backends get
+//! the already-checked request as a [`RemoteTask`]; policy enforcement and
+//! grading stay on the controller.
+
+pub mod k8s;
+pub mod ssh;
+
+use crate::sandbox::shell::Shell;
+use crate::sandbox::SandboxSpec;
+
+/// Execution backend selected for a run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Run in the local sandbox.
+    #[default]
+    Local,
+    /// Run as a Kubernetes Job via `kubectl`.
+    Kubernetes(k8s::K8sConfig),
+    /// Run on a remote host over SSH.
+    Ssh(ssh::SshConfig),
+}
+
+impl Backend {
+    /// Backend named by `MAGICRUNE_RUNTIME` (`local` | `k8s` | `ssh`); unknown values
+    /// fall back to local.
+    pub fn from_env() -> Self {
+        match crate::ports::env::var("MAGICRUNE_RUNTIME").ok().as_deref() {
+            Some("k8s") | Some("kubernetes") => Backend::Kubernetes(k8s::K8sConfig::from_env()),
+            Some("ssh") => Backend::Ssh(ssh::SshConfig::from_env()),
+            _ => Backend::Local,
+        }
+    }
+}
+
+/// A file to place in the remote sandbox before the command runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StagedFile {
+    pub path: String,
+    pub bytes: Vec<u8>,
+}
+
+/// Everything a remote backend needs to run one request.
+pub struct RemoteTask<'a> {
+    pub run_id: &'a str,
+    /// Tenant the run belongs to; names remote scratch space and objects.
+    pub tenant: &'a str,
+    pub cmd: &'a str,
+    pub stdin: &'a [u8],
+    pub files: &'a [StagedFile],
+    /// Union of request `allow_net` and policy `capabilities.net.allow`.
+    pub allow_net: &'a [String],
+    pub spec: &'a SandboxSpec,
+}
+
+/// Single-quote `s` for POSIX sh.
+pub(crate) fn sh_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Shell prologue that stages files (read from `src_dir/f<i>`) and applies the
+/// CPU rlimit, followed by the command itself reading stdin from `stdin_path`.
+/// The command runs under `spec.shell`, bash when unset.
+pub(crate) fn wrapper_script(task: &RemoteTask<'_>, src_dir: &str, stdin_path: &str) -> String {
+    let mut s = String::new();
+    for (i, f) in task.files.iter().enumerate() {
+        let dir = std::path::Path::new(&f.path)
+            .parent()
+            .map(|d| d.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "/".into());
+        s.push_str(&format!(
+            "mkdir -p {} && cp {}/f{} {}\n",
+            sh_quote(&dir),
+            src_dir,
+            i,
+            sh_quote(&f.path)
+        ));
+    }
+    let cpu_secs = task.spec.cpu_ms / 1000;
+    if cpu_secs > 0 {
+        s.push_str(&format!("ulimit -t {} 2>/dev/null\n", cpu_secs));
+    }
+    let shell = task.spec.shell.unwrap_or(Shell::Bash);
+    let invocation = match shell {
+        Shell::Argv => shell
+            .argv(task.cmd)
+            .iter()
+            .map(|w| sh_quote(w))
+            .collect::<Vec<_>>()
+            .join(" "),
+        _ => {
+            let (prog, args) = shell.program();
+            format!("{} {} {}", prog, args.join(" "), sh_quote(task.cmd))
+        }
+    };
+    s.push_str(&format!(
+        "cd /tmp && exec {} < {}\n",
+        invocation, stdin_path
+    ));
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sh_quote() {
+        assert_eq!(sh_quote("a b"), "'a b'");
+        assert_eq!(sh_quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn test_wrapper_script_stages_files() {
+        let spec = SandboxSpec {
+            wall_sec: 10,
+            cpu_ms: 3000,
+            memory_mb: 64,
+            pids: 16,
+            ..Default::default()
+        };
+        let files = vec![StagedFile {
+            path: "/tmp/in/data.txt".into(),
+            bytes: b"x".to_vec(),
+        }];
+        let task = RemoteTask {
+            run_id: "r_1",
+            tenant: "default",
+            cmd: "cat /tmp/in/data.txt",
+            stdin: b"",
+            files: &files,
+            allow_net: &[],
+            spec: &spec,
+        };
+        let s = wrapper_script(&task, "/magicrune", "/magicrune/stdin");
+        assert!(s.contains("mkdir -p '/tmp/in' && cp /magicrune/f0 '/tmp/in/data.txt'"));
+        assert!(s.contains("ulimit -t 3"));
+        assert!(s.ends_with("exec bash -lc 'cat /tmp/in/data.txt' < /magicrune/stdin\n"));
+
+        let spec = SandboxSpec {
+            shell: Some(Shell::Argv),
+            ..spec.clone()
+        };
+        let task = RemoteTask {
+            spec: &spec,
+            ..task
+        };
+        let s = wrapper_script(&task, "/magicrune", "/magicrune/stdin");
+        assert!(s.ends_with("exec 'cat' '/tmp/in/data.txt' < /magicrune/stdin\n"));
+    }
+}
@@ -0,0 +1,384 @@
+//! SSH backend: run the command on a remote host via the `ssh` client.
+//!
+//! A single bootstrap script is piped to `bash -s` on the remote side. It
+//! unpacks stdin and the staged files into a private temp directory, applies
+//! the memory rlimit, runs [`super::wrapper_script`] under `timeout`, and
+//! removes the directory again. The controller keeps its own wall-clock
+//! deadline and caps how much output it keeps, so a misbehaving host cannot
+//! stall or flood it.
+
+use super::{sh_quote, wrapper_script, RemoteTask};
+use crate::sandbox::{classify_exit, oom, SandboxOutcome, TerminationReason};
+use base64::Engine as _;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+/// `ssh` exits with 255 when the connection itself fails.
+const SSH_CONNECT_FAILED: i32 = 255;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshConfig {
+    /// `host` or `user@host`.
+    pub host: String,
+    pub port: Option<u16>,
+    /// Private key passed with `-i`.
+    pub identity: Option<String>,
+    /// known_hosts file; host keys are always checked strictly.
+    pub known_hosts: Option<String>,
+    /// ssh binary to invoke.
+    pub ssh: String,
+    /// Bytes of stdout/stderr kept per stream; the rest is discarded.
+    pub max_output_bytes: usize,
+}
+
+impl Default for SshConfig {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            port: None,
+            identity: None,
+            known_hosts: None,
+            ssh: "ssh".into(),
+            max_output_bytes: 1024 * 1024,
+        }
+    }
+}
+
+impl SshConfig {
+    /// `MAGICRUNE_SSH_HOST`, `MAGICRUNE_SSH_PORT`, `MAGICRUNE_SSH_KEY`,
+    /// `MAGICRUNE_SSH_KNOWN_HOSTS`, `MAGICRUNE_SSH`, `MAGICRUNE_SSH_MAX_OUTPUT`.
+    pub fn from_env() -> Self {
+        let d = Self::default();
+        let var = |k: &str| crate::ports::env::var(k).ok().filter(|v| !v.is_empty());
+        Self {
+            host: var("MAGICRUNE_SSH_HOST").unwrap_or(d.host),
+            port: var("MAGICRUNE_SSH_PORT").and_then(|v| v.parse().ok()),
+            identity: var("MAGICRUNE_SSH_KEY"),
+            known_hosts: var("MAGICRUNE_SSH_KNOWN_HOSTS"),
+            ssh: var("MAGICRUNE_SSH").unwrap_or(d.ssh),
+            max_output_bytes: var("MAGICRUNE_SSH_MAX_OUTPUT")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(d.max_output_bytes),
+        }
+    }
+
+    /// Arguments for the `ssh` client, ending with the remote command.
+    pub fn args(&self) -> Vec<String> {
+        let mut a: Vec<String> = vec![
+            "-T".into(),
+            "-o".into(),
+            "BatchMode=yes".into(),
+            "-o".into(),
+            "StrictHostKeyChecking=yes".into(),
+        ];
+        if let Some(kh) = &self.known_hosts {
+            a.push("-o".into());
+            a.push(format!("UserKnownHostsFile={}", kh));
+        }
+        if let Some(key) = &self.identity {
+            a.push("-i".into());
+            a.push(key.clone());
+            a.push("-o".into());
+            a.push("IdentitiesOnly=yes".into());
+        }
+        if let Some(port) = self.port {
+            a.push("-p".into());
+            a.push(port.to_string());
+        }
+        a.push(self.host.clone());
+        a.push("bash -s".into());
+        a
+    }
+}
+
+/// Script piped to the remote `bash -s`.
+pub fn bootstrap_script(task: &RemoteTask<'_>) -> String {
+    let b64 = base64::engine::general_purpose::STANDARD;
+    let mut s = format!(
+        "set -u\nd=$(mktemp -d -t {}) || exit 125\ntrap 'rm -rf \"$d\"' EXIT\n",
+        sh_quote(&format!("magicrune-{}.XXXXXX", task.tenant))
+    );
+    s.push_str(&format!(
+        "printf %s {} | base64 -d > \"$d/stdin\"\n",
+        sh_quote(&b64.encode(task.stdin))
+    ));
+    for (i, f) in task.files.iter().enumerate() {
+        s.push_str(&format!(
+            "printf %s {} | base64 -d > \"$d/f{}\"\n",
+            sh_quote(&b64.encode(&f.bytes)),
+            i
+        ));
+    }
+    // Encoded like the files: the wrapper holds the command verbatim, and
+    // no line of it may end up in this script.
+    let wrapper = wrapper_script(task, "\"$MAGICRUNE_DIR\"", "\"$MAGICRUNE_DIR/stdin\"");
+    s.push_str(&format!(
+        "printf %s {} | base64 -d > \"$d/run.sh\"\n",
+        sh_quote(&b64.encode(wrapper))
+    ));
+    if task.spec.memory_mb > 0 {
+        s.push_str(&format!(
+            "ulimit -v {} 2>/dev/null\n",
+            task.spec.memory_mb * 1024
+        ));
+    }
+    s.push_str(&format!(
+        "MAGICRUNE_DIR=\"$d\" timeout -s KILL {} bash \"$d/run.sh\"\n",
+        task.spec.wall_sec.max(1)
+    ));
+    s
+}
+
+/// Read all of `r`, keeping at most `cap` bytes.
+async fn read_capped<R: AsyncRead + Unpin>(mut r: R, cap: usize) -> Vec<u8> {
+    let mut kept = Vec::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        match r.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                let room = cap.saturating_sub(kept.len());
+                kept.extend_from_slice(&buf[..n.min(room)]);
+            }
+        }
+    }
+    kept
+}
+
+/// Run `task` on the configured host and wait for it to finish.
+pub async fn run_remote(cfg: &SshConfig, task: &RemoteTask<'_>) -> SandboxOutcome {
+    if cfg.host.is_empty() {
+        return SandboxOutcome::spawn_error("MAGICRUNE_SSH_HOST is not set");
+    }
+    let wall = Duration::from_secs(task.spec.wall_sec.max(1));
+    let started = task.spec.clock.now_millis();
+    let mut child = match tokio::process::Command::new(&cfg.ssh)
+        .args(cfg.args())
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(c) => c,
+        Err(e) => return SandboxOutcome::spawn_error(format!("{}: {}", cfg.ssh, e)),
+    };
+    let script = bootstrap_script(task);
+    if let Some(mut sin) = child.stdin.take() {
+        let _ = sin.write_all(script.as_bytes()).await;
+    }
+    let out = child.stdout.take().expect("piped stdout");
+    let err = child.stderr.take().expect("piped stderr");
+    let cap = cfg.max_output_bytes;
+    let stdout = tokio::spawn(read_capped(out, cap));
+    let stderr = tokio::spawn(read_capped(err, cap));
+
+    // The remote `timeout` normally fires first; this covers a hung connection.
+    let deadline = wall + Duration::from_secs(10);
+    let status = match tokio::select! {
+        st = child.wait() => Some(st),
+        _ = task.spec.clock.sleep(deadline) => None,
+    } {
+        Some(Ok(st)) => st,
+        Some(Err(e)) => return SandboxOutcome::spawn_error(e.to_string()),
+        None => {
+            let _ = child.kill().await;
+            return SandboxOutcome {
+                exit_code: 128 + 9,
+                stdout: stdout.await.unwrap_or_default(),
+                stderr: b"timeout".to_vec(),
+                reason: TerminationReason::WallTimeout,
+                ..SandboxOutcome::empty()
+            };
+        }
+    };
+    let stdout = stdout.await.unwrap_or_default();
+    let stderr = stderr.await.unwrap_or_default();
+    let (exit_code, reason) = classify_exit(&status);
+    if exit_code == SSH_CONNECT_FAILED && stdout.is_empty() {
+        return SandboxOutcome::spawn_error(format!(
+            "ssh {}: {}",
+            cfg.host,
+            String::from_utf8_lossy(&stderr).trim()
+        ));
+    }
+    // `timeout -s KILL` makes the remote shell report 128 + SIGKILL. So does
+    // a command that exits 137 itself, or is killed, but only `timeout` does
+    // so once `wall_sec` has passed.
+    if exit_code == 128 + 9 && task.spec.clock.since(started) >= wall {
+        return SandboxOutcome {
+            exit_code,
+            stdout,
+            stderr: b"timeout".to_vec(),
+            reason: TerminationReason::WallTimeout,
+            ..SandboxOutcome::empty()
+        };
+    }
+    // `ulimit -v` is the remote side's `RLIMIT_AS`.
+    let (reason, findings) =
+        match oom::diagnose(&reason, exit_code, &stderr, task.spec.memory_mb, 0, false) {
+            Some(f) => (TerminationReason::MemoryLimit, vec![f]),
+            None => (reason, Vec::new()),
+        };
+    SandboxOutcome {
+        exit_code,
+        stdout,
+        stderr,
+        reason,
+        findings,
+        ..SandboxOutcome::empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::StagedFile;
+    use crate::sandbox::SandboxSpec;
+
+    fn spec() -> SandboxSpec {
+        SandboxSpec {
+            wall_sec: 5,
+            cpu_ms: 2000,
+            memory_mb: 128,
+            pids: 32,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_args_check_host_keys() {
+        let cfg = SshConfig {
+            host: "runner@data-1".into(),
+            port: Some(2222),
+            identity: Some("/etc/magicrune/id_ed25519".into()),
+            ..Default::default()
+        };
+        let args = cfg.args();
+        assert!(args.contains(&"BatchMode=yes".to_string()));
+        assert!(args.contains(&"StrictHostKeyChecking=yes".to_string()));
+        assert!(args
+            .windows(2)
+            .any(|w| w[0] == "-i" && w[1] == "/etc/magicrune/id_ed25519"));
+        assert!(args.windows(2).any(|w| w[0] == "-p" && w[1] == "2222"));
+        assert_eq!(&args[args.len() - 2..], ["runner@data-1", "bash -s"]);
+    }
+
+    // Runs the bootstrap script with the local bash, standing in for the
+    // remote host.
+    #[test]
+    fn test_bootstrap_script_runs_locally() {
+        let spec = spec();
+        let path = format!("/tmp/magicrune_ssh_test_{}/in.txt", std::process::id());
+        let files = vec![StagedFile {
+            path: path.clone(),
+            bytes: b"file-data".to_vec(),
+        }];
+        let cmd = format!("cat {}; cat -", path);
+        let task = RemoteTask {
+            run_id: "r_1",
+            tenant: "default",
+            cmd: &cmd,
+            stdin: b"+stdin",
+            files: &files,
+            allow_net: &[],
+            spec: &spec,
+        };
+        let out = std::process::Command::new("bash")
+            .arg("-c")
+            .arg(bootstrap_script(&task))
+            .output()
+            .unwrap();
+        let _ = std::fs::remove_dir_all(std::path::Path::new(&path).parent().unwrap());
+        assert!(
+            out.status.success(),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        assert_eq!(out.stdout, b"file-data+stdin");
+    }
+
+    #[test]
+    fn test_command_cannot_leave_run_sh() {
+        let spec = spec();
+        let task = RemoteTask {
+            run_id: "r_1",
+            tenant: "default",
+            cmd: "echo one\nMAGICRUNE_EOF\necho two",
+            stdin: b"",
+            files: &[],
+            allow_net: &[],
+            spec: &spec,
+        };
+        let script = bootstrap_script(&task);
+        assert!(!script.contains("MAGICRUNE_EOF"));
+        let out = std::process::Command::new("bash")
+            .arg("-c")
+            .arg(&script)
+            .output()
+            .unwrap();
+        assert_eq!(out.stdout, b"one\ntwo\n");
+    }
+
+    // A stand-in `ssh` that runs the script with the local bash.
+    #[test]
+    fn test_exit_137_is_a_timeout_only_at_the_deadline() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("mr_fake_ssh_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ssh = dir.join("ssh");
+        std::fs::write(&ssh, "#!/bin/sh\nexec bash -s\n").unwrap();
+        std::fs::set_permissions(&ssh, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let cfg = SshConfig {
+            host: "local".into(),
+            ssh: ssh.display().to_string(),
+            ..Default::default()
+        };
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let run = |cmd: &str, wall_sec: u64| {
+            let spec = SandboxSpec { wall_sec, ..spec() };
+            let task = RemoteTask {
+                run_id: "r_1",
+                tenant: "default",
+                cmd,
+                stdin: b"",
+                files: &[],
+                allow_net: &[],
+                spec: &spec,
+            };
+            rt.block_on(run_remote(&cfg, &task))
+        };
+        let own = run("exit 137", 5);
+        assert_eq!(own.exit_code, 137);
+        assert!(!own.timed_out(), "{:?}", own.reason);
+        let slow = run("sleep 5", 1);
+        assert_eq!(slow.exit_code, 137);
+        assert!(slow.timed_out(), "{:?}", slow.reason);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_read_capped_drains_past_cap() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let data = vec![b'x'; 20_000];
+        let kept = rt.block_on(read_capped(&data[..], 100));
+        assert_eq!(kept.len(), 100);
+    }
+
+    #[test]
+    fn test_missing_host_is_spawn_error() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let spec = spec();
+        let task = RemoteTask {
+            run_id: "r_1",
+            tenant: "default",
+            cmd: "true",
+            stdin: b"",
+            files: &[],
+            allow_net: &[],
+            spec: &spec,
+        };
+        let out = rt.block_on(run_remote(&SshConfig::default(), &task));
+        assert!(matches!(out.reason, TerminationReason::SpawnError(_)));
+    }
+}
@@ -0,0 +1,150 @@
+//! In-process load generator behind `magicrune bench`.
+//!
+//! Requests go straight through [`crate::engine::execute`], so the numbers
+//! reflect the pipeline itself rather than process startup.
+
+use crate::engine::{self, ExecOptions};
+use crate::policy::Policy;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    /// Total number of synthetic requests.
+    pub requests: usize,
+    /// Requests in flight at once.
+    pub concurrency: usize,
+    /// Command template; `{i}` is replaced with the request index.
+    pub cmd: String,
+    pub opts: ExecOptions,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            requests: 1000,
+            concurrency: 8,
+            cmd: "echo bench_{i}".to_string(),
+            opts: ExecOptions::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct BenchReport {
+    pub requests: usize,
+    pub ok: usize,
+    pub failed: usize,
+    pub elapsed_ms: f64,
+    pub throughput_rps: f64,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+}
+
+/// Nearest-rank percentile over an ascending slice.
+pub fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn synthetic_request(cmd: &str, i: usize) -> Vec<u8> {
+    serde_json::to_vec(&serde_json::json!({
+        "cmd": cmd.replace("{i}", &i.to_string()),
+        "stdin": "",
+        "env": {},
+        "files": [],
+        "policy_id": "default",
+        "timeout_sec": 5,
+        "allow_net": [],
+        "allow_fs": []
+    }))
+    .expect("serialize request")
+}
+
+/// Run `cfg.requests` synthetic requests with `cfg.concurrency` workers.
+pub async fn run_bench(cfg: &BenchConfig, policy: &Policy) -> BenchReport {
+    let next = Arc::new(AtomicUsize::new(0));
+    let policy = Arc::new(policy.clone());
+    let started = Instant::now();
+    let mut workers = Vec::new();
+    for _ in 0..cfg.concurrency.max(1) {
+        let next = next.clone();
+        let policy = policy.clone();
+        let cfg = cfg.clone();
+        workers.push(tokio::spawn(async move {
+            let mut lats = Vec::new();
+            let mut failed = 0usize;
+            loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= cfg.requests {
+                    break;
+                }
+                let raw = synthetic_request(&cfg.cmd, i);
+                let t = Instant::now();
+                match engine::execute(&raw, &policy, &cfg.opts).await {
+                    Ok(_) => lats.push(t.elapsed()),
+                    Err(_) => failed += 1,
+                }
+            }
+            (lats, failed)
+        }));
+    }
+    let mut lats = Vec::with_capacity(cfg.requests);
+    let mut failed = 0usize;
+    for w in workers {
+        if let Ok((l, f)) = w.await {
+            lats.extend(l);
+            failed += f;
+        }
+    }
+    let elapsed = started.elapsed();
+    lats.sort();
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    BenchReport {
+        requests: cfg.requests,
+        ok: lats.len(),
+        failed,
+        elapsed_ms: ms(elapsed),
+        throughput_rps: cfg.requests as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+        p50_ms: ms(percentile(&lats, 50.0)),
+        p95_ms: ms(percentile(&lats, 95.0)),
+        p99_ms: ms(percentile(&lats, 99.0)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile_nearest_rank() {
+        let v: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&v, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&v, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&v, 100.0), Duration::from_millis(100));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_run_bench_counts() {
+        let cfg = BenchConfig {
+            requests: 20,
+            concurrency: 4,
+            opts: ExecOptions {
+                dry_run: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let report = rt.block_on(run_bench(&cfg, &Policy::default()));
+        assert_eq!(report.ok + report.failed, 20);
+        assert_eq!(report.failed, 0);
+        assert!(report.p50_ms <= report.p99_ms);
+    }
+}
@@ -1,103 +1,223 @@
 #[cfg(feature = "jet")]
 mod app {
+    use async_nats::jetstream::context::PublishErrorKind;
+    use async_nats::jetstream::{self, Context};
+    use async_nats::Client;
     use futures_util::StreamExt;
-    use magicrune::jet::{compute_msg_id, jet_impl};
-    use serde_json::Value;
-    use std::str::FromStr as _;
+    use magicrune::instance::shard_of;
+    use magicrune::jet::encoding::Compression;
+    use magicrune::jet::jet_impl;
+    use magicrune::jet::publish::{self, Outcome, PublishArgs};
+    use magicrune::jet::subjects::Subjects;
+    use std::path::Path;
 
-    fn sha256_hex(bytes: &[u8]) -> String {
-        use sha2::{Digest, Sha256};
-        let mut h = Sha256::new();
-        h.update(bytes);
-        format!("{:x}", h.finalize())
+    /// Settings shared by every file, from the environment.
+    struct Settings {
+        compression: Compression,
+        subjects: Subjects,
+        /// With MAGICRUNE_SHARDS=<n> and no subject, the run id picks the
+        /// shard.
+        shards: Option<u32>,
     }
 
     #[tokio::main]
-    pub async fn main() -> anyhow::Result<()> {
-        // Args: <file.json> [subject]
-        let mut args = std::env::args().skip(1);
-        let file = args.next().unwrap_or_else(|| "samples/ok.json".to_string());
-        let subject = args.next().unwrap_or_else(|| "run.req.default".to_string());
+    pub async fn main() -> i32 {
+        // Args: see `magicrune::jet::publish::USAGE`.
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let args = match PublishArgs::parse(&args) {
+            Ok(a) => a,
+            Err(e) => {
+                eprintln!("js_publish: {}\n{}", e, publish::USAGE);
+                return publish::EXIT_SETUP;
+            }
+        };
+        let settings = match (Compression::from_env(), Subjects::from_env()) {
+            (Ok(compression), Ok(subjects)) => Settings {
+                compression,
+                subjects,
+                shards: std::env::var("MAGICRUNE_SHARDS")
+                    .ok()
+                    .and_then(|n| n.parse::<u32>().ok())
+                    .filter(|n| *n > 0),
+            },
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("js_publish: {}", e);
+                return publish::EXIT_SETUP;
+            }
+        };
 
         let url = std::env::var("NATS_URL").unwrap_or_else(|_| "127.0.0.1:4222".to_string());
-        let nc = jet_impl::connect(&format!("nats://{}", url))
-            .await
-            .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        let mut attempt = 0;
+        let nc = loop {
+            match jet_impl::connect(&format!("nats://{}", url)).await {
+                Ok(nc) => break nc,
+                Err(e) if attempt < args.retries => {
+                    eprintln!("js_publish: connect: {} (retrying)", e);
+                    tokio::time::sleep(publish::backoff(attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    eprintln!("js_publish: connect: {}", e);
+                    return publish::EXIT_SETUP;
+                }
+            }
+        };
+        let js = jetstream::new(nc.clone());
+        ensure_stream(&js, args.subject.as_deref(), &settings).await;
 
-        let payload = std::fs::read(&file)?;
+        let outcomes: Vec<Outcome> = futures_util::stream::iter(&args.files)
+            .map(|file| {
+                let (nc, js, args, settings) = (&nc, &js, &args, &settings);
+                async move {
+                    let mut out = Outcome {
+                        file: file.display().to_string(),
+                        ..Default::default()
+                    };
+                    if let Err(e) = publish_one(nc, js, args, settings, file, &mut out).await {
+                        out.error = Some(e);
+                    }
+                    if !args.json {
+                        if let Some(r) = &out.result {
+                            println!("{}", r);
+                        }
+                    }
+                    out
+                }
+            })
+            .buffered(args.concurrency)
+            .collect()
+            .await;
+
+        if args.json {
+            println!("{}", publish::summary(&outcomes));
+        } else {
+            for o in &outcomes {
+                eprintln!("{}", o.line());
+            }
+        }
+        publish::exit_code(&outcomes)
+    }
+
+    /// Create the stream when it is missing, covering every shard when
+    /// requests may go to shard subjects.
+    async fn ensure_stream(js: &Context, subject: Option<&str>, settings: &Settings) {
+        use async_nats::jetstream::stream::{Config, RetentionPolicy, StorageType};
+        let name = std::env::var("NATS_STREAM").unwrap_or_else(|_| "RUN".to_string());
+        let subjects = &settings.subjects;
+        let covered = match subject {
+            Some(s) if subjects.parse_shard(s).is_none() => s.to_string(),
+            None if settings.shards.is_none() => subjects.request(None),
+            _ => subjects.shard_wildcard(),
+        };
+        let cfg = Config {
+            name: name.clone(),
+            subjects: vec![covered],
+            retention: RetentionPolicy::Limits,
+            max_consumers: -1,
+            max_messages: -1,
+            max_bytes: -1,
+            duplicate_window: std::time::Duration::from_secs(120),
+            storage: StorageType::File,
+            ..Default::default()
+        };
+        if js.get_stream(&name).await.is_err() {
+            let _ = js.create_stream(cfg).await;
+        }
+    }
+
+    /// Publish one request and wait for its result, filling `out`.
+    async fn publish_one(
+        nc: &Client,
+        js: &Context,
+        args: &PublishArgs,
+        settings: &Settings,
+        file: &Path,
+        out: &mut Outcome,
+    ) -> Result<(), String> {
+        let payload = std::fs::read(file).map_err(|e| format!("read: {}", e))?;
+        // A bundle goes to the object store and the message points at it; the
+        // run id is that of the request it expands to.
+        let (payload, request) = if magicrune::bundle::is_bundle(&payload) {
+            let request = magicrune::bundle::open(&payload).map_err(|e| e.to_string())?;
+            let pointer = jet_impl::put_bundle(nc, &payload)
+                .await
+                .map_err(|e| format!("store bundle: {}", e))?;
+            (pointer, request)
+        } else {
+            (payload.clone(), payload)
+        };
         // Compute run_id the same way as consumer: hash(payload + seed_le)
-        let seed_le = {
-            let v: Value = serde_json::from_slice(&payload).unwrap_or(Value::Null);
-            let seed = v
-                .get("seed")
-                .and_then(|x| x.as_u64())
-                .unwrap_or(0u64)
-                .to_le_bytes()
-                .to_vec();
-            seed
+        out.run_id = magicrune::transport::consumer_run_id(&request);
+        let subjects = &settings.subjects;
+        out.subject = match (&args.subject, settings.shards) {
+            (Some(s), _) => s.clone(),
+            (None, Some(n)) => subjects.shard(shard_of(&out.run_id, n)),
+            (None, None) => subjects.request(None),
         };
-        let mut all = payload.clone();
-        all.extend_from_slice(&seed_le);
-        let run_id = format!("r_{}", sha256_hex(&all));
+        // With tenant result routing, the tenant the consumer will resolve:
+        // the one the subject binds, else the one the request names.
+        let tenant = magicrune::tenant::from_subject(&out.subject)
+            .or_else(|| {
+                let req: serde_json::Value = serde_json::from_slice(&request).ok()?;
+                Some(req.get("tenant")?.as_str()?.to_string())
+            })
+            .filter(|t| !t.is_empty())
+            .unwrap_or_else(|| magicrune::tenant::DEFAULT_TENANT.to_string());
 
-        // Publish request with Nats-Msg-Id header (ensure stream exists first)
-        {
-            use async_nats::jetstream::{
-                self,
-                stream::{Config, RetentionPolicy, StorageType},
-            };
-            let js = jetstream::new(nc.clone());
-            let name = std::env::var("NATS_STREAM").unwrap_or_else(|_| "RUN".to_string());
-            let cfg = Config {
-                name: name.clone(),
-                subjects: vec![subject.clone()],
-                retention: RetentionPolicy::Limits,
-                max_consumers: -1,
-                max_messages: -1,
-                max_bytes: -1,
-                duplicate_window: std::time::Duration::from_secs(120),
-                storage: StorageType::File,
-                ..Default::default()
+        // Subscribe first: a fast consumer may answer before publish returns.
+        let res_subject = subjects.result(&out.run_id, &tenant);
+        let mut sub = jet_impl::subscribe_result(nc, res_subject.clone())
+            .await
+            .map_err(|e| format!("subscribe: {}", e))?;
+
+        let (headers, payload) = jet_impl::request_message(&payload, &settings.compression);
+        let ack = loop {
+            out.attempts += 1;
+            let sent = js
+                .publish_with_headers(out.subject.clone(), headers.clone(), payload.clone().into())
+                .await;
+            let acked = match sent {
+                Ok(ack) => ack.await,
+                Err(e) => Err(e),
             };
-            if js.get_stream(&name).await.is_err() {
-                let _ = js.create_stream(cfg).await;
+            match acked {
+                Ok(ack) => break ack,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        PublishErrorKind::TimedOut | PublishErrorKind::BrokenPipe
+                    ) && out.attempts <= args.retries =>
+                {
+                    tokio::time::sleep(publish::backoff(out.attempts - 1)).await;
+                }
+                Err(e) => return Err(format!("publish: {}", e)),
             }
-
-            let mut headers = async_nats::header::HeaderMap::new();
-            let id = compute_msg_id(&payload);
-            headers.insert(
-                "Nats-Msg-Id",
-                async_nats::header::HeaderValue::from_str(&id)?,
-            );
-            js.publish_with_headers(subject.clone(), headers, payload.clone().into())
-                .await?;
+        };
+        // The stream dropped it: the first copy's run already answered or is
+        // answering someone else.
+        if ack.duplicate {
+            return Err("duplicate of a request still in the stream's dedupe window".into());
         }
 
-        // Wait for response on run.res.<run_id>
-        let res_subject = format!("run.res.{}", run_id);
-        let mut sub = nc.subscribe(res_subject.clone()).await?;
-        let to_secs = std::env::var("JS_PUBLISH_TIMEOUT_SEC")
-            .ok()
-            .and_then(|s| s.parse::<u64>().ok())
-            .unwrap_or(5);
-        let got = tokio::time::timeout(std::time::Duration::from_secs(to_secs), sub.next())
+        let body = tokio::time::timeout(args.timeout, sub.next(&settings.compression))
             .await
-            .map_err(|_| anyhow::anyhow!("timeout waiting for {}", res_subject))?;
-        if let Some(m) = got {
-            println!("{}", String::from_utf8_lossy(&m.payload));
-            // Send ack-ack confirmation
-            let ack_subject = format!("run.ack.{}", run_id);
-            let _ = nc.publish(ack_subject, b"ok".to_vec().into()).await;
-        } else {
-            anyhow::bail!("subscription ended prematurely");
-        }
+            .map_err(|_| format!("timeout waiting for {}", res_subject))?
+            .map_err(|e| format!("result: {}", e))?
+            .ok_or("subscription ended prematurely")?;
+        out.result = Some(serde_json::from_slice(&body).unwrap_or_else(|_| {
+            serde_json::Value::String(String::from_utf8_lossy(&body).into_owned())
+        }));
+        // Send ack-ack confirmation
+        let _ = nc
+            .publish(subjects.ack(&out.run_id, &tenant), b"ok".to_vec().into())
+            .await;
         Ok(())
     }
 }
 
 #[cfg(feature = "jet")]
 fn main() {
-    app::main().unwrap();
+    std::process::exit(app::main());
 }
 
 #[cfg(not(feature = "jet"))]
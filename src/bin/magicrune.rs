@@ -1,15 +1,24 @@
-use magicrune::observability::{init_observability, shutdown_observability, ExecutionContext};
-use magicrune::sandbox::{detect_sandbox, SandboxKind};
+use magicrune::bench::{run_bench, BenchConfig};
+use magicrune::bundle;
+use magicrune::engine::{self, ExecOptions};
+use magicrune::keys::{self, Keyring};
+use magicrune::observability::{init_observability, shutdown_observability};
+use magicrune::policy::Policy;
+use magicrune::schema::Verdict;
+use magicrune::shadow::ShadowPolicy;
+use magicrune::spool::{Processed, Spool};
+use magicrune::template;
 use std::env;
 use std::fs;
 use std::io::{self, Write};
 use std::path::Path;
-use std::process::{Command, Stdio};
-use std::str::FromStr;
-use std::time::{Duration, Instant};
 
+#[cfg(feature = "jet")]
 use base64::Engine;
-use serde::{Deserialize, Serialize};
+#[cfg(feature = "jet")]
+use magicrune::messages::{Code, Message};
+#[cfg(feature = "jet")]
+use magicrune::schema::RequestView;
 
 // --- env helpers ------------------------------------------------------------
 #[inline]
@@ -21,652 +30,241 @@ fn env_u64(key: &str, default: u64) -> u64 {
         .unwrap_or(default)
 }
 
-#[derive(Debug, Deserialize)]
-#[allow(dead_code)]
-struct SpellRequest {
-    #[serde(default)]
-    cmd: String,
-    #[serde(default)]
-    stdin: String,
-    #[serde(default)]
-    env: serde_json::Map<String, serde_json::Value>,
-    #[serde(default)]
-    files: Vec<FileEntry>,
-    #[serde(default)]
-    policy_id: String,
-    #[serde(default)]
-    timeout_sec: u64,
-    #[serde(default)]
-    allow_net: Vec<String>,
-    #[serde(default)]
-    allow_fs: Vec<String>,
-}
-
-#[derive(Debug, Deserialize)]
-#[allow(dead_code)]
-struct FileEntry {
-    path: String,
-    #[serde(default)]
-    content_b64: String,
-}
-
-#[derive(Debug, Serialize)]
-struct SpellResult {
-    run_id: String,
-    verdict: String,
-    risk_score: u32,
-    exit_code: i32,
-    duration_ms: u64,
-    stdout_trunc: bool,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    sbom_attestation: Option<String>,
-}
-
-// Minimal, portable SHA-256 implementation (reduced, local-only)
-// Source: derived from FIPS PUB 180-4; implemented here to avoid extra deps.
-fn sha256_hex(input: &[u8]) -> String {
-    const K: [u32; 64] = [
-        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
-        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
-        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
-        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
-        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
-        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
-        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
-        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
-        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
-        0xc67178f2,
-    ];
-    let mut h: [u32; 8] = [
-        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
-        0x5be0cd19,
-    ];
-    let bit_len = (input.len() as u64) * 8;
-    let mut data = input.to_vec();
-    data.push(0x80);
-    while (data.len() % 64) != 56 {
-        data.push(0);
-    }
-    data.extend_from_slice(&bit_len.to_be_bytes());
-
-    for chunk in data.chunks(64) {
-        let mut w = [0u32; 64];
-        for (i, item) in w.iter_mut().enumerate().take(16) {
-            let j = i * 4;
-            *item = u32::from_be_bytes([chunk[j], chunk[j + 1], chunk[j + 2], chunk[j + 3]]);
-        }
-        for t in 16..64 {
-            let s0 = w[t - 15].rotate_right(7) ^ w[t - 15].rotate_right(18) ^ (w[t - 15] >> 3);
-            let s1 = w[t - 2].rotate_right(17) ^ w[t - 2].rotate_right(19) ^ (w[t - 2] >> 10);
-            w[t] = w[t - 16]
-                .wrapping_add(s0)
-                .wrapping_add(w[t - 7])
-                .wrapping_add(s1);
-        }
-
-        let mut a = h[0];
-        let mut b = h[1];
-        let mut c = h[2];
-        let mut d = h[3];
-        let mut e = h[4];
-        let mut f = h[5];
-        let mut g = h[6];
-        let mut hh = h[7];
-
-        for t in 0..64 {
-            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
-            let ch = (e & f) ^ ((!e) & g);
-            let temp1 = hh
-                .wrapping_add(s1)
-                .wrapping_add(ch)
-                .wrapping_add(K[t])
-                .wrapping_add(w[t]);
-            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
-            let maj = (a & b) ^ (a & c) ^ (b & c);
-            let temp2 = s0.wrapping_add(maj);
-
-            hh = g;
-            g = f;
-            f = e;
-            e = d.wrapping_add(temp1);
-            d = c;
-            c = b;
-            b = a;
-            a = temp1.wrapping_add(temp2);
-        }
-
-        h[0] = h[0].wrapping_add(a);
-        h[1] = h[1].wrapping_add(b);
-        h[2] = h[2].wrapping_add(c);
-        h[3] = h[3].wrapping_add(d);
-        h[4] = h[4].wrapping_add(e);
-        h[5] = h[5].wrapping_add(f);
-        h[6] = h[6].wrapping_add(g);
-        h[7] = h[7].wrapping_add(hh);
-    }
-    let mut out = String::with_capacity(64);
-    for v in h.iter() {
-        out.push_str(&format!("{:08x}", v));
-    }
-    out
-}
-
 fn print_usage() {
     eprintln!(
-        "Usage:\n  magicrune exec -f <request.json> [--policy <policy.yml>] [--timeout <secs>] [--seed <n>] [--out <result.json>] [--strict]\n  magicrune consume [--url <nats_host:port>] [--subject <run.req.*>]"
+        "Usage:\n  magicrune exec -f <request.json | bundle.spell.tgz> [--policy <policy.yml>] [--timeout <secs>] [--seed <n>] [--out <result.json>] [--strict] [--seccomp-learn] [--decode <base64|lossy>] [--interactive] [--watch] [--offline] [--var <key=value>]... [--errors <text|json>]\n  magicrune exec -f <request.json | bundle.spell.tgz> --policy <a.yml> --policy <b.yml>... --compare [--dry-run] [--out <report.json>]\n  magicrune consume [--url <nats_host:port>] [--subject-prefix <run>] [--result-routing <run | tenant>] [--subject <run.req.*>] [--shard <k>] [--transport <redis://...>] [--health-addr <host:port>] [--instance-id <id>] [--stream <name>] [--durable <name>] [--dup-window-sec <s>] [--max-ack-pending <n>] [--ack-wait-sec <s>] [--max-deliver <n>] [--dedupe-max <n>] [--ack-ack-wait-sec <s>] [--shadow-policy <policy.yml>] [--labels <l1,l2>] [--concurrency <n | auto | auto:min..max>] [--mode <pull | core>] [--test-delay-ms <ms>] [--test-delay-ms-jitter <lo..=hi>] [--test-skip-ack-once] [--test-fail-publish-nth <n>] [--show-config]\n  magicrune bench [-n <requests>] [-c <concurrency>] [--policy <policy.yml>] [--cmd <template>] [--dry-run] [--json]\n  magicrune enqueue -f <request.json> [--spool <dir>]\n  magicrune worker [--spool <dir>] [--policy <policy.yml>] [--once]\n  magicrune verify <result.json> --key <pub.pem | keyring.json>\n  magicrune keys generate --out <key.pem> [--pub <pub.pem>]\n  magicrune keys inspect [<key.pem | keyring.json>]\n  magicrune keys sign-request <request.json> --key <key.pem>\n  magicrune schema dump <request | result>\n  magicrune schema check [--dir <schemas>]\n  magicrune stats [--ledger <runs.jsonl | dir>] [--since <24h | 2024-01-31>] [--until <...>] [--tenant <t>] [--top <n>] [--json]\n  magicrune ledger prune [--ledger <runs.jsonl | dir>] [--retention <green=7d,yellow=30d,red=365d>] [--dry-run]\n  magicrune repro <run_id> [--times <n>] [--policy <policy.yml>] [--ledger <runs.jsonl | dir>] [--json]\n  magicrune gc [--min-age <minutes>] [--dry-run]\n  magicrune doctor [--policy <policy.yml>] [--url <nats_host:port>]\n  magicrune shell [--policy <policy.yml>] [--ledger <runs.jsonl | dir>]"
     );
 }
 
-#[derive(Debug, Clone)]
-struct Thresholds {
-    green: String,
-    yellow: String,
-    red: String,
+/// Print why `exec` stopped: the message, or with `--errors json` one JSON
+/// object (code, message, exit code, pointer, rule, remediation).
+fn report_error(e: &magicrune::MagicruneError, json: bool) {
+    if json {
+        eprintln!("{}", serde_json::to_string(&e.report()).expect("serialize"));
+    } else {
+        eprintln!("{}", e);
+    }
 }
 
-impl Default for Thresholds {
-    fn default() -> Self {
-        Self {
-            green: "<=20".to_string(),
-            yellow: "21..=60".to_string(),
-            red: ">=61".to_string(),
-        }
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<String>>();
+
+    // The benchmark measures the pipeline only; per-run logging would dominate it.
+    if args.first().map(|a| a == "bench").unwrap_or(false) {
+        bench_entry(&args[1..]);
+        return;
     }
-}
 
-// Minimal YAML value extractor (line-oriented). Assumes keys are unique.
-fn extract_yaml_scalar_under(content: &str, section: &str, key: &str) -> Option<String> {
-    let mut in_section = false;
-    let mut section_indent: Option<usize> = None;
-    for line in content.lines() {
-        let raw = line;
-        let trimmed = raw.trim_end();
-        let indent = raw.chars().take_while(|c| c.is_whitespace()).count();
-        if trimmed.trim_start().starts_with('#') {
-            continue;
-        }
-        if trimmed.trim() == format!("{}:", section) {
-            in_section = true;
-            section_indent = Some(indent);
-            continue;
-        }
-        if in_section {
-            // If indentation drops back to or above section start, section ends
-            if let Some(si) = section_indent {
-                if indent <= si && !trimmed.trim().is_empty() {
-                    in_section = false;
-                }
-            }
-            if in_section {
-                let t = trimmed.trim();
-                if let Some(rest0) = t.strip_prefix(key) {
-                    let rest = rest0.trim();
-                    let val = rest.trim_start_matches(':').trim();
-                    return Some(val.trim_matches('"').to_string());
-                }
-            }
-        }
+    // Initialize observability first
+    if let Err(e) = init_observability() {
+        eprintln!("Failed to initialize observability: {}", e);
     }
-    None
-}
 
-fn load_thresholds_from_policy(path: &str) -> Thresholds {
-    let text = match std::fs::read_to_string(path) {
-        Ok(s) => s,
-        Err(_) => return Thresholds::default(),
-    };
-    // Look specifically under grading -> thresholds
-    let green = extract_yaml_scalar_under(&text, "thresholds", "green")
-        .or_else(|| extract_yaml_scalar_under(&text, "grading", "green"))
-        .unwrap_or_else(|| "<=20".to_string());
-    let yellow = extract_yaml_scalar_under(&text, "thresholds", "yellow")
-        .or_else(|| extract_yaml_scalar_under(&text, "grading", "yellow"))
-        .unwrap_or_else(|| "21..=60".to_string());
-    let red = extract_yaml_scalar_under(&text, "thresholds", "red")
-        .or_else(|| extract_yaml_scalar_under(&text, "grading", "red"))
-        .unwrap_or_else(|| ">=61".to_string());
-    Thresholds { green, yellow, red }
-}
+    if args.is_empty() || args[0] == "-h" || args[0] == "--help" {
+        print_usage();
+        shutdown_observability();
+        std::process::exit(0);
+    }
 
-#[derive(Debug, Clone, Copy)]
-struct PolicyLimits {
-    wall_sec: u64,
-    #[allow(dead_code)]
-    cpu_ms: u64,
-    #[allow(dead_code)]
-    memory_mb: u64,
-    #[allow(dead_code)]
-    pids: u64,
-}
+    if args[0] == "--version" {
+        println!("magicrune 0.1.0");
+        shutdown_observability();
+        std::process::exit(0);
+    }
 
-impl Default for PolicyLimits {
-    fn default() -> Self {
-        Self {
-            wall_sec: 60,
-            cpu_ms: 5000,
-            memory_mb: 512,
-            pids: 256,
-        }
+    if args[0] == "gc" {
+        let code = gc_entry(&args[1..]);
+        shutdown_observability();
+        std::process::exit(code);
     }
-}
 
-fn extract_yaml_u64_under(content: &str, section: &str, key: &str) -> Option<u64> {
-    let mut in_section = false;
-    let mut section_indent: Option<usize> = None;
-    for line in content.lines() {
-        let raw = line;
-        let trimmed = raw.trim_end();
-        let indent = raw.chars().take_while(|c| c.is_whitespace()).count();
-        if trimmed.trim_start().starts_with('#') {
-            continue;
-        }
-        if trimmed.trim() == format!("{}:", section) {
-            in_section = true;
-            section_indent = Some(indent);
-            continue;
-        }
-        if in_section {
-            if let Some(si) = section_indent {
-                if indent <= si && !trimmed.trim().is_empty() {
-                    in_section = false;
-                }
-            }
-            if in_section {
-                let t = trimmed.trim();
-                if let Some(rest0) = t.strip_prefix(key) {
-                    let rest = rest0.trim();
-                    let val = rest.trim_start_matches(':').trim();
-                    if let Ok(v) = u64::from_str(val.trim_matches('"')) {
-                        return Some(v);
-                    }
-                }
-            }
-        }
+    if args[0] == "doctor" {
+        let code = doctor_entry(&args[1..]);
+        shutdown_observability();
+        std::process::exit(code);
     }
-    None
-}
 
-fn load_limits_from_policy(path: &str) -> PolicyLimits {
-    let text = match std::fs::read_to_string(path) {
-        Ok(s) => s,
-        Err(_) => return PolicyLimits::default(),
-    };
-    let wall_sec = extract_yaml_u64_under(&text, "limits", "wall_sec").unwrap_or(60);
-    let cpu_ms = extract_yaml_u64_under(&text, "limits", "cpu_ms").unwrap_or(5000);
-    let memory_mb = extract_yaml_u64_under(&text, "limits", "memory_mb").unwrap_or(512);
-    let pids = extract_yaml_u64_under(&text, "limits", "pids").unwrap_or(256);
-    PolicyLimits {
-        wall_sec,
-        cpu_ms,
-        memory_mb,
-        pids,
+    if args[0] == "ledger" {
+        let code = ledger_entry(&args[1..]);
+        shutdown_observability();
+        std::process::exit(code);
     }
-}
 
-// Minimal YAML walker to extract capabilities.net.allow host[:port] entries
-fn load_net_allow_from_policy(path: &str) -> Vec<String> {
-    let text = match std::fs::read_to_string(path) {
-        Ok(s) => s,
-        Err(_) => return vec![],
-    };
-    let mut out = Vec::new();
-    let mut in_caps = false;
-    let mut in_net = false;
-    let mut in_allow = false;
-    let mut caps_indent = 0usize;
-    let mut net_indent = 0usize;
-    let mut allow_indent = 0usize;
-    for raw in text.lines() {
-        let indent = raw.chars().take_while(|c| c.is_whitespace()).count();
-        let line = raw.trim();
-        if line.starts_with('#') || line.is_empty() {
-            continue;
-        }
-        if !in_caps && line == "capabilities:" {
-            in_caps = true;
-            caps_indent = indent;
-            continue;
-        }
-        if in_caps {
-            if indent <= caps_indent {
-                in_caps = false;
-                in_net = false;
-                in_allow = false;
-            }
-            if !in_net && line == "net:" {
-                in_net = true;
-                net_indent = indent;
-                continue;
-            }
-            if in_net {
-                if indent <= net_indent {
-                    in_net = false;
-                    in_allow = false;
-                }
-                if !in_allow && line == "allow:" {
-                    in_allow = true;
-                    allow_indent = indent;
-                    continue;
-                }
-                if in_allow {
-                    if indent <= allow_indent {
-                        in_allow = false;
-                    }
-                    if line.starts_with("- ") {
-                        let item = line.trim_start_matches("- ").trim();
-                        // Support multiple forms:
-                        // - host: "example.com:443" (keyed form)
-                        // - addr: "example.com:443" (keyed form)
-                        // - "example.com:443" (simple string form)
-                        if let Some((key, val)) = item.split_once(": ") {
-                            if key == "host" || key == "addr" {
-                                let v = val.trim().trim_matches('"');
-                                if !v.is_empty() {
-                                    out.push(v.to_string());
-                                }
-                            }
-                        } else {
-                            let v = item.trim().trim_matches('"');
-                            if !v.is_empty() {
-                                out.push(v.to_string());
-                            }
-                        }
-                    }
-                }
-            }
-        }
+    if args[0] == "stats" {
+        let code = stats_entry(&args[1..]);
+        shutdown_observability();
+        std::process::exit(code);
     }
-    out
-}
 
-// Extract http/https host[:port] occurrences from a command line string
-fn extract_http_hosts(cmd: &str) -> Vec<String> {
-    let mut out = Vec::new();
-    for scheme in ["http://", "https://"].iter() {
-        let mut i = 0usize;
-        while let Some(pos) = cmd[i..].find(scheme) {
-            let start = i + pos + scheme.len();
-            let rest = &cmd[start..];
-            // host[:port] until first '/' or space
-            let end = rest
-                .find(|c: char| c == '/' || c.is_whitespace())
-                .unwrap_or(rest.len());
-            let hostport = &rest[..end];
-            if !hostport.is_empty() {
-                let default_port = if *scheme == "https://" { "443" } else { "80" };
-                let (h, p) = hostport_parts(hostport);
-                let hp = if p.is_none() {
-                    format!("{}:{}", h, default_port)
-                } else {
-                    hostport.to_string()
-                };
-                out.push(hp);
-            }
-            i = start + end;
-        }
+    if args[0] == "repro" {
+        let code = repro_entry(&args[1..]);
+        shutdown_observability();
+        std::process::exit(code);
     }
-    out
-}
 
-fn hostport_parts(s: &str) -> (std::borrow::Cow<str>, Option<&str>) {
-    let st = s.trim();
-    if let Some(rest) = st.strip_prefix('[') {
-        if let Some(pos) = rest.find(']') {
-            let host = &rest[..pos];
-            let after = &rest[pos + 1..];
-            if let Some(p) = after.strip_prefix(':') {
-                return (std::borrow::Cow::Owned(host.to_string()), Some(p));
-            }
-            return (std::borrow::Cow::Owned(host.to_string()), None);
-        }
+    if args[0] == "shell" {
+        let code = shell_entry(&args[1..]);
+        shutdown_observability();
+        std::process::exit(code);
     }
-    if let Some((h, p)) = st.rsplit_once(':') {
-        if !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()) {
-            return (std::borrow::Cow::Owned(h.to_string()), Some(p));
-        }
+
+    // Long-running modes clean up after crashed runs, at startup and periodically,
+    // and compile the schemas once up front.
+    if args[0] == "worker" || args[0] == "consume" {
+        magicrune::gc::start(magicrune::gc::GcConfig::from_env());
+        engine::precompile_schemas();
     }
-    (std::borrow::Cow::Borrowed(st), None)
-}
 
-fn parse_port_spec(p: Option<&str>) -> (bool, Option<(u16, u16)>) {
-    if let Some(ps) = p {
-        if ps == "*" {
-            return (true, None);
-        }
-        if let Some((a, b)) = ps.split_once('-') {
-            if let (Ok(x), Ok(y)) = (a.parse(), b.parse()) {
-                return (false, Some((x, y)));
-            }
-        }
-        if let Ok(x) = ps.parse::<u16>() {
-            return (false, Some((x, x)));
-        }
+    if args[0] == "enqueue" || args[0] == "worker" {
+        let code = spool_entry(&args);
+        shutdown_observability();
+        std::process::exit(code);
     }
-    (false, None)
-}
 
-fn parse_cidr(host: &str) -> Option<(std::net::IpAddr, u8)> {
-    if let Some((ip, pre)) = host.split_once('/') {
-        if let (Ok(addr), Ok(p)) = (ip.parse::<std::net::IpAddr>(), pre.parse::<u8>()) {
-            return Some((addr, p));
-        }
+    if args[0] == "keys" {
+        let code = keys_entry(&args[1..]);
+        shutdown_observability();
+        std::process::exit(code);
     }
-    None
-}
 
-fn ip_in_cidr(ip: std::net::IpAddr, cidr: (std::net::IpAddr, u8)) -> bool {
-    match (ip, cidr.0) {
-        (std::net::IpAddr::V4(a), std::net::IpAddr::V4(n)) => {
-            let a = u32::from(a);
-            let n = u32::from(n);
-            let p = cidr.1;
-            if p == 0 {
-                return true;
-            }
-            let mask = if p == 32 {
-                u32::MAX
-            } else {
-                (!0u32) << (32 - p as u32)
-            };
-            (a & mask) == (n & mask)
-        }
-        (std::net::IpAddr::V6(a), std::net::IpAddr::V6(n)) => {
-            let a = u128::from(a);
-            let n = u128::from(n);
-            let p = cidr.1;
-            if p == 0 {
-                return true;
-            }
-            let mask: u128 = if p == 128 {
-                u128::MAX
-            } else {
-                (!0u128) << (128 - p as u32)
-            };
-            (a & mask) == (n & mask)
-        }
-        _ => false,
+    if args[0] == "schema" {
+        let code = schema_entry(&args[1..]);
+        shutdown_observability();
+        std::process::exit(code);
     }
-}
 
-fn allowed_match(host: &str, port: Option<&str>, allow: &str) -> bool {
-    // CIDR
-    if let Some((net, pre)) = parse_cidr(allow) {
-        if let Ok(ip) = host.parse::<std::net::IpAddr>() {
-            if ip_in_cidr(ip, (net, pre)) {
-                return true;
-            }
-        }
-        return false;
+    if args[0] == "verify" {
+        let code = verify_entry(&args[1..]);
+        shutdown_observability();
+        std::process::exit(code);
     }
-    // wildcard / exact host patterns with optional port or ranges
-    let (a_host_port, a_ps) = hostport_parts(allow);
-    let (any_port, range) = parse_port_spec(a_ps);
-    let a_host = a_host_port.as_ref();
-    if let Some(suf) = a_host.strip_prefix("*.") {
-        if host.ends_with(suf) {
-            if any_port {
-                return true;
-            }
-            if let (Some((lo, hi)), Some(p)) = (range, port.and_then(|x| x.parse::<u16>().ok())) {
-                return p >= lo && p <= hi;
+
+    if args[0] == "consume" {
+        // Non-NATS brokers go through the generic transport loop.
+        let transport = args
+            .iter()
+            .position(|a| a == "--transport")
+            .and_then(|i| args.get(i + 1).cloned())
+            .or_else(|| env::var("MAGICRUNE_TRANSPORT").ok())
+            .filter(|t| !t.starts_with("nats://"));
+        let health_addr = args
+            .iter()
+            .position(|a| a == "--health-addr")
+            .and_then(|i| args.get(i + 1).cloned())
+            .or_else(|| env::var("MAGICRUNE_HEALTH_ADDR").ok())
+            .filter(|a| !a.is_empty());
+        let instance = args
+            .iter()
+            .position(|a| a == "--instance-id")
+            .and_then(|i| args.get(i + 1).cloned())
+            .or_else(magicrune::instance::from_env);
+        if let Some(id) = &instance {
+            if let Err(e) = magicrune::instance::check_id(id) {
+                eprintln!("consume error: {}", e);
+                std::process::exit(4);
             }
-            return range.is_none();
+            magicrune::metrics::set_instance(id);
         }
-    }
-    if a_host == host {
-        if any_port {
-            return true;
-        }
-        if let (Some((lo, hi)), Some(p)) = (range, port.and_then(|x| x.parse::<u16>().ok())) {
-            return p >= lo && p <= hi;
-        }
-        return range.is_none();
-    }
-    // IPv6 literal allow entry without brackets
-    if a_host.starts_with('[') && a_host.ends_with(']') {
-        let inner = &a_host[1..a_host.len() - 1];
-        if inner == host {
-            return true;
+        let shadow = args
+            .iter()
+            .position(|a| a == "--shadow-policy")
+            .and_then(|i| args.get(i + 1).cloned())
+            .map(ShadowPolicy::new)
+            .or_else(ShadowPolicy::from_env);
+        if let Err(e) = magicrune::audit::from_env() {
+            eprintln!("consume error: {}", e);
+            std::process::exit(4);
         }
-    }
-    false
-}
-
-// Very small YAML walker to extract capabilities.fs.allow path entries
-fn load_fs_allow_from_policy(path: &str) -> Vec<String> {
-    let text = match std::fs::read_to_string(path) {
-        Ok(s) => s,
-        Err(_) => return vec![],
-    };
-    let mut out = Vec::new();
-    let mut in_caps = false;
-    let mut in_fs = false;
-    let mut in_allow = false;
-    let mut caps_indent = 0usize;
-    let mut fs_indent = 0usize;
-    let mut allow_indent = 0usize;
-    for raw in text.lines() {
-        let indent = raw.chars().take_while(|c| c.is_whitespace()).count();
-        let line = raw.trim();
-        if line.starts_with('#') || line.is_empty() {
-            continue;
+        if let Err(e) = magicrune::notify::NotifyConfig::from_env() {
+            eprintln!("consume error: {}", e);
+            std::process::exit(4);
         }
-        if !in_caps && line == "capabilities:" {
-            in_caps = true;
-            caps_indent = indent;
-            continue;
+        if let Some(s) = &shadow {
+            if let Err(e) = Policy::load(s.path()) {
+                eprintln!("consume error: shadow policy {}: {}", s.path(), e);
+                std::process::exit(4);
+            }
         }
-        if in_caps {
-            if indent <= caps_indent {
-                in_caps = false;
-                in_fs = false;
-                in_allow = false;
+        let labels = args
+            .iter()
+            .position(|a| a == "--labels")
+            .and_then(|i| args.get(i + 1))
+            .map(|l| magicrune::instance::parse_labels(l))
+            .unwrap_or_else(magicrune::instance::labels_from_env);
+        let labels = match labels {
+            Ok(l) => l,
+            Err(e) => {
+                eprintln!("consume error: {}", e);
+                std::process::exit(4);
             }
-            if !in_fs && line == "fs:" {
-                in_fs = true;
-                fs_indent = indent;
-                continue;
+        };
+        let concurrency = args
+            .iter()
+            .position(|a| a == "--concurrency")
+            .and_then(|i| args.get(i + 1))
+            .map(|c| c.parse())
+            .unwrap_or_else(magicrune::concurrency::Concurrency::from_env);
+        let concurrency = match concurrency {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("consume error: {}", e);
+                std::process::exit(4);
             }
-            if in_fs {
-                if indent <= fs_indent {
-                    in_fs = false;
-                    in_allow = false;
-                }
-                if !in_allow && line == "allow:" {
-                    in_allow = true;
-                    allow_indent = indent;
-                    continue;
+        };
+        if let Some(url) = transport {
+            let code = match transport_entry(
+                &url,
+                health_addr.as_deref(),
+                instance.as_deref(),
+                shadow,
+                labels,
+                concurrency,
+            ) {
+                Ok(()) => 0,
+                Err(e) => {
+                    eprintln!("consume error: {}", e);
+                    4
                 }
-                if in_allow {
-                    if indent <= allow_indent {
-                        in_allow = false;
-                    }
-                    if line.starts_with("- ") {
-                        // expect '- path: "..."'
-                        if let Some(rest) = line.trim_start_matches("- ").strip_prefix("path:") {
-                            let v = rest.trim().trim_start_matches(':').trim().trim_matches('"');
-                            if !v.is_empty() {
-                                out.push(v.to_string());
-                            }
-                        }
-                    }
+            };
+            shutdown_observability();
+            std::process::exit(code);
+        }
+        let cfg =
+            match magicrune::jet::config::ConsumerConfig::from_args(&args, instance.as_deref()) {
+                Ok(cfg) => cfg,
+                Err(e) => {
+                    eprintln!("consume error: {}", e);
+                    std::process::exit(4);
                 }
+            };
+        let policy_path = env::var("MAGICRUNE_POLICY")
+            .unwrap_or_else(|_| "policies/default.policy.yml".to_string());
+        let checked = Policy::load(&policy_path)
+            .map_err(|e| e.to_string())
+            .inspect(|p| warn_missing_labels(p, &cfg.labels))
+            .and_then(|p| cfg.validate(&p.limits));
+        if args.iter().any(|a| a == "--show-config") {
+            println!("{}", serde_json::to_string_pretty(&cfg).unwrap_or_default());
+            if let Err(e) = checked {
+                eprintln!("consume error: {}", e);
+                std::process::exit(4);
             }
+            return;
         }
-    }
-    out
-}
-
-// Parse range expressions like "<=20", "21..=60", ">=61" and decide verdict.
-fn decide_verdict_from_thresholds(score: u32, th: &Thresholds) -> &'static str {
-    fn matches(expr: &str, n: u32) -> bool {
-        let e = expr.trim();
-        if let Some(rest) = e.strip_prefix("<=") {
-            if let Ok(v) = u32::from_str(rest.trim()) {
-                return n <= v;
-            }
-        }
-        if let Some(rest) = e.strip_prefix(">=") {
-            if let Ok(v) = u32::from_str(rest.trim()) {
-                return n >= v;
-            }
+        if let Err(e) = checked {
+            eprintln!("consume error: {}", e);
+            std::process::exit(4);
         }
-        if let Some((a, b)) = e.split_once("..=") {
-            if let (Ok(x), Ok(y)) = (u32::from_str(a.trim()), u32::from_str(b.trim())) {
-                return n >= x && n <= y;
-            }
+        if concurrency != magicrune::concurrency::Concurrency::default() {
+            tracing::warn!(
+                %concurrency,
+                "consume: NATS requests run one at a time; --concurrency applies to --transport"
+            );
         }
-        false
-    }
-    // Touch `red` to avoid dead-code on the field when thresholds default is used
-    let _ = &th.red;
-    if matches(&th.green, score) {
-        "green"
-    } else if matches(&th.yellow, score) {
-        "yellow"
-    } else {
-        "red"
-    }
-}
-
-fn main() {
-    // Initialize observability first
-    if let Err(e) = init_observability() {
-        eprintln!("Failed to initialize observability: {}", e);
-    }
-
-    let args = env::args().skip(1).collect::<Vec<String>>();
-    if args.is_empty() || args[0] == "-h" || args[0] == "--help" {
-        print_usage();
-        shutdown_observability();
-        std::process::exit(0);
-    }
-
-    if args[0] == "--version" {
-        println!("magicrune 0.1.0");
-        shutdown_observability();
-        std::process::exit(0);
-    }
-
-    if args[0] == "consume" {
         // JetStream consumer mode (feature-gated)
         #[cfg(feature = "jet")]
         {
-            let url = args
-                .iter()
-                .position(|a| a == "--url")
-                .and_then(|i| args.get(i + 1).cloned())
-                .unwrap_or_else(|| {
-                    env::var("NATS_URL").unwrap_or_else(|_| "127.0.0.1:4222".to_string())
-                });
-            let subject = args
-                .iter()
-                .position(|a| a == "--subject")
-                .and_then(|i| args.get(i + 1).cloned())
-                .unwrap_or_else(|| {
-                    env::var("NATS_REQ_SUBJ").unwrap_or_else(|_| "run.req.default".to_string())
-                });
-            if let Err(e) = consume_entry(&url, &subject) {
+            if let Err(e) = consume_entry(&cfg, health_addr.as_deref(), shadow) {
                 eprintln!("consume error: {}", e);
                 std::process::exit(4);
             }
@@ -688,10 +286,21 @@ fn main() {
     // Defaults
     let mut in_path: Option<String> = None;
     let mut out_path: Option<String> = None;
-    let mut _policy_path: Option<String> = None; // default: policies/default.policy.yml
+    // Several only with `--compare`; default: policies/default.policy.yml
+    let mut policy_paths: Vec<String> = Vec::new();
     let mut _timeout: Option<u64> = None; // accepted but not enforced here
     let mut _seed: Option<u64> = None;
     let mut strict = false;
+    let mut seccomp_learn = false;
+    // `--decode lossy`: print stdout/stderr as text instead of base64.
+    let mut decode_lossy = false;
+    let mut interactive = false;
+    let mut watch = false;
+    let mut compare = false;
+    let mut dry_run = false;
+    let mut offline = false;
+    let mut errors_json = false;
+    let mut vars = template::Vars::new();
 
     // Parse flags
     let mut i = 1usize;
@@ -707,7 +316,7 @@ fn main() {
             }
             "--policy" => {
                 i += 1;
-                _policy_path = args.get(i).cloned();
+                policy_paths.extend(args.get(i).cloned());
             }
             "--timeout" => {
                 i += 1;
@@ -720,6 +329,61 @@ fn main() {
             "--strict" => {
                 strict = true;
             }
+            "--seccomp-learn" => {
+                seccomp_learn = true;
+            }
+            "--interactive" => {
+                interactive = true;
+            }
+            "--watch" => {
+                watch = true;
+            }
+            "--compare" => {
+                compare = true;
+            }
+            "--dry-run" => {
+                dry_run = true;
+            }
+            "--offline" => {
+                offline = true;
+            }
+            "--var" => {
+                i += 1;
+                match args.get(i).and_then(|a| template::parse_var(a)) {
+                    Some((k, v)) => {
+                        vars.insert(k, v);
+                    }
+                    None => {
+                        eprintln!("--var expects <key=value>");
+                        print_usage();
+                        std::process::exit(4);
+                    }
+                }
+            }
+            "--decode" => {
+                i += 1;
+                decode_lossy = match args.get(i).map(String::as_str) {
+                    Some("lossy") => true,
+                    Some("base64") => false,
+                    _ => {
+                        eprintln!("--decode expects base64 or lossy");
+                        print_usage();
+                        std::process::exit(4);
+                    }
+                };
+            }
+            "--errors" => {
+                i += 1;
+                errors_json = match args.get(i).map(String::as_str) {
+                    Some("json") => true,
+                    Some("text") => false,
+                    _ => {
+                        eprintln!("--errors expects text or json");
+                        print_usage();
+                        std::process::exit(4);
+                    }
+                };
+            }
             other if other.starts_with('-') => {
                 eprintln!("unknown flag: {}", other);
                 print_usage();
@@ -746,525 +410,1422 @@ fn main() {
             std::process::exit(1);
         }
     };
-
-    let req_val: serde_json::Value = match serde_json::from_slice(&raw) {
-        Ok(v) => v,
+    let raw = match bundle::expand(raw) {
+        Ok(b) => b,
+        Err(e) if errors_json => {
+            report_error(&e, true);
+            std::process::exit(e.exit_code());
+        }
         Err(e) => {
-            eprintln!("Invalid JSON in {}: {}", in_path, e);
-            std::process::exit(1);
+            eprintln!("{}: {}", in_path, e);
+            std::process::exit(e.exit_code());
         }
     };
 
-    // Also deserialize to typed struct for grading
-    let req: SpellRequest = match serde_json::from_slice(&raw) {
-        Ok(r) => r,
+    if compare {
+        if interactive || watch || seccomp_learn {
+            eprintln!(
+                "--compare cannot be combined with --interactive, --watch or --seccomp-learn"
+            );
+            shutdown_observability();
+            std::process::exit(4);
+        }
+        let code = compare_entry(&raw, &policy_paths, out_path.as_deref(), {
+            let mut opts = ExecOptions {
+                strict,
+                seed: _seed,
+                vars,
+                ..ExecOptions::from_env()
+            };
+            opts.dry_run |= dry_run;
+            opts.offline |= offline;
+            opts
+        });
+        shutdown_observability();
+        std::process::exit(code);
+    }
+    if policy_paths.len() > 1 {
+        eprintln!("several --policy need --compare");
+        print_usage();
+        std::process::exit(4);
+    }
+    let policy_path = policy_paths
+        .pop()
+        .or_else(|| std::env::var("MAGICRUNE_POLICY").ok())
+        .unwrap_or_else(|| "policies/default.policy.yml".to_string());
+    let policy = match Policy::load(&policy_path) {
+        Ok(p) => p,
         Err(e) => {
-            eprintln!("Invalid request shape: {}", e);
-            std::process::exit(1);
+            report_error(&e.into(), errors_json);
+            std::process::exit(4);
         }
     };
+    tracing::info!(
+        policy = %policy_path,
+        policy_digest = %policy.digest,
+        wall_sec = policy.limits.wall_sec,
+        cpu_ms = policy.limits.cpu_ms,
+        memory_mb = policy.limits.memory_mb,
+        "policy loaded"
+    );
 
-    if strict {
-        // JSON Schema validation against schemas/spell_request.schema.json
-        let schema_path = Path::new("schemas/spell_request.schema.json");
-        if schema_path.exists() {
-            if let Ok(schema_txt) = std::fs::read_to_string(schema_path) {
-                let schema_json: serde_json::Value =
-                    serde_json::from_str(&schema_txt).unwrap_or(serde_json::json!({}));
-                if let Ok(compiled) = jsonschema::JSONSchema::options().compile(&schema_json) {
-                    let result = compiled.validate(&req_val);
-                    if let Err(errors) = result {
-                        for err in errors {
-                            eprintln!("schema: {}", err);
-                        }
-                        std::process::exit(1);
-                    }
-                }
-            }
-        }
-        // Manual structural validation aligned with schemas (no external crates)
-        fn is_string(v: &serde_json::Value) -> bool {
-            matches!(v, serde_json::Value::String(_))
-        }
-        fn is_number(v: &serde_json::Value) -> bool {
-            matches!(v, serde_json::Value::Number(_))
-        }
-        fn is_bool(v: &serde_json::Value) -> bool {
-            matches!(v, serde_json::Value::Bool(_))
+    let mut opts = ExecOptions {
+        strict,
+        seed: _seed,
+        vars,
+        ..ExecOptions::from_env()
+    };
+    opts.dry_run |= dry_run;
+    opts.offline |= offline;
+    if seccomp_learn {
+        if !cfg!(all(target_os = "linux", feature = "native_sandbox")) {
+            eprintln!("--seccomp-learn needs a Linux build with the native_sandbox feature");
+            shutdown_observability();
+            std::process::exit(4);
         }
-        let required = [
-            "cmd",
-            "stdin",
-            "env",
-            "files",
-            "policy_id",
-            "timeout_sec",
-            "allow_net",
-            "allow_fs",
-        ];
-        for k in required.iter() {
-            if req_val.get(*k).is_none() {
-                eprintln!("schema: missing key: {}", k);
-                std::process::exit(1);
-            }
+        opts.hardening.seccomp = true;
+        opts.hardening.seccomp_learn = true;
+    }
+    if interactive {
+        if !cfg!(all(target_os = "linux", feature = "linux_native")) {
+            eprintln!("--interactive needs a Linux build with the linux_native feature");
+            shutdown_observability();
+            std::process::exit(4);
         }
-        if !is_string(&req_val["cmd"]) {
-            eprintln!("schema: cmd must be string");
-            std::process::exit(1);
+        opts.interactive = true;
+    }
+    if watch {
+        if interactive {
+            eprintln!("--watch and --interactive cannot be combined");
+            shutdown_observability();
+            std::process::exit(4);
         }
-        if !is_string(&req_val["stdin"]) {
-            eprintln!("schema: stdin must be string");
-            std::process::exit(1);
+        watch_entry(&in_path, &policy_path, out_path.as_deref(), &opts);
+        shutdown_observability();
+        std::process::exit(0);
+    }
+    // The alert waits for the quarantine below.
+    let notifier = opts.notifier.take();
+    let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
+    let run = rt.block_on(engine::execute(&raw, &policy, &opts));
+    if let Some(ledger) = &opts.ledger {
+        rt.block_on(ledger.flush());
+    }
+    let run = match run {
+        Ok(run) => run,
+        Err(e) => {
+            report_error(&e, errors_json);
+            shutdown_observability();
+            std::process::exit(e.exit_code());
         }
-        if !req_val["env"].is_object() {
-            eprintln!("schema: env must be object");
-            std::process::exit(1);
-        }
-        for (_k, v) in req_val["env"].as_object().unwrap() {
-            if !(is_string(v) || is_number(v) || is_bool(v)) {
-                eprintln!("schema: env values must be string/number/bool");
-                std::process::exit(1);
+    };
+
+    let out_json = serde_json::to_string_pretty(&run.result).expect("serialize");
+    // The process exit code is derived from the verdict only.
+    let final_exit = run.verdict.exit_code();
+
+    if let Some(p) = out_path {
+        if let Some(dir) = Path::new(&p).parent() {
+            if !dir.as_os_str().is_empty() && !dir.exists() {
+                if let Err(e) = fs::create_dir_all(dir) {
+                    eprintln!("Failed to create output dir: {}", e);
+                    std::process::exit(4);
+                }
             }
         }
-        if !req_val["files"].is_array() {
-            eprintln!("schema: files must be array");
-            std::process::exit(1);
+        if let Err(e) = fs::write(&p, out_json.as_bytes()) {
+            eprintln!("Failed to write {}: {}", p, e);
+            std::process::exit(4);
+        }
+    } else if interactive {
+        // The session already went to the terminal; only the verdict follows.
+    } else if decode_lossy {
+        let text = serde_json::to_string_pretty(&run.result.to_lossy_json()).expect("serialize");
+        let _ = io::stdout().write_all(text.as_bytes());
+    } else {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(out_json.as_bytes());
+    }
+
+    if interactive {
+        eprintln!(
+            "\nverdict: {} (risk {}, exit {})",
+            run.result.verdict, run.result.risk_score, run.result.exit_code
+        );
+    }
+
+    if seccomp_learn {
+        eprintln!("# suggested policy snippet (seccomp learn mode):");
+        eprint!(
+            "{}",
+            magicrune::policy::suggest_syscalls_yaml(&policy.syscalls_allow, &run.result.findings)
+        );
+    }
+
+    if let Some(url) = &run.callback_url {
+        if let Err(e) = rt.block_on(magicrune::webhook::deliver(&opts.webhook, url, &run.result)) {
+            tracing::warn!(run_id = %run.result.run_id, error = %e, "callback failed");
         }
-        for f in req_val["files"].as_array().unwrap() {
-            if !f.is_object() {
-                eprintln!("schema: file entry must be object");
-                std::process::exit(1);
+    }
+
+    // Quarantine for red verdict (write result + captured stdout/stderr if any),
+    // one folder per tenant.
+    if run.verdict == Verdict::Red {
+        let qdir = magicrune::tenant::quarantine_dir(&run.tenant);
+        let qdir = qdir.as_path();
+        let _ = fs::create_dir_all(qdir);
+        let _ = fs::write(qdir.join("result.red.json"), out_json.as_bytes());
+        let _ = fs::write(qdir.join("stdout.txt"), &run.stdout);
+        let _ = fs::write(qdir.join("stderr.txt"), &run.stderr);
+        if let Some(sink) = &opts.audit {
+            let event = magicrune::audit::AuditEvent::quarantine(&run.result, &policy.digest, qdir);
+            magicrune::audit::record(sink.as_ref(), &event);
+        }
+        if let Some(n) = &notifier {
+            rt.block_on(n.notify(magicrune::notify::NotifyEvent::Quarantine, &run.result));
+        }
+    }
+
+    shutdown_observability();
+    std::process::exit(final_exit);
+}
+
+/// Serve `/healthz` and `/readyz` on `addr` (`--health-addr`); readiness
+/// waits for every check in `checks`.
+fn start_health(
+    addr: Option<&str>,
+    checks: &[&'static str],
+) -> anyhow::Result<Option<std::sync::Arc<magicrune::health::Health>>> {
+    let Some(addr) = addr else {
+        return Ok(None);
+    };
+    let health = magicrune::health::Health::new(checks);
+    let bound = health
+        .serve(addr)
+        .map_err(|e| anyhow::anyhow!("health: {}: {}", addr, e))?;
+    tracing::info!(addr = %bound, "health endpoints listening");
+    Ok(Some(health))
+}
+
+/// Say so up front when this consumer will hand back every request.
+fn warn_missing_labels(policy: &Policy, labels: &[String]) {
+    let missing = magicrune::instance::missing_labels(&policy.runner_labels, labels);
+    if !missing.is_empty() {
+        tracing::warn!(
+            hint = %magicrune::instance::routing_hint(&policy.runner_labels, &missing),
+            "consume: requests under this policy will be handed back"
+        );
+    }
+}
+
+/// `consume --transport <url>`: the broker-independent consume loop.
+fn transport_entry(
+    url: &str,
+    health_addr: Option<&str>,
+    instance: Option<&str>,
+    shadow: Option<ShadowPolicy>,
+    labels: Vec<String>,
+    concurrency: magicrune::concurrency::Concurrency,
+) -> anyhow::Result<()> {
+    let health = start_health(health_addr, &["transport", "policy"])?;
+    let policy_path =
+        env::var("MAGICRUNE_POLICY").unwrap_or_else(|_| "policies/default.policy.yml".to_string());
+    let policy = Policy::load(&policy_path)?;
+    warn_missing_labels(&policy, &labels);
+    if let Some(h) = &health {
+        h.set("policy", Path::new(&policy_path).is_file());
+    }
+    let mut opts = ExecOptions {
+        instance_id: instance.map(str::to_string),
+        ..ExecOptions::from_env()
+    };
+    opts.shadow = shadow.map(std::sync::Arc::new).or(opts.shadow);
+    opts.runner_labels = labels;
+    let dedupe_max = env_u64("MAGICRUNE_DEDUPE_MAX", 1024) as usize;
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async move {
+        let transport = magicrune::transport::connect(url).await?;
+        if let Some(h) = &health {
+            h.set("transport", true);
+        }
+        tracing::info!(%concurrency, "consume: concurrency");
+        let served = magicrune::transport::serve(
+            transport.as_ref(),
+            &policy,
+            &opts,
+            dedupe_max,
+            None,
+            concurrency,
+        )
+        .await;
+        if let Some(ledger) = &opts.ledger {
+            ledger.flush().await;
+        }
+        let stats = served?;
+        tracing::info!(
+            processed = stats.total,
+            dupes = stats.dupe,
+            reds = stats.red,
+            "consume: stopped"
+        );
+        Ok(())
+    })
+}
+
+/// `exec --compare`: run `raw` under each policy in `paths` and print the
+/// report (see `magicrune::compare`), or write it to `out`. Exits with the
+/// strictest outcome's code, 4 on unusable input.
+fn compare_entry(raw: &[u8], paths: &[String], out: Option<&str>, opts: ExecOptions) -> i32 {
+    if paths.len() < 2 {
+        eprintln!("--compare needs at least two --policy");
+        print_usage();
+        return 4;
+    }
+    let mut policies = Vec::with_capacity(paths.len());
+    for path in paths {
+        match Policy::load(path) {
+            Ok(p) => policies.push((path.clone(), p)),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                return 4;
             }
-            if !f.get("path").map(is_string).unwrap_or(false) {
-                eprintln!("schema: file.path must be string");
-                std::process::exit(1);
+        }
+    }
+    let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
+    let report = rt.block_on(magicrune::compare::compare(raw, &policies, &opts));
+    let json = serde_json::to_string_pretty(&report).expect("serialize");
+    match out {
+        Some(p) => {
+            if let Err(e) = fs::write(p, json.as_bytes()) {
+                eprintln!("Failed to write {}: {}", p, e);
+                return 4;
             }
-            if let Some(cb) = f.get("content_b64") {
-                if !is_string(cb) {
-                    eprintln!("schema: file.content_b64 must be string");
-                    std::process::exit(1);
+        }
+        None => println!("{}", json),
+    }
+    report.exit_code()
+}
+
+/// `schema dump <request | result>` prints a bundled JSON Schema; `schema
+/// check [--dir <schemas>]` compares the bundled schemas (or the files in
+/// `--dir`) with the Rust types and exits 1 on drift.
+fn schema_entry(args: &[String]) -> i32 {
+    use magicrune::schemas;
+    match args.first().map(String::as_str) {
+        Some("dump") => {
+            let file = match args.get(1).map(String::as_str) {
+                Some("request") => "spell_request.schema.json",
+                Some("result") => "spell_result.schema.json",
+                _ => {
+                    print_usage();
+                    return 4;
+                }
+            };
+            let (_, text) = schemas::BUNDLED
+                .iter()
+                .find(|(name, _)| *name == file)
+                .expect("bundled schema");
+            print!("{}", text);
+            0
+        }
+        Some("check") => {
+            let dir = match args.get(1).map(String::as_str) {
+                None => None,
+                Some("--dir") if args.len() == 3 => Some(Path::new(&args[2])),
+                _ => {
+                    print_usage();
+                    return 4;
+                }
+            };
+            let mut drift = 0;
+            for (name, bundled) in schemas::BUNDLED {
+                let text = match dir {
+                    None => bundled.to_string(),
+                    Some(dir) => match fs::read_to_string(dir.join(name)) {
+                        Ok(t) => t,
+                        Err(e) => {
+                            eprintln!("{}: {}", dir.join(name).display(), e);
+                            return 4;
+                        }
+                    },
+                };
+                let issues = schemas::check(name, &text);
+                for i in &issues {
+                    eprintln!("{}", i);
+                }
+                if issues.is_empty() {
+                    println!("ok {}", name);
                 }
+                drift += issues.len();
             }
+            i32::from(drift > 0)
         }
-        if !is_string(&req_val["policy_id"]) {
-            eprintln!("schema: policy_id must be string");
-            std::process::exit(1);
+        _ => {
+            print_usage();
+            4
         }
-        if !req_val["timeout_sec"].is_i64() && !req_val["timeout_sec"].is_u64() {
-            eprintln!("schema: timeout_sec must be integer");
-            std::process::exit(1);
+    }
+}
+
+/// `verify <result.json> --key <pub.pem | keyring.json>`: check a result's
+/// signature. Exits 0 when it matches, 1 when it does not (or is missing), 4
+/// on unusable input.
+fn verify_entry(args: &[String]) -> i32 {
+    let mut path: Option<String> = None;
+    let mut key_path: Option<String> = None;
+    let mut i = 0usize;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--key" => {
+                i += 1;
+                key_path = args.get(i).cloned();
+            }
+            other if other.starts_with('-') => {
+                eprintln!("unknown flag: {}", other);
+                print_usage();
+                return 4;
+            }
+            other => path = Some(other.to_string()),
         }
-        let t = req_val["timeout_sec"]
-            .as_i64()
-            .unwrap_or_else(|| req_val["timeout_sec"].as_u64().unwrap_or(0) as i64);
-        if !(0..=60).contains(&t) {
-            eprintln!("schema: timeout_sec must be 0..=60");
-            std::process::exit(1);
+        i += 1;
+    }
+    let (Some(path), Some(key_path)) = (path, key_path) else {
+        print_usage();
+        return 4;
+    };
+    let keyring = match Keyring::from_file(&key_path) {
+        Ok(k) => k,
+        Err(e) => {
+            eprintln!("verify: {}", e);
+            return 4;
         }
-        if !req_val["allow_net"].is_array() {
-            eprintln!("schema: allow_net must be array");
-            std::process::exit(1);
+    };
+    let raw = match fs::read(&path) {
+        Ok(b) => b,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", path, e);
+            return 4;
         }
-        if !req_val["allow_fs"].is_array() {
-            eprintln!("schema: allow_fs must be array");
-            std::process::exit(1);
+    };
+    let res: magicrune::schema::SpellResult = match serde_json::from_slice(&raw) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Invalid JSON: {}", e);
+            return 4;
+        }
+    };
+    match keyring.verify_result(&res) {
+        Ok(key) => {
+            println!("ok {} {} kid={}", res.run_id, res.verdict, key.kid);
+            0
+        }
+        Err(e) => {
+            eprintln!("verify: {}: {}", path, e);
+            1
         }
     }
+}
 
-    // Deterministic run_id from request bytes + seed (SPEC: same request+seed => stable)
-    let mut seed_buf = Vec::new();
-    if let Some(s) = _seed {
-        seed_buf.extend_from_slice(&s.to_le_bytes());
-    }
-    let mut all = raw.clone();
-    all.extend_from_slice(&seed_buf);
-    let run_id = format!("r_{}", sha256_hex(&all));
-
-    // Create execution context for observability
-    let ctx = ExecutionContext::new(run_id.clone(), req.policy_id.clone());
-    let _span = ctx.span();
-    let _enter = _span.enter();
-
-    // Minimal static grading (policy thresholds aware):
-    // - if cmd suggests network and allow_net empty -> +40 (yellow)
-    // - if cmd contains 'ssh' -> +30
-    let cmd_l = req.cmd.to_lowercase();
-    let mut risk_score: u32 = 0;
-    let net_intent = cmd_l.contains("curl ")
-        || cmd_l.contains("wget ")
-        || cmd_l.contains("http://")
-        || cmd_l.contains("https://");
-    // Early policy enforcement
-    let policy_path = _policy_path
-        .or_else(|| std::env::var("MAGICRUNE_POLICY").ok())
-        .unwrap_or_else(|| "policies/default.policy.yml".to_string());
-    let limits = load_limits_from_policy(&policy_path);
+/// `keys generate` writes a new key pair and prints its key id; `keys inspect`
+/// lists the keys in a file, or the keyring configured in the environment.
+fn keys_entry(args: &[String]) -> i32 {
+    match args.first().map(String::as_str) {
+        Some("generate") => {
+            let mut out: Option<String> = None;
+            let mut public: Option<String> = None;
+            let mut i = 1usize;
+            while i < args.len() {
+                match args[i].as_str() {
+                    "--out" => {
+                        i += 1;
+                        out = args.get(i).cloned();
+                    }
+                    "--pub" => {
+                        i += 1;
+                        public = args.get(i).cloned();
+                    }
+                    other => {
+                        eprintln!("unknown flag: {}", other);
+                        print_usage();
+                        return 4;
+                    }
+                }
+                i += 1;
+            }
+            let Some(out) = out else {
+                eprintln!("Missing --out <key.pem>");
+                return 1;
+            };
+            let (private_pem, public_pem, kid) = match keys::generate() {
+                Ok(k) => k,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return 4;
+                }
+            };
+            // The private key is readable by its owner only.
+            let mut open = fs::OpenOptions::new();
+            open.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                open.mode(0o600);
+            }
+            let written = open
+                .open(&out)
+                .and_then(|mut f| f.write_all(private_pem.as_bytes()))
+                .and_then(|_| match &public {
+                    Some(p) => fs::write(p, public_pem.as_bytes()),
+                    None => Ok(()),
+                });
+            if let Err(e) = written {
+                eprintln!("Failed to write {}: {}", out, e);
+                return 4;
+            }
+            println!("{}", kid);
+            0
+        }
+        Some("inspect") => {
+            let ring = match args.get(1) {
+                Some(path) => Keyring::from_file(path),
+                None => Keyring::from_env(),
+            };
+            let ring = match ring {
+                Ok(r) => r,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return 4;
+                }
+            };
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let active = ring.active(now);
+            for k in ring.keys() {
+                let status = k.status(now);
+                println!(
+                    "{} {} {} not_before={} not_after={}{}",
+                    k.kid,
+                    if k.signing.is_some() {
+                        "private"
+                    } else {
+                        "public"
+                    },
+                    status.as_str(),
+                    k.not_before
+                        .map(|t| t.to_string())
+                        .unwrap_or_else(|| "-".into()),
+                    k.not_after
+                        .map(|t| t.to_string())
+                        .unwrap_or_else(|| "-".into()),
+                    if active.map(|a| std::ptr::eq(a, k)).unwrap_or(false) {
+                        " active"
+                    } else {
+                        ""
+                    }
+                );
+            }
+            0
+        }
+        Some("sign-request") => {
+            let mut path: Option<String> = None;
+            let mut key_path: Option<String> = None;
+            let mut i = 1usize;
+            while i < args.len() {
+                match args[i].as_str() {
+                    "--key" => {
+                        i += 1;
+                        key_path = args.get(i).cloned();
+                    }
+                    other if other.starts_with('-') => {
+                        eprintln!("unknown flag: {}", other);
+                        print_usage();
+                        return 4;
+                    }
+                    other => path = Some(other.to_string()),
+                }
+                i += 1;
+            }
+            let (Some(path), Some(key_path)) = (path, key_path) else {
+                print_usage();
+                return 4;
+            };
+            let key = match fs::read_to_string(&key_path)
+                .map_err(|e| format!("{}: {}", key_path, e))
+                .and_then(|pem| keys::KeyEntry::from_pem(&pem).map_err(|e| e.to_string()))
+            {
+                Ok(keys::KeyEntry {
+                    signing: Some(key), ..
+                }) => key,
+                Ok(_) => {
+                    eprintln!("{}: not a private key", key_path);
+                    return 4;
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return 4;
+                }
+            };
+            let mut req: serde_json::Map<String, serde_json::Value> =
+                match fs::read(&path).map(|raw| serde_json::from_slice(&raw)) {
+                    Ok(Ok(req)) => req,
+                    Ok(Err(e)) => {
+                        eprintln!("{}: {}", path, e);
+                        return 1;
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to read {}: {}", path, e);
+                        return 1;
+                    }
+                };
+            magicrune::signing::sign_request(&key, &mut req);
+            println!("{}", serde_json::Value::Object(req));
+            0
+        }
+        _ => {
+            print_usage();
+            4
+        }
+    }
+}
+
+/// `doctor`: probe the host and print a PASS/WARN/FAIL table (see
+/// `magicrune::doctor`). Exits 1 when any probe fails.
+fn doctor_entry(args: &[String]) -> i32 {
+    use magicrune::doctor::{self, DoctorConfig, Status};
+    let mut cfg = DoctorConfig::from_env();
+    let mut i = 0usize;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--policy" => {
+                i += 1;
+                match args.get(i) {
+                    Some(p) => cfg.policy_path = p.clone(),
+                    None => {
+                        eprintln!("--policy needs a path");
+                        return 1;
+                    }
+                }
+            }
+            "--url" => {
+                i += 1;
+                match args.get(i) {
+                    Some(u) => {
+                        cfg.nats_url = u.clone();
+                        cfg.nats_required = true;
+                    }
+                    None => {
+                        eprintln!("--url needs <nats_host:port>");
+                        return 1;
+                    }
+                }
+            }
+            other => {
+                eprintln!("unknown flag: {}", other);
+                print_usage();
+                return 4;
+            }
+        }
+        i += 1;
+    }
+    let checks = doctor::run(&cfg);
+    print!("{}", doctor::render(&checks));
+    match doctor::overall(&checks) {
+        Status::Fail => 1,
+        _ => 0,
+    }
+}
+
+/// `shell`: a prompt where every line runs as its own spell under the
+/// policy and is recorded in the ledger.
+fn shell_entry(args: &[String]) -> i32 {
+    use magicrune::backend::Backend;
+    use magicrune::ledger::{LedgerConfig, RunRecord};
+    use magicrune::sandbox::SandboxKind;
+    use std::io::{BufRead, IsTerminal};
+
+    let mut policy_path =
+        env::var("MAGICRUNE_POLICY").unwrap_or_else(|_| "policies/default.policy.yml".into());
+    let mut ledger_cfg = match LedgerConfig::from_env() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 4;
+        }
+    };
+    let mut i = 0usize;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--policy" => {
+                i += 1;
+                match args.get(i) {
+                    Some(p) => policy_path = p.clone(),
+                    None => {
+                        eprintln!("--policy needs a path");
+                        return 1;
+                    }
+                }
+            }
+            "--ledger" => {
+                i += 1;
+                match args.get(i) {
+                    Some(p) => ledger_cfg.path = p.into(),
+                    None => {
+                        eprintln!("--ledger needs a path");
+                        return 1;
+                    }
+                }
+            }
+            other => {
+                eprintln!("unknown flag: {}", other);
+                print_usage();
+                return 4;
+            }
+        }
+        i += 1;
+    }
+    let policy = match Policy::load(&policy_path) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 4;
+        }
+    };
+    let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
+    let ledger = ledger_cfg.open();
+    let mut opts = ExecOptions::from_env();
+    // Recorded below, with the command line.
+    opts.ledger = None;
+    // On a terminal each command gets it, as with `exec --interactive`.
+    opts.interactive = io::stdin().is_terminal()
+        && opts.backend == Backend::Local
+        && opts.sandbox == Some(SandboxKind::Linux);
     eprintln!(
-        "policy: using {} (wall_sec={}, cpu_ms={}, memory_mb={})",
-        &policy_path, limits.wall_sec, limits.cpu_ms, limits.memory_mb
+        "magicrune shell: policy {}, ledger {}; each line is a separate run, `exit` leaves",
+        policy_path,
+        ledger_cfg.path.display()
     );
-    // Enforce env allow/deny
-    let (env_allow, env_deny) = load_env_policy_from_policy(&policy_path);
-    for (k, _v) in &req.env {
-        if env_deny.iter().any(|p| pat_matches(k, p)) {
-            eprintln!("policy: env deny {}", k);
-            std::process::exit(3);
+
+    let stdin = io::stdin();
+    loop {
+        eprint!("magicrune> ");
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        let cmd = line.trim();
+        if cmd.is_empty() {
+            continue;
+        }
+        if cmd == "exit" || cmd == "quit" {
+            break;
+        }
+        let raw = serde_json::json!({ "cmd": cmd }).to_string();
+        // A repeated command line is still a new run.
+        let opts = ExecOptions {
+            seed: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_nanos() as u64),
+            ..opts.clone()
+        };
+        match execute_on_fresh_thread(raw.as_bytes(), &policy, &opts) {
+            Ok(run) => {
+                if !opts.interactive {
+                    let _ = io::stdout().write_all(&run.stdout);
+                    let _ = io::stdout().flush();
+                    let _ = io::stderr().write_all(&run.stderr);
+                }
+                eprintln!(
+                    "[{} risk {} exit {}]",
+                    run.result.verdict, run.result.risk_score, run.result.exit_code
+                );
+                rt.block_on(ledger.put(RunRecord {
+                    tenant: run.tenant.clone(),
+                    cmd: cmd.to_string(),
+                    ..RunRecord::enriched(&run.result, raw.as_bytes(), &policy)
+                }));
+            }
+            Err(e) => eprintln!("{}", e),
         }
     }
-    if !env_allow.is_empty() {
-        for (k, _v) in &req.env {
-            if !env_allow.iter().any(|p| pat_matches(k, p)) {
-                eprintln!("policy: env not allowed {}", k);
-                ctx.record_policy_violation("env_not_allowed", k);
-                shutdown_observability();
-                std::process::exit(3);
+    rt.block_on(ledger.flush());
+    0
+}
+
+/// Run one request on a thread of its own. The sandbox unshares namespaces
+/// for the calling thread, and a PID namespace takes no new children once
+/// its first one has exited, so a process that runs more than one command
+/// gives each a fresh thread.
+fn execute_on_fresh_thread(
+    raw: &[u8],
+    policy: &Policy,
+    opts: &ExecOptions,
+) -> Result<engine::RunOutput, magicrune::MagicruneError> {
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("tokio runtime")
+                .block_on(engine::execute(raw, policy, opts))
+        })
+        .join()
+        .unwrap_or_else(|_| Err(magicrune::MagicruneError::internal("run panicked")))
+    })
+}
+
+/// `exec --watch`: run the request, then again whenever it or the policy
+/// changes, printing one verdict line per run. Runs until interrupted.
+fn watch_entry(in_path: &str, policy_path: &str, out_path: Option<&str>, opts: &ExecOptions) {
+    use magicrune::watch::{verdict_diff, Watched, POLL_INTERVAL};
+    let mut watched = Watched::new([in_path.into(), policy_path.into()]);
+    let mut prev: Option<magicrune::schema::SpellResult> = None;
+    eprintln!("watching {} and {} (Ctrl-C to stop)", in_path, policy_path);
+    let mut label = "start".to_string();
+    loop {
+        let outcome = fs::read(in_path)
+            .map_err(|e| format!("read {}: {}", in_path, e))
+            .and_then(|raw| bundle::expand(raw).map_err(|e| e.to_string()))
+            .and_then(|raw| {
+                let policy = Policy::load(policy_path).map_err(|e| e.to_string())?;
+                execute_on_fresh_thread(&raw, &policy, opts).map_err(|e| e.to_string())
+            });
+        match outcome {
+            Ok(run) => {
+                println!("[{}] {}", label, verdict_diff(prev.as_ref(), &run.result));
+                if let Some(p) = out_path {
+                    if let Ok(json) = serde_json::to_string_pretty(&run.result) {
+                        let _ = fs::write(p, json);
+                    }
+                }
+                prev = Some(run.result);
             }
+            Err(e) => println!("[{}] error: {}", label, e),
         }
+        let _ = io::stdout().flush();
+        let changed = loop {
+            let changed = watched.changed();
+            if !changed.is_empty() {
+                break changed;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+        label = changed
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
     }
-    // Enforce NET allowlist: union of request.allow_net and policy capabilities.net.allow
-    if net_intent {
-        let mut allowed: Vec<String> = req.allow_net.clone();
-        allowed.extend(load_net_allow_from_policy(&policy_path));
-        let hosts = extract_http_hosts(&req.cmd);
-        if allowed.is_empty() {
-            eprintln!("policy: network is not allowed (no allowlist)");
-            std::process::exit(3);
+}
+
+/// `gc`: remove stale overlay scratch dirs and cgroups (see `magicrune::gc`).
+/// `ledger prune`: drop expired and superseded records from the ledger.
+fn ledger_entry(args: &[String]) -> i32 {
+    use magicrune::ledger::{LedgerConfig, Retention};
+    if args.first().map(String::as_str) != Some("prune") {
+        print_usage();
+        return 4;
+    }
+    let mut ledger = match LedgerConfig::from_env() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 4;
         }
-        for h in hosts {
-            let (h_host, h_port) = hostport_parts(&h);
-            let ok = allowed.iter().any(|a| allowed_match(&h_host, h_port, a));
-            if !ok {
-                eprintln!("policy: network to {} not allowed", h);
-                std::process::exit(3);
+    };
+    let mut retention = match Retention::from_env() {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let mut dry_run = false;
+    let mut i = 1usize;
+    while i < args.len() {
+        match (args[i].as_str(), args.get(i + 1)) {
+            ("--dry-run", _) => dry_run = true,
+            ("--ledger", Some(p)) => {
+                ledger.path = p.into();
+                i += 1;
+            }
+            ("--retention", Some(spec)) => {
+                match Retention::parse(spec) {
+                    Ok(r) => retention = Some(r),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return 1;
+                    }
+                }
+                i += 1;
+            }
+            (other, _) => {
+                eprintln!("unknown flag or missing value: {}", other);
+                print_usage();
+                return 4;
             }
         }
+        i += 1;
     }
-    if req.timeout_sec > limits.wall_sec {
-        eprintln!(
-            "policy: timeout_sec {} exceeds wall_sec limit {}",
-            req.timeout_sec, limits.wall_sec
-        );
-        std::process::exit(3);
+    let Some(retention) = retention else {
+        eprintln!("ledger prune needs --retention or MAGICRUNE_LEDGER_RETENTION");
+        return 4;
+    };
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
+    match rt.block_on(ledger.open().prune(&retention, now_ms, dry_run)) {
+        Ok(r) => {
+            println!(
+                "{}: kept {}, {} {} expired, {} superseded",
+                ledger.path.display(),
+                r.kept,
+                if dry_run { "would drop" } else { "dropped" },
+                r.expired,
+                r.superseded
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("{}: {}", ledger.path.display(), e);
+            4
+        }
     }
+}
 
-    if net_intent && req.allow_net.is_empty() && load_net_allow_from_policy(&policy_path).is_empty()
-    {
-        risk_score += 40;
+/// `stats`: aggregates over the ledger, as text or JSON.
+fn stats_entry(args: &[String]) -> i32 {
+    use magicrune::ledger::LedgerConfig;
+    use magicrune::stats::{self, StatsQuery};
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let mut ledger = match LedgerConfig::from_env() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 4;
+        }
+    };
+    let mut q = StatsQuery {
+        top: 5,
+        ..Default::default()
+    };
+    let mut json = false;
+    let mut i = 0usize;
+    while i < args.len() {
+        let flag = args[i].as_str();
+        let value = args.get(i + 1).map(String::as_str);
+        let parsed = match (flag, value) {
+            ("--json", _) => {
+                json = true;
+                i += 1;
+                continue;
+            }
+            ("--ledger", Some(p)) => {
+                ledger.path = p.into();
+                Ok(())
+            }
+            ("--since", Some(v)) => stats::parse_time(v, now_ms).map(|t| q.since_ms = Some(t)),
+            ("--until", Some(v)) => stats::parse_time(v, now_ms).map(|t| q.until_ms = Some(t)),
+            ("--tenant", Some(t)) => {
+                q.tenant = Some(t.to_string());
+                Ok(())
+            }
+            ("--top", Some(n)) => n
+                .parse()
+                .map(|n| q.top = n)
+                .map_err(|_| format!("{:?} is not a count", n)),
+            ("--ledger" | "--since" | "--until" | "--tenant" | "--top", None) => {
+                Err("needs a value".to_string())
+            }
+            (other, _) => {
+                eprintln!("unknown flag: {}", other);
+                print_usage();
+                return 4;
+            }
+        };
+        if let Err(e) = parsed {
+            eprintln!("{}: {}", flag, e);
+            return 1;
+        }
+        i += 2;
     }
-    if cmd_l.contains("ssh ") {
-        risk_score += 30;
+    let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
+    let report = stats::compute(&rt.block_on(ledger.open().all()), &q);
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).unwrap_or_default()
+        );
+    } else {
+        print!("{}", stats::render(&report));
     }
+    0
+}
 
-    // Load thresholds from policy (if available)
-    let thresholds = load_thresholds_from_policy(&policy_path);
-    let verdict = decide_verdict_from_thresholds(risk_score, &thresholds);
-
-    // Exit code mapping
-    let exit_code = match verdict {
-        "green" => 0,
-        "yellow" => 10,
-        _ => 20,
+fn repro_entry(args: &[String]) -> i32 {
+    use magicrune::ledger::LedgerConfig;
+    use magicrune::repro;
+    let Some(run_id) = args.first().filter(|a| !a.starts_with("--")) else {
+        eprintln!("repro needs a run_id");
+        print_usage();
+        return 4;
     };
-
-    // Minimal file materialization with policy check (allow_fs)
-    // Only allow writes under /tmp/** unless policy explicitly allows broader paths.
-    if !req.files.is_empty() {
-        let fs_readonly = load_fs_readonly_from_policy(&policy_path);
-        let policy_fs_allow = load_fs_allow_from_policy(&policy_path);
-        for f in &req.files {
-            let p = Path::new(&f.path);
-            // Basic path sanity: must be absolute and no parent traversal
-            if !p.is_absolute() || f.path.contains("..") {
-                eprintln!("schema: file.path must be absolute and must not contain '..'");
-                std::process::exit(1);
-            }
-            for ro in &fs_readonly {
-                if pat_matches(&f.path, ro) {
-                    eprintln!("policy: write to readonly {}", f.path);
-                    std::process::exit(20);
-                }
+    let mut ledger = match LedgerConfig::from_env() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 4;
+        }
+    };
+    let mut policy_path =
+        env::var("MAGICRUNE_POLICY").unwrap_or_else(|_| "policies/default.policy.yml".into());
+    let mut times = 3u32;
+    let mut json = false;
+    let mut i = 1usize;
+    while i < args.len() {
+        let flag = args[i].as_str();
+        let value = args.get(i + 1).map(String::as_str);
+        let parsed = match (flag, value) {
+            ("--json", _) => {
+                json = true;
+                i += 1;
+                continue;
             }
-            let allowed_tmp = p.starts_with("/tmp/");
-            let mut allowed = allowed_tmp; // default allow only /tmp/**
-                                           // Also allow paths granted by policy capabilities.fs.allow
-            for pat in &policy_fs_allow {
-                if pat == "/tmp/**" && allowed_tmp {
-                    allowed = true;
-                    break;
-                }
-                if pat == &f.path {
-                    allowed = true;
-                    break;
-                }
+            ("--ledger", Some(p)) => {
+                ledger.path = p.into();
+                Ok(())
             }
-            if !allowed {
-                eprintln!("policy: write denied for {}", f.path);
-                std::process::exit(3);
+            ("--policy", Some(p)) => {
+                policy_path = p.to_string();
+                Ok(())
             }
-            if let Some(dir) = p.parent() {
-                let _ = fs::create_dir_all(dir);
+            ("--times", Some(n)) => match n.parse() {
+                Ok(n) if n > 0 => {
+                    times = n;
+                    Ok(())
+                }
+                _ => Err(format!("{:?} is not a positive count", n)),
+            },
+            ("--ledger" | "--policy" | "--times", None) => Err("needs a value".to_string()),
+            (other, _) => {
+                eprintln!("unknown flag: {}", other);
+                print_usage();
+                return 4;
             }
-            if !f.content_b64.is_empty() {
-                if let Ok(bytes) = base64::engine::general_purpose::STANDARD.decode(&f.content_b64)
-                {
-                    if let Err(e) = fs::write(p, &bytes) {
-                        eprintln!("write failed: {}: {}", f.path, e);
-                        std::process::exit(4);
+        };
+        if let Err(e) = parsed {
+            eprintln!("{}: {}", flag, e);
+            return 1;
+        }
+        i += 2;
+    }
+    let policy = match Policy::load(&policy_path) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 4;
+        }
+    };
+    let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
+    let Some(rec) = rt.block_on(ledger.open().get(run_id)) else {
+        eprintln!("{}: not in the ledger", run_id);
+        return 4;
+    };
+    let report = match rt.block_on(repro::repro(&rec, times, &policy, &ExecOptions::from_env())) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 4;
+        }
+    };
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).unwrap_or_default()
+        );
+        return report.exit_code();
+    }
+    let matched = report
+        .runs
+        .iter()
+        .filter(|r| !report.differences.iter().any(|d| d.run == r.run))
+        .count();
+    println!(
+        "{}: reproduced {}/{} (score {:.2}), baseline {}",
+        report.run_id,
+        matched,
+        report.times,
+        report.score,
+        if report.baseline.run == repro::LEDGER {
+            "ledger".to_string()
+        } else {
+            format!("run {}", report.baseline.run)
+        }
+    );
+    for d in &report.differences {
+        match &d.diff {
+            Some(diff) => println!("  run {}: {} differs, {}", d.run, d.field, diff),
+            None => println!("  run {}: {} {} != {}", d.run, d.field, d.baseline, d.value),
+        }
+    }
+    if report.policy_changed {
+        eprintln!("warning: the policy is not the one the run was recorded under");
+    }
+    report.exit_code()
+}
+
+fn gc_entry(args: &[String]) -> i32 {
+    use magicrune::gc::{self, GcConfig};
+    let mut cfg = GcConfig::from_env();
+    let mut dry_run = false;
+    let mut i = 0usize;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--min-age" => {
+                i += 1;
+                match args.get(i).and_then(|s| s.parse::<u64>().ok()) {
+                    Some(m) => cfg.min_age = std::time::Duration::from_secs(m * 60),
+                    None => {
+                        eprintln!("--min-age needs a number of minutes");
+                        return 1;
                     }
                 }
-            } else if let Err(e) = fs::write(p, []) {
-                eprintln!("write failed: {}: {}", f.path, e);
-                std::process::exit(4);
+            }
+            "--dry-run" => dry_run = true,
+            other => {
+                eprintln!("unknown flag: {}", other);
+                print_usage();
+                return 4;
             }
         }
+        i += 1;
     }
+    if dry_run {
+        for p in gc::find_stale(&cfg) {
+            println!("{}", p.display());
+        }
+        return 0;
+    }
+    let report = gc::sweep(&cfg);
+    for p in &report.removed {
+        println!("{}", p.display());
+    }
+    for (p, e) in &report.failed {
+        eprintln!("could not remove {}: {}", p.display(), e);
+    }
+    if report.failed.is_empty() {
+        0
+    } else {
+        4
+    }
+}
 
-    // Optionally execute the command once.
-    // - Linux+native: run locally (placeholder for true sandbox)
-    // - Otherwise (WASI default): skip here (feature-gated path elsewhere)
-    // - MAGICRUNE_DRY_RUN=1 to skip entirely
-    let mut captured_stdout: Vec<u8> = Vec::new();
-    let mut captured_stderr: Vec<u8> = Vec::new();
-    let mut actual_exit: Option<i32> = None;
-    let mut forced_timeout_red = false;
-    let mut duration_ms: u64 = 0;
-    if std::env::var("MAGICRUNE_DRY_RUN").ok().as_deref() != Some("1") && !req.cmd.trim().is_empty()
-    {
-        let sb = detect_sandbox();
-        eprintln!("sandbox: {:?}", sb);
-        match sb {
-            SandboxKind::Linux => {
-                let started = Instant::now();
-                let mut child = Command::new("bash")
-                    .arg("-lc")
-                    .arg(&req.cmd)
-                    .stdin(Stdio::piped())
-                    .stdout(Stdio::piped())
-                    .stderr(Stdio::piped())
-                    .spawn()
-                    .expect("spawn bash");
-                if !req.stdin.is_empty() {
-                    use std::io::Write as _;
-                    if let Some(mut sin) = child.stdin.take() {
-                        let _ = sin.write_all(req.stdin.as_bytes());
-                    }
+/// `enqueue` and `worker` over a spool directory (`--spool`, `MAGICRUNE_SPOOL`,
+/// default `spool`). Returns the process exit code.
+fn spool_entry(args: &[String]) -> i32 {
+    let mut spool_dir = env::var("MAGICRUNE_SPOOL").unwrap_or_else(|_| "spool".to_string());
+    let mut in_path: Option<String> = None;
+    let mut policy_path =
+        env::var("MAGICRUNE_POLICY").unwrap_or_else(|_| "policies/default.policy.yml".to_string());
+    let mut once = false;
+    let mut i = 1usize;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--spool" => {
+                i += 1;
+                if let Some(d) = args.get(i) {
+                    spool_dir = d.clone();
                 }
-                let deadline = Instant::now() + Duration::from_secs(limits.wall_sec);
-                loop {
-                    if let Ok(Some(_status)) = child.try_wait() {
-                        let out = child.wait_with_output().expect("collect output after exit");
-                        duration_ms = started.elapsed().as_millis() as u64;
-                        captured_stdout = out.stdout.clone();
-                        captured_stderr = out.stderr.clone();
-                        actual_exit = out.status.code();
-                        break;
-                    }
-                    if Instant::now() >= deadline {
-                        let _ = child.kill();
-                        forced_timeout_red = true;
-                        duration_ms = started.elapsed().as_millis() as u64;
-                        break;
-                    }
-                    std::thread::sleep(Duration::from_millis(25));
+            }
+            "-f" | "--file" => {
+                i += 1;
+                in_path = args.get(i).cloned();
+            }
+            "--policy" => {
+                i += 1;
+                if let Some(p) = args.get(i) {
+                    policy_path = p.clone();
                 }
             }
-            SandboxKind::Wasi => {
-                // No-op here; WASI execution is wired in sandbox module when feature is enabled.
+            "--once" => once = true,
+            other => {
+                eprintln!("unknown flag: {}", other);
+                print_usage();
+                return 4;
             }
         }
+        i += 1;
     }
-
-    let result = SpellResult {
-        run_id: run_id.clone(),
-        verdict: verdict.to_string(),
-        risk_score,
-        exit_code: actual_exit.unwrap_or(exit_code),
-        duration_ms,
-        stdout_trunc: false,
-        sbom_attestation: None,
+    let spool = match Spool::open(&spool_dir) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("spool: {}: {}", spool_dir, e);
+            return 4;
+        }
     };
 
-    // Record completion metrics
-    ctx.record_completion(verdict, risk_score, actual_exit.unwrap_or(exit_code));
-
-    // If runtime timeout was hit, force red verdict and exit=20
-    let mut out_json = serde_json::to_string_pretty(&result).expect("serialize");
-    let mut final_exit = result.exit_code;
-    if forced_timeout_red {
-        let mut v: serde_json::Value = serde_json::from_str(&out_json).unwrap();
-        v["verdict"] = serde_json::Value::String("red".to_string());
-        v["exit_code"] = serde_json::Value::Number(20u64.into());
-        out_json = serde_json::to_string_pretty(&v).unwrap();
-        final_exit = 20;
-    }
-    // Output schema validation under --strict
-    if strict {
-        // Validate against schemas/spell_result.schema.json if present
-        if Path::new("schemas/spell_result.schema.json").exists() {
-            if let Ok(schema_txt) = std::fs::read_to_string("schemas/spell_result.schema.json") {
-                if let Ok(schema_json) = serde_json::from_str::<serde_json::Value>(&schema_txt) {
-                    if let Ok(compiled) = jsonschema::JSONSchema::options().compile(&schema_json) {
-                        let out_val: serde_json::Value = serde_json::from_str(&out_json).unwrap();
-                        let validation = compiled.validate(&out_val);
-                        if let Err(errors) = validation {
-                            for err in errors {
-                                eprintln!("output schema: {}", err);
-                            }
-                            std::process::exit(2);
-                        }
-                    }
-                }
+    if args[0] == "enqueue" {
+        let Some(in_path) = in_path else {
+            eprintln!("Missing -f <request.json>");
+            print_usage();
+            return 1;
+        };
+        let raw = match fs::read(&in_path) {
+            Ok(b) => b,
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", in_path, e);
+                return 1;
             }
-        }
-        // Ensure required keys and types
-        let out_val: serde_json::Value = serde_json::from_str(&out_json).unwrap();
-        let reqd = [
-            "run_id",
-            "verdict",
-            "risk_score",
-            "exit_code",
-            "duration_ms",
-            "stdout_trunc",
-        ];
-        for k in reqd.iter() {
-            if out_val.get(*k).is_none() {
-                eprintln!("output schema: missing {}", k);
-                std::process::exit(2);
+        };
+        return match spool.enqueue(&raw) {
+            Ok(id) => {
+                println!("{}", id);
+                0
             }
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                eprintln!("Invalid JSON: {}", e);
+                1
+            }
+            Err(e) => {
+                eprintln!("spool: {}", e);
+                4
+            }
+        };
+    }
+
+    let policy = match Policy::load(&policy_path) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 4;
         }
-        if !matches!(out_val["run_id"], serde_json::Value::String(_)) {
-            eprintln!("output schema: run_id");
-            std::process::exit(2);
-        }
-        if !matches!(out_val["verdict"], serde_json::Value::String(_)) {
-            eprintln!("output schema: verdict");
-            std::process::exit(2);
-        }
-        if !matches!(out_val["risk_score"], serde_json::Value::Number(_)) {
-            eprintln!("output schema: risk_score");
-            std::process::exit(2);
-        }
-        if !matches!(out_val["exit_code"], serde_json::Value::Number(_)) {
-            eprintln!("output schema: exit_code");
-            std::process::exit(2);
-        }
-        if !matches!(out_val["duration_ms"], serde_json::Value::Number(_)) {
-            eprintln!("output schema: duration_ms");
-            std::process::exit(2);
+    };
+    tracing::info!(
+        spool = %spool_dir,
+        policy = %policy_path,
+        policy_digest = %policy.digest,
+        "spool: worker started"
+    );
+    match spool.requeue_running() {
+        Ok(0) => {}
+        Ok(n) => tracing::info!(requeued = n, "spool: requeued unfinished requests"),
+        Err(e) => tracing::warn!(error = %e, "spool: requeue failed"),
+    }
+    let opts = ExecOptions::from_env();
+    let poll = std::time::Duration::from_millis(env_u64("MAGICRUNE_SPOOL_POLL_MS", 500));
+    let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
+    let worked = rt.block_on(spool.run_worker(&policy, &opts, once, poll));
+    if let Some(ledger) = &opts.ledger {
+        rt.block_on(ledger.flush());
+    }
+    match worked {
+        Ok(processed) => {
+            for p in processed {
+                match p {
+                    Processed::Done { id, exit_code } => println!("done {} {}", id, exit_code),
+                    Processed::Failed { id, exit_code } => println!("failed {} {}", id, exit_code),
+                }
+            }
+            0
         }
-        if !matches!(out_val["stdout_trunc"], serde_json::Value::Bool(_)) {
-            eprintln!("output schema: stdout_trunc");
-            std::process::exit(2);
+        Err(e) => {
+            eprintln!("spool: {}", e);
+            4
         }
     }
+}
 
-    if let Some(p) = out_path {
-        if let Some(dir) = Path::new(&p).parent() {
-            if !dir.as_os_str().is_empty() && !dir.exists() {
-                if let Err(e) = fs::create_dir_all(dir) {
-                    eprintln!("Failed to create output dir: {}", e);
-                    std::process::exit(4);
+fn bench_entry(args: &[String]) {
+    let mut cfg = BenchConfig {
+        opts: ExecOptions::from_env(),
+        ..Default::default()
+    };
+    let mut policy_path = std::env::var("MAGICRUNE_POLICY")
+        .unwrap_or_else(|_| "policies/default.policy.yml".to_string());
+    let mut json = false;
+    let mut i = 0usize;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-n" | "--requests" => {
+                i += 1;
+                cfg.requests = args
+                    .get(i)
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or(cfg.requests);
+            }
+            "-c" | "--concurrency" => {
+                i += 1;
+                cfg.concurrency = args
+                    .get(i)
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or(cfg.concurrency);
+            }
+            "--policy" => {
+                i += 1;
+                if let Some(p) = args.get(i) {
+                    policy_path = p.clone();
+                }
+            }
+            "--cmd" => {
+                i += 1;
+                if let Some(c) = args.get(i) {
+                    cfg.cmd = c.clone();
                 }
             }
+            "--dry-run" => cfg.opts.dry_run = true,
+            "--json" => json = true,
+            other => {
+                eprintln!("unknown flag: {}", other);
+                print_usage();
+                std::process::exit(4);
+            }
         }
-        if let Err(e) = fs::write(&p, out_json.as_bytes()) {
-            eprintln!("Failed to write {}: {}", p, e);
+        i += 1;
+    }
+    let policy = match Policy::load(&policy_path) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{}", e);
             std::process::exit(4);
         }
+    };
+    let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
+    let report = rt.block_on(run_bench(&cfg, &policy));
+    if json {
+        println!("{}", serde_json::to_string(&report).expect("serialize"));
     } else {
-        let mut stdout = io::stdout();
-        let _ = stdout.write_all(out_json.as_bytes());
+        println!(
+            "requests={} ok={} failed={} concurrency={}",
+            report.requests, report.ok, report.failed, cfg.concurrency
+        );
+        println!(
+            "elapsed={:.1}ms throughput={:.1} req/s",
+            report.elapsed_ms, report.throughput_rps
+        );
+        println!(
+            "latency p50={:.3}ms p95={:.3}ms p99={:.3}ms",
+            report.p50_ms, report.p95_ms, report.p99_ms
+        );
     }
+}
 
-    // Quarantine for red verdict (write result + captured stdout/stderr if any)
-    if forced_timeout_red || final_exit == 20 {
-        let qdir = Path::new("quarantine");
-        let _ = fs::create_dir_all(qdir);
-        let _ = fs::write(qdir.join("result.red.json"), out_json.as_bytes());
-        let _ = fs::write(qdir.join("stdout.txt"), &captured_stdout);
-        let _ = fs::write(qdir.join("stderr.txt"), &captured_stderr);
-    }
+/// Wall-clock now in Unix ms, which request deadlines are given in.
+#[cfg(feature = "jet")]
+fn unix_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
 
-    shutdown_observability();
-    std::process::exit(final_exit);
+/// Alert on a red result a NATS consumer reached (see `magicrune::notify`).
+#[cfg(feature = "jet")]
+async fn notify_red(res: &magicrune::schema::SpellResult) {
+    if let Some(n) = magicrune::notify::global() {
+        n.notify(magicrune::notify::NotifyEvent::Red, res).await;
+    }
 }
 
 #[cfg(feature = "jet")]
-fn consume_entry(url: &str, subject: &str) -> anyhow::Result<()> {
+fn consume_entry(
+    cfg: &magicrune::jet::config::ConsumerConfig,
+    health_addr: Option<&str>,
+    shadow: Option<ShadowPolicy>,
+) -> anyhow::Result<()> {
     use futures_util::StreamExt;
+    use magicrune::jet::config::ConsumeMode;
+    use magicrune::jet::encoding::Compression;
+    use magicrune::jet::jet_impl::{open_message, result_message, send_result};
+    use magicrune::jet::subjects::REPLY_HEADER;
+    use magicrune::webhook::{self, WebhookConfig};
     use std::collections::{HashSet, VecDeque};
+    let wh = WebhookConfig::from_env();
+    use magicrune::tenant;
+    let keyring = Keyring::from_env_or_empty();
+    let producer_keys = Keyring::producers_from_env()?;
+    let compression = Compression::from_env().map_err(anyhow::Error::msg)?;
+    let faults = magicrune::faults::Faults::new(cfg.faults.clone());
+    let clock = magicrune::ports::Clock::default();
+    let bound_tenant = env::var("MAGICRUNE_TENANT").ok().filter(|t| !t.is_empty());
+    let instance_id = cfg.instance_id.clone().unwrap_or_default();
+    let subject = cfg.subject.as_str();
+    let health = start_health(health_addr, &["nats", "stream", "policy"])?;
+    // The policy was checked with the settings; later edits are checked on
+    // reload.
+    let policy_path =
+        env::var("MAGICRUNE_POLICY").unwrap_or_else(|_| "policies/default.policy.yml".to_string());
+    if let Some(h) = &health {
+        magicrune::policy::PolicyStore::get(&policy_path);
+        h.set("policy", Path::new(&policy_path).is_file());
+    }
     let rt = tokio::runtime::Runtime::new()?;
     rt.block_on(async move {
-        let nc = magicrune::jet::jet_impl::connect(&format!("nats://{}", url))
+        let nc = magicrune::jet::jet_impl::connect(&format!("nats://{}", cfg.url))
             .await
             .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        if let Some(h) = &health {
+            let nc = nc.clone();
+            h.probe("nats", move || {
+                nc.connection_state() == async_nats::connection::State::Connected
+            });
+        }
         fn env_u64(key: &str, default: u64) -> u64 {
             std::env::var(key)
                 .ok()
                 .and_then(|s| s.parse::<u64>().ok())
                 .unwrap_or(default)
         }
-        #[allow(dead_code)]
-        fn env_i64(key: &str, default: i64) -> i64 {
-            std::env::var(key)
-                .ok()
-                .and_then(|s| s.parse::<i64>().ok())
-                .unwrap_or(default)
-        }
         use async_nats::jetstream::{
             self,
             stream::{Config, RetentionPolicy, StorageType},
         };
         let js = jetstream::new(nc.clone());
         // Ensure JetStream stream exists for dedupe window
-        {
-            let name = std::env::var("NATS_STREAM").unwrap_or_else(|_| "RUN".to_string());
-            let dup_sec = env_u64("NATS_DUP_WINDOW_SEC", 120);
-            let cfg = Config {
+        if cfg.mode == ConsumeMode::Pull {
+            let name = cfg.stream.clone();
+            let s_cfg = Config {
                 name: name.clone(),
-                subjects: vec![subject.to_string()],
+                subjects: cfg.stream_subjects.clone(),
                 retention: RetentionPolicy::Limits,
                 max_consumers: -1,
                 max_messages: -1,
                 max_bytes: -1,
-                duplicate_window: std::time::Duration::from_secs(dup_sec),
+                duplicate_window: std::time::Duration::from_secs(cfg.duplicate_window_sec),
                 storage: StorageType::File,
                 ..Default::default()
             };
             if js.get_stream(&name).await.is_err() {
-                let _ = js.create_stream(cfg).await;
+                let _ = js.create_stream(s_cfg).await;
             }
 
             // Ensure a durable consumer exists
             use async_nats::jetstream::consumer::{self, pull};
-            let durable =
-                std::env::var("NATS_DURABLE").unwrap_or_else(|_| "RUN_WORKER".to_string());
-            let max_ack_pending = std::env::var("NATS_MAX_ACK_PENDING")
-                .ok()
-                .and_then(|s| s.parse::<i64>().ok())
-                .unwrap_or(2048);
-            let ack_wait_sec = std::env::var("NATS_ACK_WAIT_SEC")
-                .ok()
-                .and_then(|s| s.parse::<u64>().ok())
-                .unwrap_or(30);
+            let durable = cfg.durable.clone();
+            let filter_subject = cfg.filter_subject.clone();
             let c_cfg = pull::Config {
                 durable_name: Some(durable.clone()),
+                filter_subject: filter_subject.clone(),
                 ack_policy: consumer::AckPolicy::Explicit,
-                max_ack_pending,
-                ack_wait: std::time::Duration::from_secs(ack_wait_sec),
+                max_ack_pending: cfg.max_ack_pending,
+                ack_wait: std::time::Duration::from_secs(cfg.ack_wait_sec),
                 ..Default::default()
             };
             if let Ok(stream) = js.get_stream(&name).await {
                 if stream.get_consumer::<pull::Config>(&durable).await.is_err() {
                     let _ = stream.create_consumer(c_cfg.clone()).await;
                 }
-                // Optional: override max_deliver via env by creating a generic consumer config
-                if let Some(max_deliver) = std::env::var("NATS_CONSUMER_MAX_DELIVER")
-                    .ok()
-                    .and_then(|s| s.parse::<i64>().ok())
-                {
+                // Optional: override max_deliver by creating a generic consumer config
+                if let Some(max_deliver) = cfg.max_deliver {
                     let base = async_nats::jetstream::consumer::Config {
                         durable_name: Some(durable.clone()),
+                        filter_subject: filter_subject.clone(),
                         max_deliver,
                         ..Default::default()
                     };
@@ -1279,14 +1840,15 @@ fn consume_entry(url: &str, subject: &str) -> anyhow::Result<()> {
                     .messages()
                     .await
                     .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+                if let Some(h) = &health {
+                    h.set("stream", true);
+                }
 
                 // Dedupe caches and simple metrics
                 let mut seen: HashSet<String> = HashSet::new();
                 let mut order: VecDeque<String> = VecDeque::new();
-                let dedupe_max = std::env::var("MAGICRUNE_DEDUPE_MAX")
-                    .ok()
-                    .and_then(|s| s.parse::<usize>().ok())
-                    .unwrap_or(1024);
+                let dedupe_max = cfg.dedupe_max;
+                let guard = magicrune::guard::ExecGuard::from_env();
                 let metrics_every = env_u64("MAGICRUNE_METRICS_EVERY", 100);
                 let mut count_total: u64 = 0;
                 let mut count_dupe: u64 = 0;
@@ -1297,61 +1859,24 @@ fn consume_entry(url: &str, subject: &str) -> anyhow::Result<()> {
                     let tmp = format!("{}.tmp", path);
                     if let Ok(mut f) = std::fs::File::create(&tmp) {
                         let _ = writeln!(f, "# magicrune metrics");
-                        let _ = writeln!(f, "{}_processed_total {}", prefix, total);
-                        let _ = writeln!(f, "{}_dupe_total {}", prefix, dupe);
-                        let _ = writeln!(f, "{}_red_total {}", prefix, red);
+                        let l = magicrune::metrics::labels(&[]);
+                        let _ = writeln!(f, "{}_processed_total{} {}", prefix, l, total);
+                        let _ = writeln!(f, "{}_dupe_total{} {}", prefix, l, dupe);
+                        let _ = writeln!(f, "{}_red_total{} {}", prefix, l, red);
+                        let _ = write!(f, "{}", magicrune::metrics::render(prefix));
+                        let cache = magicrune::policy::PolicyStore::global().stats();
+                        let _ =
+                            writeln!(f, "{}_policy_cache_hits_total{} {}", prefix, l, cache.hits);
+                        let _ = writeln!(
+                            f,
+                            "{}_policy_cache_reloads_total{} {}",
+                            prefix, l, cache.reloads
+                        );
                     }
                     let _ = std::fs::rename(tmp, path);
                 }
-                // Jitter helpers (e.g., "200..=800")
-                fn parse_jitter(spec: &str) -> Option<(u64, u64)> {
-                    let s = spec.trim();
-                    if let Some((a, b)) = s.split_once("..=") {
-                        if let (Ok(lo), Ok(hi)) = (a.trim().parse::<u64>(), b.trim().parse::<u64>())
-                        {
-                            if lo <= hi {
-                                return Some((lo, hi));
-                            }
-                        }
-                    } else if let Some((a, b)) = s.split_once("..") {
-                        if let (Ok(lo), Ok(hi)) = (a.trim().parse::<u64>(), b.trim().parse::<u64>())
-                        {
-                            if lo <= hi {
-                                return Some((lo, hi));
-                            }
-                        }
-                    }
-                    None
-                }
-                fn jitter_ms(r: Option<(u64, u64)>) -> u64 {
-                    if let Some((lo, hi)) = r {
-                        let now = std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)
-                            .unwrap_or_default()
-                            .as_nanos();
-                        let mut x = (now as u64)
-                            .wrapping_mul(6364136223846793005)
-                            .wrapping_add(1);
-                        x ^= x >> 33;
-                        x = x.wrapping_mul(0xff51afd7ed558ccd);
-                        x ^= x >> 33;
-                        let span = hi - lo + 1;
-                        return lo + (x % span);
-                    }
-                    0
-                }
-                let jitter = std::env::var("MAGICRUNE_TEST_DELAY_MS_JITTER")
-                    .ok()
-                    .and_then(|s| parse_jitter(&s));
-                let skip_ack_once = std::env::var("MAGICRUNE_TEST_SKIP_ACK_ONCE")
-                    .ok()
-                    .as_deref()
-                    == Some("1");
-                let mut skipped_once: std::collections::HashSet<String> =
-                    std::collections::HashSet::new();
                 let metrics_file = std::env::var("MAGICRUNE_METRICS_FILE").ok();
 
-                let delay_ms = env_u64("MAGICRUNE_TEST_DELAY_MS", 0);
                 while let Some(Ok(msg)) = messages.next().await {
                     count_total += 1;
                     let id = msg
@@ -1360,6 +1885,25 @@ fn consume_entry(url: &str, subject: &str) -> anyhow::Result<()> {
                         .and_then(|h| h.get("Nats-Msg-Id"))
                         .map(|v| v.to_string())
                         .unwrap_or_else(|| magicrune::jet::compute_msg_id(msg.payload.as_ref()));
+                    // Without the policy's runner labels the request goes
+                    // back before dedupe, for a consumer that has them.
+                    let needed = magicrune::policy::PolicyStore::get(&policy_path)
+                        .runner_labels
+                        .clone();
+                    let missing = magicrune::instance::missing_labels(&needed, &cfg.labels);
+                    if !missing.is_empty() {
+                        tracing::warn!(
+                            subject = %msg.subject,
+                            hint = %magicrune::instance::routing_hint(&needed, &missing),
+                            "consume: handing back"
+                        );
+                        let _ = msg
+                            .ack_with(jetstream::AckKind::Nak(Some(
+                                std::time::Duration::from_secs(1),
+                            )))
+                            .await;
+                        continue;
+                    }
                     if seen.contains(&id) {
                         count_dupe += 1;
                         let _ = msg.ack().await;
@@ -1374,29 +1918,58 @@ fn consume_entry(url: &str, subject: &str) -> anyhow::Result<()> {
                         }
                     }
 
-                    let payload = msg.payload.to_vec();
-                    let req_val: serde_json::Value = match serde_json::from_slice(&payload) {
-                        Ok(v) => v,
-                        Err(_) => {
+                    let reply = msg
+                        .headers
+                        .as_ref()
+                        .and_then(|h| h.get(REPLY_HEADER))
+                        .map(|r| r.to_string());
+                    let (body, accepts_zstd) =
+                        match open_message(msg.headers.as_ref(), &msg.payload, &compression) {
+                            Ok(b) => b,
+                            Err(e) => {
+                                tracing::warn!(subject = %msg.subject, error = %e, "consume: dropping");
+                                let _ = msg.ack().await;
+                                continue;
+                            }
+                        };
+                    let fetched = match magicrune::jet::jet_impl::fetch_bundle(&nc, &body).await {
+                        Ok(b) => b,
+                        Err(e) => {
+                            tracing::warn!(subject = %msg.subject, error = %e, "consume: dropping");
                             let _ = msg.ack().await;
                             continue;
                         }
                     };
-                    let mut seed_le = 0u64.to_le_bytes().to_vec();
-                    if let Some(s) = req_val.get("seed").and_then(|x| x.as_u64()) {
-                        seed_le = s.to_le_bytes().to_vec();
-                    }
-                    let mut all = payload.clone();
-                    all.extend_from_slice(&seed_le);
-                    let run_id = format!("r_{}", sha256_hex(&all));
-
-                    let req: SpellRequest = match serde_json::from_slice(&payload) {
+                    let raw = fetched.as_deref().unwrap_or(&body);
+                    let Ok(payload) = template::render(raw, &template::Vars::new()) else {
+                        let _ = msg.ack().await;
+                        continue;
+                    };
+                    let req = match RequestView::parse(&payload) {
                         Ok(r) => r,
                         Err(_) => {
                             let _ = msg.ack().await;
                             continue;
                         }
                     };
+                    let seed = req.seed().unwrap_or(0);
+                    let run_id = magicrune::engine::compute_run_id(&payload, Some(seed));
+                    let bound = bound_tenant
+                        .clone()
+                        .or_else(|| tenant::from_subject(&msg.subject));
+                    let tenant = match tenant::resolve(bound.as_deref(), &req.tenant) {
+                        Ok(t) => t,
+                        Err(e) => {
+                            tracing::warn!(
+                                run_id = %run_id,
+                                subject = %msg.subject,
+                                error = %e,
+                                "consume: dropping"
+                            );
+                            let _ = msg.ack().await;
+                            continue;
+                        }
+                    };
 
                     // Minimal grading and policy
                     let cmd_l = req.cmd.to_lowercase();
@@ -1407,25 +1980,127 @@ fn consume_entry(url: &str, subject: &str) -> anyhow::Result<()> {
                         || cmd_l.contains("https://");
                     let policy_path = std::env::var("MAGICRUNE_POLICY")
                         .unwrap_or_else(|_| "policies/default.policy.yml".to_string());
-                    let limits = load_limits_from_policy(&policy_path);
-                    if net_intent && req.allow_net.is_empty() {
-                        let res = SpellResult {
+                    let policy = magicrune::policy::PolicyStore::get(&policy_path);
+                    // Graded under the shadow policy too; only logged and
+                    // counted (see `magicrune::shadow`).
+                    if let Some(s) = &shadow {
+                        let opts = ExecOptions {
+                            producer_keys: producer_keys.clone(),
+                            tenant: bound.clone(),
+                            instance_id: cfg.instance_id.clone(),
+                            ..ExecOptions::default()
+                        };
+                        s.evaluate(raw, &policy, &opts).await;
+                    }
+                    let limits = magicrune::policy::PolicyLimits {
+                        wall_sec: policy.limits.wall_for(req.timeout_sec),
+                        ..policy.limits
+                    };
+                    if let Err(e) =
+                        magicrune::engine::authenticate_request(raw, &policy, &producer_keys)
+                    {
+                        tracing::warn!(run_id = %run_id, subject = %msg.subject, error = %e, "consume: rejected");
+                        let mut res = magicrune::schema::SpellResult {
+                            run_id: run_id.clone(),
+                            verdict: magicrune::schema::REJECTED_VERDICT.into(),
+                            risk_score: 80,
+                            tenant: tenant.clone(),
+                            findings: vec![e.finding()],
+                            ..Default::default()
+                        };
+                        res.instance_id = instance_id.clone();
+                        keyring.sign_result(&mut res)?;
+                        magicrune::observability::log_policy_decision(
+                            &run_id,
+                            &msg.subject,
+                            &policy.digest,
+                            &res.verdict,
+                            res.risk_score,
+                        );
+                        let subj = cfg.subjects.reply_to(reply.as_deref(), &run_id, &res.tenant);
+                        notify_red(&res).await;
+                        if webhook::dispatch(&wh, Some(&*req.callback_url), &res).await
+ && !faults.fail_publish(&run_id)
+ {
+                            let (headers, body) = result_message(&res, accepts_zstd, &compression)?;
+                            let _ = send_result(&nc, subj, headers, body).await;
+                        }
+                        let _ = msg.ack().await;
+                        continue;
+                    }
+                    // Taken off the stream past its `deadline_ms`: answered
+                    // without running.
+                    let queued_at = msg
+                        .info()
+                        .ok()
+                        .map(|i| (i.published.unix_timestamp_nanos() / 1_000_000) as u64);
+                    if let Some(late) = req
+                        .deadline_ms
+                        .and_then(|d| magicrune::schema::overdue(d, queued_at, unix_ms()))
+                    {
+                        tracing::warn!(run_id = %run_id, subject = %msg.subject, overdue_ms = late, "consume: expired");
+                        let mut res = magicrune::schema::SpellResult {
+                            run_id: run_id.clone(),
+                            verdict: magicrune::schema::EXPIRED_VERDICT.into(),
+                            tenant: tenant.clone(),
+                            findings: vec![magicrune::schema::expired_finding(late)],
+                            ..Default::default()
+                        };
+                        res.instance_id = instance_id.clone();
+                        keyring.sign_result(&mut res)?;
+                        let subj = cfg.subjects.reply_to(reply.as_deref(), &run_id, &res.tenant);
+                        if webhook::dispatch(&wh, Some(&*req.callback_url), &res).await
+                            && !faults.fail_publish(&run_id)
+                        {
+                            let (headers, body) = result_message(&res, accepts_zstd, &compression)?;
+                            let _ = send_result(&nc, subj, headers, body).await;
+                        }
+                        let _ = msg.ack().await;
+                        continue;
+                    }
+                    // Offline (`MAGICRUNE_OFFLINE=1`, `capabilities.net.default:
+                    // disabled_hard`): any request for network is refused.
+                    let offline = magicrune::engine::offline_reason(
+                        std::env::var("MAGICRUNE_OFFLINE").as_deref() == Ok("1"),
+                        &policy,
+                    );
+                    let net_refused = offline
+                        .filter(|_| net_intent || !req.allow_net.is_empty())
+                        .map(|why| Message::new(Code::NetDisabled, &[&why]));
+                    if net_refused.is_some() || (net_intent && req.allow_net.is_empty()) {
+                        let mut res = magicrune::schema::SpellResult {
                             run_id: run_id.clone(),
                             verdict: "red".into(),
                             risk_score: 80,
-                            exit_code: 20,
+                            exit_code: 0,
                             duration_ms: 0,
                             stdout_trunc: false,
-                            sbom_attestation: None,
+                            tenant: tenant.clone(),
+                            findings: vec![net_refused
+                                .unwrap_or_else(|| Message::new(Code::NetNoAllowlist, &[]))
+                                .finding()],
+                            ..Default::default()
                         };
-                        let subj = format!("run.res.{}", run_id);
-                        let total_delay = delay_ms + jitter_ms(jitter);
-                        if total_delay > 0 {
-                            tokio::time::sleep(std::time::Duration::from_millis(total_delay)).await;
+                        res.instance_id = instance_id.clone();
+                        keyring.sign_result(&mut res)?;
+                        magicrune::observability::log_policy_decision(
+                            &run_id,
+                            &msg.subject,
+                            &policy.digest,
+                            &res.verdict,
+                            res.risk_score,
+                        );
+                        let subj = cfg.subjects.reply_to(reply.as_deref(), &run_id, &res.tenant);
+                        faults.delay().await;
+                        notify_red(&res).await;
+                        if webhook::dispatch(&wh, Some(&*req.callback_url), &res).await
+ && !faults.fail_publish(&run_id)
+ {
+                            let (headers, body) = result_message(&res, accepts_zstd, &compression)?;
+                            let _ = send_result(&nc, subj, headers, body).await;
                         }
-                        let _ = js.publish(subj, serde_json::to_vec(&res)?.into()).await;
                         count_red += 1;
-                        if !(skip_ack_once && skipped_once.insert(run_id.clone())) {
+                        if !faults.drop_ack(&run_id) {
                             let _ = msg.ack().await;
                         }
                         if let Some(path) = &metrics_file {
@@ -1442,16 +2117,39 @@ fn consume_entry(url: &str, subject: &str) -> anyhow::Result<()> {
                         }
                         continue;
                     }
+                    let mut tally = magicrune::grader::RiskTally::default();
+                    let waivers_refused = magicrune::engine::accept_risk(&req.accept_risk, &policy, &mut tally);
                     if cmd_l.contains("ssh ") {
-                        risk_score += 30;
+                        tally.add("ssh", magicrune::grader::weights::SSH);
                     }
+                    risk_score += tally.score();
+                    let waivers = magicrune::engine::waivers(&req.accept_risk, &tally);
+                    // Outside the policy's change window nothing is written
+                    // or run (see `magicrune::schedule`).
+                    let uses = magicrune::schedule::Uses {
+                        net: net_intent || !req.allow_net.is_empty(),
+                        fs: !req.files.is_empty(),
+                        devices: magicrune::policy::extract_device_paths(&req.cmd)
+                            .iter()
+                            .any(|d| {
+                                !magicrune::sandbox::STANDARD_DEVICES
+                                    .iter()
+                                    .any(|s| magicrune::policy::pat_matches(d, s))
+                            }),
+                    };
+                    let outside_window = policy
+                        .schedule
+                        .and_then(|s| s.violation(uses, std::time::SystemTime::now()));
 
                     // Files
-                    let mut fs_violation = false;
-                    for f in &req.files {
-                        let p = std::path::Path::new(&f.path);
+                    let mut fs_violation = None;
+                    for f in req.files.iter().filter(|_| outside_window.is_none()) {
+                        let p = std::path::Path::new(&*f.path);
                         if !p.is_absolute() || f.path.contains("..") {
-                            fs_violation = true;
+                            fs_violation = Some(Message::new(
+                                Code::Schema,
+                                &[&"file.path must be absolute and must not contain '..'"],
+                            ));
                             break;
                         }
                         let allowed_tmp = p.starts_with("/tmp/");
@@ -1469,15 +2167,15 @@ fn consume_entry(url: &str, subject: &str) -> anyhow::Result<()> {
                             }
                         }
                         if !allowed {
-                            fs_violation = true;
+                            fs_violation = Some(Message::new(Code::WriteDenied, &[&f.path]));
                             break;
                         }
                         if let Some(dir) = p.parent() {
                             let _ = std::fs::create_dir_all(dir);
                         }
                         if !f.content_b64.is_empty() {
-                            if let Ok(bytes) =
-                                base64::engine::general_purpose::STANDARD.decode(&f.content_b64)
+                            if let Ok(bytes) = base64::engine::general_purpose::STANDARD
+                                .decode(f.content_b64.as_bytes())
                             {
                                 let _ = std::fs::write(p, &bytes);
                             }
@@ -1485,24 +2183,47 @@ fn consume_entry(url: &str, subject: &str) -> anyhow::Result<()> {
                             let _ = std::fs::write(p, []);
                         }
                     }
-                    if fs_violation {
-                        let res = SpellResult {
+                    if fs_violation.is_some() || outside_window.is_some() {
+                        let mut res = magicrune::schema::SpellResult {
                             run_id: run_id.clone(),
                             verdict: "red".into(),
                             risk_score: risk_score.max(80),
-                            exit_code: 20,
+                            exit_code: 0,
                             duration_ms: 0,
                             stdout_trunc: false,
-                            sbom_attestation: None,
+                            tenant: tenant.clone(),
+                            findings: fs_violation.iter().map(Message::finding).collect(),
+                            ..Default::default()
                         };
-                        let subj = format!("run.res.{}", run_id);
-                        let total_delay = delay_ms + jitter_ms(jitter);
-                        if total_delay > 0 {
-                            tokio::time::sleep(std::time::Duration::from_millis(total_delay)).await;
+                        if let Some(why) = outside_window {
+                            tracing::warn!(run_id = %run_id, subject = %msg.subject, reason = %why, "consume: outside change window");
+                            res.risk_score = magicrune::grader::weights::OUTSIDE_WINDOW;
+                            res.findings.push(magicrune::schema::Finding {
+                                kind: magicrune::schedule::OUTSIDE_WINDOW.into(),
+                                detail: why,
+                                count: 1,
+                            });
+                        }
+                        res.instance_id = instance_id.clone();
+                        keyring.sign_result(&mut res)?;
+                        magicrune::observability::log_policy_decision(
+                            &run_id,
+                            &msg.subject,
+                            &policy.digest,
+                            &res.verdict,
+                            res.risk_score,
+                        );
+                        let subj = cfg.subjects.reply_to(reply.as_deref(), &run_id, &res.tenant);
+                        faults.delay().await;
+                        notify_red(&res).await;
+                        if webhook::dispatch(&wh, Some(&*req.callback_url), &res).await
+ && !faults.fail_publish(&run_id)
+ {
+                            let (headers, body) = result_message(&res, accepts_zstd, &compression)?;
+                            let _ = send_result(&nc, subj, headers, body).await;
                         }
-                        let _ = js.publish(subj, serde_json::to_vec(&res)?.into()).await;
                         count_red += 1;
-                        if !(skip_ack_once && skipped_once.insert(run_id.clone())) {
+                        if !faults.drop_ack(&run_id) {
                             let _ = msg.ack().await;
                         }
                         if let Some(path) = &metrics_file {
@@ -1520,16 +2241,88 @@ fn consume_entry(url: &str, subject: &str) -> anyhow::Result<()> {
                         continue;
                     }
 
+                    let will_run = std::env::var("MAGICRUNE_DRY_RUN").ok().as_deref() != Some("1")
+                        && !req.cmd.trim().is_empty();
+                    // A run id that already ran is answered from its stored
+                    // result (see `magicrune::guard`).
+                    let mut interrupted = None;
+                    if let Some(g) = guard.as_ref().filter(|_| will_run) {
+                        use magicrune::guard::{Begin, OnRedelivery};
+                        let begun = match g.begin(&run_id, &instance_id) {
+                            Ok(b) => b,
+                            Err(e) => {
+                                tracing::warn!(run_id = %run_id, error = %e, "consume: guard failed");
+                                let _ = msg.ack_with(jetstream::AckKind::Nak(None)).await;
+                                continue;
+                            }
+                        };
+                        let replied = match begun {
+                            Begin::Fresh => None,
+                            Begin::Finished(res) => Some(*res),
+                            Begin::Interrupted(seen) => match policy.on_redelivery {
+                                OnRedelivery::Rerun => {
+                                    g.restart(&run_id, &instance_id, &seen)?;
+                                    interrupted = Some(seen);
+                                    None
+                                }
+                                OnRedelivery::Fail => {
+                                    tracing::warn!(run_id = %run_id, "consume: interrupted run refused");
+                                    let mut res = magicrune::schema::SpellResult {
+                                        run_id: run_id.clone(),
+                                        verdict: "red".into(),
+                                        risk_score: 80,
+                                        tenant: tenant.clone(),
+                                        instance_id: instance_id.clone(),
+                                        interrupted: Some(seen),
+                                        ..Default::default()
+                                    };
+                                    keyring.sign_result(&mut res)?;
+                                    Some(res)
+                                }
+                                OnRedelivery::ReturnPartial => {
+                                    let mut res = magicrune::guard::partial_result(
+                                        &run_id,
+                                        tally.verdict(&policy.thresholds).as_str(),
+                                        risk_score,
+                                        seen,
+                                    );
+                                    res.tenant = tenant.clone();
+                                    res.instance_id = instance_id.clone();
+                                    keyring.sign_result(&mut res)?;
+                                    Some(res)
+                                }
+                            },
+                        };
+                        if let Some(res) = replied {
+                            let subj = cfg.subjects.reply_to(reply.as_deref(), &run_id, &res.tenant);
+                            notify_red(&res).await;
+                            if webhook::dispatch(&wh, Some(&*req.callback_url), &res).await
+ && !faults.fail_publish(&run_id)
+ {
+                                let (headers, body) = result_message(&res, accepts_zstd, &compression)?;
+                                let _ = send_result(&nc, subj, headers, body).await;
+                            }
+                            let _ = msg.ack().await;
+                            continue;
+                        }
+                    }
+
+                    // Time on the stream, up to here.
+                    let queue_ms = queued_at.map(|q| unix_ms().saturating_sub(q));
+
                     // Execute with wall timeout
                     let mut exit_code = 0i32;
                     let mut duration_ms: u64 = 0;
-                    if std::env::var("MAGICRUNE_DRY_RUN").ok().as_deref() != Some("1")
-                        && !req.cmd.trim().is_empty()
-                    {
-                        let started = std::time::Instant::now();
-                        let mut child = std::process::Command::new("bash")
-                            .arg("-lc")
-                            .arg(&req.cmd)
+                    let mut timed_out = false;
+                    let mut shell = String::new();
+                    let mut pids_peak = None;
+                    let mut usage = None;
+                    if will_run {
+                        let started = clock.now_millis();
+                        let sh = magicrune::sandbox::shell::Shell::resolve(policy.shell);
+                        shell = sh.as_str().to_string();
+                        let mut child = sh
+                            .command(&req.cmd)
                             .stdin(std::process::Stdio::piped())
                             .stdout(std::process::Stdio::piped())
                             .stderr(std::process::Stdio::piped())
@@ -1540,58 +2333,117 @@ fn consume_entry(url: &str, subject: &str) -> anyhow::Result<()> {
                                 let _ = sin.write_all(req.stdin.as_bytes());
                             }
                         }
-                        let deadline = std::time::Instant::now()
-                            + std::time::Duration::from_secs(limits.wall_sec);
+                        let mut sampler = magicrune::sandbox::usage::Sampler::default();
+                        let wall = std::time::Duration::from_secs(limits.wall_sec);
+                        let progress = cfg.progress_interval();
+                        let mut next_progress = std::time::Instant::now() + progress;
                         loop {
+                            sampler.poll(child.id());
+                            // Restart the ack wait so the server does not
+                            // redeliver a message that is still running.
+                            if std::time::Instant::now() >= next_progress {
+                                next_progress += progress;
+                                if let Err(e) = msg.ack_with(jetstream::AckKind::Progress).await {
+                                    tracing::warn!(run_id = %run_id, error = %e, "consume: progress ack failed");
+                                }
+                            }
                             if let Ok(Some(status)) = child.try_wait() {
                                 let _ = child.wait_with_output();
-                                duration_ms = started.elapsed().as_millis() as u64;
-                                if let Some(c) = status.code() {
-                                    exit_code = c;
-                                }
+                                duration_ms = clock.since(started).as_millis() as u64;
+                                exit_code = magicrune::sandbox::classify_exit(&status).0;
                                 break;
                             }
-                            if std::time::Instant::now() >= deadline {
-                                let _ = child.kill();
-                                duration_ms = started.elapsed().as_millis() as u64;
-                                exit_code = 20;
+                            if clock.since(started) >= wall {
+                                if let Ok(status) = magicrune::sandbox::terminate(
+                                    &mut child,
+                                    std::time::Duration::from_millis(limits.kill_grace_ms),
+                                    &clock,
+                                )
+                                .await
+                                {
+                                    exit_code = magicrune::sandbox::classify_exit(&status).0;
+                                }
+                                duration_ms = clock.since(started).as_millis() as u64;
+                                timed_out = true;
                                 break;
                             }
-                            std::thread::sleep(std::time::Duration::from_millis(25));
+                            tokio::time::sleep(std::time::Duration::from_millis(25)).await;
                         }
+                        usage = sampler.usage(clock.since(started));
+                        pids_peak = usage.is_some().then(|| sampler.pids_peak());
                     }
 
-                    let thresholds = load_thresholds_from_policy(&policy_path);
-                    let verdict = decide_verdict_from_thresholds(risk_score, &thresholds);
-                    let res = SpellResult {
+                    let mut verdict = tally.verdict(&policy.thresholds);
+                    if timed_out {
+                        verdict = Verdict::Red;
+                    }
+                    let mut res = magicrune::schema::SpellResult {
                         run_id: run_id.clone(),
                         verdict: verdict.to_string(),
                         risk_score,
                         exit_code,
                         duration_ms,
                         stdout_trunc: false,
-                        sbom_attestation: None,
+                        shell,
+                        pids_peak,
+                        usage,
+                        interrupted,
+                        queue_ms,
+                        findings: waivers_refused,
+                        waivers,
+                        network: offline
+                            .map(|_| magicrune::schema::NETWORK_DISABLED.to_string())
+                            .unwrap_or_default(),
+                        reason: timed_out
+                            .then(|| {
+                                magicrune::engine::stop_reason(
+                                    &magicrune::sandbox::TerminationReason::WallTimeout,
+                                    req.timeout_sec,
+                                    &Default::default(),
+                                    &limits,
+                                )
+                            })
+                            .flatten(),
+                        ..Default::default()
                     };
-                    let subj = format!("run.res.{}", run_id);
-                    let total_delay = delay_ms + jitter_ms(jitter);
-                    if total_delay > 0 {
-                        tokio::time::sleep(std::time::Duration::from_millis(total_delay)).await;
+                    res.instance_id = instance_id.clone();
+                    keyring.sign_result(&mut res)?;
+                    if let Some(g) = guard.as_ref().filter(|_| will_run) {
+                        g.finish(&res)?;
                     }
-                    let _ = js
-                        .publish(subj.clone(), serde_json::to_vec(&res)?.into())
-                        .await;
-                    if !(skip_ack_once && skipped_once.insert(run_id.clone())) {
+                    magicrune::metrics::observe(&res);
+                    magicrune::observability::log_policy_decision(
+                        &run_id,
+                        &msg.subject,
+                        &policy.digest,
+                        &res.verdict,
+                        res.risk_score,
+                    );
+                    let subj = cfg.subjects.reply_to(reply.as_deref(), &run_id, &res.tenant);
+                    faults.delay().await;
+                    notify_red(&res).await;
+                    if webhook::dispatch(&wh, Some(&*req.callback_url), &res).await
+ && !faults.fail_publish(&run_id)
+ {
+                        let (headers, body) = result_message(&res, accepts_zstd, &compression)?;
+                        let _ = send_result(&nc, subj.clone(), headers, body).await;
+                    }
+                    if !faults.drop_ack(&run_id) {
                         let _ = msg.ack().await;
                     }
 
-                    let ack_subj = format!("run.ack.{}", run_id);
-                    let mut ack = nc.subscribe(ack_subj).await?;
-                    let ack_ack_wait = env_u64("ACK_ACK_WAIT_SEC", 2);
-                    let _ = tokio::time::timeout(
-                        std::time::Duration::from_secs(ack_ack_wait),
-                        ack.next(),
-                    )
-                    .await;
+                    // A result sent to the request's reply subject is not
+                    // acknowledged.
+                    if reply.is_none() {
+                        let ack_subj = cfg.subjects.ack(&run_id, &res.tenant);
+                        let mut ack = nc.subscribe(ack_subj).await?;
+                        let ack_ack_wait = cfg.ack_ack_wait_sec;
+                        let _ = tokio::time::timeout(
+                            std::time::Duration::from_secs(ack_ack_wait),
+                            ack.next(),
+                        )
+                        .await;
+                    }
                     if let Some(path) = &metrics_file {
                         let _ = std::fs::write(
                             path,
@@ -1605,16 +2457,27 @@ fn consume_entry(url: &str, subject: &str) -> anyhow::Result<()> {
                         write_text_metrics(p, count_total, count_dupe, count_red, "magicrune");
                     }
                     if metrics_every > 0 && count_total % metrics_every == 0 {
-                        eprintln!(
-                            "magicrune consume: processed={} dupes={} reds={}",
-                            count_total, count_dupe, count_red
+                        let cache = magicrune::policy::PolicyStore::global().stats();
+                        tracing::info!(
+                            processed = count_total,
+                            dupes = count_dupe,
+                            reds = count_red,
+                            policy_cache_hits = cache.hits,
+                            policy_reloads = cache.reloads,
+                            "consume: status"
                         );
                     }
                 }
                 return Ok(());
             }
+            tracing::warn!(stream = %name, "consume: no JetStream stream, subscribing to the subject");
         }
         let mut sub = nc.subscribe(subject.to_string()).await?;
+        if cfg.mode == ConsumeMode::Core {
+            if let Some(h) = &health {
+                h.set("stream", true);
+            }
+        }
 
         let mut seen: HashSet<String> = HashSet::new();
         let mut order: VecDeque<String> = VecDeque::new();
@@ -1639,22 +2502,47 @@ fn consume_entry(url: &str, subject: &str) -> anyhow::Result<()> {
                 }
             }
 
-            let req_val: serde_json::Value = match serde_json::from_slice(&msg.payload) {
-                Ok(v) => v,
-                Err(_) => continue,
+            let reply = msg.reply.as_ref().map(|r| r.to_string());
+            let (body, accepts_zstd) =
+                match open_message(msg.headers.as_ref(), &msg.payload, &compression) {
+                    Ok(b) => b,
+                    Err(e) => {
+                        tracing::warn!(subject = %msg.subject, error = %e, "consume: dropping");
+                        continue;
+                    }
+                };
+            let fetched = match magicrune::jet::jet_impl::fetch_bundle(&nc, &body).await {
+                Ok(b) => b,
+                Err(e) => {
+                    tracing::warn!(subject = %msg.subject, error = %e, "consume: dropping");
+                    continue;
+                }
             };
-            let mut seed_le = 0u64.to_le_bytes().to_vec();
-            if let Some(s) = req_val.get("seed").and_then(|x| x.as_u64()) {
-                seed_le = s.to_le_bytes().to_vec();
-            }
-            let mut all = msg.payload.to_vec();
-            all.extend_from_slice(&seed_le);
-            let run_id = format!("r_{}", sha256_hex(&all));
-
-            let req: SpellRequest = match serde_json::from_slice(&msg.payload) {
+            let raw = fetched.as_deref().unwrap_or(&body);
+            let Ok(payload) = template::render(raw, &template::Vars::new()) else {
+                continue;
+            };
+            let req = match RequestView::parse(&payload) {
                 Ok(r) => r,
                 Err(_) => continue,
             };
+            let seed = req.seed().unwrap_or(0);
+            let run_id = magicrune::engine::compute_run_id(&payload, Some(seed));
+            let bound = bound_tenant
+                .clone()
+                .or_else(|| tenant::from_subject(&msg.subject));
+            let tenant = match tenant::resolve(bound.as_deref(), &req.tenant) {
+                Ok(t) => t,
+                Err(e) => {
+                    tracing::warn!(
+                        run_id = %run_id,
+                        subject = %msg.subject,
+                        error = %e,
+                        "consume: dropping"
+                    );
+                    continue;
+                }
+            };
 
             // Minimal grading and policy checks
             let cmd_l = req.cmd.to_lowercase();
@@ -1665,31 +2553,124 @@ fn consume_entry(url: &str, subject: &str) -> anyhow::Result<()> {
                 || cmd_l.contains("https://");
             let policy_path = std::env::var("MAGICRUNE_POLICY")
                 .unwrap_or_else(|_| "policies/default.policy.yml".to_string());
-            let limits = load_limits_from_policy(&policy_path);
-            if net_intent && req.allow_net.is_empty() {
-                let res = SpellResult {
+            let policy = magicrune::policy::PolicyStore::get(&policy_path);
+            let limits = magicrune::policy::PolicyLimits {
+                wall_sec: policy.limits.wall_for(req.timeout_sec),
+                ..policy.limits
+            };
+            if let Err(e) = magicrune::engine::authenticate_request(raw, &policy, &producer_keys) {
+                tracing::warn!(run_id = %run_id, subject = %msg.subject, error = %e, "consume: rejected");
+                let mut res = magicrune::schema::SpellResult {
+                    run_id: run_id.clone(),
+                    verdict: magicrune::schema::REJECTED_VERDICT.into(),
+                    risk_score: 80,
+                    tenant: tenant.clone(),
+                    findings: vec![e.finding()],
+                    ..Default::default()
+                };
+                res.instance_id = instance_id.clone();
+                keyring.sign_result(&mut res)?;
+                magicrune::observability::log_policy_decision(
+                    &run_id,
+                    &msg.subject,
+                    &policy.digest,
+                    &res.verdict,
+                    res.risk_score,
+                );
+                let subj = cfg.subjects.reply_to(reply.as_deref(), &run_id, &res.tenant);
+                notify_red(&res).await;
+                if webhook::dispatch(&wh, Some(&*req.callback_url), &res).await
+ && !faults.fail_publish(&run_id)
+ {
+                    let (headers, body) = result_message(&res, accepts_zstd, &compression)?;
+                    let _ = send_result(&nc, subj, headers, body).await;
+                }
+                continue;
+            }
+            // Past an absolute `deadline_ms`; core NATS keeps no queue
+            // time for relative ones.
+            if let Some(late) = req
+                .deadline_ms
+                .and_then(|d| magicrune::schema::overdue(d, None, unix_ms()))
+            {
+                tracing::warn!(run_id = %run_id, subject = %msg.subject, overdue_ms = late, "consume: expired");
+                let mut res = magicrune::schema::SpellResult {
+                    run_id: run_id.clone(),
+                    verdict: magicrune::schema::EXPIRED_VERDICT.into(),
+                    tenant: tenant.clone(),
+                    findings: vec![magicrune::schema::expired_finding(late)],
+                    ..Default::default()
+                };
+                res.instance_id = instance_id.clone();
+                keyring.sign_result(&mut res)?;
+                let subj = cfg.subjects.reply_to(reply.as_deref(), &run_id, &res.tenant);
+                if webhook::dispatch(&wh, Some(&*req.callback_url), &res).await
+                    && !faults.fail_publish(&run_id)
+                {
+                    let (headers, body) = result_message(&res, accepts_zstd, &compression)?;
+                    let _ = send_result(&nc, subj, headers, body).await;
+                }
+                continue;
+            }
+            // Offline (`MAGICRUNE_OFFLINE=1`, `capabilities.net.default:
+            // disabled_hard`): any request for network is refused.
+            let offline = magicrune::engine::offline_reason(
+                std::env::var("MAGICRUNE_OFFLINE").as_deref() == Ok("1"),
+                &policy,
+            );
+            let net_refused = offline
+                .filter(|_| net_intent || !req.allow_net.is_empty())
+                .map(|why| Message::new(Code::NetDisabled, &[&why]));
+            if net_refused.is_some() || (net_intent && req.allow_net.is_empty()) {
+                let mut res = magicrune::schema::SpellResult {
                     run_id: run_id.clone(),
                     verdict: "red".into(),
                     risk_score: 80,
-                    exit_code: 20,
+                    exit_code: 0,
                     duration_ms: 0,
                     stdout_trunc: false,
-                    sbom_attestation: None,
+                    tenant: tenant.clone(),
+                    findings: vec![net_refused
+                        .unwrap_or_else(|| Message::new(Code::NetNoAllowlist, &[]))
+                        .finding()],
+                    ..Default::default()
                 };
-                let subj = format!("run.res.{}", run_id);
-                let _ = nc.publish(subj, serde_json::to_vec(&res)?.into()).await;
+                res.instance_id = instance_id.clone();
+                keyring.sign_result(&mut res)?;
+                magicrune::observability::log_policy_decision(
+                    &run_id,
+                    &msg.subject,
+                    &policy.digest,
+                    &res.verdict,
+                    res.risk_score,
+                );
+                let subj = cfg.subjects.reply_to(reply.as_deref(), &run_id, &res.tenant);
+                notify_red(&res).await;
+                if webhook::dispatch(&wh, Some(&*req.callback_url), &res).await
+ && !faults.fail_publish(&run_id)
+ {
+                    let (headers, body) = result_message(&res, accepts_zstd, &compression)?;
+                    let _ = send_result(&nc, subj, headers, body).await;
+                }
                 continue;
             }
+            let mut tally = magicrune::grader::RiskTally::default();
+            let waivers_refused = magicrune::engine::accept_risk(&req.accept_risk, &policy, &mut tally);
             if cmd_l.contains("ssh ") {
-                risk_score += 30;
+                tally.add("ssh", magicrune::grader::weights::SSH);
             }
+            risk_score += tally.score();
+            let waivers = magicrune::engine::waivers(&req.accept_risk, &tally);
 
             // Materialize files subject to allow_fs
-            let mut fs_violation = false;
+            let mut fs_violation = None;
             for f in &req.files {
-                let p = std::path::Path::new(&f.path);
+                let p = std::path::Path::new(&*f.path);
                 if !p.is_absolute() || f.path.contains("..") {
-                    fs_violation = true;
+                    fs_violation = Some(Message::new(
+                        Code::Schema,
+                        &[&"file.path must be absolute and must not contain '..'"],
+                    ));
                     break;
                 }
                 let allowed_tmp = p.starts_with("/tmp/");
@@ -1707,7 +2688,7 @@ fn consume_entry(url: &str, subject: &str) -> anyhow::Result<()> {
                     }
                 }
                 if !allowed {
-                    fs_violation = true;
+                    fs_violation = Some(Message::new(Code::WriteDenied, &[&f.path]));
                     break;
                 }
                 if let Some(dir) = p.parent() {
@@ -1715,7 +2696,7 @@ fn consume_entry(url: &str, subject: &str) -> anyhow::Result<()> {
                 }
                 if !f.content_b64.is_empty() {
                     if let Ok(bytes) =
-                        base64::engine::general_purpose::STANDARD.decode(&f.content_b64)
+                        base64::engine::general_purpose::STANDARD.decode(f.content_b64.as_bytes())
                     {
                         let _ = std::fs::write(p, &bytes);
                     }
@@ -1723,31 +2704,53 @@ fn consume_entry(url: &str, subject: &str) -> anyhow::Result<()> {
                     let _ = std::fs::write(p, []);
                 }
             }
-            if fs_violation {
-                let res = SpellResult {
+            if fs_violation.is_some() {
+                let mut res = magicrune::schema::SpellResult {
                     run_id: run_id.clone(),
                     verdict: "red".into(),
                     risk_score: risk_score.max(80),
-                    exit_code: 20,
+                    exit_code: 0,
                     duration_ms: 0,
                     stdout_trunc: false,
-                    sbom_attestation: None,
+                    tenant: tenant.clone(),
+                    findings: fs_violation.iter().map(Message::finding).collect(),
+                    ..Default::default()
                 };
-                let subj = format!("run.res.{}", run_id);
-                let _ = nc.publish(subj, serde_json::to_vec(&res)?.into()).await;
+                res.instance_id = instance_id.clone();
+                keyring.sign_result(&mut res)?;
+                magicrune::observability::log_policy_decision(
+                    &run_id,
+                    &msg.subject,
+                    &policy.digest,
+                    &res.verdict,
+                    res.risk_score,
+                );
+                let subj = cfg.subjects.reply_to(reply.as_deref(), &run_id, &res.tenant);
+                notify_red(&res).await;
+                if webhook::dispatch(&wh, Some(&*req.callback_url), &res).await
+ && !faults.fail_publish(&run_id)
+ {
+                    let (headers, body) = result_message(&res, accepts_zstd, &compression)?;
+                    let _ = send_result(&nc, subj, headers, body).await;
+                }
                 continue;
             }
 
             // Execute with wall timeout
             let mut exit_code = 0i32;
             let mut duration_ms: u64 = 0;
+            let mut timed_out = false;
+            let mut shell = String::new();
+            let mut pids_peak = None;
+            let mut usage = None;
             if std::env::var("MAGICRUNE_DRY_RUN").ok().as_deref() != Some("1")
                 && !req.cmd.trim().is_empty()
             {
-                let started = std::time::Instant::now();
-                let mut child = std::process::Command::new("bash")
-                    .arg("-lc")
-                    .arg(&req.cmd)
+                let started = clock.now_millis();
+                let sh = magicrune::sandbox::shell::Shell::resolve(policy.shell);
+                shell = sh.as_str().to_string();
+                let mut child = sh
+                    .command(&req.cmd)
                     .stdin(std::process::Stdio::piped())
                     .stdout(std::process::Stdio::piped())
                     .stderr(std::process::Stdio::piped())
@@ -1758,207 +2761,96 @@ fn consume_entry(url: &str, subject: &str) -> anyhow::Result<()> {
                         let _ = sin.write_all(req.stdin.as_bytes());
                     }
                 }
-                let deadline =
-                    std::time::Instant::now() + std::time::Duration::from_secs(limits.wall_sec);
+                let mut sampler = magicrune::sandbox::usage::Sampler::default();
+                let wall = std::time::Duration::from_secs(limits.wall_sec);
                 loop {
+                    sampler.poll(child.id());
                     if let Ok(Some(status)) = child.try_wait() {
                         let _ = child.wait_with_output();
-                        duration_ms = started.elapsed().as_millis() as u64;
-                        if let Some(c) = status.code() {
-                            exit_code = c;
-                        }
+                        duration_ms = clock.since(started).as_millis() as u64;
+                        exit_code = magicrune::sandbox::classify_exit(&status).0;
                         break;
                     }
-                    if std::time::Instant::now() >= deadline {
-                        let _ = child.kill();
-                        duration_ms = started.elapsed().as_millis() as u64;
-                        exit_code = 20;
+                    if clock.since(started) >= wall {
+                        if let Ok(status) = magicrune::sandbox::terminate(
+                            &mut child,
+                            std::time::Duration::from_millis(limits.kill_grace_ms),
+                            &clock,
+                        )
+                        .await
+                        {
+                            exit_code = magicrune::sandbox::classify_exit(&status).0;
+                        }
+                        duration_ms = clock.since(started).as_millis() as u64;
+                        timed_out = true;
                         break;
                     }
                     std::thread::sleep(std::time::Duration::from_millis(25));
                 }
+                usage = sampler.usage(clock.since(started));
+                pids_peak = usage.is_some().then(|| sampler.pids_peak());
             }
 
             // Verdict mapping
-            let thresholds = load_thresholds_from_policy(&policy_path);
-            let verdict = decide_verdict_from_thresholds(risk_score, &thresholds);
-            let res = SpellResult {
+            let mut verdict =
+                tally.verdict(&policy.thresholds);
+            if timed_out {
+                verdict = Verdict::Red;
+            }
+            let mut res = magicrune::schema::SpellResult {
                 run_id: run_id.clone(),
                 verdict: verdict.to_string(),
                 risk_score,
                 exit_code,
                 duration_ms,
                 stdout_trunc: false,
-                sbom_attestation: None,
+                shell,
+                pids_peak,
+                usage,
+                findings: waivers_refused,
+                waivers,
+                network: offline
+                    .map(|_| magicrune::schema::NETWORK_DISABLED.to_string())
+                    .unwrap_or_default(),
+                reason: timed_out
+                    .then(|| {
+                        magicrune::engine::stop_reason(
+                            &magicrune::sandbox::TerminationReason::WallTimeout,
+                            req.timeout_sec,
+                            &Default::default(),
+                            &limits,
+                        )
+                    })
+                    .flatten(),
+                ..Default::default()
             };
-            let subj = format!("run.res.{}", run_id);
-            let _ = nc
-                .publish(subj.clone(), serde_json::to_vec(&res)?.into())
-                .await;
-
-            // ack-ack wait
-            let ack_subj = format!("run.ack.{}", run_id);
-            let mut ack = nc.subscribe(ack_subj).await?;
-            let _ = tokio::time::timeout(std::time::Duration::from_secs(2), ack.next()).await;
+            res.instance_id = instance_id.clone();
+            keyring.sign_result(&mut res)?;
+            magicrune::metrics::observe(&res);
+            magicrune::observability::log_policy_decision(
+                &run_id,
+                &msg.subject,
+                &policy.digest,
+                &res.verdict,
+                res.risk_score,
+            );
+            let subj = cfg.subjects.reply_to(reply.as_deref(), &run_id, &res.tenant);
+            notify_red(&res).await;
+            if webhook::dispatch(&wh, Some(&*req.callback_url), &res).await
+ && !faults.fail_publish(&run_id)
+ {
+                let (headers, body) = result_message(&res, accepts_zstd, &compression)?;
+                let _ = send_result(&nc, subj.clone(), headers, body).await;
+            }
+
+            // ack-ack wait, unless the result went to the request's reply
+            // subject
+            if reply.is_none() {
+                let ack_subj = cfg.subjects.ack(&run_id, &res.tenant);
+                let mut ack = nc.subscribe(ack_subj).await?;
+                let _ = tokio::time::timeout(std::time::Duration::from_secs(cfg.ack_ack_wait_sec), ack.next()).await;
+            }
         }
         Ok(())
     })
 }
-// Minimal patterns: '*' wildcard, suffix '/**' for subtree
-fn pat_matches(s: &str, pat: &str) -> bool {
-    if pat == "*" {
-        return true;
-    }
-    if let Some(base) = pat.strip_suffix("/**") {
-        return s.starts_with(base);
-    }
-    if pat.starts_with('*') && pat.ends_with('*') {
-        let needle = &pat[1..pat.len() - 1];
-        return s.contains(needle);
-    }
-    if let Some(stripped) = pat.strip_prefix('*') {
-        return s.ends_with(stripped);
-    }
-    if let Some(stripped) = pat.strip_suffix('*') {
-        return s.starts_with(stripped);
-    }
-    s == pat
-}
-
-fn load_fs_readonly_from_policy(path: &str) -> Vec<String> {
-    let text = match std::fs::read_to_string(path) {
-        Ok(s) => s,
-        Err(_) => return vec![],
-    };
-    let mut out = Vec::new();
-    let mut in_caps = false;
-    let mut in_fs = false;
-    let mut in_ro = false;
-    let (mut ci, mut fi, mut ri) = (0usize, 0usize, 0usize);
-    for raw in text.lines() {
-        let indent = raw.chars().take_while(|c| c.is_whitespace()).count();
-        let line = raw.trim();
-        if line.is_empty() || line.starts_with('#') {
-            continue;
-        }
-        if !in_caps && line == "capabilities:" {
-            in_caps = true;
-            ci = indent;
-            continue;
-        }
-        if in_caps {
-            if indent <= ci {
-                in_caps = false;
-                in_fs = false;
-                in_ro = false;
-            }
-            if !in_fs && line == "fs:" {
-                in_fs = true;
-                fi = indent;
-                continue;
-            }
-            if in_fs {
-                if indent <= fi {
-                    in_fs = false;
-                    in_ro = false;
-                }
-                if !in_ro && line == "readonly:" {
-                    in_ro = true;
-                    ri = indent;
-                    continue;
-                }
-                if in_ro {
-                    if indent <= ri {
-                        in_ro = false;
-                    }
-                    if line.starts_with("- ") {
-                        let v = line.trim_start_matches("- ").trim().trim_matches('"');
-                        if !v.is_empty() {
-                            out.push(v.to_string());
-                        }
-                    }
-                }
-            }
-        }
-    }
-    out
-}
-
-fn load_env_policy_from_policy(path: &str) -> (Vec<String>, Vec<String>) {
-    let text = match std::fs::read_to_string(path) {
-        Ok(s) => s,
-        Err(_) => return (vec![], vec![]),
-    };
-    let mut allow = Vec::new();
-    let mut deny = Vec::new();
-    let mut in_caps = false;
-    let mut in_env = false;
-    let mut in_allow = false;
-    let mut in_deny = false;
-    let (mut ci, mut ei, mut ai, mut di) = (0usize, 0usize, 0usize, 0usize);
-    for raw in text.lines() {
-        let indent = raw.chars().take_while(|c| c.is_whitespace()).count();
-        let line = raw.trim();
-        if line.is_empty() || line.starts_with('#') {
-            continue;
-        }
-        if !in_caps && line == "capabilities:" {
-            in_caps = true;
-            ci = indent;
-            continue;
-        }
-        if in_caps {
-            if indent <= ci {
-                in_caps = false;
-                in_env = false;
-                in_allow = false;
-                in_deny = false;
-            }
-            if !in_env && line == "env:" {
-                in_env = true;
-                ei = indent;
-                continue;
-            }
-            if in_env {
-                if indent <= ei {
-                    in_env = false;
-                    in_allow = false;
-                    in_deny = false;
-                }
-                if !in_allow && line == "allow:" {
-                    in_allow = true;
-                    ai = indent;
-                    continue;
-                }
-                if !in_deny && line == "deny:" {
-                    in_deny = true;
-                    di = indent;
-                    continue;
-                }
-                if in_allow {
-                    if indent <= ai {
-                        in_allow = false;
-                    }
-                    if line.starts_with("- ") {
-                        let v = line.trim_start_matches("- ").trim().trim_matches('"');
-                        if !v.is_empty() {
-                            allow.push(v.to_string());
-                        }
-                    }
-                }
-                if in_deny {
-                    if indent <= di {
-                        in_deny = false;
-                    }
-                    if line.starts_with("- ") {
-                        let v = line.trim_start_matches("- ").trim().trim_matches('"');
-                        if !v.is_empty() {
-                            deny.push(v.to_string());
-                        }
-                    }
-                }
-            }
-        }
-    }
-    (allow, deny)
-}
@@ -0,0 +1,175 @@
+//! Request bundles: a `.spell.tgz` holding `request.json` and raw files.
+//!
+//! Large inputs inflate by a third as `content_b64` and make the request
+//! awkward to pass around; a bundle carries them as plain tar entries
+//! instead. Every regular file other than `request.json` becomes an input
+//! file at `/` + its path in the archive (`tmp/data.csv` is written to
+//! `/tmp/data.csv`). It fills the `files[]` entry of `request.json` with the
+//! same path when there is one, and is appended otherwise. The expanded
+//! request is what runs and what the run id is computed over, so a bundle
+//! runs exactly like the equivalent JSON request.
+
+use crate::error::MagicruneError;
+use crate::messages::Code;
+use std::io::Read;
+
+/// Name of the request inside a bundle.
+pub const REQUEST_ENTRY: &str = "request.json";
+
+/// Largest total size of the files in a bundle, once unpacked.
+pub const MAX_UNPACKED: u64 = 256 << 20;
+
+fn fail(msg: String) -> MagicruneError {
+    MagicruneError::new(Code::Bundle, &[&msg])
+}
+
+/// Gzip magic: anything else is taken for a JSON request.
+pub fn is_bundle(bytes: &[u8]) -> bool {
+    bytes.starts_with(&[0x1f, 0x8b])
+}
+
+/// `raw` as a JSON request: bundles are expanded, anything else is returned
+/// as it is.
+pub fn expand(raw: Vec<u8>) -> Result<Vec<u8>, MagicruneError> {
+    if is_bundle(&raw) {
+        open(&raw)
+    } else {
+        Ok(raw)
+    }
+}
+
+/// Expand a bundle into the equivalent JSON request.
+pub fn open(bytes: &[u8]) -> Result<Vec<u8>, MagicruneError> {
+    use base64::Engine as _;
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(bytes));
+    let mut request: Option<Vec<u8>> = None;
+    let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut total = 0u64;
+    let entries = archive
+        .entries()
+        .map_err(|e| fail(format!("read: {}", e)))?;
+    for entry in entries {
+        let mut entry = entry.map_err(|e| fail(format!("read: {}", e)))?;
+        let kind = entry.header().entry_type();
+        if kind.is_dir() {
+            continue;
+        }
+        let path = entry
+            .path()
+            .map_err(|e| fail(format!("entry name: {}", e)))?
+            .to_string_lossy()
+            .trim_start_matches("./")
+            .to_string();
+        if !kind.is_file() {
+            return Err(fail(format!("{}: only regular files are allowed", path)));
+        }
+        if path.is_empty() || path.starts_with('/') || path.split('/').any(|c| c == "..") {
+            return Err(fail(format!("{}: path must be relative", path)));
+        }
+        total += entry.size();
+        if total > MAX_UNPACKED {
+            return Err(fail(format!("more than {} bytes unpacked", MAX_UNPACKED)));
+        }
+        let mut data = Vec::with_capacity(entry.size() as usize);
+        entry
+            .read_to_end(&mut data)
+            .map_err(|e| fail(format!("{}: {}", path, e)))?;
+        if path == REQUEST_ENTRY {
+            request = Some(data);
+        } else {
+            files.push((format!("/{}", path), data));
+        }
+    }
+    let request = request.ok_or_else(|| fail(format!("no {}", REQUEST_ENTRY)))?;
+    let mut req: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(&request).map_err(|e| fail(format!("{}: {}", REQUEST_ENTRY, e)))?;
+    let listed = req
+        .entry("files")
+        .or_insert_with(|| serde_json::Value::Array(Vec::new()))
+        .as_array_mut()
+        .ok_or_else(|| fail("files must be array".into()))?;
+    let b64 = &base64::engine::general_purpose::STANDARD;
+    for (path, data) in files {
+        let content = serde_json::Value::String(b64.encode(&data));
+        match listed
+            .iter_mut()
+            .find(|f| f.get("path").and_then(|p| p.as_str()) == Some(path.as_str()))
+        {
+            Some(f) => {
+                let has_content = f
+                    .get("content_b64")
+                    .and_then(|c| c.as_str())
+                    .is_some_and(|c| !c.is_empty());
+                if has_content {
+                    return Err(fail(format!("{}: content given twice", path)));
+                }
+                if let Some(f) = f.as_object_mut() {
+                    f.insert("content_b64".into(), content);
+                }
+            }
+            None => listed.push(serde_json::json!({ "path": path, "content_b64": content })),
+        }
+    }
+    serde_json::to_vec(&req).map_err(|e| MagicruneError::internal(format!("serialize: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pack(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        let mut tar = tar::Builder::new(gz);
+        for (path, data) in entries {
+            // Names are set raw: `set_path` refuses the `..` under test.
+            let mut h = tar::Header::new_gnu();
+            h.as_gnu_mut().unwrap().name[..path.len()].copy_from_slice(path.as_bytes());
+            h.set_size(data.len() as u64);
+            h.set_mode(0o644);
+            h.set_cksum();
+            tar.append(&h, *data).unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn test_open_fills_and_appends_files() {
+        let bundle = pack(&[
+            (
+                "request.json",
+                br#"{"cmd":"cat /tmp/a.bin /tmp/b.txt","files":[{"path":"/tmp/a.bin"}]}"#,
+            ),
+            ("tmp/a.bin", &[0, 159, 255]),
+            ("./tmp/b.txt", b"hi"),
+        ]);
+        assert!(is_bundle(&bundle));
+        let req: serde_json::Value = serde_json::from_slice(&expand(bundle).unwrap()).unwrap();
+        assert_eq!(req["cmd"], "cat /tmp/a.bin /tmp/b.txt");
+        assert_eq!(
+            req["files"],
+            serde_json::json!([
+                { "path": "/tmp/a.bin", "content_b64": "AJ//" },
+                { "path": "/tmp/b.txt", "content_b64": "aGk=" },
+            ])
+        );
+        assert_eq!(expand(b"{}".to_vec()).unwrap(), b"{}");
+    }
+
+    #[test]
+    fn test_open_rejects_bad_bundles() {
+        let err = |entries: &[(&str, &[u8])]| match open(&pack(entries)) {
+            Err(MagicruneError::InvalidRequest(e)) => e.text,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(err(&[("tmp/a", b"x")]), "bundle: no request.json");
+        assert!(err(&[("request.json", b"{}"), ("a/../../etc/x", b"")]).contains("relative"));
+        assert!(err(&[
+            (
+                "request.json",
+                br#"{"files":[{"path":"/a","content_b64":"eA=="}]}"#
+            ),
+            ("a", b"x"),
+        ])
+        .contains("given twice"));
+    }
+}
@@ -0,0 +1,219 @@
+//! Running one request under several policies (`exec --compare`).
+//!
+//! The request is run once per policy, in the order given; the first policy
+//! is the baseline. The report lists what each run came to and every way a
+//! later run differs from the baseline: verdict, risk score, exit code, the
+//! risk rules that fired, or an error instead of a result. With dry run set
+//! nothing executes and only the static grading is compared, which is how a
+//! stricter policy can be tried against real requests before it is rolled
+//! out.
+//!
+//! The runs share a run id, so the execution guard is left out: it would
+//! replay the first result for every later policy, and so is the shadow
+//! policy. Callbacks are not delivered either, nor audit events, alerts or
+//! ledger records; the caller only gets the report.
+
+use crate::engine::{self, ExecOptions};
+use crate::policy::Policy;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// What one policy made of the request.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PolicyOutcome {
+    /// The policy as the caller named it, usually its path.
+    pub policy: String,
+    pub policy_digest: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub verdict: String,
+    pub risk_score: u32,
+    pub exit_code: i32,
+    /// Points by risk rule that fired.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub rules: BTreeMap<String, u32>,
+    /// Why there is no result.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Process exit code this outcome alone would give.
+    #[serde(skip)]
+    pub status: i32,
+}
+
+/// One way a policy's outcome differs from the baseline's.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Difference {
+    pub policy: String,
+    /// `verdict`, `risk_score`, `exit_code`, `rules` or `error`.
+    pub field: String,
+    pub baseline: serde_json::Value,
+    pub value: serde_json::Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CompareReport {
+    pub run_id: String,
+    pub dry_run: bool,
+    /// No policy differs from the baseline.
+    pub agree: bool,
+    pub outcomes: Vec<PolicyOutcome>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub differences: Vec<Difference>,
+}
+
+impl CompareReport {
+    /// The exit code of the strictest outcome: what running the request
+    /// under every policy in turn would end with at worst.
+    pub fn exit_code(&self) -> i32 {
+        self.outcomes.iter().map(|o| o.status).max().unwrap_or(0)
+    }
+}
+
+/// Run `raw` under each of `policies` (label and policy; the first is the
+/// baseline) and report the differences.
+pub async fn compare(
+    raw: &[u8],
+    policies: &[(String, Policy)],
+    opts: &ExecOptions,
+) -> CompareReport {
+    let opts = ExecOptions {
+        guard: None,
+        result_cache: None,
+        shadow: None,
+        audit: None,
+        notifier: None,
+        ledger: None,
+        ..opts.clone()
+    };
+    let mut run_id = String::new();
+    let mut outcomes = Vec::with_capacity(policies.len());
+    for (label, policy) in policies {
+        let mut outcome = PolicyOutcome {
+            policy: label.clone(),
+            policy_digest: policy.digest.clone(),
+            ..Default::default()
+        };
+        match engine::execute(raw, policy, &opts).await {
+            Ok(run) => {
+                run_id = run.result.run_id.clone();
+                outcome.verdict = run.result.verdict.clone();
+                outcome.risk_score = run.result.risk_score;
+                outcome.exit_code = run.result.exit_code;
+                outcome.rules = run
+                    .result
+                    .risk_breakdown
+                    .map(|b| b.rules)
+                    .unwrap_or_default();
+                outcome.status = run.verdict.exit_code();
+            }
+            Err(e) => {
+                outcome.status = e.exit_code();
+                outcome.error = Some(e.to_string());
+            }
+        }
+        outcomes.push(outcome);
+    }
+    if run_id.is_empty() {
+        run_id = engine::compute_run_id(raw, opts.seed);
+    }
+    let differences = differences(&outcomes);
+    CompareReport {
+        run_id,
+        dry_run: opts.dry_run,
+        agree: differences.is_empty(),
+        outcomes,
+        differences,
+    }
+}
+
+/// Every field in which an outcome differs from the first one.
+pub fn differences(outcomes: &[PolicyOutcome]) -> Vec<Difference> {
+    let Some((base, rest)) = outcomes.split_first() else {
+        return Vec::new();
+    };
+    let fields = |o: &PolicyOutcome| {
+        [
+            ("verdict", serde_json::json!(o.verdict)),
+            ("risk_score", serde_json::json!(o.risk_score)),
+            ("exit_code", serde_json::json!(o.exit_code)),
+            ("rules", serde_json::json!(o.rules)),
+            ("error", serde_json::json!(o.error)),
+        ]
+    };
+    let mut out = Vec::new();
+    for o in rest {
+        for ((field, baseline), (_, value)) in fields(base).into_iter().zip(fields(o)) {
+            if baseline != value {
+                out.push(Difference {
+                    policy: o.policy.clone(),
+                    field: field.to_string(),
+                    baseline,
+                    value,
+                });
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Verdict;
+
+    fn policy(yaml: &str) -> Policy {
+        Policy::from_yaml(yaml).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_compare_policies() {
+        let raw = br#"{"cmd":"ssh build@example.com uptime","stdin":"","env":{},"files":[],"policy_id":"default","timeout_sec":5,"allow_net":[],"allow_fs":[]}"#;
+        let lax =
+            policy("version: 1\ngrading:\n  thresholds:\n    green_max: 98\n    yellow_max: 99\n");
+        let strict =
+            policy("version: 1\ngrading:\n  thresholds:\n    green_max: 0\n    yellow_max: 1\n");
+        let opts = ExecOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let report = compare(
+            raw,
+            &[
+                ("lax.yml".into(), lax.clone()),
+                ("strict.yml".into(), strict),
+                ("same.yml".into(), lax),
+            ],
+            &opts,
+        )
+        .await;
+        assert!(report.dry_run);
+        assert!(report.run_id.starts_with("r_"));
+        assert_eq!(report.outcomes.len(), 3);
+        assert!(!report.agree);
+        assert!(report.differences.iter().all(|d| d.policy == "strict.yml"));
+        assert!(report.differences.iter().any(|d| d.field == "verdict"));
+        assert_eq!(report.exit_code(), Verdict::Red.exit_code());
+        assert_eq!(report.outcomes[0].verdict, "green");
+        assert_eq!(report.outcomes[1].verdict, "red");
+    }
+
+    #[test]
+    fn test_differences() {
+        let base = PolicyOutcome {
+            policy: "a.yml".into(),
+            verdict: "green".into(),
+            ..Default::default()
+        };
+        let errored = PolicyOutcome {
+            policy: "b.yml".into(),
+            error: Some("policy violation: signed requests only".into()),
+            status: 3,
+            ..Default::default()
+        };
+        let diffs = differences(&[base.clone(), base.clone(), errored]);
+        let fields: Vec<&str> = diffs.iter().map(|d| d.field.as_str()).collect();
+        assert_eq!(fields, ["verdict", "error"]);
+        assert_eq!(diffs[0].baseline, "green");
+        assert_eq!(diffs[0].value, "");
+        assert!(differences(&[base]).is_empty());
+    }
+}
@@ -0,0 +1,466 @@
+//! How many requests a consumer runs at once (`consume --concurrency`,
+//! `MAGICRUNE_CONCURRENCY`).
+//!
+//! A number runs that many at a time; the default, 1, runs them one after
+//! the other. `auto` (or `auto:<min>..<max>`) leaves the number to a
+//! controller, so one setting fits machines of every size. Every
+//! [`ADJUST_EVERY`] it samples the host:
+//! - the 1-minute load average per CPU (`/proc/loadavg`),
+//! - memory pressure, the share of the last 10 s in which some task stalled
+//!   on memory (`/proc/pressure/memory`),
+//! - CPU throttling of the consumer's cgroup, the share of its scheduler
+//!   periods throttled since the last sample (cgroup v2 `cpu.stat`).
+//!
+//! When any of them is high the number is halved; when requests found every
+//! slot taken and all of them are low, it grows by one (additive increase,
+//! multiplicative decrease). A figure the host does not provide counts as
+//! low. The number never leaves `min..=max`.
+//!
+//! Slots alone do not keep memory-heavy requests from landing together.
+//! Each run reserves the `memory_mb` it may use ([`memory_mb_of`]), and a
+//! request whose reservation does not fit the host budget
+//! ([`memory_budget_mb`]) next to those in flight stays pending, unacked,
+//! until enough of them finish. A request is always let through when nothing
+//! else runs, however large.
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Time between two adjustments of an `auto` concurrency.
+pub const ADJUST_EVERY: Duration = Duration::from_secs(5);
+
+/// Above these the host counts as loaded; see [`HostLoad::high`].
+const LOAD_HIGH: f64 = 1.0;
+const PRESSURE_HIGH: f64 = 10.0;
+const THROTTLED_HIGH: f64 = 0.10;
+/// Below these it has room for one more; see [`HostLoad::low`].
+const LOAD_LOW: f64 = 0.7;
+const PRESSURE_LOW: f64 = 2.0;
+const THROTTLED_LOW: f64 = 0.02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Concurrency {
+    Fixed(usize),
+    Auto { min: usize, max: usize },
+}
+
+impl Default for Concurrency {
+    fn default() -> Self {
+        Self::Fixed(1)
+    }
+}
+
+impl std::str::FromStr for Concurrency {
+    type Err = String;
+
+    /// `<n>`, `auto` (1 up to the CPU count) or `auto:<min>..<max>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "concurrency: invalid value {:?} (a number, auto or auto:<min>..<max>)",
+                s
+            )
+        };
+        let s = s.trim();
+        let parsed = match s.strip_prefix("auto") {
+            Some("") => Self::Auto {
+                min: 1,
+                max: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            },
+            Some(range) => {
+                let (min, max) = range
+                    .strip_prefix(':')
+                    .and_then(|r| r.split_once(".."))
+                    .ok_or_else(invalid)?;
+                let min = min.trim().parse().map_err(|_| invalid())?;
+                let max = max.trim().parse().map_err(|_| invalid())?;
+                if min > max {
+                    return Err(format!("concurrency: min {} is over max {}", min, max));
+                }
+                Self::Auto { min, max }
+            }
+            None => Self::Fixed(s.parse().map_err(|_| invalid())?),
+        };
+        match parsed {
+            Self::Fixed(0) | Self::Auto { min: 0, .. } => {
+                Err("concurrency: must be at least 1".into())
+            }
+            ok => Ok(ok),
+        }
+    }
+}
+
+impl std::fmt::Display for Concurrency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fixed(n) => write!(f, "{}", n),
+            Self::Auto { min, max } => write!(f, "auto:{}..{}", min, max),
+        }
+    }
+}
+
+impl Concurrency {
+    /// `MAGICRUNE_CONCURRENCY`; 1 when unset.
+    pub fn from_env() -> Result<Self, String> {
+        match crate::ports::env::var("MAGICRUNE_CONCURRENCY") {
+            Ok(v) if !v.trim().is_empty() => v.parse(),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    /// Slots to start a consumer with, adjusted from there when `auto`:
+    /// the controller is stopped once the limiter is dropped.
+    pub fn start(self) -> Arc<Limiter> {
+        match self {
+            Self::Fixed(n) => Arc::new(Limiter::new(n)),
+            Self::Auto { min, max } => {
+                let limiter = Arc::new(Limiter::new(min));
+                tokio::spawn(adjust(Arc::downgrade(&limiter), Controller { min, max }));
+                limiter
+            }
+        }
+    }
+}
+
+/// Memory the runs in flight may reserve together, in MiB:
+/// `MAGICRUNE_MEMORY_BUDGET_MB`, else the `memory.max` of the consumer's
+/// cgroup. `None`, no budget, when neither is set or the variable is 0.
+pub fn memory_budget_mb() -> Option<u64> {
+    if let Ok(v) = crate::ports::env::var("MAGICRUNE_MEMORY_BUDGET_MB") {
+        if let Ok(mb) = v.trim().parse::<u64>() {
+            return (mb > 0).then_some(mb);
+        }
+        tracing::warn!(value = %v, "MAGICRUNE_MEMORY_BUDGET_MB is not a number; ignored");
+    }
+    let dir = cgroup_dir(&std::fs::read_to_string("/proc/self/cgroup").ok()?)?;
+    memory_max_mb(&std::fs::read_to_string(format!("/sys/fs/cgroup{}/memory.max", dir)).ok()?)
+}
+
+/// The `memory_mb` a request runs under: its `limits.memory_mb`, capped by
+/// the policy's, which is also the default. Payloads that do not say
+/// reserve the policy's.
+pub fn memory_mb_of(payload: &[u8], policy_mb: u64) -> u64 {
+    serde_json::from_slice::<serde_json::Value>(payload)
+        .ok()
+        .and_then(|v| v.get("limits")?.get("memory_mb")?.as_u64())
+        .map_or(policy_mb, |mb| mb.min(policy_mb))
+}
+
+/// Adjust `limiter` until it is dropped.
+async fn adjust(limiter: Weak<Limiter>, ctl: Controller) {
+    let mut sampler = LoadSampler::new();
+    loop {
+        tokio::time::sleep(ADJUST_EVERY).await;
+        let Some(limiter) = limiter.upgrade() else {
+            return;
+        };
+        let load = sampler.sample();
+        let current = limiter.limit();
+        let next = ctl.next(current, &load, limiter.take_waited());
+        if next != current {
+            tracing::info!(
+                from = current,
+                to = next,
+                load_per_cpu = ?load.load_per_cpu,
+                memory_pressure = ?load.memory_pressure,
+                cpu_throttled = ?load.cpu_throttled,
+                "consume: concurrency"
+            );
+            limiter.set_limit(next);
+        }
+    }
+}
+
+/// Slots for requests in flight, their number changeable while in use.
+#[derive(Debug)]
+pub struct Limiter {
+    slots: Mutex<Slots>,
+    freed: Notify,
+}
+
+#[derive(Debug)]
+struct Slots {
+    limit: usize,
+    taken: usize,
+    waited: bool,
+}
+
+/// A taken slot, given back when dropped.
+#[derive(Debug)]
+pub struct Slot(Arc<Limiter>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.slots.lock().unwrap().taken -= 1;
+        self.0.freed.notify_waiters();
+    }
+}
+
+impl Limiter {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            slots: Mutex::new(Slots {
+                limit,
+                taken: 0,
+                waited: false,
+            }),
+            freed: Notify::new(),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.slots.lock().unwrap().limit
+    }
+
+    /// Change the number of slots. Lowering it takes effect as slots are
+    /// given back.
+    pub fn set_limit(&self, limit: usize) {
+        self.slots.lock().unwrap().limit = limit;
+        self.freed.notify_waiters();
+    }
+
+    /// Whether a request found every slot taken since the last call.
+    pub fn take_waited(&self) -> bool {
+        std::mem::take(&mut self.slots.lock().unwrap().waited)
+    }
+
+    /// Take a slot, waiting for one when all are taken.
+    pub async fn acquire(self: &Arc<Self>) -> Slot {
+        loop {
+            let freed = self.freed.notified();
+            tokio::pin!(freed);
+            freed.as_mut().enable();
+            {
+                let mut slots = self.slots.lock().unwrap();
+                if slots.taken < slots.limit {
+                    slots.taken += 1;
+                    return Slot(self.clone());
+                }
+                slots.waited = true;
+            }
+            freed.await;
+        }
+    }
+}
+
+/// How loaded the host is; `None` where it does not say.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HostLoad {
+    /// 1-minute load average over the CPU count.
+    pub load_per_cpu: Option<f64>,
+    /// Memory `some avg10`, in percent.
+    pub memory_pressure: Option<f64>,
+    /// Share of the cgroup's periods throttled, 0 to 1.
+    pub cpu_throttled: Option<f64>,
+}
+
+impl HostLoad {
+    /// Any figure over its high mark.
+    pub fn high(&self) -> bool {
+        self.load_per_cpu.is_some_and(|l| l > LOAD_HIGH)
+            || self.memory_pressure.is_some_and(|p| p > PRESSURE_HIGH)
+            || self.cpu_throttled.is_some_and(|t| t > THROTTLED_HIGH)
+    }
+
+    /// Every figure under its low mark.
+    pub fn low(&self) -> bool {
+        self.load_per_cpu.is_none_or(|l| l < LOAD_LOW)
+            && self.memory_pressure.is_none_or(|p| p < PRESSURE_LOW)
+            && self.cpu_throttled.is_none_or(|t| t < THROTTLED_LOW)
+    }
+}
+
+/// Picks the next number of slots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Controller {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl Controller {
+    /// The number after `current`, given the host's `load` and whether
+    /// requests `waited` for a slot.
+    pub fn next(&self, current: usize, load: &HostLoad, waited: bool) -> usize {
+        let next = if load.high() {
+            current / 2
+        } else if waited && load.low() {
+            current + 1
+        } else {
+            current
+        };
+        next.clamp(self.min, self.max)
+    }
+}
+
+/// Reads [`HostLoad`] from procfs and the consumer's cgroup.
+#[derive(Debug)]
+struct LoadSampler {
+    cpus: f64,
+    cpu_stat: Option<PathBuf>,
+    /// `nr_periods` and `nr_throttled` at the last sample.
+    last: Option<(u64, u64)>,
+}
+
+impl LoadSampler {
+    fn new() -> Self {
+        let cpu_stat = std::fs::read_to_string("/proc/self/cgroup")
+            .ok()
+            .and_then(|c| cgroup_dir(&c))
+            .map(|dir| PathBuf::from(format!("/sys/fs/cgroup{}/cpu.stat", dir)));
+        Self {
+            cpus: std::thread::available_parallelism().map_or(1, |n| n.get()) as f64,
+            cpu_stat,
+            last: None,
+        }
+    }
+
+    fn sample(&mut self) -> HostLoad {
+        let read = |p: &str| std::fs::read_to_string(p).ok();
+        let periods = self
+            .cpu_stat
+            .as_ref()
+            .and_then(|p| std::fs::read_to_string(p).ok())
+            .and_then(|s| throttle_counts(&s));
+        let cpu_throttled = match (self.last, periods) {
+            (Some((p0, t0)), Some((p1, t1))) if p1 > p0 => {
+                Some(t1.saturating_sub(t0) as f64 / (p1 - p0) as f64)
+            }
+            // No period ran: nothing was throttled either.
+            (Some(_), Some(_)) => Some(0.0),
+            _ => None,
+        };
+        self.last = periods;
+        HostLoad {
+            load_per_cpu: read("/proc/loadavg")
+                .and_then(|s| load_average(&s))
+                .map(|l| l / self.cpus),
+            memory_pressure: read("/proc/pressure/memory").and_then(|s| pressure_some_avg10(&s)),
+            cpu_throttled,
+        }
+    }
+}
+
+/// The 1-minute figure of `/proc/loadavg`.
+fn load_average(loadavg: &str) -> Option<f64> {
+    loadavg.split_whitespace().next()?.parse().ok()
+}
+
+/// `avg10` of the `some` line of a PSI file.
+fn pressure_some_avg10(psi: &str) -> Option<f64> {
+    psi.lines()
+        .find_map(|l| l.strip_prefix("some "))?
+        .split_whitespace()
+        .find_map(|f| f.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+/// `nr_periods` and `nr_throttled` of a `cpu.stat`; `None` without a CPU
+/// limit, where the kernel does not count them.
+fn throttle_counts(cpu_stat: &str) -> Option<(u64, u64)> {
+    let field = |name: &str| {
+        cpu_stat.lines().find_map(|l| {
+            let (k, v) = l.split_once(' ')?;
+            (k == name).then(|| v.trim().parse().ok()).flatten()
+        })
+    };
+    Some((field("nr_periods")?, field("nr_throttled")?))
+}
+
+/// A cgroup's `memory.max` in MiB; `None` for `max`.
+fn memory_max_mb(memory_max: &str) -> Option<u64> {
+    memory_max.trim().parse::<u64>().ok().map(|b| b >> 20)
+}
+
+/// The cgroup v2 path of `/proc/self/cgroup`.
+pub(crate) fn cgroup_dir(proc_cgroup: &str) -> Option<String> {
+    proc_cgroup
+        .lines()
+        .find_map(|l| l.strip_prefix("0::"))
+        .map(|p| p.trim_end_matches('/').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("3".parse(), Ok(Concurrency::Fixed(3)));
+        assert_eq!(
+            "auto:2..8".parse(),
+            Ok(Concurrency::Auto { min: 2, max: 8 })
+        );
+        assert!(matches!(
+            "auto".parse(),
+            Ok(Concurrency::Auto { min: 1, .. })
+        ));
+        for bad in ["0", "auto:0..4", "auto:4..2", "auto:3", "many"] {
+            assert!(bad.parse::<Concurrency>().is_err(), "{}", bad);
+        }
+        assert_eq!(
+            Concurrency::Auto { min: 2, max: 8 }.to_string(),
+            "auto:2..8"
+        );
+    }
+
+    #[test]
+    fn test_controller_aimd() {
+        let ctl = Controller { min: 1, max: 8 };
+        let idle = HostLoad::default();
+        assert_eq!(ctl.next(4, &idle, true), 5);
+        assert_eq!(ctl.next(4, &idle, false), 4);
+        assert_eq!(ctl.next(8, &idle, true), 8);
+        let pressured = HostLoad {
+            memory_pressure: Some(25.0),
+            ..Default::default()
+        };
+        assert_eq!(ctl.next(6, &pressured, true), 3);
+        assert_eq!(ctl.next(1, &pressured, true), 1);
+        // Between the marks: hold.
+        let busy = HostLoad {
+            load_per_cpu: Some(0.9),
+            ..Default::default()
+        };
+        assert_eq!(ctl.next(4, &busy, true), 4);
+    }
+
+    #[test]
+    fn test_host_figures() {
+        assert_eq!(load_average("1.50 0.80 0.40 2/300 4242\n"), Some(1.5));
+        let psi = "some avg10=12.50 avg60=3.00 avg300=1.00 total=10\nfull avg10=1.00 avg60=0.00 avg300=0.00 total=2\n";
+        assert_eq!(pressure_some_avg10(psi), Some(12.5));
+        let stat = "usage_usec 100\nnr_periods 40\nnr_throttled 4\nthrottled_usec 9\n";
+        assert_eq!(throttle_counts(stat), Some((40, 4)));
+        assert_eq!(throttle_counts("usage_usec 100\n"), None);
+        assert_eq!(memory_max_mb("2147483648\n"), Some(2048));
+        assert_eq!(memory_max_mb("max\n"), None);
+        assert_eq!(memory_mb_of(br#"{"limits":{"memory_mb":256}}"#, 512), 256);
+        assert_eq!(memory_mb_of(br#"{"limits":{"memory_mb":4096}}"#, 512), 512);
+        assert_eq!(memory_mb_of(b"not json", 512), 512);
+        assert_eq!(
+            cgroup_dir("0::/system.slice/magicrune.service\n").as_deref(),
+            Some("/system.slice/magicrune.service")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_limiter_waits_and_resizes() {
+        let limiter = Arc::new(Limiter::new(1));
+        let first = limiter.acquire().await;
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        limiter.set_limit(2);
+        let second = waiting.await.unwrap();
+        assert!(limiter.take_waited());
+        assert!(!limiter.take_waited());
+        drop((first, second));
+        assert_eq!(limiter.slots.lock().unwrap().taken, 0);
+    }
+}
@@ -0,0 +1,555 @@
+//! `magicrune doctor`: probe the host for what the sandbox and the consumers
+//! need.
+//!
+//! Every probe reports PASS, WARN (runs still work, with less isolation or
+//! without the feature) or FAIL (something that is configured will not
+//! work), plus a hint on how to fix it. Probes read system state or try the
+//! operation in a throwaway child process; nothing on the host is left
+//! changed.
+
+use crate::policy::Policy;
+use crate::sandbox::Hardening;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long the NATS probe waits to connect and for the server greeting.
+const NATS_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        }
+    }
+}
+
+/// One row of the report.
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+    /// What to do about a WARN or FAIL; empty on PASS.
+    pub hint: String,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Pass,
+            detail: detail.into(),
+            hint: String::new(),
+        }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Warn,
+            detail: detail.into(),
+            hint: hint.into(),
+        }
+    }
+
+    /// FAIL when `required`, WARN otherwise.
+    fn problem(
+        name: &'static str,
+        required: bool,
+        detail: impl Into<String>,
+        hint: impl Into<String>,
+    ) -> Self {
+        Self {
+            status: if required { Status::Fail } else { Status::Warn },
+            ..Self::warn(name, detail, hint)
+        }
+    }
+}
+
+/// What to probe.
+#[derive(Debug, Clone)]
+pub struct DoctorConfig {
+    pub policy_path: String,
+    /// `host:port` (a `nats://` prefix is accepted).
+    pub nats_url: String,
+    /// The NATS address was configured rather than defaulted, so an
+    /// unreachable server is a failure.
+    pub nats_required: bool,
+    pub cgroup_parent: PathBuf,
+    pub hardening: Hardening,
+}
+
+impl DoctorConfig {
+    /// `MAGICRUNE_POLICY`, `NATS_URL`, `MAGICRUNE_CGROUP_PARENT` and the
+    /// hardening switches, with the defaults the other commands use.
+    pub fn from_env() -> Self {
+        let nats = std::env::var("NATS_URL").ok().filter(|u| !u.is_empty());
+        Self {
+            policy_path: std::env::var("MAGICRUNE_POLICY")
+                .unwrap_or_else(|_| "policies/default.policy.yml".to_string()),
+            nats_required: nats.is_some(),
+            nats_url: nats.unwrap_or_else(|| "127.0.0.1:4222".to_string()),
+            cgroup_parent: crate::gc::GcConfig::from_env().cgroup_parent,
+            hardening: Hardening::from_env(),
+        }
+    }
+}
+
+/// Run every probe, in report order.
+pub fn run(cfg: &DoctorConfig) -> Vec<Check> {
+    vec![
+        sandbox_build(),
+        namespaces(cfg.hardening.overlay_ro),
+        seccomp(cfg.hardening.seccomp),
+        cgroups(&cfg.cgroup_parent),
+        overlayfs(cfg.hardening.overlay_ro),
+        nats(&cfg.nats_url, cfg.nats_required),
+        policy(&cfg.policy_path),
+    ]
+}
+
+/// The report as a table, with the hint under each row that is not PASS.
+pub fn render(checks: &[Check]) -> String {
+    let width = checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+    let mut out = String::new();
+    let _ = writeln!(out, "{:<6}  {:<width$}  DETAIL", "STATUS", "CHECK");
+    for c in checks {
+        let _ = writeln!(
+            out,
+            "{:<6}  {:<width$}  {}",
+            c.status.as_str(),
+            c.name,
+            c.detail
+        );
+        if !c.hint.is_empty() {
+            let _ = writeln!(out, "{:<6}  {:<width$}  hint: {}", "", "", c.hint);
+        }
+    }
+    out
+}
+
+/// The worst status in the report.
+pub fn overall(checks: &[Check]) -> Status {
+    checks
+        .iter()
+        .map(|c| c.status)
+        .max()
+        .unwrap_or(Status::Pass)
+}
+
+fn sandbox_build() -> Check {
+    const NAME: &str = "sandbox build";
+    if !cfg!(target_os = "linux") {
+        return Check::warn(
+            NAME,
+            "not Linux: commands run under WASI",
+            "the native sandbox needs a Linux host",
+        );
+    }
+    if !cfg!(feature = "linux_native") {
+        return Check::warn(
+            NAME,
+            "built without linux_native: commands run under WASI",
+            "build with --features linux_native",
+        );
+    }
+    if std::env::var("MAGICRUNE_FORCE_WASM").ok().as_deref() == Some("1") {
+        return Check::warn(
+            NAME,
+            "native sandbox built, but MAGICRUNE_FORCE_WASM=1 selects WASI",
+            "unset MAGICRUNE_FORCE_WASM",
+        );
+    }
+    Check::pass(NAME, "native Linux sandbox")
+}
+
+#[cfg(all(target_os = "linux", feature = "linux_native"))]
+const USERNS_HINT: &str = "run as root, or allow unprivileged user namespaces \
+     (sysctl kernel.unprivileged_userns_clone=1, \
+     kernel.apparmor_restrict_unprivileged_userns=0)";
+
+/// The overlay root needs a private mount namespace.
+fn namespaces(required: bool) -> Check {
+    const NAME: &str = "namespaces";
+    if !cfg!(target_os = "linux") {
+        return Check::warn(NAME, "not Linux", "");
+    }
+    let max_userns = read_trimmed("/proc/sys/user/max_user_namespaces");
+    let userns = match max_userns.as_deref() {
+        Some("0") => "user namespaces disabled",
+        Some(_) => "user namespaces available",
+        None => "user namespace limit unknown",
+    };
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    {
+        use nix::sched::{unshare, CloneFlags};
+        match in_child(|| Ok(unshare(CloneFlags::CLONE_NEWNS)?)) {
+            Ok(()) => Check::pass(NAME, format!("unshare(CLONE_NEWNS) ok; {}", userns)),
+            Err(e) => Check::problem(
+                NAME,
+                required,
+                format!("unshare(CLONE_NEWNS) failed: {}; {}", e, userns),
+                USERNS_HINT,
+            ),
+        }
+    }
+    #[cfg(not(all(target_os = "linux", feature = "linux_native")))]
+    {
+        let _ = required;
+        Check::warn(
+            NAME,
+            format!("not probed (built without linux_native); {}", userns),
+            "build with --features linux_native",
+        )
+    }
+}
+
+fn seccomp(required: bool) -> Check {
+    const NAME: &str = "seccomp";
+    let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+    if !status.lines().any(|l| l.starts_with("Seccomp:")) {
+        return Check::problem(
+            NAME,
+            required,
+            "kernel without seccomp support",
+            "use a kernel built with CONFIG_SECCOMP_FILTER",
+        );
+    }
+    let actions = read_trimmed("/proc/sys/kernel/seccomp/actions_avail").unwrap_or_default();
+    let learn = actions.split_whitespace().any(|a| a == "log");
+    if !cfg!(feature = "native_sandbox") {
+        return Check::problem(
+            NAME,
+            required,
+            "kernel supports seccomp, but this build has no filter",
+            "build with --features native_sandbox (needs libseccomp)",
+        );
+    }
+    if !learn {
+        return Check::warn(
+            NAME,
+            "filter available; kernel lacks the log action for --seccomp-learn",
+            "learn mode needs a kernel with SECCOMP_RET_LOG (4.14+)",
+        );
+    }
+    Check::pass(NAME, "filter available (MAGICRUNE_SECCOMP=1)")
+}
+
+/// cgroup v2 with the controllers and a subtree the process may create
+/// groups in (`MAGICRUNE_CGROUP_PARENT`).
+fn cgroups(parent: &Path) -> Check {
+    const NAME: &str = "cgroup v2";
+    let hint = format!(
+        "run as root, or point MAGICRUNE_CGROUP_PARENT at a delegated subtree \
+         (systemd: Delegate=yes); currently {}",
+        parent.display()
+    );
+    if !parent.join("cgroup.controllers").is_file() {
+        return Check::warn(
+            NAME,
+            format!("no cgroup v2 hierarchy at {}", parent.display()),
+            hint,
+        );
+    }
+    let enabled =
+        std::fs::read_to_string(parent.join("cgroup.subtree_control")).unwrap_or_default();
+    let missing: Vec<&str> = ["cpu", "memory", "pids"]
+        .into_iter()
+        .filter(|c| !enabled.split_whitespace().any(|e| e == *c))
+        .collect();
+    let probe = parent.join(format!("magicrune_doctor_{}", std::process::id()));
+    if let Err(e) = std::fs::create_dir(&probe) {
+        return Check::warn(
+            NAME,
+            format!("cannot create a group under {}: {}", parent.display(), e),
+            hint,
+        );
+    }
+    let _ = std::fs::remove_dir(&probe);
+    if !missing.is_empty() {
+        return Check::warn(
+            NAME,
+            format!("controllers not delegated: {}", missing.join(", ")),
+            format!(
+                "echo '{}' > {}",
+                missing
+                    .iter()
+                    .map(|c| format!("+{}", c))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                parent.join("cgroup.subtree_control").display()
+            ),
+        );
+    }
+    Check::pass(NAME, format!("delegated at {}", parent.display()))
+}
+
+/// The overlay root (`MAGICRUNE_OVERLAY_RO=1`): overlayfs and tmpfs in the
+/// kernel, and permission to mount them in a private mount namespace.
+fn overlayfs(required: bool) -> Check {
+    const NAME: &str = "overlayfs";
+    let filesystems = std::fs::read_to_string("/proc/filesystems").unwrap_or_default();
+    let has = |fs: &str| {
+        filesystems
+            .lines()
+            .any(|l| l.split_whitespace().last() == Some(fs))
+    };
+    let missing: Vec<&str> = ["overlay", "tmpfs"]
+        .into_iter()
+        .filter(|fs| !has(fs))
+        .collect();
+    if !missing.is_empty() {
+        return Check::problem(
+            NAME,
+            required,
+            format!("kernel lacks {}", missing.join(", ")),
+            "load the overlay module (modprobe overlay)",
+        );
+    }
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    {
+        match probe_overlay_mount() {
+            Ok(()) => Check::pass(NAME, "overlay mount ok"),
+            Err(e) => Check::problem(
+                NAME,
+                required,
+                format!("overlay mount failed: {}", e),
+                USERNS_HINT,
+            ),
+        }
+    }
+    #[cfg(not(all(target_os = "linux", feature = "linux_native")))]
+    {
+        Check::warn(
+            NAME,
+            "supported by the kernel; mount not probed (built without linux_native)",
+            "build with --features linux_native",
+        )
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "linux_native"))]
+fn probe_overlay_mount() -> Result<(), String> {
+    use nix::mount::{mount, MsFlags};
+    use nix::sched::{unshare, CloneFlags};
+    let scratch = std::env::temp_dir().join(format!("magicrune_doctor_ovl_{}", std::process::id()));
+    let [lower, upper, work, merged] =
+        ["lower", "upper", "work", "merged"].map(|d| scratch.join(d));
+    for d in [&lower, &upper, &work, &merged] {
+        std::fs::create_dir_all(d).map_err(|e| e.to_string())?;
+    }
+    let opts = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        lower.display(),
+        upper.display(),
+        work.display()
+    );
+    // The mounts live in the child's namespace and go away with it.
+    let result = in_child(move || {
+        unshare(CloneFlags::CLONE_NEWNS)?;
+        mount(
+            Some("none"),
+            "/",
+            Option::<&str>::None,
+            MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+            Option::<&str>::None,
+        )?;
+        mount(
+            Some("overlay"),
+            merged.as_path(),
+            Some("overlay"),
+            MsFlags::empty(),
+            Some(opts.as_str()),
+        )?;
+        Ok(())
+    });
+    let _ = std::fs::remove_dir_all(&scratch);
+    result
+}
+
+/// Run `setup` in a forked child before it execs `true`.
+#[cfg(all(target_os = "linux", feature = "linux_native"))]
+fn in_child(
+    setup: impl FnMut() -> std::io::Result<()> + Send + Sync + 'static,
+) -> Result<(), String> {
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio};
+    let mut cmd = Command::new("true");
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    unsafe { cmd.pre_exec(setup) };
+    match cmd.status() {
+        Ok(s) if s.success() => Ok(()),
+        Ok(s) => Err(format!("probe exited with {}", s)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Err("probe helper `true` not found in PATH".into())
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn nats(url: &str, required: bool) -> Check {
+    use std::io::BufRead;
+    use std::net::{TcpStream, ToSocketAddrs};
+    const NAME: &str = "nats";
+    let addr = url.trim_start_matches("nats://");
+    let hint = if required {
+        "check NATS_URL / --url and that the server is running"
+    } else {
+        "only needed for consume; set NATS_URL (e.g. docker compose up -d nats)"
+    };
+    let Some(sock) = addr.to_socket_addrs().ok().and_then(|mut a| a.next()) else {
+        return Check::problem(NAME, required, format!("cannot resolve {}", addr), hint);
+    };
+    let stream = match TcpStream::connect_timeout(&sock, NATS_TIMEOUT) {
+        Ok(s) => s,
+        Err(e) => {
+            return Check::problem(NAME, required, format!("{}: {}", addr, e), hint);
+        }
+    };
+    // The server greets every client with an INFO line.
+    let _ = stream.set_read_timeout(Some(NATS_TIMEOUT));
+    let mut greeting = String::new();
+    let _ = std::io::BufReader::new(stream).read_line(&mut greeting);
+    if !greeting.starts_with("INFO ") {
+        return Check::problem(
+            NAME,
+            required,
+            format!("{} accepts connections but is not a NATS server", addr),
+            hint,
+        );
+    }
+    if !cfg!(feature = "jet") {
+        return Check::warn(
+            NAME,
+            format!("server reachable at {}; built without jet", addr),
+            "build with --features jet to run consume",
+        );
+    }
+    Check::pass(NAME, format!("server reachable at {}", addr))
+}
+
+fn policy(path: &str) -> Check {
+    const NAME: &str = "policy";
+    let text = match std::fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => {
+            return Check {
+                name: NAME,
+                status: Status::Fail,
+                detail: format!("{}: {}", path, e),
+                hint: "set MAGICRUNE_POLICY or --policy to a readable policy file; \
+                       runs would fall back to the built-in default"
+                    .into(),
+            };
+        }
+    };
+    let policy = match Policy::from_yaml(&text) {
+        Ok(p) => p,
+        Err(e) => {
+            return Check {
+                name: NAME,
+                status: Status::Fail,
+                detail: format!("{}: {}", path, e.0),
+                hint: "grading.thresholds takes whole numbers green_max <= yellow_max".into(),
+            };
+        }
+    };
+    let mut problems = Vec::new();
+    if policy.limits.wall_sec == 0 {
+        problems.push("limits.wall_sec is 0".to_string());
+    }
+    problems.extend(
+        unknown_syscalls(&policy.syscalls_allow)
+            .into_iter()
+            .map(|s| format!("unknown syscall {:?} in capabilities.syscalls.allow", s)),
+    );
+    if !problems.is_empty() {
+        return Check {
+            name: NAME,
+            status: Status::Fail,
+            detail: format!("{}: {}", path, problems.join("; ")),
+            hint: "wall_sec must be at least 1; syscall names are as in seccomp(2)".into(),
+        };
+    }
+    Check::pass(NAME, format!("{} (sha256 {})", path, &policy.digest[..12]))
+}
+
+#[cfg(all(target_os = "linux", feature = "native_sandbox"))]
+fn unknown_syscalls(names: &[String]) -> Vec<String> {
+    names
+        .iter()
+        .filter(|n| libseccomp::ScmpSyscall::from_name(n).is_err())
+        .cloned()
+        .collect()
+}
+
+#[cfg(not(all(target_os = "linux", feature = "native_sandbox")))]
+fn unknown_syscalls(_names: &[String]) -> Vec<String> {
+    Vec::new()
+}
+
+fn read_trimmed(path: &str) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_check() {
+        let dir = std::env::temp_dir().join(format!("mr_doctor_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let good = dir.join("good.yml");
+        std::fs::write(&good, "limits:\n  wall_sec: 5\n").unwrap();
+        let bad = dir.join("bad.yml");
+        std::fs::write(&bad, "grading:\n  thresholds:\n    yellow: \"21-60\"\n").unwrap();
+
+        assert_eq!(policy(good.to_str().unwrap()).status, Status::Pass);
+        let c = policy(bad.to_str().unwrap());
+        assert_eq!(c.status, Status::Fail);
+        assert!(c.detail.contains("grading.thresholds.yellow"));
+        assert_eq!(policy("/nonexistent/p.yml").status, Status::Fail);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_nats_check() {
+        // Nothing listens on a port we just released.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = format!("nats://127.0.0.1:{}", port);
+        assert_eq!(nats(&addr, false).status, Status::Warn);
+        assert_eq!(nats(&addr, true).status, Status::Fail);
+    }
+
+    #[test]
+    fn test_render_and_overall() {
+        let checks = vec![
+            Check::pass("policy", "ok"),
+            Check::warn("seccomp", "no filter", "build with native_sandbox"),
+        ];
+        let table = render(&checks);
+        assert!(table.starts_with("STATUS  CHECK    DETAIL\n"));
+        assert!(table.contains("WARN    seccomp  no filter\n"));
+        assert!(table.contains("hint: build with native_sandbox"));
+        assert_eq!(overall(&checks), Status::Warn);
+    }
+}
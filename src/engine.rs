@@ -0,0 +1,1938 @@
+//! The spell pipeline: validate → materialize → exec → grade → publish.
+//!
+//! Every phase is timed; the totals end up in `SpellResult::timings` and
+//! `duration_ms` covers the whole run, whether or not the command executed.
+//!
+//! Nothing here reads the process environment: callers describe the run with
+//! [`ExecOptions`] (the CLI builds one with [`ExecOptions::from_env`]).
+
+use crate::audit::{self, AuditEvent, AuditSink};
+use crate::backend::{self, Backend, RemoteTask, StagedFile};
+use crate::error::MagicruneError;
+use crate::grader::{weights, RiskTally, MAX_SCORE};
+use crate::guard::{Begin, ExecGuard, OnRedelivery};
+use crate::http_cache::HttpCache;
+use crate::input_cache::InputCache;
+use crate::keys::Keyring;
+use crate::ledger::{Ledger, LedgerConfig, RunRecord};
+use crate::messages::{pointer_token, Code, Message};
+use crate::notify::{Notifier, NotifyEvent};
+use crate::observability::ExecutionContext;
+use crate::policy::{
+    allowed_match, extract_device_paths, extract_http_hosts, hostport_parts, pat_matches, Policy,
+    PolicyLimits, Unapproved, EXFILTRATION, UNAPPROVED_BINARY,
+};
+use crate::ports::Clock;
+use crate::result_cache::{self, ResultCache};
+use crate::sandbox::dns::{DnsStub, DNS_DENIED, DNS_MISMATCH};
+use crate::sandbox::egress::{EgressLimits, EgressProxy};
+use crate::sandbox::shell::Shell;
+use crate::sandbox::{
+    ansi, default_sandbox, detect_sandbox, exec_native_with, provenance, Hardening, SandboxKind,
+    SandboxSpec, TerminationReason, STANDARD_DEVICES,
+};
+use crate::schedule;
+use crate::schema::{
+    Finding, Limits, RequestLimits, RiskAcceptance, SpellRequest, SpellResult, StopReason, Timings,
+    Verdict, Waiver, NETWORK_DISABLED,
+};
+use crate::schemas::{REQUEST_SCHEMA, RESULT_SCHEMA};
+use crate::shadow::ShadowPolicy;
+use crate::template;
+use crate::tenant::{self, TenantQuotas};
+use crate::webhook::{self, WebhookConfig};
+use base64::Engine as _;
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+/// Per-run switches that are not part of the policy.
+#[derive(Debug, Clone, Default)]
+pub struct ExecOptions {
+    /// Validate request and result against the bundled JSON schemas.
+    pub strict: bool,
+    /// Mixed into the run id so identical requests can be told apart.
+    pub seed: Option<u64>,
+    /// Grade without executing the command.
+    pub dry_run: bool,
+    /// Sandbox to run in; `None` picks the build default.
+    pub sandbox: Option<SandboxKind>,
+    /// Optional native sandbox hardening.
+    pub hardening: Hardening,
+    /// Where the command runs; remote backends ignore `sandbox`/`hardening`.
+    pub backend: Backend,
+    /// How results reach a request's `callback_url`.
+    pub webhook: WebhookConfig,
+    /// Node keys; results are signed with the active one, if any.
+    pub keyring: Keyring,
+    /// Producer keys, checked when the policy requires signed requests.
+    pub producer_keys: Keyring,
+    /// Tenant every run is bound to; `None` takes the request's `tenant`.
+    pub tenant: Option<String>,
+    /// Consumer instance stamped on results (see `crate::instance`).
+    pub instance_id: Option<String>,
+    /// Per-tenant run quotas, shared by every run using these options.
+    pub quotas: Option<Arc<TenantQuotas>>,
+    /// Cache of decoded input files for local runs.
+    pub input_cache: Option<Arc<InputCache>>,
+    /// Cache of HTTP responses fetched through the egress proxy.
+    pub http_cache: Option<Arc<HttpCache>>,
+    /// Markers that keep a run id from executing twice (see `crate::guard`).
+    pub guard: Option<Arc<ExecGuard>>,
+    /// Green results that answer identical requests under policies with
+    /// `cache.ttl_sec` (see `crate::result_cache`).
+    pub result_cache: Option<Arc<ResultCache>>,
+    /// Policy every request is also graded under, without effect on the
+    /// run (see `crate::shadow`).
+    pub shadow: Option<Arc<ShadowPolicy>>,
+    /// Labels of this runner; consumers hand back requests whose policy
+    /// needs others (see `crate::instance`).
+    pub runner_labels: Vec<String>,
+    /// Where policy decisions and verdicts are exported (see `crate::audit`).
+    pub audit: Option<Arc<dyn AuditSink>>,
+    /// Alerts on red results (see `crate::notify`).
+    pub notifier: Option<Arc<Notifier>>,
+    /// Where every finished run is recorded (see `crate::ledger`).
+    pub ledger: Option<Arc<dyn Ledger>>,
+    /// Attach the caller's terminal to the command (`exec --interactive`);
+    /// refused when the static verdict is red.
+    pub interactive: bool,
+    /// No network at all (`exec --offline`, `MAGICRUNE_OFFLINE=1`): as
+    /// under `capabilities.net.default: disabled_hard`.
+    pub offline: bool,
+    /// Determinism mode (`magicrune repro`): the command starts with a
+    /// pinned locale, time zone and build date
+    /// ([`crate::sandbox::environ::DETERMINISTIC`]).
+    pub deterministic: bool,
+    /// When a consumer's request was queued, in Unix ms (see
+    /// [`crate::transport::Delivery::queued_at_ms`]); gives the result's
+    /// `queue_ms`.
+    pub queued_at_ms: Option<u64>,
+    /// Memory a consumer's runs in flight may reserve together, in MiB
+    /// ([`crate::concurrency::memory_budget_mb`]); `None` is no budget.
+    pub memory_budget_mb: Option<u64>,
+    /// Values for the request's `${VAR}` placeholders; these override the
+    /// request's own `vars`.
+    pub vars: template::Vars,
+    /// Clock the run's deadline and phase timings are measured against
+    /// (see [`Clock`]).
+    pub clock: Clock,
+}
+
+impl ExecOptions {
+    /// Options as the CLI has always read them, from the environment:
+    ///
+    /// - `MAGICRUNE_DRY_RUN`, `MAGICRUNE_FORCE_WASM` and the hardening
+    ///   toggles ([`Hardening::from_env`]);
+    /// - the backend at `MAGICRUNE_RUNTIME` ([`Backend::from_env`]) and the
+    ///   `MAGICRUNE_WEBHOOK_*` settings ([`WebhookConfig::from_env`]);
+    /// - the keyring ([`Keyring::from_env`]) and the producer keys
+    ///   ([`Keyring::producers_from_env`]);
+    /// - the tenant binding at `MAGICRUNE_TENANT`, the instance id at
+    ///   `MAGICRUNE_INSTANCE_ID` and the quotas ([`TenantQuotas::from_env`]);
+    /// - the input cache ([`InputCache::from_env`]), the HTTP cache
+    ///   ([`HttpCache::from_env`]) and the result cache
+    ///   ([`ResultCache::from_env`]);
+    /// - the execution guard ([`ExecGuard::from_env`]) and the shadow policy
+    ///   ([`ShadowPolicy::from_env`]);
+    /// - the runner labels at `MAGICRUNE_RUNNER_LABELS`, offline mode at
+    ///   `MAGICRUNE_OFFLINE` and the memory budget
+    ///   ([`crate::concurrency::memory_budget_mb`]);
+    /// - the audit sink ([`crate::audit::global`]), the red-verdict alerts
+    ///   ([`crate::notify::global`]) and the ledger at `MAGICRUNE_LEDGER`,
+    ///   when set ([`LedgerConfig::from_env_if_set`]).
+    pub fn from_env() -> Self {
+        Self {
+            strict: false,
+            seed: None,
+            dry_run: crate::ports::env::var("MAGICRUNE_DRY_RUN").ok().as_deref() == Some("1"),
+            sandbox: Some(detect_sandbox()),
+            hardening: Hardening::from_env(),
+            backend: Backend::from_env(),
+            webhook: WebhookConfig::from_env(),
+            keyring: Keyring::from_env_or_empty(),
+            producer_keys: Keyring::producers_from_env().unwrap_or_else(|e| {
+                tracing::warn!(error = %e, "no producer keys: signed requests will be rejected");
+                Keyring::default()
+            }),
+            tenant: crate::ports::env::var("MAGICRUNE_TENANT")
+                .ok()
+                .filter(|t| !t.is_empty()),
+            instance_id: crate::instance::from_env(),
+            quotas: TenantQuotas::from_env().map(Arc::new),
+            input_cache: InputCache::from_env().map(Arc::new),
+            http_cache: HttpCache::from_env().map(Arc::new),
+            guard: ExecGuard::from_env().map(Arc::new),
+            result_cache: Some(Arc::new(ResultCache::from_env())),
+            shadow: ShadowPolicy::from_env().map(Arc::new),
+            runner_labels: crate::instance::labels_from_env().unwrap_or_else(|e| {
+                tracing::warn!(error = %e, "no runner labels");
+                Vec::new()
+            }),
+            audit: crate::audit::global().cloned(),
+            notifier: crate::notify::global().cloned(),
+            ledger: LedgerConfig::from_env_if_set()
+                .unwrap_or_else(|e| {
+                    tracing::warn!(error = %e, "runs not recorded");
+                    None
+                })
+                .map(|c| c.open()),
+            interactive: false,
+            offline: crate::ports::env::var("MAGICRUNE_OFFLINE").ok().as_deref() == Some("1"),
+            deterministic: false,
+            queued_at_ms: None,
+            memory_budget_mb: crate::concurrency::memory_budget_mb(),
+            vars: template::Vars::new(),
+            clock: Clock::default(),
+        }
+    }
+}
+
+/// Why a run has no network at all: `exec --offline` (`offline`) or the
+/// policy's `capabilities.net.default: disabled_hard`; `None` when it may.
+pub fn offline_reason(offline: bool, policy: &Policy) -> Option<&'static str> {
+    if offline {
+        Some("--offline")
+    } else if policy.net_disabled {
+        Some("capabilities.net.default: disabled_hard")
+    } else {
+        None
+    }
+}
+
+/// A finished run: the result plus the child's captured output.
+#[derive(Debug, Clone)]
+pub struct RunOutput {
+    pub result: SpellResult,
+    pub verdict: Verdict,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// The request's `callback_url`; delivering to it is the caller's job.
+    pub callback_url: Option<String>,
+    /// Tenant the run was accounted to.
+    pub tenant: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    cmd: String,
+    #[serde(default)]
+    stdin: String,
+    #[serde(default)]
+    env: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    files: Vec<FileEntry>,
+    #[serde(default)]
+    policy_id: String,
+    #[serde(default)]
+    timeout_sec: u64,
+    #[serde(default)]
+    allow_net: Vec<String>,
+    #[serde(default)]
+    callback_url: String,
+    #[serde(default)]
+    tenant: String,
+    #[serde(default)]
+    limits: RequestLimits,
+    #[serde(default)]
+    accept_risk: Vec<RiskAcceptance>,
+    #[serde(default)]
+    pty: bool,
+    #[serde(default)]
+    strip_ansi: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileEntry {
+    path: String,
+    #[serde(default)]
+    content_b64: String,
+}
+
+/// Reject `raw` unless it is signed by one of `keys`, when `policy` requires
+/// signed requests. The signature covers the request as sent, before its
+/// `${VAR}` placeholders are resolved.
+pub fn authenticate_request(
+    raw: &[u8],
+    policy: &Policy,
+    keys: &Keyring,
+) -> Result<(), MagicruneError> {
+    if !policy.require_signed_requests {
+        return Ok(());
+    }
+    let req: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(raw).map_err(|e| MagicruneError::new(Code::InvalidJson, &[&e]))?;
+    keys.verify_request(&req).map(|_| ()).map_err(|e| {
+        MagicruneError::new(Code::Unauthenticated, &[&e]).rule("requests.signature: required")
+    })
+}
+
+/// The limit behind `reason`, if a limit ended the run. `limits` are the
+/// ones the command ran under; `timeout_sec` and `asked` tell whether the
+/// request or the policy set them.
+pub fn stop_reason(
+    reason: &TerminationReason,
+    timeout_sec: u64,
+    asked: &RequestLimits,
+    limits: &PolicyLimits,
+) -> Option<StopReason> {
+    let (kind, limit, by_request, value) = match reason {
+        TerminationReason::WallTimeout if timeout_sec > 0 => {
+            ("wall_timeout", "timeout_sec", true, limits.wall_sec)
+        }
+        TerminationReason::WallTimeout => {
+            ("wall_timeout", "limits.wall_sec", false, limits.wall_sec)
+        }
+        TerminationReason::CpuLimit => (
+            "cpu_limit",
+            "limits.cpu_ms",
+            asked.cpu_ms.is_some(),
+            limits.cpu_ms,
+        ),
+        TerminationReason::MemoryLimit => (
+            "memory_limit",
+            "limits.memory_mb",
+            asked.memory_mb.is_some(),
+            limits.memory_mb,
+        ),
+        _ => return None,
+    };
+    Some(StopReason {
+        kind: kind.into(),
+        limit: limit.into(),
+        source: if by_request { "request" } else { "policy" }.into(),
+        value,
+    })
+}
+
+/// Deterministic run id: `r_` + sha256(request bytes || seed as little-endian).
+pub fn compute_run_id(raw: &[u8], seed: Option<u64>) -> String {
+    use sha2::{Digest, Sha256};
+    let mut h = Sha256::new();
+    h.update(raw);
+    if let Some(s) = seed {
+        h.update(s.to_le_bytes());
+    }
+    format!("r_{:x}", h.finalize())
+}
+
+fn ms_since(clock: &Clock, t: u64) -> u64 {
+    clock.since(t).as_millis() as u64
+}
+
+/// Run a spell request under `policy` and return its graded result.
+///
+/// This is the library entry point: it performs the same validation, policy
+/// enforcement, execution and grading as `magicrune exec`, without reading
+/// any environment variables. The run id is derived from the request's JSON
+/// encoding and `opts.seed` (falling back to `req.seed`).
+///
+/// ```no_run
+/// use magicrune::{policy::Policy, run_spell, schema::SpellRequest, ExecOptions};
+///
+/// # async fn demo() -> Result<(), magicrune::MagicruneError> {
+/// let req = SpellRequest {
+///     cmd: Some("echo hello".into()),
+///     ..Default::default()
+/// };
+/// let policy = Policy::load("policies/default.policy.yml")?;
+/// let result = run_spell(&req, &policy, &ExecOptions::default()).await?;
+/// println!("{} {}", result.run_id, result.verdict);
+/// # Ok(())
+/// # }
+/// ```
+pub async fn run_spell(
+    req: &SpellRequest,
+    policy: &Policy,
+    opts: &ExecOptions,
+) -> Result<SpellResult, MagicruneError> {
+    // Unset optional fields are omitted so they take the pipeline defaults.
+    let mut val =
+        serde_json::to_value(req).map_err(|e| MagicruneError::new(Code::RequestShape, &[&e]))?;
+    if let Some(obj) = val.as_object_mut() {
+        obj.retain(|_, v| !v.is_null());
+    }
+    let raw = serde_json::to_vec(&val)
+        .map_err(|e| MagicruneError::internal(format!("serialize: {}", e)))?;
+    let mut opts = opts.clone();
+    if opts.seed.is_none() {
+        opts.seed = req.seed;
+    }
+    execute(&raw, policy, &opts).await.map(|run| run.result)
+}
+
+/// Run one spell request (raw JSON bytes) under `policy`.
+///
+/// Unlike [`run_spell`], the run id is computed over `raw` as given (after
+/// resolving its `${VAR}` placeholders), and the child's captured output is
+/// returned alongside the result.
+pub async fn execute(
+    raw: &[u8],
+    policy: &Policy,
+    opts: &ExecOptions,
+) -> Result<RunOutput, MagicruneError> {
+    let out = execute_run(raw, policy, opts).await;
+    if let Some(sink) = &opts.audit {
+        let event = match &out {
+            Ok(run) => Some(AuditEvent::verdict(&run.result, &policy.digest)),
+            Err(
+                e @ (MagicruneError::PolicyViolation(_)
+                | MagicruneError::ReadonlyWrite(_)
+                | MagicruneError::Unauthenticated(_)),
+            ) => Some(AuditEvent::denied(
+                &compute_run_id(raw, opts.seed),
+                opts.tenant.as_deref().unwrap_or_default(),
+                &policy.digest,
+                e.message(),
+            )),
+            Err(_) => None,
+        };
+        if let Some(event) = event {
+            audit::record(sink.as_ref(), &event);
+        }
+    }
+    if let (Some(ledger), Ok(run)) = (&opts.ledger, &out) {
+        ledger
+            .put(RunRecord::enriched(&run.result, raw, policy))
+            .await;
+    }
+    if let (Some(n), Ok(run)) = (&opts.notifier, &out) {
+        n.notify(NotifyEvent::Red, &run.result).await;
+    }
+    out
+}
+
+async fn execute_run(
+    raw: &[u8],
+    policy: &Policy,
+    opts: &ExecOptions,
+) -> Result<RunOutput, MagicruneError> {
+    let started = opts.clock.now_millis();
+    let queue_ms = opts.queued_at_ms.map(|q| started.saturating_sub(q));
+    let mut timings = Timings::default();
+    if let Some(shadow) = &opts.shadow {
+        Box::pin(shadow.evaluate(raw, policy, opts)).await;
+    }
+
+    // --- validate -----------------------------------------------------------
+    let phase = opts.clock.now_millis();
+    authenticate_request(raw, policy, &opts.producer_keys)?;
+    let rendered = template::render(raw, &opts.vars)?;
+    let raw = &*rendered;
+    let req_val: serde_json::Value =
+        serde_json::from_slice(raw).map_err(|e| MagicruneError::new(Code::InvalidJson, &[&e]))?;
+    let req: Request =
+        serde_json::from_slice(raw).map_err(|e| MagicruneError::new(Code::RequestShape, &[&e]))?;
+    if opts.strict {
+        validate_request_schema(&req_val)?;
+    }
+    let run_id = compute_run_id(raw, opts.seed);
+    let tenant = tenant::resolve(opts.tenant.as_deref(), &req.tenant)?;
+
+    let ctx = ExecutionContext::new(
+        run_id.clone(),
+        req.policy_id.clone(),
+        policy.digest.clone(),
+        tenant.clone(),
+    );
+    let span = ctx.span();
+    let _enter = span.enter();
+
+    // Rejections name the request field (a JSON Pointer) and the policy rule
+    // behind them, for `exec --errors json`.
+    for k in req.env.keys() {
+        if let Some(p) = policy.env_deny.iter().find(|p| pat_matches(k, p)) {
+            return Err(MagicruneError::new(Code::EnvDenied, &[k])
+                .at(format!("/env/{}", pointer_token(k)))
+                .rule(format!("capabilities.env.deny: {}", p)));
+        }
+    }
+    if !policy.env_allow.is_empty() {
+        for k in req.env.keys() {
+            if !policy.env_allow.iter().any(|p| pat_matches(k, p)) {
+                ctx.record_policy_violation("env_not_allowed", k);
+                return Err(MagicruneError::new(Code::EnvNotAllowed, &[k])
+                    .at(format!("/env/{}", pointer_token(k)))
+                    .rule("capabilities.env.allow"));
+            }
+        }
+    }
+    // Network allowlist: union of request.allow_net and policy capabilities.net.allow
+    let cmd_l = req.cmd.to_lowercase();
+    let net_intent = cmd_l.contains("curl ")
+        || cmd_l.contains("wget ")
+        || cmd_l.contains("http://")
+        || cmd_l.contains("https://");
+    // Offline: no allowlist applies, and asking for network fails the
+    // request.
+    let offline = offline_reason(opts.offline, policy);
+    let net_allow: &[String] = if offline.is_some() {
+        &[]
+    } else {
+        &policy.net_allow
+    };
+    if let Some(why) = offline {
+        let pointer = if !req.allow_net.is_empty() {
+            Some("/allow_net")
+        } else {
+            net_intent.then_some("/cmd")
+        };
+        if let Some(pointer) = pointer {
+            let err = MagicruneError::new(Code::NetDisabled, &[&why]).at(pointer);
+            return Err(if policy.net_disabled {
+                err.rule("capabilities.net.default: disabled_hard")
+            } else {
+                err
+            });
+        }
+    }
+    if net_intent {
+        let mut allowed: Vec<String> = req.allow_net.clone();
+        allowed.extend(net_allow.iter().cloned());
+        if allowed.is_empty() {
+            return Err(MagicruneError::new(Code::NetNoAllowlist, &[])
+                .at("/cmd")
+                .rule("capabilities.net.allow"));
+        }
+        for h in extract_http_hosts(&req.cmd) {
+            let (h_host, h_port) = hostport_parts(&h);
+            if !allowed.iter().any(|a| allowed_match(&h_host, h_port, a)) {
+                return Err(MagicruneError::new(Code::NetDenied, &[&h])
+                    .at("/cmd")
+                    .rule("capabilities.net.allow"));
+            }
+        }
+    }
+    // Devices beyond the standard pseudo-devices need capabilities.devices.allow.
+    let devices_used: Vec<String> = extract_device_paths(&req.cmd)
+        .into_iter()
+        .filter(|d| !STANDARD_DEVICES.iter().any(|s| pat_matches(d, s)))
+        .collect();
+    for d in &devices_used {
+        if !policy.devices_allow.iter().any(|p| pat_matches(d, p)) {
+            ctx.record_policy_violation("device_not_allowed", d);
+            return Err(MagicruneError::new(Code::DeviceDenied, &[d])
+                .at("/cmd")
+                .rule("capabilities.devices.allow"));
+        }
+    }
+    if !req.callback_url.is_empty() {
+        webhook::check_url(&req.callback_url)
+            .map_err(|e| MagicruneError::new(Code::CallbackUrl, &[&e]).at("/callback_url"))?;
+    }
+    if let Some(quotas) = &opts.quotas {
+        if !quotas.try_acquire(&tenant) {
+            ctx.record_policy_violation("tenant_quota", &tenant);
+            return Err(MagicruneError::new(Code::TenantQuota, &[&tenant]).at("/tenant"));
+        }
+    }
+    let zero_limit = [
+        ("cpu_ms", req.limits.cpu_ms),
+        ("memory_mb", req.limits.memory_mb),
+        ("pids", req.limits.pids),
+    ]
+    .into_iter()
+    .find(|(_, v)| *v == Some(0));
+    if let Some((key, _)) = zero_limit {
+        return Err(
+            MagicruneError::new(Code::Schema, &[&"limits must be at least 1"])
+                .at(format!("/limits/{}", key)),
+        );
+    }
+    let run_limits = policy
+        .limits
+        .narrowed(req.timeout_sec, &req.limits)
+        .map_err(MagicruneError::from)?;
+    // The part of the score the command line alone shows (see
+    // `grader::weights`): network intent without any allowlist, ssh, and
+    // device access (GPU, KVM, ...).
+    let mut tally = RiskTally::default();
+    let waivers_refused = accept_risk(&req.accept_risk, policy, &mut tally);
+    if net_intent && req.allow_net.is_empty() && net_allow.is_empty() {
+        tally.add("net_intent", weights::NET_INTENT);
+    }
+    if cmd_l.contains("ssh ") {
+        tally.add("ssh", weights::SSH);
+    }
+    if !devices_used.is_empty() {
+        tally.add("device", weights::DEVICE);
+    }
+    // Hosts the command may connect to (all of them, by now) but whose
+    // names the stub resolver will not look up.
+    let dns_mismatch: Vec<Finding> = if policy.dns_allow.is_empty() || offline.is_some() {
+        Vec::new()
+    } else {
+        extract_http_hosts(&req.cmd)
+            .into_iter()
+            .filter_map(|h| {
+                let (host, _) = hostport_parts(&h);
+                let by_name = host.parse::<std::net::IpAddr>().is_err();
+                (by_name
+                    && !policy
+                        .dns_allow
+                        .iter()
+                        .any(|a| allowed_match(&host, None, a)))
+                .then(|| Finding {
+                    kind: DNS_MISMATCH.to_string(),
+                    detail: format!("{}: may be connected to but not looked up", host),
+                    count: 1,
+                })
+            })
+            .collect()
+    };
+    if !dns_mismatch.is_empty() {
+        tally.add(DNS_MISMATCH, weights::DNS_MISMATCH);
+    }
+    // The programs the command runs, resolved and hashed before it runs, so
+    // it cannot swap them first. Only the local native sandbox's host can be
+    // looked at. Programs outside `capabilities.exec.digests` block the
+    // request or grade it red.
+    let local_native = opts.backend == Backend::Local
+        && opts.sandbox.unwrap_or_else(default_sandbox) == SandboxKind::Linux;
+    let executables = if (policy.observe_provenance || policy.exec_digests.is_some())
+        && local_native
+        && !req.cmd.trim().is_empty()
+    {
+        let path =
+            crate::ports::env::var("PATH").unwrap_or_else(|_| provenance::DEFAULT_PATH.to_string());
+        provenance::collect(
+            &req.cmd,
+            Shell::resolve(policy.shell),
+            &path,
+            Path::new("/"),
+        )
+    } else {
+        Vec::new()
+    };
+    let mut unapproved = Vec::new();
+    if let Some(file) = policy.exec_digests.as_deref().filter(|_| local_native) {
+        let approved = provenance::read_digests(Path::new(file)).map_err(|e| {
+            MagicruneError::new(
+                Code::PolicyLoad,
+                &[&format!("capabilities.exec.digests: {}: {}", file, e)],
+            )
+        })?;
+        unapproved.extend(executables.iter().filter(|e| !approved.contains(&e.sha256)));
+        if let Some(e) = unapproved.first() {
+            ctx.record_policy_violation(UNAPPROVED_BINARY, &e.path);
+            if policy.exec_unapproved == Unapproved::Block {
+                return Err(
+                    MagicruneError::new(Code::UnapprovedBinary, &[&e.path, &e.sha256])
+                        .at("/cmd")
+                        .rule(format!("capabilities.exec.digests: {}", file)),
+                );
+            }
+            tally.add(UNAPPROVED_BINARY, weights::UNAPPROVED_BINARY);
+        }
+    }
+    let unapproved: Vec<Finding> = unapproved
+        .into_iter()
+        .map(|e| Finding {
+            kind: UNAPPROVED_BINARY.to_string(),
+            detail: format!("{} sha256 {}", e.path, e.sha256),
+            count: 1,
+        })
+        .collect();
+    // Capabilities the policy only grants in a change window: outside it
+    // nothing is written or run, and the request grades red.
+    let uses = schedule::Uses {
+        net: net_intent || !req.allow_net.is_empty(),
+        fs: !req.files.is_empty(),
+        devices: !devices_used.is_empty(),
+    };
+    let outside_window = policy
+        .schedule
+        .and_then(|s| s.violation(uses, std::time::SystemTime::now()));
+    if let Some(why) = &outside_window {
+        ctx.record_policy_violation(schedule::OUTSIDE_WINDOW, why);
+        tally.add(schedule::OUTSIDE_WINDOW, weights::OUTSIDE_WINDOW);
+    }
+    if opts.interactive {
+        let static_score = tally.score();
+        if tally.verdict(&policy.thresholds) == Verdict::Red {
+            return Err(MagicruneError::new(Code::InteractiveRed, &[&static_score])
+                .at("/cmd")
+                .rule("grading.thresholds"));
+        }
+        if opts.backend != Backend::Local
+            || opts.sandbox.unwrap_or_else(default_sandbox) != SandboxKind::Linux
+        {
+            return Err(MagicruneError::new(Code::InteractiveSandbox, &[]));
+        }
+    }
+    timings.validate_ms = ms_since(&opts.clock, phase);
+
+    // --- cache --------------------------------------------------------------
+    // Under `cache.ttl_sec`, an identical request that got a green result
+    // within the TTL is answered with it (see `result_cache`).
+    let instance_id = opts.instance_id.clone().unwrap_or_default();
+    let cache = opts.result_cache.as_deref().filter(|_| {
+        policy.cache_ttl_sec > 0
+            && !opts.dry_run
+            && !opts.interactive
+            && !req.cmd.trim().is_empty()
+            && outside_window.is_none()
+    });
+    let cache_key = cache.map(|_| ResultCache::key(raw, opts.seed, &policy.digest));
+    if let (Some(c), Some(key)) = (cache, &cache_key) {
+        let ttl = std::time::Duration::from_secs(policy.cache_ttl_sec);
+        if let Some(mut result) = c.get(&tenant, key, ttl) {
+            tracing::info!(run_id = %run_id, "cache: returning a stored result");
+            result.run_id = run_id;
+            result.queue_ms = queue_ms;
+            result.instance_id = instance_id;
+            result.cached = true;
+            result.duration_ms = ms_since(&opts.clock, started);
+            opts.keyring
+                .sign_result(&mut result)
+                .map_err(|e| MagicruneError::internal(format!("sign: {}", e)))?;
+            return Ok(replay(result, req.callback_url, tenant));
+        }
+    }
+
+    // --- guard --------------------------------------------------------------
+    // A run id that already ran is answered from its stored result; one that
+    // started and never finished is handled as `exec.on_redelivery` says.
+    // The marker is dropped again when the run fails before its result.
+    let guard = opts
+        .guard
+        .as_deref()
+        .filter(|_| !opts.dry_run && !req.cmd.trim().is_empty() && outside_window.is_none());
+    let mut interrupted = None;
+    let mut marked = None;
+    if let Some(g) = guard {
+        let guard_err = |e: std::io::Error| MagicruneError::internal(format!("guard: {}", e));
+        match g.begin(&run_id, &instance_id).map_err(guard_err)? {
+            Begin::Fresh => {}
+            Begin::Finished(result) => {
+                tracing::info!(run_id = %run_id, "guard: returning the stored result");
+                return Ok(replay(*result, req.callback_url, tenant));
+            }
+            Begin::Interrupted(seen) => match policy.on_redelivery {
+                OnRedelivery::Rerun => {
+                    tracing::warn!(run_id = %run_id, attempts = seen.attempts, "guard: running an interrupted run again");
+                    g.restart(&run_id, &instance_id, &seen).map_err(guard_err)?;
+                    interrupted = Some(seen);
+                }
+                OnRedelivery::Fail => {
+                    return Err(MagicruneError::new(Code::Redelivered, &[&run_id])
+                        .rule("exec.on_redelivery: fail"));
+                }
+                OnRedelivery::ReturnPartial => {
+                    let mut result = crate::guard::partial_result(
+                        &run_id,
+                        tally.verdict(&policy.thresholds).as_str(),
+                        tally.score(),
+                        seen,
+                    );
+                    result.risk_breakdown = Some(tally.breakdown());
+                    result.waivers = waivers(&req.accept_risk, &tally);
+                    result.tenant = tenant.clone();
+                    result.instance_id = instance_id;
+                    result.duration_ms = ms_since(&opts.clock, started);
+                    opts.keyring
+                        .sign_result(&mut result)
+                        .map_err(|e| MagicruneError::internal(format!("sign: {}", e)))?;
+                    return Ok(replay(result, req.callback_url, tenant));
+                }
+            },
+        }
+        marked = Some(g.hold(&run_id));
+    }
+
+    // --- materialize --------------------------------------------------------
+    // Only /tmp/** is writable unless the policy grants a path explicitly.
+    // Remote backends get the staged files instead of the local filesystem.
+    let phase = opts.clock.now_millis();
+    let mut staged = Vec::new();
+    for (i, f) in req.files.iter().enumerate() {
+        if outside_window.is_some() {
+            break;
+        }
+        let p = Path::new(&f.path);
+        let at = format!("/files/{}/path", i);
+        if !p.is_absolute() || f.path.contains("..") {
+            return Err(MagicruneError::new(
+                Code::Schema,
+                &[&"file.path must be absolute and must not contain '..'"],
+            )
+            .at(at));
+        }
+        if let Some(ro) = policy
+            .fs_readonly
+            .iter()
+            .find(|ro| pat_matches(&f.path, ro))
+        {
+            return Err(MagicruneError::new(Code::ReadonlyWrite, &[&f.path])
+                .at(at)
+                .rule(format!("capabilities.fs.readonly: {}", ro)));
+        }
+        let allowed_tmp = p.starts_with("/tmp/");
+        let allowed = allowed_tmp
+            || policy
+                .fs_allow
+                .iter()
+                .any(|pat| (pat == "/tmp/**" && allowed_tmp) || pat == &f.path);
+        if !allowed {
+            return Err(MagicruneError::new(Code::WriteDenied, &[&f.path])
+                .at(at)
+                .rule("capabilities.fs.allow"));
+        }
+        // Repeated inputs are copied from the cache (see `input_cache`);
+        // undecodable content is skipped there too.
+        if let (Backend::Local, Some(cache)) = (&opts.backend, &opts.input_cache) {
+            if !f.content_b64.is_empty() {
+                if let Some(dir) = p.parent() {
+                    let _ = std::fs::create_dir_all(dir);
+                }
+                cache
+                    .materialize(&f.content_b64, p)
+                    .map_err(|e| MagicruneError::new(Code::WriteFailed, &[&f.path, &e]))?;
+                continue;
+            }
+        }
+        let bytes = if f.content_b64.is_empty() {
+            Vec::new()
+        } else {
+            match base64::engine::general_purpose::STANDARD.decode(&f.content_b64) {
+                Ok(b) => b,
+                // Undecodable content is skipped rather than written.
+                Err(_) => continue,
+            }
+        };
+        if opts.backend == Backend::Local {
+            if let Some(dir) = p.parent() {
+                let _ = std::fs::create_dir_all(dir);
+            }
+            std::fs::write(p, &bytes)
+                .map_err(|e| MagicruneError::new(Code::WriteFailed, &[&f.path, &e]))?;
+        } else {
+            staged.push(StagedFile {
+                path: f.path.clone(),
+                bytes,
+            });
+        }
+    }
+    timings.materialize_ms = ms_since(&opts.clock, phase);
+
+    // --- exec ---------------------------------------------------------------
+    // Linux+native or a remote backend runs the command; the WASI default
+    // skips it here.
+    let phase = opts.clock.now_millis();
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    // Child exit status, reported verbatim (0 when the command is not executed).
+    let mut child_exit = 0;
+    let mut timed_out = false;
+    let mut reason = None;
+    let mut pids_peak = None;
+    let mut orphans_reaped = None;
+    let mut usage = None;
+    // Limits the command ran under; stays `None` when nothing ran.
+    let mut limits = None;
+    let mut findings = Vec::new();
+    let mut syscalls = None;
+    let mut net_log = Vec::new();
+    let mut manifest = Vec::new();
+    let mut core_dumps = Vec::new();
+    let mut output_timeline = None;
+    let mut env = None;
+    let mut egress = Vec::new();
+    // Interpreter the command ran under; empty when nothing ran.
+    let mut shell = String::new();
+    if !opts.dry_run && !req.cmd.trim().is_empty() && outside_window.is_none() {
+        // HTTP(S) through the egress proxy, checked against the allowlist
+        // and counted per target. Without it the command gets no network.
+        let proxy = if policy.net_proxy && local_native {
+            let mut allow = req.allow_net.clone();
+            allow.extend(net_allow.iter().cloned());
+            let limits = EgressLimits {
+                kbps: run_limits.net_kbps,
+                connections: run_limits.net_connections,
+            };
+            let names = policy.dns_allow.clone();
+            let cache = opts.http_cache.clone();
+            match (!allow.is_empty()).then(|| EgressProxy::start(allow, names, limits, cache)) {
+                Some(Ok(p)) => Some(p),
+                Some(Err(e)) => {
+                    tracing::warn!(error = %e, "egress proxy not started; network stays off");
+                    None
+                }
+                None => None,
+            }
+        } else {
+            None
+        };
+        // Lookups answered in the command's network namespace, apart from
+        // what it may connect to.
+        let dns = (!policy.dns_allow.is_empty() && local_native && offline.is_none())
+            .then(|| DnsStub::start(policy.dns_allow.clone()));
+        let spec = SandboxSpec {
+            wall_sec: run_limits.wall_sec,
+            cpu_ms: run_limits.cpu_ms,
+            memory_mb: run_limits.memory_mb,
+            pids: run_limits.pids,
+            devices: policy.devices_allow.clone(),
+            readonly: policy.fs_readonly.clone(),
+            syscalls: policy.syscalls_allow.clone(),
+            observe_syscalls: policy.observe_syscalls,
+            observe_net: policy.observe_net && offline.is_none(),
+            tmp_mb: run_limits.tmp_mb,
+            tmp_inodes: run_limits.tmp_inodes,
+            // Remote hosts are not probed; their images provide bash.
+            shell: Some(match opts.backend {
+                Backend::Local => Shell::resolve(policy.shell),
+                _ => policy.shell.unwrap_or(Shell::Bash),
+            }),
+            tty: opts.interactive,
+            pty: policy.pty || req.pty,
+            kill_grace_ms: run_limits.kill_grace_ms,
+            clock: opts.clock.clone(),
+            proxy: proxy.as_ref().map(EgressProxy::attach),
+            dns: dns.as_ref().map(DnsStub::attach),
+            core_mb: policy.core_mb,
+            core_dir: (policy.core_mb > 0).then(|| tenant::quarantine_dir(&tenant).join(&run_id)),
+            observe_output: policy.observe_output,
+            observe_env: policy.observe_env,
+            env_values: policy.env_values.clone(),
+            deterministic: opts.deterministic,
+            timeline_dir: policy
+                .observe_output
+                .then(|| tenant::quarantine_dir(&tenant).join(&run_id)),
+        };
+        let outcome = match &opts.backend {
+            Backend::Local => {
+                let sb = opts.sandbox.unwrap_or_else(default_sandbox);
+                tracing::info!(sandbox = ?sb, "sandbox");
+                if sb == SandboxKind::Linux {
+                    if let Some(m) = marked.as_mut() {
+                        m.running();
+                    }
+                    Some(
+                        exec_native_with(&req.cmd, req.stdin.as_bytes(), &spec, opts.hardening)
+                            .await,
+                    )
+                } else {
+                    None
+                }
+            }
+            remote => {
+                let mut allow_net = req.allow_net.clone();
+                allow_net.extend(net_allow.iter().cloned());
+                let task = RemoteTask {
+                    run_id: &run_id,
+                    tenant: &tenant,
+                    cmd: &req.cmd,
+                    stdin: req.stdin.as_bytes(),
+                    files: &staged,
+                    allow_net: &allow_net,
+                    spec: &spec,
+                };
+                if let Some(m) = marked.as_mut() {
+                    m.running();
+                }
+                Some(match remote {
+                    Backend::Kubernetes(cfg) => {
+                        tracing::info!(sandbox = "kubernetes", namespace = %cfg.namespace, "sandbox");
+                        backend::k8s::run_job(cfg, &task).await
+                    }
+                    Backend::Ssh(cfg) => {
+                        tracing::info!(sandbox = "ssh", host = %cfg.host, "sandbox");
+                        backend::ssh::run_remote(cfg, &task).await
+                    }
+                    Backend::Local => unreachable!(),
+                })
+            }
+        };
+        if let Some(outcome) = outcome {
+            if let TerminationReason::SpawnError(e) = &outcome.reason {
+                if let Some(m) = marked.take() {
+                    m.abandon();
+                }
+                return Err(MagicruneError::new(Code::SpawnFailed, &[&e]));
+            }
+            timed_out = outcome.timed_out();
+            reason = stop_reason(&outcome.reason, req.timeout_sec, &req.limits, &run_limits);
+            child_exit = outcome.exit_code;
+            pids_peak = outcome.pids_peak;
+            orphans_reaped = outcome.orphans_reaped;
+            usage = outcome.usage;
+            limits = Some(Limits {
+                wall_sec: spec.wall_sec,
+                cpu_ms: spec.cpu_ms,
+                memory_mb: spec.memory_mb,
+                pids: spec.pids,
+                tmp_mb: spec.tmp_mb,
+                tmp_inodes: spec.tmp_inodes,
+            });
+            stdout = outcome.stdout;
+            stderr = outcome.stderr;
+            if policy.strip_ansi || req.strip_ansi {
+                stdout = ansi::strip(&stdout);
+                stderr = ansi::strip(&stderr);
+            }
+            findings = outcome.findings;
+            syscalls = outcome.syscalls;
+            net_log = outcome.net_log;
+            manifest = outcome.manifest;
+            core_dumps = outcome.core_dumps;
+            output_timeline = outcome.output_timeline;
+            env = outcome.env;
+            if let Some(proxy) = proxy {
+                timings.net_cache_hits = proxy.cache_hits();
+                let (hosts, over) = proxy.finish();
+                egress = hosts;
+                findings.extend(over);
+            }
+            if let Some(dns) = dns {
+                let refused = dns.finish();
+                if !refused.is_empty() {
+                    tally.add(DNS_DENIED, weights::DNS_DENIED);
+                }
+                findings.extend(refused);
+            }
+            shell = spec
+                .shell
+                .map(|s| s.as_str())
+                .unwrap_or_default()
+                .to_string();
+        }
+    }
+    timings.exec_ms = ms_since(&opts.clock, phase);
+
+    // --- grade --------------------------------------------------------------
+    // The static rules (see validate), plus:
+    // - network syscalls observed at runtime without any allowlist
+    //   (unless already scored as network intent)
+    // - large, high-entropy stdout with network allowed -> grading.exfiltration
+    let phase = opts.clock.now_millis();
+    let no_net_allowed = req.allow_net.is_empty() && net_allow.is_empty();
+    if !net_intent && no_net_allowed && syscalls.map(|s| s.net > 0).unwrap_or(false) {
+        tally.add("net_runtime", weights::NET_RUNTIME);
+    }
+    if !no_net_allowed {
+        if let Some(h) = policy.exfiltration.check(&stdout) {
+            tally.add(EXFILTRATION, policy.exfiltration.score);
+            findings.push(Finding {
+                kind: EXFILTRATION.to_string(),
+                detail: format!("stdout {} bytes at {:.2} bits/byte", stdout.len(), h),
+                count: 1,
+            });
+        }
+    }
+    findings.extend(waivers_refused);
+    findings.extend(dns_mismatch);
+    let binary_unapproved = !unapproved.is_empty();
+    findings.extend(unapproved);
+    let window_closed = outside_window.is_some();
+    if let Some(why) = outside_window {
+        findings.push(Finding {
+            kind: schedule::OUTSIDE_WINDOW.to_string(),
+            detail: why,
+            count: 1,
+        });
+    }
+    let risk_score = tally.score();
+    debug_assert!(
+        risk_score <= MAX_SCORE,
+        "risk score {} is off the 0-{} scale",
+        risk_score,
+        MAX_SCORE
+    );
+    // A mandatory rule that fired, a runtime timeout, an unapproved program,
+    // or a request outside its change window always grades red, whatever
+    // the score said.
+    let mut verdict = tally.verdict(&policy.thresholds);
+    if let Some(rule) = tally.mandatory_fired() {
+        findings.push(Finding {
+            kind: "mandatory_rule".to_string(),
+            detail: format!("{} is in grading.mandatory", rule),
+            count: 1,
+        });
+    }
+    if timed_out || window_closed || binary_unapproved {
+        verdict = Verdict::Red;
+    }
+    ctx.record_completion(verdict.as_str(), risk_score, child_exit);
+    timings.grade_ms = ms_since(&opts.clock, phase);
+
+    // --- publish ------------------------------------------------------------
+    // Preparing the result for hand-off; the transport itself is the caller's.
+    let phase = opts.clock.now_millis();
+    let mut result = SpellResult {
+        run_id,
+        verdict: verdict.to_string(),
+        risk_score,
+        exit_code: child_exit,
+        duration_ms: 0,
+        stdout_trunc: false,
+        sbom_attestation: String::new(),
+        timings: Some(timings),
+        queue_ms,
+        limits,
+        reason,
+        pids_peak,
+        orphans_reaped,
+        usage,
+        interrupted,
+        findings,
+        risk_breakdown: Some(tally.breakdown()),
+        waivers: waivers(&req.accept_risk, &tally),
+        syscalls,
+        net_log,
+        manifest,
+        core_dumps,
+        output_timeline,
+        env,
+        executables,
+        egress,
+        network: if offline.is_some() {
+            NETWORK_DISABLED.to_string()
+        } else {
+            String::new()
+        },
+        shell,
+        tenant: tenant.clone(),
+        instance_id,
+        signature: String::new(),
+        ..Default::default()
+    };
+    result.set_output(&stdout, &stderr);
+    crate::metrics::observe(&result);
+    if opts.strict {
+        validate_result_schema(&result)?;
+    }
+    timings.publish_ms = ms_since(&opts.clock, phase);
+    result.timings = Some(timings);
+    result.duration_ms = ms_since(&opts.clock, started);
+    opts.keyring
+        .sign_result(&mut result)
+        .map_err(|e| MagicruneError::internal(format!("sign: {}", e)))?;
+    if let Some(m) = marked {
+        m.finish(&result)
+            .map_err(|e| MagicruneError::internal(format!("guard: {}", e)))?;
+    }
+    if let (Some(c), Some(key)) = (cache, &cache_key) {
+        if result_cache::storable(&result) {
+            if let Err(e) = c.put(&tenant, key, &result) {
+                tracing::warn!(error = %e, "cache: result not stored");
+            }
+        }
+    }
+
+    Ok(RunOutput {
+        result,
+        verdict,
+        stdout,
+        stderr,
+        callback_url: Some(req.callback_url).filter(|u| !u.is_empty()),
+        tenant,
+    })
+}
+
+/// Make the `grading.mandatory` rules of `policy` mandatory in `tally`, and
+/// waive the `accept` rules it lists in `grading.waivable` and not there.
+/// The others still count; each is returned as a `waiver_refused` finding.
+pub fn accept_risk(
+    accept: &[RiskAcceptance],
+    policy: &Policy,
+    tally: &mut RiskTally,
+) -> Vec<Finding> {
+    for rule in &policy.mandatory {
+        tally.require(rule);
+    }
+    let mut refused = Vec::new();
+    for a in accept {
+        let why = if !policy.waivable.contains(&a.rule) {
+            "is not in grading.waivable"
+        } else if !tally.accept(&a.rule) {
+            "is in grading.mandatory"
+        } else {
+            continue;
+        };
+        tracing::warn!(rule = %a.rule, approver = %a.approver, "grading: waiver refused");
+        refused.push(Finding {
+            kind: "waiver_refused".to_string(),
+            detail: format!("{} {}", a.rule, why),
+            count: 1,
+        });
+    }
+    refused
+}
+
+/// The `accept` entries `tally` waived, with the points each kept out of
+/// its score.
+pub fn waivers(accept: &[RiskAcceptance], tally: &RiskTally) -> Vec<Waiver> {
+    accept
+        .iter()
+        .filter(|a| tally.accepted(&a.rule))
+        .map(|a| Waiver {
+            rule: a.rule.clone(),
+            reason: a.reason.clone(),
+            approver: a.approver.clone(),
+            points: tally.waived(&a.rule),
+        })
+        .collect()
+}
+
+/// A result the guard had, as a finished run.
+fn replay(result: SpellResult, callback_url: String, tenant: String) -> RunOutput {
+    let b64 = &base64::engine::general_purpose::STANDARD;
+    RunOutput {
+        verdict: result.verdict.parse().unwrap_or(Verdict::Red),
+        stdout: b64.decode(&result.stdout_b64).unwrap_or_default(),
+        stderr: b64.decode(&result.stderr_b64).unwrap_or_default(),
+        result,
+        callback_url: Some(callback_url).filter(|u| !u.is_empty()),
+        tenant,
+    }
+}
+
+fn compile_schema(txt: &str) -> Option<jsonschema::JSONSchema> {
+    let json: serde_json::Value = serde_json::from_str(txt).ok()?;
+    jsonschema::JSONSchema::options().compile(&json).ok()
+}
+
+/// The request schema, compiled on first use and shared by every run in the
+/// process; `None` if it does not compile.
+fn request_validator() -> Option<&'static jsonschema::JSONSchema> {
+    static COMPILED: OnceLock<Option<jsonschema::JSONSchema>> = OnceLock::new();
+    COMPILED
+        .get_or_init(|| compile_schema(REQUEST_SCHEMA))
+        .as_ref()
+}
+
+/// See [`request_validator`].
+fn result_validator() -> Option<&'static jsonschema::JSONSchema> {
+    static COMPILED: OnceLock<Option<jsonschema::JSONSchema>> = OnceLock::new();
+    COMPILED
+        .get_or_init(|| compile_schema(RESULT_SCHEMA))
+        .as_ref()
+}
+
+/// Compile the embedded schemas now rather than on the first strict run;
+/// long-running modes call this at startup.
+pub fn precompile_schemas() {
+    request_validator();
+    result_validator();
+}
+
+fn validate_request_schema(req_val: &serde_json::Value) -> Result<(), MagicruneError> {
+    fn fail(msg: &str) -> Result<(), MagicruneError> {
+        Err(MagicruneError::new(Code::Schema, &[&msg]))
+    }
+    // JSON Schema validation against schemas/spell_request.schema.json
+    if let Some(compiled) = request_validator() {
+        if let Err(errors) = compiled.validate(req_val) {
+            // One `schema: ...` line per error, under one code; the
+            // pointer is the first error's.
+            let errors: Vec<_> = errors.collect();
+            if let Some(first) = errors.first() {
+                let mut m =
+                    Message::new(Code::Schema, &[first]).at(first.instance_path.to_string());
+                for e in &errors[1..] {
+                    m.text.push('\n');
+                    m.text.push_str(&Message::new(Code::Schema, &[e]).text);
+                }
+                return Err(m.into());
+            }
+        }
+    }
+    // Manual structural validation aligned with schemas (no external crates)
+    match crate::schema::request_errors(req_val).first() {
+        Some(msg) => fail(msg),
+        None => Ok(()),
+    }
+}
+
+fn validate_result_schema(result: &SpellResult) -> Result<(), MagicruneError> {
+    let out_val = serde_json::to_value(result)
+        .map_err(|e| MagicruneError::internal(format!("serialize: {}", e)))?;
+    if let Some(compiled) = result_validator() {
+        if let Err(errors) = compiled.validate(&out_val) {
+            let msgs: Vec<String> = errors.map(|e| e.to_string()).collect();
+            return Err(MagicruneError::new(Code::OutputSchema, &[&msgs.join("; ")]));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `raw` on a thread and runtime of its own, so no run can leave
+    /// the test's thread in a sandbox namespace.
+    fn run(raw: &str, policy: &Policy, opts: &ExecOptions) -> Result<RunOutput, MagicruneError> {
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap()
+                    .block_on(execute(raw.as_bytes(), policy, opts))
+            })
+            .join()
+            .unwrap()
+        })
+    }
+
+    #[test]
+    fn test_compute_run_id_seed() {
+        let a = compute_run_id(b"{}", None);
+        assert!(a.starts_with("r_"));
+        assert_eq!(a.len(), 2 + 64);
+        assert_eq!(a, compute_run_id(b"{}", None));
+        assert_ne!(a, compute_run_id(b"{}", Some(0)));
+    }
+
+    #[test]
+    fn test_schemas_compiled_once() {
+        precompile_schemas();
+        let compiled = request_validator().expect("request schema compiles");
+        assert!(std::ptr::eq(compiled, request_validator().unwrap()));
+        assert!(result_validator().is_some());
+
+        let opts = ExecOptions {
+            dry_run: true,
+            strict: true,
+            ..Default::default()
+        };
+        let err = run(r#"{"cmd":"true"}"#, &Policy::default(), &opts).unwrap_err();
+        assert!(matches!(err, MagicruneError::InvalidRequest(_)));
+    }
+
+    #[test]
+    fn test_timings_populated_for_skipped_command() {
+        let opts = ExecOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let out = run(r#"{"cmd":"echo hi"}"#, &Policy::default(), &opts).unwrap();
+        let t = out.result.timings.expect("timings");
+        let sum = t.validate_ms + t.materialize_ms + t.exec_ms + t.grade_ms + t.publish_ms;
+        assert!(out.result.duration_ms >= sum);
+        assert_eq!(out.verdict, Verdict::Green);
+        assert_eq!(out.result.exit_code, 0);
+        assert!(out.callback_url.is_none());
+        assert!(out.result.signature.is_empty());
+    }
+
+    #[test]
+    fn test_results_signed_with_node_key() {
+        let (private, _, kid) = crate::keys::generate().unwrap();
+        let opts = ExecOptions {
+            dry_run: true,
+            keyring: Keyring::new(vec![crate::keys::KeyEntry::from_pem(&private).unwrap()]),
+            ..Default::default()
+        };
+        let out = run(r#"{"cmd":"echo hi"}"#, &Policy::default(), &opts).unwrap();
+        assert_eq!(opts.keyring.verify_result(&out.result).unwrap().kid, kid);
+    }
+
+    #[test]
+    fn test_tenant_binding_and_quota() {
+        let policy = Policy::default();
+        let opts = ExecOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let out = run(r#"{"cmd":"true"}"#, &policy, &opts).unwrap();
+        assert_eq!(out.result.tenant, tenant::DEFAULT_TENANT);
+        let out = run(r#"{"cmd":"true","tenant":"acme"}"#, &policy, &opts).unwrap();
+        assert_eq!(out.tenant, "acme");
+
+        let opts = ExecOptions {
+            dry_run: true,
+            tenant: Some("acme".into()),
+            quotas: Some(Arc::new(TenantQuotas::new(
+                1,
+                std::time::Duration::from_secs(60),
+            ))),
+            ..Default::default()
+        };
+        let err = run(r#"{"cmd":"true","tenant":"beta"}"#, &policy, &opts).unwrap_err();
+        assert_eq!(err.exit_code(), 3);
+        let out = run(r#"{"cmd":"true"}"#, &policy, &opts).unwrap();
+        assert_eq!(out.result.tenant, "acme");
+        let err = run(r#"{"cmd":"true","seed":1}"#, &policy, &opts).unwrap_err();
+        assert!(err.to_string().contains("over its run quota"));
+    }
+
+    #[test]
+    fn test_stop_reason_names_the_limit() {
+        let limits = PolicyLimits {
+            wall_sec: 5,
+            ..Default::default()
+        };
+        let asked = RequestLimits {
+            cpu_ms: Some(100),
+            ..Default::default()
+        };
+        let stop = |reason, timeout_sec| {
+            stop_reason(&reason, timeout_sec, &asked, &limits)
+                .map(|r| format!("{} {} {} {}", r.kind, r.limit, r.source, r.value))
+        };
+        assert_eq!(
+            stop(TerminationReason::WallTimeout, 5).unwrap(),
+            "wall_timeout timeout_sec request 5"
+        );
+        assert_eq!(
+            stop(TerminationReason::WallTimeout, 0).unwrap(),
+            "wall_timeout limits.wall_sec policy 5"
+        );
+        assert_eq!(
+            stop(TerminationReason::CpuLimit, 0).unwrap(),
+            "cpu_limit limits.cpu_ms request 5000"
+        );
+        assert_eq!(
+            stop(TerminationReason::MemoryLimit, 0).unwrap(),
+            "memory_limit limits.memory_mb policy 512"
+        );
+        assert_eq!(stop(TerminationReason::Completed, 5), None);
+        assert_eq!(stop(TerminationReason::Signalled(9), 5), None);
+    }
+
+    #[test]
+    fn test_device_access() {
+        let opts = ExecOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let out = run(r#"{"cmd":"echo hi >/dev/null"}"#, &Policy::default(), &opts).unwrap();
+        assert_eq!(out.result.risk_score, 0);
+        // Nothing ran, so no limits are reported.
+        assert!(out.result.limits.is_none());
+        let err = run(r#"{"cmd":"ls /dev/kvm"}"#, &Policy::default(), &opts).unwrap_err();
+        assert_eq!(err.exit_code(), 3);
+        let policy = Policy {
+            devices_allow: vec!["/dev/kvm".into()],
+            ..Default::default()
+        };
+        let out = run(r#"{"cmd":"ls /dev/kvm"}"#, &policy, &opts).unwrap();
+        assert_eq!(out.result.risk_score, 20);
+        assert_eq!(out.verdict, Verdict::Green);
+        let breakdown = out.result.risk_breakdown.unwrap();
+        assert_eq!(breakdown.raw, 20);
+        assert_eq!(breakdown.rules, [("device".to_string(), 20)].into());
+    }
+
+    #[test]
+    fn test_accept_risk_waives_rules() {
+        let opts = ExecOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let policy = Policy {
+            waivable: vec!["ssh".into()],
+            ..Default::default()
+        };
+        let raw = r#"{"cmd":"ssh host true","accept_risk":[{"rule":"ssh","reason":"deploy","approver":"ops"},{"rule":"device","reason":"gpu","approver":"ops"}]}"#;
+        let out = run(raw, &policy, &opts).unwrap();
+        assert_eq!(out.result.risk_score, 0);
+        assert_eq!(out.verdict, Verdict::Green);
+        assert_eq!(
+            out.result.waivers,
+            [Waiver {
+                rule: "ssh".into(),
+                reason: "deploy".into(),
+                approver: "ops".into(),
+                points: weights::SSH,
+            }]
+        );
+        assert_eq!(out.result.findings[0].kind, "waiver_refused");
+        let out = run(raw, &Policy::default(), &opts).unwrap();
+        assert_eq!(out.result.risk_score, weights::SSH);
+        assert!(out.result.waivers.is_empty());
+    }
+
+    #[test]
+    fn test_exec_digests() {
+        let opts = ExecOptions {
+            dry_run: true,
+            sandbox: Some(SandboxKind::Linux),
+            ..Default::default()
+        };
+        let list = std::env::temp_dir().join(format!("mr_digests_{}", std::process::id()));
+        let mut policy = Policy {
+            shell: Some(Shell::Sh),
+            exec_digests: Some(list.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let raw = r#"{"cmd":"true"}"#;
+        assert_eq!(
+            run(raw, &policy, &opts).unwrap_err().code(),
+            Code::PolicyLoad
+        );
+
+        std::fs::write(&list, "").unwrap();
+        let out = run(raw, &policy, &opts).unwrap();
+        assert_eq!(out.verdict, Verdict::Red);
+        assert_eq!(out.result.executables[0].name, "sh");
+        assert_eq!(out.result.findings[0].kind, UNAPPROVED_BINARY);
+
+        policy.exec_unapproved = Unapproved::Block;
+        let err = run(raw, &policy, &opts).unwrap_err();
+        assert_eq!(err.code(), Code::UnapprovedBinary);
+        assert_eq!(err.exit_code(), 3);
+
+        std::fs::write(&list, format!("{}\n", out.result.executables[0].sha256)).unwrap();
+        let out = run(raw, &policy, &opts).unwrap();
+        assert_eq!(out.verdict, Verdict::Green);
+        assert!(out.result.findings.is_empty());
+        std::fs::remove_file(&list).ok();
+    }
+
+    #[test]
+    fn test_dns_mismatch() {
+        let opts = ExecOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let mut policy = Policy {
+            net_allow: vec!["example.com".into(), "10.0.0.0/8".into()],
+            dns_allow: vec!["*.internal".into()],
+            ..Default::default()
+        };
+        let raw = r#"{"cmd":"curl https://example.com http://10.0.0.1/x"}"#;
+        let out = run(raw, &policy, &opts).unwrap();
+        assert_eq!(out.result.findings.len(), 1);
+        assert_eq!(out.result.findings[0].kind, DNS_MISMATCH);
+        assert_eq!(
+            out.result.findings[0].detail,
+            "example.com: may be connected to but not looked up"
+        );
+        assert_eq!(out.result.risk_score, weights::DNS_MISMATCH);
+
+        policy.dns_allow.push("example.com".into());
+        let out = run(raw, &policy, &opts).unwrap();
+        assert!(out.result.findings.is_empty());
+        assert_eq!(out.result.risk_score, 0);
+    }
+
+    #[test]
+    fn test_offline_refuses_network() {
+        let mut opts = ExecOptions {
+            dry_run: true,
+            offline: true,
+            ..Default::default()
+        };
+        let mut policy = Policy {
+            net_allow: vec!["example.com".into()],
+            ..Default::default()
+        };
+        let err = run(
+            r#"{"cmd":"true","allow_net":["example.com"]}"#,
+            &policy,
+            &opts,
+        )
+        .unwrap_err();
+        assert_eq!(err.code(), Code::NetDisabled);
+        assert_eq!(err.message().pointer, "/allow_net");
+        // The policy's allowlist does not apply either.
+        let curl = r#"{"cmd":"curl https://example.com"}"#;
+        assert_eq!(
+            run(curl, &policy, &opts).unwrap_err().code(),
+            Code::NetDisabled
+        );
+        let out = run(r#"{"cmd":"true"}"#, &policy, &opts).unwrap();
+        assert_eq!(out.result.network, NETWORK_DISABLED);
+
+        opts.offline = false;
+        assert_eq!(run(curl, &policy, &opts).unwrap().result.network, "");
+        policy.net_disabled = true;
+        let err = run(curl, &policy, &opts).unwrap_err();
+        assert_eq!(
+            err.message().rule,
+            "capabilities.net.default: disabled_hard"
+        );
+    }
+
+    #[test]
+    fn test_mandatory_rule_grades_red_though_waived() {
+        let opts = ExecOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let policy = Policy {
+            waivable: vec!["ssh".into()],
+            mandatory: vec!["ssh".into()],
+            ..Default::default()
+        };
+        let raw = r#"{"cmd":"ssh host true","accept_risk":[{"rule":"ssh","reason":"deploy","approver":"ops"}]}"#;
+        let out = run(raw, &policy, &opts).unwrap();
+        assert_eq!(out.result.risk_score, weights::SSH);
+        assert_eq!(out.verdict, Verdict::Red);
+        assert!(out.result.waivers.is_empty());
+        let kinds: Vec<_> = out.result.findings.iter().map(|f| &*f.kind).collect();
+        assert_eq!(kinds, ["waiver_refused", "mandatory_rule"]);
+        assert_eq!(out.result.findings[0].detail, "ssh is in grading.mandatory");
+    }
+
+    #[test]
+    fn test_interactive_refuses_static_red() {
+        let policy = Policy {
+            thresholds: crate::policy::Thresholds::new(20, 29).unwrap(),
+            ..Default::default()
+        };
+        let opts = ExecOptions {
+            interactive: true,
+            sandbox: Some(SandboxKind::Linux),
+            ..Default::default()
+        };
+        let err = run(r#"{"cmd":"ssh host"}"#, &policy, &opts).unwrap_err();
+        assert_eq!(err.exit_code(), 3);
+        assert!(err.to_string().contains("static verdict is red (risk 30)"));
+
+        let opts = ExecOptions {
+            sandbox: Some(SandboxKind::Wasi),
+            ..opts
+        };
+        let err = run(r#"{"cmd":"true"}"#, &policy, &opts).unwrap_err();
+        assert!(err.to_string().contains("Linux native sandbox"));
+    }
+
+    #[test]
+    fn test_result_records_shell() {
+        let opts = ExecOptions {
+            sandbox: Some(SandboxKind::Linux),
+            ..Default::default()
+        };
+        let policy = Policy {
+            shell: Some(Shell::Argv),
+            ..Default::default()
+        };
+        let out = run(r#"{"cmd":"echo 'a  b'"}"#, &policy, &opts).unwrap();
+        assert_eq!(out.result.shell, "argv");
+        assert_eq!(out.stdout, b"a  b\n");
+        let out = run(r#"{"cmd":"true"}"#, &Policy::default(), &opts).unwrap();
+        assert_eq!(out.result.shell, Shell::detect().as_str());
+    }
+
+    #[test]
+    fn test_strip_ansi() {
+        let opts = ExecOptions {
+            sandbox: Some(SandboxKind::Linux),
+            ..Default::default()
+        };
+        let policy = Policy {
+            shell: Some(Shell::Sh),
+            ..Default::default()
+        };
+        let cmd = r#""cmd":"printf '\\033[31mred\\033[0m\\n'""#;
+        let out = run(&format!("{{{}}}", cmd), &policy, &opts).unwrap();
+        assert_eq!(out.stdout, b"\x1b[31mred\x1b[0m\n");
+        let out = run(&format!("{{{},\"strip_ansi\":true}}", cmd), &policy, &opts).unwrap();
+        assert_eq!(out.stdout, b"red\n");
+    }
+
+    #[test]
+    fn test_result_lists_env() {
+        let opts = ExecOptions {
+            sandbox: Some(SandboxKind::Linux),
+            ..Default::default()
+        };
+        let policy = Policy {
+            observe_env: true,
+            env_values: vec!["PATH".into()],
+            ..Default::default()
+        };
+        let out = run(r#"{"cmd":"true"}"#, &policy, &opts).unwrap();
+        let env = out.result.env.unwrap();
+        let var = |n: &str| env.vars.iter().find(|v| v.name == n).unwrap();
+        assert_eq!(var("PATH").value, std::env::var("PATH").ok());
+        assert_eq!(var("TMPDIR").value, None);
+        assert_ne!(var("TMPDIR").source, "inherited");
+        assert!(run(r#"{"cmd":"true"}"#, &Policy::default(), &opts)
+            .unwrap()
+            .result
+            .env
+            .is_none());
+    }
+
+    #[test]
+    fn test_signed_requests_required() {
+        let producer = crate::signing::SigningKey::from_bytes(&[3u8; 32]);
+        let (_, public, _) = crate::keys::generate().unwrap();
+        let policy = Policy {
+            require_signed_requests: true,
+            ..Default::default()
+        };
+        let opts = ExecOptions {
+            dry_run: true,
+            producer_keys: Keyring::new(vec![
+                crate::keys::KeyEntry::from_pem(&public).unwrap(),
+                crate::keys::KeyEntry {
+                    kid: crate::keys::key_id(&producer.verifying_key()),
+                    signing: None,
+                    verifying: producer.verifying_key(),
+                    not_before: None,
+                    not_after: None,
+                },
+            ]),
+            ..Default::default()
+        };
+        let unauthenticated = |raw: &str| match run(raw, &policy, &opts) {
+            Err(MagicruneError::Unauthenticated(e)) => e.text,
+            other => panic!("{:?}", other.map(|o| o.result)),
+        };
+        assert_eq!(
+            unauthenticated(r#"{"cmd":"echo hi"}"#),
+            "unauthenticated request: not signed"
+        );
+
+        let mut req = serde_json::json!({"cmd": "echo hi"});
+        crate::signing::sign_request(&producer, req.as_object_mut().unwrap());
+        let out = run(&req.to_string(), &policy, &opts).unwrap();
+        assert_eq!(out.verdict, Verdict::Green);
+
+        req["cmd"] = "curl http://x.test/".into();
+        assert_eq!(
+            unauthenticated(&req.to_string()),
+            "unauthenticated request: signature does not match"
+        );
+        // Unsigned requests still run when the policy does not ask.
+        assert!(run(r#"{"cmd":"echo hi"}"#, &Policy::default(), &opts).is_ok());
+    }
+
+    #[test]
+    fn test_run_id_hashes_rendered_request() {
+        let opts = ExecOptions {
+            dry_run: true,
+            vars: [("HOST".to_string(), "b".to_string())].into(),
+            ..Default::default()
+        };
+        let out = run(
+            r#"{"cmd":"ping ${HOST}","vars":{"HOST":"a"}}"#,
+            &Policy::default(),
+            &opts,
+        )
+        .unwrap();
+        assert_eq!(
+            out.result.run_id,
+            compute_run_id(br#"{"cmd":"ping b"}"#, None)
+        );
+        match run(
+            r#"{"cmd":"ping ${HOST}"}"#,
+            &Policy::default(),
+            &ExecOptions::default(),
+        ) {
+            Err(MagicruneError::InvalidRequest(e)) => assert!(e.text.contains("${HOST}")),
+            other => panic!("{:?}", other.map(|o| o.result)),
+        }
+    }
+
+    #[test]
+    fn test_run_spell_typed_request() {
+        let req = SpellRequest {
+            cmd: Some("ssh host".into()),
+            seed: Some(7),
+            ..Default::default()
+        };
+        let opts = ExecOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let a = rt
+            .block_on(run_spell(&req, &Policy::default(), &opts))
+            .unwrap();
+        let b = rt
+            .block_on(run_spell(&req, &Policy::default(), &opts))
+            .unwrap();
+        assert_eq!(a.run_id, b.run_id);
+        assert_eq!(a.risk_score, 30);
+        assert_eq!(a.verdict, "yellow");
+    }
+
+    #[test]
+    fn test_policy_violations() {
+        let policy = Policy::default();
+        let opts = ExecOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let err = run(r#"{"cmd":"curl https://x.test/"}"#, &policy, &opts).unwrap_err();
+        assert_eq!(err.exit_code(), 3);
+        let err = run(r#"{"cmd":"true","timeout_sec":61}"#, &policy, &opts).unwrap_err();
+        assert!(err.to_string().contains("exceeds wall_sec limit"));
+        let err = run(
+            r#"{"cmd":"true","limits":{"memory_mb":4096}}"#,
+            &policy,
+            &opts,
+        )
+        .unwrap_err();
+        assert_eq!(err.exit_code(), 3);
+        assert_eq!(
+            err.to_string(),
+            "[MR2008] policy: limits.memory_mb 4096 exceeds policy ceiling 512"
+        );
+        let err = run(r#"{"cmd":"true","limits":{"pids":0}}"#, &policy, &opts).unwrap_err();
+        assert_eq!(err.exit_code(), 1);
+        let err = run(r#"{"cmd":"true","limits":{"tmp_mb":1}}"#, &policy, &opts).unwrap_err();
+        assert_eq!(err.exit_code(), 1);
+        let err = run(
+            r#"{"cmd":"true","callback_url":"ftp://x.test/"}"#,
+            &policy,
+            &opts,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("callback_url"));
+        let err = run("not json", &policy, &opts).unwrap_err();
+        assert_eq!(err.exit_code(), 1);
+    }
+
+    #[test]
+    fn test_guard_runs_a_run_id_once() {
+        let dir = std::env::temp_dir().join(format!("mr_engine_guard_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let guard = Arc::new(ExecGuard::new(&dir));
+        // Under the WASI default nothing executes, which is all the guard
+        // needs.
+        let opts = ExecOptions {
+            guard: Some(guard.clone()),
+            instance_id: Some("w-1".into()),
+            sandbox: Some(SandboxKind::Wasi),
+            ..Default::default()
+        };
+        let first = run(r#"{"cmd":"echo once"}"#, &Policy::default(), &opts).unwrap();
+        let again = run(r#"{"cmd":"echo once"}"#, &Policy::default(), &opts).unwrap();
+        assert_eq!(again.result.run_id, first.result.run_id);
+        assert_eq!(again.result.timings, first.result.timings);
+
+        // Started elsewhere and never finished.
+        let raw = r#"{"cmd":"ssh host"}"#;
+        let run_id = compute_run_id(raw.as_bytes(), None);
+        assert!(matches!(guard.begin(&run_id, "w-0").unwrap(), Begin::Fresh));
+        let mut policy = Policy {
+            on_redelivery: OnRedelivery::Fail,
+            ..Default::default()
+        };
+        let err = run(raw, &policy, &opts).unwrap_err();
+        assert_eq!(err.exit_code(), 3);
+        policy.on_redelivery = OnRedelivery::ReturnPartial;
+        let partial = run(raw, &policy, &opts).unwrap().result;
+        assert_eq!(partial.exit_code, -1);
+        assert_eq!(partial.risk_score, weights::SSH);
+        assert_eq!(partial.instance_id, "w-1");
+        let seen = partial.interrupted.unwrap();
+        assert_eq!((seen.instance_id.as_str(), seen.attempts), ("w-0", 1));
+
+        // A run that fails before its result leaves no marker behind.
+        let taken =
+            std::path::PathBuf::from(format!("/tmp/mr_engine_taken_{}", std::process::id()));
+        std::fs::create_dir_all(&taken).unwrap();
+        let raw = format!(
+            r#"{{"cmd":"true","files":[{{"path":"{}","content_b64":"aGk="}}]}}"#,
+            taken.display()
+        );
+        let err = run(&raw, &Policy::default(), &opts).unwrap_err();
+        assert_eq!(err.code(), Code::WriteFailed);
+        let run_id = compute_run_id(raw.as_bytes(), None);
+        assert!(matches!(guard.begin(&run_id, "w-1").unwrap(), Begin::Fresh));
+        let _ = std::fs::remove_dir_all(&taken);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_result_cache_answers_identical_requests() {
+        let dir = std::env::temp_dir().join(format!("mr_engine_cache_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let opts = ExecOptions {
+            result_cache: Some(Arc::new(ResultCache::new(&dir))),
+            instance_id: Some("w-1".into()),
+            sandbox: Some(SandboxKind::Wasi),
+            ..Default::default()
+        };
+        let mut policy = Policy {
+            digest: "p1".into(),
+            ..Default::default()
+        };
+        let raw = r#"{"cmd":"echo once"}"#;
+        assert!(!run(raw, &policy, &opts).unwrap().result.cached);
+        assert!(!run(raw, &policy, &opts).unwrap().result.cached);
+
+        policy.cache_ttl_sec = 60;
+        let first = run(raw, &policy, &opts).unwrap().result;
+        assert!(!first.cached);
+        let again = run(r#"{ "cmd": "echo once" }"#, &policy, &opts)
+            .unwrap()
+            .result;
+        assert!(again.cached);
+        assert_eq!(again.verdict, "green");
+        assert_eq!(again.instance_id, "w-1");
+        assert_ne!(again.run_id, first.run_id);
+
+        policy.digest = "p2".into();
+        assert!(!run(raw, &policy, &opts).unwrap().result.cached);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_outside_change_window_grades_red() {
+        let hour = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            / 3600;
+        // A one-hour window that starts two hours from now.
+        let closed = format!("{:02}-{:02}", (hour + 2) % 24, (hour + 3) % 24);
+        let policy = Policy {
+            schedule: crate::schedule::Schedule::parse(None, None, Some(&closed), Some("[net]"))
+                .unwrap(),
+            ..Default::default()
+        };
+        let opts = ExecOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let out = run(
+            r#"{"cmd":"curl https://example.com","allow_net":["example.com"]}"#,
+            &policy,
+            &opts,
+        )
+        .unwrap();
+        assert_eq!(out.verdict, Verdict::Red);
+        let rules = out.result.risk_breakdown.unwrap().rules;
+        assert_eq!(
+            rules.get(schedule::OUTSIDE_WINDOW),
+            Some(&weights::OUTSIDE_WINDOW)
+        );
+        assert_eq!(out.result.findings[0].kind, schedule::OUTSIDE_WINDOW);
+        // Capabilities the window does not cover are not restricted.
+        let out = run(r#"{"cmd":"echo hi"}"#, &policy, &opts).unwrap();
+        assert_eq!(out.verdict, Verdict::Green);
+    }
+
+    #[test]
+    fn test_audit_events() {
+        let path =
+            std::env::temp_dir().join(format!("mr_engine_audit_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let opts = ExecOptions {
+            dry_run: true,
+            audit: Some(Arc::new(audit::FileSink::new(&path))),
+            ..Default::default()
+        };
+        let policy = Policy::default();
+        run(r#"{"cmd":"echo hi"}"#, &policy, &opts).unwrap();
+        run(r#"{"cmd":"curl https://x.test/"}"#, &policy, &opts).unwrap_err();
+        run("not json", &policy, &opts).unwrap_err();
+        let events: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["kind"], "verdict");
+        assert_eq!(events[0]["outcome"], "green");
+        assert_eq!(events[1]["kind"], "decision");
+        assert_eq!(events[1]["outcome"], "denied");
+        assert!(events[1]["detail"]
+            .as_str()
+            .unwrap()
+            .starts_with("policy: "));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::messages::{Code, Lang, Message};
+use crate::schema::Finding;
+use serde::Serialize;
+use thiserror::Error;
+
+/// Errors that stop a spell before a result can be produced.
+///
+/// Each variant maps to the CLI exit code documented in SPEC.md; see
+/// [`MagicruneError::exit_code`]. Each carries a coded message from
+/// [`crate::messages`], displayed as `[MR2001] policy: env deny AWS_KEY`.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum MagicruneError {
+    /// The request is not valid JSON or fails schema validation.
+    #[error("{0}")]
+    InvalidRequest(Message),
+    /// The produced result does not match the result schema.
+    #[error("{0}")]
+    InvalidOutput(Message),
+    /// The request asks for something the policy does not allow.
+    #[error("{0}")]
+    PolicyViolation(Message),
+    /// A file in the request targets a read-only path; graded red unrun.
+    #[error("{0}")]
+    ReadonlyWrite(Message),
+    /// The policy requires signed requests and this one is unsigned or its
+    /// signature does not check out against the producer keys.
+    #[error("{0}")]
+    Unauthenticated(Message),
+    /// Sandbox or I/O failure.
+    #[error("{0}")]
+    Internal(Message),
+}
+
+impl MagicruneError {
+    /// The error for `code` with `args`; the code picks the variant.
+    pub fn new(code: Code, args: &[&dyn std::fmt::Display]) -> Self {
+        Message::new(code, args).into()
+    }
+
+    /// An internal failure with no code of its own.
+    pub fn internal(e: impl std::fmt::Display) -> Self {
+        Self::new(Code::Internal, &[&e])
+    }
+
+    pub fn message(&self) -> &Message {
+        match self {
+            MagicruneError::InvalidRequest(m)
+            | MagicruneError::InvalidOutput(m)
+            | MagicruneError::PolicyViolation(m)
+            | MagicruneError::ReadonlyWrite(m)
+            | MagicruneError::Unauthenticated(m)
+            | MagicruneError::Internal(m) => m,
+        }
+    }
+
+    fn message_mut(&mut self) -> &mut Message {
+        match self {
+            MagicruneError::InvalidRequest(m)
+            | MagicruneError::InvalidOutput(m)
+            | MagicruneError::PolicyViolation(m)
+            | MagicruneError::ReadonlyWrite(m)
+            | MagicruneError::Unauthenticated(m)
+            | MagicruneError::Internal(m) => m,
+        }
+    }
+
+    /// See [`Message::at`].
+    pub fn at(mut self, pointer: impl Into<String>) -> Self {
+        self.message_mut().pointer = pointer.into();
+        self
+    }
+
+    /// See [`Message::rule`].
+    pub fn rule(mut self, rule: impl Into<String>) -> Self {
+        self.message_mut().rule = rule.into();
+        self
+    }
+
+    pub fn code(&self) -> Code {
+        self.message().code
+    }
+
+    /// The error as a finding of the result a rejected request is answered
+    /// with.
+    pub fn finding(&self) -> Finding {
+        self.message().finding()
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MagicruneError::InvalidRequest(_) => 1,
+            MagicruneError::InvalidOutput(_) => 2,
+            MagicruneError::PolicyViolation(_) => 3,
+            MagicruneError::ReadonlyWrite(_) => 20,
+            MagicruneError::Internal(_) => 4,
+            MagicruneError::Unauthenticated(_) => 5,
+        }
+    }
+}
+
+/// The error as `exec --errors json` prints it on stderr.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ErrorReport {
+    pub code: &'static str,
+    pub message: String,
+    pub exit_code: i32,
+    /// JSON Pointer to the request field at fault.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub pointer: String,
+    /// The policy rule that decided it.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub rule: String,
+    pub remediation: &'static str,
+}
+
+impl MagicruneError {
+    pub fn report(&self) -> ErrorReport {
+        let m = self.message();
+        ErrorReport {
+            code: m.code.as_str(),
+            message: m.text.clone(),
+            exit_code: self.exit_code(),
+            pointer: m.pointer.clone(),
+            rule: m.rule.clone(),
+            remediation: m.code.remediation(Lang::current()),
+        }
+    }
+}
+
+impl From<Message> for MagicruneError {
+    fn from(m: Message) -> Self {
+        match (m.code, m.code.as_str().as_bytes()[2]) {
+            (Code::ReadonlyWrite, _) => MagicruneError::ReadonlyWrite(m),
+            (_, b'1') => MagicruneError::InvalidRequest(m),
+            (_, b'2') => MagicruneError::PolicyViolation(m),
+            (_, b'3') => MagicruneError::InvalidOutput(m),
+            (_, b'5') => MagicruneError::Unauthenticated(m),
+            _ => MagicruneError::Internal(m),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::Lang;
+
+    fn err(code: Code) -> MagicruneError {
+        Message::in_lang(code, Lang::En, &[&"x"]).into()
+    }
+
+    #[test]
+    fn test_exit_codes_and_messages() {
+        let e: MagicruneError = Message::in_lang(Code::EnvDenied, Lang::En, &[&"AWS_KEY"]).into();
+        assert_eq!(e.exit_code(), 3);
+        assert_eq!(e.to_string(), "[MR2001] policy: env deny AWS_KEY");
+        assert_eq!(e.finding().kind, "MR2001");
+        assert_eq!(err(Code::Schema).exit_code(), 1);
+        assert_eq!(err(Code::OutputSchema).exit_code(), 2);
+        assert_eq!(err(Code::ReadonlyWrite).exit_code(), 20);
+        assert_eq!(err(Code::SpawnFailed).exit_code(), 4);
+        assert_eq!(err(Code::Unauthenticated).exit_code(), 5);
+        assert_eq!(MagicruneError::internal("boom").code(), Code::Internal);
+    }
+
+    #[test]
+    fn test_report() {
+        let e = err(Code::EnvDenied)
+            .at("/env/AWS_KEY")
+            .rule("capabilities.env.deny: AWS_*");
+        assert_eq!(
+            serde_json::to_value(e.report()).unwrap(),
+            serde_json::json!({
+                "code": "MR2001",
+                "message": "policy: env deny x",
+                "exit_code": 3,
+                "pointer": "/env/AWS_KEY",
+                "rule": "capabilities.env.deny: AWS_*",
+                "remediation": "drop the variable from env; the policy denies it",
+            })
+        );
+        let r = serde_json::to_value(MagicruneError::internal("boom").report()).unwrap();
+        assert!(r.get("pointer").is_none() && r.get("rule").is_none());
+    }
+}
@@ -0,0 +1,224 @@
+//! Fault injection for chaos tests.
+//!
+//! A [`FaultPlan`] lists the faults a consumer injects:
+//! - a delay, plus an optional random jitter, before a result is published;
+//! - leaving each run's message unacknowledged once, so that JetStream
+//!   redelivers it;
+//! - failing the n-th result publish, as if the connection had dropped it.
+//!
+//! The plan is part of the consumer's settings (`--test-*` flags or
+//! `MAGICRUNE_TEST_*` variables, see [`SETTINGS`]) and is carried out by
+//! [`Faults`]. Injection is only compiled with the `testing` feature.
+//! Without it, the [`Faults`] hooks do nothing, and a plan that is not
+//! empty is a settings error, so a production consumer cannot be told to
+//! misbehave.
+
+use serde::Serialize;
+
+/// Flag and environment variable of each fault. `--test-skip-ack-once` is
+/// a switch; its variable must be `1`.
+pub const SETTINGS: &[(&str, &str)] = &[
+    ("--test-delay-ms", "MAGICRUNE_TEST_DELAY_MS"),
+    ("--test-delay-ms-jitter", "MAGICRUNE_TEST_DELAY_MS_JITTER"),
+    ("--test-skip-ack-once", "MAGICRUNE_TEST_SKIP_ACK_ONCE"),
+    ("--test-fail-publish-nth", "MAGICRUNE_TEST_FAIL_PUBLISH_NTH"),
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FaultPlan {
+    /// Delay before a result is published.
+    pub delay_ms: u64,
+    /// Random extra delay in `lo..=hi` ms.
+    pub delay_jitter_ms: Option<(u64, u64)>,
+    /// Leave each run's message unacknowledged the first time.
+    pub drop_ack_once: bool,
+    /// Drop the n-th result publish (1-based).
+    pub fail_publish_nth: Option<u64>,
+}
+
+impl FaultPlan {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The plan from `args`, else `env`. A value that does not parse is an
+    /// error, and so is any fault without the `testing` feature.
+    pub fn resolve(args: &[String], env: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let get = |flag: &str| -> Result<Option<String>, String> {
+            let (_, key) = SETTINGS.iter().find(|s| s.0 == flag).unwrap();
+            let from_flag = args
+                .iter()
+                .position(|a| a == flag)
+                .map(|i| {
+                    args.get(i + 1)
+                        .cloned()
+                        .ok_or(format!("{} needs a value", flag))
+                })
+                .transpose()?;
+            Ok(from_flag
+                .or_else(|| env(key))
+                .filter(|v| !v.trim().is_empty()))
+        };
+        let invalid = |flag: &str, v: &str| format!("{}: invalid value {:?}", flag, v);
+        let num = |flag: &str| -> Result<Option<u64>, String> {
+            get(flag)?
+                .map(|v| v.trim().parse().map_err(|_| invalid(flag, &v)))
+                .transpose()
+        };
+        let plan = Self {
+            delay_ms: num("--test-delay-ms")?.unwrap_or(0),
+            delay_jitter_ms: get("--test-delay-ms-jitter")?
+                .map(|v| parse_jitter(&v).ok_or(invalid("--test-delay-ms-jitter", &v)))
+                .transpose()?,
+            drop_ack_once: args.iter().any(|a| a == "--test-skip-ack-once")
+                || env("MAGICRUNE_TEST_SKIP_ACK_ONCE").as_deref() == Some("1"),
+            fail_publish_nth: match num("--test-fail-publish-nth")? {
+                Some(0) => return Err(invalid("--test-fail-publish-nth", "0")),
+                n => n,
+            },
+        };
+        if !cfg!(feature = "testing") && !plan.is_empty() {
+            return Err("fault injection (--test-*) needs the testing feature".into());
+        }
+        Ok(plan)
+    }
+}
+
+/// A `lo..=hi` (or `lo..hi`) range of milliseconds, `lo <= hi`.
+fn parse_jitter(spec: &str) -> Option<(u64, u64)> {
+    let spec = spec.trim();
+    let (a, b) = spec.split_once("..=").or_else(|| spec.split_once(".."))?;
+    let (lo, hi) = (a.trim().parse::<u64>().ok()?, b.trim().parse::<u64>().ok()?);
+    (lo <= hi).then_some((lo, hi))
+}
+
+/// A [`FaultPlan`] being carried out by one consumer.
+#[derive(Debug, Default)]
+pub struct Faults {
+    #[cfg(feature = "testing")]
+    plan: FaultPlan,
+    #[cfg(feature = "testing")]
+    acks_dropped: std::sync::Mutex<std::collections::HashSet<String>>,
+    #[cfg(feature = "testing")]
+    publishes: std::sync::atomic::AtomicU64,
+}
+
+impl Faults {
+    pub fn new(plan: FaultPlan) -> Self {
+        #[cfg(feature = "testing")]
+        return Self {
+            plan,
+            ..Default::default()
+        };
+        #[cfg(not(feature = "testing"))]
+        {
+            debug_assert!(plan.is_empty());
+            Self::default()
+        }
+    }
+
+    /// Wait out the planned delay and jitter before a result is published.
+    pub async fn delay(&self) {
+        #[cfg(feature = "testing")]
+        {
+            let mut ms = self.plan.delay_ms;
+            if let Some((lo, hi)) = self.plan.delay_jitter_ms {
+                let mut b = [0u8; 8];
+                let r = getrandom::getrandom(&mut b).map_or(0, |_| u64::from_le_bytes(b));
+                ms += lo + r % (hi - lo + 1);
+            }
+            if ms > 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(ms)).await;
+            }
+        }
+    }
+
+    /// Whether to leave the message of `run_id` unacknowledged: the first
+    /// time only, when the plan says so.
+    pub fn drop_ack(&self, run_id: &str) -> bool {
+        #[cfg(feature = "testing")]
+        if self.plan.drop_ack_once {
+            let mut dropped = self.acks_dropped.lock().unwrap();
+            if dropped.insert(run_id.to_string()) {
+                tracing::warn!(run_id = %run_id, "fault: ack dropped");
+                return true;
+            }
+        }
+        let _ = run_id;
+        false
+    }
+
+    /// Whether the result publish about to happen, for `run_id`, is to fail.
+    pub fn fail_publish(&self, run_id: &str) -> bool {
+        #[cfg(feature = "testing")]
+        {
+            use std::sync::atomic::Ordering;
+            let n = self.publishes.fetch_add(1, Ordering::SeqCst) + 1;
+            if self.plan.fail_publish_nth == Some(n) {
+                tracing::warn!(run_id = %run_id, publish = n, "fault: publish failed");
+                return true;
+            }
+        }
+        let _ = run_id;
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(args: &[&str], env: &[(&str, &str)]) -> Result<FaultPlan, String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        FaultPlan::resolve(&args, |key| {
+            env.iter().find(|e| e.0 == key).map(|e| e.1.to_string())
+        })
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert!(resolve(&["consume"], &[]).unwrap().is_empty());
+        assert_eq!(
+            resolve(&["consume", "--test-delay-ms-jitter", "9..1"], &[]).unwrap_err(),
+            "--test-delay-ms-jitter: invalid value \"9..1\""
+        );
+        assert!(resolve(&["consume", "--test-fail-publish-nth", "0"], &[]).is_err());
+        let planned = resolve(
+            &["consume", "--test-delay-ms-jitter", "200..=800"],
+            &[
+                ("MAGICRUNE_TEST_DELAY_MS", "50"),
+                ("MAGICRUNE_TEST_SKIP_ACK_ONCE", "1"),
+                ("MAGICRUNE_TEST_FAIL_PUBLISH_NTH", "2"),
+            ],
+        );
+        if cfg!(feature = "testing") {
+            let plan = planned.unwrap();
+            assert_eq!(plan.delay_ms, 50);
+            assert_eq!(plan.delay_jitter_ms, Some((200, 800)));
+            assert!(plan.drop_ack_once);
+            assert_eq!(plan.fail_publish_nth, Some(2));
+        } else {
+            assert!(planned.unwrap_err().contains("testing feature"));
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn test_faults() {
+        let faults = Faults::new(FaultPlan {
+            delay_ms: 10,
+            drop_ack_once: true,
+            fail_publish_nth: Some(2),
+            ..Default::default()
+        });
+        let started = std::time::Instant::now();
+        faults.delay().await;
+        assert!(started.elapsed() >= std::time::Duration::from_millis(10));
+        assert!(faults.drop_ack("r_1"));
+        assert!(!faults.drop_ack("r_1"));
+        assert!(faults.drop_ack("r_2"));
+        assert!(!faults.fail_publish("r_1"));
+        assert!(faults.fail_publish("r_2"));
+        assert!(!faults.fail_publish("r_3"));
+    }
+}
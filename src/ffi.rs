@@ -0,0 +1,176 @@
+//! C ABI for embedding the engine in non-Rust hosts (feature `ffi`).
+//!
+//! Build the shared library with
+//! `cargo rustc --release --lib --features ffi --crate-type cdylib`;
+//! the matching declarations are in `include/magicrune.h`.
+//!
+//! Every string crossing the boundary is NUL-terminated UTF-8. Strings
+//! returned by this module are owned by the caller and must be released with
+//! [`magicrune_free`].
+
+use crate::engine::{execute, ExecOptions};
+use crate::policy::Policy;
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::sync::OnceLock;
+
+/// Success.
+pub const MAGICRUNE_OK: i32 = 0;
+/// A required pointer argument was NULL.
+pub const MAGICRUNE_E_NULL_ARG: i32 = -1;
+/// An argument was not valid UTF-8.
+pub const MAGICRUNE_E_UTF8: i32 = -2;
+/// The engine panicked; the call had no effect on the host.
+pub const MAGICRUNE_E_PANIC: i32 = -3;
+// Positive codes are MagicruneError::exit_code() values (1, 2, 3, 4, 5, 20).
+
+thread_local! {
+    static LAST_ERROR: RefCell<(i32, String)> = const { RefCell::new((MAGICRUNE_OK, String::new())) };
+}
+
+fn set_last_error(code: i32, msg: impl Into<String>) {
+    LAST_ERROR.with(|e| *e.borrow_mut() = (code, msg.into()));
+}
+
+fn runtime() -> &'static tokio::runtime::Runtime {
+    static RT: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    RT.get_or_init(|| tokio::runtime::Runtime::new().expect("tokio runtime"))
+}
+
+fn into_c_string(s: String) -> *mut c_char {
+    // Interior NULs cannot occur in serde_json output or our messages.
+    CString::new(s)
+        .map(CString::into_raw)
+        .unwrap_or(std::ptr::null_mut())
+}
+
+unsafe fn arg<'a>(p: *const c_char) -> Result<&'a str, i32> {
+    if p.is_null() {
+        set_last_error(MAGICRUNE_E_NULL_ARG, "argument is NULL");
+        return Err(MAGICRUNE_E_NULL_ARG);
+    }
+    CStr::from_ptr(p).to_str().map_err(|e| {
+        set_last_error(MAGICRUNE_E_UTF8, e.to_string());
+        MAGICRUNE_E_UTF8
+    })
+}
+
+/// Run a spell request (JSON) under a policy (YAML) and return the result JSON.
+///
+/// Returns NULL on failure; [`magicrune_last_error_code`] and
+/// [`magicrune_last_error_message`] then describe the error on this thread.
+///
+/// # Safety
+/// `request_json` and `policy_yaml` must be NULL or valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn magicrune_exec(
+    request_json: *const c_char,
+    policy_yaml: *const c_char,
+) -> *mut c_char {
+    let (req, pol) = match (arg(request_json), arg(policy_yaml)) {
+        (Ok(r), Ok(p)) => (r, p),
+        _ => return std::ptr::null_mut(),
+    };
+    let run = std::panic::catch_unwind(|| {
+        let policy = Policy::from_yaml(pol)?;
+        runtime().block_on(execute(req.as_bytes(), &policy, &ExecOptions::default()))
+    });
+    match run {
+        Ok(Ok(out)) => match serde_json::to_string(&out.result) {
+            Ok(json) => {
+                set_last_error(MAGICRUNE_OK, "");
+                into_c_string(json)
+            }
+            Err(e) => {
+                set_last_error(4, e.to_string());
+                std::ptr::null_mut()
+            }
+        },
+        Ok(Err(e)) => {
+            set_last_error(e.exit_code(), e.to_string());
+            std::ptr::null_mut()
+        }
+        Err(_) => {
+            set_last_error(MAGICRUNE_E_PANIC, "engine panicked");
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Error code of the last call on this thread (`MAGICRUNE_OK` after success).
+#[no_mangle]
+pub extern "C" fn magicrune_last_error_code() -> i32 {
+    LAST_ERROR.with(|e| e.borrow().0)
+}
+
+/// Message of the last error on this thread, or NULL if the last call succeeded.
+/// Free the returned string with [`magicrune_free`].
+#[no_mangle]
+pub extern "C" fn magicrune_last_error_message() -> *mut c_char {
+    LAST_ERROR.with(|e| {
+        let e = e.borrow();
+        if e.0 == MAGICRUNE_OK {
+            std::ptr::null_mut()
+        } else {
+            into_c_string(e.1.clone())
+        }
+    })
+}
+
+/// Release a string returned by this library. NULL is ignored.
+///
+/// # Safety
+/// `s` must be NULL or a pointer previously returned by this library that has
+/// not been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn magicrune_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exec(req: &str, policy: &str) -> Option<String> {
+        let req = CString::new(req).unwrap();
+        let policy = CString::new(policy).unwrap();
+        let out = unsafe { magicrune_exec(req.as_ptr(), policy.as_ptr()) };
+        if out.is_null() {
+            return None;
+        }
+        let s = unsafe { CStr::from_ptr(out) }.to_str().unwrap().to_string();
+        unsafe { magicrune_free(out) };
+        Some(s)
+    }
+
+    #[test]
+    fn test_exec_returns_result_json() {
+        let out = exec(r#"{"cmd":""}"#, "version: 1\n").expect("result");
+        let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(v["verdict"], "green");
+        assert_eq!(magicrune_last_error_code(), MAGICRUNE_OK);
+        assert!(magicrune_last_error_message().is_null());
+    }
+
+    #[test]
+    fn test_exec_reports_policy_error() {
+        assert!(exec(r#"{"cmd":"curl http://x.test/"}"#, "version: 1\n").is_none());
+        assert_eq!(magicrune_last_error_code(), 3);
+        let msg = magicrune_last_error_message();
+        let text = unsafe { CStr::from_ptr(msg) }.to_str().unwrap().to_string();
+        unsafe { magicrune_free(msg) };
+        assert_eq!(
+            text,
+            "[MR2004] policy: network is not allowed (no allowlist)"
+        );
+    }
+
+    #[test]
+    fn test_null_argument() {
+        let out = unsafe { magicrune_exec(std::ptr::null(), std::ptr::null()) };
+        assert!(out.is_null());
+        assert_eq!(magicrune_last_error_code(), MAGICRUNE_E_NULL_ARG);
+    }
+}
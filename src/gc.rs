@@ -0,0 +1,253 @@
+//! Cleanup of sandbox artifacts that crashed runs leave behind.
+//!
+//! The overlay scratch dir (`/tmp/mr_ovl_<pid>`) and the cgroup
+//! (`<MAGICRUNE_CGROUP_PARENT>/magicrune_<pid>`) are named after the process
+//! that made them. An artifact is stale once that pid is gone and it has not
+//! been touched for [`GcConfig::min_age`]. [`sweep`] removes stale
+//! artifacts. Long-running commands (`worker`, `consume`) sweep at startup
+//! and then every `MAGICRUNE_GC_INTERVAL_SEC`; `magicrune gc` sweeps once.
+//! With a ledger retention set, those background sweeps prune the ledger
+//! too (see `crate::ledger`).
+
+use crate::ledger::{LedgerConfig, Retention};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Prefix of overlay scratch dirs under [`GcConfig::scratch_dir`].
+pub const SCRATCH_PREFIX: &str = "mr_ovl_";
+/// Prefix of per-run cgroups under [`GcConfig::cgroup_parent`].
+pub const CGROUP_PREFIX: &str = "magicrune_";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcConfig {
+    pub scratch_dir: PathBuf,
+    pub cgroup_parent: PathBuf,
+    /// Artifacts modified more recently than this are left alone.
+    pub min_age: Duration,
+    /// Time between background sweeps; zero disables them.
+    pub interval: Duration,
+    /// Ledger pruned by the background sweeps, and how.
+    pub ledger: Option<(LedgerConfig, Retention)>,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            scratch_dir: PathBuf::from("/tmp"),
+            cgroup_parent: PathBuf::from("/sys/fs/cgroup"),
+            min_age: Duration::from_secs(10 * 60),
+            interval: Duration::from_secs(10 * 60),
+            ledger: None,
+        }
+    }
+}
+
+impl GcConfig {
+    /// Defaults overridden by `MAGICRUNE_CGROUP_PARENT`,
+    /// `MAGICRUNE_GC_MIN_AGE_MIN` and `MAGICRUNE_GC_INTERVAL_SEC`, and the
+    /// ledger ([`LedgerConfig::from_env`]) when `MAGICRUNE_LEDGER_RETENTION`
+    /// is set.
+    pub fn from_env() -> Self {
+        let num = |key: &str| {
+            std::env::var(key)
+                .ok()
+                .and_then(|s| s.trim().parse::<u64>().ok())
+        };
+        let mut cfg = Self::default();
+        if let Ok(p) = std::env::var("MAGICRUNE_CGROUP_PARENT") {
+            cfg.cgroup_parent = PathBuf::from(p);
+        }
+        if let Some(m) = num("MAGICRUNE_GC_MIN_AGE_MIN") {
+            cfg.min_age = Duration::from_secs(m * 60);
+        }
+        if let Some(s) = num("MAGICRUNE_GC_INTERVAL_SEC") {
+            cfg.interval = Duration::from_secs(s);
+        }
+        let ledger = Retention::from_env()
+            .and_then(|r| r.map(|r| Ok((LedgerConfig::from_env()?, r))).transpose());
+        match ledger {
+            Ok(l) => cfg.ledger = l,
+            Err(e) => tracing::warn!(error = %e, "gc: ledger not pruned"),
+        }
+        cfg
+    }
+}
+
+/// What a sweep found.
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub removed: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// Stale scratch dirs and cgroups, scratch dirs first.
+pub fn find_stale(cfg: &GcConfig) -> Vec<PathBuf> {
+    let mut out = stale_in(&cfg.scratch_dir, SCRATCH_PREFIX, cfg.min_age);
+    out.extend(stale_in(&cfg.cgroup_parent, CGROUP_PREFIX, cfg.min_age));
+    out
+}
+
+/// Remove everything [`find_stale`] reports. A cgroup is removed with
+/// `rmdir`, which the kernel refuses while it still holds processes.
+pub fn sweep(cfg: &GcConfig) -> GcReport {
+    let mut report = GcReport::default();
+    for path in find_stale(cfg) {
+        let res = if path.starts_with(&cfg.cgroup_parent) {
+            std::fs::remove_dir(&path)
+        } else {
+            std::fs::remove_dir_all(&path)
+        };
+        match res {
+            Ok(()) => report.removed.push(path),
+            Err(e) => report.failed.push((path, e.to_string())),
+        }
+    }
+    report
+}
+
+/// Sweep now, then every [`GcConfig::interval`] on a background thread.
+/// Removals and failures are logged to stderr.
+pub fn start(cfg: GcConfig) {
+    log(&sweep(&cfg));
+    prune_ledger(&cfg);
+    if cfg.interval.is_zero() {
+        return;
+    }
+    let spawned = std::thread::Builder::new()
+        .name("magicrune-gc".into())
+        .spawn(move || loop {
+            std::thread::sleep(cfg.interval);
+            log(&sweep(&cfg));
+            prune_ledger(&cfg);
+        });
+    if let Err(e) = spawned {
+        tracing::warn!(error = %e, "gc: background sweep not started");
+    }
+}
+
+fn prune_ledger(cfg: &GcConfig) {
+    let Some((ledger, retention)) = &cfg.ledger else {
+        return;
+    };
+    let now_ms = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let path = &ledger.path;
+    let pruned = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .and_then(|rt| rt.block_on(ledger.open().prune(retention, now_ms, false)));
+    match pruned {
+        Ok(r) if r.expired + r.superseded > 0 => tracing::info!(
+            path = %path.display(),
+            expired = r.expired,
+            superseded = r.superseded,
+            kept = r.kept,
+            "gc: ledger pruned"
+        ),
+        Ok(_) => {}
+        Err(e) => tracing::warn!(path = %path.display(), error = %e, "gc: ledger not pruned"),
+    }
+}
+
+fn log(report: &GcReport) {
+    for p in &report.removed {
+        tracing::info!(path = %p.display(), "gc: removed");
+    }
+    for (p, e) in &report.failed {
+        tracing::warn!(path = %p.display(), error = %e, "gc: could not remove");
+    }
+}
+
+fn stale_in(dir: &Path, prefix: &str, min_age: Duration) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let now = SystemTime::now();
+    let mut out: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| {
+            let name = e.file_name();
+            let Some(pid) = name
+                .to_str()
+                .and_then(|n| n.strip_prefix(prefix))
+                .and_then(|p| p.parse::<u32>().ok())
+            else {
+                return false;
+            };
+            // symlink_metadata: never follow a link planted in /tmp.
+            let Ok(meta) = e.path().symlink_metadata() else {
+                return false;
+            };
+            let age = meta
+                .modified()
+                .ok()
+                .and_then(|m| now.duration_since(m).ok())
+                .unwrap_or_default();
+            meta.is_dir() && age >= min_age && !pid_alive(pid)
+        })
+        .map(|e| e.path())
+        .collect();
+    out.sort();
+    out
+}
+
+#[cfg(target_os = "linux")]
+fn pid_alive(pid: u32) -> bool {
+    pid == std::process::id() || Path::new("/proc").join(pid.to_string()).exists()
+}
+
+// Without /proc liveness is unknown; keep everything.
+#[cfg(not(target_os = "linux"))]
+fn pid_alive(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_sweep_removes_only_stale() {
+        let root = std::env::temp_dir().join(format!("magicrune_gc_test_{}", std::process::id()));
+        let scratch = root.join("tmp");
+        let cgroups = root.join("cg");
+        // pid_max is at most 2^22, so this pid is never alive.
+        let dead = 4_194_304 + 1;
+        let me = std::process::id();
+        for d in [
+            scratch.join(format!("mr_ovl_{dead}/upper")),
+            scratch.join(format!("mr_ovl_{me}")),
+            scratch.join("mr_ovl_notapid"),
+            cgroups.join(format!("magicrune_{dead}")),
+            cgroups.join("other"),
+        ] {
+            std::fs::create_dir_all(d).unwrap();
+        }
+        let mut cfg = GcConfig {
+            scratch_dir: scratch.clone(),
+            cgroup_parent: cgroups.clone(),
+            min_age: Duration::from_secs(3600),
+            interval: Duration::ZERO,
+            ledger: None,
+        };
+        // Too young.
+        assert!(find_stale(&cfg).is_empty());
+
+        cfg.min_age = Duration::ZERO;
+        let report = sweep(&cfg);
+        assert_eq!(
+            report.removed,
+            vec![
+                scratch.join(format!("mr_ovl_{dead}")),
+                cgroups.join(format!("magicrune_{dead}")),
+            ]
+        );
+        assert!(report.failed.is_empty());
+        assert!(scratch.join(format!("mr_ovl_{me}")).exists());
+        assert!(scratch.join("mr_ovl_notapid").exists());
+        assert!(cgroups.join("other").exists());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,4 +1,151 @@
-use crate::schema::{PolicyDoc, SpellRequest};
+//! Risk scoring.
+//!
+//! Each grading rule that fires adds its weight to a raw score. The raw sum
+//! is then capped to the documented 0–100 scale, so thresholds and
+//! dashboards never see more than [`MAX_SCORE`]; the raw sum and the rules
+//! behind it are kept in the result's `risk_breakdown`.
+
+use crate::schema::{GradingThresholds, PolicyDoc, RiskBreakdown, SpellRequest, Verdict};
+
+#[cfg(not(feature = "std"))]
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+#[cfg(feature = "std")]
+use std::collections::BTreeMap;
+
+/// Top of the risk scale: every score is in `0..=MAX_SCORE`.
+pub const MAX_SCORE: u32 = 100;
+
+/// Points each grading rule adds to the raw score. Exfiltration is weighted
+/// by the policy (`grading.exfiltration.score`).
+pub mod weights {
+    /// Network tools or URLs in the command, with no network allowlist.
+    pub const NET_INTENT: u32 = 40;
+    /// `ssh` in the command.
+    pub const SSH: u32 = 30;
+    /// The command refers to a device node outside the standard set.
+    pub const DEVICE: u32 = 20;
+    /// Network syscalls observed at runtime, with no network allowlist.
+    pub const NET_RUNTIME: u32 = 20;
+    /// [`super::grade`]: the request opens the network.
+    pub const ALLOW_NET: u32 = 40;
+    /// [`super::grade`]: the request allows paths other than `/tmp/**`.
+    pub const BROAD_FS: u32 = 20;
+    /// A capability used outside the policy's change window
+    /// (`crate::schedule`); the request grades red whatever else it scores.
+    pub const OUTSIDE_WINDOW: u32 = 100;
+    /// A program the command runs is not in `capabilities.exec.digests`;
+    /// the request grades red whatever else it scores.
+    pub const UNAPPROVED_BINARY: u32 = 100;
+    /// Lookups the stub resolver refused (`capabilities.net.dns.allow`).
+    pub const DNS_DENIED: u32 = 20;
+    /// A host in the command the policy lets it connect to but not look
+    /// up; the command will most likely fail to reach it.
+    pub const DNS_MISMATCH: u32 = 10;
+}
+
+/// A raw score on the 0–100 scale: anything above [`MAX_SCORE`] is capped.
+pub fn normalize(raw: u32) -> u32 {
+    raw.min(MAX_SCORE)
+}
+
+/// The rules that fired for a run, with the points each added.
+///
+/// Points of a rule the request accepted the risk of (see
+/// [`RiskTally::accept`]) are kept apart: they are reported as waived and
+/// count towards neither the score nor the breakdown. A mandatory rule (see
+/// [`RiskTally::require`]) is never waived, and grades red when it fires.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RiskTally {
+    rules: BTreeMap<&'static str, u32>,
+    accepted: Vec<String>,
+    waived: BTreeMap<&'static str, u32>,
+    mandatory: Vec<String>,
+}
+
+impl RiskTally {
+    pub fn add(&mut self, rule: &'static str, points: u32) {
+        let rules = if self.accepted(rule) {
+            &mut self.waived
+        } else {
+            &mut self.rules
+        };
+        let p = rules.entry(rule).or_default();
+        *p = p.saturating_add(points);
+    }
+
+    /// Waive `rule`: from now on its points go to [`RiskTally::waived`].
+    /// False, and nothing is waived, when `rule` is mandatory.
+    pub fn accept(&mut self, rule: &str) -> bool {
+        if self.mandatory.iter().any(|r| r == rule) {
+            return false;
+        }
+        if !self.accepted(rule) {
+            self.accepted.push(rule.to_string());
+        }
+        true
+    }
+
+    /// Whether `rule` is waived.
+    pub fn accepted(&self, rule: &str) -> bool {
+        self.accepted.iter().any(|r| r == rule)
+    }
+
+    /// Make `rule` mandatory: it cannot be waived, and once it fires the
+    /// verdict is red whatever the score.
+    pub fn require(&mut self, rule: &str) {
+        self.accepted.retain(|r| r != rule);
+        if !self.mandatory.iter().any(|r| r == rule) {
+            self.mandatory.push(rule.to_string());
+        }
+    }
+
+    /// The first mandatory rule that fired, if any.
+    pub fn mandatory_fired(&self) -> Option<&'static str> {
+        self.rules
+            .keys()
+            .copied()
+            .find(|k| self.mandatory.iter().any(|r| r == k))
+    }
+
+    /// The verdict for [`RiskTally::score`]: red when a mandatory rule fired.
+    pub fn verdict(&self, thresholds: &GradingThresholds) -> Verdict {
+        match self.mandatory_fired() {
+            Some(_) => Verdict::Red,
+            None => thresholds.verdict_for(self.score()),
+        }
+    }
+
+    /// Points the waived rules would have added; 0 for a rule that was
+    /// accepted but did not fire.
+    pub fn waived(&self, rule: &str) -> u32 {
+        self.waived.get(rule).copied().unwrap_or_default()
+    }
+
+    /// Sum of the weights, before normalization.
+    pub fn raw(&self) -> u32 {
+        self.rules.values().fold(0, |sum, p| sum.saturating_add(*p))
+    }
+
+    /// The score on the 0–100 scale.
+    pub fn score(&self) -> u32 {
+        normalize(self.raw())
+    }
+
+    pub fn breakdown(&self) -> RiskBreakdown {
+        RiskBreakdown {
+            raw: self.raw(),
+            rules: self
+                .rules
+                .iter()
+                .map(|(k, v)| (k.to_string(), *v))
+                .collect(),
+        }
+    }
+}
 
 pub struct GradeOutcome {
     pub risk_score: u32,
@@ -6,44 +153,30 @@ pub struct GradeOutcome {
 }
 
 pub fn grade(req: &SpellRequest, policy: &PolicyDoc) -> GradeOutcome {
-    let mut risk: i32 = 0;
+    let mut tally = RiskTally::default();
     // Simple static scoring
-    if let Some(nets) = &req.allow_net {
-        if !nets.is_empty() {
-            risk += 40; // opening network
-        }
+    if req.allow_net.as_ref().is_some_and(|n| !n.is_empty()) {
+        tally.add("allow_net", weights::ALLOW_NET);
     }
-    if let Some(fs) = &req.allow_fs {
-        for p in fs {
-            if p != "/tmp/**" {
-                risk += 20; // broader FS allow
-                break;
-            }
-        }
+    if req
+        .allow_fs
+        .as_ref()
+        .is_some_and(|fs| fs.iter().any(|p| p != "/tmp/**"))
+    {
+        tally.add("broad_fs", weights::BROAD_FS);
     }
 
     // thresholds from policy or defaults
-    let _thresholds = policy
+    let thresholds = policy
         .grading
         .as_ref()
-        .map(|g| g.thresholds.clone())
-        .unwrap_or_else(|| crate::schema::GradingThresholds {
-            green: "<=20".to_string(),
-            yellow: "21..=60".to_string(),
-            red: ">=61".to_string(),
-        });
-
-    let verdict = if risk <= 20 {
-        "green"
-    } else if risk <= 60 {
-        "yellow"
-    } else {
-        "red"
-    };
+        .map(|g| g.thresholds)
+        .unwrap_or_default();
+    let risk_score = tally.score();
 
     GradeOutcome {
-        risk_score: risk.max(0) as u32,
-        verdict: verdict.to_string(),
+        risk_score,
+        verdict: thresholds.verdict_for(risk_score).to_string(),
     }
 }
 
@@ -133,17 +266,61 @@ mod tests {
         let policy = PolicyDoc {
             version: 1,
             grading: Some(GradingCfg {
-                thresholds: GradingThresholds {
-                    green: "<=10".to_string(),
-                    yellow: "11..=50".to_string(),
-                    red: ">=51".to_string(),
-                },
+                thresholds: GradingThresholds::new(10, 30).unwrap(),
             }),
         };
 
         let outcome = grade(&req, &policy);
         assert_eq!(outcome.risk_score, 40);
-        assert_eq!(outcome.verdict, "yellow");
+        assert_eq!(outcome.verdict, "red");
+    }
+
+    #[test]
+    fn test_tally_caps_at_max_score() {
+        let mut tally = RiskTally::default();
+        tally.add("ssh", weights::SSH);
+        tally.add("net_intent", weights::NET_INTENT);
+        tally.add("exfiltration", 50);
+        tally.add("exfiltration", 10);
+        assert_eq!(tally.raw(), 130);
+        assert_eq!(tally.score(), MAX_SCORE);
+        let b = tally.breakdown();
+        assert_eq!(b.raw, 130);
+        assert_eq!(b.rules["exfiltration"], 60);
+        assert_eq!(RiskTally::default().score(), 0);
+        assert_eq!(normalize(u32::MAX), MAX_SCORE);
+    }
+
+    #[test]
+    fn test_tally_waives_accepted_rules() {
+        let mut tally = RiskTally::default();
+        tally.accept("ssh");
+        tally.add("ssh", weights::SSH);
+        tally.add("device", weights::DEVICE);
+        assert_eq!(tally.score(), weights::DEVICE);
+        assert_eq!(tally.waived("ssh"), weights::SSH);
+        assert_eq!(tally.waived("device"), 0);
+        assert!(!tally.breakdown().rules.contains_key("ssh"));
+    }
+
+    #[test]
+    fn test_mandatory_rules_cannot_be_waived() {
+        let thresholds = GradingThresholds::default();
+        let mut tally = RiskTally::default();
+        assert!(tally.accept("ssh"));
+        tally.require("ssh");
+        assert!(!tally.accept("ssh"));
+        tally.add("ssh", weights::SSH);
+        assert_eq!(tally.waived("ssh"), 0);
+        assert_eq!(tally.score(), weights::SSH);
+        assert_eq!(tally.mandatory_fired(), Some("ssh"));
+        // 30 alone is yellow; a mandatory rule makes it red.
+        assert_eq!(tally.verdict(&thresholds), Verdict::Red);
+
+        let mut tally = RiskTally::default();
+        tally.require("net_intent");
+        tally.add("device", weights::DEVICE);
+        assert_eq!(tally.verdict(&thresholds), Verdict::Green);
     }
 
     #[test]
@@ -0,0 +1,313 @@
+//! Execution guard: run a request's command at most once per run id.
+//!
+//! The dedupe windows drop a repeated request only while they remember it,
+//! and a consumer that dies between running a command and acknowledging
+//! its message gets the message redelivered. With `MAGICRUNE_GUARD_DIR`
+//! set, a marker `<run_id>.started` is written and synced before the
+//! command is spawned, and replaced by `<run_id>.result.json` once the
+//! result is signed. A run id seen again then finds either:
+//!
+//! - its result, which is returned as it was without running anything, or
+//! - a marker without a result: the run started and never finished. The
+//!   policy's `exec.on_redelivery` decides: `rerun` (the default) runs it
+//!   again, `fail` refuses the request, `return_partial` returns what the
+//!   marker recorded (see [`partial_result`]).
+//!
+//! Several consumers may share the directory: the marker is created with
+//! `O_EXCL` and results are renamed into place. A run still going on
+//! another consumer looks like an interrupted one.
+
+use crate::schema::{Interrupted, SpellResult};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// What to do with a run that was started and never finished.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnRedelivery {
+    #[default]
+    Rerun,
+    Fail,
+    ReturnPartial,
+}
+
+impl FromStr for OnRedelivery {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rerun" => Ok(Self::Rerun),
+            "fail" => Ok(Self::Fail),
+            "return_partial" => Ok(Self::ReturnPartial),
+            other => Err(format!(
+                "unknown on_redelivery {:?} (rerun, fail or return_partial)",
+                other
+            )),
+        }
+    }
+}
+
+/// What the guard knew of a run id before it ran.
+#[derive(Debug, Clone)]
+pub enum Begin {
+    /// Never seen: the marker is now written.
+    Fresh,
+    /// Finished before; its result.
+    Finished(Box<SpellResult>),
+    /// Started and never finished.
+    Interrupted(Interrupted),
+}
+
+#[derive(Debug, Clone)]
+pub struct ExecGuard {
+    dir: PathBuf,
+}
+
+impl ExecGuard {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The guard at `MAGICRUNE_GUARD_DIR`; `None` when unset.
+    pub fn from_env() -> Option<Self> {
+        crate::ports::env::var("MAGICRUNE_GUARD_DIR")
+            .ok()
+            .filter(|d| !d.is_empty())
+            .map(Self::new)
+    }
+
+    fn marker(&self, run_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.started", run_id))
+    }
+
+    fn result(&self, run_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.result.json", run_id))
+    }
+
+    /// Look `run_id` up, and mark it started when it is new.
+    pub fn begin(&self, run_id: &str, instance_id: &str) -> io::Result<Begin> {
+        if let Ok(bytes) = std::fs::read(self.result(run_id)) {
+            if let Ok(result) = serde_json::from_slice(&bytes) {
+                return Ok(Begin::Finished(Box::new(result)));
+            }
+        }
+        std::fs::create_dir_all(&self.dir)?;
+        let first = Interrupted {
+            started_at_ms: now_ms(),
+            instance_id: instance_id.to_string(),
+            attempts: 1,
+        };
+        match std::fs::File::options()
+            .write(true)
+            .create_new(true)
+            .open(self.marker(run_id))
+        {
+            Ok(mut f) => {
+                f.write_all(&serde_json::to_vec(&first)?)?;
+                f.sync_all()?;
+                sync_dir(&self.dir)?;
+                Ok(Begin::Fresh)
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                // A marker the crash left half-written still says "started".
+                let seen = std::fs::read(self.marker(run_id))
+                    .ok()
+                    .and_then(|b| serde_json::from_slice(&b).ok())
+                    .unwrap_or(Interrupted {
+                        started_at_ms: 0,
+                        instance_id: String::new(),
+                        attempts: 1,
+                    });
+                Ok(Begin::Interrupted(seen))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Mark an interrupted run started again, by `instance_id`.
+    pub fn restart(&self, run_id: &str, instance_id: &str, seen: &Interrupted) -> io::Result<()> {
+        let again = Interrupted {
+            started_at_ms: now_ms(),
+            instance_id: instance_id.to_string(),
+            attempts: seen.attempts + 1,
+        };
+        write_synced(&self.marker(run_id), &serde_json::to_vec(&again)?)?;
+        sync_dir(&self.dir)
+    }
+
+    /// Record the result of a run and drop its marker.
+    pub fn finish(&self, result: &SpellResult) -> io::Result<()> {
+        write_synced(&self.result(&result.run_id), &serde_json::to_vec(result)?)?;
+        std::fs::remove_file(self.marker(&result.run_id))?;
+        sync_dir(&self.dir)
+    }
+
+    /// Drop the marker of a run whose command never started. Once it has,
+    /// the marker stays until [`ExecGuard::finish`], whatever fails on the
+    /// way: a redelivery takes the run as interrupted rather than running
+    /// the command again unasked.
+    pub fn abandon(&self, run_id: &str) {
+        let _ = std::fs::remove_file(self.marker(run_id));
+    }
+
+    /// Hold the marker [`ExecGuard::begin`] or [`ExecGuard::restart`] wrote
+    /// for `run_id` until the run finishes.
+    pub fn hold(&self, run_id: &str) -> Started<'_> {
+        Started {
+            guard: self,
+            run_id: run_id.to_string(),
+            running: false,
+            finished: false,
+        }
+    }
+}
+
+/// A run marked started. Dropped before its command runs
+/// ([`Started::running`]), it abandons the marker: a run that fails before
+/// executing anything leaves nothing for a redelivery to take as
+/// interrupted. Dropped later without [`Started::finish`], it keeps it.
+#[derive(Debug)]
+pub struct Started<'a> {
+    guard: &'a ExecGuard,
+    run_id: String,
+    running: bool,
+    finished: bool,
+}
+
+impl Started<'_> {
+    /// The command is about to run.
+    pub fn running(&mut self) {
+        self.running = true;
+    }
+
+    /// The command did not start after all ([`ExecGuard::abandon`]).
+    pub fn abandon(mut self) {
+        self.running = false;
+    }
+
+    /// Record the run's result ([`ExecGuard::finish`]).
+    pub fn finish(mut self, result: &SpellResult) -> io::Result<()> {
+        let out = self.guard.finish(result);
+        self.finished = out.is_ok();
+        out
+    }
+}
+
+impl Drop for Started<'_> {
+    fn drop(&mut self) {
+        if !self.running && !self.finished {
+            self.guard.abandon(&self.run_id);
+        }
+    }
+}
+
+/// The result of a `return_partial` redelivery: the verdict the command
+/// line alone earns, exit code -1, no output, and `interrupted` saying when
+/// and where the run started.
+pub fn partial_result(
+    run_id: &str,
+    verdict: &str,
+    risk_score: u32,
+    seen: Interrupted,
+) -> SpellResult {
+    SpellResult {
+        run_id: run_id.to_string(),
+        verdict: verdict.to_string(),
+        risk_score,
+        exit_code: -1,
+        interrupted: Some(seen),
+        ..Default::default()
+    }
+}
+
+fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Write `bytes` to `path` through a synced temporary file and a rename.
+fn write_synced(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut f = std::fs::File::create(&tmp)?;
+    f.write_all(bytes)?;
+    f.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    std::fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_begin_finish_and_redelivery() {
+        let dir = std::env::temp_dir().join(format!("mr_guard_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let guard = ExecGuard::new(&dir);
+        assert!(matches!(guard.begin("r_1", "w-1").unwrap(), Begin::Fresh));
+        let Begin::Interrupted(seen) = guard.begin("r_1", "w-2").unwrap() else {
+            panic!("not interrupted");
+        };
+        assert_eq!((seen.instance_id.as_str(), seen.attempts), ("w-1", 1));
+        guard.restart("r_1", "w-2", &seen).unwrap();
+        let Begin::Interrupted(again) = guard.begin("r_1", "w-3").unwrap() else {
+            panic!("not interrupted");
+        };
+        assert_eq!((again.instance_id.as_str(), again.attempts), ("w-2", 2));
+
+        let result = SpellResult {
+            run_id: "r_1".into(),
+            verdict: "green".into(),
+            ..Default::default()
+        };
+        guard.finish(&result).unwrap();
+        let Begin::Finished(stored) = guard.begin("r_1", "w-3").unwrap() else {
+            panic!("not finished");
+        };
+        assert_eq!(stored.verdict, "green");
+        assert!(!guard.marker("r_1").exists());
+
+        assert!(matches!(guard.begin("r_2", "").unwrap(), Begin::Fresh));
+        guard.abandon("r_2");
+        assert!(matches!(guard.begin("r_2", "").unwrap(), Begin::Fresh));
+        drop(guard.hold("r_2"));
+        assert!(matches!(guard.begin("r_2", "").unwrap(), Begin::Fresh));
+        let held = guard.hold("r_2");
+        held.finish(&SpellResult {
+            run_id: "r_2".into(),
+            ..result
+        })
+        .unwrap();
+        assert!(matches!(
+            guard.begin("r_2", "").unwrap(),
+            Begin::Finished(_)
+        ));
+
+        // Once the command runs, only a result clears the marker.
+        assert!(matches!(guard.begin("r_3", "w-1").unwrap(), Begin::Fresh));
+        let mut held = guard.hold("r_3");
+        held.running();
+        held.abandon();
+        assert!(matches!(guard.begin("r_3", "w-1").unwrap(), Begin::Fresh));
+        let mut held = guard.hold("r_3");
+        held.running();
+        drop(held);
+        assert!(matches!(
+            guard.begin("r_3", "w-2").unwrap(),
+            Begin::Interrupted(_)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_on_redelivery_parse() {
+        assert_eq!("fail".parse(), Ok(OnRedelivery::Fail));
+        assert_eq!("return_partial".parse(), Ok(OnRedelivery::ReturnPartial));
+        assert!("skip".parse::<OnRedelivery>().is_err());
+    }
+}
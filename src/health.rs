@@ -0,0 +1,159 @@
+//! Liveness and readiness endpoints for consumer deployments
+//! (`consume --health-addr`).
+//!
+//! `GET /healthz` answers 200 while the process is up. `GET /readyz` answers
+//! 200 once every check the consumer declared passes, and 503 with the
+//! failing ones otherwise. A check is either a flag the consumer sets as it
+//! comes up (stream and consumer created, policy loaded) or a probe run on
+//! every request (the NATS connection state), so a consumer that loses its
+//! broker drops out of readiness until it reconnects. `GET /metrics` serves
+//! the run histograms of [`crate::metrics`].
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long a client may take to send its request line.
+const READ_TIMEOUT: Duration = Duration::from_secs(2);
+
+enum Check {
+    Flag(bool),
+    Probe(Box<dyn Fn() -> bool + Send + Sync>),
+}
+
+impl Check {
+    fn passes(&self) -> bool {
+        match self {
+            Check::Flag(ok) => *ok,
+            Check::Probe(f) => f(),
+        }
+    }
+}
+
+pub struct Health {
+    checks: Mutex<Vec<(&'static str, Check)>>,
+}
+
+impl Health {
+    /// Readiness requires every check in `required`; all start out failing.
+    pub fn new(required: &[&'static str]) -> Arc<Self> {
+        Arc::new(Self {
+            checks: Mutex::new(
+                required
+                    .iter()
+                    .map(|name| (*name, Check::Flag(false)))
+                    .collect(),
+            ),
+        })
+    }
+
+    pub fn set(&self, name: &'static str, ok: bool) {
+        self.put(name, Check::Flag(ok));
+    }
+
+    /// Evaluate `check` on every readiness request instead of a flag.
+    pub fn probe(&self, name: &'static str, check: impl Fn() -> bool + Send + Sync + 'static) {
+        self.put(name, Check::Probe(Box::new(check)));
+    }
+
+    fn put(&self, name: &'static str, check: Check) {
+        let mut checks = self.checks.lock().unwrap();
+        match checks.iter_mut().find(|(n, _)| *n == name) {
+            Some(slot) => slot.1 = check,
+            None => checks.push((name, check)),
+        }
+    }
+
+    /// Names of the checks that do not pass, in declaration order.
+    pub fn failing(&self) -> Vec<&'static str> {
+        self.checks
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, c)| !c.passes())
+            .map(|(n, _)| *n)
+            .collect()
+    }
+
+    /// Listen on `addr` and answer from a background thread. Returns the
+    /// bound address (useful with port 0).
+    pub fn serve(self: &Arc<Self>, addr: &str) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local = listener.local_addr()?;
+        let health = self.clone();
+        std::thread::Builder::new()
+            .name("magicrune-health".into())
+            .spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let _ = health.respond(stream);
+                }
+            })?;
+        Ok(local)
+    }
+
+    fn respond(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+        let mut parts = line.split_whitespace();
+        let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        let (status, body) = match (method, path) {
+            ("GET", "/healthz") => ("200 OK", "ok\n".to_string()),
+            ("GET", "/readyz") => match self.failing() {
+                failing if failing.is_empty() => ("200 OK", "ready\n".to_string()),
+                failing => (
+                    "503 Service Unavailable",
+                    format!("not ready: {}\n", failing.join(", ")),
+                ),
+            },
+            ("GET", "/metrics") => ("200 OK", crate::metrics::render("magicrune")),
+            ("GET", _) => ("404 Not Found", "not found\n".to_string()),
+            _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut s = TcpStream::connect(addr).unwrap();
+        write!(s, "GET {} HTTP/1.1\r\nHost: x\r\n\r\n", path).unwrap();
+        let mut out = String::new();
+        s.read_to_string(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_health_and_readiness() {
+        let health = Health::new(&["nats", "stream", "policy"]);
+        let addr = health.serve("127.0.0.1:0").unwrap();
+        assert!(get(addr, "/healthz").starts_with("HTTP/1.1 200"));
+        assert!(get(addr, "/metrics").contains("# TYPE magicrune_run_pids_peak histogram\n"));
+
+        let resp = get(addr, "/readyz");
+        assert!(resp.starts_with("HTTP/1.1 503"));
+        assert!(resp.ends_with("not ready: nats, stream, policy\n"));
+
+        let connected = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let c = connected.clone();
+        health.probe("nats", move || c.load(std::sync::atomic::Ordering::Relaxed));
+        health.set("stream", true);
+        health.set("policy", true);
+        assert!(get(addr, "/readyz").starts_with("HTTP/1.1 200"));
+
+        connected.store(false, std::sync::atomic::Ordering::Relaxed);
+        assert!(get(addr, "/readyz").ends_with("not ready: nats\n"));
+        assert!(get(addr, "/other").starts_with("HTTP/1.1 404"));
+    }
+}
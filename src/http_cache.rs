@@ -0,0 +1,322 @@
+//! Cache of HTTP responses fetched through the egress proxy.
+//!
+//! CI-like workloads `curl` the same artifacts over and over. With
+//! `MAGICRUNE_HTTP_CACHE_DIR` set, the proxy keeps the responses to plain
+//! `GET`s there, keyed by the sha256 of the URL, together with their
+//! `ETag`. A repeated fetch is still asked of the server, with
+//! `If-None-Match`; when the server answers `304 Not Modified` the kept
+//! response is sent instead, so only the headers cross the network.
+//!
+//! Only complete `200` responses with an `ETag` are kept: not those marked
+//! `no-store` or `private`, those that `Vary`, nor requests that carry
+//! credentials, cookies, ranges or conditions of their own. HTTPS goes
+//! through `CONNECT` tunnels the proxy cannot read and is never cached.
+//!
+//! The store is bounded by `MAGICRUNE_HTTP_CACHE_MB` (default 256), least
+//! recently used entries dropped first, as the input cache
+//! ([`crate::input_cache`]). Entries are written under a temporary name and
+//! renamed into place once the whole response has arrived.
+
+use crate::input_cache::{evict_lru, unique};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// Default bound of the store, in MiB.
+pub const DEFAULT_CACHE_MB: u64 = 256;
+
+/// Request headers that make a response the client's own.
+const PERSONAL: &[&str] = &[
+    "authorization",
+    "cookie",
+    "range",
+    "if-range",
+    "if-match",
+    "if-none-match",
+    "if-modified-since",
+    "if-unmodified-since",
+];
+
+#[derive(Debug, Clone)]
+pub struct HttpCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+/// A kept response, positioned after its `ETag`.
+#[derive(Debug)]
+pub struct Cached {
+    pub etag: String,
+    response: BufReader<File>,
+}
+
+impl Cached {
+    /// Send the response as it was received; the bytes sent.
+    pub fn serve(mut self, to: &mut impl Write) -> io::Result<u64> {
+        io::copy(&mut self.response, to)
+    }
+}
+
+/// A response being written to the store as it passes through.
+#[derive(Debug)]
+pub struct Fill {
+    dir: PathBuf,
+    tmp: PathBuf,
+    file: File,
+    max_bytes: u64,
+    written: u64,
+    body: u64,
+    framing: Framing,
+    tail: Vec<u8>,
+    done: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    Length(u64),
+    Chunked,
+}
+
+impl HttpCache {
+    pub fn new(dir: impl Into<PathBuf>, max_bytes: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            max_bytes,
+        }
+    }
+
+    /// The cache at `MAGICRUNE_HTTP_CACHE_DIR`, bounded by
+    /// `MAGICRUNE_HTTP_CACHE_MB`; `None` when unset or the bound is 0.
+    pub fn from_env() -> Option<Self> {
+        let dir = crate::ports::env::var("MAGICRUNE_HTTP_CACHE_DIR")
+            .ok()
+            .filter(|d| !d.is_empty())?;
+        let mb = crate::ports::env::var("MAGICRUNE_HTTP_CACHE_MB")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_CACHE_MB);
+        (mb > 0).then(|| Self::new(dir, mb << 20))
+    }
+
+    /// Cache key of a URL: its hex sha256.
+    pub fn key(url: &str) -> String {
+        use sha2::{Digest, Sha256};
+        Sha256::digest(url.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// The response kept for `url`, if any, marked as recently used.
+    pub fn lookup(&self, url: &str) -> Option<Cached> {
+        let entry = self.dir.join(Self::key(url));
+        let file = File::options().read(true).write(true).open(&entry).ok()?;
+        let _ = file.set_modified(SystemTime::now());
+        let mut response = BufReader::new(file);
+        let mut etag = String::new();
+        response.read_line(&mut etag).ok()?;
+        let etag = etag.trim_end().to_string();
+        (!etag.is_empty()).then_some(Cached { etag, response })
+    }
+
+    /// Start keeping the response whose head is `head`, when it may be
+    /// kept and fits the store. Failing to start is not an error.
+    pub fn fill(&self, head: &str) -> Option<Fill> {
+        let etag = storable(head)?;
+        let framing = match header(head, "content-length") {
+            Some(n) => Framing::Length(n.parse().ok()?),
+            None if header(head, "transfer-encoding")
+                .is_some_and(|te| te.eq_ignore_ascii_case("chunked")) =>
+            {
+                Framing::Chunked
+            }
+            None => return None,
+        };
+        if let Framing::Length(n) = framing {
+            if n + head.len() as u64 > self.max_bytes {
+                return None;
+            }
+        }
+        std::fs::create_dir_all(&self.dir).ok()?;
+        let tmp = self
+            .dir
+            .join(format!(".tmp.{}.{}", std::process::id(), unique()));
+        let file = File::create(&tmp).ok()?;
+        let mut fill = Fill {
+            dir: self.dir.clone(),
+            tmp,
+            file,
+            max_bytes: self.max_bytes,
+            written: 0,
+            body: 0,
+            framing,
+            tail: Vec::new(),
+            done: false,
+        };
+        fill.file.write_all(format!("{}\n", etag).as_bytes()).ok()?;
+        fill.file.write_all(head.as_bytes()).ok()?;
+        fill.written = head.len() as u64;
+        Some(fill)
+    }
+}
+
+impl Fill {
+    /// Add the next `bytes` of the body; `None`, dropping what was kept,
+    /// once the response outgrows the store or cannot be written.
+    pub fn push(mut self, bytes: &[u8]) -> Option<Self> {
+        self.written += bytes.len() as u64;
+        self.body += bytes.len() as u64;
+        if self.written > self.max_bytes {
+            return None;
+        }
+        self.file.write_all(bytes).ok()?;
+        if self.framing == Framing::Chunked {
+            self.tail.extend_from_slice(bytes);
+            let cut = self.tail.len().saturating_sub(5);
+            self.tail.drain(..cut);
+        }
+        Some(self)
+    }
+
+    /// Put the response in place as `url`'s, when all of it arrived.
+    pub fn commit(mut self, url: &str) {
+        let complete = match self.framing {
+            Framing::Length(n) => self.body == n,
+            Framing::Chunked => self.tail == b"0\r\n\r\n",
+        };
+        if !complete || self.file.flush().is_err() {
+            return;
+        }
+        if std::fs::rename(&self.tmp, self.dir.join(HttpCache::key(url))).is_ok() {
+            self.done = true;
+            evict_lru(&self.dir, self.max_bytes);
+        }
+    }
+}
+
+impl Drop for Fill {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = std::fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+/// Whether the request with head `head` may be answered from the cache.
+pub fn cacheable(head: &str) -> bool {
+    head.starts_with("GET ")
+        && head
+            .lines()
+            .skip(1)
+            .filter_map(|l| l.split_once(':'))
+            .all(|(name, _)| {
+                let name = name.trim().to_ascii_lowercase();
+                !PERSONAL.contains(&name.as_str())
+            })
+}
+
+/// Status code of a response head.
+pub fn status(head: &str) -> Option<u16> {
+    head.lines().next()?.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// The `ETag` of a response that may be kept.
+fn storable(head: &str) -> Option<&str> {
+    if status(head) != Some(200) || header(head, "vary").is_some() {
+        return None;
+    }
+    if header(head, "cache-control").is_some_and(|cc| {
+        let cc = cc.to_ascii_lowercase();
+        cc.contains("no-store") || cc.contains("private")
+    }) {
+        return None;
+    }
+    header(head, "etag").filter(|e| !e.is_empty())
+}
+
+/// Value of the first header `name` (lowercase) in `head`.
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().skip(1).find_map(|l| {
+        let (n, v) = l.split_once(':')?;
+        n.trim().eq_ignore_ascii_case(name).then(|| v.trim())
+    })
+}
+
+/// `head` with `If-None-Match: etag` added before the blank line.
+pub fn revalidate(head: &[u8], etag: &str) -> Vec<u8> {
+    let mut out = head[..head.len().saturating_sub(2)].to_vec();
+    out.extend_from_slice(format!("If-None-Match: {}\r\n\r\n", etag).as_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let d = std::env::temp_dir().join(format!("mr_http_cache_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&d);
+        d
+    }
+
+    #[test]
+    fn test_fill_and_lookup() {
+        let dir = scratch("fill");
+        let cache = HttpCache::new(&dir, 1 << 20);
+        let head = "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 4\r\n\r\n";
+        let fill = cache.fill(head).unwrap().push(b"ab").unwrap();
+        // Cut short: nothing is kept.
+        fill.commit("http://a.test/x");
+        assert!(cache.lookup("http://a.test/x").is_none());
+        assert!(std::fs::read_dir(&dir).unwrap().next().is_none());
+
+        let fill = cache.fill(head).unwrap().push(b"ab").unwrap();
+        fill.push(b"cd").unwrap().commit("http://a.test/x");
+        let hit = cache.lookup("http://a.test/x").unwrap();
+        assert_eq!(hit.etag, "\"v1\"");
+        let mut out = Vec::new();
+        hit.serve(&mut out).unwrap();
+        assert_eq!(out, format!("{}abcd", head).as_bytes());
+        assert!(dir.join(HttpCache::key("http://a.test/x")).exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_what_is_kept() {
+        let cache = HttpCache::new(scratch("kept"), 100);
+        let ok = "HTTP/1.1 200 OK\r\nETag: \"e\"\r\n";
+        assert!(cache
+            .fill(&format!("{}Transfer-Encoding: chunked\r\n\r\n", ok))
+            .is_some());
+        // Too big, not framed, no ETag, not 200, not to be stored.
+        assert!(cache
+            .fill(&format!("{}Content-Length: 100\r\n\r\n", ok))
+            .is_none());
+        assert!(cache.fill(&format!("{}\r\n", ok)).is_none());
+        assert!(cache
+            .fill("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n")
+            .is_none());
+        assert!(cache
+            .fill("HTTP/1.1 206 Partial\r\nETag: \"e\"\r\nContent-Length: 1\r\n\r\n")
+            .is_none());
+        assert!(cache
+            .fill(&format!(
+                "{}Cache-Control: private\r\nContent-Length: 1\r\n\r\n",
+                ok
+            ))
+            .is_none());
+
+        assert!(cacheable(
+            "GET http://a.test/ HTTP/1.1\r\nHost: a.test\r\n\r\n"
+        ));
+        assert!(!cacheable("POST http://a.test/ HTTP/1.1\r\n\r\n"));
+        assert!(!cacheable(
+            "GET http://a.test/ HTTP/1.1\r\nAuthorization: Bearer x\r\n\r\n"
+        ));
+        assert_eq!(
+            revalidate(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n", "\"e\""),
+            b"GET / HTTP/1.1\r\nHost: a\r\nIf-None-Match: \"e\"\r\n\r\n"
+        );
+    }
+}
@@ -0,0 +1,183 @@
+//! Content-addressed cache of materialized input files.
+//!
+//! CI pipelines send the same `files[].content_b64` over and over. With
+//! `MAGICRUNE_INPUT_CACHE_DIR` set, the decoded bytes are kept there under
+//! the sha256 of the encoded content, so a repeated input skips the base64
+//! decode and is copied into place from the cache.
+//!
+//! Entries are copied, never hard-linked: the command may write to its
+//! inputs, and a shared inode would carry those writes into the cache. On
+//! Linux `std::fs::copy` uses `copy_file_range`, which the filesystem can
+//! serve without moving the data through userspace.
+//!
+//! The store is bounded by `MAGICRUNE_INPUT_CACHE_MB` (default 256); the
+//! least recently used entries are dropped first. Several processes may
+//! share one directory: entries are written under a temporary name and
+//! renamed into place.
+
+use base64::Engine as _;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Default bound of the store, in MiB.
+pub const DEFAULT_CACHE_MB: u64 = 256;
+
+#[derive(Debug, Clone)]
+pub struct InputCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl InputCache {
+    pub fn new(dir: impl Into<PathBuf>, max_bytes: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            max_bytes,
+        }
+    }
+
+    /// The cache at `MAGICRUNE_INPUT_CACHE_DIR`, bounded by
+    /// `MAGICRUNE_INPUT_CACHE_MB`; `None` when unset or the bound is 0.
+    pub fn from_env() -> Option<Self> {
+        let dir = crate::ports::env::var("MAGICRUNE_INPUT_CACHE_DIR")
+            .ok()
+            .filter(|d| !d.is_empty())?;
+        let mb = crate::ports::env::var("MAGICRUNE_INPUT_CACHE_MB")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_CACHE_MB);
+        (mb > 0).then(|| Self::new(dir, mb << 20))
+    }
+
+    /// Cache key of an input: hex sha256 of its encoded content.
+    pub fn key(content_b64: &str) -> String {
+        use sha2::{Digest, Sha256};
+        Sha256::digest(content_b64.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Write the decoded `content_b64` to `dest`, from the cache when it is
+    /// there. Returns `Ok(false)` without writing when the content does not
+    /// decode. Failing to fill the cache is not an error.
+    pub fn materialize(&self, content_b64: &str, dest: &Path) -> io::Result<bool> {
+        let entry = self.dir.join(Self::key(content_b64));
+        if std::fs::copy(&entry, dest).is_ok() {
+            // Mark the entry as recently used.
+            if let Ok(f) = std::fs::File::options().write(true).open(&entry) {
+                let _ = f.set_modified(SystemTime::now());
+            }
+            return Ok(true);
+        }
+        let Ok(bytes) = base64::engine::general_purpose::STANDARD.decode(content_b64) else {
+            return Ok(false);
+        };
+        std::fs::write(dest, &bytes)?;
+        if (bytes.len() as u64) <= self.max_bytes && self.store(&entry, &bytes).is_ok() {
+            self.evict();
+        }
+        Ok(true)
+    }
+
+    fn store(&self, entry: &Path, bytes: &[u8]) -> io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let tmp = self
+            .dir
+            .join(format!(".tmp.{}.{}", std::process::id(), unique()));
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(&tmp, entry).inspect_err(|_| {
+            let _ = std::fs::remove_file(&tmp);
+        })
+    }
+
+    fn evict(&self) {
+        evict_lru(&self.dir, self.max_bytes);
+    }
+}
+
+/// Drop the least recently used entries of `dir` until they fit in
+/// `max_bytes`. Names starting with a dot (files being written) are left.
+pub(crate) fn evict_lru(dir: &Path, max_bytes: u64) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut files: Vec<(SystemTime, u64, PathBuf)> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(|e| {
+            let meta = e.metadata().ok()?;
+            Some((meta.modified().ok()?, meta.len(), e.path()))
+        })
+        .collect();
+    let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+    files.sort();
+    for (_, len, path) in files {
+        if total <= max_bytes {
+            break;
+        }
+        if std::fs::remove_file(&path).is_ok() {
+            total -= len;
+        }
+    }
+}
+
+/// Distinguishes temporary names written by one process.
+pub(crate) fn unique() -> u64 {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let d =
+            std::env::temp_dir().join(format!("mr_input_cache_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&d);
+        std::fs::create_dir_all(&d).unwrap();
+        d
+    }
+
+    #[test]
+    fn test_materialize_hits_cache() {
+        let base = scratch("hit");
+        let cache = InputCache::new(base.join("cache"), 1 << 20);
+        let b64 = base64::engine::general_purpose::STANDARD.encode(b"hello");
+        assert!(cache.materialize(&b64, &base.join("a")).unwrap());
+        let entry = base.join("cache").join(InputCache::key(&b64));
+        assert_eq!(std::fs::read(&entry).unwrap(), b"hello");
+
+        // A changed copy does not reach the cache.
+        std::fs::write(base.join("a"), b"tampered").unwrap();
+        assert!(cache.materialize(&b64, &base.join("b")).unwrap());
+        assert_eq!(std::fs::read(base.join("b")).unwrap(), b"hello");
+
+        assert!(!cache.materialize("not base64!", &base.join("c")).unwrap());
+        assert!(!base.join("c").exists());
+        let _ = std::fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let base = scratch("evict");
+        let cache = InputCache::new(base.join("cache"), 10);
+        let enc = |s: &[u8]| base64::engine::general_purpose::STANDARD.encode(s);
+        let (old, new) = (enc(b"aaaaaa"), enc(b"bbbbbb"));
+        cache.materialize(&old, &base.join("a")).unwrap();
+        let old_entry = base.join("cache").join(InputCache::key(&old));
+        std::fs::File::options()
+            .write(true)
+            .open(&old_entry)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        cache.materialize(&new, &base.join("b")).unwrap();
+        assert!(!old_entry.exists());
+        assert!(base.join("cache").join(InputCache::key(&new)).exists());
+        let _ = std::fs::remove_dir_all(&base);
+    }
+}
@@ -0,0 +1,135 @@
+//! Running several consumers side by side.
+//!
+//! A consumer may be given an instance id (`consume --instance-id`,
+//! `MAGICRUNE_INSTANCE_ID`). It names the instance's JetStream durable
+//! consumer, labels its metrics and is stamped on every result and ledger
+//! record it produces, so two replicas never share a durable by accident
+//! and their output can be told apart.
+//!
+//! Work is split by subject rather than by competing on one durable. A
+//! producer with `MAGICRUNE_SHARDS=<n>` publishes each request to
+//! `<prefix>.req.shard.<k>` (see `crate::jet::subjects`), `k` derived from the run id, and each consumer takes
+//! one shard (`consume --shard <k>`) through a durable filtered on it. A
+//! given request always lands on the same shard, so redeliveries and
+//! duplicates reach the instance that has already seen it.
+//!
+//! Fleets of unlike runners are told apart by labels. A consumer advertises
+//! its own (`consume --labels gpu,eu-west`, `MAGICRUNE_RUNNER_LABELS`), and
+//! a policy may require some (`constraints.runner_labels`). A consumer
+//! missing one of them hands the request back unrun with a routing hint,
+//! for a consumer that has them to pick it up.
+
+use sha2::{Digest, Sha256};
+
+/// Environment variable read when `--instance-id` is not given.
+pub const INSTANCE_ENV: &str = "MAGICRUNE_INSTANCE_ID";
+
+/// Environment variable read when `--labels` is not given.
+pub const LABELS_ENV: &str = "MAGICRUNE_RUNNER_LABELS";
+
+/// Check an instance id. The rules are those of tenant names, which also
+/// makes it a valid durable name and metric label value.
+pub fn check_id(id: &str) -> Result<(), String> {
+    crate::tenant::check_name(id).map_err(|_| format!("invalid instance id {:?}", id))
+}
+
+/// The instance id at [`INSTANCE_ENV`], if set and non-empty.
+pub fn from_env() -> Option<String> {
+    crate::ports::env::var(INSTANCE_ENV)
+        .ok()
+        .filter(|i| !i.is_empty())
+}
+
+/// `base`, suffixed with the instance id when there is one.
+pub fn durable_name(base: &str, instance: Option<&str>) -> String {
+    match instance {
+        Some(id) => format!("{}_{}", base, id),
+        None => base.to_string(),
+    }
+}
+
+/// Labels from a comma-separated list; each follows the instance id rules.
+pub fn parse_labels(list: &str) -> Result<Vec<String>, String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(|l| {
+            crate::tenant::check_name(l)
+                .map(|_| l.to_string())
+                .map_err(|_| format!("invalid runner label {:?}", l))
+        })
+        .collect()
+}
+
+/// The labels at [`LABELS_ENV`]; none when unset.
+pub fn labels_from_env() -> Result<Vec<String>, String> {
+    parse_labels(&crate::ports::env::var(LABELS_ENV).unwrap_or_default())
+}
+
+/// Labels of `required` that are not in `have`.
+pub fn missing_labels<'a>(required: &'a [String], have: &[String]) -> Vec<&'a str> {
+    required
+        .iter()
+        .filter(|l| !have.contains(l))
+        .map(String::as_str)
+        .collect()
+}
+
+/// What a consumer missing `missing` logs when handing a request back.
+pub fn routing_hint(required: &[String], missing: &[&str]) -> String {
+    format!(
+        "needs a runner labelled {} (this one lacks {})",
+        required.join(","),
+        missing.join(",")
+    )
+}
+
+/// Shard of `run_id` among `shards` (at least one): the run id's SHA-256
+/// read as a big-endian integer, modulo `shards`.
+pub fn shard_of(run_id: &str, shards: u32) -> u32 {
+    let digest = Sha256::digest(run_id.as_bytes());
+    let head = u64::from_be_bytes(digest[..8].try_into().unwrap());
+    (head % shards.max(1) as u64) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_durable_and_check() {
+        assert_eq!(durable_name("RUN_WORKER", None), "RUN_WORKER");
+        assert_eq!(durable_name("RUN_WORKER", Some("w-1")), "RUN_WORKER_w-1");
+        assert!(check_id("w-1").is_ok());
+        assert_eq!(check_id("a.b").unwrap_err(), "invalid instance id \"a.b\"");
+    }
+
+    #[test]
+    fn test_shards() {
+        let counts = (0..400).fold([0u32; 4], |mut c, i| {
+            c[shard_of(&format!("r_{}", i), 4) as usize] += 1;
+            c
+        });
+        assert!(counts.iter().all(|&n| n > 50), "{:?}", counts);
+        assert_eq!(shard_of("r_1", 4), shard_of("r_1", 4));
+        assert_eq!(shard_of("r_1", 0), 0);
+    }
+
+    #[test]
+    fn test_labels() {
+        let have = parse_labels(" eu-west, cpu ,").unwrap();
+        assert_eq!(have, ["eu-west", "cpu"]);
+        assert_eq!(
+            parse_labels("gpu,a b").unwrap_err(),
+            "invalid runner label \"a b\""
+        );
+        let required = vec!["gpu".to_string(), "eu-west".to_string()];
+        let missing = missing_labels(&required, &have);
+        assert_eq!(missing, ["gpu"]);
+        assert_eq!(
+            routing_hint(&required, &missing),
+            "needs a runner labelled gpu,eu-west (this one lacks gpu)"
+        );
+        assert!(missing_labels(&[], &have).is_empty());
+    }
+}
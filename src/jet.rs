@@ -1,5 +1,11 @@
 // JetStream placeholders (no network in local env). CIで依存を導入後に差し替え可能。
 
+pub mod chunk;
+pub mod config;
+pub mod encoding;
+pub mod publish;
+pub mod subjects;
+
 pub struct JsConfig {
     pub subject_req: String,
 }
@@ -37,9 +43,14 @@ pub async fn publish_result(_subject: &str, _bytes: &[u8]) -> JsResult<()> {
 // Optional async-nats implementation; compiled only when feature `jet` is enabled (CI).
 #[cfg(feature = "jet")]
 pub mod jet_impl {
+    use super::chunk;
     use super::compute_msg_id;
+    use super::encoding::{
+        accepts_zstd, Compression, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, ZSTD,
+    };
     use async_nats::header::HeaderMap;
-    use async_nats::Client;
+    use async_nats::{Client, Subscriber};
+    use std::borrow::Cow;
     use std::error::Error as StdError;
     use std::str::FromStr as _;
 
@@ -54,16 +65,137 @@ pub mod jet_impl {
         subject: &str,
         req: &[u8],
     ) -> Result<(), Box<dyn StdError + Send + Sync>> {
+        let (headers, body) = request_message(req, &Compression::from_env()?);
+        nc.publish_with_headers(subject.to_string(), headers, body.into())
+            .await
+            .map_err(|e| Box::new(e) as Box<dyn StdError + Send + Sync>)?;
+        Ok(())
+    }
+
+    /// Headers and body of a request: the `Nats-Msg-Id` of `req` as given,
+    /// `Accept-Encoding: zstd`, and `req` compressed when it is large (see
+    /// `super::encoding`).
+    pub fn request_message(req: &[u8], c: &Compression) -> (HeaderMap, Vec<u8>) {
         let mut headers = HeaderMap::new();
         let id = compute_msg_id(req);
         headers.insert(
             "Nats-Msg-Id",
             async_nats::header::HeaderValue::from_str(&id).unwrap(),
         );
-        nc.publish_with_headers(subject.to_string(), headers, req.to_vec().into())
+        headers.insert(ACCEPT_ENCODING, ZSTD);
+        let (body, encoding) = c.encode(req.to_vec(), true);
+        if let Some(e) = encoding {
+            headers.insert(CONTENT_ENCODING, e);
+        }
+        (headers, body)
+    }
+
+    /// Headers and body of a result for a requester that reads zstd when
+    /// `accepts_zstd`.
+    pub fn result_message(
+        res: &impl serde::Serialize,
+        accepts_zstd: bool,
+        c: &Compression,
+    ) -> serde_json::Result<(HeaderMap, Vec<u8>)> {
+        let mut headers = HeaderMap::new();
+        let (body, encoding) = c.encode(serde_json::to_vec(res)?, accepts_zstd);
+        if let Some(e) = encoding {
+            headers.insert(CONTENT_ENCODING, e);
+        }
+        Ok((headers, body))
+    }
+
+    /// Publish a result on `subject`, as chunks and a manifest when it does
+    /// not fit in one message (see `super::chunk`).
+    pub async fn send_result(
+        nc: &Client,
+        subject: String,
+        mut headers: HeaderMap,
+        body: Vec<u8>,
+    ) -> Result<(), async_nats::PublishError> {
+        let Some((manifest, chunks)) = chunk::split(&body, nc.server_info().max_payload) else {
+            return nc.publish_with_headers(subject, headers, body.into()).await;
+        };
+        for (n, part) in chunks.into_iter().enumerate() {
+            nc.publish(chunk::chunk_subject(&subject, n), part.to_vec().into())
+                .await?;
+        }
+        headers.insert(chunk::CHUNKS_HEADER, manifest.chunks.to_string());
+        let manifest = serde_json::to_vec(&manifest).unwrap_or_default();
+        nc.publish_with_headers(subject, headers, manifest.into())
             .await
-            .map_err(|e| Box::new(e) as Box<dyn StdError + Send + Sync>)?;
-        Ok(())
+    }
+
+    /// Results for one run, as sent by `send_result`.
+    pub struct ResultSubscription {
+        results: Subscriber,
+        chunks: Subscriber,
+    }
+
+    /// Subscribe to the result on `subject` (see `super::subjects`); do so
+    /// before publishing the request, since a fast consumer may answer first.
+    pub async fn subscribe_result(
+        nc: &Client,
+        subject: String,
+    ) -> Result<ResultSubscription, async_nats::SubscribeError> {
+        Ok(ResultSubscription {
+            chunks: nc.subscribe(format!("{}.chunk.*", subject)).await?,
+            results: nc.subscribe(subject).await?,
+        })
+    }
+
+    impl ResultSubscription {
+        /// The next result body, reassembled from its chunks and decoded;
+        /// `None` once the subscription ends.
+        pub async fn next(&mut self, c: &Compression) -> Result<Option<Vec<u8>>, String> {
+            use futures_util::StreamExt as _;
+            let Some(msg) = self.results.next().await else {
+                return Ok(None);
+            };
+            let headers = msg.headers.as_ref();
+            if headers.and_then(|h| h.get(chunk::CHUNKS_HEADER)).is_none() {
+                let (body, _) = open_message(headers, &msg.payload, c)?;
+                return Ok(Some(body.into_owned()));
+            }
+            let manifest: chunk::Manifest = serde_json::from_slice(&msg.payload)
+                .map_err(|e| format!("chunk manifest: {}", e))?;
+            let mut parts = chunk::Assembler::new(manifest, c.max_decoded)?;
+            while !parts.is_complete() {
+                let part = self
+                    .chunks
+                    .next()
+                    .await
+                    .ok_or("subscription ended before the last chunk")?;
+                if let Some(n) = chunk::chunk_index(&part.subject) {
+                    parts.add(n, &part.payload);
+                }
+            }
+            let body = parts.finish()?;
+            let (body, _) = open_message(headers, &body, c)?;
+            Ok(Some(body.into_owned()))
+        }
+    }
+
+    /// The body of a received message, decoded by its `Content-Encoding`
+    /// (and from protobuf to JSON when its `Content-Type` says so, see
+    /// `crate::proto`), and whether its sender reads zstd-compressed
+    /// replies.
+    pub fn open_message<'a>(
+        headers: Option<&HeaderMap>,
+        payload: &'a [u8],
+        c: &Compression,
+    ) -> Result<(Cow<'a, [u8]>, bool), String> {
+        let header = |name| headers.and_then(|h| h.get(name)).map(|v| v.as_str());
+        let body = c.decode(payload, header(CONTENT_ENCODING))?;
+        let body = match header(CONTENT_TYPE)
+            .map(|t| t.split(';').next().unwrap_or_default().trim())
+        {
+            None | Some("") | Some("application/json") => body,
+            #[cfg(feature = "protobuf")]
+            Some(crate::proto::CONTENT_TYPE) => Cow::Owned(crate::proto::decode_request(&body)?),
+            Some(t) => return Err(format!("unsupported Content-Type {:?}", t)),
+        };
+        Ok((body, accepts_zstd(header(ACCEPT_ENCODING))))
     }
 
     pub async fn publish_res(
@@ -76,6 +208,63 @@ pub mod jet_impl {
             .map_err(|e| Box::new(e) as Box<dyn StdError + Send + Sync>)?;
         Ok(())
     }
+
+    /// Object store bucket for request bundles (`MAGICRUNE_BUNDLE_BUCKET`,
+    /// default `spells`).
+    pub fn bundle_bucket() -> String {
+        std::env::var("MAGICRUNE_BUNDLE_BUCKET")
+            .ok()
+            .filter(|b| !b.is_empty())
+            .unwrap_or_else(|| "spells".to_string())
+    }
+
+    /// Store a `.spell.tgz` under its sha256 and return the request message
+    /// that points at it, `{"bundle":"<sha256>"}`.
+    pub async fn put_bundle(
+        nc: &Client,
+        bundle: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn StdError + Send + Sync>> {
+        use async_nats::jetstream::{self, object_store};
+        let js = jetstream::new(nc.clone());
+        let bucket = bundle_bucket();
+        let store = match js.get_object_store(&bucket).await {
+            Ok(store) => store,
+            Err(_) => {
+                js.create_object_store(object_store::Config {
+                    bucket,
+                    ..Default::default()
+                })
+                .await?
+            }
+        };
+        let name = compute_msg_id(bundle);
+        store.put(name.as_str(), &mut &bundle[..]).await?;
+        Ok(serde_json::to_vec(&serde_json::json!({ "bundle": name }))?)
+    }
+
+    /// For a message of the form `{"bundle":"<name>"}`, the bundle fetched
+    /// from the object store and expanded into its request (see
+    /// `crate::bundle`); `None` for any other payload.
+    pub async fn fetch_bundle(
+        nc: &Client,
+        payload: &[u8],
+    ) -> Result<Option<Vec<u8>>, Box<dyn StdError + Send + Sync>> {
+        use tokio::io::AsyncReadExt as _;
+        if !payload.windows(8).any(|w| w == b"\"bundle\"") {
+            return Ok(None);
+        }
+        let Ok(serde_json::Value::Object(msg)) = serde_json::from_slice(payload) else {
+            return Ok(None);
+        };
+        let Some(name) = msg.get("bundle").and_then(|n| n.as_str()) else {
+            return Ok(None);
+        };
+        let js = async_nats::jetstream::new(nc.clone());
+        let store = js.get_object_store(bundle_bucket()).await?;
+        let mut bytes = Vec::new();
+        store.get(name).await?.read_to_end(&mut bytes).await?;
+        Ok(Some(crate::bundle::open(&bytes)?))
+    }
 }
 
 #[cfg(test)]
@@ -140,6 +329,66 @@ mod tests {
         assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
     }
 
+    #[cfg(feature = "jet")]
+    #[test]
+    fn test_compressed_messages() {
+        use encoding::{Compression, CONTENT_ENCODING};
+        let c = Compression {
+            min_bytes: 1024,
+            ..Default::default()
+        };
+        let req = serde_json::json!({ "cmd": "echo", "stdin": "x".repeat(10_000) })
+            .to_string()
+            .into_bytes();
+        let (headers, body) = jet_impl::request_message(&req, &c);
+        assert_eq!(
+            headers.get("Nats-Msg-Id").unwrap().as_str(),
+            compute_msg_id(&req)
+        );
+        assert_eq!(headers.get(CONTENT_ENCODING).unwrap().as_str(), "zstd");
+        let (opened, accepts) = jet_impl::open_message(Some(&headers), &body, &c).unwrap();
+        assert_eq!(opened, req);
+        assert!(accepts);
+
+        let res = serde_json::json!({ "stdout": "y".repeat(10_000) });
+        let (headers, body) = jet_impl::result_message(&res, false, &c).unwrap();
+        assert!(headers.get(CONTENT_ENCODING).is_none());
+        assert_eq!(body, serde_json::to_vec(&res).unwrap());
+        let (headers, body) = jet_impl::result_message(&res, true, &c).unwrap();
+        let (opened, _) = jet_impl::open_message(Some(&headers), &body, &c).unwrap();
+        assert_eq!(opened, serde_json::to_vec(&res).unwrap());
+    }
+
+    #[cfg(feature = "jet")]
+    #[test]
+    fn test_open_message_content_type() {
+        use async_nats::header::HeaderMap;
+        use encoding::{Compression, CONTENT_TYPE};
+        let c = Compression::default();
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, "application/json; charset=utf-8");
+        assert!(jet_impl::open_message(Some(&headers), b"{}", &c).is_ok());
+        headers.insert(CONTENT_TYPE, "text/yaml");
+        let err = jet_impl::open_message(Some(&headers), b"{}", &c).unwrap_err();
+        assert_eq!(err, "unsupported Content-Type \"text/yaml\"");
+
+        #[cfg(feature = "protobuf")]
+        {
+            use prost::Message as _;
+            let req = crate::proto::SpellRequest {
+                cmd: "echo hi".into(),
+                timeout_sec: 5,
+                ..Default::default()
+            };
+            headers.insert(CONTENT_TYPE, crate::proto::CONTENT_TYPE);
+            let payload = req.encode_to_vec();
+            let (body, _) = jet_impl::open_message(Some(&headers), &payload, &c).unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(json["cmd"], "echo hi");
+            assert_eq!(json["timeout_sec"], 5);
+        }
+    }
+
     #[tokio::test]
     async fn test_send_request_disabled() {
         let config = JsConfig {
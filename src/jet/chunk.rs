@@ -0,0 +1,149 @@
+//! Results split across several NATS messages.
+//!
+//! Compression (see `super::encoding`) is not always enough: a result can
+//! still exceed the server's `max_payload`. Such a body is sent as ordered
+//! chunks on `<subject>.chunk.<n>` (`n` from 0), followed by a manifest on
+//! the result subject itself (`run.res.<run_id>` by default, see
+//! `super::subjects`). The manifest carries the `Magicrune-Chunks` header
+//! and the body's `Content-Encoding`, and its payload is a [`Manifest`]. A
+//! requester that sees the header collects the chunks, checks their length
+//! and sha256 against the manifest, and decodes the joined body as if it had
+//! arrived in one message.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const CHUNKS_HEADER: &str = "Magicrune-Chunks";
+
+/// Room left in each message for the subject and headers.
+pub const MESSAGE_OVERHEAD: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub chunks: usize,
+    pub bytes: usize,
+    pub sha256: String,
+}
+
+/// Subject of chunk `n` of the result on `subject`.
+pub fn chunk_subject(subject: &str, n: usize) -> String {
+    format!("{}.chunk.{}", subject, n)
+}
+
+/// Index of the chunk on `subject`, if it is a chunk subject.
+pub fn chunk_index(subject: &str) -> Option<usize> {
+    let (_, n) = subject.rsplit_once(".chunk.")?;
+    n.parse().ok()
+}
+
+/// `body` as chunks that fit in messages of `max_payload` bytes, and their
+/// manifest; `None` when it fits in one message (or the limit is unknown).
+pub fn split(body: &[u8], max_payload: usize) -> Option<(Manifest, Vec<&[u8]>)> {
+    if max_payload == 0 || body.len() + MESSAGE_OVERHEAD <= max_payload {
+        return None;
+    }
+    let size = max_payload.saturating_sub(MESSAGE_OVERHEAD).max(1);
+    let chunks: Vec<&[u8]> = body.chunks(size).collect();
+    let manifest = Manifest {
+        chunks: chunks.len(),
+        bytes: body.len(),
+        sha256: super::compute_msg_id(body),
+    };
+    Some((manifest, chunks))
+}
+
+/// Chunks collected for one manifest.
+#[derive(Debug)]
+pub struct Assembler {
+    manifest: Manifest,
+    parts: BTreeMap<usize, Vec<u8>>,
+}
+
+impl Assembler {
+    /// Expect the chunks of `manifest`, refusing bodies over `max_bytes`.
+    pub fn new(manifest: Manifest, max_bytes: usize) -> Result<Self, String> {
+        if manifest.bytes > max_bytes {
+            return Err(format!(
+                "chunked result of {} bytes is over the {} byte limit",
+                manifest.bytes, max_bytes
+            ));
+        }
+        Ok(Self {
+            manifest,
+            parts: BTreeMap::new(),
+        })
+    }
+
+    /// Add chunk `index`; a redelivered chunk replaces the earlier copy.
+    pub fn add(&mut self, index: usize, bytes: &[u8]) {
+        if index < self.manifest.chunks {
+            self.parts.insert(index, bytes.to_vec());
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.parts.len() == self.manifest.chunks
+    }
+
+    /// The joined body, checked against the manifest.
+    pub fn finish(self) -> Result<Vec<u8>, String> {
+        if !self.is_complete() {
+            return Err(format!(
+                "{} of {} chunks received",
+                self.parts.len(),
+                self.manifest.chunks
+            ));
+        }
+        let body: Vec<u8> = self.parts.into_values().flatten().collect();
+        if body.len() != self.manifest.bytes || super::compute_msg_id(&body) != self.manifest.sha256
+        {
+            return Err("chunks do not match their manifest".into());
+        }
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_and_assemble() {
+        let body: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        assert!(split(&body, 0).is_none());
+        assert!(split(&body, 1 << 20).is_none());
+
+        let (manifest, chunks) = split(&body, 4096).unwrap();
+        assert_eq!(manifest.chunks, 4);
+        assert_eq!(manifest.bytes, body.len());
+        assert!(chunks.iter().all(|c| c.len() <= 4096 - MESSAGE_OVERHEAD));
+
+        // Arrival order does not matter, and a redelivered chunk is harmless.
+        let mut a = Assembler::new(manifest.clone(), body.len()).unwrap();
+        for (n, c) in chunks.iter().enumerate().rev() {
+            a.add(n, c);
+        }
+        a.add(0, chunks[0]);
+        a.add(99, b"stray");
+        assert!(a.is_complete());
+        assert_eq!(a.finish().unwrap(), body);
+
+        let mut short = Assembler::new(manifest.clone(), body.len()).unwrap();
+        short.add(0, chunks[0]);
+        assert!(short.finish().is_err());
+        let mut bad = Assembler::new(manifest.clone(), body.len()).unwrap();
+        for n in 0..chunks.len() {
+            bad.add(n, chunks[0]);
+        }
+        assert!(bad.finish().is_err());
+        assert!(Assembler::new(manifest, 100).is_err());
+    }
+
+    #[test]
+    fn test_chunk_subjects() {
+        let s = chunk_subject("run.res.r_1", 3);
+        assert_eq!(s, "run.res.r_1.chunk.3");
+        assert_eq!(chunk_index(&s), Some(3));
+        assert_eq!(chunk_index("run.res.r_1"), None);
+    }
+}
@@ -0,0 +1,330 @@
+//! Settings of the JetStream consumer (`magicrune consume`).
+//!
+//! Every setting is taken from its flag, else from its environment
+//! variable, else from its default; see [`SETTINGS`]. A value that does not
+//! parse is an error rather than a silent fallback to the default, and
+//! [`ConsumerConfig::validate`] checks the whole against the policy before
+//! the consumer connects. `consume --show-config` prints the result.
+//!
+//! While a command runs, the consumer sends an in-progress acknowledgement
+//! every [`ConsumerConfig::progress_interval`], which restarts the server's
+//! ack wait: a run is not redelivered for outlasting `ack_wait_sec`, only
+//! once its consumer has stopped answering for that long.
+
+use super::subjects::Subjects;
+use crate::faults::FaultPlan;
+use crate::policy::PolicyLimits;
+use serde::Serialize;
+use std::time::Duration;
+
+/// Flag, environment variable and default of each setting.
+pub const SETTINGS: &[(&str, &str, &str)] = &[
+    ("--url", "NATS_URL", "127.0.0.1:4222"),
+    ("--subject-prefix", "MAGICRUNE_SUBJECT_PREFIX", "run"),
+    ("--result-routing", "MAGICRUNE_RESULT_ROUTING", "run"),
+    // Empty: `<prefix>.req.default`.
+    ("--subject", "NATS_REQ_SUBJ", ""),
+    ("--stream", "NATS_STREAM", "RUN"),
+    ("--dup-window-sec", "NATS_DUP_WINDOW_SEC", "120"),
+    ("--durable", "NATS_DURABLE", "RUN_WORKER"),
+    ("--max-ack-pending", "NATS_MAX_ACK_PENDING", "2048"),
+    ("--ack-wait-sec", "NATS_ACK_WAIT_SEC", "30"),
+    ("--max-deliver", "NATS_CONSUMER_MAX_DELIVER", ""),
+    ("--dedupe-max", "MAGICRUNE_DEDUPE_MAX", "1024"),
+    ("--ack-ack-wait-sec", "ACK_ACK_WAIT_SEC", "2"),
+    ("--labels", "MAGICRUNE_RUNNER_LABELS", ""),
+    ("--mode", "MAGICRUNE_CONSUME_MODE", "pull"),
+];
+
+/// Where `consume` takes requests from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsumeMode {
+    /// A durable JetStream pull consumer, created with its stream when
+    /// missing. Without JetStream on the server, a core subscription.
+    #[default]
+    Pull,
+    /// A core NATS subscription on the subject: no stream, no redelivery,
+    /// and results go to the request's reply subject when it has one.
+    Core,
+}
+
+impl std::str::FromStr for ConsumeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "pull" => Ok(Self::Pull),
+            "core" => Ok(Self::Core),
+            other => Err(format!("--mode: invalid value {:?} (pull or core)", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConsumerConfig {
+    /// NATS server, `host:port`.
+    pub url: String,
+    /// How request, result and ack subjects are formed.
+    pub subjects: Subjects,
+    /// Subject this consumer takes requests from.
+    pub subject: String,
+    pub stream: String,
+    /// Subjects the stream is created with: the request subject, or every
+    /// shard when it is a shard subject.
+    pub stream_subjects: Vec<String>,
+    /// Window in which the stream drops messages with a known `Nats-Msg-Id`.
+    pub duplicate_window_sec: u64,
+    /// Durable consumer name, suffixed with the instance id.
+    pub durable: String,
+    /// Subject the durable is filtered on; empty when it takes the stream.
+    pub filter_subject: String,
+    /// Unacknowledged messages in flight; -1 for no limit.
+    pub max_ack_pending: i64,
+    /// Time after which an unacknowledged message is redelivered.
+    pub ack_wait_sec: u64,
+    /// Deliveries of a message before JetStream gives up on it; server
+    /// default when unset, -1 for no limit.
+    pub max_deliver: Option<i64>,
+    /// Run ids remembered to drop redeliveries of finished runs.
+    pub dedupe_max: usize,
+    /// Time to wait for the acknowledgement of a published result.
+    pub ack_ack_wait_sec: u64,
+    pub instance_id: Option<String>,
+    /// Runner labels advertised (see `crate::instance`).
+    pub labels: Vec<String>,
+    pub mode: ConsumeMode,
+    /// Faults injected by chaos tests; empty otherwise.
+    pub faults: FaultPlan,
+}
+
+impl ConsumerConfig {
+    /// Settings from `args`, then the environment, then the defaults.
+    pub fn from_args(args: &[String], instance: Option<&str>) -> Result<Self, String> {
+        Self::resolve(args, instance, |key| std::env::var(key).ok())
+    }
+
+    fn resolve(
+        args: &[String],
+        instance: Option<&str>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, String> {
+        let flag = |name: &str| {
+            args.iter()
+                .position(|a| a == name)
+                .map(|i| {
+                    args.get(i + 1)
+                        .cloned()
+                        .ok_or(format!("{} needs a value", name))
+                })
+                .transpose()
+        };
+        let get = |name: &str| -> Result<String, String> {
+            let (_, key, default) = SETTINGS.iter().find(|s| s.0 == name).unwrap();
+            Ok(flag(name)?
+                .or_else(|| env(key).filter(|v| !v.is_empty()))
+                .unwrap_or_else(|| default.to_string()))
+        };
+        fn num<T: std::str::FromStr>(name: &str, value: String) -> Result<T, String> {
+            value
+                .trim()
+                .parse()
+                .map_err(|_| format!("{}: invalid value {:?}", name, value))
+        }
+        let subjects = Subjects::new(&get("--subject-prefix")?, get("--result-routing")?.parse()?)?;
+        // `--shard <k>` stands for `--subject <prefix>.req.shard.<k>`.
+        let subject = match flag("--shard")? {
+            Some(k) => subjects.shard(num("--shard", k)?),
+            None => Some(get("--subject")?)
+                .filter(|s| !s.is_empty())
+                .unwrap_or_else(|| subjects.request(None)),
+        };
+        let sharded = subjects.parse_shard(&subject).is_some();
+        let max_deliver = get("--max-deliver")?;
+        Ok(Self {
+            url: get("--url")?,
+            stream_subjects: vec![if sharded {
+                subjects.shard_wildcard()
+            } else {
+                subject.clone()
+            }],
+            // A shard's stream covers every shard; its durable only takes
+            // this one.
+            filter_subject: if sharded {
+                subject.clone()
+            } else {
+                String::new()
+            },
+            subject,
+            subjects,
+            stream: get("--stream")?,
+            duplicate_window_sec: num("--dup-window-sec", get("--dup-window-sec")?)?,
+            durable: crate::instance::durable_name(&get("--durable")?, instance),
+            max_ack_pending: num("--max-ack-pending", get("--max-ack-pending")?)?,
+            ack_wait_sec: num("--ack-wait-sec", get("--ack-wait-sec")?)?,
+            max_deliver: if max_deliver.is_empty() {
+                None
+            } else {
+                Some(num("--max-deliver", max_deliver)?)
+            },
+            dedupe_max: num("--dedupe-max", get("--dedupe-max")?)?,
+            ack_ack_wait_sec: num("--ack-ack-wait-sec", get("--ack-ack-wait-sec")?)?,
+            instance_id: instance.map(str::to_string),
+            labels: crate::instance::parse_labels(&get("--labels")?)?,
+            mode: get("--mode")?.parse()?,
+            faults: FaultPlan::resolve(args, &env)?,
+        })
+    }
+
+    /// Time between two in-progress acknowledgements of a running message:
+    /// a third of the ack wait, so that one lost on the way is not enough
+    /// for a redelivery.
+    pub fn progress_interval(&self) -> Duration {
+        Duration::from_secs(self.ack_wait_sec) / 3
+    }
+
+    /// Check the settings against each other and the policy's `limits`.
+    /// Every problem found is reported, separated by `; `.
+    pub fn validate(&self, limits: &PolicyLimits) -> Result<(), String> {
+        let mut problems = Vec::new();
+        for (what, name) in [("stream", &self.stream), ("durable", &self.durable)] {
+            if name.is_empty()
+                || name
+                    .chars()
+                    .any(|c| c.is_whitespace() || matches!(c, '.' | '*' | '>' | '/' | '\\'))
+            {
+                problems.push(format!("invalid {} name {:?}", what, name));
+            }
+        }
+        if self.subject.is_empty() || self.subject.chars().any(char::is_whitespace) {
+            problems.push(format!("invalid subject {:?}", self.subject));
+        }
+        if self.duplicate_window_sec == 0 {
+            problems.push("dup_window_sec must be at least 1".into());
+        }
+        if self.max_ack_pending == 0 || self.max_ack_pending < -1 {
+            problems.push("max_ack_pending must be at least 1, or -1".into());
+        }
+        if matches!(self.max_deliver, Some(n) if n == 0 || n < -1) {
+            problems.push("max_deliver must be at least 1, or -1".into());
+        }
+        if self.dedupe_max == 0 {
+            problems.push("dedupe_max must be at least 1".into());
+        }
+        if self.ack_ack_wait_sec == 0 {
+            problems.push("ack_ack_wait_sec must be at least 1".into());
+        }
+        // Runs are kept reserved by progress acknowledgements, but a policy
+        // whose runs may not even fit in one ack wait is a misconfiguration.
+        let longest_ms = limits.wall_sec * 1000 + limits.kill_grace_ms;
+        if self.ack_wait_sec * 1000 <= longest_ms {
+            problems.push(format!(
+                "ack_wait_sec {} must exceed the policy's wall_sec {} plus kill_grace_ms {}",
+                self.ack_wait_sec, limits.wall_sec, limits.kill_grace_ms
+            ));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("; "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(args: &[&str], env: &[(&str, &str)]) -> Result<ConsumerConfig, String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        ConsumerConfig::resolve(&args, Some("w-1"), |key| {
+            env.iter().find(|e| e.0 == key).map(|e| e.1.to_string())
+        })
+    }
+
+    #[test]
+    fn test_flags_over_env_over_defaults() {
+        let cfg = resolve(
+            &["consume", "--ack-wait-sec", "90", "--shard", "2"],
+            &[("NATS_ACK_WAIT_SEC", "45"), ("NATS_STREAM", "JOBS")],
+        )
+        .unwrap();
+        assert_eq!(cfg.ack_wait_sec, 90);
+        assert_eq!(cfg.progress_interval(), Duration::from_secs(30));
+        assert_eq!(cfg.stream, "JOBS");
+        assert_eq!(cfg.max_ack_pending, 2048);
+        assert_eq!(cfg.max_deliver, None);
+        assert_eq!(cfg.durable, "RUN_WORKER_w-1");
+        assert_eq!(cfg.subject, "run.req.shard.2");
+        assert_eq!(cfg.filter_subject, "run.req.shard.2");
+        assert_eq!(cfg.stream_subjects, ["run.req.shard.*"]);
+        assert!(cfg.labels.is_empty());
+
+        let cfg = resolve(
+            &["consume"],
+            &[
+                ("NATS_CONSUMER_MAX_DELIVER", "5"),
+                ("MAGICRUNE_RUNNER_LABELS", "gpu,eu-west"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(cfg.max_deliver, Some(5));
+        assert_eq!(cfg.labels, ["gpu", "eu-west"]);
+        assert_eq!(cfg.filter_subject, "");
+        assert_eq!(cfg.stream_subjects, ["run.req.default"]);
+
+        let cfg = resolve(
+            &["consume", "--shard", "1", "--result-routing", "tenant"],
+            &[("MAGICRUNE_SUBJECT_PREFIX", "prod.mr")],
+        )
+        .unwrap();
+        assert_eq!(cfg.subject, "prod.mr.req.shard.1");
+        assert_eq!(cfg.stream_subjects, ["prod.mr.req.shard.*"]);
+        assert_eq!(cfg.subjects.result("r_1", "acme"), "prod.mr.res.acme.r_1");
+        assert!(resolve(&["consume", "--result-routing", "inbox"], &[]).is_err());
+        assert_eq!(cfg.mode, ConsumeMode::Pull);
+
+        assert!(cfg.faults.is_empty());
+        let cfg = resolve(&["consume", "--mode", "core"], &[]).unwrap();
+        assert_eq!(cfg.mode, ConsumeMode::Core);
+        assert!(resolve(&["consume", "--mode", "push"], &[]).is_err());
+        assert!(resolve(&["consume", "--test-delay-ms-jitter", "9..1"], &[]).is_err());
+
+        assert_eq!(
+            resolve(&["consume"], &[("NATS_MAX_ACK_PENDING", "lots")]).unwrap_err(),
+            "--max-ack-pending: invalid value \"lots\""
+        );
+        assert_eq!(
+            resolve(&["consume", "--durable"], &[]).unwrap_err(),
+            "--durable needs a value"
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let limits = PolicyLimits {
+            wall_sec: 30,
+            ..Default::default()
+        };
+        let cfg = resolve(&["consume", "--ack-wait-sec", "31"], &[]).unwrap();
+        assert!(cfg.validate(&limits).is_ok());
+        let graced = PolicyLimits {
+            kill_grace_ms: 1000,
+            ..limits
+        };
+        assert_eq!(
+            cfg.validate(&graced).unwrap_err(),
+            "ack_wait_sec 31 must exceed the policy's wall_sec 30 plus kill_grace_ms 1000"
+        );
+        let bad = ConsumerConfig {
+            durable: "a.b".into(),
+            max_ack_pending: 0,
+            max_deliver: Some(-1),
+            ..cfg
+        };
+        assert_eq!(
+            bad.validate(&limits).unwrap_err(),
+            "invalid durable name \"a.b\"; max_ack_pending must be at least 1, or -1"
+        );
+    }
+}
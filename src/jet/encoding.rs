@@ -0,0 +1,167 @@
+//! Compressed NATS payloads.
+//!
+//! A result with a large stdout can outgrow the server's `max_payload`
+//! (1 MiB by default). Bodies of at least `MAGICRUNE_COMPRESS_MIN_BYTES`
+//! (64 KiB by default, `0` turns compression off) are sent zstd-compressed
+//! with a `Content-Encoding: zstd` header, at `MAGICRUNE_COMPRESS_LEVEL`.
+//!
+//! Compression is negotiated per message. A producer that reads compressed
+//! results says so with `Accept-Encoding: zstd` on its request, and only
+//! then is its result compressed; results for other producers go out as
+//! before. Consumers read compressed requests, so `js_publish` compresses
+//! large ones. A body is never sent compressed when that does not make it
+//! smaller. Request bundles already travel through the object store, which
+//! has no payload limit.
+//!
+//! Decoding stops at `MAGICRUNE_DECOMPRESS_MAX_BYTES` (64 MiB by default),
+//! so a small message cannot expand without bound.
+
+use std::borrow::Cow;
+
+pub const CONTENT_ENCODING: &str = "Content-Encoding";
+pub const ACCEPT_ENCODING: &str = "Accept-Encoding";
+pub const CONTENT_TYPE: &str = "Content-Type";
+pub const ZSTD: &str = "zstd";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    /// Smallest body compressed; 0 never compresses.
+    pub min_bytes: usize,
+    /// zstd level, 1 (fastest) to 22.
+    pub level: i32,
+    /// Largest body a compressed message may decode to.
+    pub max_decoded: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            min_bytes: 64 * 1024,
+            level: 3,
+            max_decoded: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl Compression {
+    /// Defaults overridden by `MAGICRUNE_COMPRESS_MIN_BYTES`,
+    /// `MAGICRUNE_COMPRESS_LEVEL` and `MAGICRUNE_DECOMPRESS_MAX_BYTES`.
+    pub fn from_env() -> Result<Self, String> {
+        let d = Self::default();
+        let num = |key: &str| -> Result<Option<u64>, String> {
+            match std::env::var(key) {
+                Ok(v) if !v.trim().is_empty() => v
+                    .trim()
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| format!("{}: {:?} is not a number", key, v)),
+                _ => Ok(None),
+            }
+        };
+        let level = match num("MAGICRUNE_COMPRESS_LEVEL")? {
+            None => d.level,
+            Some(l @ 1..=22) => l as i32,
+            Some(l) => {
+                return Err(format!(
+                    "MAGICRUNE_COMPRESS_LEVEL: {} is not a zstd level (1-22)",
+                    l
+                ))
+            }
+        };
+        Ok(Self {
+            min_bytes: num("MAGICRUNE_COMPRESS_MIN_BYTES")?.map_or(d.min_bytes, |n| n as usize),
+            level,
+            max_decoded: num("MAGICRUNE_DECOMPRESS_MAX_BYTES")?
+                .map_or(d.max_decoded, |n| n as usize),
+        })
+    }
+
+    /// `body` as sent to a receiver that reads zstd when `accepts_zstd`:
+    /// the bytes, and the `Content-Encoding` to send them with, if any.
+    pub fn encode(&self, body: Vec<u8>, accepts_zstd: bool) -> (Vec<u8>, Option<&'static str>) {
+        if !accepts_zstd || self.min_bytes == 0 || body.len() < self.min_bytes {
+            return (body, None);
+        }
+        match zstd::bulk::compress(&body, self.level) {
+            Ok(packed) if packed.len() < body.len() => (packed, Some(ZSTD)),
+            _ => (body, None),
+        }
+    }
+
+    /// The body of a message sent with `encoding` (its `Content-Encoding`).
+    pub fn decode<'a>(
+        &self,
+        body: &'a [u8],
+        encoding: Option<&str>,
+    ) -> Result<Cow<'a, [u8]>, String> {
+        match encoding.map(str::trim).filter(|e| !e.is_empty()) {
+            None | Some("identity") => Ok(Cow::Borrowed(body)),
+            Some(e) if e.eq_ignore_ascii_case(ZSTD) => {
+                zstd::bulk::decompress(body, self.max_decoded)
+                    .map(Cow::Owned)
+                    .map_err(|e| {
+                        format!(
+                            "zstd body does not decode within {} bytes: {}",
+                            self.max_decoded, e
+                        )
+                    })
+            }
+            Some(e) => Err(format!("unsupported Content-Encoding {:?}", e)),
+        }
+    }
+}
+
+/// Whether an `Accept-Encoding` value lists zstd.
+pub fn accepts_zstd(accept: Option<&str>) -> bool {
+    accept.is_some_and(|v| {
+        v.split(',')
+            .map(|e| e.split(';').next().unwrap_or_default().trim())
+            .any(|e| e.eq_ignore_ascii_case(ZSTD))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip_and_negotiation() {
+        let c = Compression {
+            min_bytes: 1024,
+            ..Default::default()
+        };
+        let big = serde_json::json!({ "stdout_b64": "A".repeat(100_000) })
+            .to_string()
+            .into_bytes();
+        let (packed, encoding) = c.encode(big.clone(), true);
+        assert_eq!(encoding, Some(ZSTD));
+        assert!(packed.len() < big.len() / 10);
+        assert_eq!(c.decode(&packed, encoding).unwrap(), big);
+
+        assert_eq!(c.encode(big.clone(), false), (big.clone(), None));
+        assert_eq!(c.encode(b"{}".to_vec(), true), (b"{}".to_vec(), None));
+        let off = Compression { min_bytes: 0, ..c };
+        assert_eq!(off.encode(big.clone(), true).1, None);
+        // Random bytes do not shrink, so they go out as they are.
+        let mut noise = vec![0u8; 4096];
+        getrandom::getrandom(&mut noise).unwrap();
+        assert_eq!(c.encode(noise.clone(), true), (noise, None));
+
+        assert_eq!(c.decode(b"{}", None).unwrap(), &b"{}"[..]);
+        assert!(c.decode(b"{}", Some("gzip")).is_err());
+        assert!(c.decode(b"not zstd", Some("zstd")).is_err());
+        let small = Compression {
+            max_decoded: 1000,
+            ..c
+        };
+        assert!(small.decode(&packed, Some("zstd")).is_err());
+    }
+
+    #[test]
+    fn test_accepts_zstd() {
+        assert!(accepts_zstd(Some("zstd")));
+        assert!(accepts_zstd(Some("gzip, ZSTD;q=0.9")));
+        assert!(!accepts_zstd(Some("gzip")));
+        assert!(!accepts_zstd(None));
+    }
+}
@@ -0,0 +1,261 @@
+//! What `js_publish` is asked to do and what it reports.
+//!
+//! `js_publish` publishes every request it is given, at most
+//! `--concurrency` at a time. Publishing is retried on transient errors
+//! with [`backoff`], and each request then waits up to `--timeout` for its
+//! own result. Every input ends up as one [`Outcome`]. The process exits
+//! with [`exit_code`]: 0 when every request got a result, 1 when any did
+//! not, and 4 when it could not start at all.
+
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Exit code when some request got no result.
+pub const EXIT_FAILED: i32 = 1;
+/// Exit code for bad arguments or no connection.
+pub const EXIT_SETUP: i32 = 4;
+
+pub const USAGE: &str = "Usage: js_publish [--subject <subj>] [--concurrency <n>] [--retries <n>] [--timeout <secs>] [--json] <request.json | bundle.spell.tgz | dir>...";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublishArgs {
+    /// Files as given, directories expanded (see [`expand_inputs`]).
+    pub files: Vec<PathBuf>,
+    /// Subject for every request; picked per request when unset.
+    pub subject: Option<String>,
+    /// Requests in flight at once.
+    pub concurrency: usize,
+    /// Publish attempts after the first, on transient errors.
+    pub retries: u32,
+    /// Wait for each result, counted from its own publish.
+    pub timeout: Duration,
+    /// One JSON summary on stdout instead of the results.
+    pub json: bool,
+}
+
+impl PublishArgs {
+    /// Parse the arguments after the program name. `--timeout` defaults to
+    /// `JS_PUBLISH_TIMEOUT_SEC`, then 5 seconds. As before, a second plain
+    /// argument that is not a file or directory is the subject.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let timeout_env = std::env::var("JS_PUBLISH_TIMEOUT_SEC").ok();
+        let mut out = Self {
+            files: Vec::new(),
+            subject: None,
+            concurrency: 4,
+            retries: 3,
+            timeout: Duration::from_secs(timeout_env.and_then(|s| s.parse().ok()).unwrap_or(5)),
+            json: false,
+        };
+        let mut plain = Vec::new();
+        let mut it = args.iter();
+        while let Some(a) = it.next() {
+            let mut value = |name: &str| {
+                it.next()
+                    .cloned()
+                    .ok_or_else(|| format!("{} needs a value", name))
+            };
+            fn num<T: std::str::FromStr>(name: &str, v: String) -> Result<T, String> {
+                v.parse()
+                    .map_err(|_| format!("{}: invalid value {:?}", name, v))
+            }
+            match a.as_str() {
+                "--subject" => out.subject = Some(value(a)?),
+                "--concurrency" => out.concurrency = num(a, value(a)?)?,
+                "--retries" => out.retries = num(a, value(a)?)?,
+                "--timeout" => out.timeout = Duration::from_secs(num(a, value(a)?)?),
+                "--json" => out.json = true,
+                flag if flag.starts_with("--") => return Err(format!("unknown flag {}", flag)),
+                _ => plain.push(PathBuf::from(a)),
+            }
+        }
+        if out.concurrency == 0 {
+            return Err("--concurrency must be at least 1".into());
+        }
+        if out.subject.is_none() && plain.len() == 2 && !plain[1].exists() {
+            out.subject = plain.pop().map(|s| s.to_string_lossy().into_owned());
+        }
+        if plain.is_empty() {
+            plain.push(PathBuf::from("samples/ok.json"));
+        }
+        out.files = expand_inputs(&plain);
+        Ok(out)
+    }
+}
+
+/// `paths` with every directory replaced by the requests directly in it
+/// (`*.json` and `*.spell.tgz`), sorted. Anything else is kept as it is,
+/// so that a missing file is reported as that file's failure.
+pub fn expand_inputs(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let is_request = |p: &Path| {
+        let name = p.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        p.is_file() && (name.ends_with(".json") || name.ends_with(".spell.tgz"))
+    };
+    let mut out = Vec::new();
+    for p in paths {
+        match std::fs::read_dir(p) {
+            Ok(entries) => {
+                let mut found: Vec<PathBuf> = entries
+                    .filter_map(|e| e.ok().map(|e| e.path()))
+                    .filter(|p| is_request(p))
+                    .collect();
+                found.sort();
+                out.extend(found);
+            }
+            Err(_) => out.push(p.clone()),
+        }
+    }
+    out
+}
+
+/// Wait before publish attempt `attempt + 1`: 200 ms, doubling, at most 5 s.
+pub fn backoff(attempt: u32) -> Duration {
+    Duration::from_millis(200u64.saturating_mul(1 << attempt.min(5))).min(Duration::from_secs(5))
+}
+
+/// What became of one input.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Outcome {
+    pub file: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub run_id: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub subject: String,
+    /// Publish attempts made.
+    pub attempts: u32,
+    /// The result, as the consumer sent it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    /// Why there is no result.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Outcome {
+    pub fn ok(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// One line for the per-file summary.
+    pub fn line(&self) -> String {
+        match (&self.error, &self.result) {
+            (Some(e), _) => format!("failed {} {}", self.file, e),
+            (None, r) => {
+                let verdict = r
+                    .as_ref()
+                    .and_then(|r| r.get("verdict"))
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("?");
+                format!("ok     {} {} {}", self.file, self.run_id, verdict)
+            }
+        }
+    }
+}
+
+/// `{"ok": n, "failed": n, "files": [...]}`.
+pub fn summary(outcomes: &[Outcome]) -> serde_json::Value {
+    let ok = outcomes.iter().filter(|o| o.ok()).count();
+    serde_json::json!({
+        "ok": ok,
+        "failed": outcomes.len() - ok,
+        "files": outcomes,
+    })
+}
+
+pub fn exit_code(outcomes: &[Outcome]) -> i32 {
+    if outcomes.iter().all(Outcome::ok) {
+        0
+    } else {
+        EXIT_FAILED
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<PublishArgs, String> {
+        PublishArgs::parse(&args.iter().map(|a| a.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let a = parse(&[
+            "--json",
+            "--concurrency",
+            "8",
+            "--timeout",
+            "9",
+            "a.json",
+            "b.json",
+        ])
+        .unwrap();
+        assert!(a.json);
+        assert_eq!((a.concurrency, a.retries), (8, 3));
+        assert_eq!(a.timeout, Duration::from_secs(9));
+        // Neither exists, so the second is the subject, as it used to be.
+        assert_eq!(a.files, [PathBuf::from("a.json")]);
+        assert_eq!(a.subject.as_deref(), Some("b.json"));
+        let a = parse(&["--subject", "run.req.x", "a.json", "b.json"]).unwrap();
+        assert_eq!(a.files.len(), 2);
+        assert_eq!(
+            parse(&[]).unwrap().files,
+            [PathBuf::from("samples/ok.json")]
+        );
+        assert_eq!(
+            parse(&["--retries"]).unwrap_err(),
+            "--retries needs a value"
+        );
+        assert!(parse(&["--concurrency", "0"]).is_err());
+        assert!(parse(&["--bogus"]).is_err());
+    }
+
+    #[test]
+    fn test_expand_inputs() {
+        let dir = std::env::temp_dir().join(format!("mr_js_publish_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in ["b.json", "a.spell.tgz", "notes.txt"] {
+            std::fs::write(dir.join(name), b"{}").unwrap();
+        }
+        let missing = PathBuf::from("no/such.json");
+        assert_eq!(
+            expand_inputs(&[dir.clone(), missing.clone()]),
+            [dir.join("a.spell.tgz"), dir.join("b.json"), missing]
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_backoff_and_summary() {
+        assert_eq!(backoff(0), Duration::from_millis(200));
+        assert_eq!(backoff(2), Duration::from_millis(800));
+        assert_eq!(backoff(40), Duration::from_secs(5));
+        let ok = Outcome {
+            file: "a.json".into(),
+            run_id: "r_1".into(),
+            attempts: 1,
+            result: Some(serde_json::json!({"verdict": "green"})),
+            ..Default::default()
+        };
+        let failed = Outcome {
+            file: "b.json".into(),
+            attempts: 4,
+            error: Some("timeout waiting for run.res.r_2".into()),
+            ..Default::default()
+        };
+        assert_eq!(ok.line(), "ok     a.json r_1 green");
+        assert_eq!(
+            failed.line(),
+            "failed b.json timeout waiting for run.res.r_2"
+        );
+        assert_eq!(exit_code(std::slice::from_ref(&ok)), 0);
+        let both = [ok, failed];
+        assert_eq!(exit_code(&both), EXIT_FAILED);
+        let s = summary(&both);
+        assert_eq!((s["ok"].as_u64(), s["failed"].as_u64()), (Some(1), Some(1)));
+        assert_eq!(s["files"][1]["error"], "timeout waiting for run.res.r_2");
+        assert!(s["files"][1].get("result").is_none());
+    }
+}
@@ -0,0 +1,188 @@
+//! NATS subjects.
+//!
+//! Every subject is built here from a [`Subjects`] config, under a prefix
+//! (`MAGICRUNE_SUBJECT_PREFIX`, `run` by default):
+//! - requests on `<prefix>.req.<tenant>`, `<prefix>.req.default` when the
+//!   subject binds no tenant, or `<prefix>.req.shard.<k>` (see
+//!   `crate::instance`);
+//! - results on `<prefix>.res.<run_id>`, and their acknowledgements on
+//!   `<prefix>.ack.<run_id>`. With `MAGICRUNE_RESULT_ROUTING=tenant` both
+//!   carry the run's tenant, `<prefix>.res.<tenant>.<run_id>`, so NATS
+//!   permissions can confine a tenant to its own results.
+//!
+//! A request may instead name where its result goes. A core NATS request
+//! sent with a reply subject (`Client::request`) is answered there, and a
+//! JetStream request, whose reply subject the stream does not keep, can
+//! carry it in a `Magicrune-Reply-To` header. Such a result is not
+//! acknowledged, so the consumer does not wait for an ack.
+
+use serde::Serialize;
+use std::str::FromStr;
+
+/// Environment variable holding the subject prefix.
+pub const PREFIX_ENV: &str = "MAGICRUNE_SUBJECT_PREFIX";
+
+/// Environment variable holding the [`ResultRouting`].
+pub const ROUTING_ENV: &str = "MAGICRUNE_RESULT_ROUTING";
+
+/// Header naming where the result of a JetStream request goes.
+pub const REPLY_HEADER: &str = "Magicrune-Reply-To";
+
+/// How result and ack subjects are formed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResultRouting {
+    /// `<prefix>.res.<run_id>`
+    #[default]
+    Run,
+    /// `<prefix>.res.<tenant>.<run_id>`
+    Tenant,
+}
+
+impl FromStr for ResultRouting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "run" => Ok(Self::Run),
+            "tenant" => Ok(Self::Tenant),
+            other => Err(format!(
+                "invalid result routing {:?} (expected run or tenant)",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Subjects {
+    pub prefix: String,
+    pub results: ResultRouting,
+}
+
+impl Default for Subjects {
+    fn default() -> Self {
+        Self {
+            prefix: "run".into(),
+            results: ResultRouting::Run,
+        }
+    }
+}
+
+impl Subjects {
+    /// Subjects under `prefix`: one or more `.`-separated tokens, without
+    /// wildcards or whitespace.
+    pub fn new(prefix: &str, results: ResultRouting) -> Result<Self, String> {
+        let ok = prefix.split('.').all(|t| {
+            !t.is_empty()
+                && !t
+                    .chars()
+                    .any(|c| c.is_whitespace() || matches!(c, '*' | '>'))
+        });
+        if !ok {
+            return Err(format!("invalid subject prefix {:?}", prefix));
+        }
+        Ok(Self {
+            prefix: prefix.to_string(),
+            results,
+        })
+    }
+
+    /// Subjects from [`PREFIX_ENV`] and [`ROUTING_ENV`], defaults when unset.
+    pub fn from_env() -> Result<Self, String> {
+        let var = |key| std::env::var(key).ok().filter(|v| !v.is_empty());
+        Self::new(
+            var(PREFIX_ENV).as_deref().unwrap_or("run"),
+            var(ROUTING_ENV).map_or(Ok(ResultRouting::Run), |r| r.parse())?,
+        )
+    }
+
+    /// Request subject of `tenant`, or the shared one.
+    pub fn request(&self, tenant: Option<&str>) -> String {
+        format!(
+            "{}.req.{}",
+            self.prefix,
+            tenant.unwrap_or(crate::tenant::DEFAULT_TENANT)
+        )
+    }
+
+    /// Request subject of shard `k`.
+    pub fn shard(&self, k: u32) -> String {
+        format!("{}.req.shard.{}", self.prefix, k)
+    }
+
+    /// Stream subject covering every shard.
+    pub fn shard_wildcard(&self) -> String {
+        format!("{}.req.shard.*", self.prefix)
+    }
+
+    /// Shard number of a shard subject.
+    pub fn parse_shard(&self, subject: &str) -> Option<u32> {
+        subject
+            .strip_prefix(&self.prefix)?
+            .strip_prefix(".req.shard.")?
+            .parse()
+            .ok()
+    }
+
+    /// Subject the result of `run_id` is published on.
+    pub fn result(&self, run_id: &str, tenant: &str) -> String {
+        self.for_run("res", run_id, tenant)
+    }
+
+    /// Subject the requester acknowledges the result of `run_id` on.
+    pub fn ack(&self, run_id: &str, tenant: &str) -> String {
+        self.for_run("ack", run_id, tenant)
+    }
+
+    /// Where the result of `run_id` goes: the request's reply subject if it
+    /// named one, else [`Subjects::result`].
+    pub fn reply_to(&self, reply: Option<&str>, run_id: &str, tenant: &str) -> String {
+        match reply {
+            Some(r) => r.to_string(),
+            None => self.result(run_id, tenant),
+        }
+    }
+
+    fn for_run(&self, kind: &str, run_id: &str, tenant: &str) -> String {
+        match self.results {
+            ResultRouting::Run => format!("{}.{}.{}", self.prefix, kind, run_id),
+            ResultRouting::Tenant => format!("{}.{}.{}.{}", self.prefix, kind, tenant, run_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_subjects() {
+        let s = Subjects::default();
+        assert_eq!(s.request(None), "run.req.default");
+        assert_eq!(s.request(Some("acme")), "run.req.acme");
+        assert_eq!(s.shard(3), "run.req.shard.3");
+        assert_eq!(s.parse_shard("run.req.shard.3"), Some(3));
+        assert_eq!(s.parse_shard(&s.shard_wildcard()), None);
+        assert_eq!(s.parse_shard("run.req.default"), None);
+        assert_eq!(s.result("r_1", "acme"), "run.res.r_1");
+        assert_eq!(s.ack("r_1", "acme"), "run.ack.r_1");
+        assert_eq!(s.reply_to(None, "r_1", "acme"), "run.res.r_1");
+        assert_eq!(s.reply_to(Some("_INBOX.x"), "r_1", "acme"), "_INBOX.x");
+    }
+
+    #[test]
+    fn test_prefix_and_tenant_routing() {
+        let s = Subjects::new("prod.magicrune", "tenant".parse().unwrap()).unwrap();
+        assert_eq!(s.request(Some("acme")), "prod.magicrune.req.acme");
+        assert_eq!(s.parse_shard("prod.magicrune.req.shard.2"), Some(2));
+        assert_eq!(s.parse_shard("run.req.shard.2"), None);
+        assert_eq!(s.result("r_1", "acme"), "prod.magicrune.res.acme.r_1");
+        assert_eq!(s.ack("r_1", "acme"), "prod.magicrune.ack.acme.r_1");
+
+        for bad in ["", "a..b", "run.*", "run.>", "a b"] {
+            assert!(Subjects::new(bad, ResultRouting::Run).is_err(), "{:?}", bad);
+        }
+        assert!("subject".parse::<ResultRouting>().is_err());
+    }
+}
@@ -0,0 +1,392 @@
+//! Signing keys: loading, key ids and rotation.
+//!
+//! A [`Keyring`] holds Ed25519 keys with optional validity windows. Results
+//! are signed with the newest key whose window covers the current time, and
+//! a signature is accepted from any key in the ring that has not expired, so
+//! a rotation publishes the new key with a `not_before` while the old key's
+//! `not_after` is left a little later; both verify during the overlap.
+//!
+//! Every key is named by its key id, the first 16 hex digits of the SHA-256
+//! of the raw public key. Signatures carry it as the JWS `kid`.
+//!
+//! Sources, first match wins ([`Keyring::from_env`]):
+//!
+//! - `MAGICRUNE_KEY_COMMAND`: a plugin command (run with `sh -c`) that
+//!   prints a keyring manifest with inline `pem`, e.g. a KMS or vault client
+//! - `MAGICRUNE_KEYRING`: a manifest file, or a single PEM key
+//! - `MAGICRUNE_SIGNING_KEY`: a single PKCS#8 PEM key file
+//! - `MAGICRUNE_SIGNING_KEY_PEM`: the PEM text itself
+//!
+//! A manifest is a JSON array of
+//! `{"file" | "pem": .., "not_before"?: unix_secs, "not_after"?: unix_secs}`;
+//! relative files are resolved against the manifest's directory. Entries may
+//! be private (PKCS#8) or public (SPKI) keys; public ones only verify.
+//!
+//! Producer keys, trusted to sign requests, are a separate ring read from
+//! `MAGICRUNE_PRODUCER_KEYS` (a manifest or a single PEM key).
+
+use crate::schema::SpellResult;
+use crate::signing::{self, SignatureError};
+use ed25519_dalek::pkcs8::spki::der::pem::LineEnding;
+use ed25519_dalek::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("keys: {0}")]
+pub struct KeyError(pub String);
+
+impl KeyError {
+    pub fn new(e: impl std::fmt::Display) -> Self {
+        KeyError(e.to_string())
+    }
+}
+
+/// Key id of a public key.
+pub fn key_id(key: &VerifyingKey) -> String {
+    use sha2::{Digest, Sha256};
+    let digest = Sha256::digest(key.as_bytes());
+    digest[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// One key in a [`Keyring`].
+#[derive(Debug, Clone)]
+pub struct KeyEntry {
+    pub kid: String,
+    /// `None` for verify-only keys.
+    pub signing: Option<SigningKey>,
+    pub verifying: VerifyingKey,
+    pub not_before: Option<u64>,
+    pub not_after: Option<u64>,
+}
+
+/// Where a key stands at a given time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyStatus {
+    Pending,
+    Valid,
+    Expired,
+}
+
+impl KeyStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            KeyStatus::Pending => "pending",
+            KeyStatus::Valid => "valid",
+            KeyStatus::Expired => "expired",
+        }
+    }
+}
+
+impl KeyEntry {
+    /// Parse a PEM key: PKCS#8 private keys sign, SPKI public keys only verify.
+    pub fn from_pem(pem: &str) -> Result<Self, KeyError> {
+        let (signing, verifying) = if pem.contains("PRIVATE KEY") {
+            let sk = SigningKey::from_pkcs8_pem(pem).map_err(KeyError::new)?;
+            let vk = sk.verifying_key();
+            (Some(sk), vk)
+        } else {
+            (
+                None,
+                VerifyingKey::from_public_key_pem(pem).map_err(KeyError::new)?,
+            )
+        };
+        Ok(Self {
+            kid: key_id(&verifying),
+            signing,
+            verifying,
+            not_before: None,
+            not_after: None,
+        })
+    }
+
+    pub fn status(&self, now: u64) -> KeyStatus {
+        if self.not_before.map(|t| now < t).unwrap_or(false) {
+            KeyStatus::Pending
+        } else if self.not_after.map(|t| now >= t).unwrap_or(false) {
+            KeyStatus::Expired
+        } else {
+            KeyStatus::Valid
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ManifestEntry {
+    #[serde(default)]
+    file: Option<String>,
+    #[serde(default)]
+    pem: Option<String>,
+    #[serde(default)]
+    not_before: Option<u64>,
+    #[serde(default)]
+    not_after: Option<u64>,
+}
+
+/// A set of keys with validity windows.
+#[derive(Debug, Clone, Default)]
+pub struct Keyring {
+    keys: Vec<KeyEntry>,
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Keyring {
+    pub fn new(keys: Vec<KeyEntry>) -> Self {
+        Self { keys }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn keys(&self) -> &[KeyEntry] {
+        &self.keys
+    }
+
+    /// Parse a manifest (see the module docs); `base` resolves relative files.
+    pub fn from_manifest(json: &str, base: &Path) -> Result<Self, KeyError> {
+        let entries: Vec<ManifestEntry> = serde_json::from_str(json).map_err(KeyError::new)?;
+        let mut keys = Vec::new();
+        for e in entries {
+            let pem = match (e.pem, e.file) {
+                (Some(pem), _) => pem,
+                (None, Some(file)) => {
+                    let p = base.join(&file);
+                    std::fs::read_to_string(&p)
+                        .map_err(|err| KeyError(format!("{}: {}", p.display(), err)))?
+                }
+                (None, None) => return Err(KeyError("manifest entry needs file or pem".into())),
+            };
+            let mut entry = KeyEntry::from_pem(&pem)?;
+            entry.not_before = e.not_before;
+            entry.not_after = e.not_after;
+            keys.push(entry);
+        }
+        Ok(Self { keys })
+    }
+
+    /// A manifest (`.json`) or a single PEM key.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, KeyError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| KeyError(format!("{}: {}", path.display(), e)))?;
+        if text.trim_start().starts_with('[') {
+            let base = path.parent().map(Path::to_path_buf).unwrap_or_default();
+            Self::from_manifest(&text, &base)
+        } else {
+            Ok(Self::new(vec![KeyEntry::from_pem(&text)?]))
+        }
+    }
+
+    /// Run a key plugin and parse the manifest it prints.
+    pub fn from_command(cmd: &str) -> Result<Self, KeyError> {
+        let out = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .stdin(std::process::Stdio::null())
+            .output()
+            .map_err(|e| KeyError(format!("{}: {}", cmd, e)))?;
+        if !out.status.success() {
+            return Err(KeyError(format!(
+                "{} exited with {}: {}",
+                cmd,
+                out.status,
+                String::from_utf8_lossy(&out.stderr).trim()
+            )));
+        }
+        let text = String::from_utf8(out.stdout).map_err(KeyError::new)?;
+        Self::from_manifest(&text, &PathBuf::from("."))
+    }
+
+    /// The keyring named by the environment (see the module docs); empty
+    /// when nothing is configured.
+    pub fn from_env() -> Result<Self, KeyError> {
+        let var = |k: &str| crate::ports::env::var(k).ok().filter(|v| !v.is_empty());
+        if let Some(cmd) = var("MAGICRUNE_KEY_COMMAND") {
+            Self::from_command(&cmd)
+        } else if let Some(path) = var("MAGICRUNE_KEYRING").or_else(|| var("MAGICRUNE_SIGNING_KEY"))
+        {
+            Self::from_file(path)
+        } else if let Some(pem) = var("MAGICRUNE_SIGNING_KEY_PEM") {
+            Ok(Self::new(vec![KeyEntry::from_pem(&pem)?]))
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    /// Keys trusted to sign requests, from `MAGICRUNE_PRODUCER_KEYS`; empty
+    /// when unset.
+    pub fn producers_from_env() -> Result<Self, KeyError> {
+        match crate::ports::env::var("MAGICRUNE_PRODUCER_KEYS") {
+            Ok(path) if !path.is_empty() => Self::from_file(path),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    /// [`Keyring::from_env`], reporting a broken configuration on stderr and
+    /// falling back to no keys (unsigned results).
+    pub fn from_env_or_empty() -> Self {
+        Self::from_env().unwrap_or_else(|e| {
+            tracing::warn!(error = %e, "results will be unsigned");
+            Self::default()
+        })
+    }
+
+    /// The key to sign with at `now`: the valid private key with the latest
+    /// `not_before`.
+    pub fn active(&self, now: u64) -> Option<&KeyEntry> {
+        self.keys
+            .iter()
+            .filter(|k| k.signing.is_some() && k.status(now) == KeyStatus::Valid)
+            .max_by_key(|k| k.not_before.unwrap_or(0))
+    }
+
+    /// Sign with the currently active key; no-op on a ring without one.
+    pub fn sign_result(&self, result: &mut SpellResult) -> Result<(), SignatureError> {
+        match self.active(now_secs()).and_then(|k| k.signing.as_ref()) {
+            Some(key) => signing::sign_result(key, result),
+            None => Ok(()),
+        }
+    }
+
+    /// Verify against the key named by the signature's `kid` (any unexpired
+    /// key when the signature has none).
+    pub fn verify_result(&self, result: &SpellResult) -> Result<&KeyEntry, SignatureError> {
+        let now = now_secs();
+        let kid = signing::signature_kid(result)?;
+        let mut last = SignatureError::Key("no key in the keyring matches".into());
+        for k in &self.keys {
+            if kid.as_deref().map(|id| id != k.kid).unwrap_or(false) {
+                continue;
+            }
+            if k.status(now) == KeyStatus::Expired {
+                last = SignatureError::Key(format!("key {} expired", k.kid));
+                continue;
+            }
+            match signing::verify_result(&k.verifying, result) {
+                Ok(()) => return Ok(k),
+                Err(e) => last = e,
+            }
+        }
+        Err(last)
+    }
+
+    /// Verify a signed request against the key named by its `key_id` (any
+    /// unexpired key when it has none).
+    pub fn verify_request(
+        &self,
+        req: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<&KeyEntry, SignatureError> {
+        let now = now_secs();
+        let (kid, _) = signing::request_signature(req)?;
+        let mut last = SignatureError::Key("no key in the keyring matches".into());
+        for k in &self.keys {
+            if kid.map(|id| id != k.kid).unwrap_or(false) {
+                continue;
+            }
+            if k.status(now) == KeyStatus::Expired {
+                last = SignatureError::Key(format!("key {} expired", k.kid));
+                continue;
+            }
+            match signing::verify_request(&k.verifying, req) {
+                Ok(()) => return Ok(k),
+                Err(e) => last = e,
+            }
+        }
+        Err(last)
+    }
+}
+
+/// A fresh key pair as `(PKCS#8 PEM, SPKI PEM, kid)`.
+pub fn generate() -> Result<(String, String, String), KeyError> {
+    let mut seed = [0u8; 32];
+    getrandom::getrandom(&mut seed).map_err(KeyError::new)?;
+    let sk = SigningKey::from_bytes(&seed);
+    let private = sk.to_pkcs8_pem(LineEnding::LF).map_err(KeyError::new)?;
+    let public = sk
+        .verifying_key()
+        .to_public_key_pem(LineEnding::LF)
+        .map_err(KeyError::new)?;
+    Ok((private.to_string(), public, key_id(&sk.verifying_key())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(seed: u8, not_before: Option<u64>, not_after: Option<u64>) -> KeyEntry {
+        let sk = SigningKey::from_bytes(&[seed; 32]);
+        KeyEntry {
+            kid: key_id(&sk.verifying_key()),
+            verifying: sk.verifying_key(),
+            signing: Some(sk),
+            not_before,
+            not_after,
+        }
+    }
+
+    #[test]
+    fn test_generate_roundtrip() {
+        let (private, public, kid) = generate().unwrap();
+        let sk = KeyEntry::from_pem(&private).unwrap();
+        let pk = KeyEntry::from_pem(&public).unwrap();
+        assert!(sk.signing.is_some() && pk.signing.is_none());
+        assert_eq!(sk.kid, kid);
+        assert_eq!(pk.kid, kid);
+        assert_eq!(kid.len(), 16);
+    }
+
+    #[test]
+    fn test_rotation_overlap() {
+        let old = entry(1, None, Some(200));
+        let new = entry(2, Some(100), None);
+        let ring = Keyring::new(vec![old.clone(), new.clone()]);
+        assert_eq!(ring.active(50).unwrap().kid, old.kid);
+        // Both are valid during the overlap; the newer one signs.
+        assert_eq!(ring.active(150).unwrap().kid, new.kid);
+        assert_eq!(ring.active(250).unwrap().kid, new.kid);
+        assert_eq!(old.status(250), KeyStatus::Expired);
+        assert_eq!(new.status(50), KeyStatus::Pending);
+    }
+
+    #[test]
+    fn test_verify_picks_key_by_kid() {
+        let a = entry(1, None, None);
+        let b = entry(2, None, None);
+        let mut res = SpellResult {
+            run_id: "r_1".into(),
+            ..Default::default()
+        };
+        signing::sign_result(b.signing.as_ref().unwrap(), &mut res).unwrap();
+        let ring = Keyring::new(vec![a.clone(), b.clone()]);
+        assert_eq!(ring.verify_result(&res).unwrap().kid, b.kid);
+        let expired = Keyring::new(vec![entry(2, None, Some(1))]);
+        assert!(expired.verify_result(&res).is_err());
+        assert!(Keyring::new(vec![a]).verify_result(&res).is_err());
+    }
+
+    #[test]
+    fn test_manifest_with_inline_pem() {
+        let (private, public, kid) = generate().unwrap();
+        let json = serde_json::json!([
+            { "pem": private, "not_after": 10 },
+            { "pem": public, "not_before": 5 },
+        ])
+        .to_string();
+        let ring = Keyring::from_manifest(&json, Path::new(".")).unwrap();
+        assert_eq!(ring.keys().len(), 2);
+        assert!(ring.keys().iter().all(|k| k.kid == kid));
+        assert_eq!(ring.keys()[0].not_after, Some(10));
+        // The public entry cannot sign.
+        assert!(ring.active(7).is_some());
+        assert!(ring.active(11).is_none());
+    }
+}
@@ -1,15 +1,205 @@
-#[derive(Debug, Clone)]
+//! Run records, keyed by run id.
+//!
+//! [`InMemoryLedger`] lives as long as the process. [`JsonlLedger`] appends
+//! one JSON line per record to a file (`MAGICRUNE_LEDGER`), for runs that
+//! should outlive it, such as the commands typed into `magicrune shell`.
+//!
+//! [`DirLedger`] keeps one JSON file per run id in a directory, a key-value
+//! store that needs no database library, for static builds;
+//! `MAGICRUNE_LEDGER_BACKEND=dir` selects it (see [`LedgerConfig`]).
+//! With `MAGICRUNE_LEDGER_BATCH` above 1, records are written through a
+//! [`BufferedLedger`], in batches of that many or every
+//! `MAGICRUNE_LEDGER_FLUSH_MS`.
+//!
+//! A [`Retention`] (`MAGICRUNE_LEDGER_RETENTION=green=7d,yellow=30d,red=365d`)
+//! bounds how long a record is kept, by verdict. [`Ledger::prune`] drops the
+//! expired records (and, in a JSON-lines file, the lines later ones
+//! superseded); `magicrune ledger prune` runs it once, and `worker` and
+//! `consume` run it along with their periodic gc.
+
+use crate::policy::Policy;
+use crate::schema::{SpellResult, Waiver};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct RunRecord {
     pub run_id: String,
+    /// Tenant the run belongs to (see `crate::tenant`).
+    pub tenant: String,
+    /// Consumer instance that ran it (see `crate::instance`).
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub instance_id: String,
     pub verdict: String,
     pub risk_score: u32,
     pub exit_code: i32,
+    /// Command line of the run, when it is recorded.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub cmd: String,
+    /// When the run was recorded, Unix milliseconds; 0 in records written
+    /// before the field existed.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub at_ms: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub duration_ms: u64,
+    /// Points by risk rule that fired.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub rules: BTreeMap<String, u32>,
+    /// Risk rules waived at the request's `accept_risk`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub waivers: Vec<Waiver>,
+    /// Digest of the policy the run was graded under.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub policy_digest: String,
+    /// The request as canonical JSON (keys sorted), under
+    /// `ledger.request: store`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<serde_json::Value>,
+    /// Hex sha256 of the canonical request, under `store` or `hash`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub request_sha256: String,
+    /// The whole result, under `ledger.result: store`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+}
+
+/// What run records keep of a request body, which may carry secrets
+/// (`ledger.request` in the policy).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RequestBody {
+    /// Nothing.
+    #[default]
+    Drop,
+    /// Its sha256 only, to match a run to a request kept elsewhere.
+    Hash,
+    /// The request itself, and its sha256.
+    Store,
+}
+
+impl std::str::FromStr for RequestBody {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(Self::Drop),
+            "hash" => Ok(Self::Hash),
+            "store" => Ok(Self::Store),
+            other => Err(format!(
+                "unknown ledger request {:?} (store, hash or drop)",
+                other
+            )),
+        }
+    }
+}
+
+impl RunRecord {
+    /// The record of `res`, stamped with the current time.
+    pub fn from_result(res: &SpellResult) -> Self {
+        Self {
+            run_id: res.run_id.clone(),
+            tenant: res.tenant.clone(),
+            instance_id: res.instance_id.clone(),
+            verdict: res.verdict.clone(),
+            risk_score: res.risk_score,
+            exit_code: res.exit_code,
+            cmd: String::new(),
+            at_ms: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            duration_ms: res.duration_ms,
+            rules: res
+                .risk_breakdown
+                .as_ref()
+                .map(|b| b.rules.clone())
+                .unwrap_or_default(),
+            waivers: res.waivers.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// [`RunRecord::from_result`] with the policy digest, and as much of the
+    /// request `raw` and of the result as `policy` keeps. A request that is
+    /// not JSON is kept as its hash only.
+    pub fn enriched(res: &SpellResult, raw: &[u8], policy: &Policy) -> Self {
+        use sha2::{Digest, Sha256};
+        let mut rec = Self {
+            policy_digest: policy.digest.clone(),
+            result: policy
+                .ledger_result
+                .then(|| serde_json::to_value(res).ok())
+                .flatten(),
+            ..Self::from_result(res)
+        };
+        if policy.ledger_request == RequestBody::Drop {
+            return rec;
+        }
+        let request = serde_json::from_slice::<serde_json::Value>(raw).ok();
+        let canonical = request
+            .as_ref()
+            .map(|v| v.to_string().into_bytes())
+            .unwrap_or_else(|| raw.to_vec());
+        rec.request_sha256 = Sha256::digest(&canonical)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        if policy.ledger_request == RequestBody::Store {
+            rec.request = request;
+        }
+        rec
+    }
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
 }
 
-#[allow(async_fn_in_trait)]
-pub trait Ledger: Send + Sync {
-    fn put(&self, rec: RunRecord);
-    fn get(&self, run_id: &str) -> Option<RunRecord>;
+/// Where run records are kept. Backends that touch the disk do it on the
+/// blocking pool, so a consumer's workers are not held up by it; wrap one
+/// in a [`BufferedLedger`] to write records in batches.
+#[async_trait]
+pub trait Ledger: Send + Sync + std::fmt::Debug {
+    async fn put(&self, rec: RunRecord) {
+        self.put_batch(vec![rec]).await
+    }
+    /// Record `recs` in one write, in order; for a run id recorded more than
+    /// once, the last record wins.
+    async fn put_batch(&self, recs: Vec<RunRecord>);
+    async fn get(&self, run_id: &str) -> Option<RunRecord>;
+    /// Every record of `tenant`.
+    async fn list(&self, tenant: &str) -> Vec<RunRecord>;
+    /// The latest record of every run id.
+    async fn all(&self) -> Vec<RunRecord>;
+    /// Drop the records `retention` no longer keeps at `now_ms`; with
+    /// `dry_run`, only count them.
+    async fn prune(
+        &self,
+        retention: &Retention,
+        now_ms: u64,
+        dry_run: bool,
+    ) -> std::io::Result<PruneReport>;
+    /// `run_id`, only if it belongs to `tenant`.
+    async fn get_for(&self, tenant: &str, run_id: &str) -> Option<RunRecord> {
+        self.get(run_id).await.filter(|r| r.tenant == tenant)
+    }
+    /// Write out whatever is held back; a no-op for unbuffered ledgers.
+    async fn flush(&self) {}
+}
+
+/// Run `f` on a clone of `ledger` on the blocking pool.
+async fn blocking<L, T>(ledger: &L, f: impl FnOnce(&L) -> T + Send + 'static) -> T
+where
+    L: Clone + Send + 'static,
+    T: Send + 'static,
+{
+    let ledger = ledger.clone();
+    match tokio::task::spawn_blocking(move || f(&ledger)).await {
+        Ok(v) => v,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
 }
 
 #[derive(Default, Debug)]
@@ -23,15 +213,640 @@ impl InMemoryLedger {
     }
 }
 
+#[async_trait]
 impl Ledger for InMemoryLedger {
-    fn put(&self, rec: RunRecord) {
+    async fn put_batch(&self, recs: Vec<RunRecord>) {
         let mut g = self.inner.lock().unwrap();
-        g.insert(rec.run_id.clone(), rec);
+        for rec in recs {
+            g.insert(rec.run_id.clone(), rec);
+        }
     }
-    fn get(&self, run_id: &str) -> Option<RunRecord> {
+    async fn get(&self, run_id: &str) -> Option<RunRecord> {
         let g = self.inner.lock().unwrap();
         g.get(run_id).cloned()
     }
+    async fn list(&self, tenant: &str) -> Vec<RunRecord> {
+        let g = self.inner.lock().unwrap();
+        let mut recs: Vec<RunRecord> = g.values().filter(|r| r.tenant == tenant).cloned().collect();
+        recs.sort_by(|a, b| a.run_id.cmp(&b.run_id));
+        recs
+    }
+    async fn all(&self) -> Vec<RunRecord> {
+        let g = self.inner.lock().unwrap();
+        let mut recs: Vec<RunRecord> = g.values().cloned().collect();
+        recs.sort_by(|a, b| a.run_id.cmp(&b.run_id));
+        recs
+    }
+    async fn prune(
+        &self,
+        retention: &Retention,
+        now_ms: u64,
+        dry_run: bool,
+    ) -> std::io::Result<PruneReport> {
+        let mut g = self.inner.lock().unwrap();
+        let mut report = PruneReport::default();
+        g.retain(|_, r| {
+            let keep = retention.keeps(r, now_ms);
+            if keep {
+                report.kept += 1;
+            } else {
+                report.expired += 1;
+            }
+            keep || dry_run
+        });
+        Ok(report)
+    }
+}
+
+/// Environment variable holding the [`Retention`].
+pub const RETENTION_ENV: &str = "MAGICRUNE_LEDGER_RETENTION";
+
+/// How long records are kept, by verdict. A verdict without an age of its
+/// own takes the `*` one; with neither, its records are kept for good, as
+/// are records without a time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Milliseconds by verdict, `*` for the rest.
+    pub max_age_ms: BTreeMap<String, u64>,
+}
+
+impl Retention {
+    /// `green=7d,yellow=30d,red=365d,*=90d`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut max_age_ms = BTreeMap::new();
+        for item in spec.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let age = item
+                .split_once('=')
+                .and_then(|(v, age)| Some((v.trim(), crate::stats::parse_age(age.trim())?)))
+                .filter(|(v, _)| !v.is_empty());
+            let Some((verdict, ms)) = age else {
+                return Err(format!(
+                    "ledger retention: {:?} is not <verdict>=<age> like red=365d",
+                    item
+                ));
+            };
+            max_age_ms.insert(verdict.to_string(), ms);
+        }
+        Ok(Self { max_age_ms })
+    }
+
+    /// The retention at [`RETENTION_ENV`]; `None` when unset.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        match crate::ports::env::var(RETENTION_ENV) {
+            Ok(spec) if !spec.trim().is_empty() => Self::parse(&spec).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Whether `rec` is still to be kept at `now_ms`.
+    pub fn keeps(&self, rec: &RunRecord, now_ms: u64) -> bool {
+        let max_age = self
+            .max_age_ms
+            .get(&rec.verdict)
+            .or_else(|| self.max_age_ms.get("*"));
+        match max_age {
+            Some(&age) if rec.at_ms > 0 => now_ms.saturating_sub(rec.at_ms) < age,
+            _ => true,
+        }
+    }
+}
+
+/// What a prune did, or would do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    pub kept: usize,
+    /// Records past their verdict's age.
+    pub expired: usize,
+    /// Lines a later record of the same run id replaced.
+    pub superseded: usize,
+}
+
+/// Default file of [`JsonlLedger::from_env`].
+pub const DEFAULT_LEDGER_PATH: &str = "ledger.jsonl";
+
+/// Records appended to a JSON-lines file; for a run id recorded more than
+/// once, the last line wins. Lookups scan the file.
+#[derive(Debug, Clone)]
+pub struct JsonlLedger {
+    path: PathBuf,
+    lock: std::sync::Arc<std::sync::Mutex<()>>,
+}
+
+impl JsonlLedger {
+    /// Ledgers on the same path share a lock, so a prune does not lose a
+    /// record appended meanwhile in this process.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        static LOCKS: std::sync::OnceLock<
+            std::sync::Mutex<
+                std::collections::HashMap<PathBuf, std::sync::Arc<std::sync::Mutex<()>>>,
+            >,
+        > = std::sync::OnceLock::new();
+        let path = path.into();
+        let lock = LOCKS
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap()
+            .entry(path.clone())
+            .or_default()
+            .clone();
+        Self { path, lock }
+    }
+
+    /// The ledger at `MAGICRUNE_LEDGER`, or [`DEFAULT_LEDGER_PATH`].
+    pub fn from_env() -> Self {
+        Self::new(
+            crate::ports::env::var("MAGICRUNE_LEDGER")
+                .ok()
+                .filter(|p| !p.is_empty())
+                .unwrap_or_else(|| DEFAULT_LEDGER_PATH.to_string()),
+        )
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    fn append(&self, recs: &[RunRecord]) -> std::io::Result<()> {
+        let _g = self.lock.lock().unwrap();
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut lines = Vec::new();
+        for rec in recs {
+            serde_json::to_writer(&mut lines, rec)?;
+            lines.push(b'\n');
+        }
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&lines)
+    }
+
+    fn records(&self) -> Vec<RunRecord> {
+        let _g = self.lock.lock().unwrap();
+        let Ok(f) = std::fs::File::open(&self.path) else {
+            return Vec::new();
+        };
+        std::io::BufReader::new(f)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|l| serde_json::from_str(&l).ok())
+            .collect()
+    }
+
+    /// The latest record of every run id, in the order they were first
+    /// recorded.
+    fn latest(&self) -> Vec<RunRecord> {
+        let mut index = std::collections::HashMap::new();
+        let mut recs: Vec<RunRecord> = Vec::new();
+        for r in self.records() {
+            match index.get(&r.run_id) {
+                Some(&i) => recs[i] = r,
+                None => {
+                    index.insert(r.run_id.clone(), recs.len());
+                    recs.push(r);
+                }
+            }
+        }
+        recs
+    }
+
+    /// Rewrite the file with only the latest record of each run id that
+    /// `retention` still keeps at `now_ms`; with `dry_run`, only count.
+    /// Lines that do not parse are kept as they are.
+    fn rewrite(
+        &self,
+        retention: &Retention,
+        now_ms: u64,
+        dry_run: bool,
+    ) -> std::io::Result<PruneReport> {
+        let _g = self.lock.lock().unwrap();
+        let text = match std::fs::read_to_string(&self.path) {
+            Ok(t) => t,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(PruneReport::default()),
+            Err(e) => return Err(e),
+        };
+        let lines: Vec<(&str, Option<RunRecord>)> = text
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| (l, serde_json::from_str(l).ok()))
+            .collect();
+        let mut last = std::collections::HashMap::new();
+        for (i, (_, rec)) in lines.iter().enumerate() {
+            if let Some(r) = rec {
+                last.insert(r.run_id.as_str(), i);
+            }
+        }
+        let mut report = PruneReport::default();
+        let mut out = String::new();
+        for (i, (line, rec)) in lines.iter().enumerate() {
+            match rec {
+                Some(r) if last[r.run_id.as_str()] != i => report.superseded += 1,
+                Some(r) if !retention.keeps(r, now_ms) => report.expired += 1,
+                _ => {
+                    report.kept += 1;
+                    out.push_str(line);
+                    out.push('\n');
+                }
+            }
+        }
+        if !dry_run && report.expired + report.superseded > 0 {
+            let tmp = self.path.with_extension("prune.tmp");
+            std::fs::write(&tmp, out)?;
+            std::fs::rename(&tmp, &self.path)?;
+        }
+        Ok(report)
+    }
+}
+
+#[async_trait]
+impl Ledger for JsonlLedger {
+    async fn put_batch(&self, recs: Vec<RunRecord>) {
+        let (n, path) = (recs.len(), self.path.clone());
+        if let Err(e) = blocking(self, move |l| l.append(&recs)).await {
+            tracing::warn!(records = n, path = %path.display(), error = %e, "ledger: append failed");
+        }
+    }
+    async fn get(&self, run_id: &str) -> Option<RunRecord> {
+        let run_id = run_id.to_string();
+        blocking(self, move |l| {
+            l.records().into_iter().rev().find(|r| r.run_id == run_id)
+        })
+        .await
+    }
+    async fn list(&self, tenant: &str) -> Vec<RunRecord> {
+        let mut recs: Vec<RunRecord> = self
+            .all()
+            .await
+            .into_iter()
+            .filter(|r| r.tenant == tenant)
+            .collect();
+        recs.sort_by(|a, b| a.run_id.cmp(&b.run_id));
+        recs
+    }
+    /// In the order the run ids were first recorded.
+    async fn all(&self) -> Vec<RunRecord> {
+        blocking(self, |l| l.latest()).await
+    }
+    async fn prune(
+        &self,
+        retention: &Retention,
+        now_ms: u64,
+        dry_run: bool,
+    ) -> std::io::Result<PruneReport> {
+        let retention = retention.clone();
+        blocking(self, move |l| l.rewrite(&retention, now_ms, dry_run)).await
+    }
+}
+
+/// Environment variable naming the [`LedgerBackend`].
+pub const BACKEND_ENV: &str = "MAGICRUNE_LEDGER_BACKEND";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LedgerBackend {
+    /// [`JsonlLedger`].
+    #[default]
+    Jsonl,
+    /// [`DirLedger`].
+    Dir,
+}
+
+impl LedgerBackend {
+    /// `jsonl` or `dir`.
+    pub fn parse(v: &str) -> Result<Self, String> {
+        match v.trim() {
+            "jsonl" => Ok(Self::Jsonl),
+            "dir" => Ok(Self::Dir),
+            _ => Err(format!(
+                "ledger backend: {:?} is not jsonl or dir",
+                v.trim()
+            )),
+        }
+    }
+
+    /// Where the ledger is kept when `MAGICRUNE_LEDGER` is not set.
+    pub fn default_path(self) -> &'static str {
+        match self {
+            Self::Jsonl => DEFAULT_LEDGER_PATH,
+            Self::Dir => DEFAULT_LEDGER_DIR,
+        }
+    }
+}
+
+/// Environment variable holding [`LedgerConfig::batch`].
+pub const BATCH_ENV: &str = "MAGICRUNE_LEDGER_BATCH";
+/// Environment variable holding [`LedgerConfig::flush_ms`].
+pub const FLUSH_ENV: &str = "MAGICRUNE_LEDGER_FLUSH_MS";
+
+/// Which ledger to open, and where.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LedgerConfig {
+    pub backend: LedgerBackend,
+    pub path: PathBuf,
+    /// Records written at once; above 1 the ledger is a [`BufferedLedger`].
+    pub batch: usize,
+    /// Time between flushes of a buffered ledger.
+    pub flush_ms: u64,
+}
+
+impl LedgerConfig {
+    /// The backend at [`BACKEND_ENV`], the path at `MAGICRUNE_LEDGER` or
+    /// the backend's default path, and the batching at [`BATCH_ENV`] and
+    /// [`FLUSH_ENV`] (unbatched, and a second, by default).
+    pub fn from_env() -> Result<Self, String> {
+        let num = |key: &str, default: u64| match crate::ports::env::var(key) {
+            Ok(v) if !v.trim().is_empty() => v
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| format!("{}: {:?} is not a number", key, v)),
+            _ => Ok(default),
+        };
+        let backend = match crate::ports::env::var(BACKEND_ENV) {
+            Ok(v) if !v.trim().is_empty() => LedgerBackend::parse(&v)?,
+            _ => LedgerBackend::default(),
+        };
+        let path = crate::ports::env::var("MAGICRUNE_LEDGER")
+            .ok()
+            .filter(|p| !p.is_empty())
+            .unwrap_or_else(|| backend.default_path().to_string());
+        Ok(Self {
+            backend,
+            path: path.into(),
+            batch: num(BATCH_ENV, 1)? as usize,
+            flush_ms: num(FLUSH_ENV, 1_000)?,
+        })
+    }
+
+    /// As [`LedgerConfig::from_env`], only when `MAGICRUNE_LEDGER` is set.
+    pub fn from_env_if_set() -> Result<Option<Self>, String> {
+        match crate::ports::env::var("MAGICRUNE_LEDGER") {
+            Ok(p) if !p.is_empty() => Self::from_env().map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn new(backend: LedgerBackend, path: impl Into<PathBuf>) -> Self {
+        Self {
+            backend,
+            path: path.into(),
+            batch: 1,
+            flush_ms: 1_000,
+        }
+    }
+
+    pub fn open(&self) -> std::sync::Arc<dyn Ledger> {
+        let ledger: std::sync::Arc<dyn Ledger> = match self.backend {
+            LedgerBackend::Jsonl => std::sync::Arc::new(JsonlLedger::new(&self.path)),
+            LedgerBackend::Dir => std::sync::Arc::new(DirLedger::new(&self.path)),
+        };
+        if self.batch <= 1 {
+            return ledger;
+        }
+        BufferedLedger::new(
+            ledger,
+            self.batch,
+            std::time::Duration::from_millis(self.flush_ms),
+        )
+    }
+}
+
+/// Default directory of a [`DirLedger`] opened through [`LedgerConfig`].
+pub const DEFAULT_LEDGER_DIR: &str = "ledger.d";
+
+/// Records kept as `<run_id>.json` files in a directory, each replaced
+/// whole on a new record of its run id. Lookups by run id read one file.
+#[derive(Debug, Clone)]
+pub struct DirLedger {
+    dir: PathBuf,
+}
+
+impl DirLedger {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.dir
+    }
+
+    /// The file of `run_id`; `None` for an id that is not a plain file name.
+    fn file(&self, run_id: &str) -> Option<PathBuf> {
+        let plain = !run_id.is_empty()
+            && !run_id.starts_with('.')
+            && run_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        plain.then(|| self.dir.join(format!("{}.json", run_id)))
+    }
+
+    fn write(&self, rec: &RunRecord) -> std::io::Result<()> {
+        let Some(path) = self.file(&rec.run_id) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("run id {:?} is not a plain file name", rec.run_id),
+            ));
+        };
+        std::fs::create_dir_all(&self.dir)?;
+        let tmp = path.with_extension(format!("json.{}.tmp", std::process::id()));
+        std::fs::write(&tmp, serde_json::to_vec(rec)?)?;
+        std::fs::rename(&tmp, &path)
+    }
+
+    /// Every record with the file it is kept in.
+    fn entries(&self) -> Vec<(PathBuf, RunRecord)> {
+        let Ok(dir) = std::fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        let mut recs: Vec<(PathBuf, RunRecord)> = dir
+            .filter_map(Result::ok)
+            .map(|e| e.path())
+            .filter(|p| p.extension().is_some_and(|x| x == "json"))
+            .filter_map(|p| {
+                let rec = serde_json::from_slice(&std::fs::read(&p).ok()?).ok()?;
+                Some((p, rec))
+            })
+            .collect();
+        recs.sort_by(|a, b| a.1.run_id.cmp(&b.1.run_id));
+        recs
+    }
+    /// Remove the files of expired records. Files that do not parse are
+    /// left alone.
+    fn remove_expired(
+        &self,
+        retention: &Retention,
+        now_ms: u64,
+        dry_run: bool,
+    ) -> std::io::Result<PruneReport> {
+        let mut report = PruneReport::default();
+        for (path, rec) in self.entries() {
+            if retention.keeps(&rec, now_ms) {
+                report.kept += 1;
+                continue;
+            }
+            report.expired += 1;
+            if !dry_run {
+                std::fs::remove_file(&path)?;
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[async_trait]
+impl Ledger for DirLedger {
+    async fn put_batch(&self, recs: Vec<RunRecord>) {
+        let failed = blocking(self, move |l| {
+            recs.into_iter()
+                .filter_map(|rec| l.write(&rec).err().map(|e| (rec.run_id, e)))
+                .collect::<Vec<_>>()
+        })
+        .await;
+        for (run_id, e) in failed {
+            tracing::warn!(run_id = %run_id, path = %self.dir.display(), error = %e, "ledger: write failed");
+        }
+    }
+    async fn get(&self, run_id: &str) -> Option<RunRecord> {
+        let path = self.file(run_id)?;
+        blocking(self, move |_| {
+            serde_json::from_slice(&std::fs::read(path).ok()?).ok()
+        })
+        .await
+    }
+    async fn list(&self, tenant: &str) -> Vec<RunRecord> {
+        self.all()
+            .await
+            .into_iter()
+            .filter(|r| r.tenant == tenant)
+            .collect()
+    }
+    async fn all(&self) -> Vec<RunRecord> {
+        blocking(self, |l| l.entries().into_iter().map(|(_, r)| r).collect()).await
+    }
+    async fn prune(
+        &self,
+        retention: &Retention,
+        now_ms: u64,
+        dry_run: bool,
+    ) -> std::io::Result<PruneReport> {
+        let retention = retention.clone();
+        blocking(self, move |l| l.remove_expired(&retention, now_ms, dry_run)).await
+    }
+}
+
+/// A ledger that holds records back and hands them to the one it wraps in
+/// batches: once [`BufferedLedger::max_batch`] records are waiting, and
+/// every flush interval otherwise. A `put` that fills the batch waits for
+/// it to be written, so writers slow down with the backend instead of
+/// piling up records in memory. Records still waiting when the process
+/// exits without a [`Ledger::flush`] are lost.
+#[derive(Debug)]
+pub struct BufferedLedger {
+    inner: std::sync::Arc<dyn Ledger>,
+    max_batch: usize,
+    interval: std::time::Duration,
+    pending: std::sync::Mutex<Vec<RunRecord>>,
+    flusher: std::sync::Once,
+    this: std::sync::Weak<Self>,
+}
+
+impl BufferedLedger {
+    pub fn new(
+        inner: std::sync::Arc<dyn Ledger>,
+        max_batch: usize,
+        interval: std::time::Duration,
+    ) -> std::sync::Arc<Self> {
+        std::sync::Arc::new_cyclic(|this| Self {
+            inner,
+            max_batch: max_batch.max(1),
+            interval,
+            pending: Default::default(),
+            flusher: std::sync::Once::new(),
+            this: this.clone(),
+        })
+    }
+
+    pub fn max_batch(&self) -> usize {
+        self.max_batch
+    }
+
+    /// Records waiting to be written.
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// Flush every interval on the runtime of the first `put`, for as long
+    /// as the ledger is in use.
+    fn start_flusher(&self) {
+        if self.interval.is_zero() || tokio::runtime::Handle::try_current().is_err() {
+            return;
+        }
+        self.flusher.call_once(|| {
+            let (this, interval) = (self.this.clone(), self.interval);
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(interval).await;
+                    let Some(ledger) = this.upgrade() else {
+                        break;
+                    };
+                    ledger.flush().await;
+                }
+            });
+        });
+    }
+
+    fn take(&self) -> Vec<RunRecord> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+}
+
+#[async_trait]
+impl Ledger for BufferedLedger {
+    async fn put_batch(&self, recs: Vec<RunRecord>) {
+        self.start_flusher();
+        let full = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.extend(recs);
+            (pending.len() >= self.max_batch).then(|| std::mem::take(&mut *pending))
+        };
+        if let Some(batch) = full {
+            self.inner.put_batch(batch).await;
+        }
+    }
+    async fn get(&self, run_id: &str) -> Option<RunRecord> {
+        let waiting = {
+            let pending = self.pending.lock().unwrap();
+            pending.iter().rev().find(|r| r.run_id == run_id).cloned()
+        };
+        match waiting {
+            Some(r) => Some(r),
+            None => self.inner.get(run_id).await,
+        }
+    }
+    async fn list(&self, tenant: &str) -> Vec<RunRecord> {
+        self.flush().await;
+        self.inner.list(tenant).await
+    }
+    async fn all(&self) -> Vec<RunRecord> {
+        self.flush().await;
+        self.inner.all().await
+    }
+    async fn prune(
+        &self,
+        retention: &Retention,
+        now_ms: u64,
+        dry_run: bool,
+    ) -> std::io::Result<PruneReport> {
+        self.flush().await;
+        self.inner.prune(retention, now_ms, dry_run).await
+    }
+    async fn flush(&self) {
+        let batch = self.take();
+        if !batch.is_empty() {
+            self.inner.put_batch(batch).await;
+        }
+        self.inner.flush().await;
+    }
 }
 
 #[cfg(test)]
@@ -42,9 +857,13 @@ mod tests {
     fn test_run_record_creation() {
         let record = RunRecord {
             run_id: "test-123".to_string(),
+            tenant: "default".to_string(),
+            instance_id: String::new(),
             verdict: "safe".to_string(),
             risk_score: 25,
             exit_code: 0,
+            cmd: String::new(),
+            ..Default::default()
         };
 
         assert_eq!(record.run_id, "test-123");
@@ -57,9 +876,13 @@ mod tests {
     fn test_run_record_clone() {
         let record = RunRecord {
             run_id: "test-456".to_string(),
+            tenant: "default".to_string(),
+            instance_id: String::new(),
             verdict: "risky".to_string(),
             risk_score: 75,
             exit_code: 1,
+            cmd: String::new(),
+            ..Default::default()
         };
 
         let cloned = record.clone();
@@ -69,25 +892,29 @@ mod tests {
         assert_eq!(cloned.exit_code, record.exit_code);
     }
 
-    #[test]
-    fn test_in_memory_ledger_new() {
+    #[tokio::test]
+    async fn test_in_memory_ledger_new() {
         let ledger = InMemoryLedger::new();
-        assert!(ledger.get("non-existent").is_none());
+        assert!(ledger.get("non-existent").await.is_none());
     }
 
-    #[test]
-    fn test_in_memory_ledger_put_and_get() {
+    #[tokio::test]
+    async fn test_in_memory_ledger_put_and_get() {
         let ledger = InMemoryLedger::new();
         let record = RunRecord {
             run_id: "test-789".to_string(),
+            tenant: "default".to_string(),
+            instance_id: String::new(),
             verdict: "safe".to_string(),
             risk_score: 10,
             exit_code: 0,
+            cmd: String::new(),
+            ..Default::default()
         };
 
-        ledger.put(record.clone());
+        ledger.put(record.clone()).await;
 
-        let retrieved = ledger.get("test-789");
+        let retrieved = ledger.get("test-789").await;
         assert!(retrieved.is_some());
 
         let retrieved = retrieved.unwrap();
@@ -97,62 +924,317 @@ mod tests {
         assert_eq!(retrieved.exit_code, 0);
     }
 
-    #[test]
-    fn test_in_memory_ledger_multiple_records() {
+    #[tokio::test]
+    async fn test_in_memory_ledger_multiple_records() {
         let ledger = InMemoryLedger::new();
 
         let record1 = RunRecord {
             run_id: "run-1".to_string(),
+            tenant: "default".to_string(),
+            instance_id: String::new(),
             verdict: "safe".to_string(),
             risk_score: 5,
             exit_code: 0,
+            cmd: String::new(),
+            ..Default::default()
         };
 
         let record2 = RunRecord {
             run_id: "run-2".to_string(),
+            tenant: "default".to_string(),
+            instance_id: String::new(),
             verdict: "risky".to_string(),
             risk_score: 85,
             exit_code: 2,
+            cmd: String::new(),
+            ..Default::default()
         };
 
-        ledger.put(record1.clone());
-        ledger.put(record2.clone());
+        ledger.put(record1.clone()).await;
+        ledger.put(record2.clone()).await;
 
-        assert!(ledger.get("run-1").is_some());
-        assert!(ledger.get("run-2").is_some());
-        assert!(ledger.get("run-3").is_none());
+        assert!(ledger.get("run-1").await.is_some());
+        assert!(ledger.get("run-2").await.is_some());
+        assert!(ledger.get("run-3").await.is_none());
 
-        let r1 = ledger.get("run-1").unwrap();
+        let r1 = ledger.get("run-1").await.unwrap();
         assert_eq!(r1.verdict, "safe");
 
-        let r2 = ledger.get("run-2").unwrap();
+        let r2 = ledger.get("run-2").await.unwrap();
         assert_eq!(r2.verdict, "risky");
     }
 
-    #[test]
-    fn test_in_memory_ledger_overwrite() {
+    #[tokio::test]
+    async fn test_in_memory_ledger_overwrite() {
         let ledger = InMemoryLedger::new();
 
         let record1 = RunRecord {
             run_id: "test-id".to_string(),
+            tenant: "default".to_string(),
+            instance_id: String::new(),
             verdict: "safe".to_string(),
             risk_score: 10,
             exit_code: 0,
+            cmd: String::new(),
+            ..Default::default()
         };
 
         let record2 = RunRecord {
             run_id: "test-id".to_string(),
+            tenant: "default".to_string(),
+            instance_id: String::new(),
             verdict: "risky".to_string(),
             risk_score: 90,
             exit_code: 1,
+            cmd: String::new(),
+            ..Default::default()
         };
 
-        ledger.put(record1);
-        ledger.put(record2);
+        ledger.put(record1).await;
+        ledger.put(record2).await;
 
-        let retrieved = ledger.get("test-id").unwrap();
+        let retrieved = ledger.get("test-id").await.unwrap();
         assert_eq!(retrieved.verdict, "risky");
         assert_eq!(retrieved.risk_score, 90);
         assert_eq!(retrieved.exit_code, 1);
     }
+
+    #[tokio::test]
+    async fn test_in_memory_ledger_tenant_queries() {
+        let ledger = InMemoryLedger::new();
+        for (run_id, tenant) in [("r_2", "acme"), ("r_1", "acme"), ("r_3", "beta")] {
+            ledger
+                .put(RunRecord {
+                    run_id: run_id.to_string(),
+                    tenant: tenant.to_string(),
+                    instance_id: String::new(),
+                    verdict: "green".to_string(),
+                    risk_score: 0,
+                    exit_code: 0,
+                    cmd: String::new(),
+                    ..Default::default()
+                })
+                .await;
+        }
+        let acme: Vec<String> = ledger
+            .list("acme")
+            .await
+            .into_iter()
+            .map(|r| r.run_id)
+            .collect();
+        assert_eq!(acme, ["r_1", "r_2"]);
+        assert!(ledger.get_for("acme", "r_1").await.is_some());
+        assert!(ledger.get_for("beta", "r_1").await.is_none());
+        assert!(ledger.list("gamma").await.is_empty());
+    }
+    #[tokio::test]
+    async fn test_jsonl_ledger_persists() {
+        let path = std::env::temp_dir().join(format!("mr_ledger_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let rec = |run_id: &str, exit_code| RunRecord {
+            run_id: run_id.to_string(),
+            tenant: "default".to_string(),
+            instance_id: "w-1".to_string(),
+            verdict: "green".to_string(),
+            exit_code,
+            cmd: "ls -l".to_string(),
+            ..Default::default()
+        };
+        let ledger = JsonlLedger::new(&path);
+        ledger.put(rec("r_1", 0)).await;
+        ledger.put_batch(vec![rec("r_2", 0), rec("r_1", 2)]).await;
+
+        let reopened = JsonlLedger::new(&path);
+        assert_eq!(reopened.get("r_1").await, Some(rec("r_1", 2)));
+        assert_eq!(reopened.list("default").await.len(), 2);
+        let all: Vec<(String, i32)> = reopened
+            .all()
+            .await
+            .into_iter()
+            .map(|r| (r.run_id, r.exit_code))
+            .collect();
+        assert_eq!(all, [("r_1".to_string(), 2), ("r_2".to_string(), 0)]);
+        assert!(reopened.list("acme").await.is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_retention_and_prune() {
+        let day = 86_400_000;
+        let now = 400 * day;
+        let rec = |run_id: &str, verdict: &str, age_days: u64| RunRecord {
+            run_id: run_id.to_string(),
+            tenant: "default".to_string(),
+            verdict: verdict.to_string(),
+            at_ms: now - age_days * day,
+            ..Default::default()
+        };
+        let r = Retention::parse("green=7d, yellow=30d,red=365d").unwrap();
+        assert!(r.keeps(&rec("a", "green", 6), now));
+        assert!(!r.keeps(&rec("a", "green", 7), now));
+        assert!(r.keeps(&rec("a", "red", 364), now));
+        assert!(r.keeps(&rec("a", "rejected", 399), now));
+        assert!(r.keeps(&RunRecord::default(), now));
+        assert!(!Retention::parse("*=1d")
+            .unwrap()
+            .keeps(&rec("a", "rejected", 2), now));
+        assert!(Retention::parse("green").is_err());
+        assert!(Retention::parse("green=7w").is_err());
+
+        let path =
+            std::env::temp_dir().join(format!("mr_ledger_prune_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let ledger = JsonlLedger::new(&path);
+        ledger.put(rec("r_1", "green", 10)).await;
+        ledger.put(rec("r_2", "yellow", 10)).await;
+        ledger.put(rec("r_3", "red", 100)).await;
+        ledger.put(rec("r_2", "red", 9)).await;
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"not a record\n")
+            .unwrap();
+        let dry = ledger.prune(&r, now, true).await.unwrap();
+        assert_eq!(
+            dry,
+            PruneReport {
+                kept: 3,
+                expired: 1,
+                superseded: 1
+            }
+        );
+        assert_eq!(ledger.all().await.len(), 3);
+        assert_eq!(ledger.prune(&r, now, false).await.unwrap(), dry);
+        let left: Vec<String> = ledger.all().await.into_iter().map(|r| r.run_id).collect();
+        assert_eq!(left, ["r_3", "r_2"]);
+        assert!(std::fs::read_to_string(&path)
+            .unwrap()
+            .ends_with("not a record\n"));
+        assert_eq!(ledger.prune(&r, now, false).await.unwrap().kept, 3);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_dir_ledger() {
+        let dir = std::env::temp_dir().join(format!("mr_ledger_dir_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let day = 86_400_000;
+        let rec = |run_id: &str, tenant: &str, verdict: &str, at_ms: u64| RunRecord {
+            run_id: run_id.to_string(),
+            tenant: tenant.to_string(),
+            verdict: verdict.to_string(),
+            at_ms,
+            ..Default::default()
+        };
+        let ledger = LedgerConfig::new(LedgerBackend::Dir, &dir).open();
+        assert!(ledger.all().await.is_empty());
+        ledger.put(rec("r_2", "acme", "green", 9 * day)).await;
+        ledger.put(rec("r_1", "acme", "yellow", 9 * day)).await;
+        ledger.put(rec("r_3", "beta", "red", 9 * day)).await;
+        ledger.put(rec("r_1", "acme", "green", day)).await;
+        ledger.put(rec("../r_4", "acme", "red", day)).await;
+        std::fs::write(dir.join("junk.json"), "not a record").unwrap();
+
+        let reopened = DirLedger::new(&dir);
+        assert_eq!(
+            reopened.get("r_1").await,
+            Some(rec("r_1", "acme", "green", day))
+        );
+        assert_eq!(reopened.get("../r_4").await, None);
+        let acme: Vec<String> = reopened
+            .list("acme")
+            .await
+            .into_iter()
+            .map(|r| r.run_id)
+            .collect();
+        assert_eq!(acme, ["r_1", "r_2"]);
+        assert_eq!(reopened.all().await.len(), 3);
+
+        let r = Retention::parse("green=7d").unwrap();
+        let dry = reopened.prune(&r, 10 * day, true).await.unwrap();
+        assert_eq!((dry.kept, dry.expired), (2, 1));
+        assert_eq!(reopened.prune(&r, 10 * day, false).await.unwrap(), dry);
+        assert_eq!(reopened.get("r_1").await, None);
+        assert!(dir.join("junk.json").exists());
+        assert_eq!(LedgerBackend::parse(" dir"), Ok(LedgerBackend::Dir));
+        assert!(LedgerBackend::parse("sqlite").is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_buffered_ledger_batches() {
+        let rec = |run_id: &str| RunRecord {
+            run_id: run_id.to_string(),
+            tenant: "acme".to_string(),
+            ..Default::default()
+        };
+        let inner = std::sync::Arc::new(InMemoryLedger::new());
+        let ledger = BufferedLedger::new(inner.clone(), 3, std::time::Duration::ZERO);
+        ledger.put(rec("r_1")).await;
+        ledger.put(rec("r_2")).await;
+        assert_eq!(ledger.pending(), 2);
+        assert!(inner.all().await.is_empty());
+        assert_eq!(ledger.get("r_2").await, Some(rec("r_2")));
+        ledger.put_batch(vec![rec("r_3"), rec("r_4")]).await;
+        assert_eq!((ledger.pending(), inner.all().await.len()), (0, 4));
+        ledger.put(rec("r_5")).await;
+        assert_eq!(ledger.list("acme").await.len(), 5);
+        assert_eq!(ledger.pending(), 0);
+
+        let timed = BufferedLedger::new(inner.clone(), 100, std::time::Duration::from_millis(20));
+        timed.put(rec("r_6")).await;
+        for _ in 0..100 {
+            if inner.get("r_6").await.is_some() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(timed.pending(), 0);
+        assert!(inner.get("r_6").await.is_some());
+    }
+
+    #[test]
+    fn test_enriched_record() {
+        let res = SpellResult {
+            run_id: "r_1".into(),
+            verdict: "green".into(),
+            tenant: "acme".into(),
+            ..Default::default()
+        };
+        let raw = br#"{"tenant":"acme","cmd":"echo $TOKEN","env":{"TOKEN":"s3cret"}}"#;
+        let policy = |text: &str| Policy::from_yaml(text).unwrap();
+
+        let dropped = RunRecord::enriched(&res, raw, &policy("version: 1\n"));
+        assert!(dropped.request.is_none() && dropped.result.is_none());
+        assert_eq!(dropped.request_sha256, "");
+        assert_eq!(dropped.policy_digest.len(), 64);
+
+        let hashed = RunRecord::enriched(&res, raw, &policy("ledger:\n  request: hash\n"));
+        assert!(hashed.request.is_none());
+        assert_eq!(hashed.request_sha256.len(), 64);
+        // The hash is of the canonical form, whatever the key order.
+        let reordered = br#"{"env":{"TOKEN":"s3cret"},"cmd":"echo $TOKEN","tenant":"acme"}"#;
+        assert_eq!(
+            RunRecord::enriched(&res, reordered, &policy("ledger:\n  request: hash\n"))
+                .request_sha256,
+            hashed.request_sha256
+        );
+
+        let stored = RunRecord::enriched(
+            &res,
+            raw,
+            &policy("ledger:\n  request: store\n  result: store\n"),
+        );
+        assert_eq!(stored.request.as_ref().unwrap()["env"]["TOKEN"], "s3cret");
+        assert_eq!(stored.request_sha256, hashed.request_sha256);
+        assert_eq!(stored.result.as_ref().unwrap()["run_id"], "r_1");
+        let line = serde_json::to_string(&stored).unwrap();
+        assert_eq!(serde_json::from_str::<RunRecord>(&line).unwrap(), stored);
+
+        assert_eq!(
+            policy("ledger:\n  request: keep\n").ledger_request,
+            RequestBody::Drop
+        );
+    }
 }
@@ -13,9 +13,97 @@ mod check_forbidden_apis;
 pub fn is_wasm() -> bool {
     cfg!(target_arch = "wasm32")
 }
+
+#[cfg(feature = "std")]
+pub mod audit;
+#[cfg(feature = "std")]
+pub mod backend;
+#[cfg(feature = "std")]
+pub mod bench;
+#[cfg(feature = "std")]
+pub mod bundle;
+#[cfg(feature = "std")]
+pub mod compare;
+#[cfg(feature = "std")]
+pub mod concurrency;
+#[cfg(feature = "std")]
+pub mod doctor;
+#[cfg(feature = "std")]
+pub mod engine;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "std")]
+pub mod faults;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "std")]
+pub mod gc;
 pub mod grader;
+#[cfg(feature = "std")]
+pub mod guard;
+#[cfg(feature = "std")]
+pub mod health;
+#[cfg(feature = "std")]
+pub mod http_cache;
+#[cfg(feature = "std")]
+pub mod input_cache;
+#[cfg(feature = "std")]
+pub mod instance;
+#[cfg(feature = "std")]
 pub mod jet;
+#[cfg(feature = "std")]
+pub mod keys;
+#[cfg(feature = "std")]
 pub mod ledger;
+#[cfg(feature = "std")]
+pub mod messages;
+#[cfg(feature = "std")]
+pub mod metrics;
+#[cfg(feature = "std")]
+pub mod notify;
+#[cfg(feature = "std")]
 pub mod observability;
+#[cfg(feature = "std")]
+pub mod policy;
+/// Without std, only policy evaluation (see `policy/eval.rs`).
+#[cfg(not(feature = "std"))]
+#[path = "policy/eval.rs"]
+pub mod policy;
+#[cfg(feature = "protobuf")]
+pub mod proto;
+#[cfg(feature = "std")]
+pub mod repro;
+#[cfg(feature = "std")]
+pub mod result_cache;
+#[cfg(feature = "std")]
 pub mod sandbox;
+#[cfg(feature = "std")]
+pub mod schedule;
 pub mod schema;
+#[cfg(feature = "std")]
+pub mod schemas;
+#[cfg(feature = "std")]
+pub mod shadow;
+#[cfg(feature = "std")]
+pub mod signing;
+#[cfg(feature = "std")]
+pub mod spool;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(feature = "std")]
+pub mod template;
+#[cfg(feature = "std")]
+pub mod tenant;
+#[cfg(all(feature = "std", any(test, feature = "testing")))]
+pub mod testing;
+#[cfg(feature = "std")]
+pub mod transport;
+#[cfg(feature = "std")]
+pub mod watch;
+#[cfg(feature = "std")]
+pub mod webhook;
+
+#[cfg(feature = "std")]
+pub use engine::{run_spell, ExecOptions};
+#[cfg(feature = "std")]
+pub use error::MagicruneError;
@@ -0,0 +1,396 @@
+//! The message catalog: every reason magicrune stops a request has a stable
+//! code and a text in each language it speaks.
+//!
+//! Codes are `MR` and four digits, grouped by what went wrong: `MR1xxx`
+//! the request itself, `MR2xxx` the policy, `MR3xxx` the produced result,
+//! `MR4xxx` magicrune or its sandbox, `MR5xxx` authentication. A code
+//! keeps its meaning once released; match on it rather than the text.
+//! ERROR_CODES.md lists them.
+//!
+//! A message may also say where in the request the problem is (a JSON
+//! Pointer) and which policy rule decided it, and each code has a hint on
+//! what to change; `exec --errors json` prints all of it.
+//!
+//! `MAGICRUNE_LANG` picks the language of the text (`ja`, else English).
+//! It is read when the message is made, so it follows the environment the
+//! engine sees (see `crate::ports::env`).
+
+use crate::schema::Finding;
+use std::fmt;
+
+/// Environment variable choosing the language of messages.
+pub const LANG_ENV: &str = "MAGICRUNE_LANG";
+
+/// A language messages are written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Lang {
+    #[default]
+    En,
+    Ja,
+}
+
+impl Lang {
+    /// `ja`, `ja_JP.UTF-8` and the like are Japanese; anything else English.
+    pub fn parse(s: &str) -> Self {
+        if s.trim().to_ascii_lowercase().starts_with("ja") {
+            Lang::Ja
+        } else {
+            Lang::En
+        }
+    }
+
+    /// The language [`LANG_ENV`] asks for.
+    pub fn current() -> Self {
+        crate::ports::env::var(LANG_ENV)
+            .map(|v| Self::parse(&v))
+            .unwrap_or_default()
+    }
+}
+
+/// Each entry: name, code, English and Japanese template. `{}` is replaced
+/// by the message's arguments in order.
+macro_rules! catalog {
+    ($($(#[$doc:meta])* $name:ident = $code:literal, $en:literal, $ja:literal;)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum Code {
+            $($(#[$doc])* $name,)*
+        }
+
+        impl Code {
+            /// Every code, in catalog order.
+            pub const ALL: &'static [Code] = &[$(Code::$name),*];
+
+            /// `MR1001` and so on.
+            pub fn as_str(self) -> &'static str {
+                match self {
+                    $(Code::$name => $code,)*
+                }
+            }
+
+            fn template(self, lang: Lang) -> &'static str {
+                match (self, lang) {
+                    $((Code::$name, Lang::En) => $en,
+                    (Code::$name, Lang::Ja) => $ja,)*
+                }
+            }
+        }
+    };
+}
+
+catalog! {
+    /// The request breaks the request schema.
+    Schema = "MR1001", "schema: {}", "スキーマ: {}";
+    /// The request is not JSON.
+    InvalidJson = "MR1002", "Invalid JSON: {}", "JSON として読めません: {}";
+    /// The request is JSON but not a request.
+    RequestShape = "MR1003", "Invalid request shape: {}", "リクエストの形式が不正です: {}";
+    /// A request bundle (tar or zip) cannot be unpacked.
+    Bundle = "MR1004", "bundle: {}", "バンドル: {}";
+    /// `callback_url` is not a URL magicrune will call.
+    CallbackUrl = "MR1005", "callback_url: {}", "callback_url: {}";
+    /// `tenant` is not a valid tenant name.
+    InvalidTenant = "MR1006", "invalid tenant name {}", "テナント名が不正です: {}";
+    /// An environment variable matches `env.deny`.
+    EnvDenied = "MR2001", "policy: env deny {}", "ポリシー: 環境変数 {} は拒否されています";
+    /// An environment variable is not in `env.allow`.
+    EnvNotAllowed = "MR2002", "policy: env not allowed {}", "ポリシー: 環境変数 {} は許可されていません";
+    /// The command reaches a host no allowlist grants.
+    NetDenied = "MR2003", "policy: network to {} not allowed", "ポリシー: {} へのネットワーク接続は許可されていません";
+    /// The command uses the network and nothing allows any.
+    NetNoAllowlist = "MR2004", "policy: network is not allowed (no allowlist)", "ポリシー: ネットワークは許可されていません (許可リストがありません)";
+    /// The command uses a device `capabilities.devices.allow` does not grant.
+    DeviceDenied = "MR2005", "policy: device {} not allowed", "ポリシー: デバイス {} は許可されていません";
+    /// The tenant has used up its run quota.
+    TenantQuota = "MR2006", "policy: tenant {} is over its run quota", "ポリシー: テナント {} は実行回数の上限を超えています";
+    /// `timeout_sec` is above the policy's `limits.wall_sec`.
+    TimeoutAboveWall = "MR2007", "policy: timeout_sec {} exceeds wall_sec limit {}", "ポリシー: timeout_sec {} が wall_sec の上限 {} を超えています";
+    /// A request limit is above the policy's.
+    LimitAboveCeiling = "MR2008", "policy: limits.{} {} exceeds policy ceiling {}", "ポリシー: limits.{} {} がポリシーの上限 {} を超えています";
+    /// An interactive run whose command line alone grades red.
+    InteractiveRed = "MR2009", "policy: interactive: static verdict is red (risk {})", "ポリシー: interactive: 静的判定が red です (リスク {})";
+    /// A file outside the writable paths.
+    WriteDenied = "MR2010", "policy: write denied for {}", "ポリシー: {} への書き込みは拒否されました";
+    /// A file under `fs.readonly`.
+    ReadonlyWrite = "MR2011", "policy: write to readonly {}", "ポリシー: 読み取り専用の {} には書き込めません";
+    /// The request names another tenant than the one it is bound to.
+    TenantMismatch = "MR2012", "policy: tenant {} may not submit as {}", "ポリシー: テナント {} は {} として送信できません";
+    /// A redelivered run that never finished, under `exec.on_redelivery: fail`.
+    Redelivered = "MR2013", "policy: run {} was started and never finished (exec.on_redelivery: fail)", "ポリシー: 実行 {} は開始されたまま終了していません (exec.on_redelivery: fail)";
+    /// A program of the command whose digest `capabilities.exec.digests`
+    /// does not list, under `capabilities.exec.unapproved: block`.
+    UnapprovedBinary = "MR2014", "policy: {} (sha256 {}) is not an approved binary", "ポリシー: {} (sha256 {}) は承認されたバイナリではありません";
+    /// A request for network while network is off (`exec --offline` or
+    /// `capabilities.net.default: disabled_hard`).
+    NetDisabled = "MR2015", "policy: network is disabled ({})", "ポリシー: ネットワークは無効化されています ({})";
+    /// The result breaks the result schema.
+    OutputSchema = "MR3001", "output schema: {}", "出力スキーマ: {}";
+    /// Any other failure inside magicrune.
+    Internal = "MR4001", "{}", "{}";
+    /// A request file could not be written.
+    WriteFailed = "MR4002", "write failed: {}: {}", "書き込みに失敗しました: {}: {}";
+    /// The command could not be started.
+    SpawnFailed = "MR4003", "spawn failed: {}", "起動に失敗しました: {}";
+    /// Interactive mode outside the local Linux native sandbox.
+    InteractiveSandbox = "MR4004", "interactive mode needs the local Linux native sandbox", "interactive モードにはローカルの Linux ネイティブサンドボックスが必要です";
+    /// The policy file cannot be read or parsed.
+    PolicyLoad = "MR4005", "{}", "{}";
+    /// The request is unsigned or its signature does not verify.
+    Unauthenticated = "MR5001", "unauthenticated request: {}", "認証されていないリクエスト: {}";
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Code {
+    /// What to change so the request goes through.
+    pub fn remediation(self, lang: Lang) -> &'static str {
+        let (en, ja) = match self {
+            Code::Schema => (
+                "fix the field against schemas/spell_request.schema.json",
+                "schemas/spell_request.schema.json に合わせてフィールドを直してください",
+            ),
+            Code::InvalidJson => (
+                "send the request as a JSON object",
+                "リクエストを JSON オブジェクトで送ってください",
+            ),
+            Code::RequestShape => (
+                "give each field the type the request schema has for it",
+                "各フィールドをリクエストスキーマの型に合わせてください",
+            ),
+            Code::Bundle => (
+                "pack request.json and the input files at the bundle root, with relative paths",
+                "request.json と入力ファイルを相対パスでバンドルの直下に入れてください",
+            ),
+            Code::CallbackUrl => (
+                "give an absolute http:// or https:// URL with a host",
+                "ホスト付きの絶対 URL (http:// か https://) を指定してください",
+            ),
+            Code::InvalidTenant => (
+                "use 1-63 letters, digits, '-' or '_', starting and ending with a letter or digit",
+                "英数字・'-'・'_' の 1〜63 文字で、先頭と末尾は英数字にしてください",
+            ),
+            Code::EnvDenied => (
+                "drop the variable from env; the policy denies it",
+                "env からこの変数を外してください (ポリシーで拒否されています)",
+            ),
+            Code::EnvNotAllowed => (
+                "drop the variable from env or add it to capabilities.env.allow",
+                "env からこの変数を外すか capabilities.env.allow に追加してください",
+            ),
+            Code::NetDenied => (
+                "add the host to allow_net or to capabilities.net.allow",
+                "ホストを allow_net か capabilities.net.allow に追加してください",
+            ),
+            Code::NetNoAllowlist => (
+                "list the hosts the command needs in allow_net or capabilities.net.allow",
+                "コマンドが使うホストを allow_net か capabilities.net.allow に列挙してください",
+            ),
+            Code::DeviceDenied => (
+                "add the device to capabilities.devices.allow",
+                "デバイスを capabilities.devices.allow に追加してください",
+            ),
+            Code::TenantQuota => (
+                "retry once the quota window has passed",
+                "クォータの期間が過ぎてから再実行してください",
+            ),
+            Code::TimeoutAboveWall => (
+                "lower timeout_sec to at most the policy's limits.wall_sec",
+                "timeout_sec をポリシーの limits.wall_sec 以下にしてください",
+            ),
+            Code::LimitAboveCeiling => (
+                "lower the limit to at most the policy's value or leave it out",
+                "limits をポリシーの値以下にするか指定を外してください",
+            ),
+            Code::InteractiveRed => (
+                "run it without --interactive, or remove what raises the risk score",
+                "--interactive を外すか、リスクスコアを上げる要素を取り除いてください",
+            ),
+            Code::WriteDenied => (
+                "write under /tmp/ or add the path to capabilities.fs.allow",
+                "/tmp/ 以下に書くか capabilities.fs.allow にパスを追加してください",
+            ),
+            Code::ReadonlyWrite => (
+                "write somewhere outside capabilities.fs.readonly",
+                "capabilities.fs.readonly の外に書いてください",
+            ),
+            Code::TenantMismatch => (
+                "drop tenant from the request or submit on the tenant's own subject",
+                "リクエストの tenant を外すか、そのテナントの subject に送ってください",
+            ),
+            Code::Redelivered => (
+                "submit the request again with another seed",
+                "seed を変えてリクエストを送り直してください",
+            ),
+            Code::UnapprovedBinary => (
+                "run only programs listed in capabilities.exec.digests, or add the digest there",
+                "capabilities.exec.digests に載っているプログラムだけを実行するか、そこにダイジェストを追加してください",
+            ),
+            Code::NetDisabled => (
+                "drop allow_net and network use from the request; this runner allows no network",
+                "リクエストから allow_net とネットワーク利用を外してください。このランナーはネットワークを許可しません",
+            ),
+            Code::OutputSchema => (
+                "report this: magicrune produced a result its own schema rejects",
+                "magicrune の不具合です: 結果が自身のスキーマに合いません",
+            ),
+            Code::Internal | Code::WriteFailed | Code::SpawnFailed => (
+                "check the sandbox host's logs and retry",
+                "サンドボックスのホストのログを確認して再実行してください",
+            ),
+            Code::InteractiveSandbox => (
+                "use a Linux build with linux_native and the local backend",
+                "linux_native 付きの Linux ビルドとローカルバックエンドで実行してください",
+            ),
+            Code::PolicyLoad => (
+                "check the policy file path and its YAML",
+                "ポリシーファイルのパスと YAML を確認してください",
+            ),
+            Code::Unauthenticated => (
+                "sign the request with a producer key the consumer trusts",
+                "consumer が信頼する producer 鍵でリクエストに署名してください",
+            ),
+        };
+        match lang {
+            Lang::En => en,
+            Lang::Ja => ja,
+        }
+    }
+}
+
+/// A coded message, rendered in one language.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub code: Code,
+    pub text: String,
+    /// JSON Pointer to the request field at fault; empty when there is
+    /// none.
+    pub pointer: String,
+    /// The policy rule that decided it, as `key: value`; empty when no
+    /// rule did.
+    pub rule: String,
+}
+
+impl Message {
+    /// `code` with `args`, in the language of [`Lang::current`].
+    pub fn new(code: Code, args: &[&dyn fmt::Display]) -> Self {
+        Self::in_lang(code, Lang::current(), args)
+    }
+
+    pub fn in_lang(code: Code, lang: Lang, args: &[&dyn fmt::Display]) -> Self {
+        let mut text = String::new();
+        let mut args = args.iter();
+        for (i, part) in code.template(lang).split("{}").enumerate() {
+            if i > 0 {
+                if let Some(a) = args.next() {
+                    text.push_str(&a.to_string());
+                }
+            }
+            text.push_str(part);
+        }
+        Self {
+            code,
+            text,
+            pointer: String::new(),
+            rule: String::new(),
+        }
+    }
+
+    /// The request field at fault, as a JSON Pointer.
+    pub fn at(mut self, pointer: impl Into<String>) -> Self {
+        self.pointer = pointer.into();
+        self
+    }
+
+    /// The policy rule that decided it.
+    pub fn rule(mut self, rule: impl Into<String>) -> Self {
+        self.rule = rule.into();
+        self
+    }
+
+    /// The message as a result finding: the code is the kind.
+    pub fn finding(&self) -> Finding {
+        Finding {
+            kind: self.code.as_str().to_string(),
+            detail: self.text.clone(),
+            count: 1,
+        }
+    }
+}
+
+/// `key` escaped as one JSON Pointer reference token.
+pub fn pointer_token(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", self.code, self.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catalog() {
+        let mut seen = std::collections::HashSet::new();
+        for &code in Code::ALL {
+            let s = code.as_str();
+            assert!(
+                s.len() == 6 && s.starts_with("MR") && s[2..].bytes().all(|b| b.is_ascii_digit()),
+                "{}",
+                s
+            );
+            assert!(seen.insert(s), "{} listed twice", s);
+            assert!(
+                !code.remediation(Lang::En).is_empty() && !code.remediation(Lang::Ja).is_empty()
+            );
+            assert_eq!(
+                code.template(Lang::En).matches("{}").count(),
+                code.template(Lang::Ja).matches("{}").count(),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let m = Message::in_lang(Code::NetDenied, Lang::En, &[&"x.test:443"]);
+        assert_eq!(m.text, "policy: network to x.test:443 not allowed");
+        assert_eq!(
+            m.to_string(),
+            "[MR2003] policy: network to x.test:443 not allowed"
+        );
+        let m = Message::in_lang(Code::LimitAboveCeiling, Lang::Ja, &[&"pids", &65, &64]);
+        assert_eq!(
+            m.text,
+            "ポリシー: limits.pids 65 がポリシーの上限 64 を超えています"
+        );
+        assert_eq!(
+            m.finding(),
+            Finding {
+                kind: "MR2008".into(),
+                detail: m.text.clone(),
+                count: 1
+            }
+        );
+        assert_eq!(Lang::parse("ja_JP.UTF-8"), Lang::Ja);
+        assert_eq!(Lang::parse("en"), Lang::En);
+    }
+
+    #[test]
+    fn test_lang_from_env() {
+        let _env = crate::testing::MockEnvironmentPort::new()
+            .with_var(LANG_ENV, "ja")
+            .install();
+        assert_eq!(
+            Message::new(Code::EnvDenied, &[&"AWS_KEY"]).text,
+            "ポリシー: 環境変数 AWS_KEY は拒否されています"
+        );
+    }
+}
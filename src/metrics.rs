@@ -0,0 +1,228 @@
+//! Per-run resource histograms in the Prometheus text format.
+//!
+//! Each result that carries `pids_peak`, `usage` or `queue_ms` is observed
+//! into process-wide histograms. Consumers export them on the health server's
+//! `GET /metrics` (`consume --health-addr`) and in the
+//! `MAGICRUNE_METRICS_TEXTFILE` file. Samples carry an `instance` label
+//! once [`set_instance`] has been called (see `crate::instance`).
+//!
+//! With a shadow policy (see `crate::shadow`), its evaluations and
+//! divergences are counted alongside.
+
+use crate::schema::SpellResult;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::{Mutex, OnceLock};
+
+static INSTANCE: OnceLock<String> = OnceLock::new();
+
+/// Label every sample with `instance="<id>"`; the first call wins.
+pub fn set_instance(id: &str) {
+    let _ = INSTANCE.set(id.to_string());
+}
+
+/// `{instance="<id>",k="v",...}` for `extra`, or nothing when there is no
+/// label at all.
+pub fn labels(extra: &[(&str, &str)]) -> String {
+    let pairs: Vec<String> = INSTANCE
+        .get()
+        .map(|id| ("instance", id.as_str()))
+        .into_iter()
+        .chain(extra.iter().copied())
+        .map(|(k, v)| format!("{}=\"{}\"", k, v))
+        .collect();
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    bounds: &'static [f64],
+    /// Observations per bucket (the last one is `+Inf`), and their sum.
+    state: Mutex<(Vec<u64>, f64)>,
+}
+
+impl Histogram {
+    pub const fn new(name: &'static str, help: &'static str, bounds: &'static [f64]) -> Self {
+        Self {
+            name,
+            help,
+            bounds,
+            state: Mutex::new((Vec::new(), 0.0)),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let mut state = self.state.lock().unwrap();
+        let (counts, sum) = &mut *state;
+        counts.resize(self.bounds.len() + 1, 0);
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|b| value <= *b)
+            .unwrap_or(self.bounds.len());
+        counts[bucket] += 1;
+        *sum += value;
+    }
+
+    /// Append the histogram as `<prefix>_<name>` to `out`.
+    pub fn render(&self, prefix: &str, out: &mut String) {
+        let state = self.state.lock().unwrap();
+        let (counts, sum) = &*state;
+        let name = format!("{}_{}", prefix, self.name);
+        let _ = writeln!(out, "# HELP {} {}", name, self.help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut total = 0;
+        let bucket = |le: &str| labels(&[("le", le)]);
+        for (i, bound) in self.bounds.iter().enumerate() {
+            total += counts.get(i).copied().unwrap_or(0);
+            let le = bucket(&bound.to_string());
+            let _ = writeln!(out, "{}_bucket{} {}", name, le, total);
+        }
+        total += counts.get(self.bounds.len()).copied().unwrap_or(0);
+        let _ = writeln!(out, "{}_bucket{} {}", name, bucket("+Inf"), total);
+        let _ = writeln!(out, "{}_sum{} {}", name, labels(&[]), sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels(&[]), total);
+    }
+}
+
+const BYTES: &[f64] = &[1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9];
+
+pub static PIDS_PEAK: Histogram = Histogram::new(
+    "run_pids_peak",
+    "Most processes of a run alive at once.",
+    &[1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0],
+);
+pub static READ_BYTES: Histogram = Histogram::new(
+    "run_read_bytes",
+    "Bytes a run read through read calls.",
+    BYTES,
+);
+pub static WRITE_BYTES: Histogram = Histogram::new(
+    "run_write_bytes",
+    "Bytes a run wrote through write calls.",
+    BYTES,
+);
+pub static CPU_UTILIZATION: Histogram = Histogram::new(
+    "run_cpu_utilization",
+    "CPU time of a run over its wall time, in cores.",
+    &[0.05, 0.1, 0.25, 0.5, 0.75, 1.0, 2.0, 4.0],
+);
+
+pub static QUEUE_MS: Histogram = Histogram::new(
+    "run_queue_ms",
+    "Time a request waited in the queue before a consumer started on it, in ms.",
+    &[10.0, 100.0, 500.0, 1e3, 5e3, 1e4, 3e4, 6e4, 3e5],
+);
+
+/// Record the resource figures and queue wait of `result`, when it has them.
+pub fn observe(result: &SpellResult) {
+    if let Some(ms) = result.queue_ms {
+        QUEUE_MS.observe(ms as f64);
+    }
+    if let Some(peak) = result.pids_peak {
+        PIDS_PEAK.observe(peak as f64);
+    }
+    if let Some(usage) = &result.usage {
+        READ_BYTES.observe(usage.read_bytes as f64);
+        WRITE_BYTES.observe(usage.write_bytes as f64);
+        CPU_UTILIZATION.observe(usage.cpu_pct as f64 / 100.0);
+    }
+}
+
+/// Shadow evaluations, those that diverged, and divergences by field.
+static SHADOW: Mutex<(u64, u64, BTreeMap<String, u64>)> = Mutex::new((0, 0, BTreeMap::new()));
+
+/// Count one shadow evaluation that differed from the active policy in
+/// `fields` (none when they agreed).
+pub fn observe_shadow(fields: &[&str]) {
+    let mut shadow = SHADOW.lock().unwrap();
+    shadow.0 += 1;
+    if !fields.is_empty() {
+        shadow.1 += 1;
+    }
+    for f in fields {
+        *shadow.2.entry(f.to_string()).or_default() += 1;
+    }
+}
+
+/// Every histogram, named `<prefix>_run_*`, then the shadow counters once
+/// there has been a shadow evaluation.
+pub fn render(prefix: &str) -> String {
+    let mut out = String::new();
+    for h in [
+        &PIDS_PEAK,
+        &READ_BYTES,
+        &WRITE_BYTES,
+        &CPU_UTILIZATION,
+        &QUEUE_MS,
+    ] {
+        h.render(prefix, &mut out);
+    }
+    let shadow = SHADOW.lock().unwrap();
+    if shadow.0 > 0 {
+        let counters = [
+            (
+                "evaluations",
+                "Requests also graded under the shadow policy.",
+                shadow.0,
+            ),
+            (
+                "divergent",
+                "Requests the shadow policy decided differently.",
+                shadow.1,
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {}_shadow_{}_total {}", prefix, name, help);
+            let _ = writeln!(out, "# TYPE {}_shadow_{}_total counter", prefix, name);
+            let _ = writeln!(
+                out,
+                "{}_shadow_{}_total{} {}",
+                prefix,
+                name,
+                labels(&[]),
+                value
+            );
+        }
+        let name = format!("{}_shadow_divergences_total", prefix);
+        let _ = writeln!(
+            out,
+            "# HELP {} Shadow divergences by differing field.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for (field, n) in &shadow.2 {
+            let _ = writeln!(out, "{}{} {}", name, labels(&[("field", field)]), n);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_render() {
+        static H: Histogram = Histogram::new("x", "An x.", &[1.0, 10.0]);
+        let mut out = String::new();
+        H.render("m", &mut out);
+        assert!(out.contains("m_x_bucket{le=\"+Inf\"} 0\nm_x_sum 0\nm_x_count 0\n"));
+        for v in [0.5, 1.0, 5.0, 50.0] {
+            H.observe(v);
+        }
+        let mut out = String::new();
+        H.render("m", &mut out);
+        assert_eq!(
+            out,
+            "# HELP m_x An x.\n# TYPE m_x histogram\n\
+             m_x_bucket{le=\"1\"} 2\nm_x_bucket{le=\"10\"} 3\nm_x_bucket{le=\"+Inf\"} 4\n\
+             m_x_sum 56.5\nm_x_count 4\n"
+        );
+    }
+}
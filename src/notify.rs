@@ -0,0 +1,320 @@
+//! Alerts on red verdicts and quarantines.
+//!
+//! With `MAGICRUNE_NOTIFY_URL` set, every red result (and, for `exec`, the
+//! quarantine that follows it) is POSTed there as a Slack-style message:
+//!
+//! ```json
+//! {"text": "...", "event": "red", "run_id": "r_...", "tenant": "default",
+//!  "verdict": "red", "risk_score": 80, "rule_hits": {"ssh": 30}, "link": "..."}
+//! ```
+//!
+//! `text` is rendered from `MAGICRUNE_NOTIFY_TEMPLATE`, whose `{event}`,
+//! `{run_id}`, `{tenant}`, `{verdict}`, `{risk_score}`, `{rules}` and
+//! `{link}` placeholders are filled in; `{link}` is `MAGICRUNE_NOTIFY_LINK`
+//! with its own `{run_id}` filled in, for a page showing the run. At most
+//! `MAGICRUNE_NOTIFY_MAX_PER_MIN` alerts go out a minute; the rest are
+//! counted and the count is carried by the next alert sent, so a burst of red
+//! runs does not turn into an alert storm. Alerts are not retried.
+
+use crate::schema::SpellResult;
+use crate::webhook::{self, WebhookError};
+use serde::Serialize;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_TEMPLATE: &str =
+    "magicrune: {event} run {run_id} (tenant {tenant}, risk {risk_score}) rules: {rules} {link}";
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyEvent {
+    /// A result graded red.
+    Red,
+    /// A red run's result and output kept under `quarantine/`.
+    Quarantine,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotifyConfig {
+    pub url: String,
+    /// Text of the message, with placeholders.
+    pub template: String,
+    /// Link to the run, with a `{run_id}` placeholder.
+    pub link: Option<String>,
+    /// Alerts sent per minute at most.
+    pub max_per_min: u32,
+    pub timeout_ms: u64,
+}
+
+impl NotifyConfig {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            template: DEFAULT_TEMPLATE.to_string(),
+            link: None,
+            max_per_min: 10,
+            timeout_ms: 5_000,
+        }
+    }
+
+    /// `MAGICRUNE_NOTIFY_URL`, `MAGICRUNE_NOTIFY_TEMPLATE`,
+    /// `MAGICRUNE_NOTIFY_LINK`, `MAGICRUNE_NOTIFY_MAX_PER_MIN`; `None` when
+    /// no URL is set.
+    pub fn from_env() -> Result<Option<Self>, WebhookError> {
+        let var = |k: &str| crate::ports::env::var(k).ok().filter(|v| !v.is_empty());
+        let Some(url) = var("MAGICRUNE_NOTIFY_URL") else {
+            return Ok(None);
+        };
+        webhook::check_url(&url)?;
+        let d = Self::new(url);
+        Ok(Some(Self {
+            template: var("MAGICRUNE_NOTIFY_TEMPLATE").unwrap_or(d.template.clone()),
+            link: var("MAGICRUNE_NOTIFY_LINK"),
+            max_per_min: match var("MAGICRUNE_NOTIFY_MAX_PER_MIN") {
+                Some(v) => v.parse().map_err(|_| {
+                    WebhookError(format!(
+                        "MAGICRUNE_NOTIFY_MAX_PER_MIN: {:?} is not a count",
+                        v
+                    ))
+                })?,
+                None => d.max_per_min,
+            },
+            ..d
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    sent: u32,
+    suppressed: u32,
+}
+
+#[derive(Debug)]
+pub struct Notifier {
+    cfg: NotifyConfig,
+    window: Mutex<Window>,
+}
+
+impl Notifier {
+    pub fn new(cfg: NotifyConfig) -> Self {
+        Self {
+            cfg,
+            window: Mutex::new(Window {
+                start: Instant::now(),
+                sent: 0,
+                suppressed: 0,
+            }),
+        }
+    }
+
+    /// Whether an alert may go out at `now`, and if so how many were held
+    /// back since the last one.
+    fn admit(&self, now: Instant) -> Option<u32> {
+        let mut w = self.window.lock().unwrap_or_else(|e| e.into_inner());
+        if now.duration_since(w.start) >= WINDOW {
+            w.start = now;
+            w.sent = 0;
+        }
+        if w.sent >= self.cfg.max_per_min {
+            w.suppressed += 1;
+            return None;
+        }
+        w.sent += 1;
+        Some(std::mem::take(&mut w.suppressed))
+    }
+
+    /// The message for `res`; `suppressed` alerts were held back before it.
+    pub fn message(
+        &self,
+        event: NotifyEvent,
+        res: &SpellResult,
+        suppressed: u32,
+    ) -> serde_json::Value {
+        let rules = res
+            .risk_breakdown
+            .as_ref()
+            .map(|b| b.rules.clone())
+            .unwrap_or_default();
+        let rule_list = if rules.is_empty() {
+            "none".to_string()
+        } else {
+            rules
+                .iter()
+                .map(|(r, p)| format!("{} {}", r, p))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let link = self
+            .cfg
+            .link
+            .as_deref()
+            .map(|l| l.replace("{run_id}", &res.run_id))
+            .unwrap_or_default();
+        let event_name = match event {
+            NotifyEvent::Red => "red",
+            NotifyEvent::Quarantine => "quarantine",
+        };
+        let mut text = self
+            .cfg
+            .template
+            .replace("{event}", event_name)
+            .replace("{run_id}", &res.run_id)
+            .replace("{tenant}", &res.tenant)
+            .replace("{verdict}", &res.verdict)
+            .replace("{risk_score}", &res.risk_score.to_string())
+            .replace("{rules}", &rule_list)
+            .replace("{link}", &link)
+            .trim_end()
+            .to_string();
+        if suppressed > 0 {
+            text.push_str(&format!(" (+{} more suppressed)", suppressed));
+        }
+        serde_json::json!({
+            "text": text,
+            "event": event,
+            "run_id": res.run_id,
+            "tenant": res.tenant,
+            "verdict": res.verdict,
+            "risk_score": res.risk_score,
+            "rule_hits": rules,
+            "link": link,
+            "suppressed": suppressed,
+        })
+    }
+
+    /// Alert on `res` if it is red, within the rate limit. Failures are
+    /// logged; the run is not held up beyond the timeout.
+    pub async fn notify(&self, event: NotifyEvent, res: &SpellResult) {
+        if res.verdict != "red" {
+            return;
+        }
+        let Some(suppressed) = self.admit(Instant::now()) else {
+            tracing::debug!(run_id = %res.run_id, "notify: rate limited");
+            return;
+        };
+        let body = self.message(event, res, suppressed);
+        if let Err(e) = self.post(&body).await {
+            tracing::warn!(run_id = %res.run_id, error = %e, "notify failed");
+        }
+    }
+
+    async fn post(&self, body: &serde_json::Value) -> Result<(), WebhookError> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(self.cfg.timeout_ms))
+            .build()
+            .map_err(|e| WebhookError(e.to_string()))?;
+        let resp = client
+            .post(&self.cfg.url)
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(|e| WebhookError(e.to_string()))?;
+        if resp.status().is_success() {
+            Ok(())
+        } else {
+            Err(WebhookError(format!(
+                "{} answered {}",
+                self.cfg.url,
+                resp.status()
+            )))
+        }
+    }
+}
+
+/// The process-wide notifier, read from the environment once; a bad setting
+/// is logged and disables alerts.
+pub fn global() -> Option<&'static std::sync::Arc<Notifier>> {
+    static NOTIFIER: OnceLock<Option<std::sync::Arc<Notifier>>> = OnceLock::new();
+    NOTIFIER
+        .get_or_init(|| match NotifyConfig::from_env() {
+            Ok(cfg) => cfg.map(|c| std::sync::Arc::new(Notifier::new(c))),
+            Err(e) => {
+                tracing::warn!(error = %e, "notifications disabled");
+                None
+            }
+        })
+        .as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::RiskBreakdown;
+    use std::io::{Read, Write};
+
+    fn red() -> SpellResult {
+        SpellResult {
+            run_id: "r_1".into(),
+            verdict: "red".into(),
+            risk_score: 70,
+            tenant: "acme".into(),
+            risk_breakdown: Some(RiskBreakdown {
+                rules: [("ssh".to_string(), 30), ("net_intent".to_string(), 40)].into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_message() {
+        let n = Notifier::new(NotifyConfig {
+            link: Some("https://ops.example.com/runs/{run_id}".into()),
+            ..NotifyConfig::new("http://127.0.0.1:9/")
+        });
+        let m = n.message(NotifyEvent::Quarantine, &red(), 2);
+        assert_eq!(
+            m["text"],
+            "magicrune: quarantine run r_1 (tenant acme, risk 70) rules: net_intent 40, ssh 30 https://ops.example.com/runs/r_1 (+2 more suppressed)"
+        );
+        assert_eq!(m["event"], "quarantine");
+        assert_eq!(m["rule_hits"]["ssh"], 30);
+        let plain = Notifier::new(NotifyConfig::new("http://127.0.0.1:9/"));
+        let m = plain.message(NotifyEvent::Red, &SpellResult::default(), 0);
+        assert!(m["text"].as_str().unwrap().ends_with("rules: none"));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let n = Notifier::new(NotifyConfig {
+            max_per_min: 2,
+            ..NotifyConfig::new("http://127.0.0.1:9/")
+        });
+        let t = Instant::now();
+        assert_eq!(n.admit(t), Some(0));
+        assert_eq!(n.admit(t), Some(0));
+        assert_eq!(n.admit(t), None);
+        assert_eq!(n.admit(t + Duration::from_secs(1)), None);
+        assert_eq!(n.admit(t + WINDOW), Some(2));
+        assert_eq!(n.admit(t + WINDOW), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_notify_posts_red_only() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut req = Vec::new();
+            let mut buf = [0u8; 4096];
+            while !String::from_utf8_lossy(&req).contains("\"run_id\"") {
+                let n = conn.read(&mut buf).unwrap();
+                req.extend_from_slice(&buf[..n]);
+            }
+            conn.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8_lossy(&req).to_string()
+        });
+        let n = Notifier::new(NotifyConfig::new(url));
+        n.notify(NotifyEvent::Red, &SpellResult::default()).await;
+        n.notify(NotifyEvent::Red, &red()).await;
+        let req = server.join().unwrap();
+        assert!(req.starts_with("POST /hook "));
+        assert!(req.contains("\"rule_hits\":{\"net_intent\":40,\"ssh\":30}"));
+    }
+}
@@ -20,12 +20,14 @@ pub fn init_observability() -> Result<(), Box<dyn std::error::Error + Send + Syn
             .with_env_filter(env_filter)
             .with_target(true)
             .with_current_span(true)
+            .with_writer(std::io::stderr)
             .try_init()?;
     } else {
         tracing_subscriber::fmt()
             .pretty()
             .with_env_filter(env_filter)
             .with_target(false)
+            .with_writer(std::io::stderr)
             .try_init()?;
     }
 
@@ -73,14 +75,18 @@ fn init_otel_tracer(
 pub struct ExecutionContext {
     pub run_id: String,
     pub policy_id: String,
+    pub policy_digest: String,
+    pub tenant: String,
     pub start_time: Instant,
 }
 
 impl ExecutionContext {
-    pub fn new(run_id: String, policy_id: String) -> Self {
+    pub fn new(run_id: String, policy_id: String, policy_digest: String, tenant: String) -> Self {
         Self {
             run_id,
             policy_id,
+            policy_digest,
+            tenant,
             start_time: Instant::now(),
         }
     }
@@ -91,6 +97,8 @@ impl ExecutionContext {
             "exec",
             run_id = %self.run_id,
             policy_id = %self.policy_id,
+            policy_digest = %self.policy_digest,
+            tenant = %self.tenant,
             otel.kind = "server",
         )
     }
@@ -114,6 +122,7 @@ impl ExecutionContext {
             metric_name = "magicrune_execution_duration_ms",
             value = duration_ms,
             run_id = %self.run_id,
+            tenant = %self.tenant,
             verdict = %verdict,
             "metric"
         );
@@ -122,6 +131,7 @@ impl ExecutionContext {
             metric_name = "magicrune_risk_score",
             value = risk_score,
             run_id = %self.run_id,
+            tenant = %self.tenant,
             verdict = %verdict,
             "metric"
         );
@@ -141,6 +151,7 @@ impl ExecutionContext {
             metric_name = "magicrune_policy_violations_total",
             value = 1,
             run_id = %self.run_id,
+            tenant = %self.tenant,
             violation_type = %violation_type,
             "metric"
         );
@@ -160,12 +171,36 @@ impl ExecutionContext {
             metric_name = "magicrune_errors_total",
             value = 1,
             run_id = %self.run_id,
+            tenant = %self.tenant,
             error_code = %error_code,
             "metric"
         );
     }
 }
 
+/// Log the verdict a consumer reached for one message, and export it to the
+/// audit sink (`crate::audit`)
+pub fn log_policy_decision(
+    run_id: &str,
+    subject: &str,
+    policy_digest: &str,
+    verdict: &str,
+    risk_score: u32,
+) {
+    info!(
+        run_id = %run_id,
+        subject = %subject,
+        policy_digest = %policy_digest,
+        verdict = %verdict,
+        risk_score = risk_score,
+        "Policy decision"
+    );
+    if let Some(sink) = crate::audit::global() {
+        let event = crate::audit::AuditEvent::graded(run_id, policy_digest, verdict, risk_score);
+        crate::audit::record(sink.as_ref(), &event);
+    }
+}
+
 /// Log sandbox operations
 #[instrument]
 pub fn log_sandbox_operation(sandbox_type: &str, operation: &str, success: bool) {
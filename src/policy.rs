@@ -0,0 +1,1332 @@
+//! Policy document loading.
+//!
+//! Policies are small YAML files; they are read with the same line-oriented
+//! walkers the CLI has always used so no YAML dependency is required.
+
+use crate::guard::OnRedelivery;
+use crate::ledger::RequestBody;
+use crate::messages::{Code, Message};
+use crate::sandbox::shell::Shell;
+use crate::schedule::Schedule;
+use crate::schema::RequestLimits;
+use std::str::FromStr;
+
+mod eval;
+
+pub use eval::*;
+
+/// A parsed policy file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Policy {
+    pub limits: PolicyLimits,
+    pub thresholds: Thresholds,
+    pub exfiltration: Exfiltration,
+    /// `grading.waivable`: grading rules a request may accept the risk of
+    /// (`accept_risk`); none by default.
+    pub waivable: Vec<String>,
+    /// `grading.mandatory`: grading rules that are never waived and grade
+    /// red whenever they fire, whatever the thresholds say.
+    pub mandatory: Vec<String>,
+    /// `capabilities.net.allow` entries (host[:port], wildcards, CIDRs),
+    /// with those of `capabilities.net.connect.allow`.
+    pub net_allow: Vec<String>,
+    /// `capabilities.net.dns.allow`: names (wildcards allowed) the stub
+    /// resolver in the command's network namespace answers, apart from
+    /// what it may connect to (see `crate::sandbox::dns`). Empty: no
+    /// resolver, as before.
+    pub dns_allow: Vec<String>,
+    /// `capabilities.net.proxy: on`: send the command's HTTP(S) traffic
+    /// through the egress proxy (see `crate::sandbox::egress`).
+    pub net_proxy: bool,
+    /// `capabilities.net.default: disabled_hard`: no network at all, as
+    /// with `exec --offline`; allowlists are ignored and requests for
+    /// network refused.
+    pub net_disabled: bool,
+    /// `capabilities.fs.allow` path patterns.
+    pub fs_allow: Vec<String>,
+    /// `capabilities.fs.readonly` path patterns.
+    pub fs_readonly: Vec<String>,
+    pub env_allow: Vec<String>,
+    pub env_deny: Vec<String>,
+    /// `capabilities.devices.allow` device node patterns (`/dev/kvm`,
+    /// `/dev/nvidia*`); none by default.
+    pub devices_allow: Vec<String>,
+    /// `capabilities.exec.digests`: file of the sha256 digests of the
+    /// programs commands may run (see `crate::sandbox::provenance`).
+    pub exec_digests: Option<String>,
+    /// `capabilities.exec.unapproved`: what a program not in
+    /// `exec_digests` does to the run; `red` unless set.
+    pub exec_unapproved: Unapproved,
+    /// `capabilities.syscalls.allow`: syscalls allowed on top of the native
+    /// sandbox's built-in seccomp allow-list.
+    pub syscalls_allow: Vec<String>,
+    /// `observe.syscalls: summary`: count the command's syscalls by category.
+    pub observe_syscalls: bool,
+    /// `observe.net: log`: log the command's connection attempts.
+    pub observe_net: bool,
+    /// `observe.provenance: record`: resolve and hash the programs the
+    /// command runs (see `crate::sandbox::provenance`).
+    pub observe_provenance: bool,
+    /// `observe.output: timeline`: record stdout and stderr as interleaved,
+    /// timestamped chunks (see `crate::sandbox::timeline`).
+    pub observe_output: bool,
+    /// `observe.env: names`: list the command's environment in the result
+    /// (see `crate::sandbox::environ`).
+    pub observe_env: bool,
+    /// `observe.env_values`: name patterns whose values are listed too
+    /// (`[PATH, "LC_*"]`); the rest may be secret.
+    pub env_values: Vec<String>,
+    /// `exec.pty: true`: run every command on a PTY of its own (requests
+    /// can ask with `pty: true`).
+    pub pty: bool,
+    /// `exec.strip_ansi: true`: drop ANSI escape sequences from captured
+    /// output (requests can ask with `strip_ansi: true`).
+    pub strip_ansi: bool,
+    /// `exec.shell`: interpreter for the command; `None` when unset (the
+    /// platform default) or not a known shell.
+    pub shell: Option<Shell>,
+    /// `exec.on_redelivery`: what to do with a run that was started and
+    /// never finished (see `crate::guard`); `rerun` unless set.
+    pub on_redelivery: OnRedelivery,
+    /// `requests.signature: required`: only run requests signed by a
+    /// producer key (see `crate::signing`).
+    pub require_signed_requests: bool,
+    /// `constraints`: when the restricted capabilities may be used (see
+    /// `crate::schedule`); `None` when they always may.
+    pub schedule: Option<Schedule>,
+    /// `constraints.runner_labels`: labels a consumer needs to run requests
+    /// under this policy (see `crate::instance`).
+    pub runner_labels: Vec<String>,
+    /// `ledger.request`: what run records keep of the request body (see
+    /// `crate::ledger`); `drop` unless set.
+    pub ledger_request: RequestBody,
+    /// `ledger.result: store`: keep the whole result in run records.
+    pub ledger_result: bool,
+    /// `debug.core_mb`: core dump size limit of the command, in MiB; 0 (the
+    /// default) keeps no core dumps (see `crate::sandbox::coredump`).
+    pub core_mb: u64,
+    /// `cache.ttl_sec`: how long a green result answers identical requests
+    /// under this policy without running them; 0 (the default) runs every
+    /// request (see `crate::result_cache`).
+    pub cache_ttl_sec: u64,
+    /// Hex sha256 of the policy text, logged as `policy_digest`; empty for
+    /// the built-in default.
+    pub digest: String,
+}
+
+impl Policy {
+    /// Parse a policy document. Missing keys fall back to defaults; grading
+    /// thresholds that do not parse or do not line up are an error.
+    pub fn from_yaml(text: &str) -> Result<Self, PolicyError> {
+        let (env_allow, env_deny) = parse_env_policy(text);
+        Ok(Self {
+            limits: parse_limits(text),
+            thresholds: Thresholds::from_yaml(text)?,
+            exfiltration: parse_exfiltration(text),
+            waivable: parse_grading_list(text, "waivable"),
+            mandatory: parse_grading_list(text, "mandatory"),
+            net_allow: parse_net_allow(text),
+            net_proxy: extract_yaml_scalar_under(text, "net", "proxy").as_deref() == Some("on"),
+            net_disabled: extract_yaml_scalar_under(text, "net", "default").as_deref()
+                == Some("disabled_hard"),
+            fs_allow: parse_fs_allow(text),
+            fs_readonly: parse_fs_readonly(text),
+            env_allow,
+            env_deny,
+            devices_allow: parse_caps_list(text, "devices", "allow"),
+            syscalls_allow: parse_caps_list(text, "syscalls", "allow"),
+            dns_allow: parse_caps_list(text, "dns", "allow"),
+            exec_digests: extract_yaml_scalar_under(text, "exec", "digests")
+                .filter(|v| !v.is_empty()),
+            exec_unapproved: extract_yaml_scalar_under(text, "exec", "unapproved")
+                .and_then(|v| {
+                    v.parse()
+                        .inspect_err(
+                            |e| tracing::warn!(error = %e, "policy: capabilities.exec.unapproved ignored"),
+                        )
+                        .ok()
+                })
+                .unwrap_or_default(),
+            observe_syscalls: extract_yaml_scalar_under(text, "observe", "syscalls").as_deref()
+                == Some("summary"),
+            observe_net: extract_yaml_scalar_under(text, "observe", "net").as_deref()
+                == Some("log"),
+            observe_provenance: extract_yaml_scalar_under(text, "observe", "provenance").as_deref()
+                == Some("record"),
+            observe_output: extract_yaml_scalar_under(text, "observe", "output").as_deref()
+                == Some("timeline"),
+            observe_env: extract_yaml_scalar_under(text, "observe", "env").as_deref()
+                == Some("names"),
+            env_values: extract_yaml_scalar_under(text, "observe", "env_values")
+                .map(|v| {
+                    crate::schedule::list(v.split('#').next().unwrap_or_default())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            pty: extract_yaml_scalar_under(text, "exec", "pty").as_deref() == Some("true"),
+            strip_ansi: extract_yaml_scalar_under(text, "exec", "strip_ansi").as_deref()
+                == Some("true"),
+            shell: extract_yaml_scalar_under(text, "exec", "shell").and_then(|v| {
+                v.parse()
+                    .inspect_err(|e| tracing::warn!(error = %e, "policy: exec.shell ignored"))
+                    .ok()
+            }),
+            on_redelivery: extract_yaml_scalar_under(text, "exec", "on_redelivery")
+                .and_then(|v| {
+                    v.parse()
+                        .inspect_err(
+                            |e| tracing::warn!(error = %e, "policy: exec.on_redelivery ignored"),
+                        )
+                        .ok()
+                })
+                .unwrap_or_default(),
+            require_signed_requests: extract_yaml_scalar_under(text, "requests", "signature")
+                .as_deref()
+                == Some("required"),
+            schedule: {
+                let get = |key: &str| {
+                    extract_yaml_scalar_under(text, "constraints", key)
+                        .map(|v| v.split('#').next().unwrap_or_default().trim().to_string())
+                };
+                Schedule::parse(
+                    get("timezone").as_deref(),
+                    get("allowed_days").as_deref(),
+                    get("allowed_hours").as_deref(),
+                    get("applies_to").as_deref(),
+                )?
+            },
+            runner_labels: extract_yaml_scalar_under(text, "constraints", "runner_labels")
+                .map(|v| {
+                    crate::schedule::list(v.split('#').next().unwrap_or_default())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            ledger_request: extract_yaml_scalar_under(text, "ledger", "request")
+                .and_then(|v| {
+                    v.parse()
+                        .inspect_err(
+                            |e| tracing::warn!(error = %e, "policy: ledger.request ignored"),
+                        )
+                        .ok()
+                })
+                .unwrap_or_default(),
+            ledger_result: extract_yaml_scalar_under(text, "ledger", "result").as_deref()
+                == Some("store"),
+            core_mb: extract_yaml_scalar_under(text, "debug", "core_mb")
+                .and_then(|v| {
+                    v.parse()
+                        .inspect_err(
+                            |e| tracing::warn!(error = %e, "policy: debug.core_mb ignored"),
+                        )
+                        .ok()
+                })
+                .unwrap_or(0),
+            cache_ttl_sec: extract_yaml_scalar_under(text, "cache", "ttl_sec")
+                .and_then(|v| {
+                    v.parse()
+                        .inspect_err(
+                            |e| tracing::warn!(error = %e, "policy: cache.ttl_sec ignored"),
+                        )
+                        .ok()
+                })
+                .unwrap_or(0),
+            digest: {
+                use sha2::{Digest, Sha256};
+                Sha256::digest(text.as_bytes())
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect()
+            },
+        })
+    }
+
+    /// Load a policy file. An unreadable file yields the default policy.
+    pub fn load(path: &str) -> Result<Self, PolicyError> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => {
+                Self::from_yaml(&text).map_err(|e| PolicyError(format!("{}: {}", path, e.0)))
+            }
+            Err(_) => Ok(Self::default()),
+        }
+    }
+}
+
+/// Rule and `Finding::kind` of a program outside
+/// `capabilities.exec.digests`.
+pub const UNAPPROVED_BINARY: &str = "unapproved_binary";
+
+/// What a program outside `capabilities.exec.digests` does to a run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Unapproved {
+    /// Run it, and grade the run red.
+    #[default]
+    Red,
+    /// Refuse the request.
+    Block,
+}
+
+impl FromStr for Unapproved {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "red" => Ok(Self::Red),
+            "block" => Ok(Self::Block),
+            other => Err(format!("unknown unapproved {:?} (red or block)", other)),
+        }
+    }
+}
+
+/// Process-wide cache of parsed policy files, for consumers that look the
+/// policy up on every message.
+///
+/// A cached policy is served as long as the file's mtime and size are
+/// unchanged. Otherwise the file is read again, and it is only re-parsed
+/// when its sha256 differs from the cached copy.
+#[derive(Default)]
+pub struct PolicyStore {
+    entries: std::sync::Mutex<std::collections::HashMap<String, CachedPolicy>>,
+    hits: std::sync::atomic::AtomicU64,
+    reloads: std::sync::atomic::AtomicU64,
+}
+
+struct CachedPolicy {
+    mtime: Option<std::time::SystemTime>,
+    len: u64,
+    digest: [u8; 32],
+    policy: std::sync::Arc<Policy>,
+}
+
+/// Lookups served by a [`PolicyStore`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PolicyCacheStats {
+    /// Served from the cache, without parsing.
+    pub hits: u64,
+    /// Parsed from the file (first use, or its contents changed).
+    pub reloads: u64,
+}
+
+impl PolicyStore {
+    /// The store shared by the whole process.
+    pub fn global() -> &'static PolicyStore {
+        static STORE: std::sync::OnceLock<PolicyStore> = std::sync::OnceLock::new();
+        STORE.get_or_init(PolicyStore::default)
+    }
+
+    /// The policy at `path` from the process-wide store (see
+    /// [`PolicyStore::load`]).
+    pub fn get(path: &str) -> std::sync::Arc<Policy> {
+        Self::global().load(path)
+    }
+
+    /// The policy at `path`, parsed again only when the file changed. Like
+    /// [`Policy::load`], an unreadable file yields the default policy; an
+    /// invalid one is logged and the previous version (or the default, if
+    /// there is none) stays in use until the file changes again.
+    pub fn load(&self, path: &str) -> std::sync::Arc<Policy> {
+        use sha2::{Digest, Sha256};
+        use std::sync::atomic::Ordering;
+        let Ok(meta) = std::fs::metadata(path) else {
+            self.entries.lock().unwrap().remove(path);
+            self.reloads.fetch_add(1, Ordering::Relaxed);
+            return std::sync::Arc::new(Policy::default());
+        };
+        let mtime = meta.modified().ok();
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(c) = entries.get(path) {
+            if c.mtime.is_some() && c.mtime == mtime && c.len == meta.len() {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return c.policy.clone();
+            }
+        }
+        let text = std::fs::read_to_string(path).unwrap_or_default();
+        let digest: [u8; 32] = Sha256::digest(text.as_bytes()).into();
+        if let Some(c) = entries.get_mut(path) {
+            if c.digest == digest {
+                // Touched but not changed.
+                c.mtime = mtime;
+                c.len = meta.len();
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return c.policy.clone();
+            }
+        }
+        let policy = match Policy::from_yaml(&text) {
+            Ok(p) => std::sync::Arc::new(p),
+            Err(e) => {
+                // Keep serving the last good version rather than whatever a
+                // half-finished edit would grade like.
+                tracing::error!(policy = %path, error = %e, "policy: not reloaded");
+                let last = entries.get(path).map(|c| c.policy.clone());
+                last.unwrap_or_default()
+            }
+        };
+        entries.insert(
+            path.to_string(),
+            CachedPolicy {
+                mtime,
+                len: meta.len(),
+                digest,
+                policy: policy.clone(),
+            },
+        );
+        self.reloads.fetch_add(1, Ordering::Relaxed);
+        policy
+    }
+
+    pub fn stats(&self) -> PolicyCacheStats {
+        use std::sync::atomic::Ordering;
+        PolicyCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            reloads: self.reloads.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A `grading.<key>` list of rule names (`[ssh, device]`).
+fn parse_grading_list(text: &str, key: &str) -> Vec<String> {
+    extract_yaml_scalar_under(text, "grading", key)
+        .map(|v| {
+            crate::schedule::list(v.split('#').next().unwrap_or_default())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// A broken policy is a configuration problem: exit code 4.
+impl From<PolicyError> for crate::error::MagicruneError {
+    fn from(e: PolicyError) -> Self {
+        Self::new(crate::messages::Code::PolicyLoad, &[&e])
+    }
+}
+
+/// `grading.exfiltration`: large, high-entropy stdout from a run that was
+/// allowed to reach the network is scored as possible exfiltration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Exfiltration {
+    /// Output shorter than this is never flagged.
+    pub min_bytes: u64,
+    /// Shannon entropy, in bits per byte (0–8), at which output is flagged.
+    pub min_entropy: f64,
+    /// Risk added when the rule fires; 0 turns it off.
+    pub score: u32,
+}
+
+impl Default for Exfiltration {
+    fn default() -> Self {
+        // Base64 of random bytes sits near 6 bits/byte, prose and logs
+        // below 5.
+        Self {
+            min_bytes: 1 << 20,
+            min_entropy: 5.5,
+            score: 30,
+        }
+    }
+}
+
+/// `Finding::kind` recorded when the exfiltration rule fires.
+pub const EXFILTRATION: &str = "exfiltration";
+
+impl Exfiltration {
+    /// The entropy of `stdout` when it trips the rule.
+    pub fn check(&self, stdout: &[u8]) -> Option<f64> {
+        if self.score == 0 || (stdout.len() as u64) < self.min_bytes {
+            return None;
+        }
+        let h = shannon_entropy(stdout);
+        (h >= self.min_entropy).then_some(h)
+    }
+}
+
+/// Shannon entropy of `bytes` in bits per byte.
+pub fn shannon_entropy(bytes: &[u8]) -> f64 {
+    if bytes.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0u64; 256];
+    for &b in bytes {
+        counts[b as usize] += 1;
+    }
+    let n = bytes.len() as f64;
+    counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / n;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+fn parse_exfiltration(text: &str) -> Exfiltration {
+    let d = Exfiltration::default();
+    Exfiltration {
+        min_bytes: extract_yaml_u64_under(text, "exfiltration", "min_bytes").unwrap_or(d.min_bytes),
+        min_entropy: extract_yaml_scalar_under(text, "exfiltration", "min_entropy")
+            .and_then(|v| v.parse::<f64>().ok())
+            .map(|v| v.clamp(0.0, 8.0))
+            .unwrap_or(d.min_entropy),
+        score: extract_yaml_u64_under(text, "exfiltration", "score")
+            .map(|v| v.min(u32::MAX as u64) as u32)
+            .unwrap_or(d.score),
+    }
+}
+
+/// Resource limits from the `limits` section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PolicyLimits {
+    pub wall_sec: u64,
+    pub cpu_ms: u64,
+    pub memory_mb: u64,
+    pub pids: u64,
+    /// Size of the sandbox's `/tmp` tmpfs, in MiB.
+    pub tmp_mb: u64,
+    /// Inode cap of the sandbox's `/tmp` tmpfs.
+    pub tmp_inodes: u64,
+    /// Time between SIGTERM and SIGKILL when a command runs out of time;
+    /// 0 kills it at once.
+    pub kill_grace_ms: u64,
+    /// Bandwidth of the command's traffic through the egress proxy, both
+    /// directions together, in kbit/s; 0 is no limit.
+    pub net_kbps: u64,
+    /// Connections the egress proxy lets through in a run; 0 is no limit.
+    pub net_connections: u64,
+}
+
+impl PolicyLimits {
+    /// The wall-clock limit for a request asking for `timeout_sec`: the
+    /// request's value when it is set and not above the policy's.
+    pub fn wall_for(&self, timeout_sec: u64) -> u64 {
+        if timeout_sec > 0 {
+            timeout_sec.min(self.wall_sec)
+        } else {
+            self.wall_sec
+        }
+    }
+
+    /// The limits a request runs under: a non-zero `timeout_sec` and the
+    /// request's `limits` replace the policy values they are given for, and
+    /// may not exceed them.
+    pub fn narrowed(&self, timeout_sec: u64, req: &RequestLimits) -> Result<Self, Message> {
+        if timeout_sec > self.wall_sec {
+            return Err(
+                Message::new(Code::TimeoutAboveWall, &[&timeout_sec, &self.wall_sec])
+                    .at("/timeout_sec")
+                    .rule(format!("limits.wall_sec: {}", self.wall_sec)),
+            );
+        }
+        let pick = |name: &str, asked: Option<u64>, ceiling: u64| match asked {
+            Some(v) if v > ceiling => Err(Message::new(
+                Code::LimitAboveCeiling,
+                &[&name, &v, &ceiling],
+            )
+            .at(format!("/limits/{}", name))
+            .rule(format!("limits.{}: {}", name, ceiling))),
+            Some(v) => Ok(v),
+            None => Ok(ceiling),
+        };
+        Ok(Self {
+            wall_sec: self.wall_for(timeout_sec),
+            cpu_ms: pick("cpu_ms", req.cpu_ms, self.cpu_ms)?,
+            memory_mb: pick("memory_mb", req.memory_mb, self.memory_mb)?,
+            pids: pick("pids", req.pids, self.pids)?,
+            ..*self
+        })
+    }
+}
+
+impl Default for PolicyLimits {
+    fn default() -> Self {
+        Self {
+            wall_sec: 60,
+            cpu_ms: 5000,
+            memory_mb: 512,
+            pids: 256,
+            tmp_mb: 64,
+            tmp_inodes: 16384,
+            kill_grace_ms: 0,
+            net_kbps: 0,
+            net_connections: 0,
+        }
+    }
+}
+
+fn extract_yaml_u64_under(content: &str, section: &str, key: &str) -> Option<u64> {
+    let mut in_section = false;
+    let mut section_indent: Option<usize> = None;
+    for line in content.lines() {
+        let raw = line;
+        let trimmed = raw.trim_end();
+        let indent = raw.chars().take_while(|c| c.is_whitespace()).count();
+        if trimmed.trim_start().starts_with('#') {
+            continue;
+        }
+        if trimmed.trim() == format!("{}:", section) {
+            in_section = true;
+            section_indent = Some(indent);
+            continue;
+        }
+        if in_section {
+            if let Some(si) = section_indent {
+                if indent <= si && !trimmed.trim().is_empty() {
+                    in_section = false;
+                }
+            }
+            if in_section {
+                let t = trimmed.trim();
+                // `green` must not match `green_max:`.
+                if let Some(rest0) = t
+                    .strip_prefix(key)
+                    .filter(|r| r.trim_start().starts_with(':'))
+                {
+                    let rest = rest0.trim();
+                    let val = rest.trim_start_matches(':').trim();
+                    if let Ok(v) = u64::from_str(val.trim_matches('"')) {
+                        return Some(v);
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+fn parse_limits(text: &str) -> PolicyLimits {
+    let wall_sec = extract_yaml_u64_under(text, "limits", "wall_sec").unwrap_or(60);
+    let cpu_ms = extract_yaml_u64_under(text, "limits", "cpu_ms").unwrap_or(5000);
+    let memory_mb = extract_yaml_u64_under(text, "limits", "memory_mb").unwrap_or(512);
+    let pids = extract_yaml_u64_under(text, "limits", "pids").unwrap_or(256);
+    let tmp_mb = extract_yaml_u64_under(text, "limits", "tmp_mb").unwrap_or(64);
+    let tmp_inodes = extract_yaml_u64_under(text, "limits", "tmp_inodes").unwrap_or(16384);
+    let kill_grace_ms = extract_yaml_u64_under(text, "limits", "kill_grace_ms").unwrap_or(0);
+    let net_kbps = extract_yaml_u64_under(text, "limits", "net_kbps").unwrap_or(0);
+    let net_connections = extract_yaml_u64_under(text, "limits", "net_connections").unwrap_or(0);
+    PolicyLimits {
+        wall_sec,
+        cpu_ms,
+        memory_mb,
+        pids,
+        tmp_mb,
+        tmp_inodes,
+        kill_grace_ms,
+        net_kbps,
+        net_connections,
+    }
+}
+
+// Minimal YAML walker to extract capabilities.net.allow host[:port] entries,
+// and those of capabilities.net.connect.allow; net.dns is skipped.
+fn parse_net_allow(text: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut in_caps = false;
+    let mut in_net = false;
+    let mut in_allow = false;
+    let mut dns_indent: Option<usize> = None;
+    let mut caps_indent = 0usize;
+    let mut net_indent = 0usize;
+    let mut allow_indent = 0usize;
+    for raw in text.lines() {
+        let indent = raw.chars().take_while(|c| c.is_whitespace()).count();
+        let line = raw.trim();
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        if !in_caps && line == "capabilities:" {
+            in_caps = true;
+            caps_indent = indent;
+            continue;
+        }
+        if in_caps {
+            if indent <= caps_indent {
+                in_caps = false;
+                in_net = false;
+                in_allow = false;
+            }
+            if !in_net && line == "net:" {
+                in_net = true;
+                net_indent = indent;
+                continue;
+            }
+            if in_net {
+                if indent <= net_indent {
+                    in_net = false;
+                    in_allow = false;
+                }
+                if let Some(di) = dns_indent {
+                    if indent > di {
+                        continue;
+                    }
+                    dns_indent = None;
+                }
+                if in_net && line == "dns:" {
+                    dns_indent = Some(indent);
+                    in_allow = false;
+                    continue;
+                }
+                if !in_allow && line == "allow:" {
+                    in_allow = true;
+                    allow_indent = indent;
+                    continue;
+                }
+                if in_allow {
+                    if indent <= allow_indent {
+                        in_allow = false;
+                    }
+                    if line.starts_with("- ") {
+                        let item = line.trim_start_matches("- ").trim();
+                        // Support multiple forms:
+                        // - host: "example.com:443" (keyed form)
+                        // - addr: "example.com:443" (keyed form)
+                        // - "example.com:443" (simple string form)
+                        if let Some((key, val)) = item.split_once(": ") {
+                            if key == "host" || key == "addr" {
+                                let v = val.trim().trim_matches('"');
+                                if !v.is_empty() {
+                                    out.push(v.to_string());
+                                }
+                            }
+                        } else {
+                            let v = item.trim().trim_matches('"');
+                            if !v.is_empty() {
+                                out.push(v.to_string());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+// Very small YAML walker to extract capabilities.fs.allow path entries
+fn parse_fs_allow(text: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut in_caps = false;
+    let mut in_fs = false;
+    let mut in_allow = false;
+    let mut caps_indent = 0usize;
+    let mut fs_indent = 0usize;
+    let mut allow_indent = 0usize;
+    for raw in text.lines() {
+        let indent = raw.chars().take_while(|c| c.is_whitespace()).count();
+        let line = raw.trim();
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        if !in_caps && line == "capabilities:" {
+            in_caps = true;
+            caps_indent = indent;
+            continue;
+        }
+        if in_caps {
+            if indent <= caps_indent {
+                in_caps = false;
+                in_fs = false;
+                in_allow = false;
+            }
+            if !in_fs && line == "fs:" {
+                in_fs = true;
+                fs_indent = indent;
+                continue;
+            }
+            if in_fs {
+                if indent <= fs_indent {
+                    in_fs = false;
+                    in_allow = false;
+                }
+                if !in_allow && line == "allow:" {
+                    in_allow = true;
+                    allow_indent = indent;
+                    continue;
+                }
+                if in_allow {
+                    if indent <= allow_indent {
+                        in_allow = false;
+                    }
+                    if line.starts_with("- ") {
+                        // expect '- path: "..."'
+                        if let Some(rest) = line.trim_start_matches("- ").strip_prefix("path:") {
+                            let v = rest.trim().trim_start_matches(':').trim().trim_matches('"');
+                            if !v.is_empty() {
+                                out.push(v.to_string());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+fn parse_fs_readonly(text: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut in_caps = false;
+    let mut in_fs = false;
+    let mut in_ro = false;
+    let (mut ci, mut fi, mut ri) = (0usize, 0usize, 0usize);
+    for raw in text.lines() {
+        let indent = raw.chars().take_while(|c| c.is_whitespace()).count();
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if !in_caps && line == "capabilities:" {
+            in_caps = true;
+            ci = indent;
+            continue;
+        }
+        if in_caps {
+            if indent <= ci {
+                in_caps = false;
+                in_fs = false;
+                in_ro = false;
+            }
+            if !in_fs && line == "fs:" {
+                in_fs = true;
+                fi = indent;
+                continue;
+            }
+            if in_fs {
+                if indent <= fi {
+                    in_fs = false;
+                    in_ro = false;
+                }
+                if !in_ro && line == "readonly:" {
+                    in_ro = true;
+                    ri = indent;
+                    continue;
+                }
+                if in_ro {
+                    if indent <= ri {
+                        in_ro = false;
+                    }
+                    if line.starts_with("- ") {
+                        let v = line.trim_start_matches("- ").trim().trim_matches('"');
+                        if !v.is_empty() {
+                            out.push(v.to_string());
+                        }
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Policy snippet allowing `existing` plus the syscalls that seccomp learn
+/// mode logged (findings of kind `seccomp_logged`).
+pub fn suggest_syscalls_yaml(existing: &[String], findings: &[crate::schema::Finding]) -> String {
+    let mut names: Vec<&str> = existing.iter().map(String::as_str).collect();
+    names.extend(
+        findings
+            .iter()
+            .filter(|f| f.kind == crate::sandbox::audit::SECCOMP_LOGGED)
+            .map(|f| f.detail.as_str()),
+    );
+    names.sort_unstable();
+    names.dedup();
+    let mut out = String::from("capabilities:\n  syscalls:\n    allow:");
+    if names.is_empty() {
+        out.push_str(" []");
+    }
+    for n in names {
+        out.push_str(&format!("\n      - \"{}\"", n));
+    }
+    out.push('\n');
+    out
+}
+
+/// Items of the list `capabilities.<group>.<key>`, written either as plain
+/// strings or as `- path: "..."`.
+fn parse_caps_list(text: &str, group: &str, key: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let (group_line, key_line) = (format!("{}:", group), format!("{}:", key));
+    let mut in_caps = false;
+    let mut in_group = false;
+    let mut in_key = false;
+    let (mut ci, mut gi, mut ki) = (0usize, 0usize, 0usize);
+    for raw in text.lines() {
+        let indent = raw.chars().take_while(|c| c.is_whitespace()).count();
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if !in_caps && line == "capabilities:" {
+            in_caps = true;
+            ci = indent;
+            continue;
+        }
+        if in_caps {
+            if indent <= ci {
+                in_caps = false;
+                in_group = false;
+                in_key = false;
+            }
+            if !in_group && line == group_line {
+                in_group = true;
+                gi = indent;
+                continue;
+            }
+            if in_group {
+                if indent <= gi {
+                    in_group = false;
+                    in_key = false;
+                }
+                if !in_key && line == key_line {
+                    in_key = true;
+                    ki = indent;
+                    continue;
+                }
+                if in_key {
+                    if indent <= ki {
+                        in_key = false;
+                    }
+                    if let Some(item) = line.strip_prefix("- ") {
+                        let item = item.trim();
+                        let v = item.strip_prefix("path:").unwrap_or(item);
+                        let v = v.trim().trim_matches('"');
+                        if !v.is_empty() {
+                            out.push(v.to_string());
+                        }
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+fn parse_env_policy(text: &str) -> (Vec<String>, Vec<String>) {
+    let mut allow = Vec::new();
+    let mut deny = Vec::new();
+    let mut in_caps = false;
+    let mut in_env = false;
+    let mut in_allow = false;
+    let mut in_deny = false;
+    let (mut ci, mut ei, mut ai, mut di) = (0usize, 0usize, 0usize, 0usize);
+    for raw in text.lines() {
+        let indent = raw.chars().take_while(|c| c.is_whitespace()).count();
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if !in_caps && line == "capabilities:" {
+            in_caps = true;
+            ci = indent;
+            continue;
+        }
+        if in_caps {
+            if indent <= ci {
+                in_caps = false;
+                in_env = false;
+                in_allow = false;
+                in_deny = false;
+            }
+            if !in_env && line == "env:" {
+                in_env = true;
+                ei = indent;
+                continue;
+            }
+            if in_env {
+                if indent <= ei {
+                    in_env = false;
+                    in_allow = false;
+                    in_deny = false;
+                }
+                if !in_allow && line == "allow:" {
+                    in_allow = true;
+                    ai = indent;
+                    continue;
+                }
+                if !in_deny && line == "deny:" {
+                    in_deny = true;
+                    di = indent;
+                    continue;
+                }
+                if in_allow {
+                    if indent <= ai {
+                        in_allow = false;
+                    }
+                    if line.starts_with("- ") {
+                        let v = line.trim_start_matches("- ").trim().trim_matches('"');
+                        if !v.is_empty() {
+                            allow.push(v.to_string());
+                        }
+                    }
+                }
+                if in_deny {
+                    if indent <= di {
+                        in_deny = false;
+                    }
+                    if line.starts_with("- ") {
+                        let v = line.trim_start_matches("- ").trim().trim_matches('"');
+                        if !v.is_empty() {
+                            deny.push(v.to_string());
+                        }
+                    }
+                }
+            }
+        }
+    }
+    (allow, deny)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Verdict;
+
+    const SAMPLE: &str = r#"version: 1
+capabilities:
+  fs:
+    allow:
+      - path: "/tmp/**"
+    readonly:
+      - "/etc/**"
+  net:
+    allow:
+      - host: "example.com:443"
+      - "10.0.0.0/8"
+    proxy: on
+    dns:
+      allow:
+        - "*.health.internal"
+    connect:
+      allow:
+        - "api.internal:8443"
+  env:
+    deny:
+      - "AWS_*"
+  devices:
+    allow:
+      - "/dev/kvm"
+      - path: "/dev/nvidia*"
+  syscalls:
+    allow:
+      - "getdents64"
+  exec:
+    digests: /etc/magicrune/approved.sha256
+    unapproved: block
+limits:
+  wall_sec: 15
+  pids: 64
+  tmp_mb: 16
+  net_kbps: 512
+  net_connections: 20
+observe:
+  syscalls: summary
+  net: log
+  provenance: record
+  output: timeline
+  env: names
+  env_values: [PATH, "LC_*"]
+exec:
+  shell: sh
+  pty: true
+  strip_ansi: true
+  on_redelivery: return_partial
+requests:
+  signature: required
+debug:
+  core_mb: 32
+cache:
+  ttl_sec: 600
+constraints:
+  timezone: "+09:00"
+  allowed_days: [mon, tue, wed, thu, fri]
+  allowed_hours: "09-18" # change window
+  applies_to: [net]
+  runner_labels: [gpu, eu-west]
+grading:
+  waivable: [ssh, device]
+  mandatory: [net_intent]
+  thresholds:
+    green: "<=10"
+    yellow: "11..=50"
+    red: ">=51"
+  exfiltration:
+    min_bytes: 4096
+    min_entropy: 7.5
+"#;
+
+    #[test]
+    fn test_from_yaml_sections() {
+        let p = Policy::from_yaml(SAMPLE).unwrap();
+        assert_eq!(p.limits.wall_sec, 15);
+        assert_eq!(p.limits.pids, 64);
+        assert_eq!(p.limits.cpu_ms, 5000);
+        assert_eq!(p.limits.tmp_mb, 16);
+        assert_eq!(p.limits.tmp_inodes, 16384);
+        assert_eq!((p.limits.net_kbps, p.limits.net_connections), (512, 20));
+        assert_eq!(p.fs_allow, vec!["/tmp/**"]);
+        assert_eq!(p.fs_readonly, vec!["/etc/**"]);
+        assert_eq!(
+            p.net_allow,
+            vec!["example.com:443", "10.0.0.0/8", "api.internal:8443"]
+        );
+        assert_eq!(p.dns_allow, vec!["*.health.internal"]);
+        assert!(p.net_proxy);
+        assert!(!p.net_disabled);
+        assert!(
+            Policy::from_yaml("capabilities:\n  net:\n    default: disabled_hard\n")
+                .unwrap()
+                .net_disabled
+        );
+        assert!(!Policy::default().net_proxy);
+        assert_eq!(p.env_deny, vec!["AWS_*"]);
+        assert_eq!(p.devices_allow, vec!["/dev/kvm", "/dev/nvidia*"]);
+        assert_eq!(p.syscalls_allow, vec!["getdents64"]);
+        assert!(p.observe_syscalls);
+        assert!(p.observe_net);
+        assert!(p.observe_provenance);
+        assert!(p.observe_output);
+        assert!(p.observe_env);
+        assert_eq!(p.env_values, vec!["PATH", "LC_*"]);
+        assert!(!Policy::default().observe_output);
+        assert!(p.pty && p.strip_ansi);
+        assert!(!Policy::default().pty && !Policy::default().strip_ansi);
+        assert_eq!(
+            p.exec_digests.as_deref(),
+            Some("/etc/magicrune/approved.sha256")
+        );
+        assert_eq!(p.exec_unapproved, Unapproved::Block);
+        assert_eq!(Policy::default().exec_unapproved, Unapproved::Red);
+        assert_eq!(p.shell, Some(Shell::Sh));
+        assert_eq!(p.on_redelivery, OnRedelivery::ReturnPartial);
+        assert_eq!(Policy::default().on_redelivery, OnRedelivery::Rerun);
+        assert!(p.require_signed_requests);
+        assert!(!Policy::default().require_signed_requests);
+        assert_eq!((p.core_mb, Policy::default().core_mb), (32, 0));
+        assert_eq!((p.cache_ttl_sec, Policy::default().cache_ttl_sec), (600, 0));
+        let s = p.schedule.expect("constraints");
+        assert_eq!(s.describe(), "mon,tue,wed,thu,fri 09-18 +09:00");
+        assert!(s.applies_to.net && !s.applies_to.fs);
+        assert_eq!(Policy::default().schedule, None);
+        assert_eq!(p.runner_labels, ["gpu", "eu-west"]);
+        assert!(p.env_allow.is_empty());
+        assert_eq!(p.thresholds, Thresholds::new(10, 50).unwrap());
+        assert_eq!(p.exfiltration.min_bytes, 4096);
+        assert_eq!(p.exfiltration.min_entropy, 7.5);
+        assert_eq!(p.exfiltration.score, 30);
+        assert_eq!(p.waivable, ["ssh", "device"]);
+        assert_eq!(p.mandatory, ["net_intent"]);
+    }
+
+    #[test]
+    fn test_limits_narrowed_by_request() {
+        let ceiling = PolicyLimits::default();
+        let asked = RequestLimits {
+            memory_mb: Some(128),
+            pids: Some(16),
+            ..Default::default()
+        };
+        let l = ceiling.narrowed(5, &asked).unwrap();
+        assert_eq!(
+            (l.wall_sec, l.cpu_ms, l.memory_mb, l.pids),
+            (5, ceiling.cpu_ms, 128, 16)
+        );
+        assert_eq!(l.tmp_mb, ceiling.tmp_mb);
+        assert_eq!(
+            ceiling.narrowed(0, &RequestLimits::default()).unwrap(),
+            ceiling
+        );
+        // Equal to the policy's wall time is allowed, and changes nothing.
+        assert_eq!(ceiling.narrowed(60, &RequestLimits::default()), Ok(ceiling));
+        assert_eq!(ceiling.wall_for(0), 60);
+        assert_eq!(ceiling.wall_for(90), 60);
+        assert_eq!(
+            parse_limits("limits:\n  kill_grace_ms: 1500\n").kill_grace_ms,
+            1500
+        );
+        assert_eq!(ceiling.kill_grace_ms, 0);
+        let too_much = RequestLimits {
+            cpu_ms: Some(ceiling.cpu_ms + 1),
+            ..Default::default()
+        };
+        assert_eq!(
+            ceiling.narrowed(0, &too_much).unwrap_err().text,
+            "policy: limits.cpu_ms 5001 exceeds policy ceiling 5000"
+        );
+    }
+
+    #[test]
+    fn test_exfiltration_check() {
+        assert_eq!(shannon_entropy(b"aaaa"), 0.0);
+        let all: Vec<u8> = (0..=255u8).cycle().take(8192).collect();
+        assert!((shannon_entropy(&all) - 8.0).abs() < 1e-9);
+
+        let rule = Exfiltration {
+            min_bytes: 4096,
+            ..Default::default()
+        };
+        assert!(rule.check(&all).is_some());
+        assert!(rule.check(&all[..1024]).is_none());
+        assert!(rule.check(&b"log line\n".repeat(1000)).is_none());
+        let off = Exfiltration { score: 0, ..rule };
+        assert!(off.check(&all).is_none());
+    }
+
+    #[test]
+    fn test_suggest_syscalls_round_trips() {
+        use crate::schema::Finding;
+        let logged = |name: &str| Finding {
+            kind: "seccomp_logged".into(),
+            detail: name.into(),
+            count: 1,
+        };
+        let findings = vec![
+            logged("socket"),
+            logged("getdents64"),
+            Finding {
+                kind: "seccomp_denied".into(),
+                detail: "ptrace".into(),
+                count: 1,
+            },
+        ];
+        let yaml = suggest_syscalls_yaml(&["getdents64".to_string()], &findings);
+        assert_eq!(
+            Policy::from_yaml(&yaml).unwrap().syscalls_allow,
+            vec!["getdents64", "socket"]
+        );
+        assert!(suggest_syscalls_yaml(&[], &[]).contains("allow: []"));
+    }
+
+    #[test]
+    fn test_missing_file_is_default() {
+        assert_eq!(
+            Policy::load("/nonexistent/policy.yml"),
+            Ok(Policy::default())
+        );
+    }
+
+    #[test]
+    fn test_policy_digest() {
+        let p = Policy::from_yaml("limits:\n  wall_sec: 5\n").unwrap();
+        assert_eq!(p.digest.len(), 64);
+        assert_eq!(
+            p.digest,
+            Policy::from_yaml("limits:\n  wall_sec: 5\n")
+                .unwrap()
+                .digest
+        );
+        assert_ne!(
+            p.digest,
+            Policy::from_yaml("limits:\n  wall_sec: 6\n")
+                .unwrap()
+                .digest
+        );
+        assert!(Policy::default().digest.is_empty());
+    }
+
+    #[test]
+    fn test_policy_store_reloads_on_change() {
+        let path = std::env::temp_dir().join(format!("mr_policy_store_{}.yml", std::process::id()));
+        let path_s = path.to_str().unwrap();
+        std::fs::write(&path, "limits:\n  wall_sec: 5\n").unwrap();
+        let store = PolicyStore::default();
+        assert_eq!(store.load(path_s).limits.wall_sec, 5);
+        assert_eq!(store.load(path_s).limits.wall_sec, 5);
+        assert_eq!(
+            store.stats(),
+            PolicyCacheStats {
+                hits: 1,
+                reloads: 1
+            }
+        );
+
+        // Same contents, new mtime: no re-parse.
+        let f = std::fs::File::options().write(true).open(&path).unwrap();
+        f.set_modified(std::time::SystemTime::UNIX_EPOCH).unwrap();
+        store.load(path_s);
+        assert_eq!(
+            store.stats(),
+            PolicyCacheStats {
+                hits: 2,
+                reloads: 1
+            }
+        );
+
+        std::fs::write(&path, "limits:\n  wall_sec: 9\n").unwrap();
+        assert_eq!(store.load(path_s).limits.wall_sec, 9);
+        assert_eq!(store.stats().reloads, 2);
+        // A broken edit keeps the last good version.
+        std::fs::write(&path, "grading:\n  thresholds:\n    green_max: x\n").unwrap();
+        assert_eq!(store.load(path_s).limits.wall_sec, 9);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(*store.load(path_s), Policy::default());
+    }
+
+    #[test]
+    fn test_thresholds() {
+        let th = Thresholds::default();
+        assert_eq!(th.verdict_for(0), Verdict::Green);
+        assert_eq!(th.verdict_for(20), Verdict::Green);
+        assert_eq!(th.verdict_for(40), Verdict::Yellow);
+        assert_eq!(th.verdict_for(61), Verdict::Red);
+
+        let parse = |body: &str| {
+            Policy::from_yaml(&format!("grading:\n  thresholds:\n{}", body)).map(|p| p.thresholds)
+        };
+        assert_eq!(
+            parse("    green_max: 10 # comment\n    yellow_max: 30\n"),
+            Ok(Thresholds::new(10, 30).unwrap())
+        );
+        // Legacy strings, alone or next to the integers, as long as they agree.
+        assert_eq!(
+            parse("    yellow: \"21..=29\"\n    red: \">=30\"\n"),
+            Ok(Thresholds::new(20, 29).unwrap())
+        );
+        assert_eq!(
+            parse("    green_max: 5\n    green: \"<=5\"\n"),
+            Ok(Thresholds::new(5, 60).unwrap())
+        );
+        assert_eq!(
+            parse("    red: \">=101\"\n"),
+            Ok(Thresholds::new(20, 100).unwrap())
+        );
+
+        let err = |body: &str| parse(body).unwrap_err().0;
+        assert_eq!(
+            err("    yellow: \"21-60\"\n"),
+            "grading.thresholds.yellow: \"21-60\" is not one of <=N, A..=B, >=N"
+        );
+        assert_eq!(
+            err("    green_max: twenty\n"),
+            "grading.thresholds.green_max: \"twenty\" is not a whole number"
+        );
+        assert!(err("    green: \"<=20\"\n    yellow: \"25..=60\"\n").contains("green_max"));
+        assert!(err("    yellow: \"21..=60\"\n    red: \">=50\"\n").contains("yellow_max"));
+        assert!(err("    green_max: 70\n    yellow_max: 60\n").contains("above yellow_max"));
+        assert!(err("    yellow: \"0..=60\"\n").contains("above green"));
+        assert!(Thresholds::new(70, 60).is_err());
+    }
+
+    #[test]
+    fn test_extract_device_paths() {
+        assert_eq!(
+            extract_device_paths("nvidia-smi >/dev/null; cat /dev/nvidia0 '/dev/kvm'"),
+            vec!["/dev/null", "/dev/nvidia0", "/dev/kvm"]
+        );
+        assert!(extract_device_paths("ls /srv/dev/x ./dev/y").is_empty());
+    }
+
+    #[test]
+    fn test_allowed_match() {
+        assert!(allowed_match(
+            "api.example.com",
+            Some("443"),
+            "*.example.com:443"
+        ));
+        assert!(!allowed_match(
+            "api.example.com",
+            Some("80"),
+            "*.example.com:443"
+        ));
+        assert!(allowed_match("10.1.2.3", Some("80"), "10.0.0.0/8"));
+        assert!(allowed_match("localhost", Some("8080"), "localhost:8080"));
+    }
+}
@@ -0,0 +1,436 @@
+//! Policy evaluation that needs neither std nor the host: verdict
+//! thresholds, network allowlist matching and path patterns. This is the
+//! part of `crate::policy` that builds without the `std` feature, for
+//! pre-grading requests where they are written (e.g. in a browser, on
+//! `wasm32-unknown-unknown`); see `crate::grader` and `crate::schema`.
+
+use crate::schema::Verdict;
+use core::net::IpAddr;
+use core::str::FromStr;
+
+#[cfg(not(feature = "std"))]
+use alloc::{
+    borrow::Cow,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+#[cfg(feature = "std")]
+use std::borrow::Cow;
+
+/// Where each verdict starts, from `grading.thresholds`: scores up to
+/// `green_max` are green, up to `yellow_max` yellow, anything above red.
+///
+/// The legacy range strings (`green: "<=20"`, `yellow: "21..=60"`,
+/// `red: ">=61"`) are still accepted, but must parse and leave neither a
+/// gap nor an overlap between verdicts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "RawThresholds")]
+pub struct Thresholds {
+    pub green_max: u32,
+    pub yellow_max: u32,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            green_max: 20,
+            yellow_max: 60,
+        }
+    }
+}
+
+/// `grading.thresholds` as written, both forms.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawThresholds {
+    pub green_max: Option<u32>,
+    pub yellow_max: Option<u32>,
+    pub green: Option<String>,
+    pub yellow: Option<String>,
+    pub red: Option<String>,
+}
+
+impl TryFrom<RawThresholds> for Thresholds {
+    type Error = PolicyError;
+
+    fn try_from(raw: RawThresholds) -> Result<Self, PolicyError> {
+        let fail = |field: &str, msg: String| {
+            PolicyError(format!("grading.thresholds.{}: {}", field, msg))
+        };
+        let legacy = |field: &str, expr: &Option<String>| -> Result<_, PolicyError> {
+            expr.as_deref()
+                .map(|e| {
+                    parse_score_range(e).ok_or_else(|| {
+                        fail(field, format!("{:?} is not one of <=N, A..=B, >=N", e))
+                    })
+                })
+                .transpose()
+        };
+        let green = legacy("green", &raw.green)?;
+        let yellow = legacy("yellow", &raw.yellow)?;
+        let red = legacy("red", &raw.red)?;
+        if green.is_some_and(|(lo, _)| lo != 0) {
+            return Err(fail("green", "must start at 0".into()));
+        }
+        if yellow.is_some_and(|(lo, hi)| lo == 0 || lo > hi) {
+            return Err(fail("yellow", "must be a range above green".into()));
+        }
+        if red.is_some_and(|(lo, hi)| lo == 0 || hi != u32::MAX) {
+            return Err(fail("red", "must be >=N with N above yellow".into()));
+        }
+        // Each bound is read from whichever entry states it; entries stating
+        // the same bound must agree.
+        let pick = |field: &str, candidates: [Option<u32>; 3], default: u32| {
+            let mut found = candidates.into_iter().flatten();
+            let first = found.next();
+            match first {
+                Some(v) if found.all(|w| w == v) => Ok(v),
+                Some(_) => Err(fail(
+                    field,
+                    "disagrees with the neighbouring verdict".into(),
+                )),
+                None => Ok(default),
+            }
+        };
+        let green_max = pick(
+            "green_max",
+            [
+                raw.green_max,
+                green.map(|(_, hi)| hi),
+                yellow.and_then(|(lo, _)| lo.checked_sub(1)),
+            ],
+            Self::default().green_max,
+        )?;
+        let yellow_max = pick(
+            "yellow_max",
+            [
+                raw.yellow_max,
+                yellow.map(|(_, hi)| hi),
+                red.and_then(|(lo, _)| lo.checked_sub(1)),
+            ],
+            Self::default().yellow_max.max(green_max),
+        )?;
+        Self::new(green_max, yellow_max)
+    }
+}
+
+impl Thresholds {
+    pub fn new(green_max: u32, yellow_max: u32) -> Result<Self, PolicyError> {
+        if green_max > yellow_max {
+            return Err(PolicyError(format!(
+                "grading.thresholds: green_max {} is above yellow_max {}",
+                green_max, yellow_max
+            )));
+        }
+        Ok(Self {
+            green_max,
+            yellow_max,
+        })
+    }
+
+    /// `grading.thresholds` from a policy file's YAML, the defaults for
+    /// any bound it leaves out.
+    pub fn from_yaml(text: &str) -> Result<Self, PolicyError> {
+        // Look specifically under grading -> thresholds
+        let get = |key: &str| {
+            extract_yaml_scalar_under(text, "thresholds", key)
+                .or_else(|| extract_yaml_scalar_under(text, "grading", key))
+        };
+        let int = |key: &str| {
+            get(key)
+                .map(|v| {
+                    let n = v.split('#').next().unwrap_or_default().trim();
+                    u32::from_str(n).map_err(|_| {
+                        PolicyError(format!(
+                            "grading.thresholds.{}: {:?} is not a whole number",
+                            key, v
+                        ))
+                    })
+                })
+                .transpose()
+        };
+        Thresholds::try_from(RawThresholds {
+            green_max: int("green_max")?,
+            yellow_max: int("yellow_max")?,
+            green: get("green"),
+            yellow: get("yellow"),
+            red: get("red"),
+        })
+    }
+
+    /// The verdict for a risk score.
+    pub fn verdict_for(&self, score: u32) -> Verdict {
+        if score <= self.green_max {
+            Verdict::Green
+        } else if score <= self.yellow_max {
+            Verdict::Yellow
+        } else {
+            Verdict::Red
+        }
+    }
+}
+
+/// A policy document that cannot be used.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("invalid policy: {0}")]
+pub struct PolicyError(pub String);
+
+// Minimal YAML value extractor (line-oriented). Assumes keys are unique.
+pub(crate) fn extract_yaml_scalar_under(content: &str, section: &str, key: &str) -> Option<String> {
+    let mut in_section = false;
+    let mut section_indent: Option<usize> = None;
+    for line in content.lines() {
+        let raw = line;
+        let trimmed = raw.trim_end();
+        let indent = raw.chars().take_while(|c| c.is_whitespace()).count();
+        if trimmed.trim_start().starts_with('#') {
+            continue;
+        }
+        if trimmed.trim() == format!("{}:", section) {
+            in_section = true;
+            section_indent = Some(indent);
+            continue;
+        }
+        if in_section {
+            // If indentation drops back to or above section start, section ends
+            if let Some(si) = section_indent {
+                if indent <= si && !trimmed.trim().is_empty() {
+                    in_section = false;
+                }
+            }
+            if in_section {
+                let t = trimmed.trim();
+                // `green` must not match `green_max:`.
+                if let Some(rest0) = t
+                    .strip_prefix(key)
+                    .filter(|r| r.trim_start().starts_with(':'))
+                {
+                    let rest = rest0.trim();
+                    let val = rest.trim_start_matches(':').trim();
+                    return Some(val.trim_matches('"').to_string());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// A threshold expression (`<=20`, `21..=60`, `>=61`) as an inclusive range.
+fn parse_score_range(expr: &str) -> Option<(u32, u32)> {
+    let e = expr.trim();
+    if let Some(rest) = e.strip_prefix("<=") {
+        if let Ok(v) = u32::from_str(rest.trim()) {
+            return Some((0, v));
+        }
+    }
+    if let Some(rest) = e.strip_prefix(">=") {
+        if let Ok(v) = u32::from_str(rest.trim()) {
+            return Some((v, u32::MAX));
+        }
+    }
+    if let Some((a, b)) = e.split_once("..=") {
+        if let (Ok(x), Ok(y)) = (u32::from_str(a.trim()), u32::from_str(b.trim())) {
+            return Some((x, y));
+        }
+    }
+    None
+}
+
+// Minimal patterns: '*' wildcard, suffix '/**' for subtree
+pub fn pat_matches(s: &str, pat: &str) -> bool {
+    if pat == "*" {
+        return true;
+    }
+    if let Some(base) = pat.strip_suffix("/**") {
+        return s.starts_with(base);
+    }
+    if pat.starts_with('*') && pat.ends_with('*') {
+        let needle = &pat[1..pat.len() - 1];
+        return s.contains(needle);
+    }
+    if let Some(stripped) = pat.strip_prefix('*') {
+        return s.ends_with(stripped);
+    }
+    if let Some(stripped) = pat.strip_suffix('*') {
+        return s.starts_with(stripped);
+    }
+    s == pat
+}
+
+// Extract http/https host[:port] occurrences from a command line string
+pub fn extract_http_hosts(cmd: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    for scheme in ["http://", "https://"].iter() {
+        let mut i = 0usize;
+        while let Some(pos) = cmd[i..].find(scheme) {
+            let start = i + pos + scheme.len();
+            let rest = &cmd[start..];
+            // host[:port] until first '/' or space
+            let end = rest
+                .find(|c: char| c == '/' || c.is_whitespace())
+                .unwrap_or(rest.len());
+            let hostport = &rest[..end];
+            if !hostport.is_empty() {
+                let default_port = if *scheme == "https://" { "443" } else { "80" };
+                let (h, p) = hostport_parts(hostport);
+                let hp = if p.is_none() {
+                    format!("{}:{}", h, default_port)
+                } else {
+                    hostport.to_string()
+                };
+                out.push(hp);
+            }
+            i = start + end;
+        }
+    }
+    out
+}
+
+// Extract /dev/... paths a command line refers to
+pub fn extract_device_paths(cmd: &str) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    let mut i = 0usize;
+    while let Some(pos) = cmd[i..].find("/dev/") {
+        let start = i + pos;
+        let preceded_ok = cmd[..start]
+            .chars()
+            .next_back()
+            .map(|c| !(c.is_ascii_alphanumeric() || c == '/' || c == '.' || c == '_' || c == '-'))
+            .unwrap_or(true);
+        let rest = &cmd[start..];
+        let end = rest
+            .find(|c: char| c.is_whitespace() || "'\";|&<>()`$".contains(c))
+            .unwrap_or(rest.len());
+        let path = &rest[..end];
+        if preceded_ok && path.len() > "/dev/".len() && !out.iter().any(|p| p == path) {
+            out.push(path.to_string());
+        }
+        i = start + end.max(1);
+    }
+    out
+}
+
+pub fn hostport_parts(s: &str) -> (Cow<str>, Option<&str>) {
+    let st = s.trim();
+    if let Some(rest) = st.strip_prefix('[') {
+        if let Some(pos) = rest.find(']') {
+            let host = &rest[..pos];
+            let after = &rest[pos + 1..];
+            if let Some(p) = after.strip_prefix(':') {
+                return (Cow::Owned(host.to_string()), Some(p));
+            }
+            return (Cow::Owned(host.to_string()), None);
+        }
+    }
+    if let Some((h, p)) = st.rsplit_once(':') {
+        if !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()) {
+            return (Cow::Owned(h.to_string()), Some(p));
+        }
+    }
+    (Cow::Borrowed(st), None)
+}
+
+fn parse_port_spec(p: Option<&str>) -> (bool, Option<(u16, u16)>) {
+    if let Some(ps) = p {
+        if ps == "*" {
+            return (true, None);
+        }
+        if let Some((a, b)) = ps.split_once('-') {
+            if let (Ok(x), Ok(y)) = (a.parse(), b.parse()) {
+                return (false, Some((x, y)));
+            }
+        }
+        if let Ok(x) = ps.parse::<u16>() {
+            return (false, Some((x, x)));
+        }
+    }
+    (false, None)
+}
+
+fn parse_cidr(host: &str) -> Option<(IpAddr, u8)> {
+    if let Some((ip, pre)) = host.split_once('/') {
+        if let (Ok(addr), Ok(p)) = (ip.parse::<IpAddr>(), pre.parse::<u8>()) {
+            return Some((addr, p));
+        }
+    }
+    None
+}
+
+fn ip_in_cidr(ip: IpAddr, cidr: (IpAddr, u8)) -> bool {
+    match (ip, cidr.0) {
+        (IpAddr::V4(a), IpAddr::V4(n)) => {
+            let a = u32::from(a);
+            let n = u32::from(n);
+            let p = cidr.1;
+            if p == 0 {
+                return true;
+            }
+            let mask = if p == 32 {
+                u32::MAX
+            } else {
+                (!0u32) << (32 - p as u32)
+            };
+            (a & mask) == (n & mask)
+        }
+        (IpAddr::V6(a), IpAddr::V6(n)) => {
+            let a = u128::from(a);
+            let n = u128::from(n);
+            let p = cidr.1;
+            if p == 0 {
+                return true;
+            }
+            let mask: u128 = if p == 128 {
+                u128::MAX
+            } else {
+                (!0u128) << (128 - p as u32)
+            };
+            (a & mask) == (n & mask)
+        }
+        _ => false,
+    }
+}
+
+pub fn allowed_match(host: &str, port: Option<&str>, allow: &str) -> bool {
+    // CIDR
+    if let Some((net, pre)) = parse_cidr(allow) {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            if ip_in_cidr(ip, (net, pre)) {
+                return true;
+            }
+        }
+        return false;
+    }
+    // wildcard / exact host patterns with optional port or ranges
+    let (a_host_port, a_ps) = hostport_parts(allow);
+    let (any_port, range) = parse_port_spec(a_ps);
+    let a_host = a_host_port.as_ref();
+    if let Some(suf) = a_host.strip_prefix("*.") {
+        if host.ends_with(suf) {
+            if any_port {
+                return true;
+            }
+            if let (Some((lo, hi)), Some(p)) = (range, port.and_then(|x| x.parse::<u16>().ok())) {
+                return p >= lo && p <= hi;
+            }
+            return range.is_none();
+        }
+    }
+    if a_host == host {
+        if any_port {
+            return true;
+        }
+        if let (Some((lo, hi)), Some(p)) = (range, port.and_then(|x| x.parse::<u16>().ok())) {
+            return p >= lo && p <= hi;
+        }
+        return range.is_none();
+    }
+    // IPv6 literal allow entry without brackets
+    if a_host.starts_with('[') && a_host.ends_with(']') {
+        let inner = &a_host[1..a_host.len() - 1];
+        if inner == host {
+            return true;
+        }
+    }
+    false
+}
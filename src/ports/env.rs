@@ -20,3 +20,54 @@ pub trait EnvironmentPort: Send + Sync {
     fn current_dir(&self) -> Result<String, EnvError>;
     fn args(&self) -> Vec<String>;
 }
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+mod registry {
+    use super::{EnvError, EnvironmentPort};
+    use crate::adapters::std_adapters::StdEnvAdapter;
+    use std::cell::RefCell;
+    use std::sync::Arc;
+
+    thread_local! {
+        static INSTALLED: RefCell<Option<Arc<dyn EnvironmentPort>>> = const { RefCell::new(None) };
+    }
+
+    /// The environment the engine reads on this thread: the process
+    /// environment, unless a test installed another with [`install`].
+    pub fn current() -> Arc<dyn EnvironmentPort> {
+        INSTALLED
+            .with(|i| i.borrow().clone())
+            .unwrap_or_else(|| Arc::new(StdEnvAdapter))
+    }
+
+    /// `key` from [`current`]; what engine code calls instead of
+    /// `std::env::var`.
+    pub fn var(key: &str) -> Result<String, EnvError> {
+        INSTALLED.with(|i| match &*i.borrow() {
+            Some(port) => port.get_var(key),
+            None => StdEnvAdapter.get_var(key),
+        })
+    }
+
+    /// Make `port` the environment of this thread until the guard is
+    /// dropped, which puts back the one it replaced.
+    pub fn install(port: Arc<dyn EnvironmentPort>) -> InstalledEnv {
+        let previous = INSTALLED.with(|i| i.borrow_mut().replace(port));
+        InstalledEnv { previous }
+    }
+
+    #[must_use = "the environment is uninstalled when the guard is dropped"]
+    pub struct InstalledEnv {
+        previous: Option<Arc<dyn EnvironmentPort>>,
+    }
+
+    impl Drop for InstalledEnv {
+        fn drop(&mut self) {
+            let previous = self.previous.take();
+            INSTALLED.with(|i| *i.borrow_mut() = previous);
+        }
+    }
+}
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub use registry::{current, install, var, InstalledEnv};
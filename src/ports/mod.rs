@@ -4,4 +4,4 @@ pub mod time;
 
 pub use env::EnvironmentPort;
 pub use io::{FileSystemPort, NetworkPort};
-pub use time::TimePort;
+pub use time::{Clock, TimePort};
@@ -2,6 +2,10 @@ use core::time::Duration;
 
 #[cfg(not(feature = "std"))]
 use alloc::boxed::Box;
+#[cfg(not(feature = "std"))]
+use alloc::sync::Arc;
+#[cfg(feature = "std")]
+use std::sync::Arc;
 
 #[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
 #[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
@@ -10,3 +14,44 @@ pub trait TimePort: Send + Sync {
     fn now_secs(&self) -> u64;
     async fn sleep(&self, duration: Duration);
 }
+
+/// A shared [`TimePort`]: the clock deadlines are measured against. The
+/// default is the system clock, sleeping on tokio's timer; tests install a
+/// manual one (`crate::testing::MockTimePort`) to move time themselves.
+///
+/// Polling intervals stay on real time; only deadlines and pauses go
+/// through the clock.
+#[derive(Clone)]
+pub struct Clock(Arc<dyn TimePort>);
+
+impl Clock {
+    pub fn new(port: Arc<dyn TimePort>) -> Self {
+        Self(port)
+    }
+
+    /// Time since `start`, a [`TimePort::now_millis`] reading.
+    pub fn since(&self, start: u64) -> Duration {
+        Duration::from_millis(self.0.now_millis().saturating_sub(start))
+    }
+}
+
+impl core::ops::Deref for Clock {
+    type Target = dyn TimePort;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl core::fmt::Debug for Clock {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("Clock").field(&self.0.now_millis()).finish()
+    }
+}
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+impl Default for Clock {
+    fn default() -> Self {
+        Self(Arc::new(crate::adapters::std_adapters::StdTimeAdapter))
+    }
+}
@@ -0,0 +1,760 @@
+//! Protobuf requests and results (`proto/magicrune.proto`, feature
+//! `protobuf`).
+//!
+//! The types below are what prost generates from the `.proto` file, kept
+//! in the tree so the build needs no `protoc`; change both together. They
+//! convert to and from the serde types in [`crate::schema`]: file contents
+//! and output travel as raw bytes instead of base64, and `env`/`vars`
+//! values as strings.
+//!
+//! Consumers accept a request sent with `Content-Type: application/protobuf`
+//! (see `crate::jet::jet_impl::open_message`) and run it as the JSON
+//! [`decode_request`] gives; results are still sent as JSON. A protobuf
+//! request carries no signature, so policies that require signed requests
+//! reject it.
+
+use crate::schema;
+use base64::Engine as _;
+use prost::Message as _;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// `Content-Type` of a protobuf-encoded [`SpellRequest`].
+pub const CONTENT_TYPE: &str = "application/protobuf";
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SpellRequest {
+    #[prost(string, tag = "1")]
+    pub cmd: String,
+    #[prost(string, tag = "2")]
+    pub stdin: String,
+    #[prost(btree_map = "string, string", tag = "3")]
+    pub env: BTreeMap<String, String>,
+    #[prost(message, repeated, tag = "4")]
+    pub files: Vec<File>,
+    #[prost(string, tag = "5")]
+    pub policy_id: String,
+    #[prost(uint64, tag = "6")]
+    pub timeout_sec: u64,
+    #[prost(string, repeated, tag = "7")]
+    pub allow_net: Vec<String>,
+    #[prost(string, repeated, tag = "8")]
+    pub allow_fs: Vec<String>,
+    #[prost(uint64, optional, tag = "9")]
+    pub seed: Option<u64>,
+    #[prost(string, optional, tag = "10")]
+    pub callback_url: Option<String>,
+    #[prost(string, optional, tag = "11")]
+    pub tenant: Option<String>,
+    #[prost(message, optional, tag = "12")]
+    pub limits: Option<RequestLimits>,
+    #[prost(btree_map = "string, string", tag = "13")]
+    pub vars: BTreeMap<String, String>,
+    #[prost(message, repeated, tag = "14")]
+    pub accept_risk: Vec<RiskAcceptance>,
+    #[prost(uint64, optional, tag = "15")]
+    pub deadline_ms: Option<u64>,
+    #[prost(bool, optional, tag = "16")]
+    pub pty: Option<bool>,
+    #[prost(bool, optional, tag = "17")]
+    pub strip_ansi: Option<bool>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RiskAcceptance {
+    #[prost(string, tag = "1")]
+    pub rule: String,
+    #[prost(string, tag = "2")]
+    pub reason: String,
+    #[prost(string, tag = "3")]
+    pub approver: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct File {
+    #[prost(string, tag = "1")]
+    pub path: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub content: Vec<u8>,
+}
+
+#[derive(Clone, Copy, PartialEq, prost::Message)]
+pub struct RequestLimits {
+    #[prost(uint64, optional, tag = "1")]
+    pub cpu_ms: Option<u64>,
+    #[prost(uint64, optional, tag = "2")]
+    pub memory_mb: Option<u64>,
+    #[prost(uint64, optional, tag = "3")]
+    pub pids: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SpellResult {
+    #[prost(string, tag = "1")]
+    pub run_id: String,
+    #[prost(string, tag = "2")]
+    pub verdict: String,
+    #[prost(uint32, tag = "3")]
+    pub risk_score: u32,
+    #[prost(int32, tag = "4")]
+    pub exit_code: i32,
+    #[prost(uint64, tag = "5")]
+    pub duration_ms: u64,
+    #[prost(bool, tag = "6")]
+    pub stdout_trunc: bool,
+    #[prost(bytes = "vec", tag = "7")]
+    pub stdout: Vec<u8>,
+    #[prost(bytes = "vec", tag = "8")]
+    pub stderr: Vec<u8>,
+    #[prost(bool, tag = "9")]
+    pub stderr_trunc: bool,
+    #[prost(string, tag = "10")]
+    pub sbom_attestation: String,
+    #[prost(message, optional, tag = "11")]
+    pub timings: Option<Timings>,
+    #[prost(message, optional, tag = "12")]
+    pub limits: Option<Limits>,
+    #[prost(message, optional, tag = "13")]
+    pub reason: Option<StopReason>,
+    #[prost(uint32, optional, tag = "14")]
+    pub pids_peak: Option<u32>,
+    #[prost(uint32, optional, tag = "15")]
+    pub orphans_reaped: Option<u32>,
+    #[prost(message, optional, tag = "16")]
+    pub usage: Option<Usage>,
+    #[prost(message, optional, tag = "17")]
+    pub interrupted: Option<Interrupted>,
+    #[prost(message, repeated, tag = "18")]
+    pub findings: Vec<Finding>,
+    #[prost(message, optional, tag = "19")]
+    pub risk_breakdown: Option<RiskBreakdown>,
+    #[prost(message, optional, tag = "20")]
+    pub syscalls: Option<SyscallSummary>,
+    #[prost(message, repeated, tag = "21")]
+    pub net_log: Vec<NetConnection>,
+    #[prost(message, repeated, tag = "22")]
+    pub manifest: Vec<FileChange>,
+    #[prost(string, tag = "23")]
+    pub shell: String,
+    #[prost(string, tag = "24")]
+    pub tenant: String,
+    #[prost(string, tag = "25")]
+    pub instance_id: String,
+    #[prost(string, tag = "26")]
+    pub signature: String,
+    #[prost(message, repeated, tag = "27")]
+    pub waivers: Vec<Waiver>,
+    #[prost(message, repeated, tag = "28")]
+    pub executables: Vec<Executable>,
+    #[prost(message, repeated, tag = "29")]
+    pub egress: Vec<EgressHost>,
+    #[prost(string, tag = "30")]
+    pub network: String,
+    #[prost(uint64, optional, tag = "31")]
+    pub queue_ms: Option<u64>,
+    #[prost(message, repeated, tag = "32")]
+    pub core_dumps: Vec<CoreDump>,
+    #[prost(message, optional, tag = "33")]
+    pub output_timeline: Option<OutputTimeline>,
+    #[prost(message, optional, tag = "34")]
+    pub env: Option<EnvSnapshot>,
+    #[prost(bool, tag = "35")]
+    pub cached: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, prost::Message)]
+pub struct Timings {
+    #[prost(uint64, tag = "1")]
+    pub validate_ms: u64,
+    #[prost(uint64, tag = "2")]
+    pub materialize_ms: u64,
+    #[prost(uint64, tag = "3")]
+    pub exec_ms: u64,
+    #[prost(uint64, tag = "4")]
+    pub grade_ms: u64,
+    #[prost(uint64, tag = "5")]
+    pub publish_ms: u64,
+    #[prost(uint64, tag = "6")]
+    pub net_cache_hits: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, prost::Message)]
+pub struct Limits {
+    #[prost(uint64, tag = "1")]
+    pub wall_sec: u64,
+    #[prost(uint64, tag = "2")]
+    pub cpu_ms: u64,
+    #[prost(uint64, tag = "3")]
+    pub memory_mb: u64,
+    #[prost(uint64, tag = "4")]
+    pub pids: u64,
+    #[prost(uint64, tag = "5")]
+    pub tmp_mb: u64,
+    #[prost(uint64, tag = "6")]
+    pub tmp_inodes: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StopReason {
+    #[prost(string, tag = "1")]
+    pub kind: String,
+    #[prost(string, tag = "2")]
+    pub limit: String,
+    #[prost(string, tag = "3")]
+    pub source: String,
+    #[prost(uint64, tag = "4")]
+    pub value: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, prost::Message)]
+pub struct Usage {
+    #[prost(uint64, tag = "1")]
+    pub read_bytes: u64,
+    #[prost(uint64, tag = "2")]
+    pub write_bytes: u64,
+    #[prost(uint64, tag = "3")]
+    pub cpu_ms: u64,
+    #[prost(uint32, tag = "4")]
+    pub cpu_pct: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Interrupted {
+    #[prost(uint64, tag = "1")]
+    pub started_at_ms: u64,
+    #[prost(string, tag = "2")]
+    pub instance_id: String,
+    #[prost(uint32, tag = "3")]
+    pub attempts: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Finding {
+    #[prost(string, tag = "1")]
+    pub kind: String,
+    #[prost(string, tag = "2")]
+    pub detail: String,
+    #[prost(uint64, tag = "3")]
+    pub count: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RiskBreakdown {
+    #[prost(uint32, tag = "1")]
+    pub raw: u32,
+    #[prost(btree_map = "string, uint32", tag = "2")]
+    pub rules: BTreeMap<String, u32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Waiver {
+    #[prost(string, tag = "1")]
+    pub rule: String,
+    #[prost(string, tag = "2")]
+    pub reason: String,
+    #[prost(string, tag = "3")]
+    pub approver: String,
+    #[prost(uint32, tag = "4")]
+    pub points: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, prost::Message)]
+pub struct SyscallSummary {
+    #[prost(uint64, tag = "1")]
+    pub file: u64,
+    #[prost(uint64, tag = "2")]
+    pub net: u64,
+    #[prost(uint64, tag = "3")]
+    pub process: u64,
+    #[prost(uint64, tag = "4")]
+    pub other: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NetConnection {
+    #[prost(string, tag = "1")]
+    pub ip: String,
+    #[prost(uint32, tag = "2")]
+    pub port: u32,
+    #[prost(bool, tag = "3")]
+    pub allowed: bool,
+    #[prost(string, tag = "4")]
+    pub error: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum FileChangeKind {
+    Created = 0,
+    Modified = 1,
+    Deleted = 2,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FileChange {
+    #[prost(string, tag = "1")]
+    pub path: String,
+    #[prost(enumeration = "FileChangeKind", tag = "2")]
+    pub change: i32,
+    #[prost(uint64, tag = "3")]
+    pub size: u64,
+    #[prost(string, tag = "4")]
+    pub sha256: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Executable {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub path: String,
+    #[prost(string, tag = "3")]
+    pub sha256: String,
+    #[prost(string, tag = "4")]
+    pub package: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CoreDump {
+    #[prost(string, tag = "1")]
+    pub path: String,
+    #[prost(uint64, tag = "2")]
+    pub bytes: u64,
+    #[prost(string, tag = "3")]
+    pub sha256: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OutputTimeline {
+    #[prost(uint64, tag = "1")]
+    pub chunks: u64,
+    #[prost(string, tag = "2")]
+    pub jsonl_b64: String,
+    #[prost(string, tag = "3")]
+    pub path: String,
+    #[prost(uint64, tag = "4")]
+    pub bytes: u64,
+    #[prost(string, tag = "5")]
+    pub sha256: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct EnvSnapshot {
+    #[prost(message, repeated, tag = "1")]
+    pub vars: Vec<EnvVar>,
+    #[prost(string, tag = "2")]
+    pub sha256: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct EnvVar {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, optional, tag = "2")]
+    pub value: Option<String>,
+    #[prost(string, tag = "3")]
+    pub source: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct EgressHost {
+    #[prost(string, tag = "1")]
+    pub target: String,
+    #[prost(uint64, tag = "2")]
+    pub bytes_out: u64,
+    #[prost(uint64, tag = "3")]
+    pub bytes_in: u64,
+    #[prost(uint32, tag = "4")]
+    pub connections: u32,
+    #[prost(uint32, tag = "5")]
+    pub denied: u32,
+}
+
+/// The JSON request the engine runs for a protobuf-encoded [`SpellRequest`].
+pub fn decode_request(body: &[u8]) -> Result<Vec<u8>, String> {
+    let req = SpellRequest::decode(body).map_err(|e| format!("protobuf request: {}", e))?;
+    let mut json =
+        serde_json::to_value(schema::SpellRequest::from(req)).map_err(|e| e.to_string())?;
+    if let Some(obj) = json.as_object_mut() {
+        obj.retain(|_, v| !v.is_null());
+    }
+    serde_json::to_vec(&json).map_err(|e| e.to_string())
+}
+
+fn b64() -> &'static base64::engine::GeneralPurpose {
+    &base64::engine::general_purpose::STANDARD
+}
+
+/// Protobuf carries `env` and `vars` values as strings.
+fn scalars_to_strings(
+    name: &str,
+    map: Option<&serde_json::Map<String, Value>>,
+) -> Result<BTreeMap<String, String>, String> {
+    map.into_iter()
+        .flatten()
+        .map(|(k, v)| match v {
+            Value::String(s) => Ok((k.clone(), s.clone())),
+            Value::Number(_) | Value::Bool(_) => Ok((k.clone(), v.to_string())),
+            _ => Err(format!("{}.{} must be string/number/bool", name, k)),
+        })
+        .collect()
+}
+
+fn strings_to_scalars(map: BTreeMap<String, String>) -> serde_json::Map<String, Value> {
+    map.into_iter()
+        .map(|(k, v)| (k, Value::String(v)))
+        .collect()
+}
+
+impl From<SpellRequest> for schema::SpellRequest {
+    fn from(p: SpellRequest) -> Self {
+        let files = p
+            .files
+            .into_iter()
+            .map(|f| serde_json::json!({ "path": f.path, "content_b64": b64().encode(f.content) }))
+            .collect();
+        Self {
+            cmd: Some(p.cmd),
+            stdin: Some(p.stdin),
+            env: Some(strings_to_scalars(p.env)),
+            files: Some(files),
+            policy_id: Some(p.policy_id),
+            timeout_sec: Some(p.timeout_sec),
+            allow_net: Some(p.allow_net),
+            allow_fs: Some(p.allow_fs),
+            seed: p.seed,
+            callback_url: p.callback_url,
+            tenant: p.tenant,
+            limits: p.limits.map(|l| schema::RequestLimits {
+                cpu_ms: l.cpu_ms,
+                memory_mb: l.memory_mb,
+                pids: l.pids,
+            }),
+            vars: Some(strings_to_scalars(p.vars)).filter(|v| !v.is_empty()),
+            accept_risk: Some(p.accept_risk.into_iter().map(Into::into).collect())
+                .filter(|a: &Vec<_>| !a.is_empty()),
+            deadline_ms: p.deadline_ms,
+            pty: p.pty,
+            strip_ansi: p.strip_ansi,
+        }
+    }
+}
+
+impl TryFrom<&schema::SpellRequest> for SpellRequest {
+    type Error = String;
+
+    fn try_from(r: &schema::SpellRequest) -> Result<Self, String> {
+        let files = r
+            .files
+            .iter()
+            .flatten()
+            .map(|f| {
+                let path = f.get("path").and_then(Value::as_str);
+                let content = f.get("content_b64").and_then(Value::as_str).unwrap_or("");
+                Ok(File {
+                    path: path.ok_or("file.path must be string")?.to_string(),
+                    content: b64()
+                        .decode(content)
+                        .map_err(|e| format!("file.content_b64: {}", e))?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            cmd: r.cmd.clone().unwrap_or_default(),
+            stdin: r.stdin.clone().unwrap_or_default(),
+            env: scalars_to_strings("env", r.env.as_ref())?,
+            files,
+            policy_id: r.policy_id.clone().unwrap_or_default(),
+            timeout_sec: r.timeout_sec.unwrap_or_default(),
+            allow_net: r.allow_net.clone().unwrap_or_default(),
+            allow_fs: r.allow_fs.clone().unwrap_or_default(),
+            seed: r.seed,
+            callback_url: r.callback_url.clone(),
+            tenant: r.tenant.clone(),
+            limits: r.limits.map(|l| RequestLimits {
+                cpu_ms: l.cpu_ms,
+                memory_mb: l.memory_mb,
+                pids: l.pids,
+            }),
+            vars: scalars_to_strings("vars", r.vars.as_ref())?,
+            accept_risk: r
+                .accept_risk
+                .iter()
+                .flatten()
+                .cloned()
+                .map(Into::into)
+                .collect(),
+            deadline_ms: r.deadline_ms,
+            pty: r.pty,
+            strip_ansi: r.strip_ansi,
+        })
+    }
+}
+
+/// `From` both ways between a message and the serde type of the same name
+/// and fields.
+macro_rules! same_fields {
+    ($($ty:ident { $($f:ident),* })*) => {$(
+        impl From<schema::$ty> for $ty {
+            fn from(v: schema::$ty) -> Self {
+                Self { $($f: v.$f),* }
+            }
+        }
+
+        impl From<$ty> for schema::$ty {
+            fn from(v: $ty) -> Self {
+                Self { $($f: v.$f),* }
+            }
+        }
+    )*};
+}
+
+same_fields! {
+    Timings { validate_ms, materialize_ms, exec_ms, grade_ms, publish_ms, net_cache_hits }
+    Limits { wall_sec, cpu_ms, memory_mb, pids, tmp_mb, tmp_inodes }
+    StopReason { kind, limit, source, value }
+    Usage { read_bytes, write_bytes, cpu_ms, cpu_pct }
+    Interrupted { started_at_ms, instance_id, attempts }
+    Finding { kind, detail, count }
+    RiskAcceptance { rule, reason, approver }
+    RiskBreakdown { raw, rules }
+    Waiver { rule, reason, approver, points }
+    Executable { name, path, sha256, package }
+    CoreDump { path, bytes, sha256 }
+    OutputTimeline { chunks, jsonl_b64, path, bytes, sha256 }
+    EnvVar { name, value, source }
+    EgressHost { target, bytes_out, bytes_in, connections, denied }
+    SyscallSummary { file, net, process, other }
+}
+
+impl From<schema::NetConnection> for NetConnection {
+    fn from(c: schema::NetConnection) -> Self {
+        Self {
+            ip: c.ip,
+            port: c.port.into(),
+            allowed: c.allowed,
+            error: c.error,
+        }
+    }
+}
+
+impl From<schema::FileChange> for FileChange {
+    fn from(c: schema::FileChange) -> Self {
+        let change = match c.change {
+            schema::FileChangeKind::Created => FileChangeKind::Created,
+            schema::FileChangeKind::Modified => FileChangeKind::Modified,
+            schema::FileChangeKind::Deleted => FileChangeKind::Deleted,
+        };
+        Self {
+            path: c.path,
+            change: change.into(),
+            size: c.size,
+            sha256: c.sha256,
+        }
+    }
+}
+
+impl From<schema::EnvSnapshot> for EnvSnapshot {
+    fn from(e: schema::EnvSnapshot) -> Self {
+        Self {
+            vars: e.vars.into_iter().map(Into::into).collect(),
+            sha256: e.sha256,
+        }
+    }
+}
+
+impl From<EnvSnapshot> for schema::EnvSnapshot {
+    fn from(e: EnvSnapshot) -> Self {
+        Self {
+            vars: e.vars.into_iter().map(Into::into).collect(),
+            sha256: e.sha256,
+        }
+    }
+}
+
+impl TryFrom<schema::SpellResult> for SpellResult {
+    type Error = String;
+
+    fn try_from(r: schema::SpellResult) -> Result<Self, String> {
+        let decode =
+            |name: &str, s: &str| b64().decode(s).map_err(|e| format!("{}_b64: {}", name, e));
+        Ok(Self {
+            stdout: decode("stdout", &r.stdout_b64)?,
+            stderr: decode("stderr", &r.stderr_b64)?,
+            run_id: r.run_id,
+            verdict: r.verdict,
+            risk_score: r.risk_score,
+            exit_code: r.exit_code,
+            duration_ms: r.duration_ms,
+            stdout_trunc: r.stdout_trunc,
+            stderr_trunc: r.stderr_trunc,
+            sbom_attestation: r.sbom_attestation,
+            timings: r.timings.map(Into::into),
+            queue_ms: r.queue_ms,
+            limits: r.limits.map(Into::into),
+            reason: r.reason.map(Into::into),
+            pids_peak: r.pids_peak,
+            orphans_reaped: r.orphans_reaped,
+            usage: r.usage.map(Into::into),
+            interrupted: r.interrupted.map(Into::into),
+            findings: r.findings.into_iter().map(Into::into).collect(),
+            risk_breakdown: r.risk_breakdown.map(Into::into),
+            waivers: r.waivers.into_iter().map(Into::into).collect(),
+            executables: r.executables.into_iter().map(Into::into).collect(),
+            egress: r.egress.into_iter().map(Into::into).collect(),
+            network: r.network,
+            syscalls: r.syscalls.map(Into::into),
+            net_log: r.net_log.into_iter().map(Into::into).collect(),
+            manifest: r.manifest.into_iter().map(Into::into).collect(),
+            core_dumps: r.core_dumps.into_iter().map(Into::into).collect(),
+            output_timeline: r.output_timeline.map(Into::into),
+            env: r.env.map(Into::into),
+            cached: r.cached,
+            shell: r.shell,
+            tenant: r.tenant,
+            instance_id: r.instance_id,
+            signature: r.signature,
+        })
+    }
+}
+
+impl TryFrom<SpellResult> for schema::SpellResult {
+    type Error = String;
+
+    fn try_from(p: SpellResult) -> Result<Self, String> {
+        let net_log = p
+            .net_log
+            .into_iter()
+            .map(|c| {
+                Ok(schema::NetConnection {
+                    port: u16::try_from(c.port).map_err(|_| format!("net_log port {}", c.port))?,
+                    ip: c.ip,
+                    allowed: c.allowed,
+                    error: c.error,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        let manifest = p
+            .manifest
+            .into_iter()
+            .map(|c| {
+                let change = match FileChangeKind::try_from(c.change) {
+                    Ok(FileChangeKind::Created) => schema::FileChangeKind::Created,
+                    Ok(FileChangeKind::Modified) => schema::FileChangeKind::Modified,
+                    Ok(FileChangeKind::Deleted) => schema::FileChangeKind::Deleted,
+                    Err(_) => return Err(format!("manifest change {}", c.change)),
+                };
+                Ok(schema::FileChange {
+                    path: c.path,
+                    change,
+                    size: c.size,
+                    sha256: c.sha256,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            run_id: p.run_id,
+            verdict: p.verdict,
+            risk_score: p.risk_score,
+            exit_code: p.exit_code,
+            duration_ms: p.duration_ms,
+            stdout_trunc: p.stdout_trunc,
+            stdout_b64: b64().encode(p.stdout),
+            stderr_b64: b64().encode(p.stderr),
+            stderr_trunc: p.stderr_trunc,
+            sbom_attestation: p.sbom_attestation,
+            timings: p.timings.map(Into::into),
+            queue_ms: p.queue_ms,
+            limits: p.limits.map(Into::into),
+            reason: p.reason.map(Into::into),
+            pids_peak: p.pids_peak,
+            orphans_reaped: p.orphans_reaped,
+            usage: p.usage.map(Into::into),
+            interrupted: p.interrupted.map(Into::into),
+            findings: p.findings.into_iter().map(Into::into).collect(),
+            risk_breakdown: p.risk_breakdown.map(Into::into),
+            waivers: p.waivers.into_iter().map(Into::into).collect(),
+            executables: p.executables.into_iter().map(Into::into).collect(),
+            egress: p.egress.into_iter().map(Into::into).collect(),
+            network: p.network,
+            syscalls: p.syscalls.map(Into::into),
+            net_log,
+            manifest,
+            core_dumps: p.core_dumps.into_iter().map(Into::into).collect(),
+            output_timeline: p.output_timeline.map(Into::into),
+            env: p.env.map(Into::into),
+            cached: p.cached,
+            shell: p.shell,
+            tenant: p.tenant,
+            instance_id: p.instance_id,
+            signature: p.signature,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_roundtrip() {
+        let json = r#"{"cmd":"cat a","stdin":"","env":{"N":1,"S":"x"},"files":[{"path":"a","content_b64":"aGk="}],"policy_id":"p","timeout_sec":5,"allow_net":[],"allow_fs":["/tmp/**"],"seed":7,"limits":{"pids":4},"accept_risk":[{"rule":"ssh","reason":"deploy","approver":"ops"}]}"#;
+        let req: schema::SpellRequest = serde_json::from_str(json).unwrap();
+        let p = SpellRequest::try_from(&req).unwrap();
+        assert_eq!(p.files[0].content, b"hi");
+        assert_eq!(p.env["N"], "1");
+
+        let decoded: Value =
+            serde_json::from_slice(&decode_request(&p.encode_to_vec()).unwrap()).unwrap();
+        assert_eq!(decoded["env"], serde_json::json!({ "N": "1", "S": "x" }));
+        assert_eq!(decoded["files"][0]["content_b64"], "aGk=");
+        assert_eq!(decoded["seed"], 7);
+        assert_eq!(decoded["limits"], serde_json::json!({ "pids": 4 }));
+        assert_eq!(decoded["accept_risk"][0]["approver"], "ops");
+        assert!(decoded.get("tenant").is_none() && decoded.get("vars").is_none());
+        assert!(schema::request_errors(&decoded).is_empty());
+
+        assert!(decode_request(b"\xff")
+            .unwrap_err()
+            .starts_with("protobuf request"));
+    }
+
+    #[test]
+    fn test_result_roundtrip() {
+        let mut res = schema::SpellResult {
+            run_id: "r_1".into(),
+            verdict: "green".into(),
+            net_log: vec![schema::NetConnection {
+                ip: "10.0.0.1".into(),
+                port: 443,
+                allowed: true,
+                error: String::new(),
+            }],
+            manifest: vec![schema::FileChange {
+                path: "/tmp/a".into(),
+                change: schema::FileChangeKind::Modified,
+                size: 2,
+                sha256: String::new(),
+            }],
+            timings: Some(schema::Timings {
+                exec_ms: 3,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        res.set_output(b"out\xff", b"");
+        let p = SpellResult::try_from(res.clone()).unwrap();
+        assert_eq!(p.stdout, b"out\xff");
+        let back =
+            schema::SpellResult::try_from(SpellResult::decode(&p.encode_to_vec()[..]).unwrap())
+                .unwrap();
+        assert_eq!(
+            serde_json::to_value(&back).unwrap(),
+            serde_json::to_value(&res).unwrap()
+        );
+
+        let mut bad = p;
+        bad.net_log[0].port = 70_000;
+        assert!(schema::SpellResult::try_from(bad).is_err());
+    }
+}
@@ -0,0 +1,340 @@
+//! Re-running a recorded request to see that it reproduces (`magicrune
+//! repro`).
+//!
+//! The request comes from the run's ledger record, which keeps it under
+//! `ledger.request: store`. It is run the given number of times in
+//! determinism mode ([`ExecOptions::deterministic`]), and every run is
+//! compared with the baseline on exit code, the sha256 of stdout and of
+//! stderr (as the result carries them, capped at
+//! [`crate::schema::OUTPUT_CAP`]) and whether it failed. The baseline is
+//! the recorded result under `ledger.result: store`, otherwise the first
+//! run. The score is the share of runs that match it; a spell whose runs all
+//! match is hermetic, as far as this can tell, and its cached results can
+//! be relied on.
+//!
+//! The recorded run did not run in determinism mode, so a difference from a
+//! recorded baseline alone may come from the consumer's locale, time zone
+//! or clock. As with `exec --compare`, the execution guard, the shadow
+//! policy, audit events, alerts and ledger records are left out.
+
+use crate::engine::{self, ExecOptions};
+use crate::ledger::RunRecord;
+use crate::policy::Policy;
+use crate::schema::SpellResult;
+use serde::Serialize;
+
+/// The baseline's `run` when it is the recorded result.
+pub const LEDGER: &str = "ledger";
+
+/// Longest line quoted in a [`Difference`].
+const QUOTE_MAX: usize = 120;
+
+/// What one run, or the recorded one, came to.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Attempt {
+    /// [`LEDGER`], or the run's number from 1.
+    pub run: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub verdict: String,
+    pub exit_code: i32,
+    pub stdout_sha256: String,
+    pub stderr_sha256: String,
+    /// Why there is no result.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip)]
+    stdout: Vec<u8>,
+    #[serde(skip)]
+    stderr: Vec<u8>,
+}
+
+impl Attempt {
+    fn of_result(run: String, result: &SpellResult) -> Self {
+        use base64::Engine as _;
+        let b64 = &base64::engine::general_purpose::STANDARD;
+        let stdout = b64.decode(&result.stdout_b64).unwrap_or_default();
+        let stderr = b64.decode(&result.stderr_b64).unwrap_or_default();
+        Self {
+            run,
+            verdict: result.verdict.clone(),
+            exit_code: result.exit_code,
+            stdout_sha256: sha256_hex(&stdout),
+            stderr_sha256: sha256_hex(&stderr),
+            error: None,
+            stdout,
+            stderr,
+        }
+    }
+
+    fn failed(run: String, error: String) -> Self {
+        Self {
+            run,
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+}
+
+/// One way a run differs from the baseline.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Difference {
+    pub run: String,
+    /// `exit_code`, `stdout`, `stderr` or `error`.
+    pub field: String,
+    pub baseline: serde_json::Value,
+    pub value: serde_json::Value,
+    /// The first line that differs, for `stdout` and `stderr`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReproReport {
+    pub run_id: String,
+    pub times: u32,
+    /// Runs that match the baseline over runs made, 0 to 1.
+    pub score: f64,
+    /// Every run matches the baseline.
+    pub hermetic: bool,
+    /// The policy is not the one the run was recorded under.
+    pub policy_changed: bool,
+    pub baseline: Attempt,
+    pub runs: Vec<Attempt>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub differences: Vec<Difference>,
+}
+
+impl ReproReport {
+    /// 0 when hermetic, 1 otherwise.
+    pub fn exit_code(&self) -> i32 {
+        i32::from(!self.hermetic)
+    }
+}
+
+/// Run the request of `rec` `times` times under `policy` and compare the
+/// runs. Fails when the record has no request to run.
+pub async fn repro(
+    rec: &RunRecord,
+    times: u32,
+    policy: &Policy,
+    opts: &ExecOptions,
+) -> Result<ReproReport, String> {
+    let Some(request) = &rec.request else {
+        return Err(format!(
+            "{}: the ledger has no request to run (ledger.request: store)",
+            rec.run_id
+        ));
+    };
+    let raw = request.to_string().into_bytes();
+    let opts = ExecOptions {
+        guard: None,
+        result_cache: None,
+        shadow: None,
+        audit: None,
+        notifier: None,
+        ledger: None,
+        interactive: false,
+        deterministic: true,
+        ..opts.clone()
+    };
+    let recorded = rec
+        .result
+        .as_ref()
+        .and_then(|v| serde_json::from_value::<SpellResult>(v.clone()).ok())
+        .map(|r| Attempt::of_result(LEDGER.into(), &r));
+    let mut runs = Vec::with_capacity(times as usize);
+    for n in 1..=times {
+        runs.push(match engine::execute(&raw, policy, &opts).await {
+            Ok(run) => Attempt::of_result(n.to_string(), &run.result),
+            Err(e) => Attempt::failed(n.to_string(), e.to_string()),
+        });
+    }
+    let baseline = recorded
+        .or_else(|| runs.first().cloned())
+        .unwrap_or_default();
+    let differences = differences(&baseline, &runs);
+    let matched = runs
+        .iter()
+        .filter(|r| !differences.iter().any(|d| d.run == r.run))
+        .count();
+    Ok(ReproReport {
+        run_id: rec.run_id.clone(),
+        times,
+        score: if times == 0 {
+            0.0
+        } else {
+            matched as f64 / times as f64
+        },
+        hermetic: times > 0 && matched == runs.len(),
+        policy_changed: !rec.policy_digest.is_empty() && rec.policy_digest != policy.digest,
+        baseline,
+        runs,
+        differences,
+    })
+}
+
+/// Every field in which one of `runs` differs from `baseline`.
+pub fn differences(baseline: &Attempt, runs: &[Attempt]) -> Vec<Difference> {
+    let mut out = Vec::new();
+    for run in runs {
+        let mut differ = |field: &str, baseline, value, diff| {
+            out.push(Difference {
+                run: run.run.clone(),
+                field: field.to_string(),
+                baseline,
+                value,
+                diff,
+            })
+        };
+        if run.error != baseline.error {
+            differ(
+                "error",
+                serde_json::json!(baseline.error),
+                serde_json::json!(run.error),
+                None,
+            );
+            continue;
+        }
+        if run.exit_code != baseline.exit_code {
+            differ(
+                "exit_code",
+                baseline.exit_code.into(),
+                run.exit_code.into(),
+                None,
+            );
+        }
+        if run.stdout_sha256 != baseline.stdout_sha256 {
+            differ(
+                "stdout",
+                baseline.stdout_sha256.clone().into(),
+                run.stdout_sha256.clone().into(),
+                Some(first_difference(&baseline.stdout, &run.stdout)),
+            );
+        }
+        if run.stderr_sha256 != baseline.stderr_sha256 {
+            differ(
+                "stderr",
+                baseline.stderr_sha256.clone().into(),
+                run.stderr_sha256.clone().into(),
+                Some(first_difference(&baseline.stderr, &run.stderr)),
+            );
+        }
+    }
+    out
+}
+
+/// `line <n>: "<baseline>" != "<value>"` for the first line that differs.
+fn first_difference(baseline: &[u8], value: &[u8]) -> String {
+    let (a, b) = (
+        String::from_utf8_lossy(baseline),
+        String::from_utf8_lossy(value),
+    );
+    let quote = |l: Option<&str>| match l {
+        Some(l) => format!("{:?}", l.chars().take(QUOTE_MAX).collect::<String>()),
+        None => "<end>".to_string(),
+    };
+    let (mut la, mut lb) = (a.split('\n'), b.split('\n'));
+    for n in 1.. {
+        match (la.next(), lb.next()) {
+            (Some(x), Some(y)) if x == y => continue,
+            (None, None) => break,
+            (x, y) => return format!("line {}: {} != {}", n, quote(x), quote(y)),
+        }
+    }
+    "same lines".to_string()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(cmd: &str) -> RunRecord {
+        RunRecord {
+            run_id: "r_1".into(),
+            request: Some(serde_json::json!({"cmd": cmd})),
+            ..Default::default()
+        }
+    }
+
+    fn opts() -> ExecOptions {
+        ExecOptions {
+            sandbox: Some(crate::sandbox::SandboxKind::Linux),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_repro_scores_runs() {
+        let policy = Policy::default();
+        let report = repro(&record("echo $TZ"), 3, &policy, &opts())
+            .await
+            .unwrap();
+        assert!(report.hermetic, "{:?}", report.differences);
+        assert_eq!(report.score, 1.0);
+        assert_eq!(report.baseline.run, "1");
+        assert_eq!(report.runs[2].stdout, b"UTC\n");
+        assert_eq!(report.exit_code(), 0);
+
+        let report = repro(&record("date +%s%N"), 2, &policy, &opts())
+            .await
+            .unwrap();
+        assert!(!report.hermetic);
+        assert_eq!(report.score, 0.5);
+        assert_eq!(report.differences[0].field, "stdout");
+        assert!(report.differences[0]
+            .diff
+            .as_deref()
+            .unwrap()
+            .starts_with("line 1: "));
+        assert_eq!(report.exit_code(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_repro_against_recorded_result() {
+        let mut rec = record("echo hi");
+        rec.policy_digest = "sha256:other".into();
+        let mut recorded = SpellResult {
+            run_id: "r_1".into(),
+            verdict: "green".into(),
+            ..Default::default()
+        };
+        recorded.set_output(b"hello\n", b"");
+        rec.result = Some(serde_json::to_value(&recorded).unwrap());
+        let report = repro(&rec, 1, &Policy::default(), &opts()).await.unwrap();
+        assert_eq!(report.baseline.run, LEDGER);
+        assert!(report.policy_changed);
+        assert_eq!(report.score, 0.0);
+        assert_eq!(
+            report.differences[0].diff.as_deref(),
+            Some(r#"line 1: "hello" != "hi""#)
+        );
+
+        let err = repro(
+            &RunRecord::default(),
+            1,
+            &Policy::default(),
+            &ExecOptions::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.contains("ledger.request: store"), "{}", err);
+    }
+
+    #[test]
+    fn test_first_difference() {
+        assert_eq!(
+            first_difference(b"a\nb\n", b"a\nc\n"),
+            r#"line 2: "b" != "c""#
+        );
+        assert_eq!(first_difference(b"a\n", b"a\nb"), r#"line 2: "" != "b""#);
+        assert_eq!(first_difference(b"a", b"a\n"), r#"line 2: <end> != """#);
+    }
+}
@@ -0,0 +1,164 @@
+//! Result cache: answer a repeated request with the result it got before.
+//!
+//! A pure spell gives the same result every time, so running an identical
+//! request again only spends compute, and CI retry storms send the same
+//! request many times. A policy opts in with `cache.ttl_sec`: green results
+//! of runs under it are then kept, and an identical request under the same
+//! policy within the TTL is answered with the stored result without running
+//! anything. That result carries this run's `run_id`, `queue_ms`,
+//! `duration_ms` and `instance_id`, is marked `cached: true` and is signed
+//! again.
+//!
+//! Entries live in `MAGICRUNE_RESULT_CACHE_DIR` (default `result_cache`),
+//! as `<tenant>/<key>.json`, so one tenant's results never answer
+//! another's requests. The key ([`ResultCache::key`]) is the sha256 of the
+//! request digest, the run's seed and the policy digest. The request digest
+//! is of the canonical request (keys sorted, as the ledger's
+//! `request_sha256`) after its `${VAR}` placeholders are filled in, so
+//! changing a variable or the policy misses the cache.
+//!
+//! Only results worth repeating are kept ([`storable`]): green, exit code
+//! 0, stopped by no limit and with all their output embedded. An entry's
+//! age is its file's mtime; an expired entry is dropped when looked up.
+//! Several consumers may share the directory: entries are written under a
+//! temporary name and renamed into place.
+//!
+//! Whether a spell is pure is the policy's call; `magicrune repro` checks
+//! it, and never reads the cache.
+
+use crate::schema::SpellResult;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// Directory of the cache when `MAGICRUNE_RESULT_CACHE_DIR` is unset.
+pub const DEFAULT_DIR: &str = "result_cache";
+
+#[derive(Debug, Clone)]
+pub struct ResultCache {
+    dir: PathBuf,
+}
+
+impl ResultCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The cache at `MAGICRUNE_RESULT_CACHE_DIR`, or [`DEFAULT_DIR`]. It is
+    /// only used under policies with `cache.ttl_sec`.
+    pub fn from_env() -> Self {
+        let dir = crate::ports::env::var("MAGICRUNE_RESULT_CACHE_DIR")
+            .ok()
+            .filter(|d| !d.is_empty())
+            .unwrap_or_else(|| DEFAULT_DIR.to_string());
+        Self::new(dir)
+    }
+
+    /// Cache key of the request `raw` run with `seed` under the policy with
+    /// digest `policy_digest`, as hex sha256.
+    pub fn key(raw: &[u8], seed: Option<u64>, policy_digest: &str) -> String {
+        use sha2::{Digest, Sha256};
+        let canonical = serde_json::from_slice::<serde_json::Value>(raw)
+            .map(|v| v.to_string().into_bytes())
+            .unwrap_or_else(|_| raw.to_vec());
+        let mut h = Sha256::new();
+        h.update(Sha256::digest(&canonical));
+        h.update(seed.map(|s| s.to_string()).unwrap_or_default());
+        h.update(b"\0");
+        h.update(policy_digest.as_bytes());
+        h.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn entry(&self, tenant: &str, key: &str) -> PathBuf {
+        self.dir.join(tenant).join(format!("{}.json", key))
+    }
+
+    /// The result stored for `key` under `tenant`, unless it is older than
+    /// `ttl`.
+    pub fn get(&self, tenant: &str, key: &str, ttl: Duration) -> Option<SpellResult> {
+        let path = self.entry(tenant, key);
+        let age = std::fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .ok()
+            .map(|t| SystemTime::now().duration_since(t).unwrap_or_default())?;
+        if age > ttl {
+            let _ = std::fs::remove_file(&path);
+            return None;
+        }
+        serde_json::from_slice(&std::fs::read(&path).ok()?).ok()
+    }
+
+    /// Keep `result` for `key` under `tenant`, replacing what was there.
+    pub fn put(&self, tenant: &str, key: &str, result: &SpellResult) -> io::Result<()> {
+        let path = self.entry(tenant, key);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        let mut f = std::fs::File::create(&tmp)?;
+        f.write_all(&serde_json::to_vec(result)?)?;
+        std::fs::rename(&tmp, &path)
+    }
+}
+
+/// Whether `result` may answer later requests: green, exit code 0, not
+/// stopped by a limit and with untruncated output.
+pub fn storable(result: &SpellResult) -> bool {
+    result.verdict == "green"
+        && result.exit_code == 0
+        && result.reason.is_none()
+        && !result.stdout_trunc
+        && !result.stderr_trunc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_is_canonical_and_policy_bound() {
+        let raw = br#"{"cmd":"echo hi","timeout_sec":5}"#;
+        let a = ResultCache::key(raw, None, "p1");
+        let b = ResultCache::key(br#"{ "timeout_sec": 5, "cmd": "echo hi" }"#, None, "p1");
+        assert_eq!(a, b);
+        assert_eq!(a.len(), 64);
+        assert_ne!(a, ResultCache::key(raw, None, "p2"));
+        assert_ne!(a, ResultCache::key(raw, Some(1), "p1"));
+    }
+
+    #[test]
+    fn test_put_get_and_expiry() {
+        let dir = std::env::temp_dir().join(format!("mr_result_cache_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = ResultCache::new(&dir);
+        let result = SpellResult {
+            run_id: "r_1".into(),
+            verdict: "green".into(),
+            ..Default::default()
+        };
+        assert!(storable(&result));
+        assert!(cache.get("acme", "k", Duration::from_secs(60)).is_none());
+        cache.put("acme", "k", &result).unwrap();
+        let hit = cache.get("acme", "k", Duration::from_secs(60)).unwrap();
+        assert_eq!(hit.run_id, "r_1");
+        assert!(cache.get("beta", "k", Duration::from_secs(60)).is_none());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(cache.get("acme", "k", Duration::from_millis(1)).is_none());
+        assert!(!cache.entry("acme", "k").exists());
+
+        assert!(!storable(&SpellResult {
+            verdict: "yellow".into(),
+            ..result.clone()
+        }));
+        assert!(!storable(&SpellResult {
+            exit_code: 1,
+            ..result.clone()
+        }));
+        assert!(!storable(&SpellResult {
+            stdout_trunc: true,
+            ..result
+        }));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -4,17 +4,265 @@ pub enum SandboxKind {
     Linux,
 }
 
+pub mod ansi;
+pub mod audit;
+pub mod coredump;
+pub mod dns;
+pub mod egress;
+pub mod environ;
+#[cfg(all(target_os = "linux", feature = "linux_native"))]
+pub mod init;
+pub mod manifest;
+pub mod netlog;
+pub mod oom;
+pub mod provenance;
+#[cfg(all(target_os = "linux", feature = "linux_native"))]
+pub mod pty;
+pub mod shell;
+pub mod syscalls;
+pub mod timeline;
+pub mod trace;
+pub mod usage;
+
+#[derive(Debug, Clone, Default)]
 pub struct SandboxSpec {
     pub wall_sec: u64,
     pub cpu_ms: u64,
     pub memory_mb: u64,
     pub pids: u64,
+    /// Device node patterns (`capabilities.devices.allow`) bound into the
+    /// overlay root's `/dev` next to [`STANDARD_DEVICES`].
+    pub devices: Vec<String>,
+    /// Host path patterns (`capabilities.fs.readonly`) bind-mounted read-only
+    /// into the overlay root.
+    pub readonly: Vec<String>,
+    /// Syscalls allowed on top of the built-in seccomp allow-list
+    /// (`capabilities.syscalls.allow`).
+    pub syscalls: Vec<String>,
+    /// Count the command's syscalls by category under `strace`
+    /// (`observe.syscalls: summary`).
+    pub observe_syscalls: bool,
+    /// Log the command's `connect` calls when it runs in its own network
+    /// namespace (`observe.net: log`).
+    pub observe_net: bool,
+    /// Size of the overlay root's `/tmp` tmpfs in MiB (`limits.tmp_mb`);
+    /// 0 means [`DEFAULT_TMP_MB`].
+    pub tmp_mb: u64,
+    /// Inode cap of that tmpfs (`limits.tmp_inodes`); 0 means
+    /// [`DEFAULT_TMP_INODES`].
+    pub tmp_inodes: u64,
+    /// Interpreter for the command (`exec.shell`); `None` means
+    /// [`shell::Shell::detect`].
+    pub shell: Option<shell::Shell>,
+    /// Attach the caller's terminal through a PTY (`exec --interactive`,
+    /// Linux native only); stdout and stderr arrive merged as stdout.
+    pub tty: bool,
+    /// Run the command on a PTY of its own without attaching the caller's
+    /// terminal (`pty: true`, Linux native only, see [`pty`]); stdout and
+    /// stderr arrive merged as stdout.
+    pub pty: bool,
+    /// SIGTERM-to-SIGKILL delay on timeout (`limits.kill_grace_ms`); see
+    /// [`terminate`].
+    pub kill_grace_ms: u64,
+    /// Clock that `wall_sec` and `kill_grace_ms` are measured against.
+    pub clock: Clock,
+    /// Egress proxy (`capabilities.net.proxy: on`, see [`egress`]), handed
+    /// to the command in the proxy variables and listening on the loopback
+    /// of its network namespace.
+    pub proxy: Option<egress::ProxyAttach>,
+    /// Stub resolver for the command's network namespace
+    /// (`capabilities.net.dns.allow`, see [`dns`]); left out when the
+    /// command has none of its own.
+    pub dns: Option<dns::DnsAttach>,
+    /// Core dump size limit in MiB (`debug.core_mb`); 0 leaves the limit
+    /// as inherited and keeps no cores.
+    pub core_mb: u64,
+    /// Where cores of a crashed command are kept (see [`coredump`]).
+    pub core_dir: Option<std::path::PathBuf>,
+    /// Record stdout and stderr as they interleave (`observe.output:
+    /// timeline`, see [`timeline`]).
+    pub observe_output: bool,
+    /// Where a timeline over the inline cap is kept.
+    pub timeline_dir: Option<std::path::PathBuf>,
+    /// List the environment the command starts with (`observe.env:
+    /// names`, see [`environ`]).
+    pub observe_env: bool,
+    /// Names whose values that list shows (`observe.env_values`).
+    pub env_values: Vec<String>,
+    /// Start the command with [`environ::DETERMINISTIC`] (`magicrune
+    /// repro`).
+    pub deterministic: bool,
+}
+
+pub const DEFAULT_TMP_MB: u64 = 64;
+pub const DEFAULT_TMP_INODES: u64 = 16384;
+
+/// Mount options for the overlay root's `/tmp`. Zero never reaches the
+/// kernel, where it would mean "unlimited".
+pub fn tmpfs_options(tmp_mb: u64, tmp_inodes: u64) -> String {
+    let size = if tmp_mb == 0 { DEFAULT_TMP_MB } else { tmp_mb };
+    let inodes = if tmp_inodes == 0 {
+        DEFAULT_TMP_INODES
+    } else {
+        tmp_inodes
+    };
+    format!("size={}m,nr_inodes={},mode=1777", size, inodes)
+}
+
+/// The kernel refused the `/tmp` tmpfs options. Unlike other overlay
+/// failures this does not fall back: the command would otherwise run
+/// without the size limits the policy asked for.
+#[derive(Debug, thiserror::Error)]
+#[error("tmpfs options {options:?} rejected: {errno}")]
+pub struct TmpfsRejected {
+    pub options: String,
+    pub errno: String,
+}
+
+/// Pseudo-devices every sandbox gets; anything else under `/dev` must be
+/// allowed by the policy.
+pub const STANDARD_DEVICES: &[&str] = &[
+    "/dev/null",
+    "/dev/zero",
+    "/dev/full",
+    "/dev/random",
+    "/dev/urandom",
+    "/dev/tty",
+    "/dev/stdin",
+    "/dev/stdout",
+    "/dev/stderr",
+    "/dev/fd/*",
+];
+
+/// Host paths to bind read-only for `patterns`: exact paths and `/**`
+/// subtrees that exist on this host. Other wildcards cannot name a mount and
+/// are skipped.
+pub fn readonly_mounts(patterns: &[String]) -> Vec<std::path::PathBuf> {
+    let mut out: Vec<std::path::PathBuf> = patterns
+        .iter()
+        .map(|p| p.strip_suffix("/**").unwrap_or(p))
+        .filter(|p| p.starts_with('/') && !p.contains('*') && !p.contains(".."))
+        .map(std::path::PathBuf::from)
+        .filter(|p| p.exists() && p.parent().is_some())
+        .collect();
+    out.sort();
+    out.dedup();
+    out
+}
+
+/// What the overlay root is built from, resolved before fork so the child
+/// only mounts what is listed here.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OverlayPlan {
+    pub devices: Vec<std::path::PathBuf>,
+    pub readonly: Vec<std::path::PathBuf>,
+    /// Options for the `/tmp` tmpfs (see [`tmpfs_options`]).
+    pub tmp_options: String,
+    /// Socket the child sends its scratch dirs to (see [`manifest`]).
+    pub report_fd: Option<i32>,
+}
+
+impl OverlayPlan {
+    pub fn for_spec(spec: &SandboxSpec) -> Self {
+        Self {
+            devices: expand_devices(&spec.devices),
+            readonly: readonly_mounts(&spec.readonly),
+            tmp_options: tmpfs_options(spec.tmp_mb, spec.tmp_inodes),
+            report_fd: None,
+        }
+    }
+}
+
+/// Existing device nodes matching `patterns` (see `crate::policy::pat_matches`),
+/// plus the standard pseudo-devices that exist on this host.
+pub fn expand_devices(patterns: &[String]) -> Vec<std::path::PathBuf> {
+    use crate::policy::pat_matches;
+    let mut out = Vec::new();
+    let mut push = |p: std::path::PathBuf| {
+        if !out.contains(&p) {
+            out.push(p);
+        }
+    };
+    for d in STANDARD_DEVICES.iter().filter(|d| !d.contains('*')) {
+        let p = std::path::Path::new(d);
+        // The stdio links point into /proc; they are recreated as symlinks.
+        if p.exists() && !d.starts_with("/dev/std") {
+            push(p.to_path_buf());
+        }
+    }
+    for pat in patterns {
+        if !pat.contains('*') {
+            let p = std::path::Path::new(pat);
+            if p.starts_with("/dev/") && p.exists() {
+                push(p.to_path_buf());
+            }
+            continue;
+        }
+        // Only top-level wildcards (`/dev/nvidia*`) and subtrees (`/dev/dri/**`).
+        let Some((dir, _)) = pat.rsplit_once('/') else {
+            continue;
+        };
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            continue;
+        };
+        let mut found: Vec<_> = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| pat_matches(&p.to_string_lossy(), pat))
+            .collect();
+        found.sort();
+        found.into_iter().for_each(&mut push);
+    }
+    out
+}
+
+/// Why the sandboxed child stopped running.
+///
+/// `exit_code` on [`SandboxOutcome`] is always the child's own status; policy
+/// decisions (timeouts, limits) are reported here instead of being folded into
+/// the exit code.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind", content = "detail")]
+pub enum TerminationReason {
+    /// The child exited on its own (any exit code).
+    Completed,
+    /// The wall-clock deadline elapsed and the child was killed.
+    WallTimeout,
+    /// The child exceeded its CPU time rlimit (SIGXCPU).
+    CpuLimit,
+    /// The child was stopped for exceeding its memory limit.
+    MemoryLimit,
+    /// The child was terminated by the given signal.
+    Signalled(i32),
+    /// The child could not be started.
+    SpawnError(String),
 }
 
 pub struct SandboxOutcome {
     pub exit_code: i32,
     pub stdout: Vec<u8>,
     pub stderr: Vec<u8>,
+    pub reason: TerminationReason,
+    /// Activity observed during the run (seccomp denials, ...).
+    pub findings: Vec<crate::schema::Finding>,
+    /// Syscall counts when `SandboxSpec::observe_syscalls` was honoured.
+    pub syscalls: Option<crate::schema::SyscallSummary>,
+    /// Connections attempted from the network namespace, when logged.
+    pub net_log: Vec<crate::schema::NetConnection>,
+    /// Files written under the overlay root (see [`manifest`]).
+    pub manifest: Vec<crate::schema::FileChange>,
+    /// Most processes seen alive at once, sampled while waiting.
+    pub pids_peak: Option<u32>,
+    /// Orphans reaped by the namespace's init (see [`init`]).
+    pub orphans_reaped: Option<u32>,
+    /// I/O and CPU sampled while waiting (see [`usage`]).
+    pub usage: Option<crate::schema::Usage>,
+    /// Core dumps kept after a crash (see [`coredump`]).
+    pub core_dumps: Vec<crate::schema::CoreDump>,
+    /// Stdout and stderr as they interleaved, when recorded.
+    pub output_timeline: Option<crate::schema::OutputTimeline>,
+    /// The environment the command started with, when listed.
+    pub env: Option<crate::schema::EnvSnapshot>,
 }
 
 impl SandboxOutcome {
@@ -23,18 +271,119 @@ impl SandboxOutcome {
             exit_code: 0,
             stdout: Vec::new(),
             stderr: Vec::new(),
+            reason: TerminationReason::Completed,
+            findings: Vec::new(),
+            syscalls: None,
+            net_log: Vec::new(),
+            manifest: Vec::new(),
+            pids_peak: None,
+            orphans_reaped: None,
+            usage: None,
+            core_dumps: Vec::new(),
+            output_timeline: None,
+            env: None,
         }
     }
+
+    /// Outcome for a child that never started.
+    pub fn spawn_error(msg: impl Into<String>) -> Self {
+        Self {
+            exit_code: SPAWN_ERROR_EXIT,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            reason: TerminationReason::SpawnError(msg.into()),
+            findings: Vec::new(),
+            syscalls: None,
+            net_log: Vec::new(),
+            manifest: Vec::new(),
+            pids_peak: None,
+            orphans_reaped: None,
+            usage: None,
+            core_dumps: Vec::new(),
+            output_timeline: None,
+            env: None,
+        }
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.reason == TerminationReason::WallTimeout
+    }
 }
 
+/// Stop a child that ran out of time: SIGTERM, then SIGKILL once `grace`
+/// has passed without it exiting. With no grace, or where signals other
+/// than SIGKILL cannot be sent, it is killed at once.
+///
+/// In a PID namespace the child is the namespace's [`init`], which passes
+/// SIGTERM on to the command.
+pub async fn terminate(
+    child: &mut std::process::Child,
+    grace: Duration,
+    clock: &Clock,
+) -> std::io::Result<std::process::ExitStatus> {
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    if !grace.is_zero() {
+        use nix::sys::signal::{kill, Signal};
+        use nix::unistd::Pid;
+        if kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM).is_ok() {
+            let started = clock.now_millis();
+            while clock.since(started) < grace {
+                if let Some(status) = child.try_wait()? {
+                    return Ok(status);
+                }
+                tokio::time::sleep(Duration::from_millis(25)).await;
+            }
+        }
+    }
+    #[cfg(not(all(target_os = "linux", feature = "linux_native")))]
+    let _ = (grace, clock);
+    let _ = child.kill();
+    child.wait()
+}
+
+/// Exit code reported when the child could not be spawned (shell convention).
+pub const SPAWN_ERROR_EXIT: i32 = 127;
+
+/// Map a child's exit status to (exit_code, reason). Signals follow the shell
+/// convention of `128 + signo` for the exit code.
+pub fn classify_exit(status: &std::process::ExitStatus) -> (i32, TerminationReason) {
+    if let Some(code) = status.code() {
+        return (code, TerminationReason::Completed);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(sig) = status.signal() {
+            let reason = if sig == SIGXCPU {
+                TerminationReason::CpuLimit
+            } else {
+                TerminationReason::Signalled(sig)
+            };
+            return (128 + sig, reason);
+        }
+    }
+    (1, TerminationReason::Completed)
+}
+
+#[cfg(unix)]
+const SIGXCPU: i32 = 24;
+
 /// Detect which sandbox to use at runtime.
 /// Defaults to WASI unless running on Linux with the optional `linux_native` feature enabled.
 /// If the env `MAGICRUNE_FORCE_WASM=1` is set, always selects WASI.
 pub fn detect_sandbox() -> SandboxKind {
-    if std::env::var("MAGICRUNE_FORCE_WASM").ok().as_deref() == Some("1") {
+    if crate::ports::env::var("MAGICRUNE_FORCE_WASM")
+        .ok()
+        .as_deref()
+        == Some("1")
+    {
         return SandboxKind::Wasi;
     }
+    default_sandbox()
+}
 
+/// The sandbox this build prefers, ignoring the environment.
+pub fn default_sandbox() -> SandboxKind {
     #[cfg(all(target_os = "linux", feature = "linux_native"))]
     {
         return SandboxKind::Linux;
@@ -45,15 +394,51 @@ pub fn detect_sandbox() -> SandboxKind {
     SandboxKind::Wasi
 }
 
+/// Optional hardening layers for the native sandbox (best-effort, Linux only).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Hardening {
+    /// Read-only overlay root with tmpfs /tmp (`MAGICRUNE_OVERLAY_RO`).
+    pub overlay_ro: bool,
+    /// Minimal seccomp allow-list (`MAGICRUNE_SECCOMP`).
+    pub seccomp: bool,
+    /// Extra syscalls for the seccomp allow-list (`MAGICRUNE_SECCOMP_LOOSEN`).
+    pub seccomp_loosen: bool,
+    /// Log syscalls outside the allow-list instead of refusing them
+    /// (`MAGICRUNE_SECCOMP_LEARN`, `exec --seccomp-learn`).
+    pub seccomp_learn: bool,
+}
+
+impl Hardening {
+    pub fn from_env() -> Self {
+        let on = |k: &str| crate::ports::env::var(k).ok().as_deref() == Some("1");
+        Self {
+            overlay_ro: on("MAGICRUNE_OVERLAY_RO"),
+            seccomp: on("MAGICRUNE_SECCOMP"),
+            seccomp_loosen: on("MAGICRUNE_SECCOMP_LOOSEN"),
+            seccomp_learn: on("MAGICRUNE_SECCOMP_LEARN"),
+        }
+    }
+}
+
 // Placeholders for native/wasm sandbox backends (wired in CI later)
 pub async fn exec_native(cmd: &str, stdin: &[u8], spec: &SandboxSpec) -> SandboxOutcome {
+    exec_native_with(cmd, stdin, spec, Hardening::from_env()).await
+}
+
+/// Like [`exec_native`], with hardening given explicitly instead of read from env.
+pub async fn exec_native_with(
+    cmd: &str,
+    stdin: &[u8],
+    spec: &SandboxSpec,
+    hardening: Hardening,
+) -> SandboxOutcome {
     #[cfg(all(target_os = "linux", feature = "linux_native"))]
     {
-        if let Some(out) = linux_try_exec(cmd, stdin, spec).await {
+        if let Some(out) = linux_try_exec(cmd, stdin, spec, hardening).await {
             return out;
         }
     }
-    simple_exec_with_timeout(cmd, stdin, spec).await
+    simple_exec_with_timeout(cmd, stdin, spec, hardening, false).await
 }
 
 pub async fn exec_wasm(_wasm_bytes: &[u8], _spec: &SandboxSpec) -> SandboxOutcome {
@@ -62,12 +447,18 @@ pub async fn exec_wasm(_wasm_bytes: &[u8], _spec: &SandboxSpec) -> SandboxOutcom
 }
 
 #[cfg(all(target_os = "linux", feature = "native_sandbox"))]
-fn seccomp_minimal_allow() -> Result<(), String> {
+fn seccomp_minimal_allow(loosen: bool, learn: bool, extra: &[String]) -> Result<(), String> {
     use libseccomp::*;
     // Note: ScmpError is not available in libseccomp v0.3, using String for errors
-    // Default deny
-    let mut filter =
-        ScmpFilterContext::new_filter(ScmpAction::Errno(1)).map_err(|e| format!("{:?}", e))?;
+    // Default deny; in learn mode, default log (see `audit`).
+    let default = if learn {
+        ScmpAction::Log
+    } else {
+        ScmpAction::Errno(1)
+    };
+    let mut filter = ScmpFilterContext::new_filter(default).map_err(|e| format!("{:?}", e))?;
+    // Have the kernel audit every refused syscall (see `audit`).
+    filter.set_ctl_log(true).map_err(|e| format!("{:?}", e))?;
     let arch = get_api();
     let _ = arch; // touch API to satisfy MSRV lint
     let allow = |f: &mut ScmpFilterContext, sys: ScmpSyscall| -> Result<(), String> {
@@ -103,7 +494,6 @@ fn seccomp_minimal_allow() -> Result<(), String> {
             .unwrap_or_else(|_| ScmpSyscall::from_name("readlink").unwrap()),
     ];
     // getrandom は緩和時に確実に許可
-    let loosen = std::env::var("MAGICRUNE_SECCOMP_LOOSEN").ok().as_deref() == Some("1");
     if loosen {
         for name in ["getrandom", "prlimit64", "setrlimit", "clone3"].iter() {
             if let Ok(sys) = ScmpSyscall::from_name(name) {
@@ -116,6 +506,12 @@ fn seccomp_minimal_allow() -> Result<(), String> {
     } else if let Ok(sys) = ScmpSyscall::from_name("getrandom") {
         list.push(sys);
     }
+    for name in extra {
+        match ScmpSyscall::from_name(name) {
+            Ok(sys) => list.push(sys),
+            Err(_) => eprintln!("[seccomp] WARN: unknown syscall {:?} in policy", name),
+        }
+    }
     for s in list.into_iter() {
         allow(&mut filter, s).map_err(|e| format!("{:?}", e))?;
     }
@@ -123,20 +519,29 @@ fn seccomp_minimal_allow() -> Result<(), String> {
     Ok(())
 }
 
+#[cfg(all(target_os = "linux", feature = "native_sandbox"))]
+fn syscall_name(nr: i64) -> String {
+    libseccomp::ScmpSyscall::from(nr as i32)
+        .get_name()
+        .unwrap_or_else(|_| format!("syscall_{}", nr))
+}
+
+#[cfg(not(all(target_os = "linux", feature = "native_sandbox")))]
+fn syscall_name(nr: i64) -> String {
+    format!("syscall_{}", nr)
+}
+
 #[cfg(not(all(target_os = "linux", feature = "native_sandbox")))]
 #[allow(dead_code)]
-fn seccomp_minimal_allow() -> Result<(), String> {
+fn seccomp_minimal_allow(_loosen: bool, _learn: bool, _extra: &[String]) -> Result<(), String> {
     Err("seccomp not supported in this build".into())
 }
 
 // OverlayFS(ro) + tmpfs:/tmp (best-effort). Returns guard on success.
 #[cfg(all(target_os = "linux", feature = "linux_native"))]
-fn try_enable_overlay_ro() -> anyhow::Result<Option<OverlayGuard>> {
+fn try_enable_overlay_ro(plan: &OverlayPlan) -> anyhow::Result<Option<OverlayGuard>> {
     use nix::{mount, mount::MsFlags, sched::unshare, unistd};
     use std::{fs, path::PathBuf};
-    if std::env::var("MAGICRUNE_OVERLAY_RO").ok().as_deref() != Some("1") {
-        return Ok(None);
-    }
     // 1) new mount namespace
     unshare(nix::sched::CloneFlags::CLONE_NEWNS)
         .map_err(|e| anyhow::anyhow!("unshare(CLONE_NEWNS) failed: {e}"))?;
@@ -151,7 +556,7 @@ fn try_enable_overlay_ro() -> anyhow::Result<Option<OverlayGuard>> {
     .map_err(|e| anyhow::anyhow!("make-rprivate failed: {e}"))?;
     // 3) scratch
     let pid = std::process::id();
-    let scratch = PathBuf::from(format!("/tmp/mr_ovl_{pid}"));
+    let scratch = PathBuf::from(format!("/tmp/{}{pid}", crate::gc::SCRATCH_PREFIX));
     let lower = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("/"));
     let upper = scratch.join("upper");
     let work = scratch.join("work");
@@ -167,9 +572,15 @@ fn try_enable_overlay_ro() -> anyhow::Result<Option<OverlayGuard>> {
         tmp_in_root.as_path(),
         Some("tmpfs"),
         MsFlags::empty(),
-        Some("size=64m,mode=1777"),
+        Some(plan.tmp_options.as_str()),
     )
-    .map_err(|e| anyhow::anyhow!("mount tmpfs failed: {e}"))?;
+    .map_err(|e| match e {
+        nix::errno::Errno::EINVAL => anyhow::Error::new(TmpfsRejected {
+            options: plan.tmp_options.clone(),
+            errno: e.to_string(),
+        }),
+        e => anyhow::anyhow!("mount tmpfs failed: {e}"),
+    })?;
     // 5) overlay mount
     let opts = format!(
         "lowerdir={},upperdir={},workdir={}",
@@ -185,6 +596,11 @@ fn try_enable_overlay_ro() -> anyhow::Result<Option<OverlayGuard>> {
         Some(opts.as_str()),
     )
     .map_err(|e| anyhow::anyhow!("mount overlay failed: {e}"))?;
+    if let Some(sock) = plan.report_fd {
+        if let Err(e) = manifest::send_dirs(sock, &upper, &tmp_in_root) {
+            eprintln!("[overlay-ro] WARN: manifest unavailable: {}", e);
+        }
+    }
     // 6) minimal fs inside root
     let proc_path = root.join("proc");
     fs::create_dir_all(&proc_path).ok();
@@ -206,6 +622,82 @@ fn try_enable_overlay_ro() -> anyhow::Result<Option<OverlayGuard>> {
         Option::<&str>::None,
     )
     .map_err(|e| anyhow::anyhow!("bind tmp into overlay root failed: {e}"))?;
+    // 7) /dev: a fresh tmpfs holding only the allowed device nodes
+    let root_dev = root.join("dev");
+    fs::create_dir_all(&root_dev)?;
+    mount::mount(
+        Some("tmpfs"),
+        root_dev.as_path(),
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+        Some("size=64k,mode=755"),
+    )
+    .map_err(|e| anyhow::anyhow!("mount tmpfs /dev failed: {e}"))?;
+    for dev in &plan.devices {
+        let Ok(rel) = dev.strip_prefix("/dev") else {
+            continue;
+        };
+        let target = root_dev.join(rel);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).ok();
+        }
+        if dev.is_dir() {
+            fs::create_dir_all(&target).ok();
+        } else if fs::File::create(&target).is_err() {
+            continue;
+        }
+        if let Err(e) = mount::mount(
+            Some(dev.as_path()),
+            target.as_path(),
+            Option::<&str>::None,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            Option::<&str>::None,
+        ) {
+            eprintln!(
+                "[overlay-ro] WARN: device {} not bound: {}",
+                dev.display(),
+                e
+            );
+        }
+    }
+    for (name, fd) in [("stdin", 0), ("stdout", 1), ("stderr", 2)] {
+        let _ = std::os::unix::fs::symlink(format!("/proc/self/fd/{fd}"), root_dev.join(name));
+    }
+    let _ = std::os::unix::fs::symlink("/proc/self/fd", root_dev.join("fd"));
+    // 7b) capabilities.fs.readonly: host paths bound read-only at the same place
+    for src in &plan.readonly {
+        let Ok(rel) = src.strip_prefix("/") else {
+            continue;
+        };
+        let target = root.join(rel);
+        if src.is_dir() {
+            fs::create_dir_all(&target)?;
+        } else {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if !target.exists() {
+                fs::File::create(&target)?;
+            }
+        }
+        mount::mount(
+            Some(src.as_path()),
+            target.as_path(),
+            Option::<&str>::None,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            Option::<&str>::None,
+        )
+        .map_err(|e| anyhow::anyhow!("bind {} failed: {e}", src.display()))?;
+        // A bind mount keeps the source's flags until remounted.
+        mount::mount(
+            Option::<&str>::None,
+            target.as_path(),
+            Option::<&str>::None,
+            MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY | MsFlags::MS_REC,
+            Option::<&str>::None,
+        )
+        .map_err(|e| anyhow::anyhow!("remount {} read-only failed: {e}", src.display()))?;
+    }
     // 8) pivot_root (best-effort), fallback to chroot
     let put_old = root.join(".old_root");
     std::fs::create_dir_all(&put_old).ok();
@@ -241,7 +733,7 @@ fn try_enable_overlay_ro() -> anyhow::Result<Option<OverlayGuard>> {
 
 #[cfg(not(all(target_os = "linux", feature = "linux_native")))]
 #[allow(dead_code)]
-fn try_enable_overlay_ro() -> anyhow::Result<Option<()>> {
+fn try_enable_overlay_ro(_plan: &OverlayPlan) -> anyhow::Result<Option<()>> {
     Ok(None)
 }
 
@@ -292,15 +784,66 @@ pub mod wasm_impl {
     }
 }
 
+use crate::ports::Clock;
 use std::process::{Command, Stdio};
-use std::time::{Duration, Instant};
+use std::time::Duration;
+
+/// Working directory of the command, and the lower layer of the overlay root.
+const WORKDIR: &str = "/tmp";
+
+fn piped() -> (Stdio, Stdio, Stdio) {
+    (Stdio::piped(), Stdio::piped(), Stdio::piped())
+}
 
-async fn simple_exec_with_timeout(cmd: &str, stdin: &[u8], spec: &SandboxSpec) -> SandboxOutcome {
-    let mut command = Command::new("bash");
+async fn simple_exec_with_timeout(
+    cmd: &str,
+    stdin: &[u8],
+    spec: &SandboxSpec,
+    hardening: Hardening,
+    netns: bool,
+) -> SandboxOutcome {
+    #[cfg(not(all(target_os = "linux", feature = "linux_native")))]
+    let _ = hardening;
+    // Our end of the manifest socket and, until spawn, the child's.
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    let mut reaper = init::Reaper::new()
+        .map_err(|e| tracing::warn!(error = %e, "pid namespace: no init"))
+        .ok();
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    let mut report: Option<(
+        std::os::unix::net::UnixDatagram,
+        Option<std::os::unix::net::UnixDatagram>,
+    )> = None;
+    // strace's summary table would land on the terminal.
+    let mut tracer = trace::Tracer::new(
+        spec.observe_syscalls && !spec.tty && !spec.pty,
+        spec.observe_net && netns,
+        hardening.seccomp,
+    );
+    let argv = shell::Shell::resolve(spec.shell).argv(cmd);
+    let Some((program, args)) = argv.split_first() else {
+        return SandboxOutcome::spawn_error("empty command");
+    };
+    let mut command = match &tracer {
+        Some(t) => {
+            let mut c = t.command();
+            c.arg(program);
+            c
+        }
+        None => Command::new(program),
+    };
     // Constrain working directory and env to /tmp
-    command.current_dir("/tmp");
-    command.env("HOME", "/tmp");
-    command.env("TMPDIR", "/tmp");
+    command.current_dir(WORKDIR);
+    let overrides = environ::overrides(spec);
+    for (var, value) in &overrides {
+        command.env(var, value);
+    }
+    let env = spec.observe_env.then(|| {
+        environ::snapshot(
+            &environ::presented(environ::host(), &overrides),
+            &spec.env_values,
+        )
+    });
     // Apply POSIX-style rlimits and optional Linux features only when the
     // linux_native feature is enabled on Linux.
     #[cfg(all(target_os = "linux", feature = "linux_native"))]
@@ -313,18 +856,48 @@ async fn simple_exec_with_timeout(cmd: &str, stdin: &[u8], spec: &SandboxSpec) -
         let cpu_ms = spec.cpu_ms;
         let memory_mb = spec.memory_mb;
         let pids = spec.pids;
+        let core_mb = spec.core_mb;
+        let tty = spec.tty || spec.pty;
+        let report_fd = reaper.as_ref().map_or(-1, init::Reaper::report_fd);
+        #[cfg(feature = "native_sandbox")]
+        let syscalls = spec.syscalls.clone();
+        let mut plan = if hardening.overlay_ro {
+            OverlayPlan::for_spec(spec)
+        } else {
+            OverlayPlan::default()
+        };
+        if hardening.overlay_ro {
+            use std::os::fd::AsRawFd;
+            match std::os::unix::net::UnixDatagram::pair() {
+                Ok((ours, theirs)) => {
+                    plan.report_fd = Some(theirs.as_raw_fd());
+                    report = Some((ours, Some(theirs)));
+                }
+                Err(e) => tracing::warn!(error = %e, "overlay-ro: manifest unavailable"),
+            }
+        }
 
+        // Runs in the forked child: messages from here go to the command's
+        // own stderr, not through tracing.
         let _ = unsafe {
             command.pre_exec(move || {
+                init::become_init(report_fd)?;
+                if tty {
+                    pty::become_session_leader()?;
+                }
                 // Optional overlayfs(ro) + tmpfs:/tmp (best-effort)
                 #[cfg(all(target_os = "linux", feature = "linux_native"))]
                 {
-                    if std::env::var("MAGICRUNE_OVERLAY_RO").ok().as_deref() == Some("1") {
-                        match try_enable_overlay_ro() {
+                    if hardening.overlay_ro {
+                        match try_enable_overlay_ro(&plan) {
                             Ok(Some(_g)) => {
                                 eprintln!("[overlay-ro] enabled (overlay root ro + tmpfs:/tmp)");
                             }
                             Ok(None) => { /* gate off; do nothing */ }
+                            Err(e) if e.is::<TmpfsRejected>() => {
+                                eprintln!("[overlay-ro] WARN: {}; refusing to run", e);
+                                return Err(std::io::Error::other(e.to_string()));
+                            }
                             Err(e) => {
                                 eprintln!("[overlay-ro] WARN: enable failed, fallback: {}", e);
                             }
@@ -345,11 +918,19 @@ async fn simple_exec_with_timeout(cmd: &str, stdin: &[u8], spec: &SandboxSpec) -
                 if pids > 0 {
                     let _ = setrlimit(Resource::RLIMIT_NPROC, pids, pids);
                 }
+                // Core dumps (bytes)
+                if core_mb > 0 {
+                    let _ = setrlimit(Resource::RLIMIT_CORE, core_mb << 20, core_mb << 20);
+                }
                 // Optional seccomp enable (best-effort) when feature/native and env toggled
                 #[cfg(all(target_os = "linux", feature = "native_sandbox"))]
                 {
-                    if std::env::var("MAGICRUNE_SECCOMP").ok().as_deref() == Some("1") {
-                        if let Err(e) = seccomp_minimal_allow() {
+                    if hardening.seccomp {
+                        if let Err(e) = seccomp_minimal_allow(
+                            hardening.seccomp_loosen,
+                            hardening.seccomp_learn,
+                            &syscalls,
+                        ) {
                             eprintln!("WARN: seccomp enable failed: {} (fallback)", e);
                         }
                     }
@@ -361,7 +942,7 @@ async fn simple_exec_with_timeout(cmd: &str, stdin: &[u8], spec: &SandboxSpec) -
         // TODO: cgroups module is not implemented yet
         /*
         #[cfg(all(target_os = "linux", feature = "linux_native"))]
-        if std::env::var("MAGICRUNE_CGROUPS").ok().as_deref() == Some("1") {
+        if crate::ports::env::var("MAGICRUNE_CGROUPS").ok().as_deref() == Some("1") {
             match crate::sandbox::cgroups::try_enable_cgroups(
                 spec.cpu_ms,
                 spec.memory_mb,
@@ -374,16 +955,121 @@ async fn simple_exec_with_timeout(cmd: &str, stdin: &[u8], spec: &SandboxSpec) -
         }
         */
     }
+    // Seccomp denials are read back from the audit trail (see `audit`).
+    let mut audit_tail = if hardening.seccomp && cfg!(feature = "native_sandbox") {
+        audit::AuditTail::open()
+    } else {
+        None
+    };
+    if hardening.seccomp_learn && audit_tail.is_none() {
+        tracing::warn!("seccomp: audit log unreadable; learn mode records nothing");
+    }
+    let kind = if hardening.seccomp_learn {
+        audit::SECCOMP_LOGGED
+    } else {
+        audit::SECCOMP_DENIED
+    };
+    let mut run_pids = std::collections::HashSet::new();
+    // With a terminal attached the command talks to the PTY, not to pipes.
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    let opened = if spec.tty {
+        Some(pty::Pty::open())
+    } else {
+        spec.pty.then(pty::Pty::open_captured)
+    };
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    let (pty, (child_in, child_out, child_err)) = match opened
+        .map(|p| p.and_then(|p| Ok((p.stdio()?, p))))
+        .transpose()
+    {
+        Ok(Some((stdio, p))) => (Some(p), stdio),
+        Ok(None) => (None, piped()),
+        Err(e) => return SandboxOutcome::spawn_error(e.to_string()),
+    };
+    #[cfg(not(all(target_os = "linux", feature = "linux_native")))]
+    let (child_in, child_out, child_err) = piped();
+    #[cfg(not(all(target_os = "linux", feature = "linux_native")))]
+    if spec.pty {
+        tracing::warn!("pty: true needs the Linux native sandbox; output is piped");
+    }
+    let spawned_at = std::time::SystemTime::now();
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    let spawned = std::time::Instant::now();
     let mut child = match command
-        .arg("-lc")
-        .arg(cmd)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
+        .args(args)
+        .stdin(child_in)
+        .stdout(child_out)
+        .stderr(child_err)
         .spawn()
     {
         Ok(c) => c,
-        Err(_) => return SandboxOutcome::empty(),
+        Err(e) => return SandboxOutcome::spawn_error(e.to_string()),
+    };
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    if let Some(r) = reaper.as_mut() {
+        r.spawned();
+    }
+    // The command's wait status and orphan count, from its init when it
+    // had one.
+    let reaped = move |status: std::process::ExitStatus| {
+        #[cfg(all(target_os = "linux", feature = "linux_native"))]
+        if let Some(r) = reaper.and_then(init::Reaper::finish) {
+            use std::os::unix::process::ExitStatusExt;
+            return (
+                std::process::ExitStatus::from_raw(r.status),
+                Some(r.orphans_reaped),
+            );
+        }
+        (status, None)
+    };
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    let mut relay = pty.map(|p| {
+        if spec.tty {
+            p.relay()
+        } else {
+            p.capture(stdin)
+        }
+    });
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    let _raw = spec.tty.then(pty::RawMode::enable);
+    // Both pipes are read from the wait loop, which sees their order.
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    let mut capture = if spec.observe_output && relay.is_none() {
+        child
+            .stdout
+            .take()
+            .zip(child.stderr.take())
+            .map(|(out, err)| timeline::Capture::new(out, err, spawned))
+    } else {
+        None
+    };
+    #[cfg(not(all(target_os = "linux", feature = "linux_native")))]
+    if spec.observe_output {
+        tracing::warn!("observe.output: timeline needs the Linux native sandbox; not recorded");
+    }
+    if let Some(t) = tracer.as_mut() {
+        t.started();
+    }
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    let scratch = report.as_mut().and_then(|(ours, theirs)| {
+        // The child has exec'd, so anything it sent is queued.
+        theirs.take();
+        manifest::recv_dirs(ours)
+    });
+    // The command's working directory as seen from here.
+    let workdir = || {
+        #[cfg(all(target_os = "linux", feature = "linux_native"))]
+        if let Some(dirs) = &scratch {
+            return dirs.tmp();
+        }
+        std::path::PathBuf::from(WORKDIR)
+    };
+    let scan = || {
+        #[cfg(all(target_os = "linux", feature = "linux_native"))]
+        if let Some(dirs) = &scratch {
+            return dirs.scan(std::path::Path::new(WORKDIR));
+        }
+        Vec::new()
     };
     if !stdin.is_empty() {
         use std::io::Write as _;
@@ -391,34 +1077,199 @@ async fn simple_exec_with_timeout(cmd: &str, stdin: &[u8], spec: &SandboxSpec) -
             let _ = sin.write_all(stdin);
         }
     }
-    let start = Instant::now();
-    let deadline = start + Duration::from_secs(spec.wall_sec);
+    let start = spec.clock.now_millis();
+    let wall = Duration::from_secs(spec.wall_sec);
+    let mut sampler = usage::Sampler::default();
+    let oom_events = oom::OomEvents::start();
+    // `RLIMIT_AS` is only set with the native features.
+    let enforced_memory_mb = if cfg!(all(target_os = "linux", feature = "linux_native")) {
+        spec.memory_mb
+    } else {
+        0
+    };
     loop {
+        if audit_tail.is_some() {
+            audit::collect_tree(child.id(), &mut run_pids);
+        }
+        sampler.poll(child.id());
         if let Ok(Some(_st)) = child.try_wait() {
-            let out = match child.wait_with_output() {
+            #[allow(unused_mut)]
+            let mut out = match child.wait_with_output() {
                 Ok(o) => o,
-                Err(_) => return SandboxOutcome::empty(),
+                Err(e) => return SandboxOutcome::spawn_error(e.to_string()),
             };
+            #[allow(unused_mut)]
+            let mut output_timeline = None;
+            #[cfg(all(target_os = "linux", feature = "linux_native"))]
+            if let Some(c) = capture.take() {
+                let t = c.finish();
+                out.stdout = t.bytes(timeline::Stream::Stdout);
+                out.stderr = t.bytes(timeline::Stream::Stderr);
+                output_timeline = Some(t.keep(spec.timeline_dir.as_deref()));
+            }
+            let (status, orphans_reaped) = reaped(out.status);
+            let (exit_code, mut reason) = classify_exit(&status);
+            let (stderr, summary, net_log) = match tracer {
+                Some(t) => t.finish(out.stderr),
+                None => (out.stderr, None, Vec::new()),
+            };
+            let mut findings = seccomp_findings(audit_tail.as_mut(), &run_pids, kind);
+            let core_dumps = match &spec.core_dir {
+                Some(into) if spec.core_mb > 0 && coredump::crashed(&reason, exit_code) => {
+                    coredump::collect(&workdir(), spawned_at, into)
+                }
+                _ => Vec::new(),
+            };
+            if let Some(f) = oom::diagnose(
+                &reason,
+                exit_code,
+                &stderr,
+                enforced_memory_mb,
+                sampler.vm_peak_kb(),
+                oom_events.fired(),
+            ) {
+                reason = TerminationReason::MemoryLimit;
+                findings.push(f);
+            }
+            #[allow(unused_mut)]
+            let mut stdout = out.stdout;
+            #[cfg(all(target_os = "linux", feature = "linux_native"))]
+            if let Some(r) = relay.take() {
+                stdout = r.finish();
+            }
             return SandboxOutcome {
-                exit_code: out.status.code().unwrap_or(1),
-                stdout: out.stdout,
-                stderr: out.stderr,
+                exit_code,
+                stdout,
+                stderr,
+                reason,
+                findings,
+                syscalls: summary,
+                net_log,
+                manifest: scan(),
+                pids_peak: peak(sampler.pids_peak(), orphans_reaped),
+                orphans_reaped,
+                usage: sampler.usage(spec.clock.since(start)),
+                core_dumps,
+                output_timeline,
+                env,
             };
         }
-        if Instant::now() >= deadline {
-            let _ = child.kill();
+        if spec.clock.since(start) >= wall {
+            // Reap the direct child; its exit code reflects the signal that
+            // ended it.
+            let (exit_code, orphans_reaped) = match terminate(
+                &mut child,
+                Duration::from_millis(spec.kill_grace_ms),
+                &spec.clock,
+            )
+            .await
+            {
+                Ok(st) => {
+                    let (st, orphans) = reaped(st);
+                    (classify_exit(&st).0, orphans)
+                }
+                Err(_) => (128 + 9, None),
+            };
+            #[cfg(all(target_os = "linux", feature = "linux_native"))]
+            if let Some(r) = relay.take() {
+                r.finish();
+            }
+            // What a hung command wrote, and in which order.
+            #[allow(unused_mut)]
+            let mut output_timeline = None;
+            #[cfg(all(target_os = "linux", feature = "linux_native"))]
+            if let Some(c) = capture.take() {
+                output_timeline = Some(c.finish().keep(spec.timeline_dir.as_deref()));
+            }
+            // The connection log still explains what a hung command tried.
+            let net_log = tracer.map(|t| t.finish(Vec::new()).2).unwrap_or_default();
             return SandboxOutcome {
-                exit_code: 20,
+                exit_code,
                 stdout: Vec::new(),
                 stderr: b"timeout".to_vec(),
+                reason: TerminationReason::WallTimeout,
+                findings: seccomp_findings(audit_tail.as_mut(), &run_pids, kind),
+                syscalls: None,
+                net_log,
+                manifest: scan(),
+                pids_peak: peak(sampler.pids_peak(), orphans_reaped),
+                orphans_reaped,
+                usage: sampler.usage(spec.clock.since(start)),
+                core_dumps: Vec::new(),
+                output_timeline,
+                env,
             };
         }
-        std::thread::sleep(Duration::from_millis(25));
+        #[cfg(all(target_os = "linux", feature = "linux_native"))]
+        if let Some(r) = relay.as_mut() {
+            r.pump(Duration::from_millis(25));
+            continue;
+        }
+        #[cfg(all(target_os = "linux", feature = "linux_native"))]
+        if let Some(c) = capture.as_mut() {
+            c.pump(Duration::from_millis(25));
+            continue;
+        }
+        tokio::time::sleep(Duration::from_millis(25)).await;
+    }
+}
+
+/// The command's share of `sampled`: the init, when there was one, is not
+/// counted. Only Linux can see the process tree.
+fn peak(sampled: u32, orphans_reaped: Option<u32>) -> Option<u32> {
+    let init = orphans_reaped.is_some() as u32;
+    cfg!(target_os = "linux").then(|| sampled.saturating_sub(init).max(1))
+}
+
+fn seccomp_findings(
+    tail: Option<&mut audit::AuditTail>,
+    pids: &std::collections::HashSet<u32>,
+    kind: &str,
+) -> Vec<crate::schema::Finding> {
+    match tail {
+        Some(tail) => audit::syscall_findings(&tail.read_new(), pids, kind, syscall_name),
+        None => Vec::new(),
+    }
+}
+
+/// Run the command from a thread of its own. `unshare` moves the calling
+/// thread into the new namespaces for good: once in a new PID namespace it
+/// can neither start threads nor spawn a second command, so the caller's
+/// thread (a tokio worker, a test, `magicrune repro`'s loop) must stay out.
+#[cfg(all(target_os = "linux", feature = "linux_native"))]
+async fn linux_try_exec(
+    cmd: &str,
+    stdin: &[u8],
+    spec: &SandboxSpec,
+    hardening: Hardening,
+) -> Option<SandboxOutcome> {
+    let (cmd, stdin, spec) = (cmd.to_string(), stdin.to_vec(), spec.clone());
+    let span = tracing::Span::current();
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .ok()?;
+    let thread = std::thread::Builder::new()
+        .name("magicrune-sandbox".into())
+        .spawn(move || {
+            let _enter = span.enter();
+            rt.block_on(linux_unshare_exec(&cmd, &stdin, &spec, hardening))
+        })
+        .ok()?;
+    match tokio::task::spawn_blocking(move || thread.join()).await {
+        Ok(Ok(out)) => out,
+        Ok(Err(panic)) => std::panic::resume_unwind(panic),
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
     }
 }
 
 #[cfg(all(target_os = "linux", feature = "linux_native"))]
-async fn linux_try_exec(cmd: &str, stdin: &[u8], spec: &SandboxSpec) -> Option<SandboxOutcome> {
+async fn linux_unshare_exec(
+    cmd: &str,
+    stdin: &[u8],
+    spec: &SandboxSpec,
+    hardening: Hardening,
+) -> Option<SandboxOutcome> {
     use nix::sched::{unshare, CloneFlags};
     // Try a stronger isolation first (include NEWNET/NEWUSER when allowed),
     // fall back to a minimal set if kernel/permissions reject.
@@ -439,17 +1290,25 @@ async fn linux_try_exec(cmd: &str, stdin: &[u8], spec: &SandboxSpec) -> Option<S
             | CloneFlags::CLONE_NEWPID
             | CloneFlags::CLONE_NEWNS,
     ];
-    let mut ok = false;
-    for flags in attempts {
-        if unshare(*flags).is_ok() {
-            ok = true;
-            break;
+    let flags = attempts.iter().find(|f| unshare(**f).is_ok())?;
+    let netns = flags.contains(CloneFlags::CLONE_NEWNET);
+    // Sockets belong to the namespace of the thread that makes them, so
+    // the proxy's listener and the resolver's socket are made here, right
+    // after unshare.
+    if let Some(proxy) = spec.proxy.as_ref().filter(|_| netns) {
+        match dns::loopback_up().and_then(|()| std::net::TcpListener::bind(proxy.addr())) {
+            Ok(listener) => proxy.attach(listener),
+            Err(e) => tracing::warn!(error = %e, "egress: no proxy in the network namespace"),
         }
     }
-    if !ok {
-        return None;
+    if let Some(attach) = spec.dns.as_ref().filter(|_| netns) {
+        let conf = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+        match dns::listen_in_netns(dns::nameserver(&conf)) {
+            Ok(sock) => attach.attach(sock),
+            Err(e) => tracing::warn!(error = %e, "dns: no resolver in the network namespace"),
+        }
     }
-    let out = simple_exec_with_timeout(cmd, stdin, spec).await;
+    let out = simple_exec_with_timeout(cmd, stdin, spec, hardening, netns).await;
     Some(out)
 }
 
@@ -482,6 +1341,251 @@ mod tests {
         assert_eq!(outcome.exit_code, 0);
         assert!(outcome.stdout.is_empty());
         assert!(outcome.stderr.is_empty());
+        assert_eq!(outcome.reason, TerminationReason::Completed);
+    }
+
+    #[test]
+    fn test_sandbox_outcome_spawn_error() {
+        let outcome = SandboxOutcome::spawn_error("no such file");
+        assert_eq!(outcome.exit_code, SPAWN_ERROR_EXIT);
+        assert_eq!(
+            outcome.reason,
+            TerminationReason::SpawnError("no such file".to_string())
+        );
+        assert!(!outcome.timed_out());
+    }
+
+    #[tokio::test]
+    async fn test_exec_native_reports_child_exit_verbatim() {
+        let spec = SandboxSpec {
+            wall_sec: 5,
+            cpu_ms: 1000,
+            memory_mb: 64,
+            pids: 10,
+            ..Default::default()
+        };
+        let outcome = exec_native("exit 20", b"", &spec).await;
+        assert_eq!(outcome.exit_code, 20);
+        assert_eq!(outcome.reason, TerminationReason::Completed);
+    }
+
+    #[tokio::test]
+    async fn test_exec_native_wall_timeout() {
+        let spec = SandboxSpec {
+            wall_sec: 0,
+            cpu_ms: 1000,
+            memory_mb: 64,
+            pids: 10,
+            ..Default::default()
+        };
+        let outcome = exec_native("sleep 5", b"", &spec).await;
+        assert!(outcome.timed_out());
+        assert_ne!(outcome.exit_code, 0);
+    }
+
+    #[tokio::test]
+    async fn test_wall_deadline_follows_the_clock() {
+        let time = crate::testing::MockTimePort::new(0);
+        let spec = SandboxSpec {
+            wall_sec: 60,
+            cpu_ms: 1000,
+            memory_mb: 64,
+            pids: 10,
+            clock: Clock::new(std::sync::Arc::new(time.clone())),
+            ..Default::default()
+        };
+        // A minute of virtual time passes while the command is still running.
+        let advance = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            time.advance(Duration::from_secs(61));
+        });
+        let started = std::time::Instant::now();
+        let outcome = exec_native("sleep 5", b"", &spec).await;
+        advance.await.unwrap();
+        assert!(outcome.timed_out());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    #[tokio::test]
+    async fn test_terminate_grace() {
+        let spawn = || {
+            let child = std::process::Command::new("sh")
+                .args(["-c", "trap 'exit 7' TERM; while :; do sleep 0.05; done"])
+                .spawn()
+                .unwrap();
+            // Let the shell install its trap.
+            std::thread::sleep(Duration::from_millis(200));
+            child
+        };
+        let clock = Clock::default();
+        let status = terminate(&mut spawn(), Duration::from_secs(5), &clock)
+            .await
+            .unwrap();
+        assert_eq!(classify_exit(&status), (7, TerminationReason::Completed));
+        let status = terminate(&mut spawn(), Duration::ZERO, &clock)
+            .await
+            .unwrap();
+        assert_eq!(classify_exit(&status).1, TerminationReason::Signalled(9));
+    }
+
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    #[tokio::test]
+    async fn test_init_reaps_orphans() {
+        let spec = SandboxSpec {
+            wall_sec: 5,
+            shell: Some(shell::Shell::Sh),
+            ..Default::default()
+        };
+        // The subshell exits at once, leaving its sleep to the init.
+        let cmd = "(sleep 0.2 &); sleep 0.5; exit 3";
+        let outcome = exec_native_with(cmd, b"", &spec, Hardening::default()).await;
+        assert_eq!(
+            (outcome.exit_code, outcome.reason),
+            (3, TerminationReason::Completed)
+        );
+        assert!(outcome.pids_peak >= Some(2), "{:?}", outcome.pids_peak);
+        // Unset when this host has no PID namespaces for us.
+        if let Some(n) = outcome.orphans_reaped {
+            assert_eq!(n, 1);
+        }
+    }
+
+    // Each run enters its namespaces from a thread of its own, so the
+    // caller's thread can run again.
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    #[tokio::test]
+    async fn test_runs_again_from_the_same_thread() {
+        let spec = SandboxSpec {
+            wall_sec: 5,
+            shell: Some(shell::Shell::Sh),
+            ..Default::default()
+        };
+        for n in 0..3 {
+            let cmd = format!("exit {}", n);
+            let outcome = exec_native_with(&cmd, b"", &spec, Hardening::default()).await;
+            assert_eq!(
+                (outcome.exit_code, outcome.reason),
+                (n, TerminationReason::Completed)
+            );
+        }
+        std::thread::spawn(|| ()).join().unwrap();
+    }
+
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    #[tokio::test]
+    async fn test_init_passes_on_signals() {
+        let spec = SandboxSpec {
+            wall_sec: 5,
+            shell: Some(shell::Shell::Sh),
+            ..Default::default()
+        };
+        let outcome = exec_native_with("kill -XCPU $$", b"", &spec, Hardening::default()).await;
+        assert_eq!(
+            (outcome.exit_code, outcome.reason),
+            (128 + SIGXCPU, TerminationReason::CpuLimit)
+        );
+    }
+
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    #[tokio::test]
+    async fn test_output_timeline_keeps_order() {
+        let spec = SandboxSpec {
+            wall_sec: 5,
+            shell: Some(shell::Shell::Sh),
+            observe_output: true,
+            ..Default::default()
+        };
+        let cmd = "echo a; sleep 0.1; echo b >&2; sleep 0.1; echo c";
+        let outcome = exec_native_with(cmd, b"", &spec, Hardening::default()).await;
+        assert_eq!(outcome.stdout, b"a\nc\n");
+        assert_eq!(outcome.stderr, b"b\n");
+        let kept = outcome.output_timeline.unwrap();
+        use base64::Engine as _;
+        let jsonl = base64::engine::general_purpose::STANDARD
+            .decode(&kept.jsonl_b64)
+            .unwrap();
+        let streams: Vec<String> = String::from_utf8(jsonl)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap()["stream"].to_string())
+            .collect();
+        assert_eq!(streams, [r#""stdout""#, r#""stderr""#, r#""stdout""#]);
+    }
+
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    #[tokio::test]
+    async fn test_proxy_is_the_only_way_out() {
+        use std::io::{Read, Write};
+        let origin = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = origin.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            while let Ok((mut s, _)) = origin.accept() {
+                let mut buf = [0u8; 1024];
+                let _ = s.read(&mut buf);
+                let _ = s.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi");
+            }
+        });
+        let proxy = egress::EgressProxy::start(
+            vec![format!("127.0.0.1:{}", port)],
+            vec![],
+            Default::default(),
+            None,
+        )
+        .unwrap();
+        let attach = proxy.attach();
+        let url = attach.url();
+        let proxy_port = url.rsplit(':').next().unwrap();
+        let host_ns = std::fs::read_link("/proc/self/ns/net").unwrap();
+        let spec = SandboxSpec {
+            wall_sec: 5,
+            shell: Some(shell::Shell::Bash),
+            proxy: Some(attach),
+            ..Default::default()
+        };
+        let cmd = format!(
+            "[ \"$(readlink /proc/self/ns/net)\" = '{}' ] && echo shared\n\
+             (exec 3<>/dev/tcp/127.0.0.1/{port}) 2>/dev/null && echo direct\n\
+             exec 3<>/dev/tcp/127.0.0.1/{proxy_port}\n\
+             printf 'GET http://127.0.0.1:{port}/ HTTP/1.1\\r\\nHost: x\\r\\n\\r\\n' >&3\n\
+             cat <&3",
+            host_ns.display()
+        );
+        let outcome = exec_native_with(&cmd, b"", &spec, Hardening::default()).await;
+        let stdout = String::from_utf8_lossy(&outcome.stdout);
+        assert!(stdout.ends_with("\r\n\r\nhi"), "{}", stdout);
+        // Unchecked when this host has no network namespaces for us.
+        if !stdout.starts_with("shared") {
+            assert!(!stdout.contains("direct"), "{}", stdout);
+        }
+        let (egress, _) = proxy.finish();
+        assert_eq!(egress.len(), 1);
+        assert_eq!(egress[0].connections, 1);
+    }
+
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    #[tokio::test]
+    async fn test_pty_capture() {
+        let spec = SandboxSpec {
+            wall_sec: 5,
+            shell: Some(shell::Shell::Sh),
+            pty: true,
+            ..Default::default()
+        };
+        let cmd = "test -t 1 && echo tty; stty size; echo err >&2; cat";
+        let outcome = exec_native_with(cmd, b"typed\n", &spec, Hardening::default()).await;
+        assert_eq!(outcome.exit_code, 0);
+        // One line each: no echo of the input, no \r\n.
+        assert_eq!(outcome.stdout, b"tty\n24 80\nerr\ntyped\n");
+        assert!(outcome.stderr.is_empty());
+    }
+
+    #[test]
+    fn test_termination_reason_serialization() {
+        let json = serde_json::to_string(&TerminationReason::Signalled(9)).unwrap();
+        assert_eq!(json, r#"{"kind":"signalled","detail":9}"#);
+        let json = serde_json::to_string(&TerminationReason::WallTimeout).unwrap();
+        assert_eq!(json, r#"{"kind":"wall_timeout"}"#);
     }
 
     #[test]
@@ -491,6 +1595,7 @@ mod tests {
             cpu_ms: 5000,
             memory_mb: 128,
             pids: 100,
+            ..Default::default()
         };
         assert_eq!(spec.wall_sec, 10);
         assert_eq!(spec.cpu_ms, 5000);
@@ -498,6 +1603,44 @@ mod tests {
         assert_eq!(spec.pids, 100);
     }
 
+    #[test]
+    fn test_expand_devices() {
+        let none = expand_devices(&[]);
+        assert!(none
+            .iter()
+            .all(|d| STANDARD_DEVICES.contains(&&*d.to_string_lossy())));
+        assert!(!none.iter().any(|d| d.ends_with("stdout")));
+        let with = expand_devices(&["/dev/nonexistent0".into(), "/dev/nul*".into()]);
+        assert_eq!(with, none);
+    }
+
+    #[test]
+    fn test_readonly_mounts() {
+        let dir = std::env::temp_dir();
+        let pats = vec![
+            format!("{}/**", dir.display()),
+            dir.display().to_string(),
+            "/nonexistent/ref".into(),
+            "/etc/*.conf".into(),
+            "relative/path".into(),
+        ];
+        assert_eq!(readonly_mounts(&pats), vec![dir]);
+    }
+
+    #[test]
+    fn test_tmpfs_options() {
+        assert_eq!(tmpfs_options(16, 1000), "size=16m,nr_inodes=1000,mode=1777");
+        assert_eq!(tmpfs_options(0, 0), "size=64m,nr_inodes=16384,mode=1777");
+        let spec = SandboxSpec {
+            tmp_mb: 8,
+            ..Default::default()
+        };
+        assert_eq!(
+            OverlayPlan::for_spec(&spec).tmp_options,
+            "size=8m,nr_inodes=16384,mode=1777"
+        );
+    }
+
     #[test]
     fn test_sandbox_kind_equality() {
         assert_eq!(SandboxKind::Wasi, SandboxKind::Wasi);
@@ -512,6 +1655,7 @@ mod tests {
             cpu_ms: 1000,
             memory_mb: 64,
             pids: 10,
+            ..Default::default()
         };
         let _outcome = exec_native("echo hello", b"", &spec).await;
         // Basic check - the function should return something without panic
@@ -524,6 +1668,7 @@ mod tests {
             cpu_ms: 1000,
             memory_mb: 64,
             pids: 10,
+            ..Default::default()
         };
         let outcome = exec_wasm(b"dummy", &spec).await;
         assert_eq!(outcome.exit_code, 0);
@@ -535,7 +1680,7 @@ mod tests {
     fn test_seccomp_minimal_allow_not_linux() {
         #[cfg(not(all(target_os = "linux", feature = "native_sandbox")))]
         {
-            let result = seccomp_minimal_allow();
+            let result = seccomp_minimal_allow(false, false, &[]);
             assert!(result.is_err());
             assert_eq!(result.unwrap_err(), "seccomp not supported in this build");
         }
@@ -545,7 +1690,7 @@ mod tests {
     fn test_try_enable_overlay_ro_not_linux() {
         #[cfg(not(all(target_os = "linux", feature = "linux_native")))]
         {
-            let result = try_enable_overlay_ro();
+            let result = try_enable_overlay_ro(&OverlayPlan::default());
             assert!(result.is_ok());
             assert!(result.unwrap().is_none());
         }
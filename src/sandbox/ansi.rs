@@ -0,0 +1,85 @@
+//! Escape sequences out of captured output (`strip_ansi: true`).
+//!
+//! Tools that see a terminal, or think they do, color and redraw their
+//! output; kept verbatim the codes make logs and grading harder to read.
+//! [`strip`] drops the ECMA-48 sequences that start with ESC: CSI
+//! (`ESC [` ... final byte, colors and cursor movement), the string
+//! sequences OSC, DCS, SOS, PM and APC up to their terminator (BEL or
+//! `ESC \`), and the two-byte and `ESC <intermediate> <final>` escapes.
+//! Other bytes, carriage returns included, stay as they are; 8-bit C1
+//! controls are left alone since in UTF-8 they are continuation bytes.
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+/// `bytes` without its escape sequences. An unfinished sequence at the end
+/// is dropped too.
+pub fn strip(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != ESC {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        i = skip_escape(bytes, i + 1);
+    }
+    out
+}
+
+/// Index just past the escape sequence whose ESC precedes `i`.
+fn skip_escape(bytes: &[u8], i: usize) -> usize {
+    match bytes.get(i) {
+        None => i,
+        // CSI: parameters and intermediates up to a final byte.
+        Some(b'[') => match bytes[i + 1..]
+            .iter()
+            .position(|b| (0x40..=0x7e).contains(b))
+        {
+            Some(n) => i + 1 + n + 1,
+            None => bytes.len(),
+        },
+        // OSC, DCS, SOS, PM, APC: a string up to BEL or ST.
+        Some(b']' | b'P' | b'X' | b'^' | b'_') => {
+            let mut j = i + 1;
+            while j < bytes.len() {
+                match bytes[j] {
+                    BEL => return j + 1,
+                    ESC if bytes.get(j + 1) == Some(&b'\\') => return j + 2,
+                    _ => j += 1,
+                }
+            }
+            j
+        }
+        // Intermediates, then one final byte (`ESC ( B`, `ESC 7`, ...).
+        Some(_) => {
+            let mut j = i;
+            while bytes.get(j).is_some_and(|b| (0x20..=0x2f).contains(b)) {
+                j += 1;
+            }
+            (j + 1).min(bytes.len())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip() {
+        assert_eq!(strip(b"\x1b[1;31merror\x1b[0m: x"), b"error: x");
+        assert_eq!(strip(b"50%\r\x1b[2K100%\n"), b"50%\r100%\n");
+        assert_eq!(strip(b"\x1b]0;title\x07ok"), b"ok");
+        assert_eq!(strip(b"\x1b]8;;http://x\x1b\\link\x1b]8;;\x1b\\"), b"link");
+        assert_eq!(strip(b"\x1b(Bplain\x1b7"), b"plain");
+        assert_eq!(
+            strip("caf\u{e9} \u{2713}".as_bytes()),
+            "caf\u{e9} \u{2713}".as_bytes()
+        );
+        // Cut off mid-sequence.
+        assert_eq!(strip(b"done\x1b[3"), b"done");
+        assert_eq!(strip(b"done\x1b"), b"done");
+    }
+}
@@ -0,0 +1,218 @@
+//! Seccomp denials read back from the kernel audit trail.
+//!
+//! The native filter is loaded with `SCMP_FLTATR_CTL_LOG`, so every syscall
+//! it refuses (or, in learn mode, lets through with `SCMP_ACT_LOG`) is logged
+//! by the kernel as a `type=1326` (`SECCOMP`) audit record. Those go to `/var/log/audit/audit.log` when auditd runs and to the
+//! kernel ring (`/dev/kmsg`) otherwise; [`AuditTail`] follows both (or
+//! `MAGICRUNE_AUDIT_LOG` alone) from where they ended before the run.
+//! Records carry host pids, so they are attributed to a run through the pids
+//! seen in its process tree.
+
+use crate::schema::Finding;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+
+/// `Finding::kind` of a syscall the filter refused.
+pub const SECCOMP_DENIED: &str = "seccomp_denied";
+/// `Finding::kind` of a syscall outside the allow-list that learn mode let
+/// through.
+pub const SECCOMP_LOGGED: &str = "seccomp_logged";
+
+/// One seccomp audit record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeccompRecord {
+    pub pid: u32,
+    pub syscall: i64,
+}
+
+/// Parse a `type=1326` / `type=SECCOMP` line from `/dev/kmsg` or audit.log.
+pub fn parse_seccomp_record(line: &str) -> Option<SeccompRecord> {
+    if !line.contains("type=1326") && !line.contains("type=SECCOMP") {
+        return None;
+    }
+    let field = |key: &str| {
+        line.split_whitespace()
+            .find_map(|kv| kv.strip_prefix(key)?.strip_prefix('='))
+    };
+    Some(SeccompRecord {
+        pid: field("pid")?.parse().ok()?,
+        syscall: field("syscall")?.parse().ok()?,
+    })
+}
+
+/// Count the records from `pids` by syscall, named by `name`, as findings
+/// of `kind` sorted by syscall name.
+pub fn syscall_findings(
+    records: &[SeccompRecord],
+    pids: &HashSet<u32>,
+    kind: &str,
+    name: impl Fn(i64) -> String,
+) -> Vec<Finding> {
+    let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+    for r in records.iter().filter(|r| pids.contains(&r.pid)) {
+        *counts.entry(name(r.syscall)).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .map(|(detail, count)| Finding {
+            kind: kind.to_string(),
+            detail,
+            count,
+        })
+        .collect()
+}
+
+enum Source {
+    /// One record per `read`; non-blocking, so `WouldBlock` marks the end.
+    Kmsg(File),
+    Text(File),
+}
+
+/// The audit sources, positioned at their current end.
+pub struct AuditTail {
+    sources: Vec<Source>,
+}
+
+impl AuditTail {
+    /// `None` when no source can be read (typically missing privileges).
+    pub fn open() -> Option<Self> {
+        let paths = match crate::ports::env::var("MAGICRUNE_AUDIT_LOG") {
+            Ok(p) if !p.is_empty() => vec![p],
+            _ => vec!["/var/log/audit/audit.log".into(), "/dev/kmsg".into()],
+        };
+        let sources: Vec<Source> = paths
+            .iter()
+            .filter_map(|p| {
+                if p == "/dev/kmsg" {
+                    let mut f = open_nonblocking(p)?;
+                    f.seek(SeekFrom::End(0)).ok()?;
+                    Some(Source::Kmsg(f))
+                } else {
+                    let mut f = File::open(p).ok()?;
+                    f.seek(SeekFrom::End(0)).ok()?;
+                    Some(Source::Text(f))
+                }
+            })
+            .collect();
+        (!sources.is_empty()).then_some(Self { sources })
+    }
+
+    /// Seccomp records logged since the last call.
+    pub fn read_new(&mut self) -> Vec<SeccompRecord> {
+        let mut out = Vec::new();
+        for src in &mut self.sources {
+            match src {
+                Source::Kmsg(f) => {
+                    let mut buf = [0u8; 8192];
+                    loop {
+                        match f.read(&mut buf) {
+                            Ok(0) => break,
+                            Ok(n) => {
+                                let line = String::from_utf8_lossy(&buf[..n]);
+                                out.extend(parse_seccomp_record(&line));
+                            }
+                            // The ring overwrote records we had not read yet.
+                            Err(e) if e.kind() == ErrorKind::BrokenPipe => continue,
+                            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                            Err(_) => break,
+                        }
+                    }
+                }
+                Source::Text(f) => {
+                    let mut text = String::new();
+                    if f.read_to_string(&mut text).is_ok() {
+                        out.extend(text.lines().filter_map(parse_seccomp_record));
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(unix)]
+fn open_nonblocking(path: &str) -> Option<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    // O_NONBLOCK
+    std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(0o4000)
+        .open(path)
+        .ok()
+}
+
+#[cfg(not(unix))]
+fn open_nonblocking(_path: &str) -> Option<File> {
+    None
+}
+
+/// Add `pid` and every process below it (per `/proc/<pid>/task/*/children`)
+/// to `seen`. Processes that start and exit between two calls are missed.
+#[cfg(target_os = "linux")]
+pub fn collect_tree(pid: u32, seen: &mut HashSet<u32>) {
+    seen.insert(pid);
+    let Ok(tasks) = std::fs::read_dir(format!("/proc/{}/task", pid)) else {
+        return;
+    };
+    for task in tasks.filter_map(|t| t.ok()) {
+        let Ok(children) = std::fs::read_to_string(task.path().join("children")) else {
+            continue;
+        };
+        for child in children.split_whitespace().filter_map(|c| c.parse().ok()) {
+            if !seen.contains(&child) {
+                collect_tree(child, seen);
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn collect_tree(pid: u32, seen: &mut HashSet<u32>) {
+    seen.insert(pid);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_attribute() {
+        let kmsg = r#"5,812,9384812,-;audit: type=1326 audit(1700000000.123:45): auid=4294967295 uid=0 gid=0 ses=4294967295 pid=4242 comm="curl" exe="/usr/bin/curl" sig=0 arch=c000003e syscall=41 compat=0 ip=0x7f code=0x50001"#;
+        let auditd = r#"type=SECCOMP msg=audit(1700000000.124:46): auid=1000 uid=1000 gid=1000 ses=2 pid=4243 comm="sh" exe="/bin/sh" sig=0 arch=c000003e syscall=57 compat=0 ip=0x7f code=0x50001"#;
+        let other = r#"type=SYSCALL msg=audit(1700000000.125:47): arch=c000003e syscall=59 success=yes pid=4242"#;
+        let records: Vec<_> = [kmsg, auditd, other, kmsg]
+            .iter()
+            .filter_map(|l| parse_seccomp_record(l))
+            .collect();
+        assert_eq!(
+            records[0],
+            SeccompRecord {
+                pid: 4242,
+                syscall: 41
+            }
+        );
+        assert_eq!(records.len(), 3);
+
+        let pids: HashSet<u32> = [4242].into_iter().collect();
+        let found = syscall_findings(&records, &pids, SECCOMP_DENIED, |nr| {
+            format!("syscall_{}", nr)
+        });
+        assert_eq!(
+            found,
+            vec![Finding {
+                kind: SECCOMP_DENIED.into(),
+                detail: "syscall_41".into(),
+                count: 2,
+            }]
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_collect_tree_includes_self() {
+        let mut seen = HashSet::new();
+        collect_tree(std::process::id(), &mut seen);
+        assert!(seen.contains(&std::process::id()));
+    }
+}
@@ -3,10 +3,10 @@ pub fn try_enable_cgroups(cpu_ms: u64, mem_mb: u64, pids: u64) -> Result<Option<
     use std::fs;
     use std::io::Write;
     use std::path::PathBuf;
-    if std::env::var("MAGICRUNE_CGROUPS").ok().as_deref() != Some("1") {
+    if crate::ports::env::var("MAGICRUNE_CGROUPS").ok().as_deref() != Some("1") {
         return Ok(None);
     }
-    let parent = std::env::var("MAGICRUNE_CGROUP_PARENT").unwrap_or_else(|_| "/sys/fs/cgroup".to_string());
+    let parent = crate::ports::env::var("MAGICRUNE_CGROUP_PARENT").unwrap_or_else(|_| "/sys/fs/cgroup".to_string());
     let name = format!("magicrune_{}", std::process::id());
     let path = PathBuf::from(parent).join(&name);
     fs::create_dir_all(&path).map_err(|e| format!("create cgroup dir failed: {e}"))?;
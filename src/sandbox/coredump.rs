@@ -0,0 +1,138 @@
+//! Core dumps of crashed commands (policy `debug.core_mb`).
+//!
+//! With a size set, the command runs with `RLIMIT_CORE` at that many MiB.
+//! When it dies of SIGSEGV or SIGABRT, or its shell reports that it did, the
+//! core files the kernel wrote to its working directory during the run are
+//! moved to the run's quarantine directory and listed in the result's
+//! `core_dumps`, so a crash can be looked into without running it again.
+//!
+//! Where cores go is the kernel's choice (`/proc/sys/kernel/core_pattern`):
+//! only a pattern relative to the working directory, such as the default
+//! `core`, leaves them where they can be collected. A pattern that pipes
+//! them to a program (`|/usr/lib/systemd/systemd-coredump`) leaves them to
+//! that program.
+
+use super::manifest::sha256_file;
+use super::TerminationReason;
+use crate::schema::CoreDump;
+use std::path::Path;
+use std::time::SystemTime;
+
+const SIGABRT: i32 = 6;
+const SIGSEGV: i32 = 11;
+
+/// Whether a command that ended with `reason` and `exit_code` crashed.
+pub fn crashed(reason: &TerminationReason, exit_code: i32) -> bool {
+    let crash = |sig: i32| sig == SIGSEGV || sig == SIGABRT;
+    match reason {
+        TerminationReason::Signalled(sig) => crash(*sig),
+        // A shell reports its child's signal this way.
+        TerminationReason::Completed => exit_code > 128 && crash(exit_code - 128),
+        _ => false,
+    }
+}
+
+/// Move the cores written to `cwd` since `since` to `into`.
+pub fn collect(cwd: &Path, since: SystemTime, into: &Path) -> Vec<CoreDump> {
+    let pattern = std::fs::read_to_string("/proc/sys/kernel/core_pattern").unwrap_or_default();
+    match local_prefix(pattern.trim()) {
+        Some(prefix) => gather(prefix, cwd, since, into),
+        None => {
+            tracing::info!(core_pattern = %pattern.trim(), "core dump not collected");
+            Vec::new()
+        }
+    }
+}
+
+/// Name prefix of the cores `pattern` leaves in the working directory:
+/// `core` for `core` or `core.%p`; `None` when they go elsewhere.
+fn local_prefix(pattern: &str) -> Option<&str> {
+    if pattern.starts_with('|') || pattern.contains('/') {
+        return None;
+    }
+    let prefix = pattern.split('%').next().unwrap_or_default();
+    (!prefix.is_empty()).then_some(prefix)
+}
+
+fn gather(prefix: &str, cwd: &Path, since: SystemTime, into: &Path) -> Vec<CoreDump> {
+    let Ok(entries) = std::fs::read_dir(cwd) else {
+        return Vec::new();
+    };
+    let mut cores = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let fresh = entry
+            .metadata()
+            .is_ok_and(|m| m.is_file() && m.modified().is_ok_and(|t| t >= since));
+        if !fresh || !name.to_string_lossy().starts_with(prefix) {
+            continue;
+        }
+        let to = into.join(&name);
+        if let Err(e) = std::fs::create_dir_all(into).and_then(|()| move_file(&entry.path(), &to)) {
+            tracing::warn!(error = %e, core = %entry.path().display(), "core dump not kept");
+            continue;
+        }
+        cores.push(CoreDump {
+            path: to.display().to_string(),
+            bytes: std::fs::metadata(&to).map_or(0, |m| m.len()),
+            sha256: sha256_file(&to).unwrap_or_default(),
+        });
+    }
+    cores.sort_by(|a, b| a.path.cmp(&b.path));
+    cores
+}
+
+/// Rename, or copy and remove across file systems.
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(from, to)?;
+    std::fs::remove_file(from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crashed() {
+        assert!(crashed(&TerminationReason::Signalled(SIGSEGV), 139));
+        assert!(crashed(&TerminationReason::Completed, 128 + SIGABRT));
+        assert!(!crashed(&TerminationReason::Signalled(9), 137));
+        assert!(!crashed(&TerminationReason::Completed, 1));
+        assert!(!crashed(&TerminationReason::WallTimeout, 139));
+    }
+
+    #[test]
+    fn test_local_prefix() {
+        assert_eq!(local_prefix("core"), Some("core"));
+        assert_eq!(local_prefix("core.%e.%p"), Some("core."));
+        assert_eq!(local_prefix("|/usr/lib/systemd/systemd-coredump %P"), None);
+        assert_eq!(local_prefix("/var/crash/core.%p"), None);
+        assert_eq!(local_prefix("%e.core"), None);
+    }
+
+    #[test]
+    fn test_gather_moves_fresh_cores() {
+        let base = std::env::temp_dir().join(format!("mr_coredump_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let (cwd, into) = (base.join("cwd"), base.join("quarantine/default/r_1"));
+        std::fs::create_dir_all(&cwd).unwrap();
+        std::fs::write(cwd.join("core.old"), b"old").unwrap();
+        let since = SystemTime::now() + std::time::Duration::from_secs(3600);
+        // Older than the run: left alone.
+        assert!(gather("core", &cwd, since, &into).is_empty());
+        std::fs::write(cwd.join("core.42"), b"\x7fELF").unwrap();
+        std::fs::write(cwd.join("notes.txt"), b"x").unwrap();
+        let since = SystemTime::UNIX_EPOCH;
+        let cores = gather("core", &cwd, since, &into);
+        assert_eq!(cores.len(), 2);
+        assert_eq!(cores[0].path, into.join("core.42").display().to_string());
+        assert_eq!(cores[0].bytes, 4);
+        assert_eq!(cores[0].sha256.len(), 64);
+        assert!(!cwd.join("core.42").exists());
+        assert!(cwd.join("notes.txt").exists());
+        let _ = std::fs::remove_dir_all(&base);
+    }
+}
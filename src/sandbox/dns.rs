@@ -0,0 +1,389 @@
+//! Stub DNS resolver (`capabilities.net.dns.allow`).
+//!
+//! Name resolution is allowed apart from connecting: a command may look up
+//! `*.health.internal` without being able to connect anywhere, or connect
+//! to addresses it was given without looking anything up.
+//!
+//! The command's network namespace gets its loopback up and a UDP socket
+//! on port 53 at the host's nameserver address (see [`listen_in_netns`]),
+//! so `/etc/resolv.conf` needs no change. The socket is served by a thread
+//! that stays in the host's namespace: names on the allowlist are resolved
+//! there and answered (A and AAAA), anything else gets `REFUSED` and is
+//! reported as a `dns_denied` finding.
+
+use crate::policy::allowed_match;
+use crate::schema::Finding;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Finding kind (and grading rule) of lookups outside the allowlist.
+pub const DNS_DENIED: &str = "dns_denied";
+/// Finding kind of a name the command line uses that the command may
+/// connect to but not look up.
+pub const DNS_MISMATCH: &str = "dns_mismatch";
+
+/// Refused names kept per run.
+const DENIED_CAP: usize = 100;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u8 = 3;
+const RCODE_REFUSED: u8 = 5;
+/// TTL of the answers; short, the command runs briefly.
+const TTL: u32 = 30;
+
+/// Where the command's socket is handed to the resolver thread. Cloned
+/// into the sandbox spec; the sandbox sends the socket once it made it
+/// inside the command's network namespace.
+#[derive(Debug, Clone)]
+pub struct DnsAttach(Sender<UdpSocket>);
+
+impl DnsAttach {
+    pub fn attach(&self, sock: UdpSocket) {
+        let _ = self.0.send(sock);
+    }
+}
+
+pub struct DnsStub {
+    attach: DnsAttach,
+    stop: Arc<AtomicBool>,
+    denied: Arc<Mutex<BTreeMap<String, u64>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DnsStub {
+    /// Start the resolver thread, answering the names `allow` matches.
+    /// Call it outside the command's network namespace: lookups are made
+    /// from the calling thread's.
+    pub fn start(allow: Vec<String>) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let denied = Arc::new(Mutex::new(BTreeMap::new()));
+        let thread = {
+            let (stop, denied) = (stop.clone(), denied.clone());
+            std::thread::spawn(move || serve(rx, &allow, &stop, &denied))
+        };
+        Self {
+            attach: DnsAttach(tx),
+            stop,
+            denied,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn attach(&self) -> DnsAttach {
+        self.attach.clone()
+    }
+
+    /// Stop the resolver; a `dns_denied` finding per refused name.
+    pub fn finish(mut self) -> Vec<Finding> {
+        self.shutdown();
+        let denied = self.denied.lock().unwrap();
+        denied
+            .iter()
+            .map(|(name, count)| Finding {
+                kind: DNS_DENIED.to_string(),
+                detail: name.clone(),
+                count: *count,
+            })
+            .collect()
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+impl Drop for DnsStub {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn serve(
+    rx: Receiver<UdpSocket>,
+    allow: &[String],
+    stop: &AtomicBool,
+    denied: &Mutex<BTreeMap<String, u64>>,
+) {
+    let tick = Duration::from_millis(100);
+    let sock = loop {
+        if stop.load(Ordering::Relaxed) {
+            return;
+        }
+        match rx.recv_timeout(tick) {
+            Ok(s) => break s,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+    };
+    let _ = sock.set_read_timeout(Some(tick));
+    let mut buf = [0u8; 512];
+    while !stop.load(Ordering::Relaxed) {
+        let Ok((n, from)) = sock.recv_from(&mut buf) else {
+            continue;
+        };
+        let Some((reply, name, allowed)) = answer(&buf[..n], allow, &lookup) else {
+            continue;
+        };
+        if !allowed {
+            let mut denied = denied.lock().unwrap();
+            if denied.contains_key(&name) || denied.len() < DENIED_CAP {
+                *denied.entry(name).or_default() += 1;
+            }
+        }
+        let _ = sock.send_to(&reply, from);
+    }
+}
+
+/// Addresses of `name` from the system resolver; `None` when it has none.
+fn lookup(name: &str) -> Option<Vec<IpAddr>> {
+    let addrs: Vec<IpAddr> = (name, 0).to_socket_addrs().ok()?.map(|a| a.ip()).collect();
+    (!addrs.is_empty()).then_some(addrs)
+}
+
+/// The reply to `query`, its name and whether the allowlist let it
+/// through; `None` for anything but a standard query with one question.
+fn answer(
+    query: &[u8],
+    allow: &[String],
+    resolve: &dyn Fn(&str) -> Option<Vec<IpAddr>>,
+) -> Option<(Vec<u8>, String, bool)> {
+    if query.len() < 12 || query[2] & 0xf8 != 0 || query[4..6] != [0, 1] {
+        return None;
+    }
+    let mut labels = Vec::new();
+    let mut pos = 12;
+    loop {
+        let len = *query.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        if len > 63 {
+            return None;
+        }
+        labels.push(std::str::from_utf8(query.get(pos..pos + len)?).ok()?);
+        pos += len;
+    }
+    let qtype = u16::from_be_bytes([*query.get(pos)?, *query.get(pos + 1)?]);
+    let question = query.get(12..pos + 4)?;
+    let name = labels.join(".").to_ascii_lowercase();
+    let allowed = allow.iter().any(|a| allowed_match(&name, None, a));
+    let (rcode, addrs) = if !allowed {
+        (RCODE_REFUSED, Vec::new())
+    } else {
+        match resolve(&name) {
+            Some(all) => (0, all),
+            None => (RCODE_NXDOMAIN, Vec::new()),
+        }
+    };
+    let records: Vec<Vec<u8>> = addrs
+        .iter()
+        .filter_map(|ip| match (ip, qtype) {
+            (IpAddr::V4(v4), TYPE_A) => Some(v4.octets().to_vec()),
+            (IpAddr::V6(v6), TYPE_AAAA) => Some(v6.octets().to_vec()),
+            _ => None,
+        })
+        .collect();
+    let mut out = Vec::with_capacity(question.len() + 12 + records.len() * 28);
+    out.extend_from_slice(&query[..2]);
+    // QR, the query's RD, RA.
+    out.push(0x80 | (query[2] & 0x01));
+    out.push(0x80 | rcode);
+    out.extend_from_slice(&[0, 1]);
+    out.extend_from_slice(&(records.len() as u16).to_be_bytes());
+    out.extend_from_slice(&[0, 0, 0, 0]);
+    out.extend_from_slice(question);
+    for rdata in &records {
+        // The name, as a pointer to the question's.
+        out.extend_from_slice(&[0xc0, 12]);
+        out.extend_from_slice(&qtype.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        out.extend_from_slice(&TTL.to_be_bytes());
+        out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        out.extend_from_slice(rdata);
+    }
+    Some((out, name, allowed))
+}
+
+/// The first `nameserver` of `resolv_conf`; 127.0.0.1 when there is none.
+pub fn nameserver(resolv_conf: &str) -> IpAddr {
+    resolv_conf
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix("nameserver"))
+        .find_map(|rest| rest.trim().parse().ok())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+}
+
+/// Interface requests on the calling thread's network namespace.
+#[cfg(all(target_os = "linux", feature = "linux_native"))]
+struct Ifctl(UdpSocket);
+
+#[cfg(all(target_os = "linux", feature = "linux_native"))]
+impl Ifctl {
+    fn new() -> std::io::Result<Self> {
+        UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map(Self)
+    }
+
+    fn ioctl(
+        &self,
+        name: &str,
+        req: nix::libc::c_ulong,
+        ifr: &mut nix::libc::ifreq,
+    ) -> std::io::Result<()> {
+        use std::os::fd::AsRawFd;
+        for (dst, src) in ifr.ifr_name.iter_mut().zip(name.bytes()) {
+            *dst = src as nix::libc::c_char;
+        }
+        // SAFETY: `ifr` is a valid ifreq for the requests made here.
+        if unsafe { nix::libc::ioctl(self.0.as_raw_fd(), req as _, ifr as *mut nix::libc::ifreq) }
+            < 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn up(&self, name: &str) -> std::io::Result<()> {
+        use nix::libc;
+        // SAFETY: all-zero is a valid ifreq.
+        let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+        ifr.ifr_ifru.ifru_flags = (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+        self.ioctl(name, libc::SIOCSIFFLAGS, &mut ifr)
+    }
+}
+
+/// Bring up the loopback of the calling thread's (fresh) network
+/// namespace; a new namespace starts with it down.
+#[cfg(all(target_os = "linux", feature = "linux_native"))]
+pub fn loopback_up() -> std::io::Result<()> {
+    Ifctl::new()?.up("lo")
+}
+
+/// In the calling thread's (fresh) network namespace: bring up the
+/// loopback, give it `ns` when that is not a loopback address, and bind
+/// port 53 there.
+#[cfg(all(target_os = "linux", feature = "linux_native"))]
+pub fn listen_in_netns(ns: IpAddr) -> std::io::Result<UdpSocket> {
+    use nix::libc;
+
+    let ctl = Ifctl::new()?;
+    let sockaddr = |v4: Ipv4Addr| {
+        let sin = libc::sockaddr_in {
+            sin_family: libc::AF_INET as libc::sa_family_t,
+            sin_port: 0,
+            sin_addr: libc::in_addr {
+                s_addr: u32::from(v4).to_be(),
+            },
+            sin_zero: [0; 8],
+        };
+        // SAFETY: sockaddr_in and sockaddr have the same size.
+        unsafe { std::mem::transmute::<libc::sockaddr_in, libc::sockaddr>(sin) }
+    };
+    ctl.up("lo")?;
+    match ns {
+        IpAddr::V4(v4) if !v4.is_loopback() => {
+            // SAFETY: all-zero is a valid ifreq.
+            let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+            ifr.ifr_ifru.ifru_addr = sockaddr(v4);
+            ctl.ioctl("lo:0", libc::SIOCSIFADDR, &mut ifr)?;
+            ifr.ifr_ifru.ifru_netmask = sockaddr(Ipv4Addr::BROADCAST);
+            ctl.ioctl("lo:0", libc::SIOCSIFNETMASK, &mut ifr)?;
+            ctl.up("lo:0")?;
+        }
+        IpAddr::V6(v6) if !v6.is_loopback() => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("nameserver {} is not IPv4 or loopback", v6),
+            ));
+        }
+        _ => {}
+    }
+    UdpSocket::bind(std::net::SocketAddr::new(ns, 53))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut q = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            q.push(label.len() as u8);
+            q.extend_from_slice(label.as_bytes());
+        }
+        q.push(0);
+        q.extend_from_slice(&qtype.to_be_bytes());
+        q.extend_from_slice(&CLASS_IN.to_be_bytes());
+        q
+    }
+
+    #[test]
+    fn test_answer() {
+        let allow = vec!["*.health.internal".to_string()];
+        let resolve = |_: &str| Some(vec!["10.1.2.3".parse().unwrap(), "::1".parse().unwrap()]);
+
+        let q = query("db.Health.internal", TYPE_A);
+        let (reply, name, allowed) = answer(&q, &allow, &resolve).unwrap();
+        assert_eq!((name.as_str(), allowed), ("db.health.internal", true));
+        assert_eq!(&reply[..4], &[0x12, 0x34, 0x81, 0x80]);
+        // One answer, after the copied question: pointer, type, class,
+        // TTL, length, address.
+        assert_eq!(&reply[6..8], &[0, 1]);
+        let rr = &reply[q.len()..];
+        assert_eq!(rr, &[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 30, 0, 4, 10, 1, 2, 3]);
+
+        let (reply, name, allowed) =
+            answer(&query("example.com", TYPE_A), &allow, &resolve).unwrap();
+        assert_eq!((name.as_str(), allowed), ("example.com", false));
+        assert_eq!(reply[3] & 0x0f, RCODE_REFUSED);
+        assert_eq!(&reply[6..8], &[0, 0]);
+
+        let none = |_: &str| None;
+        let (reply, _, _) = answer(&query("x.health.internal", TYPE_AAAA), &allow, &none).unwrap();
+        assert_eq!(reply[3] & 0x0f, RCODE_NXDOMAIN);
+        assert!(answer(&q[..10], &allow, &resolve).is_none());
+    }
+
+    #[test]
+    fn test_stub_serves_attached_socket() {
+        let stub = DnsStub::start(vec!["localhost".into()]);
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        stub.attach().attach(server);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buf = [0u8; 512];
+        for name in ["blocked.test", "blocked.test", "localhost"] {
+            client.send_to(&query(name, TYPE_A), addr).unwrap();
+            client.recv_from(&mut buf).unwrap();
+        }
+        let findings = stub.finish();
+        assert_eq!(findings.len(), 1);
+        assert_eq!(
+            (findings[0].detail.as_str(), findings[0].count),
+            ("blocked.test", 2)
+        );
+    }
+
+    #[test]
+    fn test_nameserver() {
+        assert_eq!(
+            nameserver("# x\nsearch lan\nnameserver 10.0.0.2\nnameserver 1.1.1.1\n"),
+            "10.0.0.2".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(nameserver(""), IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+}
@@ -0,0 +1,774 @@
+//! Egress proxy (`capabilities.net.proxy: on`).
+//!
+//! A forward proxy on the loopback interface that the command reaches
+//! through `HTTP_PROXY` / `HTTPS_PROXY`. `CONNECT host:port` opens a tunnel
+//! (HTTPS), an absolute-form request (`GET http://host/path`) is passed on
+//! for one request and response (plain HTTP). Either way the target must
+//! match the network allowlist, checked by host name before anything is
+//! resolved; refused targets get a `403`. When `capabilities.net.dns.allow`
+//! is set, a target given by name must also be a name the command may look
+//! up. An allowed name is resolved once and the proxy connects to the very
+//! address it checked: loopback, link-local and unspecified addresses are
+//! refused unless an address or CIDR entry of the allowlist names them, so
+//! a name cannot be pointed at the host or its metadata service. Bytes in
+//! both directions are counted per target and reported in the result's
+//! `egress`.
+//!
+//! The command keeps its own network namespace. The proxy listens on the
+//! host's loopback and, once the sandbox hands it one ([`ProxyAttach`]),
+//! at the same address on the command's loopback; it connects out from
+//! the host's namespace, the only way out the command has.
+//!
+//! `limits.net_connections` caps the connections let through in a run
+//! (further ones get a `429`) and `limits.net_kbps` the bandwidth of all
+//! of them together, both directions, through one token bucket. Both are
+//! reported as findings when they bite.
+//!
+//! With an HTTP cache ([`crate::http_cache`]), plain `GET`s are revalidated
+//! against the responses kept from earlier runs and answered from them on
+//! `304 Not Modified`; the hits are counted for the result's timings. Bytes
+//! served from the cache are not counted as `bytes_in`, nor held to the
+//! bandwidth limit.
+//!
+//! The proxy is a thread per connection on std sockets, so it keeps
+//! serving while the engine waits for the command; connections over
+//! [`MAX_ACTIVE`] at once get a `503`.
+
+use crate::http_cache::{self, Cached, HttpCache};
+use crate::policy::{allowed_match, hostport_parts};
+use crate::schema::{EgressHost, Finding};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// At most this many targets are kept per run.
+pub const EGRESS_CAP: usize = 100;
+
+/// Longest request head read before a request is refused.
+const MAX_HEAD: usize = 16 * 1024;
+
+/// Connections served at once; further ones get a `503`.
+pub const MAX_ACTIVE: usize = 64;
+
+/// Finding kind of connections refused over `limits.net_connections`.
+pub const NET_CONNECTIONS: &str = "net_connections";
+/// Finding kind of traffic held back to `limits.net_kbps`.
+pub const NET_KBPS: &str = "net_kbps";
+
+type Stats = Arc<Mutex<BTreeMap<String, EgressHost>>>;
+
+/// `limits.net_kbps` and `limits.net_connections`; 0 is no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EgressLimits {
+    pub kbps: u64,
+    pub connections: u64,
+}
+
+/// What a run's connections share: the connection count and the bandwidth.
+#[derive(Debug)]
+struct Shared {
+    limits: EgressLimits,
+    allow: Vec<String>,
+    names: Vec<String>,
+    stats: Stats,
+    budget: Mutex<Budget>,
+    cache: Option<Arc<HttpCache>>,
+    cache_hits: AtomicU64,
+    active: AtomicUsize,
+}
+
+#[derive(Debug, Default)]
+struct Budget {
+    connections: u64,
+    refused: u64,
+    throttle: Option<Throttle>,
+}
+
+/// Token bucket without a burst: each chunk is sent no earlier than the
+/// rate allows after the previous one.
+#[derive(Debug)]
+struct Throttle {
+    bytes_per_sec: f64,
+    next: Option<Instant>,
+    waited: Duration,
+}
+
+impl Throttle {
+    fn new(kbps: u64) -> Self {
+        Self {
+            bytes_per_sec: kbps as f64 * 125.0,
+            next: None,
+            waited: Duration::ZERO,
+        }
+    }
+
+    /// How long to wait at `now` before `n` more bytes may go.
+    fn reserve(&mut self, n: usize, now: Instant) -> Duration {
+        let start = self.next.map_or(now, |next| next.max(now));
+        let wait = start - now;
+        self.next = Some(start + Duration::from_secs_f64(n as f64 / self.bytes_per_sec));
+        self.waited += wait;
+        wait
+    }
+}
+
+/// Where the listener in the command's network namespace is handed to
+/// the proxy. Cloned into the sandbox spec; the sandbox sends the listener
+/// once it made it at [`ProxyAttach::addr`] inside the namespace.
+#[derive(Debug, Clone)]
+pub struct ProxyAttach {
+    addr: SocketAddr,
+    tx: Sender<TcpListener>,
+}
+
+impl ProxyAttach {
+    /// `http://127.0.0.1:<port>`, for the proxy variables.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// The proxy's address on the host's loopback, to listen at on the
+    /// command's.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn attach(&self, listener: TcpListener) {
+        let _ = self.tx.send(listener);
+    }
+}
+
+pub struct EgressProxy {
+    addr: SocketAddr,
+    attach: Sender<TcpListener>,
+    stop: Arc<AtomicBool>,
+    shared: Arc<Shared>,
+    accept: Option<JoinHandle<()>>,
+}
+
+impl EgressProxy {
+    /// Listen on an ephemeral loopback port, letting through the targets
+    /// `allow` matches (`host[:port]`, wildcards, CIDRs, as in
+    /// `capabilities.net.allow`) within `limits`. Unless `names` is empty,
+    /// targets given by name must match it too (`capabilities.net.dns.allow`).
+    /// Plain `GET`s are answered from `cache` when it is still current.
+    pub fn start(
+        allow: Vec<String>,
+        names: Vec<String>,
+        limits: EgressLimits,
+        cache: Option<Arc<HttpCache>>,
+    ) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        let addr = listener.local_addr()?;
+        listener.set_nonblocking(true)?;
+        let (tx, rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let shared = Arc::new(Shared {
+            limits,
+            allow,
+            names,
+            stats: Stats::default(),
+            budget: Mutex::new(Budget {
+                throttle: (limits.kbps > 0).then(|| Throttle::new(limits.kbps)),
+                ..Default::default()
+            }),
+            cache,
+            cache_hits: AtomicU64::new(0),
+            active: AtomicUsize::new(0),
+        });
+        let accept = {
+            let (stop, shared) = (stop.clone(), shared.clone());
+            std::thread::spawn(move || accept(listener, rx, &stop, &shared))
+        };
+        Ok(Self {
+            addr,
+            attach: tx,
+            stop,
+            shared,
+            accept: Some(accept),
+        })
+    }
+
+    pub fn attach(&self) -> ProxyAttach {
+        ProxyAttach {
+            addr: self.addr,
+            tx: self.attach.clone(),
+        }
+    }
+
+    /// Requests answered from the HTTP cache so far.
+    pub fn cache_hits(&self) -> u64 {
+        self.shared.cache_hits.load(Ordering::Relaxed)
+    }
+
+    /// Stop accepting connections; the counters so far, by target, and a
+    /// finding for each limit that refused or held back traffic.
+    pub fn finish(mut self) -> (Vec<EgressHost>, Vec<Finding>) {
+        self.shutdown();
+        let hosts = self
+            .shared
+            .stats
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        let budget = self.shared.budget.lock().unwrap();
+        let mut findings = Vec::new();
+        if budget.refused > 0 {
+            findings.push(Finding {
+                kind: NET_CONNECTIONS.to_string(),
+                detail: format!(
+                    "connections over limits.net_connections {} refused",
+                    self.shared.limits.connections
+                ),
+                count: budget.refused,
+            });
+        }
+        if let Some(t) = budget.throttle.as_ref().filter(|t| !t.waited.is_zero()) {
+            findings.push(Finding {
+                kind: NET_KBPS.to_string(),
+                detail: format!(
+                    "traffic held back {} ms to limits.net_kbps {}",
+                    t.waited.as_millis(),
+                    self.shared.limits.kbps
+                ),
+                count: 1,
+            });
+        }
+        (hosts, findings)
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(t) = self.accept.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+impl Drop for EgressProxy {
+    fn drop(&mut self) {
+        if self.accept.is_some() {
+            self.shutdown();
+        }
+    }
+}
+
+/// Serve the connections of `host` and of the listeners `attached` brings
+/// until `stop`, at most [`MAX_ACTIVE`] at once. The listeners are polled:
+/// a blocking accept in the command's namespace could not be woken from
+/// the host's.
+fn accept(
+    host: TcpListener,
+    attached: Receiver<TcpListener>,
+    stop: &AtomicBool,
+    shared: &Arc<Shared>,
+) {
+    let tick = Duration::from_millis(10);
+    let mut listeners = vec![host];
+    while !stop.load(Ordering::Relaxed) {
+        listeners.extend(
+            attached
+                .try_iter()
+                .filter(|l| l.set_nonblocking(true).is_ok()),
+        );
+        let mut idle = true;
+        for listener in &listeners {
+            let Ok((mut conn, _)) = listener.accept() else {
+                continue;
+            };
+            idle = false;
+            if conn.set_nonblocking(false).is_err() {
+                continue;
+            }
+            if shared.active.fetch_add(1, Ordering::Relaxed) >= MAX_ACTIVE {
+                shared.active.fetch_sub(1, Ordering::Relaxed);
+                let _ = conn
+                    .write_all(b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\n\r\n");
+                continue;
+            }
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                serve(conn, &shared);
+                shared.active.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+        if idle {
+            std::thread::sleep(tick);
+        }
+    }
+}
+
+/// Whether `ip` is the host's own or its link's: loopback, link-local
+/// (where cloud metadata services answer) or unspecified.
+fn internal(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => v4.is_loopback() || v4.is_link_local() || v4.is_unspecified(),
+        IpAddr::V6(v6) => {
+            v6.is_loopback() || v6.is_unspecified() || (v6.segments()[0] & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+/// Whether the allowlist entry `allow` is an address or a CIDR rather than
+/// a name.
+fn by_address(allow: &str) -> bool {
+    let (host, _) = hostport_parts(allow);
+    host.contains('/') || host.parse::<IpAddr>().is_ok()
+}
+
+fn record(stats: &Stats, target: &str, f: impl FnOnce(&mut EgressHost)) {
+    let mut stats = stats.lock().unwrap();
+    if !stats.contains_key(target) && stats.len() >= EGRESS_CAP {
+        return;
+    }
+    f(stats
+        .entry(target.to_string())
+        .or_insert_with(|| EgressHost {
+            target: target.to_string(),
+            ..Default::default()
+        }));
+}
+
+fn serve(mut client: TcpStream, shared: &Shared) {
+    let stats = &shared.stats;
+    let _ = client.set_read_timeout(Some(Duration::from_secs(30)));
+    let Some((head, rest)) = read_head(&mut client) else {
+        return;
+    };
+    let _ = client.set_read_timeout(None);
+    let Some((method, target, version)) = request_line(&head) else {
+        let _ = client.write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n");
+        return;
+    };
+    let url = target.to_string();
+    let (authority, forward) = if method.eq_ignore_ascii_case("CONNECT") {
+        (target.to_string(), None)
+    } else {
+        let Some(url) = target.strip_prefix("http://") else {
+            let _ = client.write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n");
+            return;
+        };
+        let (authority, path) = url.split_at(url.find('/').unwrap_or(url.len()));
+        let path = if path.is_empty() { "/" } else { path };
+        (
+            authority.to_string(),
+            Some(rewrite(&head, method, path, version)),
+        )
+    };
+    let (host, port) = hostport_parts(&authority);
+    let port = port.unwrap_or(if forward.is_some() { "80" } else { "443" });
+    let target = if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+    let by_name = host.parse::<std::net::IpAddr>().is_err();
+    let name_ok = !by_name
+        || shared.names.is_empty()
+        || shared.names.iter().any(|n| allowed_match(&host, None, n));
+    if !name_ok
+        || !shared
+            .allow
+            .iter()
+            .any(|a| allowed_match(&host, Some(port), a))
+    {
+        record(stats, &target, |h| h.denied += 1);
+        let _ = client.write_all(b"HTTP/1.1 403 Forbidden\r\nConnection: close\r\n\r\n");
+        return;
+    }
+    // Resolved once here: the address checked is the one connected to.
+    let addrs: Vec<SocketAddr> = (host.as_ref(), port.parse::<u16>().unwrap_or(0))
+        .to_socket_addrs()
+        .map(Iterator::collect)
+        .unwrap_or_default();
+    if addrs.is_empty() {
+        record(stats, &target, |h| h.connections += 1);
+        let _ = client.write_all(b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n");
+        return;
+    }
+    let permitted = |addr: &SocketAddr| {
+        !internal(addr.ip())
+            || shared.allow.iter().any(|a| {
+                by_address(a) && allowed_match(&addr.ip().to_canonical().to_string(), Some(port), a)
+            })
+    };
+    let Some(addr) = addrs.into_iter().find(permitted) else {
+        record(stats, &target, |h| h.denied += 1);
+        let _ = client.write_all(b"HTTP/1.1 403 Forbidden\r\nConnection: close\r\n\r\n");
+        return;
+    };
+    {
+        let mut budget = shared.budget.lock().unwrap();
+        let max = shared.limits.connections;
+        if max > 0 && budget.connections >= max {
+            budget.refused += 1;
+            drop(budget);
+            let _ =
+                client.write_all(b"HTTP/1.1 429 Too Many Requests\r\nConnection: close\r\n\r\n");
+            return;
+        }
+        budget.connections += 1;
+    }
+    // A plain GET the cache may answer, and what it holds for it.
+    let cached = match (&shared.cache, &forward) {
+        (Some(cache), Some(_)) if http_cache::cacheable(&head) => Some((cache, cache.lookup(&url))),
+        _ => None,
+    };
+    let forward = match (forward, &cached) {
+        (Some(head), Some((_, Some(hit)))) => Some(http_cache::revalidate(&head, &hit.etag)),
+        (forward, _) => forward,
+    };
+    let mut upstream_w = match TcpStream::connect(addr) {
+        Ok(u) => u,
+        Err(_) => {
+            record(stats, &target, |h| h.connections += 1);
+            let _ = client.write_all(b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n");
+            return;
+        }
+    };
+    let mut sent = 0u64;
+    match &forward {
+        Some(head) => {
+            if upstream_w.write_all(head).is_err() {
+                return;
+            }
+            sent += head.len() as u64;
+        }
+        None => {
+            if client
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+    if upstream_w.write_all(&rest).is_err() {
+        return;
+    }
+    sent += rest.len() as u64;
+    let (Ok(mut client_r), Ok(mut upstream_r)) = (client.try_clone(), upstream_w.try_clone())
+    else {
+        return;
+    };
+    let received = std::thread::scope(|s| {
+        let up = s.spawn(|| {
+            let n = pump(&mut client_r, &mut upstream_w, shared, |_| {});
+            let _ = upstream_w.shutdown(Shutdown::Write);
+            n
+        });
+        let received = match cached {
+            Some((cache, hit)) => relay(&mut upstream_r, &mut client, shared, cache, &url, hit),
+            None => pump(&mut upstream_r, &mut client, shared, |_| {}),
+        };
+        let _ = client.shutdown(Shutdown::Write);
+        sent += up.join().unwrap_or(0);
+        received
+    });
+    record(stats, &target, |h| {
+        h.connections += 1;
+        h.bytes_out += sent;
+        h.bytes_in += received;
+    });
+}
+
+/// The server's response to a request the cache may answer: the kept one
+/// when the server says it is still current, otherwise the server's own,
+/// kept on the way when it may be. The bytes read from the server.
+fn relay(
+    from: &mut TcpStream,
+    to: &mut TcpStream,
+    shared: &Shared,
+    cache: &HttpCache,
+    url: &str,
+    hit: Option<Cached>,
+) -> u64 {
+    let Some((head, rest)) = read_head(from) else {
+        return 0;
+    };
+    let received = (head.len() + rest.len()) as u64;
+    if let Some(hit) = hit.filter(|_| http_cache::status(&head) == Some(304)) {
+        if hit.serve(to).is_ok() {
+            shared.cache_hits.fetch_add(1, Ordering::Relaxed);
+        }
+        return received;
+    }
+    if to.write_all(head.as_bytes()).is_err() || to.write_all(&rest).is_err() {
+        return received;
+    }
+    let mut fill = cache.fill(&head).and_then(|f| f.push(&rest));
+    let n = pump(from, to, shared, |bytes| {
+        fill = fill.take().and_then(|f| f.push(bytes));
+    });
+    if let Some(fill) = fill {
+        fill.commit(url);
+    }
+    received + n
+}
+
+/// Copy `from` to `to` until either end closes, at the rate the bandwidth
+/// limit leaves, showing each chunk to `tee`; the bytes copied.
+fn pump(
+    from: &mut TcpStream,
+    to: &mut TcpStream,
+    shared: &Shared,
+    mut tee: impl FnMut(&[u8]),
+) -> u64 {
+    let mut buf = [0u8; 16 * 1024];
+    let mut total = 0u64;
+    loop {
+        let n = match from.read(&mut buf) {
+            Ok(0) | Err(_) => return total,
+            Ok(n) => n,
+        };
+        let wait = {
+            let mut budget = shared.budget.lock().unwrap();
+            budget
+                .throttle
+                .as_mut()
+                .map_or(Duration::ZERO, |t| t.reserve(n, Instant::now()))
+        };
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+        if to.write_all(&buf[..n]).is_err() {
+            return total;
+        }
+        tee(&buf[..n]);
+        total += n as u64;
+    }
+}
+
+/// The request head (through the blank line) and whatever followed it.
+fn read_head(client: &mut TcpStream) -> Option<(String, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(end + 4);
+            return String::from_utf8(buf).ok().map(|h| (h, rest));
+        }
+        if buf.len() > MAX_HEAD {
+            return None;
+        }
+        let n = client.read(&mut chunk).ok().filter(|n| *n > 0)?;
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+fn request_line(head: &str) -> Option<(&str, &str, &str)> {
+    let mut parts = head.lines().next()?.split_whitespace();
+    Some((parts.next()?, parts.next()?, parts.next()?))
+}
+
+/// `head` in origin form for the server: `path` as the target, the proxy
+/// headers dropped, and one request per connection, so later requests
+/// cannot reach another host through it.
+fn rewrite(head: &str, method: &str, path: &str, version: &str) -> Vec<u8> {
+    let mut out = format!("{} {} {}\r\n", method, path, version);
+    for line in head.lines().skip(1).filter(|l| !l.is_empty()) {
+        let name = line.split(':').next().unwrap_or_default().trim();
+        if name.eq_ignore_ascii_case("connection")
+            || name.to_ascii_lowercase().starts_with("proxy-")
+        {
+            continue;
+        }
+        out.push_str(line);
+        out.push_str("\r\n");
+    }
+    out.push_str("Connection: close\r\n\r\n");
+    out.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A server that answers one request with `body` and closes.
+    fn server(body: &'static [u8]) -> SocketAddr {
+        let l = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = l.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut s, _) = l.accept().unwrap();
+            let _ = read_head(&mut s);
+            s.write_all(body).unwrap();
+        });
+        addr
+    }
+
+    fn ask(proxy: &EgressProxy, req: String) -> String {
+        let mut s = TcpStream::connect(proxy.addr).unwrap();
+        s.write_all(req.as_bytes()).unwrap();
+        let mut out = String::new();
+        s.read_to_string(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_forwards_allowed_and_counts() {
+        let origin = server(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi");
+        let limits = EgressLimits {
+            connections: 1,
+            ..Default::default()
+        };
+        let proxy = EgressProxy::start(
+            vec![format!("127.0.0.1:{}", origin.port())],
+            vec![],
+            limits,
+            None,
+        )
+        .unwrap();
+        let get = format!(
+            "GET http://{}/x HTTP/1.1\r\nHost: {}\r\nProxy-Connection: keep-alive\r\n\r\n",
+            origin, origin
+        );
+        let reply = ask(&proxy, get.clone());
+        assert!(reply.ends_with("\r\n\r\nhi"), "{}", reply);
+        assert!(ask(&proxy, get).starts_with("HTTP/1.1 429"));
+        let denied = ask(
+            &proxy,
+            "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n".into(),
+        );
+        assert!(denied.starts_with("HTTP/1.1 403"));
+
+        let (egress, findings) = proxy.finish();
+        assert_eq!(findings.len(), 1);
+        assert_eq!(
+            (findings[0].kind.as_str(), findings[0].count),
+            (NET_CONNECTIONS, 1)
+        );
+        assert_eq!(egress.len(), 2);
+        let ok = egress
+            .iter()
+            .find(|h| h.target == origin.to_string())
+            .unwrap();
+        assert_eq!((ok.connections, ok.denied), (1, 0));
+        assert_eq!(ok.bytes_in, 40);
+        assert!(ok.bytes_out > 0);
+        let no = egress
+            .iter()
+            .find(|h| h.target == "example.com:443")
+            .unwrap();
+        assert_eq!((no.connections, no.denied, no.bytes_out), (0, 1, 0));
+    }
+
+    #[test]
+    fn test_answers_from_cache_when_not_modified() {
+        let l = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let origin = l.local_addr().unwrap();
+        let asked = std::thread::spawn(move || {
+            let mut heads = Vec::new();
+            for reply in [
+                &b"HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 4\r\n\r\nbody"[..],
+                b"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\r\n",
+            ] {
+                let (mut s, _) = l.accept().unwrap();
+                heads.push(read_head(&mut s).unwrap().0);
+                s.write_all(reply).unwrap();
+            }
+            heads
+        });
+        let dir = std::env::temp_dir().join(format!("mr_egress_cache_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = Arc::new(HttpCache::new(&dir, 1 << 20));
+        let proxy = EgressProxy::start(
+            vec![format!("127.0.0.1:{}", origin.port())],
+            vec![],
+            EgressLimits::default(),
+            Some(cache),
+        )
+        .unwrap();
+        let get = format!(
+            "GET http://{}/a HTTP/1.1\r\nHost: {}\r\n\r\n",
+            origin, origin
+        );
+        let first = ask(&proxy, get.clone());
+        assert_eq!(ask(&proxy, get), first);
+        assert!(first.ends_with("\r\n\r\nbody"), "{}", first);
+        assert_eq!(proxy.cache_hits(), 1);
+
+        let heads = asked.join().unwrap();
+        assert!(!heads[0].contains("If-None-Match"));
+        assert!(
+            heads[1].contains("If-None-Match: \"v1\"\r\n"),
+            "{}",
+            heads[1]
+        );
+        drop(proxy);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_refuses_internal_addresses_by_name() {
+        let origin = server(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi");
+        let get = format!(
+            "GET http://localhost:{}/ HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            origin.port()
+        );
+        let by_name = vec![format!("localhost:{}", origin.port())];
+        let proxy =
+            EgressProxy::start(by_name.clone(), vec![], EgressLimits::default(), None).unwrap();
+        assert!(ask(&proxy, get.clone()).starts_with("HTTP/1.1 403"));
+        let (egress, _) = proxy.finish();
+        assert_eq!((egress[0].connections, egress[0].denied), (0, 1));
+
+        let mut allow = by_name;
+        allow.push("127.0.0.0/8".into());
+        let proxy = EgressProxy::start(allow, vec![], EgressLimits::default(), None).unwrap();
+        assert!(ask(&proxy, get).ends_with("\r\n\r\nhi"));
+
+        assert!(internal("169.254.169.254".parse().unwrap()));
+        assert!(internal("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(internal("fe80::1".parse().unwrap()));
+        assert!(!internal("10.0.0.1".parse().unwrap()));
+        assert!(by_address("[::1]:443") && by_address("10.0.0.0/8"));
+        assert!(!by_address("*.1:443"));
+    }
+
+    #[test]
+    fn test_caps_connections_served_at_once() {
+        let proxy = EgressProxy::start(vec![], vec![], EgressLimits::default(), None).unwrap();
+        // Idle connections, each holding a thread while it waits for a head.
+        let idle: Vec<TcpStream> = (0..MAX_ACTIVE)
+            .map(|_| TcpStream::connect(proxy.addr).unwrap())
+            .collect();
+        while proxy.shared.active.load(Ordering::Relaxed) < MAX_ACTIVE {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // Turned away before anything is read, so nothing is sent.
+        let mut reply = String::new();
+        TcpStream::connect(proxy.addr)
+            .unwrap()
+            .read_to_string(&mut reply)
+            .unwrap();
+        assert!(reply.starts_with("HTTP/1.1 503"), "{}", reply);
+        drop(idle);
+    }
+
+    #[test]
+    fn test_throttle_spaces_chunks() {
+        // 8 kbps is 1000 bytes a second.
+        let mut t = Throttle::new(8);
+        let now = Instant::now();
+        assert_eq!(t.reserve(500, now), Duration::ZERO);
+        assert_eq!(t.reserve(500, now), Duration::from_millis(500));
+        assert_eq!(t.reserve(100, now + Duration::from_secs(3)), Duration::ZERO);
+        assert_eq!(t.waited, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_rewrite_drops_proxy_headers() {
+        let head = "GET http://a.test/p HTTP/1.1\r\nHost: a.test\r\nProxy-Authorization: x\r\nConnection: keep-alive\r\n\r\n";
+        assert_eq!(
+            String::from_utf8(rewrite(head, "GET", "/p", "HTTP/1.1")).unwrap(),
+            "GET /p HTTP/1.1\r\nHost: a.test\r\nConnection: close\r\n\r\n"
+        );
+    }
+}
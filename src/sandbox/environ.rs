@@ -0,0 +1,187 @@
+//! The environment a command started with (policy `observe.env: names`).
+//!
+//! The command inherits the consumer's environment, with the variables the
+//! sandbox sets on top ([`overrides`]: `HOME`, `TMPDIR`, the proxy
+//! variables, and [`DETERMINISTIC`] in determinism mode). The result's
+//! `env` lists every variable it got, by name, with where it came from
+//! relative to the consumer's environment: `inherited` as is, `set` by the
+//! sandbox, or `overridden` by the sandbox.
+//! Values are recorded only for the names `observe.env_values` matches;
+//! the rest may hold secrets. `sha256` covers the names and the recorded
+//! values only: a digest over a withheld value would let anyone who knows
+//! the other variables test guesses of it offline.
+//!
+//! The result is signed, which makes the list part of the run's
+//! attestation.
+
+use super::SandboxSpec;
+use crate::policy::pat_matches;
+use crate::schema::{EnvSnapshot, EnvVar};
+
+pub const INHERITED: &str = "inherited";
+pub const SET: &str = "set";
+pub const OVERRIDDEN: &str = "overridden";
+
+/// Variables pinned in determinism mode, so that a command's output does
+/// not follow the consumer's locale, time zone or clock where the tools
+/// honour them. `SOURCE_DATE_EPOCH` is 1980-01-01, the earliest date zip
+/// archives can hold.
+pub const DETERMINISTIC: &[(&str, &str)] = &[
+    ("LANG", "C"),
+    ("LC_ALL", "C"),
+    ("PYTHONHASHSEED", "0"),
+    ("SOURCE_DATE_EPOCH", "315532800"),
+    ("TZ", "UTC"),
+];
+
+/// Variables the sandbox sets for the command.
+pub fn overrides(spec: &SandboxSpec) -> Vec<(&'static str, String)> {
+    let mut vars = vec![("HOME", "/tmp".to_string()), ("TMPDIR", "/tmp".to_string())];
+    if spec.deterministic {
+        vars.extend(DETERMINISTIC.iter().map(|(k, v)| (*k, v.to_string())));
+    }
+    if let Some(proxy) = &spec.proxy {
+        for var in ["HTTP_PROXY", "HTTPS_PROXY", "http_proxy", "https_proxy"] {
+            vars.push((var, proxy.url()));
+        }
+    }
+    vars
+}
+
+/// One variable of the command's environment, value included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Presented {
+    pub name: String,
+    pub value: String,
+    pub source: &'static str,
+}
+
+/// The environment `host` turns into with `overrides` set, in name order.
+pub fn presented(
+    host: impl IntoIterator<Item = (String, String)>,
+    overrides: &[(&'static str, String)],
+) -> Vec<Presented> {
+    let mut vars: std::collections::BTreeMap<String, Presented> = host
+        .into_iter()
+        .map(|(name, value)| {
+            let p = Presented {
+                name: name.clone(),
+                value,
+                source: INHERITED,
+            };
+            (name, p)
+        })
+        .collect();
+    for (name, value) in overrides {
+        let source = if vars.contains_key(*name) {
+            OVERRIDDEN
+        } else {
+            SET
+        };
+        vars.insert(
+            name.to_string(),
+            Presented {
+                name: name.to_string(),
+                value: value.clone(),
+                source,
+            },
+        );
+    }
+    vars.into_values().collect()
+}
+
+/// The consumer's environment, as the command inherits it.
+pub fn host() -> Vec<(String, String)> {
+    std::env::vars_os()
+        .map(|(k, v)| {
+            (
+                k.to_string_lossy().into_owned(),
+                v.to_string_lossy().into_owned(),
+            )
+        })
+        .collect()
+}
+
+/// The result's view of `vars`: values only for names matching one of
+/// `values`, and the digest of the listing in order, `NAME=VALUE\0` for a
+/// recorded value and `NAME\0` for a withheld one.
+pub fn snapshot(vars: &[Presented], values: &[String]) -> EnvSnapshot {
+    use sha2::{Digest, Sha256};
+    let vars: Vec<EnvVar> = vars
+        .iter()
+        .map(|v| EnvVar {
+            name: v.name.clone(),
+            value: values
+                .iter()
+                .any(|p| pat_matches(&v.name, p))
+                .then(|| v.value.clone()),
+            source: v.source.to_string(),
+        })
+        .collect();
+    let mut h = Sha256::new();
+    for v in &vars {
+        h.update(v.name.as_bytes());
+        if let Some(value) = &v.value {
+            h.update(b"=");
+            h.update(value.as_bytes());
+        }
+        h.update(b"\0");
+    }
+    EnvSnapshot {
+        vars,
+        sha256: h.finalize().iter().map(|b| format!("{:02x}", b)).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presented_and_snapshot() {
+        let host = [
+            ("PATH", "/usr/bin"),
+            ("HOME", "/root"),
+            ("API_TOKEN", "s3cret"),
+        ]
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        let proxy =
+            crate::sandbox::egress::EgressProxy::start(vec![], vec![], Default::default(), None)
+                .unwrap();
+        let spec = SandboxSpec {
+            proxy: Some(proxy.attach()),
+            ..Default::default()
+        };
+        let vars = presented(host, &overrides(&spec));
+        let by_name = |n: &str| vars.iter().find(|v| v.name == n).unwrap();
+        assert_eq!(vars.len(), 8);
+        assert_eq!(vars[0].name, "API_TOKEN");
+        assert_eq!(
+            (by_name("HOME").source, by_name("HOME").value.as_str()),
+            (OVERRIDDEN, "/tmp")
+        );
+        assert_eq!(by_name("TMPDIR").source, SET);
+        assert_eq!(by_name("PATH").source, INHERITED);
+        let pinned = overrides(&SandboxSpec {
+            deterministic: true,
+            ..Default::default()
+        });
+        assert!(pinned.contains(&("TZ", "UTC".to_string())));
+
+        let snap = snapshot(&vars, &["PATH".into(), "*_PROXY".into()]);
+        let by_name = |n: &str| snap.vars.iter().find(|v| v.name == n).unwrap();
+        assert_eq!(by_name("PATH").value.as_deref(), Some("/usr/bin"));
+        assert_eq!(by_name("HTTPS_PROXY").value, Some(proxy.attach().url()));
+        assert_eq!(by_name("API_TOKEN").value, None);
+        assert_eq!(snap.sha256.len(), 64);
+        // A withheld secret is not in the digest; a recorded value is.
+        let mut other = vars.clone();
+        other[0].value = "other".into();
+        let again = snapshot(&other, &["PATH".into(), "*_PROXY".into()]);
+        assert_eq!(again, snap);
+        let path = other.iter_mut().find(|v| v.name == "PATH").unwrap();
+        path.value = "/bin".into();
+        let again = snapshot(&other, &["PATH".into(), "*_PROXY".into()]);
+        assert_ne!(again.sha256, snap.sha256);
+    }
+}
@@ -0,0 +1,145 @@
+//! A minimal init for the sandbox's PID namespace.
+//!
+//! The command used to be PID 1 of its namespace. Everything it left behind
+//! was reparented to it, and few commands wait for children they did not
+//! start, so orphans stayed zombies until teardown. Between fork and exec
+//! the child now forks once more: the command runs as PID 2 and PID 1 stays
+//! behind as a reaper. It waits for every process in the namespace and
+//! forwards SIGTERM to the command. Once the command is gone it sends the
+//! command's wait status and the number of orphans it reaped over a pipe,
+//! then exits with the command's status, which tears the namespace down.
+
+use nix::libc;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// What PID 1 saw of the run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    /// The command's raw wait status.
+    pub status: i32,
+    /// Processes other than the command that PID 1 reaped.
+    pub orphans_reaped: u32,
+}
+
+/// Our end of the report pipe and, until spawn, the child's.
+pub struct Reaper {
+    ours: OwnedFd,
+    theirs: Option<OwnedFd>,
+}
+
+impl Reaper {
+    pub fn new() -> io::Result<Self> {
+        let (ours, theirs) =
+            nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC).map_err(io::Error::from)?;
+        Ok(Self {
+            ours,
+            theirs: Some(theirs),
+        })
+    }
+
+    /// Descriptor to hand to [`become_init`]; -1 once [`Reaper::spawned`].
+    pub fn report_fd(&self) -> RawFd {
+        self.theirs.as_ref().map_or(-1, |fd| fd.as_raw_fd())
+    }
+
+    /// Drop our copy of the child's end, so that [`Reaper::finish`] sees EOF
+    /// once PID 1 is gone.
+    pub fn spawned(&mut self) {
+        self.theirs.take();
+    }
+
+    /// The report, once PID 1 has exited; `None` when it was killed first or
+    /// the child was not PID 1 and never forked.
+    pub fn finish(self) -> Option<Report> {
+        let mut buf = [0u8; 8];
+        let mut file = std::fs::File::from(self.ours);
+        io::Read::read_exact(&mut file, &mut buf).ok()?;
+        Some(Report {
+            status: i32::from_ne_bytes(buf[..4].try_into().ok()?),
+            orphans_reaped: u32::from_ne_bytes(buf[4..].try_into().ok()?),
+        })
+    }
+}
+
+static COMMAND: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward(sig: libc::c_int) {
+    let pid = COMMAND.load(Ordering::Relaxed);
+    if pid > 0 {
+        unsafe { libc::kill(pid, sig) };
+    }
+}
+
+/// Child side, between fork and exec. As PID 1 of a namespace, fork: the
+/// new process returns to go on to exec the command, this one reaps until
+/// the command exits and never returns. Anywhere else this is a no-op.
+pub fn become_init(report_fd: RawFd) -> io::Result<()> {
+    if report_fd < 0 || unsafe { libc::getpid() } != 1 {
+        return Ok(());
+    }
+    unsafe {
+        // Held until the handler knows where to forward to.
+        let mut term: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut term);
+        libc::sigaddset(&mut term, libc::SIGTERM);
+        libc::sigprocmask(libc::SIG_BLOCK, &term, std::ptr::null_mut());
+        let pid = libc::fork();
+        if pid < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if pid == 0 {
+            libc::sigprocmask(libc::SIG_UNBLOCK, &term, std::ptr::null_mut());
+            return Ok(());
+        }
+        COMMAND.store(pid, Ordering::Relaxed);
+        libc::signal(
+            libc::SIGTERM,
+            forward as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+        libc::sigprocmask(libc::SIG_UNBLOCK, &term, std::ptr::null_mut());
+        // Keep nothing the command's readers wait on, the spawn error pipe
+        // included: only the report pipe stays open.
+        libc::syscall(libc::SYS_close_range, 0, report_fd - 1, 0);
+        libc::syscall(libc::SYS_close_range, report_fd + 1, u32::MAX, 0);
+        reap(pid, report_fd)
+    }
+}
+
+fn reap(command: libc::pid_t, report_fd: RawFd) -> ! {
+    let mut status = 0;
+    let mut orphans_reaped = 0u32;
+    loop {
+        let mut st = 0;
+        let pid = unsafe { libc::waitpid(-1, &mut st, 0) };
+        if pid == command {
+            status = st;
+            break;
+        }
+        if pid > 0 {
+            orphans_reaped += 1;
+        } else if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            break;
+        }
+    }
+    // Zombies already queued; the rest die with the namespace.
+    let mut st = 0;
+    while unsafe { libc::waitpid(-1, &mut st, libc::WNOHANG) } > 0 {
+        orphans_reaped += 1;
+    }
+    let mut buf = [0u8; 8];
+    buf[..4].copy_from_slice(&status.to_ne_bytes());
+    buf[4..].copy_from_slice(&orphans_reaped.to_ne_bytes());
+    unsafe {
+        libc::write(report_fd, buf.as_ptr().cast(), buf.len());
+        // Signals cannot end an init from inside its namespace: report them
+        // shell-style, the real status is in the report.
+        let code = if libc::WIFEXITED(status) {
+            libc::WEXITSTATUS(status)
+        } else {
+            128 + libc::WTERMSIG(status)
+        };
+        libc::_exit(code)
+    }
+}
@@ -0,0 +1,235 @@
+//! Files the command wrote, read back from the overlay scratch.
+//!
+//! With the overlay root on, every write lands in one of two places: the
+//! overlay upperdir (paths outside `/tmp`) or the `/tmp` tmpfs. The tmpfs
+//! lives in the child's mount namespace and disappears with it, so the child
+//! sends open descriptors for both directories to the parent over a socket
+//! ([`send_dirs`] / [`recv_dirs`]); the parent walks them once the command
+//! has finished ([`ScratchDirs::scan`]).
+
+use crate::schema::{FileChange, FileChangeKind};
+use std::path::{Path, PathBuf};
+
+/// At most this many entries are kept per run.
+pub const FILE_MANIFEST_CAP: usize = 256;
+
+/// Changes in `upper` (relative to `lower`, reported under `/`) and in `tmp`
+/// (all new, reported under `/tmp`), sorted by path and capped at
+/// [`FILE_MANIFEST_CAP`].
+pub fn scan(upper: &Path, lower: &Path, tmp: &Path) -> Vec<FileChange> {
+    let mut out = Vec::new();
+    walk(upper, Path::new(""), &mut |rel, meta| {
+        let path = Path::new("/").join(rel);
+        if is_whiteout(meta) {
+            out.push(FileChange {
+                path: path.to_string_lossy().into_owned(),
+                change: FileChangeKind::Deleted,
+                size: 0,
+                sha256: String::new(),
+            });
+        } else if meta.is_file() {
+            let change = if lower.join(rel).exists() {
+                FileChangeKind::Modified
+            } else {
+                FileChangeKind::Created
+            };
+            out.push(entry(&upper.join(rel), path, change, meta.len()));
+        }
+        out.len() < FILE_MANIFEST_CAP
+    });
+    if out.len() < FILE_MANIFEST_CAP {
+        walk(tmp, Path::new(""), &mut |rel, meta| {
+            if meta.is_file() {
+                let path = Path::new("/tmp").join(rel);
+                out.push(entry(
+                    &tmp.join(rel),
+                    path,
+                    FileChangeKind::Created,
+                    meta.len(),
+                ));
+            }
+            out.len() < FILE_MANIFEST_CAP
+        });
+    }
+    out
+}
+
+fn entry(file: &Path, path: PathBuf, change: FileChangeKind, size: u64) -> FileChange {
+    FileChange {
+        path: path.to_string_lossy().into_owned(),
+        change,
+        size,
+        sha256: sha256_file(file).unwrap_or_default(),
+    }
+}
+
+pub(crate) fn sha256_file(path: &Path) -> std::io::Result<String> {
+    use sha2::{Digest, Sha256};
+    let mut h = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut h)?;
+    Ok(h.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Visit everything below `root` in path order without following symlinks;
+/// stops as soon as `visit` returns false.
+fn walk(root: &Path, rel: &Path, visit: &mut dyn FnMut(&Path, &std::fs::Metadata) -> bool) -> bool {
+    let Ok(entries) = std::fs::read_dir(root.join(rel)) else {
+        return true;
+    };
+    let mut names: Vec<_> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name())
+        .collect();
+    names.sort();
+    for name in names {
+        let rel = rel.join(name);
+        let Ok(meta) = std::fs::symlink_metadata(root.join(&rel)) else {
+            continue;
+        };
+        if !visit(&rel, &meta) {
+            return false;
+        }
+        if meta.is_dir() && !walk(root, &rel, visit) {
+            return false;
+        }
+    }
+    true
+}
+
+/// overlayfs records a deletion as a 0:0 character device.
+#[cfg(unix)]
+fn is_whiteout(meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    meta.file_type().is_char_device() && meta.rdev() == 0
+}
+
+#[cfg(not(unix))]
+fn is_whiteout(_meta: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// The child's upperdir and `/tmp`, held open by the parent.
+#[cfg(all(target_os = "linux", feature = "linux_native"))]
+pub struct ScratchDirs {
+    upper: std::os::fd::OwnedFd,
+    tmp: std::os::fd::OwnedFd,
+}
+
+#[cfg(all(target_os = "linux", feature = "linux_native"))]
+impl ScratchDirs {
+    /// See [`scan`]; `lower` is the directory the overlay was built on.
+    pub fn scan(&self, lower: &Path) -> Vec<FileChange> {
+        use std::os::fd::AsRawFd;
+        let fd_path =
+            |fd: &std::os::fd::OwnedFd| PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd()));
+        scan(&fd_path(&self.upper), lower, &fd_path(&self.tmp))
+    }
+
+    /// The child's `/tmp`.
+    pub fn tmp(&self) -> PathBuf {
+        use std::os::fd::AsRawFd;
+        PathBuf::from(format!("/proc/self/fd/{}", self.tmp.as_raw_fd()))
+    }
+}
+
+/// Child side: send descriptors for `upper` and `tmp` over `sock`.
+#[cfg(all(target_os = "linux", feature = "linux_native"))]
+pub fn send_dirs(sock: std::os::fd::RawFd, upper: &Path, tmp: &Path) -> std::io::Result<()> {
+    use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags};
+    use std::os::fd::AsRawFd;
+    let upper = std::fs::File::open(upper)?;
+    let tmp = std::fs::File::open(tmp)?;
+    let fds = [upper.as_raw_fd(), tmp.as_raw_fd()];
+    sendmsg::<()>(
+        sock,
+        &[std::io::IoSlice::new(b"d")],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )?;
+    Ok(())
+}
+
+/// Parent side: the descriptors sent by [`send_dirs`], if the child sent
+/// them before it exec'd.
+#[cfg(all(target_os = "linux", feature = "linux_native"))]
+pub fn recv_dirs(sock: &std::os::unix::net::UnixDatagram) -> Option<ScratchDirs> {
+    use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    let mut buf = [0u8; 1];
+    let mut iov = [std::io::IoSliceMut::new(&mut buf)];
+    let mut cmsg = nix::cmsg_space!([std::os::fd::RawFd; 2]);
+    let msg = recvmsg::<()>(
+        sock.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg),
+        MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_CMSG_CLOEXEC,
+    )
+    .ok()?;
+    for c in msg.cmsgs().ok()? {
+        if let ControlMessageOwned::ScmRights(fds) = c {
+            // Take ownership first so nothing leaks on a short message.
+            let mut owned: Vec<OwnedFd> = fds
+                .into_iter()
+                .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+                .collect();
+            if owned.len() == 2 {
+                let tmp = owned.pop()?;
+                let upper = owned.pop()?;
+                return Some(ScratchDirs { upper, tmp });
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_scratch() {
+        let base = std::env::temp_dir().join(format!("mr_manifest_{}", std::process::id()));
+        let (upper, lower, tmp) = (base.join("upper"), base.join("lower"), base.join("tmp"));
+        for d in [&upper.join("out"), &lower.join("out"), &tmp.join("cache")] {
+            std::fs::create_dir_all(d).unwrap();
+        }
+        std::fs::write(lower.join("out/old.txt"), b"old").unwrap();
+        std::fs::write(upper.join("out/old.txt"), b"new").unwrap();
+        std::fs::write(upper.join("out/report.json"), b"{}").unwrap();
+        std::fs::write(tmp.join("cache/blob"), b"abc").unwrap();
+
+        let found = scan(&upper, &lower, &tmp);
+        let _ = std::fs::remove_dir_all(&base);
+        let summary: Vec<_> = found
+            .iter()
+            .map(|f| (f.path.as_str(), f.change, f.size))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("/out/old.txt", FileChangeKind::Modified, 3),
+                ("/out/report.json", FileChangeKind::Created, 2),
+                ("/tmp/cache/blob", FileChangeKind::Created, 3),
+            ]
+        );
+        assert_eq!(
+            found[2].sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_scan_caps_entries() {
+        let base = std::env::temp_dir().join(format!("mr_manifest_cap_{}", std::process::id()));
+        let tmp = base.join("tmp");
+        std::fs::create_dir_all(&tmp).unwrap();
+        for i in 0..FILE_MANIFEST_CAP + 5 {
+            std::fs::write(tmp.join(format!("f{i:04}")), b"").unwrap();
+        }
+        let found = scan(&base.join("upper"), &base.join("lower"), &tmp);
+        let _ = std::fs::remove_dir_all(&base);
+        assert_eq!(found.len(), FILE_MANIFEST_CAP);
+        assert_eq!(found[0].path, "/tmp/f0000");
+    }
+}
@@ -0,0 +1,139 @@
+//! Connection log (`observe.net: log`).
+//!
+//! Inside the network namespace the command has no route out, so every
+//! `connect` fails; the log says what it tried to reach. The command runs
+//! under `strace -f -e trace=connect` writing to a pipe, and
+//! [`parse_connects`] turns the trace into [`NetConnection`]s.
+
+use crate::schema::NetConnection;
+use std::collections::HashMap;
+
+/// At most this many connections are kept per run.
+pub const NET_LOG_CAP: usize = 100;
+
+/// Connections to IPv4/IPv6 addresses in `strace -f` output, in call order,
+/// capped at [`NET_LOG_CAP`]. Unix sockets are not network activity and are
+/// left out.
+pub fn parse_connects(trace: &str) -> Vec<NetConnection> {
+    let mut out = Vec::new();
+    // Blocking calls are split into `<unfinished ...>` and `<... resumed>`
+    // lines; the address is on the first, the return value on the second.
+    let mut pending: HashMap<&str, usize> = HashMap::new();
+    for line in trace.lines() {
+        let (pid, call) = split_pid(line);
+        if let Some(rest) = call.strip_prefix("<... connect resumed>") {
+            if let Some(i) = pending.remove(pid) {
+                set_outcome(&mut out[i], rest);
+            }
+            continue;
+        }
+        let Some(args) = call.strip_prefix("connect(") else {
+            continue;
+        };
+        let Some((ip, port)) = inet_address(args) else {
+            continue;
+        };
+        if out.len() >= NET_LOG_CAP {
+            break;
+        }
+        out.push(NetConnection {
+            ip,
+            port,
+            allowed: false,
+            error: String::new(),
+        });
+        if args.contains("<unfinished") {
+            pending.insert(pid, out.len() - 1);
+        } else {
+            set_outcome(out.last_mut().unwrap(), args);
+        }
+    }
+    out
+}
+
+/// `"1234  connect(..."` → (`"1234"`, `"connect(..."`); lines of the traced
+/// process itself may have no pid.
+fn split_pid(line: &str) -> (&str, &str) {
+    let line = line.trim_start();
+    match line.split_once(char::is_whitespace) {
+        Some((pid, rest)) if pid.chars().all(|c| c.is_ascii_digit()) => (pid, rest.trim_start()),
+        _ => ("", line),
+    }
+}
+
+fn inet_address(args: &str) -> Option<(String, u16)> {
+    let quoted_after = |key: &str| {
+        let at = args.find(key)? + key.len();
+        let rest = &args[at..];
+        let start = rest.find('"')? + 1;
+        let len = rest[start..].find('"')?;
+        Some(rest[start..start + len].to_string())
+    };
+    let port_after = |key: &str| {
+        let at = args.find(key)? + key.len();
+        let rest = &args[at..];
+        rest[..rest.find(')')?].parse().ok()
+    };
+    if args.contains("sa_family=AF_INET6") {
+        Some((
+            quoted_after("inet_pton(AF_INET6,")?,
+            port_after("sin6_port=htons(")?,
+        ))
+    } else if args.contains("sa_family=AF_INET") {
+        Some((
+            quoted_after("sin_addr=inet_addr(")?,
+            port_after("sin_port=htons(")?,
+        ))
+    } else {
+        None
+    }
+}
+
+/// Record the return value at the end of `tail` (`... = 0`,
+/// `... = -1 ENETUNREACH (Network is unreachable)`).
+fn set_outcome(conn: &mut NetConnection, tail: &str) {
+    let Some((_, ret)) = tail.rsplit_once(") = ") else {
+        return;
+    };
+    let mut words = ret.split_whitespace();
+    let code = words.next().unwrap_or("");
+    let errno = words.next().unwrap_or("");
+    // A non-blocking connect that is under way was let through.
+    conn.allowed = code == "0" || errno == "EINPROGRESS";
+    if !conn.allowed {
+        conn.error = errno.to_string();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_connects() {
+        let trace = r#"4101  connect(3, {sa_family=AF_UNIX, sun_path="/var/run/nscd/socket"}, 110) = -1 ENOENT (No such file or directory)
+4101  connect(3, {sa_family=AF_INET, sin_port=htons(53), sin_addr=inet_addr("10.0.0.2")}, 16) = -1 ENETUNREACH (Network is unreachable)
+4102  connect(4, {sa_family=AF_INET6, sin6_port=htons(443), sin6_flowinfo=htonl(0), inet_pton(AF_INET6, "2606:2800::1", &sin6_addr), sin6_scope_id=0}, 28 <unfinished ...>
+4101  connect(5, {sa_family=AF_INET, sin_port=htons(8080), sin_addr=inet_addr("127.0.0.1")}, 16) = -1 EINPROGRESS (Operation now in progress)
+4102  <... connect resumed>) = -1 ENETUNREACH (Network is unreachable)
+"#;
+        let log = parse_connects(trace);
+        assert_eq!(log.len(), 3);
+        assert_eq!(
+            log[0],
+            NetConnection {
+                ip: "10.0.0.2".into(),
+                port: 53,
+                allowed: false,
+                error: "ENETUNREACH".into(),
+            }
+        );
+        assert_eq!((log[1].ip.as_str(), log[1].port), ("2606:2800::1", 443));
+        assert_eq!(log[1].error, "ENETUNREACH");
+        assert!(log[2].allowed);
+
+        let many = "connect(3, {sa_family=AF_INET, sin_port=htons(1), sin_addr=inet_addr(\"1.1.1.1\")}, 16) = 0\n"
+            .repeat(NET_LOG_CAP + 5);
+        assert_eq!(parse_connects(&many).len(), NET_LOG_CAP);
+    }
+}
@@ -0,0 +1,179 @@
+//! Telling a command that ran out of memory from one that failed.
+//!
+//! `limits.memory_mb` is enforced as `RLIMIT_AS`, and the OOM killer of the
+//! host or of the consumer's cgroup may step in first. Neither shows in the
+//! exit status: the first surfaces as an allocation failure the command
+//! reports itself, the second as a plain SIGKILL. [`diagnose`] weighs:
+//! - an `oom_kill` counted in the consumer's cgroup `memory.events` during
+//!   the run ([`OomEvents`]), the command having been killed;
+//! - a SIGKILL with the largest peak address space of the command's
+//!   processes (`VmPeak`) within [`NEAR_LIMIT_PCT`] % of the limit;
+//! - a failed run whose stderr ends in an allocation failure
+//!   (`Cannot allocate memory`, `MemoryError`, `std::bad_alloc`, ...) while a
+//!   limit was in force.
+//!
+//! A match turns the run's reason into `memory_limit`, with a
+//! [`MEMORY_LIMIT`] finding saying which evidence it rests on.
+
+use super::TerminationReason;
+use crate::schema::Finding;
+use std::path::PathBuf;
+
+/// Finding kind of a run stopped by its memory limit.
+pub const MEMORY_LIMIT: &str = "memory_limit";
+
+/// A SIGKILL counts as the memory limit from this share of it, in percent.
+pub const NEAR_LIMIT_PCT: u64 = 90;
+
+/// Messages of failed allocations, lowercase.
+const ALLOCATION_FAILED: &[&str] = &[
+    "cannot allocate memory",
+    "out of memory",
+    "memoryerror",
+    "bad_alloc",
+    "memory allocation of",
+    "failed to allocate",
+    "allocation failed",
+];
+
+/// Stderr bytes searched, from the end.
+const STDERR_TAIL: usize = 4096;
+
+const SIGKILL: i32 = 9;
+
+/// OOM kills in the consumer's cgroup, counted from when a run started.
+#[derive(Debug)]
+pub struct OomEvents {
+    path: Option<PathBuf>,
+    before: u64,
+}
+
+impl OomEvents {
+    /// Count the kills so far; without cgroup v2 nothing is counted.
+    pub fn start() -> Self {
+        let path = std::fs::read_to_string("/proc/self/cgroup")
+            .ok()
+            .and_then(|c| crate::concurrency::cgroup_dir(&c))
+            .map(|dir| PathBuf::from(format!("/sys/fs/cgroup{}/memory.events", dir)));
+        let before = path.as_ref().and_then(read_oom_kills).unwrap_or(0);
+        Self { path, before }
+    }
+
+    /// Whether the OOM killer struck since [`OomEvents::start`].
+    pub fn fired(&self) -> bool {
+        self.path
+            .as_ref()
+            .and_then(read_oom_kills)
+            .is_some_and(|n| n > self.before)
+    }
+}
+
+fn read_oom_kills(path: &PathBuf) -> Option<u64> {
+    oom_kills(&std::fs::read_to_string(path).ok()?)
+}
+
+/// `oom_kill` of a `memory.events`.
+fn oom_kills(events: &str) -> Option<u64> {
+    events
+        .lines()
+        .find_map(|l| l.strip_prefix("oom_kill ")?.trim().parse().ok())
+}
+
+/// The finding when a command that ended with `reason` and `exit_code`
+/// ran out of memory. `memory_mb` is the limit enforced (0 for none),
+/// `vm_peak_kb` the largest `VmPeak` sampled (0 if unknown) and `oom_killed`
+/// what [`OomEvents::fired`] said.
+pub fn diagnose(
+    reason: &TerminationReason,
+    exit_code: i32,
+    stderr: &[u8],
+    memory_mb: u64,
+    vm_peak_kb: u64,
+    oom_killed: bool,
+) -> Option<Finding> {
+    let killed = match reason {
+        TerminationReason::Signalled(sig) => *sig == SIGKILL,
+        // A shell reports its child's SIGKILL this way.
+        TerminationReason::Completed => exit_code == 128 + SIGKILL,
+        _ => return None,
+    };
+    let finding = |detail: String| Finding {
+        kind: MEMORY_LIMIT.to_string(),
+        detail,
+        count: 1,
+    };
+    if killed && oom_killed {
+        return Some(finding(
+            "killed by the OOM killer (oom_kill in memory.events)".into(),
+        ));
+    }
+    if memory_mb == 0 {
+        return None;
+    }
+    let limit_kb = memory_mb * 1024;
+    if killed && vm_peak_kb * 100 >= limit_kb * NEAR_LIMIT_PCT {
+        return Some(finding(format!(
+            "killed by SIGKILL at {}% of limits.memory_mb ({} MiB)",
+            vm_peak_kb * 100 / limit_kb,
+            memory_mb
+        )));
+    }
+    if exit_code == 0 {
+        return None;
+    }
+    let tail = &stderr[stderr.len().saturating_sub(STDERR_TAIL)..];
+    let tail = String::from_utf8_lossy(tail);
+    let line = tail.lines().rev().find(|l| {
+        let l = l.to_ascii_lowercase();
+        ALLOCATION_FAILED.iter().any(|m| l.contains(m))
+    })?;
+    Some(finding(format!(
+        "allocation failed under limits.memory_mb ({} MiB): {}",
+        memory_mb,
+        line.trim().chars().take(120).collect::<String>()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oom_kills() {
+        let events = "low 0\nhigh 0\nmax 12\noom 3\noom_kill 2\noom_group_kill 0\n";
+        assert_eq!(oom_kills(events), Some(2));
+        assert_eq!(oom_kills("low 0\n"), None);
+    }
+
+    #[test]
+    fn test_diagnose() {
+        let killed = TerminationReason::Signalled(SIGKILL);
+        assert!(diagnose(&killed, 137, b"", 0, 0, true)
+            .unwrap()
+            .detail
+            .contains("OOM killer"));
+        let near = diagnose(&killed, 137, b"", 100, 95 * 1024, false).unwrap();
+        assert_eq!(near.kind, MEMORY_LIMIT);
+        assert!(near.detail.contains("95%"), "{}", near.detail);
+        // Killed far from the limit: someone else's doing.
+        assert!(diagnose(&killed, 137, b"", 100, 10 * 1024, false).is_none());
+
+        let failed = TerminationReason::Completed;
+        let stderr = b"loading\nTraceback (most recent call last):\nMemoryError\n";
+        let alloc = diagnose(&failed, 1, stderr, 64, 0, false).unwrap();
+        assert!(alloc.detail.ends_with("MemoryError"), "{}", alloc.detail);
+        // No limit in force, or nothing failed.
+        assert!(diagnose(&failed, 1, stderr, 0, 0, false).is_none());
+        assert!(diagnose(&failed, 0, stderr, 64, 0, false).is_none());
+        assert!(diagnose(&failed, 1, b"no such file", 64, 0, false).is_none());
+        assert!(diagnose(
+            &TerminationReason::WallTimeout,
+            137,
+            b"",
+            64,
+            64 * 1024,
+            true
+        )
+        .is_none());
+    }
+}
@@ -0,0 +1,250 @@
+//! The programs a command line runs, resolved and hashed.
+//!
+//! The command is split the way the shell would split it into simple
+//! commands (on unquoted `|`, `;`, `&`, parentheses, backticks and newlines).
+//! The first word of each one, past `VAR=value` assignments and wrappers
+//! such as `env` or `nohup`, is resolved against `PATH` under the sandbox
+//! root, like `execvp` would. Shell builtins and keywords resolve to
+//! nothing and are left out. Each program is recorded with its sha256 and,
+//! when `dpkg` or `rpm` knows the file, the package it came from.
+
+use super::manifest::sha256_file;
+use super::shell::{split_words, Shell};
+use crate::schema::Executable;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Default `PATH` of the sandboxed command.
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Words that run the next word as the program.
+const WRAPPERS: &[&str] = &["env", "exec", "nohup", "time", "command", "nice"];
+
+/// Builtins and keywords, run by the shell itself even when a binary of
+/// the same name is on `PATH`.
+const BUILTINS: &[&str] = &[
+    "!", ".", ":", "[", "[[", "alias", "break", "case", "cd", "continue", "do", "done", "echo",
+    "elif", "else", "esac", "eval", "exit", "export", "false", "fi", "for", "function", "if", "in",
+    "local", "printf", "pwd", "read", "return", "set", "shift", "source", "test", "then", "trap",
+    "true", "type", "ulimit", "umask", "unset", "until", "wait", "while", "{", "}",
+];
+
+/// Programs `cmd` names, in order of appearance and without repeats. Under
+/// a shell, the interpreter comes first.
+pub fn programs(cmd: &str, shell: Shell) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    let mut push = |p: &str| {
+        if !out.iter().any(|o| o == p) {
+            out.push(p.to_string());
+        }
+    };
+    if shell == Shell::Argv {
+        if let Some(p) = split_words(cmd).first() {
+            push(p);
+        }
+        return out;
+    }
+    push(shell.program().0);
+    for segment in simple_commands(cmd) {
+        let words = split_words(&segment);
+        let first = words.iter().find(|w| {
+            let assignment = w.split_once('=').is_some_and(|(k, _)| {
+                !k.is_empty() && k.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            });
+            !assignment && !WRAPPERS.contains(&w.as_str()) && !w.starts_with('-')
+        });
+        if let Some(p) = first.filter(|p| !BUILTINS.contains(&p.as_str())) {
+            push(p);
+        }
+    }
+    out
+}
+
+/// `cmd` cut at unquoted command separators.
+fn simple_commands(cmd: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut cur = String::new();
+    let mut quote: Option<char> = None;
+    let mut chars = cmd.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => {
+                quote = None;
+                cur.push(c);
+            }
+            (Some(_), _) => cur.push(c),
+            (None, '\\') => {
+                cur.push(c);
+                if let Some(n) = chars.next() {
+                    cur.push(n);
+                }
+            }
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                cur.push(c);
+            }
+            // `$(` starts a command substitution; `$VAR` is a word.
+            (None, '$') if chars.peek() == Some(&'(') => {
+                out.push(std::mem::take(&mut cur));
+            }
+            (None, '|' | ';' | '&' | '(' | ')' | '`' | '\n') => {
+                out.push(std::mem::take(&mut cur));
+            }
+            (None, _) => cur.push(c),
+        }
+    }
+    out.push(cur);
+    out.retain(|s| !s.trim().is_empty());
+    out
+}
+
+/// Where `program` is under `root`, searching `path` when it has no slash.
+/// The returned path is as the command sees it (relative to `root`).
+pub fn resolve(program: &str, path: &str, root: &Path) -> Option<PathBuf> {
+    let executable = |p: &Path| {
+        use std::os::unix::fs::PermissionsExt;
+        let rel = p.strip_prefix("/").unwrap_or(p);
+        std::fs::metadata(root.join(rel))
+            .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+    };
+    if program.contains('/') {
+        let p = PathBuf::from(program);
+        return (p.is_absolute() && executable(&p)).then_some(p);
+    }
+    path.split(':')
+        .filter(|d| d.starts_with('/'))
+        .map(|d| Path::new(d).join(program))
+        .find(|p| executable(p))
+}
+
+/// The package that installed `path`, from `dpkg -S` or `rpm -qf`; `None`
+/// when neither is installed or neither knows the file.
+pub fn package_owner(path: &Path) -> Option<String> {
+    let mut candidates = vec![path.to_path_buf()];
+    // Merged-/usr systems register files under their old /bin, /sbin and
+    // /lib paths.
+    if let Ok(rest) = path.strip_prefix("/usr") {
+        if ["bin", "sbin", "lib"].iter().any(|d| rest.starts_with(d)) {
+            candidates.push(Path::new("/").join(rest));
+        }
+    }
+    let query = |prog: &str, args: &[&str], p: &Path| {
+        let out = std::process::Command::new(prog)
+            .args(args)
+            .arg(p)
+            .stderr(std::process::Stdio::null())
+            .output()
+            .ok()
+            .filter(|o| o.status.success())?;
+        let text = String::from_utf8_lossy(&out.stdout);
+        let line = text.lines().next()?.trim();
+        Some(line.to_string()).filter(|l| !l.is_empty())
+    };
+    candidates.iter().find_map(|p| {
+        query("dpkg", &["-S"], p)
+            .and_then(|l| l.split_once(": ").map(|(pkg, _)| pkg.to_string()))
+            .or_else(|| query("rpm", &["-qf"], p))
+    })
+}
+
+/// Resolve and hash the programs of `cmd` (see [`programs`]) under `root`;
+/// those not found or not readable are left out.
+pub fn collect(cmd: &str, shell: Shell, path: &str, root: &Path) -> Vec<Executable> {
+    let mut out: Vec<Executable> = Vec::new();
+    for name in programs(cmd, shell) {
+        let Some(p) = resolve(&name, path, root) else {
+            continue;
+        };
+        if out.iter().any(|e| Path::new(&e.path) == p) {
+            continue;
+        }
+        let host = root.join(p.strip_prefix("/").unwrap_or(&p));
+        let Ok(sha256) = sha256_file(&host) else {
+            continue;
+        };
+        out.push(Executable {
+            name,
+            sha256,
+            package: package_owner(&host).unwrap_or_default(),
+            path: p.to_string_lossy().into_owned(),
+        });
+    }
+    out
+}
+
+/// The digests listed in `path`, one per line in `sha256sum` format
+/// (`<hex>  <file>`, or just `<hex>`); `#` starts a comment. A line that
+/// is not a sha256 is an error.
+pub fn read_digests(path: &Path) -> std::io::Result<HashSet<String>> {
+    let text = std::fs::read_to_string(path)?;
+    let mut out = HashSet::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let Some(hex) = line.split_whitespace().next() else {
+            continue;
+        };
+        if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("line {}: not a sha256: {}", i + 1, hex),
+            ));
+        }
+        out.insert(hex.to_ascii_lowercase());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_programs() {
+        assert_eq!(
+            programs(
+                r#"FOO=1 env -i curl "a|b" | grep -v x; cd $HOME && (nohup ./run.sh &) $(date)"#,
+                Shell::Sh
+            ),
+            ["sh", "curl", "grep", "./run.sh", "date"]
+        );
+        assert_eq!(programs("echo 'ls; rm'", Shell::Bash), ["bash"]);
+        assert_eq!(programs("python3 -c 'a; b'", Shell::Argv), ["python3"]);
+    }
+
+    #[test]
+    fn test_collect_under_root() {
+        use std::os::unix::fs::PermissionsExt;
+        let root = std::env::temp_dir().join(format!("mr_provenance_{}", std::process::id()));
+        let bin = root.join("opt/bin");
+        std::fs::create_dir_all(&bin).unwrap();
+        std::fs::write(bin.join("tool"), b"hi").unwrap();
+        std::fs::set_permissions(bin.join("tool"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(bin.join("data"), b"x").unwrap();
+
+        assert_eq!(resolve("data", "/opt/bin", &root), None);
+        let found = collect("tool x | tool y; data", Shell::Argv, "/opt/bin", &root);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].name, "tool");
+        assert_eq!(found[0].path, "/opt/bin/tool");
+        assert_eq!(
+            found[0].sha256,
+            "8f434346648f6b96df89dda901c5176b10a6d83961dd3c1ac88b59b2dc327aa4"
+        );
+        assert_eq!(found[0].package, "");
+
+        let list = root.join("approved.sha256");
+        let digest = &found[0].sha256;
+        std::fs::write(
+            &list,
+            format!("# tools\n{}  /opt/bin/tool\n\n", digest.to_uppercase()),
+        )
+        .unwrap();
+        assert!(read_digests(&list).unwrap().contains(digest));
+        std::fs::write(&list, "abc /opt/bin/tool\n").unwrap();
+        assert_eq!(
+            read_digests(&list).unwrap_err().to_string(),
+            "line 1: not a sha256: abc"
+        );
+        std::fs::remove_dir_all(&root).ok();
+    }
+}
@@ -0,0 +1,291 @@
+//! Terminal plumbing for `exec --interactive`.
+//!
+//! The command gets the slave side of a fresh PTY as its stdin, stdout and
+//! stderr, and becomes the session leader with that PTY as its controlling
+//! terminal, so line editing, job control signals and full-screen programs
+//! behave as in a login shell. The caller's terminal is switched to raw mode
+//! for the duration and bytes are relayed both ways; what the command
+//! writes is also kept, so it can be graded like captured output. Resizing
+//! the caller's terminal resizes the PTY, which sends the command SIGWINCH.
+//!
+//! With `pty: true` (policy or request) the command gets a PTY nobody is
+//! looking at: [`CAPTURE_SIZE`], no echo of its input and `\n` left alone
+//! rather than written as `\r\n`, so tools that only talk to terminals
+//! behave while the captured output reads like a log.
+
+use nix::pty::{openpty, Winsize};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::termios::{
+    cfmakeraw, tcgetattr, tcsetattr, LocalFlags, OutputFlags, SetArg, Termios,
+};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd, RawFd};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Rows and columns of a PTY whose output is only captured.
+pub const CAPTURE_SIZE: (u16, u16) = (24, 80);
+
+/// End-of-file for a terminal in canonical mode (`VEOF`, ^D).
+const EOF: u8 = 0x04;
+
+/// Both ends of a PTY sized like the caller's terminal.
+pub struct Pty {
+    pub master: OwnedFd,
+    pub slave: OwnedFd,
+}
+
+impl Pty {
+    pub fn open() -> io::Result<Self> {
+        let mut ws: Winsize = unsafe { std::mem::zeroed() };
+        // Not a terminal: keep the kernel's default size.
+        let sized = unsafe { nix::libc::ioctl(0, nix::libc::TIOCGWINSZ, &mut ws) } == 0;
+        let pty = openpty(sized.then_some(&ws), None).map_err(io::Error::from)?;
+        Ok(Self {
+            master: pty.master,
+            slave: pty.slave,
+        })
+    }
+
+    /// A PTY for `pty: true`: [`CAPTURE_SIZE`], no echo, no `\r\n`.
+    pub fn open_captured() -> io::Result<Self> {
+        let ws = Winsize {
+            ws_row: CAPTURE_SIZE.0,
+            ws_col: CAPTURE_SIZE.1,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let pty = openpty(Some(&ws), None).map_err(io::Error::from)?;
+        let mut t = tcgetattr(pty.slave.as_fd()).map_err(io::Error::from)?;
+        t.local_flags.remove(LocalFlags::ECHO);
+        t.output_flags.remove(OutputFlags::ONLCR);
+        tcsetattr(pty.slave.as_fd(), SetArg::TCSANOW, &t).map_err(io::Error::from)?;
+        Ok(Self {
+            master: pty.master,
+            slave: pty.slave,
+        })
+    }
+
+    /// The slave as the command's stdin, stdout and stderr.
+    pub fn stdio(&self) -> io::Result<(Stdio, Stdio, Stdio)> {
+        Ok((
+            self.slave.try_clone()?.into(),
+            self.slave.try_clone()?.into(),
+            self.slave.try_clone()?.into(),
+        ))
+    }
+
+    /// Drop our copy of the slave (the child has its own) and hand the
+    /// master to a [`Relay`].
+    pub fn relay(self) -> Relay {
+        drop(self.slave);
+        Relay {
+            master: std::fs::File::from(self.master),
+            attached: true,
+            stdin_open: true,
+            master_open: true,
+            kept: Vec::new(),
+            input: Vec::new(),
+            _winch: Winch::install(),
+        }
+    }
+
+    /// Drop our copy of the slave and hand the master to a [`Relay`] that
+    /// leaves the caller's terminal alone: it types `stdin` and then
+    /// end-of-file, and keeps what the command writes.
+    pub fn capture(self, stdin: &[u8]) -> Relay {
+        drop(self.slave);
+        // Typed as the command reads, so a large stdin cannot stall the
+        // wait loop.
+        let _ = nix::fcntl::fcntl(
+            self.master.as_raw_fd(),
+            nix::fcntl::FcntlArg::F_SETFL(nix::fcntl::OFlag::O_NONBLOCK),
+        );
+        let mut input = stdin.to_vec();
+        // ^D ends a partial line; at the start of one, it reads as EOF.
+        if !input.is_empty() && !input.ends_with(b"\n") {
+            input.push(EOF);
+        }
+        input.push(EOF);
+        Relay {
+            master: std::fs::File::from(self.master),
+            attached: false,
+            stdin_open: false,
+            master_open: true,
+            kept: Vec::new(),
+            input,
+            _winch: None,
+        }
+    }
+}
+
+static RESIZED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_winch(_: nix::libc::c_int) {
+    RESIZED.store(true, Ordering::Relaxed);
+}
+
+/// Our SIGWINCH handler, until dropped.
+struct Winch(SigAction);
+
+impl Winch {
+    fn install() -> Option<Self> {
+        let ours = SigAction::new(
+            SigHandler::Handler(on_winch),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        RESIZED.store(false, Ordering::Relaxed);
+        unsafe { sigaction(Signal::SIGWINCH, &ours) }.ok().map(Self)
+    }
+}
+
+impl Drop for Winch {
+    fn drop(&mut self) {
+        let _ = unsafe { sigaction(Signal::SIGWINCH, &self.0) };
+    }
+}
+
+/// Give `to` the window size of `from`; the kernel signals the resize to
+/// the foreground process group of `to`.
+fn copy_size(from: RawFd, to: RawFd) {
+    let mut ws: Winsize = unsafe { std::mem::zeroed() };
+    unsafe {
+        if nix::libc::ioctl(from, nix::libc::TIOCGWINSZ, &mut ws) == 0 {
+            nix::libc::ioctl(to, nix::libc::TIOCSWINSZ, &ws);
+        }
+    }
+}
+
+/// Moves bytes between the caller's terminal and the master from the
+/// sandbox's wait loop. No threads: once the sandbox has unshared its PID
+/// namespace the process cannot create any.
+pub struct Relay {
+    master: std::fs::File,
+    /// Relaying to and from the caller's terminal.
+    attached: bool,
+    stdin_open: bool,
+    master_open: bool,
+    kept: Vec<u8>,
+    /// Still to be typed into the PTY.
+    input: Vec<u8>,
+    _winch: Option<Winch>,
+}
+
+impl Relay {
+    /// Relay whatever is ready within `wait`.
+    pub fn pump(&mut self, wait: Duration) {
+        use nix::libc::{poll, pollfd, POLLERR, POLLHUP, POLLIN, POLLOUT};
+        if !self.master_open {
+            std::thread::sleep(wait);
+            return;
+        }
+        if self.attached && RESIZED.swap(false, Ordering::Relaxed) {
+            copy_size(0, self.master.as_raw_fd());
+        }
+        let typing = if self.input.is_empty() { 0 } else { POLLOUT };
+        let mut fds = [
+            pollfd {
+                fd: self.master.as_raw_fd(),
+                events: POLLIN | typing,
+                revents: 0,
+            },
+            pollfd {
+                fd: if self.stdin_open { 0 } else { -1 },
+                events: POLLIN,
+                revents: 0,
+            },
+        ];
+        if unsafe { poll(fds.as_mut_ptr(), 2, wait.as_millis() as i32) } <= 0 {
+            return;
+        }
+        let mut buf = [0u8; 4096];
+        if fds[0].revents & (POLLIN | POLLHUP | POLLERR) != 0 {
+            // The master reads EIO once the last slave descriptor closes.
+            match self.master.read(&mut buf) {
+                Ok(n) if n > 0 => {
+                    if self.attached {
+                        let mut out = io::stdout();
+                        let _ = out.write_all(&buf[..n]);
+                        let _ = out.flush();
+                    }
+                    self.kept.extend_from_slice(&buf[..n]);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                _ => self.master_open = false,
+            }
+        }
+        if fds[0].revents & POLLOUT != 0 {
+            match self.master.write(&self.input) {
+                Ok(n) => {
+                    self.input.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(_) => self.input.clear(),
+            }
+        }
+        if fds[1].revents != 0 {
+            // Unbuffered: bytes left in `Stdin`'s buffer would not wake poll.
+            match nix::unistd::read(0, &mut buf) {
+                Ok(n) if n > 0 => {
+                    let _ = self.master.write_all(&buf[..n]);
+                }
+                _ => self.stdin_open = false,
+            }
+        }
+    }
+
+    /// Everything the command wrote, after relaying what is still queued.
+    pub fn finish(mut self) -> Vec<u8> {
+        let mut quiet = 0;
+        while self.master_open && quiet < 4 {
+            let before = self.kept.len();
+            self.pump(Duration::from_millis(25));
+            quiet = if self.kept.len() == before {
+                quiet + 1
+            } else {
+                0
+            };
+        }
+        self.kept
+    }
+}
+
+/// Child side, between fork and exec: start a session and take the PTY on
+/// stdin as the controlling terminal.
+pub fn become_session_leader() -> io::Result<()> {
+    unsafe {
+        if nix::libc::setsid() < 0 || nix::libc::ioctl(0, nix::libc::TIOCSCTTY, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// The caller's terminal in raw mode until dropped; a no-op when stdin is
+/// not a terminal.
+pub struct RawMode(Option<Termios>);
+
+impl RawMode {
+    pub fn enable() -> Self {
+        let stdin = io::stdin();
+        let Ok(saved) = tcgetattr(stdin.as_fd()) else {
+            return Self(None);
+        };
+        let mut raw = saved.clone();
+        cfmakeraw(&mut raw);
+        match tcsetattr(stdin.as_fd(), SetArg::TCSANOW, &raw) {
+            Ok(()) => Self(Some(saved)),
+            Err(_) => Self(None),
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Some(saved) = &self.0 {
+            let _ = tcsetattr(io::stdin().as_fd(), SetArg::TCSANOW, saved);
+        }
+    }
+}
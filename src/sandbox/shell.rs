@@ -0,0 +1,191 @@
+//! The interpreter a spell's `cmd` is handed to.
+//!
+//! The sandbox used to run every command with `bash -lc`, which fails on
+//! hosts without bash (Alpine, distroless images, Windows). The policy may
+//! pin one with `exec.shell`; otherwise the platform default is used: bash
+//! when it is on `PATH`, `sh` otherwise, and `cmd.exe` on Windows. `argv`
+//! skips the interpreter altogether and executes the command's first word
+//! with the rest as arguments.
+
+use std::process::Command;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    /// `bash -lc <cmd>`.
+    Bash,
+    /// `sh -c <cmd>`.
+    Sh,
+    /// `cmd.exe /C <cmd>`.
+    Cmd,
+    /// `powershell -NoProfile -NonInteractive -Command <cmd>`.
+    PowerShell,
+    /// No interpreter: `cmd` split into words, quotes honoured.
+    Argv,
+}
+
+impl Shell {
+    /// Name recorded in the result's `shell` field and accepted by
+    /// `exec.shell`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Shell::Bash => "bash",
+            Shell::Sh => "sh",
+            Shell::Cmd => "cmd",
+            Shell::PowerShell => "powershell",
+            Shell::Argv => "argv",
+        }
+    }
+
+    /// The shell to use on this host when the policy does not name one.
+    pub fn detect() -> Self {
+        if cfg!(windows) {
+            Shell::Cmd
+        } else if on_path("bash") {
+            Shell::Bash
+        } else {
+            Shell::Sh
+        }
+    }
+
+    /// `preferred`, or [`Shell::detect`] when unset.
+    pub fn resolve(preferred: Option<Shell>) -> Self {
+        preferred.unwrap_or_else(Self::detect)
+    }
+
+    /// Program and leading arguments, before the command itself.
+    pub fn program(&self) -> (&'static str, &'static [&'static str]) {
+        match self {
+            Shell::Bash => ("bash", &["-lc"]),
+            Shell::Sh => ("sh", &["-c"]),
+            Shell::Cmd => ("cmd.exe", &["/C"]),
+            Shell::PowerShell => ("powershell", &["-NoProfile", "-NonInteractive", "-Command"]),
+            Shell::Argv => ("", &[]),
+        }
+    }
+
+    /// Full argv for `cmd`; empty when `Argv` is given a blank command.
+    pub fn argv(&self, cmd: &str) -> Vec<String> {
+        match self {
+            Shell::Argv => split_words(cmd),
+            _ => {
+                let (prog, args) = self.program();
+                let mut v: Vec<String> = std::iter::once(prog)
+                    .chain(args.iter().copied())
+                    .map(String::from)
+                    .collect();
+                v.push(cmd.to_string());
+                v
+            }
+        }
+    }
+
+    /// A `Command` running `cmd`; its program is empty (and fails to
+    /// spawn) when `Argv` is given a blank command.
+    pub fn command(&self, cmd: &str) -> Command {
+        let argv = self.argv(cmd);
+        let mut c = Command::new(argv.first().map(String::as_str).unwrap_or(""));
+        c.args(&argv[argv.len().min(1)..]);
+        c
+    }
+}
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "bash" => Ok(Shell::Bash),
+            "sh" => Ok(Shell::Sh),
+            "cmd" | "cmd.exe" => Ok(Shell::Cmd),
+            "powershell" | "pwsh" => Ok(Shell::PowerShell),
+            "argv" | "none" => Ok(Shell::Argv),
+            other => Err(format!("unknown shell: {}", other)),
+        }
+    }
+}
+
+fn on_path(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .map(|paths| std::env::split_paths(&paths).any(|d| d.join(program).is_file()))
+        .unwrap_or(false)
+}
+
+/// Split on unquoted whitespace. Single quotes keep everything literal;
+/// inside double quotes and outside quotes a backslash escapes the next
+/// character.
+pub(crate) fn split_words(cmd: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut cur = String::new();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+    let mut chars = cmd.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), _) => cur.push(c),
+            (_, '\\') => {
+                if let Some(n) = chars.next() {
+                    cur.push(n);
+                }
+                in_word = true;
+            }
+            (Some(_), _) => cur.push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut cur));
+                    in_word = false;
+                }
+            }
+            (None, _) => {
+                cur.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(cur);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_argv_per_shell() {
+        assert_eq!(Shell::Bash.argv("echo hi"), ["bash", "-lc", "echo hi"]);
+        assert_eq!(Shell::Sh.argv("echo hi"), ["sh", "-c", "echo hi"]);
+        assert_eq!(Shell::Cmd.argv("dir"), ["cmd.exe", "/C", "dir"]);
+        assert_eq!(
+            Shell::Argv.argv(r#"python3 -c 'print("a b")' "x y" z\ w"#),
+            ["python3", "-c", r#"print("a b")"#, "x y", "z w"]
+        );
+        assert!(Shell::Argv.argv("   ").is_empty());
+        assert_eq!(Shell::Argv.argv("a ''"), ["a", ""]);
+    }
+
+    #[test]
+    fn test_parse_and_resolve() {
+        assert_eq!("PowerShell".parse::<Shell>(), Ok(Shell::PowerShell));
+        assert_eq!("cmd.exe".parse::<Shell>(), Ok(Shell::Cmd));
+        assert!("zsh".parse::<Shell>().is_err());
+        assert_eq!(Shell::resolve(Some(Shell::Sh)), Shell::Sh);
+        #[cfg(unix)]
+        assert_ne!(Shell::detect(), Shell::Cmd);
+    }
+
+    #[test]
+    fn test_command_runs() {
+        #[cfg(unix)]
+        for shell in [Shell::Sh, Shell::Argv] {
+            let out = shell.command("echo 'a  b'").output().unwrap();
+            assert_eq!(out.stdout, b"a  b\n");
+        }
+    }
+}
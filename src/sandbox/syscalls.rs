@@ -0,0 +1,224 @@
+//! Per-category syscall counts (`observe.syscalls: summary`).
+//!
+//! The command runs under `strace -f -c` (see [`trace`](super::trace)),
+//! which writes a per-syscall table when it exits. [`split_summary`] finds
+//! the table at the end of strace's output and [`summarize`] folds it into
+//! a [`SyscallSummary`].
+
+use crate::schema::SyscallSummary;
+
+/// Whether `strace` can be run on this host.
+pub fn strace_available() -> bool {
+    std::process::Command::new("strace")
+        .arg("-V")
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false)
+}
+
+/// Split captured stderr into the child's own output and strace's table
+/// (from its last `% time` header on); `None` when there is no table.
+pub fn split_summary(stderr: &[u8]) -> Option<(Vec<u8>, String)> {
+    // Searched as bytes: the child's own output need not be UTF-8.
+    let at = stderr.windows(6).rposition(|w| w == b"% time")?;
+    let line_start = stderr[..at]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    let table = String::from_utf8_lossy(&stderr[line_start..]).into_owned();
+    Some((stderr[..line_start].to_vec(), table))
+}
+
+/// Per-syscall call counts from an `strace -c` table.
+pub fn parse_table(table: &str) -> Vec<(String, u64)> {
+    table
+        .lines()
+        .filter_map(|line| {
+            let cols: Vec<&str> = line.split_whitespace().collect();
+            // % time, seconds, usecs/call, calls, [errors,] syscall
+            if cols.len() < 5 || !cols[0].chars().all(|c| c.is_ascii_digit() || c == '.') {
+                return None;
+            }
+            let name = *cols.last()?;
+            if name == "total" {
+                return None;
+            }
+            Some((name.to_string(), cols[3].parse().ok()?))
+        })
+        .collect()
+}
+
+const FILE: &[&str] = &[
+    "read",
+    "write",
+    "pread64",
+    "pwrite64",
+    "readv",
+    "writev",
+    "open",
+    "openat",
+    "openat2",
+    "creat",
+    "close",
+    "close_range",
+    "stat",
+    "fstat",
+    "lstat",
+    "newfstatat",
+    "statx",
+    "lseek",
+    "access",
+    "faccessat",
+    "faccessat2",
+    "getdents",
+    "getdents64",
+    "mkdir",
+    "mkdirat",
+    "rmdir",
+    "unlink",
+    "unlinkat",
+    "rename",
+    "renameat",
+    "renameat2",
+    "link",
+    "linkat",
+    "symlink",
+    "symlinkat",
+    "readlink",
+    "readlinkat",
+    "chmod",
+    "fchmod",
+    "fchmodat",
+    "chown",
+    "fchown",
+    "fchownat",
+    "lchown",
+    "truncate",
+    "ftruncate",
+    "fsync",
+    "fdatasync",
+    "dup",
+    "dup2",
+    "dup3",
+    "fcntl",
+    "ioctl",
+    "pipe",
+    "pipe2",
+    "chdir",
+    "fchdir",
+    "getcwd",
+    "statfs",
+    "fstatfs",
+    "utimensat",
+    "sendfile",
+    "copy_file_range",
+    "mmap",
+    "munmap",
+];
+
+const NET: &[&str] = &[
+    "socket",
+    "socketpair",
+    "connect",
+    "bind",
+    "listen",
+    "accept",
+    "accept4",
+    "sendto",
+    "recvfrom",
+    "sendmsg",
+    "recvmsg",
+    "sendmmsg",
+    "recvmmsg",
+    "shutdown",
+    "getsockopt",
+    "setsockopt",
+    "getsockname",
+    "getpeername",
+];
+
+const PROCESS: &[&str] = &[
+    "clone",
+    "clone3",
+    "fork",
+    "vfork",
+    "execve",
+    "execveat",
+    "exit",
+    "exit_group",
+    "wait4",
+    "waitid",
+    "kill",
+    "tkill",
+    "tgkill",
+    "ptrace",
+    "prctl",
+    "setsid",
+    "setpgid",
+    "getpid",
+    "getppid",
+    "gettid",
+    "setuid",
+    "setgid",
+    "setresuid",
+    "setresgid",
+    "unshare",
+    "setns",
+    "prlimit64",
+    "setrlimit",
+    "getrlimit",
+];
+
+/// Fold per-syscall counts into file / net / process / other.
+pub fn summarize(counts: &[(String, u64)]) -> SyscallSummary {
+    let mut s = SyscallSummary::default();
+    for (name, n) in counts {
+        let slot = if FILE.contains(&name.as_str()) {
+            &mut s.file
+        } else if NET.contains(&name.as_str()) {
+            &mut s.net
+        } else if PROCESS.contains(&name.as_str()) {
+            &mut s.process
+        } else {
+            &mut s.other
+        };
+        *slot += n;
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_from_strace_table() {
+        let stderr = b"ls: cannot access 'x'\n\
+% time     seconds  usecs/call     calls    errors syscall\n\
+------ ----------- ----------- --------- --------- ----------------\n\
+ 40.00    0.000040           4        10           read\n\
+ 20.00    0.000020          10         2         1 openat\n\
+ 10.00    0.000010           5         2         2 connect\n\
+ 10.00    0.000010          10         1           execve\n\
+  0.00    0.000000           0         3           rt_sigaction\n\
+------ ----------- ----------- --------- --------- ----------------\n\
+100.00    0.000100           5        18         3 total\n";
+        let (own, table) = split_summary(stderr).unwrap();
+        assert_eq!(own, b"ls: cannot access 'x'\n");
+        let counts = parse_table(&table);
+        assert_eq!(counts.len(), 5);
+        assert_eq!(
+            summarize(&counts),
+            SyscallSummary {
+                file: 12,
+                net: 2,
+                process: 1,
+                other: 3,
+            }
+        );
+        assert!(split_summary(b"plain stderr\n").is_none());
+    }
+}
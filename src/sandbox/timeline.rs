@@ -0,0 +1,252 @@
+//! Interleaved output (policy `observe.output: timeline`).
+//!
+//! Captured stdout and stderr say what a command wrote, not in which order:
+//! a script that races a background job against its own error messages
+//! looks the same either way. With the timeline on, the sandbox reads both
+//! pipes as data arrives and records each read as a [`Chunk`] stamped with
+//! the time since spawn. The result gets the chunks as JSONL, one
+//! `{"t_us":..,"stream":"stdout"|"stderr","b64":..}` per line; beyond
+//! [`OUTPUT_CAP`] the JSONL is kept as `timeline.jsonl` in the run's
+//! quarantine directory and the result names the file instead.
+//!
+//! Order between the streams is the order the reads saw, which is the order
+//! of the writes as long as the command does not outpace a 64 KiB read.
+//! Linux native only, and not with a terminal attached: a PTY merges the
+//! streams before anyone can tell them apart.
+
+use crate::schema::{OutputTimeline, OUTPUT_CAP};
+use std::path::Path;
+
+/// File name of a timeline kept beside the result.
+pub const FILE: &str = "timeline.jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/// One read from one of the command's pipes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    /// Microseconds since the command was spawned.
+    pub t_us: u64,
+    pub stream: Stream,
+    pub data: Vec<u8>,
+}
+
+/// The chunks of a run, in the order they were read.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Timeline {
+    pub chunks: Vec<Chunk>,
+}
+
+impl Timeline {
+    /// Everything written to `stream`, as plain capture would have it.
+    pub fn bytes(&self, stream: Stream) -> Vec<u8> {
+        self.chunks
+            .iter()
+            .filter(|c| c.stream == stream)
+            .flat_map(|c| c.data.iter().copied())
+            .collect()
+    }
+
+    pub fn to_jsonl(&self) -> Vec<u8> {
+        use base64::Engine as _;
+        let b64 = &base64::engine::general_purpose::STANDARD;
+        let mut out = Vec::new();
+        for c in &self.chunks {
+            let line = serde_json::json!({
+                "t_us": c.t_us,
+                "stream": c.stream.as_str(),
+                "b64": b64.encode(&c.data),
+            });
+            out.extend_from_slice(line.to_string().as_bytes());
+            out.push(b'\n');
+        }
+        out
+    }
+
+    /// The result's view of the timeline: inline up to [`OUTPUT_CAP`], else
+    /// written to [`FILE`] under `dir`.
+    pub fn keep(&self, dir: Option<&Path>) -> OutputTimeline {
+        use base64::Engine as _;
+        let jsonl = self.to_jsonl();
+        let mut kept = OutputTimeline {
+            chunks: self.chunks.len() as u64,
+            bytes: jsonl.len() as u64,
+            sha256: sha256_hex(&jsonl),
+            ..Default::default()
+        };
+        if jsonl.len() <= OUTPUT_CAP {
+            kept.jsonl_b64 = base64::engine::general_purpose::STANDARD.encode(&jsonl);
+            return kept;
+        }
+        let Some(dir) = dir else {
+            return kept;
+        };
+        let to = dir.join(FILE);
+        match std::fs::create_dir_all(dir).and_then(|()| std::fs::write(&to, &jsonl)) {
+            Ok(()) => kept.path = to.display().to_string(),
+            Err(e) => tracing::warn!(error = %e, path = %to.display(), "output timeline not kept"),
+        }
+        kept
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Reads the command's stdout and stderr from the sandbox's wait loop. No
+/// threads: once the sandbox has unshared its PID namespace the process
+/// cannot create any.
+#[cfg(all(target_os = "linux", feature = "linux_native"))]
+pub struct Capture {
+    /// Stdout and stderr, each until it reads end-of-file.
+    pipes: [Option<std::fs::File>; 2],
+    spawned: std::time::Instant,
+    timeline: Timeline,
+}
+
+#[cfg(all(target_os = "linux", feature = "linux_native"))]
+impl Capture {
+    pub fn new(
+        stdout: std::process::ChildStdout,
+        stderr: std::process::ChildStderr,
+        spawned: std::time::Instant,
+    ) -> Self {
+        let file = |fd: std::os::fd::OwnedFd| Some(std::fs::File::from(fd));
+        Self {
+            pipes: [file(stdout.into()), file(stderr.into())],
+            spawned,
+            timeline: Timeline::default(),
+        }
+    }
+
+    /// Read whatever is ready within `wait`.
+    pub fn pump(&mut self, wait: std::time::Duration) {
+        use nix::libc::{poll, pollfd, POLLIN};
+        use std::io::Read;
+        use std::os::fd::AsRawFd;
+        if self.pipes.iter().all(Option::is_none) {
+            std::thread::sleep(wait);
+            return;
+        }
+        let mut fds = self.pipes.each_ref().map(|p| pollfd {
+            fd: p.as_ref().map_or(-1, |f| f.as_raw_fd()),
+            events: POLLIN,
+            revents: 0,
+        });
+        if unsafe { poll(fds.as_mut_ptr(), 2, wait.as_millis() as i32) } <= 0 {
+            return;
+        }
+        let mut buf = vec![0u8; 64 * 1024];
+        for (i, stream) in [Stream::Stdout, Stream::Stderr].into_iter().enumerate() {
+            if fds[i].revents == 0 {
+                continue;
+            }
+            let Some(pipe) = self.pipes[i].as_mut() else {
+                continue;
+            };
+            match pipe.read(&mut buf) {
+                Ok(n) if n > 0 => self.timeline.chunks.push(Chunk {
+                    t_us: self.spawned.elapsed().as_micros() as u64,
+                    stream,
+                    data: buf[..n].to_vec(),
+                }),
+                _ => self.pipes[i] = None,
+            }
+        }
+    }
+
+    /// Read what is still queued after the command ended. Stops once both
+    /// pipes are closed, or quiet for 100 ms when something the command
+    /// left behind holds them open.
+    pub fn finish(mut self) -> Timeline {
+        let mut quiet = 0;
+        while self.pipes.iter().any(Option::is_some) && quiet < 4 {
+            let before = self.timeline.chunks.len();
+            self.pump(std::time::Duration::from_millis(25));
+            quiet = if self.timeline.chunks.len() == before {
+                quiet + 1
+            } else {
+                0
+            };
+        }
+        self.timeline
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(t_us: u64, stream: Stream, data: &[u8]) -> Chunk {
+        Chunk {
+            t_us,
+            stream,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_jsonl_and_streams() {
+        let t = Timeline {
+            chunks: vec![
+                chunk(10, Stream::Stdout, b"a\n"),
+                chunk(25, Stream::Stderr, b"oops\n"),
+                chunk(40, Stream::Stdout, b"b\n"),
+            ],
+        };
+        assert_eq!(t.bytes(Stream::Stdout), b"a\nb\n");
+        assert_eq!(t.bytes(Stream::Stderr), b"oops\n");
+        let jsonl = String::from_utf8(t.to_jsonl()).unwrap();
+        let lines: Vec<serde_json::Value> = jsonl
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1]["t_us"], 25);
+        assert_eq!(lines[1]["stream"], "stderr");
+        assert_eq!(lines[1]["b64"], "b29wcwo=");
+    }
+
+    #[test]
+    fn test_keep_inline_then_as_file() {
+        let small = Timeline {
+            chunks: vec![chunk(1, Stream::Stdout, b"hi")],
+        };
+        let kept = small.keep(None);
+        assert_eq!(kept.chunks, 1);
+        assert!(!kept.jsonl_b64.is_empty());
+        assert!(kept.path.is_empty());
+        assert_eq!(kept.sha256.len(), 64);
+
+        let dir = std::env::temp_dir().join(format!("mr_timeline_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let big = Timeline {
+            chunks: vec![chunk(1, Stream::Stderr, &vec![b'x'; OUTPUT_CAP])],
+        };
+        let kept = big.keep(Some(&dir));
+        assert!(kept.jsonl_b64.is_empty());
+        assert_eq!(kept.path, dir.join(FILE).display().to_string());
+        let on_disk = std::fs::read(dir.join(FILE)).unwrap();
+        assert_eq!(on_disk.len() as u64, kept.bytes);
+        assert_eq!(sha256_hex(&on_disk), kept.sha256);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
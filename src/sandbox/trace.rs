@@ -0,0 +1,155 @@
+//! Running the command under `strace` for the policy's `observe` options.
+//!
+//! [`Tracer`] builds the `strace` command line and collects what it wrote:
+//! the per-syscall table for [`syscalls`](super::syscalls) and the `connect`
+//! calls for [`netlog`](super::netlog). On `linux_native` builds strace
+//! writes to a pipe the child inherits (`-o /proc/self/fd/N`), keeping its
+//! output apart from the child's stderr; elsewhere only the summary is
+//! available and is taken off the end of stderr.
+
+use super::syscalls;
+use crate::schema::{NetConnection, SyscallSummary};
+use std::process::Command;
+
+/// Trace output beyond this is dropped.
+#[cfg(all(target_os = "linux", feature = "linux_native"))]
+const TRACE_CAP: u64 = 4 << 20;
+/// How long to wait for the trace after the child exits; background
+/// processes holding the pipe open must not stall the run.
+#[cfg(all(target_os = "linux", feature = "linux_native"))]
+const TRACE_GRACE: std::time::Duration = std::time::Duration::from_millis(500);
+
+pub struct Tracer {
+    summary: bool,
+    connects: bool,
+    #[cfg(all(target_os = "linux", feature = "linux_native"))]
+    pipe: Option<Pipe>,
+}
+
+#[cfg(all(target_os = "linux", feature = "linux_native"))]
+struct Pipe {
+    read: Option<std::os::fd::OwnedFd>,
+    write: Option<std::os::fd::OwnedFd>,
+    text: Option<std::sync::mpsc::Receiver<String>>,
+}
+
+impl Tracer {
+    /// A tracer for the requested observations, or `None` when none can be
+    /// made. strace's own ptrace/wait calls would be refused by the seccomp
+    /// filter, so nothing is traced under seccomp; the connection log needs
+    /// the pipe and so a `linux_native` build.
+    pub fn new(summary: bool, connects: bool, seccomp: bool) -> Option<Self> {
+        let connects = connects && cfg!(all(target_os = "linux", feature = "linux_native"));
+        if !summary && !connects {
+            return None;
+        }
+        if seccomp {
+            tracing::warn!("trace: strace cannot run under seccomp; observe skipped");
+            return None;
+        }
+        if !syscalls::strace_available() {
+            tracing::warn!("trace: strace not found; observe skipped");
+            return None;
+        }
+        #[allow(unused_mut)]
+        let mut tracer = Self {
+            summary,
+            connects,
+            #[cfg(all(target_os = "linux", feature = "linux_native"))]
+            pipe: None,
+        };
+        #[cfg(all(target_os = "linux", feature = "linux_native"))]
+        {
+            use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+            use std::os::fd::AsRawFd;
+            let (read, write) = match nix::unistd::pipe2(OFlag::O_CLOEXEC) {
+                Ok(p) => p,
+                Err(e) => {
+                    tracing::warn!(error = %e, "trace: pipe failed; observe skipped");
+                    return None;
+                }
+            };
+            // strace opens the write end by path, so it must survive exec.
+            if let Err(e) = fcntl(write.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::empty())) {
+                tracing::warn!(error = %e, "trace: fcntl failed; observe skipped");
+                return None;
+            }
+            tracer.pipe = Some(Pipe {
+                read: Some(read),
+                write: Some(write),
+                text: None,
+            });
+        }
+        Some(tracer)
+    }
+
+    /// `strace ...`, ready for the program and its arguments.
+    pub fn command(&self) -> Command {
+        let mut c = Command::new("strace");
+        c.args(["-f", "-q"]);
+        match (self.summary, self.connects) {
+            // Both: the full trace plus the table.
+            (true, true) => c.arg("-C"),
+            (true, false) => c.arg("-c"),
+            _ => c.args(["--seccomp-bpf", "-e", "trace=connect"]),
+        };
+        #[cfg(all(target_os = "linux", feature = "linux_native"))]
+        if let Some(fd) = self.pipe.as_ref().and_then(|p| p.write.as_ref()) {
+            use std::os::fd::AsRawFd;
+            c.arg("-o").arg(format!("/proc/self/fd/{}", fd.as_raw_fd()));
+        }
+        c
+    }
+
+    /// Call once the child is spawned: drop our copy of the pipe's write end
+    /// and start reading the trace.
+    pub fn started(&mut self) {
+        #[cfg(all(target_os = "linux", feature = "linux_native"))]
+        if let Some(pipe) = self.pipe.as_mut() {
+            use std::io::Read;
+            pipe.write = None;
+            if let Some(read) = pipe.read.take() {
+                let (tx, rx) = std::sync::mpsc::channel();
+                std::thread::spawn(move || {
+                    let mut buf = Vec::new();
+                    let mut f = std::fs::File::from(read);
+                    let _ = f.by_ref().take(TRACE_CAP).read_to_end(&mut buf);
+                    // Keep draining so the tracer never blocks on a full pipe.
+                    let _ = std::io::copy(&mut f, &mut std::io::sink());
+                    let _ = tx.send(String::from_utf8_lossy(&buf).into_owned());
+                });
+                pipe.text = Some(rx);
+            }
+        }
+    }
+
+    /// Split the observations off a finished run: the child's own stderr,
+    /// the syscall summary (if asked for) and the connection log.
+    pub fn finish(self, stderr: Vec<u8>) -> (Vec<u8>, Option<SyscallSummary>, Vec<NetConnection>) {
+        #[cfg(all(target_os = "linux", feature = "linux_native"))]
+        if let Some(rx) = self.pipe.and_then(|p| p.text) {
+            let text = rx.recv_timeout(TRACE_GRACE).unwrap_or_default();
+            let (trace, table) = match syscalls::split_summary(text.as_bytes()) {
+                Some((trace, table)) => (String::from_utf8_lossy(&trace).into_owned(), table),
+                None => (text, String::new()),
+            };
+            let summary = self
+                .summary
+                .then(|| syscalls::summarize(&syscalls::parse_table(&table)));
+            let connects = if self.connects {
+                super::netlog::parse_connects(&trace)
+            } else {
+                Vec::new()
+            };
+            return (stderr, summary, connects);
+        }
+        match syscalls::split_summary(&stderr) {
+            Some((own, table)) if self.summary => (
+                own,
+                Some(syscalls::summarize(&syscalls::parse_table(&table))),
+                Vec::new(),
+            ),
+            _ => (stderr, None, Vec::new()),
+        }
+    }
+}
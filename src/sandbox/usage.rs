@@ -0,0 +1,162 @@
+//! Resource usage of a run, sampled from procfs.
+//!
+//! Wait loops call [`Sampler::poll`] rather than handing this to a thread:
+//! a process that has unshared its PID namespace cannot create any. Every
+//! [`INTERVAL`] the command's process tree is walked and its processes'
+//! `/proc/<pid>/io` (`rchar`/`wchar`: bytes through read and write calls,
+//! whatever the file) and CPU times from `/proc/<pid>/stat` are summed; the
+//! kernel folds reaped children into their parent's figures. The largest
+//! peak address space of a process (`VmPeak` in `/proc/<pid>/status`) is
+//! kept as well, for [`super::oom`]. A process that
+//! exits between two samples takes its last interval with it, so every
+//! figure is a lower bound. Elsewhere than Linux nothing is sampled.
+
+use crate::schema::Usage;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+/// Time between two samples.
+pub const INTERVAL: Duration = Duration::from_millis(100);
+
+/// Unit of the CPU times in `/proc/<pid>/stat`, fixed by the kernel ABI.
+const USER_HZ: u64 = 100;
+
+#[derive(Debug, Default)]
+pub struct Sampler {
+    next: Option<Instant>,
+    pids_peak: u32,
+    read_bytes: u64,
+    write_bytes: u64,
+    cpu_ticks: u64,
+    vm_peak_kb: u64,
+}
+
+impl Sampler {
+    /// Sample the tree under `root` when the interval has elapsed.
+    pub fn poll(&mut self, root: u32) {
+        let now = Instant::now();
+        if self.next.is_some_and(|next| now < next) {
+            return;
+        }
+        self.next = Some(now + INTERVAL);
+        self.sample(root);
+    }
+
+    fn sample(&mut self, root: u32) {
+        let mut tree = HashSet::new();
+        super::audit::collect_tree(root, &mut tree);
+        let (mut read, mut written, mut ticks) = (0, 0, 0);
+        for pid in &tree {
+            if let Ok(io) = std::fs::read_to_string(format!("/proc/{}/io", pid)) {
+                let (r, w) = parse_io(&io);
+                read += r;
+                written += w;
+            }
+            if let Ok(stat) = std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+                ticks += parse_cpu_ticks(&stat).unwrap_or(0);
+            }
+            if let Ok(status) = std::fs::read_to_string(format!("/proc/{}/status", pid)) {
+                let peak = parse_vm_peak_kb(&status).unwrap_or(0);
+                self.vm_peak_kb = self.vm_peak_kb.max(peak);
+            }
+        }
+        self.pids_peak = self.pids_peak.max(tree.len() as u32);
+        self.read_bytes = self.read_bytes.max(read);
+        self.write_bytes = self.write_bytes.max(written);
+        self.cpu_ticks = self.cpu_ticks.max(ticks);
+    }
+
+    /// Most processes seen alive at once.
+    pub fn pids_peak(&self) -> u32 {
+        self.pids_peak
+    }
+
+    /// Largest `VmPeak` of a process seen, in KiB.
+    pub fn vm_peak_kb(&self) -> u64 {
+        self.vm_peak_kb
+    }
+
+    /// What was sampled over a run that lasted `wall`.
+    pub fn usage(&self, wall: Duration) -> Option<Usage> {
+        if !cfg!(target_os = "linux") {
+            return None;
+        }
+        let cpu_ms = self.cpu_ticks * 1000 / USER_HZ;
+        Some(Usage {
+            read_bytes: self.read_bytes,
+            write_bytes: self.write_bytes,
+            cpu_ms,
+            cpu_pct: (cpu_ms * 100 / (wall.as_millis() as u64).max(1)) as u32,
+        })
+    }
+}
+
+/// `rchar` and `wchar` of a `/proc/<pid>/io`.
+fn parse_io(io: &str) -> (u64, u64) {
+    let field = |name: &str| {
+        io.lines()
+            .find_map(|l| l.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(0)
+    };
+    (field("rchar"), field("wchar"))
+}
+
+/// `VmPeak` of a `/proc/<pid>/status`, in KiB.
+fn parse_vm_peak_kb(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|l| l.strip_prefix("VmPeak:"))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// `utime + stime + cutime + cstime` of a `/proc/<pid>/stat`, in ticks.
+/// Fields are counted after the command name, which may hold spaces.
+fn parse_cpu_ticks(stat: &str) -> Option<u64> {
+    let rest = &stat[stat.rfind(')')? + 1..];
+    // `state` is field 3; the times are fields 14 to 17.
+    rest.split_whitespace()
+        .skip(11)
+        .take(4)
+        .map(|f| f.parse::<i64>().ok().map(|t| t.max(0) as u64))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc_files() {
+        let io = "rchar: 4096\nwchar: 12\nsyscr: 3\nsyscw: 1\nread_bytes: 0\nwrite_bytes: 0\n";
+        assert_eq!(parse_io(io), (4096, 12));
+        assert_eq!(parse_io(""), (0, 0));
+        let stat = "42 (a (b) c) S 1 42 42 0 -1 4194304 100 0 0 0 7 3 2 1 20 0 1 0 5 0 0";
+        assert_eq!(parse_cpu_ticks(stat), Some(13));
+        assert_eq!(parse_cpu_ticks("42 (x) S 1"), Some(0));
+        assert_eq!(parse_cpu_ticks("garbage"), None);
+        let status = "Name:\tsh\nVmPeak:\t    8412 kB\nVmSize:\t    8400 kB\n";
+        assert_eq!(parse_vm_peak_kb(status), Some(8412));
+    }
+
+    #[test]
+    fn test_sampler_sees_itself() {
+        let mut s = Sampler::default();
+        s.poll(std::process::id());
+        // Not due again until the interval has passed.
+        let before = s.next;
+        s.poll(std::process::id());
+        assert_eq!(s.next, before);
+        let usage = s.usage(Duration::from_millis(10));
+        #[cfg(target_os = "linux")]
+        {
+            assert!(s.pids_peak() >= 1);
+            assert!(usage.unwrap().read_bytes > 0);
+        }
+        #[cfg(not(target_os = "linux"))]
+        assert!(usage.is_none());
+    }
+}
@@ -0,0 +1,307 @@
+//! Change windows: capabilities a policy only grants at certain times.
+//!
+//! ```yaml
+//! constraints:
+//!   timezone: "+09:00"
+//!   allowed_days: [mon, tue, wed, thu, fri]
+//!   allowed_hours: "09-18"
+//!   applies_to: [net, fs]
+//! ```
+//!
+//! A request that uses one of the `applies_to` capabilities outside the
+//! window is not run: it grades red under the [`OUTSIDE_WINDOW`] rule. Hours
+//! run from the start hour up to, not including, the end hour, and may wrap
+//! past midnight (`"22-06"`); a day is that of the window's start.
+//! The time zone is a fixed UTC offset (`UTC`, `+09:00`, `-0530`), so a
+//! window under daylight saving time has to be moved by hand.
+
+use crate::policy::PolicyError;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Risk rule and `Finding::kind` of a request outside its window.
+pub const OUTSIDE_WINDOW: &str = "outside_window";
+
+const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// What a request uses, as far as windows are concerned.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Uses {
+    /// Network tools or URLs in the command, or a network allowlist.
+    pub net: bool,
+    /// Input files written before the command runs.
+    pub fs: bool,
+    /// Device nodes outside the standard set.
+    pub devices: bool,
+}
+
+/// `constraints` of a policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schedule {
+    /// Minutes east of UTC.
+    pub offset_min: i32,
+    /// Bit `d` set when day `d` (0 = Sunday) is allowed.
+    pub days: u8,
+    /// Allowed hours, start inclusive, end exclusive.
+    pub hours: (u8, u8),
+    /// The capabilities restricted; every run when all are set.
+    pub applies_to: Uses,
+}
+
+impl Schedule {
+    /// Build the schedule from the `constraints` values as written; `None`
+    /// when none is set.
+    pub fn parse<'a>(
+        timezone: Option<&'a str>,
+        days: Option<&'a str>,
+        hours: Option<&'a str>,
+        applies_to: Option<&'a str>,
+    ) -> Result<Option<Self>, PolicyError> {
+        if timezone.is_none() && days.is_none() && hours.is_none() && applies_to.is_none() {
+            return Ok(None);
+        }
+        let unquoted = |v: Option<&'a str>| v.map(|v| v.trim().trim_matches(['"', '\'']));
+        let (timezone, hours) = (unquoted(timezone), unquoted(hours));
+        let fail = |field: &str, v: &str, msg: &str| {
+            PolicyError(format!("constraints.{}: {:?} {}", field, v, msg))
+        };
+        let offset_min = match timezone {
+            None => 0,
+            Some(tz) => parse_offset(tz)
+                .ok_or_else(|| fail("timezone", tz, "is not UTC or an offset like +09:00"))?,
+        };
+        let days = match days {
+            None => 0x7f,
+            Some(v) => list(v).try_fold(0u8, |mask, d| {
+                DAYS.iter()
+                    .position(|n| d.eq_ignore_ascii_case(n))
+                    .map(|i| mask | 1 << i)
+                    .ok_or_else(|| fail("allowed_days", v, "is not a list of mon..sun"))
+            })?,
+        };
+        let hours = match hours {
+            None => (0, 24),
+            Some(v) => v
+                .split_once('-')
+                .and_then(|(a, b)| Some((a.trim().parse().ok()?, b.trim().parse().ok()?)))
+                .filter(|&(a, b): &(u8, u8)| a < 24 && b <= 24 && a != b)
+                .ok_or_else(|| fail("allowed_hours", v, "is not a range like \"09-18\""))?,
+        };
+        let applies_to = match applies_to {
+            None => Uses {
+                net: true,
+                fs: true,
+                devices: true,
+            },
+            Some(v) => list(v).try_fold(Uses::default(), |mut u, c| {
+                match c {
+                    "net" => u.net = true,
+                    "fs" => u.fs = true,
+                    "devices" => u.devices = true,
+                    "all" => {
+                        u = Uses {
+                            net: true,
+                            fs: true,
+                            devices: true,
+                        }
+                    }
+                    _ => {
+                        return Err(fail(
+                            "applies_to",
+                            v,
+                            "is not a list of net, fs, devices, all",
+                        ))
+                    }
+                }
+                Ok(u)
+            })?,
+        };
+        Ok(Some(Self {
+            offset_min,
+            days,
+            hours,
+            applies_to,
+        }))
+    }
+
+    /// Whether `at` falls in the window.
+    pub fn is_open(&self, at: SystemTime) -> bool {
+        let secs = match at.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        };
+        let local_min = secs.div_euclid(60) + self.offset_min as i64;
+        let day = local_min.div_euclid(24 * 60);
+        let hour = (local_min.rem_euclid(24 * 60) / 60) as u8;
+        let (start, end) = self.hours;
+        // 1970-01-01 was a Thursday.
+        let weekday = |day: i64| (day + 4).rem_euclid(7) as u8;
+        let allowed = |day: i64| self.days & (1 << weekday(day)) != 0;
+        if start < end {
+            allowed(day) && (start..end).contains(&hour)
+        } else if hour >= start {
+            allowed(day)
+        } else {
+            // Early hours of a window that started the day before.
+            hour < end && allowed(day - 1)
+        }
+    }
+
+    /// Why a request using `uses` may not run at `at`, if it may not.
+    pub fn violation(&self, uses: Uses, at: SystemTime) -> Option<String> {
+        let restricted: Vec<&str> = [
+            ("net", uses.net && self.applies_to.net),
+            ("fs", uses.fs && self.applies_to.fs),
+            ("devices", uses.devices && self.applies_to.devices),
+        ]
+        .into_iter()
+        .filter_map(|(name, hit)| hit.then_some(name))
+        .collect();
+        if restricted.is_empty() || self.is_open(at) {
+            return None;
+        }
+        Some(format!(
+            "{} outside {}",
+            restricted.join(", "),
+            self.describe()
+        ))
+    }
+
+    /// `mon,tue 09-18 +09:00`.
+    pub fn describe(&self) -> String {
+        let days: Vec<&str> = (0..7)
+            .filter(|d| self.days & (1 << d) != 0)
+            .map(|d| DAYS[d])
+            .collect();
+        let sign = if self.offset_min < 0 { '-' } else { '+' };
+        format!(
+            "{} {:02}-{:02} {}{:02}:{:02}",
+            days.join(","),
+            self.hours.0,
+            self.hours.1,
+            sign,
+            self.offset_min.abs() / 60,
+            self.offset_min.abs() % 60
+        )
+    }
+}
+
+/// Items of a `[a, b]` flow list or a bare `a, b`.
+pub(crate) fn list(v: &str) -> impl Iterator<Item = &str> {
+    v.trim_start_matches('[')
+        .trim_end_matches(']')
+        .split(',')
+        .map(|s| s.trim().trim_matches('"').trim_matches('\''))
+        .filter(|s| !s.is_empty())
+}
+
+/// Minutes east of UTC of `UTC`, `Z`, `+09:00`, `-0530` or `+9`.
+fn parse_offset(tz: &str) -> Option<i32> {
+    if tz.eq_ignore_ascii_case("utc") || tz == "Z" {
+        return Some(0);
+    }
+    let tz = tz.strip_prefix("UTC").unwrap_or(tz);
+    let (sign, rest) = match tz.as_bytes().first()? {
+        b'+' => (1, &tz[1..]),
+        b'-' => (-1, &tz[1..]),
+        _ => return None,
+    };
+    let (h, m) = match rest.split_once(':') {
+        Some((h, m)) => (h, m),
+        None if rest.len() == 4 => rest.split_at(2),
+        None => (rest, "0"),
+    };
+    let (h, m): (i32, i32) = (h.parse().ok()?, m.parse().ok()?);
+    (h <= 14 && m < 60).then_some(sign * (h * 60 + m))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    // 2024-01-01 was a Monday.
+    const MONDAY: u64 = 1_704_067_200;
+    const HOUR: u64 = 3600;
+
+    #[test]
+    fn test_parse() {
+        let s = Schedule::parse(
+            Some("+09:00"),
+            Some("[mon, tue, wed, thu, fri]"),
+            Some("\"09-18\""),
+            Some("[net]"),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(s.offset_min, 540);
+        assert_eq!(s.days, 0b0011_1110);
+        assert_eq!(s.hours, (9, 18));
+        assert!(s.applies_to.net && !s.applies_to.fs);
+        assert_eq!(s.describe(), "mon,tue,wed,thu,fri 09-18 +09:00");
+        assert_eq!(Schedule::parse(None, None, None, None), Ok(None));
+        assert_eq!(parse_offset("-0530"), Some(-330));
+        assert_eq!(parse_offset("UTC+2"), Some(120));
+        assert_eq!(
+            Schedule::parse(Some("Asia/Tokyo"), None, None, None)
+                .unwrap_err()
+                .0,
+            "constraints.timezone: \"Asia/Tokyo\" is not UTC or an offset like +09:00"
+        );
+        assert!(Schedule::parse(None, Some("[funday]"), None, None).is_err());
+        assert!(Schedule::parse(None, None, Some("9-9"), None).is_err());
+        assert!(Schedule::parse(None, None, None, Some("[gpu]")).is_err());
+    }
+
+    #[test]
+    fn test_window() {
+        let s = Schedule::parse(
+            Some("+09:00"),
+            Some("mon, tue, wed, thu, fri"),
+            Some("09-18"),
+            None,
+        )
+        .unwrap()
+        .unwrap();
+        // Monday 00:00 UTC is 09:00 in +09:00.
+        assert!(s.is_open(at(MONDAY)));
+        assert!(!s.is_open(at(MONDAY - 1)));
+        assert!(!s.is_open(at(MONDAY + 9 * HOUR)));
+        // Saturday 10:00 local.
+        assert!(!s.is_open(at(MONDAY + 5 * 24 * HOUR + HOUR)));
+
+        let night = Schedule::parse(None, Some("[fri]"), Some("22-06"), None)
+            .unwrap()
+            .unwrap();
+        let friday = MONDAY + 4 * 24 * HOUR;
+        assert!(night.is_open(at(friday + 23 * HOUR)));
+        assert!(night.is_open(at(friday + 29 * HOUR)));
+        assert!(!night.is_open(at(friday + 30 * HOUR)));
+        assert!(!night.is_open(at(friday + 5 * HOUR)));
+    }
+
+    #[test]
+    fn test_violation() {
+        let s = Schedule::parse(None, None, Some("09-18"), Some("[net, devices]"))
+            .unwrap()
+            .unwrap();
+        let net = Uses {
+            net: true,
+            ..Default::default()
+        };
+        let files = Uses {
+            fs: true,
+            ..Default::default()
+        };
+        let night = at(MONDAY + 2 * HOUR);
+        assert_eq!(
+            s.violation(net, night).as_deref(),
+            Some("net outside sun,mon,tue,wed,thu,fri,sat 09-18 +00:00")
+        );
+        assert_eq!(s.violation(files, night), None);
+        assert_eq!(s.violation(net, at(MONDAY + 10 * HOUR)), None);
+    }
+}
@@ -1,4 +1,10 @@
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+#[cfg(not(feature = "std"))]
+use alloc::{borrow::Cow, collections::BTreeMap, format, string::String, vec::Vec};
+#[cfg(feature = "std")]
+use std::{borrow::Cow, collections::BTreeMap};
 
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct SpellRequest {
@@ -11,26 +17,729 @@ pub struct SpellRequest {
     pub allow_net: Option<Vec<String>>,
     pub allow_fs: Option<Vec<String>>,
     pub seed: Option<u64>,
+    /// POST the result here as well (see `crate::webhook`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback_url: Option<String>,
+    /// Tenant to run under (see `crate::tenant`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Lower resource limits than the policy's for this run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<RequestLimits>,
+    /// Values for `${VAR}` placeholders (see `crate::template`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vars: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Grading rules whose risk the submitter accepts; waived when the
+    /// policy lists them in `grading.waivable`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accept_risk: Option<Vec<RiskAcceptance>>,
+    /// Not worth running after this: a Unix time in ms from
+    /// [`DEADLINE_ABSOLUTE_MS`] on, otherwise ms after the request was
+    /// queued. Consumers answer late requests `expired` (see [`overdue`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline_ms: Option<u64>,
+    /// Run the command on a PTY of its own, for tools that only write
+    /// useful output to a terminal (policy `exec.pty` turns it on for all).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pty: Option<bool>,
+    /// Drop ANSI escape sequences from the captured output (policy
+    /// `exec.strip_ansi` turns it on for all).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strip_ansi: Option<bool>,
+}
+
+/// One `accept_risk` entry: the grading rule (`ssh`, `net_intent`, ...),
+/// why its risk is acceptable, and who signed off on it.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct RiskAcceptance {
+    pub rule: String,
+    pub reason: String,
+    pub approver: String,
+}
+
+/// A request payload parsed once, borrowing from the message bytes.
+///
+/// The consumers read a few fields of every message; this view takes the
+/// strings (file contents above all) straight out of the payload when they
+/// need no unescaping, and leaves `env` and `seed` as raw JSON.
+#[derive(Debug, Deserialize, Default)]
+pub struct RequestView<'a> {
+    #[serde(default, borrow)]
+    pub cmd: Cow<'a, str>,
+    #[serde(default, borrow)]
+    pub stdin: Cow<'a, str>,
+    #[serde(default, borrow)]
+    pub env: Option<&'a RawValue>,
+    #[serde(default, borrow)]
+    pub files: Vec<FileView<'a>>,
+    #[serde(default, borrow)]
+    pub policy_id: Cow<'a, str>,
+    #[serde(default)]
+    pub timeout_sec: u64,
+    #[serde(default, borrow)]
+    pub allow_net: Vec<Cow<'a, str>>,
+    #[serde(default, borrow)]
+    pub allow_fs: Vec<Cow<'a, str>>,
+    #[serde(default, borrow)]
+    pub seed: Option<&'a RawValue>,
+    #[serde(default, borrow)]
+    pub callback_url: Cow<'a, str>,
+    #[serde(default, borrow)]
+    pub tenant: Cow<'a, str>,
+    #[serde(default)]
+    pub accept_risk: Vec<RiskAcceptance>,
+    #[serde(default)]
+    pub deadline_ms: Option<u64>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Default)]
+pub struct FileView<'a> {
+    #[serde(borrow)]
+    pub path: Cow<'a, str>,
+    #[serde(default, borrow)]
+    pub content_b64: Cow<'a, str>,
+}
+
+impl<'a> RequestView<'a> {
+    pub fn parse(payload: &'a [u8]) -> serde_json::Result<Self> {
+        serde_json::from_slice(payload)
+    }
+
+    /// The request seed: 0 when absent, `None` when it is not a `u64`.
+    pub fn seed(&self) -> Option<u64> {
+        match self.seed {
+            None => Some(0),
+            Some(raw) => raw.get().parse().ok(),
+        }
+    }
+}
+
+/// Every way `req` breaks `schemas/spell_request.schema.json`, in field
+/// order; empty when it conforms. Checked by hand, so it needs neither std
+/// nor a JSON Schema engine: the engine runs it after the compiled schema,
+/// and `bindings/wasm` runs it in the browser.
+pub fn request_errors(req: &serde_json::Value) -> Vec<String> {
+    use serde_json::Value;
+    fn is_scalar(v: &Value) -> bool {
+        v.is_string() || v.is_number() || v.is_boolean()
+    }
+    fn strings(v: &Value) -> bool {
+        v.as_array().is_some_and(|a| a.iter().all(Value::is_string))
+    }
+    fn scalars(v: &Value) -> bool {
+        v.as_object().is_some_and(|m| m.values().all(is_scalar))
+    }
+    let mut errors = Vec::new();
+    let mut fail = |msg: String| errors.push(msg);
+    let Some(obj) = req.as_object() else {
+        fail("request must be an object".into());
+        return errors;
+    };
+    let required = [
+        "cmd",
+        "stdin",
+        "env",
+        "files",
+        "policy_id",
+        "timeout_sec",
+        "allow_net",
+        "allow_fs",
+    ];
+    for k in required {
+        if !obj.contains_key(k) {
+            fail(format!("missing key: {}", k));
+        }
+    }
+    for k in ["cmd", "stdin", "policy_id"] {
+        if obj.get(k).is_some_and(|v| !v.is_string()) {
+            fail(format!("{} must be string", k));
+        }
+    }
+    if let Some(env) = obj.get("env") {
+        if !env.is_object() {
+            fail("env must be object".into());
+        } else if !scalars(env) {
+            fail("env values must be string/number/bool".into());
+        }
+    }
+    if let Some(files) = obj.get("files") {
+        match files.as_array() {
+            None => fail("files must be array".into()),
+            Some(files) => {
+                for f in files {
+                    if !f.is_object() {
+                        fail("file entry must be object".into());
+                    } else if !f.get("path").is_some_and(Value::is_string) {
+                        fail("file.path must be string".into());
+                    } else if f.get("content_b64").is_some_and(|c| !c.is_string()) {
+                        fail("file.content_b64 must be string".into());
+                    }
+                }
+            }
+        }
+    }
+    if let Some(t) = obj.get("timeout_sec") {
+        match t.as_u64() {
+            None if t.is_i64() => fail("timeout_sec must be 0..=60".into()),
+            None => fail("timeout_sec must be integer".into()),
+            Some(t) if t > 60 => fail("timeout_sec must be 0..=60".into()),
+            Some(_) => {}
+        }
+    }
+    for k in ["allow_net", "allow_fs"] {
+        if obj.get(k).is_some_and(|v| !strings(v)) {
+            fail(format!("{} must be array of strings", k));
+        }
+    }
+    if let Some(url) = obj.get("callback_url") {
+        let ok = url
+            .as_str()
+            .is_some_and(|u| u.starts_with("http://") || u.starts_with("https://"));
+        if !ok {
+            fail("callback_url must be an http(s) URL".into());
+        }
+    }
+    if let Some(limits) = obj.get("limits") {
+        match limits.as_object() {
+            None => fail("limits must be object".into()),
+            Some(limits) => {
+                for (k, v) in limits {
+                    if !["cpu_ms", "memory_mb", "pids"].contains(&k.as_str()) {
+                        fail(format!("unknown limit: {}", k));
+                    } else if v.as_u64().is_none_or(|n| n == 0) {
+                        fail(format!("limits.{} must be an integer of at least 1", k));
+                    }
+                }
+            }
+        }
+    }
+    if obj.get("vars").is_some_and(|v| !scalars(v)) {
+        fail("vars must be object of string/number/bool".into());
+    }
+    if let Some(accept) = obj.get("accept_risk") {
+        match accept.as_array() {
+            None => fail("accept_risk must be array".into()),
+            Some(accept) => {
+                for a in accept {
+                    let filled = |k: &str| {
+                        a.get(k)
+                            .and_then(Value::as_str)
+                            .is_some_and(|v| !v.is_empty())
+                    };
+                    if !a.is_object() {
+                        fail("accept_risk entry must be object".into());
+                    } else if let Some(k) = ["rule", "reason", "approver"]
+                        .into_iter()
+                        .find(|k| !filled(k))
+                    {
+                        fail(format!("accept_risk.{} must be a non-empty string", k));
+                    }
+                }
+            }
+        }
+    }
+    if obj.get("deadline_ms").is_some_and(|d| d.as_u64().is_none()) {
+        fail("deadline_ms must be a non-negative integer".into());
+    }
+    for k in ["pty", "strip_ansi"] {
+        if obj.get(k).is_some_and(|v| !v.is_boolean()) {
+            fail(format!("{} must be boolean", k));
+        }
+    }
+    if let Some(tenant) = obj.get("tenant") {
+        // Same rule as `crate::tenant::check_name`.
+        let ok = tenant.as_str().is_some_and(|t| {
+            (1..=63).contains(&t.len())
+                && t.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                && t.starts_with(|c: char| c.is_ascii_alphanumeric())
+                && t.ends_with(|c: char| c.is_ascii_alphanumeric())
+        });
+        if !ok {
+            fail("tenant must be 1-63 letters, digits, '-' or '_'".into());
+        }
+    }
+    errors
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct SpellResult {
     pub run_id: String,
     pub verdict: String,
     pub risk_score: u32,
     pub exit_code: i32,
+    /// Wall time of the whole pipeline, from request validation to publish.
     pub duration_ms: u64,
     pub stdout_trunc: bool,
+    /// The command's stdout, base64 of the raw bytes; at most [`OUTPUT_CAP`]
+    /// bytes, with `stdout_trunc` set when more were captured.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub stdout_b64: String,
+    /// The command's stderr, capped like `stdout_b64`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub stderr_b64: String,
+    #[serde(default, skip_serializing_if = "core::ops::Not::not")]
+    pub stderr_trunc: bool,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub sbom_attestation: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
+    /// How long the request waited in the queue before a consumer started
+    /// on it, in ms; absent when it did not come from a queue that says.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue_ms: Option<u64>,
+    /// Resource limits the command ran under; absent when it did not run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<Limits>,
+    /// The limit that stopped the command; absent when it exited on its own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<StopReason>,
+    /// Most of the command's processes seen alive at once. Sampled every
+    /// 100 ms, so a lower bound; absent when the command did not run here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pids_peak: Option<u32>,
+    /// Orphans the PID namespace's init reaped; absent without one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub orphans_reaped: Option<u32>,
+    /// I/O and CPU sampled alongside `pids_peak`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// An earlier attempt at this run that never finished (see
+    /// `crate::guard`): set when it was run again or returned partially.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interrupted: Option<Interrupted>,
+    /// What the sandbox observed while the command ran.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub findings: Vec<Finding>,
+    /// The rules behind `risk_score` and its raw, uncapped sum.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub risk_breakdown: Option<RiskBreakdown>,
+    /// `accept_risk` entries the policy let waive, with the points each
+    /// kept out of `risk_score`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub waivers: Vec<Waiver>,
+    /// Syscall counts by category (policy `observe.syscalls: summary`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub syscalls: Option<SyscallSummary>,
+    /// Connections the command attempted (policy `observe.net: log`),
+    /// capped at 100.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub net_log: Vec<NetConnection>,
+    /// Files the command created, modified or deleted (overlay runs only),
+    /// capped at 256.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub manifest: Vec<FileChange>,
+    /// Core dumps of a crashed command, kept in its quarantine directory
+    /// (policy `debug.core_mb`).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub core_dumps: Vec<CoreDump>,
+    /// Stdout and stderr chunks in the order they were written (policy
+    /// `observe.output: timeline`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_timeline: Option<OutputTimeline>,
+    /// The environment the command started with (policy `observe.env:
+    /// names`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<EnvSnapshot>,
+    /// Programs the command line names, resolved on `PATH` and hashed
+    /// (policy `observe.provenance: record`).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub executables: Vec<Executable>,
+    /// Traffic through the egress proxy (policy `capabilities.net.proxy:
+    /// on`), by target, capped at 100.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub egress: Vec<EgressHost>,
+    /// `disabled` when the run had no network at all (`exec --offline` or
+    /// `capabilities.net.default: disabled_hard`); absent otherwise.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub network: String,
+    /// Interpreter the command ran under (`bash`, `sh`, `cmd`, `powershell`
+    /// or `argv`); absent when it did not run.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub shell: String,
+    /// Tenant the run was accounted to.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub tenant: String,
+    /// Consumer instance that produced the result (see `crate::instance`).
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub instance_id: String,
+    /// Returned from the result cache instead of running the command (see
+    /// `crate::result_cache`).
+    #[serde(default, skip_serializing_if = "core::ops::Not::not")]
+    pub cached: bool,
+    /// Detached JWS over the rest of the result (see `crate::signing`).
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub signature: String,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Default)]
-pub struct GradingThresholds {
-    pub green: String,
-    pub yellow: String,
-    pub red: String,
+/// `verdict` of the result a consumer answers an unauthenticated request
+/// with: the request was not run, and is not graded.
+pub const REJECTED_VERDICT: &str = "rejected";
+
+/// `verdict` of the result a consumer answers a request with when it took
+/// the request off the queue past its `deadline_ms`: the request was not
+/// run, and is not graded.
+pub const EXPIRED_VERDICT: &str = "expired";
+
+/// `deadline_ms` from here on (2001-09-09) is a Unix time; below it, a
+/// time after the request was queued.
+pub const DEADLINE_ABSOLUTE_MS: u64 = 1_000_000_000_000;
+
+/// Finding `kind` of an expired request.
+pub const DEADLINE: &str = "deadline";
+
+/// How many ms past `deadline_ms` it is at `now_ms`, for a request queued
+/// at `queued_at_ms`. `None` when the deadline has not passed, or when it
+/// counts from the queue time and the queue does not say.
+pub fn overdue(deadline_ms: u64, queued_at_ms: Option<u64>, now_ms: u64) -> Option<u64> {
+    let at = if deadline_ms >= DEADLINE_ABSOLUTE_MS {
+        deadline_ms
+    } else {
+        queued_at_ms?.saturating_add(deadline_ms)
+    };
+    (now_ms > at).then(|| now_ms - at)
+}
+
+/// The finding an expired request is answered with.
+pub fn expired_finding(overdue_ms: u64) -> Finding {
+    Finding {
+        kind: DEADLINE.into(),
+        detail: format!("taken off the queue {} ms past deadline_ms", overdue_ms),
+        count: 1,
+    }
+}
+
+/// `network` of a run that had no network at all.
+pub const NETWORK_DISABLED: &str = "disabled";
+
+/// Bytes of each captured stream embedded in a result.
+pub const OUTPUT_CAP: usize = 1 << 20;
+
+#[cfg(feature = "std")]
+impl SpellResult {
+    /// Embed captured output, capped at [`OUTPUT_CAP`] bytes per stream.
+    /// Output is kept as bytes: whatever the child's locale, nothing is
+    /// decoded until it is displayed.
+    pub fn set_output(&mut self, stdout: &[u8], stderr: &[u8]) {
+        use base64::Engine as _;
+        let b64 = &base64::engine::general_purpose::STANDARD;
+        self.stdout_b64 = b64.encode(&stdout[..stdout.len().min(OUTPUT_CAP)]);
+        self.stdout_trunc = stdout.len() > OUTPUT_CAP;
+        self.stderr_b64 = b64.encode(&stderr[..stderr.len().min(OUTPUT_CAP)]);
+        self.stderr_trunc = stderr.len() > OUTPUT_CAP;
+    }
+
+    /// The result as JSON with `stdout_b64` / `stderr_b64` replaced by
+    /// `stdout` / `stderr` text, invalid UTF-8 shown as U+FFFD. For display
+    /// only: the signature covers the base64 form.
+    pub fn to_lossy_json(&self) -> serde_json::Value {
+        use base64::Engine as _;
+        let mut v = serde_json::to_value(self).unwrap_or_default();
+        if let Some(obj) = v.as_object_mut() {
+            for name in ["stdout", "stderr"] {
+                let Some(encoded) = obj.remove(&format!("{}_b64", name)) else {
+                    continue;
+                };
+                let bytes = base64::engine::general_purpose::STANDARD
+                    .decode(encoded.as_str().unwrap_or_default())
+                    .unwrap_or_default();
+                obj.insert(
+                    name.to_string(),
+                    String::from_utf8_lossy(&bytes).into_owned().into(),
+                );
+            }
+        }
+        v
+    }
+}
+
+/// Per-phase breakdown of `duration_ms`, in milliseconds, and the HTTP
+/// cache hits that shortened `exec_ms`.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timings {
+    pub validate_ms: u64,
+    pub materialize_ms: u64,
+    pub exec_ms: u64,
+    pub grade_ms: u64,
+    pub publish_ms: u64,
+    /// Requests the egress proxy answered from the HTTP cache.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub net_cache_hits: u64,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+/// Limits a request asks for. Each may only lower the policy's; unset ones
+/// keep the policy value.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct RequestLimits {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_mb: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pids: Option<u64>,
+}
+
+/// Limits applied to the sandboxed command: the policy's, narrowed by the
+/// request's.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub wall_sec: u64,
+    pub cpu_ms: u64,
+    pub memory_mb: u64,
+    pub pids: u64,
+    pub tmp_mb: u64,
+    pub tmp_inodes: u64,
+}
+
+/// Resources the command's processes used, sampled while it ran (see
+/// `sandbox::usage`); lower bounds.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Bytes read through read calls, whatever the file.
+    pub read_bytes: u64,
+    /// Bytes written through write calls.
+    pub write_bytes: u64,
+    /// User and system CPU time.
+    pub cpu_ms: u64,
+    /// `cpu_ms` against the wall time, in percent of one core.
+    pub cpu_pct: u32,
+}
+
+/// A run that was started and never finished, as its guard marker
+/// recorded it.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct Interrupted {
+    /// When the last attempt started, in milliseconds since the Unix epoch.
+    pub started_at_ms: u64,
+    /// Consumer instance that started it; empty when it had none.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub instance_id: String,
+    /// Attempts started so far.
+    pub attempts: u32,
+}
+
+/// One kind of activity observed during the run, e.g. `seccomp_denied` with
+/// the syscall name as `detail`.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct Finding {
+    pub kind: String,
+    pub detail: String,
+    pub count: u64,
+}
+
+/// The limit that stopped a command before it exited on its own.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct StopReason {
+    /// `wall_timeout`, `cpu_limit` or `memory_limit`.
+    pub kind: String,
+    /// The setting that was reached: `timeout_sec`, `limits.wall_sec`,
+    /// `limits.cpu_ms` or `limits.memory_mb`.
+    pub limit: String,
+    /// Who set the value in force: `request` or `policy`.
+    pub source: String,
+    /// That value, in the setting's unit.
+    pub value: u64,
+}
+
+/// How `risk_score` came about: the points each grading rule added and
+/// their sum before it was capped to the 0–100 scale.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct RiskBreakdown {
+    /// Sum of `rules`, uncapped.
+    pub raw: u32,
+    /// Points by rule; rules that did not fire are left out.
+    pub rules: BTreeMap<String, u32>,
+}
+
+/// A grading rule waived for a run: the request's `accept_risk` entry and
+/// the points the rule would have added (0 when it did not fire).
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct Waiver {
+    pub rule: String,
+    pub reason: String,
+    pub approver: String,
+    pub points: u32,
+}
+
+/// Syscalls the command made, by category.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyscallSummary {
+    pub file: u64,
+    pub net: u64,
+    pub process: u64,
+    pub other: u64,
+}
+
+/// One `connect` the command made.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct NetConnection {
+    pub ip: String,
+    pub port: u16,
+    /// The connection went through (or was under way).
+    pub allowed: bool,
+    /// Why it did not, as an errno name (`ENETUNREACH`).
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub error: String,
+}
+
+/// One file the command wrote or removed.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct FileChange {
+    /// Path as the command saw it.
+    pub path: String,
+    pub change: FileChangeKind,
+    pub size: u64,
+    /// Hex digest of the new contents; empty for deletions.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub sha256: String,
+}
+
+/// A core dump kept from a crashed command.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct CoreDump {
+    /// Where it was kept, relative to the consumer's working directory.
+    pub path: String,
+    pub bytes: u64,
+    /// Hex digest of the file.
+    pub sha256: String,
+}
+
+/// The command's interleaved output, as JSONL of
+/// `{"t_us", "stream", "b64"}` chunks.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct OutputTimeline {
+    pub chunks: u64,
+    /// Base64 of the JSONL when it fits in [`OUTPUT_CAP`].
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub jsonl_b64: String,
+    /// Where the JSONL was kept instead, relative to the consumer's working
+    /// directory.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub path: String,
+    pub bytes: u64,
+    /// Hex digest of the JSONL.
+    pub sha256: String,
+}
+
+/// The command's environment, by name.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct EnvSnapshot {
+    /// In name order.
+    pub vars: Vec<EnvVar>,
+    /// Hex digest of `vars` in order: `NAME=VALUE\0` for a variable with a
+    /// value, `NAME\0` for one without.
+    pub sha256: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct EnvVar {
+    pub name: String,
+    /// Only for names the policy lists in `observe.env_values`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// `inherited` from the consumer's environment, or `set` or
+    /// `overridden` by the sandbox.
+    pub source: String,
+}
+
+/// One program the command ran.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct Executable {
+    /// As written in the command line.
+    pub name: String,
+    /// Where it resolved to, as the command saw it.
+    pub path: String,
+    /// Hex digest of the file.
+    pub sha256: String,
+    /// Package that installed it (`dpkg` or `rpm`); empty when unknown.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub package: String,
 }
 
+/// Traffic to one target through the egress proxy.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct EgressHost {
+    /// `host:port` as the command asked for it.
+    pub target: String,
+    /// Bytes sent to the target, request heads included.
+    pub bytes_out: u64,
+    /// Bytes received from the target.
+    pub bytes_in: u64,
+    /// Connections let through.
+    pub connections: u32,
+    /// Connections refused by the allowlist.
+    pub denied: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileChangeKind {
+    #[default]
+    Created,
+    Modified,
+    Deleted,
+}
+
+/// Grading verdict.
+///
+/// Each verdict maps to a fixed magicrune process exit code (see
+/// [`Verdict::exit_code`]). The sandboxed child's own exit status is reported
+/// separately in `SpellResult::exit_code` and is never substituted for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Green,
+    Yellow,
+    Red,
+}
+
+impl Verdict {
+    /// Process exit code of the `magicrune` CLI for this verdict.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Verdict::Green => 0,
+            Verdict::Yellow => 10,
+            Verdict::Red => 20,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Verdict::Green => "green",
+            Verdict::Yellow => "yellow",
+            Verdict::Red => "red",
+        }
+    }
+}
+
+impl core::fmt::Display for Verdict {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl core::str::FromStr for Verdict {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "green" => Ok(Verdict::Green),
+            "yellow" => Ok(Verdict::Yellow),
+            "red" => Ok(Verdict::Red),
+            other => Err(format!("unknown verdict: {}", other)),
+        }
+    }
+}
+
+/// `grading.thresholds`; the same type the engine grades with, so the
+/// legacy range strings are accepted and checked here too.
+pub use crate::policy::Thresholds as GradingThresholds;
+
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct PolicyDoc {
     pub version: u8,
@@ -58,6 +767,8 @@ mod tests {
         assert!(req.allow_net.is_none());
         assert!(req.allow_fs.is_none());
         assert!(req.seed.is_none());
+        assert!(req.callback_url.is_none());
+        assert!(req.tenant.is_none());
     }
 
     #[test]
@@ -72,6 +783,17 @@ mod tests {
             allow_net: Some(vec!["localhost".to_string()]),
             allow_fs: Some(vec!["/tmp".to_string()]),
             seed: Some(42),
+            callback_url: Some("https://hooks.example.com/cb".to_string()),
+            tenant: Some("acme".to_string()),
+            limits: Some(RequestLimits {
+                memory_mb: Some(128),
+                ..Default::default()
+            }),
+            vars: None,
+            accept_risk: None,
+            deadline_ms: Some(30_000),
+            pty: Some(true),
+            strip_ansi: None,
         };
 
         let json = serde_json::to_string(&req).unwrap();
@@ -82,6 +804,44 @@ mod tests {
         assert_eq!(deserialized.policy_id, req.policy_id);
         assert_eq!(deserialized.timeout_sec, req.timeout_sec);
         assert_eq!(deserialized.seed, req.seed);
+        assert_eq!(deserialized.callback_url, req.callback_url);
+        assert_eq!(deserialized.tenant, req.tenant);
+        assert_eq!(deserialized.pty, Some(true));
+        assert!(!json.contains("strip_ansi"));
+    }
+
+    #[test]
+    fn test_request_view_borrows_payload() {
+        let payload = br#"{"cmd":"echo \"hi\"","files":[{"path":"/tmp/a","content_b64":"aGk="}],"env":{"K":"v"},"seed":7}"#;
+        let req = RequestView::parse(payload).unwrap();
+        assert_eq!(req.cmd, "echo \"hi\"");
+        assert!(matches!(req.cmd, Cow::Owned(_)));
+        assert!(matches!(req.files[0].content_b64, Cow::Borrowed("aGk=")));
+        assert_eq!(req.env.unwrap().get(), r#"{"K":"v"}"#);
+        assert_eq!(req.seed(), Some(7));
+
+        assert_eq!(RequestView::parse(b"{}").unwrap().seed(), Some(0));
+        assert_eq!(RequestView::parse(br#"{"seed":"7"}"#).unwrap().seed(), None);
+        assert!(RequestView::parse(b"not json").is_err());
+    }
+
+    #[test]
+    fn test_output_is_byte_safe() {
+        let mut result = SpellResult::default();
+        result.set_output(b"caf\xe9\n", b"");
+        assert_eq!(result.stdout_b64, "Y2Fm6Qo=");
+        assert!(!result.stdout_trunc);
+        let json = serde_json::to_string(&result).unwrap();
+        assert!(!json.contains("stderr"));
+
+        let lossy = result.to_lossy_json();
+        assert_eq!(lossy["stdout"], "caf\u{fffd}\n");
+        assert!(lossy.get("stdout_b64").is_none());
+
+        result.set_output(&vec![b'x'; OUTPUT_CAP + 1], b"e");
+        assert!(result.stdout_trunc);
+        assert_eq!(result.stdout_b64.len(), OUTPUT_CAP.div_ceil(3) * 4);
+        assert_eq!(result.stderr_b64, "ZQ==");
     }
 
     #[test]
@@ -94,6 +854,7 @@ mod tests {
             duration_ms: 100,
             stdout_trunc: false,
             sbom_attestation: "attestation".to_string(),
+            ..Default::default()
         };
 
         let json = serde_json::to_string(&result).unwrap();
@@ -106,14 +867,56 @@ mod tests {
         assert_eq!(deserialized.duration_ms, result.duration_ms);
         assert_eq!(deserialized.stdout_trunc, result.stdout_trunc);
         assert_eq!(deserialized.sbom_attestation, result.sbom_attestation);
+        assert!(!json.contains("limits"));
+
+        let limits = Limits {
+            tmp_mb: 16,
+            tmp_inodes: 1000,
+            ..Default::default()
+        };
+        let result = SpellResult {
+            limits: Some(limits),
+            ..result
+        };
+        let json = serde_json::to_string(&result).unwrap();
+        let deserialized: SpellResult = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized.limits, Some(limits));
+    }
+
+    #[test]
+    fn test_verdict_exit_codes() {
+        assert_eq!(Verdict::Green.exit_code(), 0);
+        assert_eq!(Verdict::Yellow.exit_code(), 10);
+        assert_eq!(Verdict::Red.exit_code(), 20);
+    }
+
+    #[test]
+    fn test_overdue() {
+        let at = DEADLINE_ABSOLUTE_MS + 5_000;
+        assert_eq!(overdue(at, None, at + 7), Some(7));
+        assert_eq!(overdue(at, Some(0), at), None);
+        // Relative: from the queue time, unknown without one.
+        assert_eq!(overdue(1_000, Some(at), at + 1_500), Some(500));
+        assert_eq!(overdue(1_000, Some(at), at + 900), None);
+        assert_eq!(overdue(1_000, None, u64::MAX), None);
+    }
+
+    #[test]
+    fn test_verdict_roundtrip() {
+        for v in [Verdict::Green, Verdict::Yellow, Verdict::Red] {
+            assert_eq!(v.as_str().parse::<Verdict>().unwrap(), v);
+            let json = serde_json::to_string(&v).unwrap();
+            assert_eq!(json, format!("\"{}\"", v));
+        }
+        assert!("blue".parse::<Verdict>().is_err());
+        assert!(Verdict::Green < Verdict::Yellow && Verdict::Yellow < Verdict::Red);
     }
 
     #[test]
     fn test_grading_thresholds_default() {
         let thresholds = GradingThresholds::default();
-        assert_eq!(thresholds.green, "");
-        assert_eq!(thresholds.yellow, "");
-        assert_eq!(thresholds.red, "");
+        assert_eq!(thresholds.green_max, 20);
+        assert_eq!(thresholds.yellow_max, 60);
     }
 
     #[test]
@@ -126,18 +929,53 @@ mod tests {
     #[test]
     fn test_grading_cfg_serialization() {
         let cfg = GradingCfg {
-            thresholds: GradingThresholds {
-                green: "0-30".to_string(),
-                yellow: "31-70".to_string(),
-                red: "71-100".to_string(),
-            },
+            thresholds: GradingThresholds::new(30, 70).unwrap(),
         };
 
         let json = serde_json::to_string(&cfg).unwrap();
+        assert_eq!(json, r#"{"thresholds":{"green_max":30,"yellow_max":70}}"#);
         let deserialized: GradingCfg = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized.thresholds, cfg.thresholds);
+
+        let legacy: GradingCfg = serde_json::from_str(
+            r#"{"thresholds":{"green":"<=30","yellow":"31..=70","red":">=71"}}"#,
+        )
+        .unwrap();
+        assert_eq!(legacy.thresholds, cfg.thresholds);
+        assert!(serde_json::from_str::<GradingCfg>(r#"{"thresholds":{"green":"0-30"}}"#).is_err());
+    }
+
+    #[test]
+    fn test_request_errors() {
+        let ok = serde_json::json!({
+            "cmd": "echo hi", "stdin": "", "env": {"A": 1}, "files": [{"path": "a"}],
+            "policy_id": "p", "timeout_sec": 60, "allow_net": [], "allow_fs": [],
+            "tenant": "team-a", "limits": {"pids": 4}, "callback_url": "https://x.test/"
+        });
+        assert!(request_errors(&ok).is_empty());
 
-        assert_eq!(deserialized.thresholds.green, cfg.thresholds.green);
-        assert_eq!(deserialized.thresholds.yellow, cfg.thresholds.yellow);
-        assert_eq!(deserialized.thresholds.red, cfg.thresholds.red);
+        let mut bad = ok.clone();
+        bad["timeout_sec"] = (-1).into();
+        bad["tenant"] = "-a".into();
+        bad["limits"] = serde_json::json!({"pids": 0, "disk_mb": 1});
+        bad["accept_risk"] = serde_json::json!([{"rule": "ssh", "reason": "deploy"}]);
+        bad["pty"] = "yes".into();
+        bad.as_object_mut().unwrap().remove("stdin");
+        assert_eq!(
+            request_errors(&bad),
+            [
+                "missing key: stdin",
+                "timeout_sec must be 0..=60",
+                "unknown limit: disk_mb",
+                "limits.pids must be an integer of at least 1",
+                "accept_risk.approver must be a non-empty string",
+                "pty must be boolean",
+                "tenant must be 1-63 letters, digits, '-' or '_'",
+            ]
+        );
+        assert_eq!(
+            request_errors(&serde_json::json!([])),
+            ["request must be an object"]
+        );
     }
 }
@@ -0,0 +1,229 @@
+//! The JSON Schemas bundled with magicrune (`schemas/*.json`) and their
+//! check against the Rust types in [`crate::schema`].
+//!
+//! The schema files are written by hand, with the bounds and patterns the
+//! types cannot express. What the types do say is checked here: every
+//! field serde reads or writes must be a schema property and the other way
+//! round, and a field's JSON type must be one the schema allows. Nested
+//! objects are checked against their own types. `magicrune schema check`
+//! runs this against the compiled-in copies or a directory of files.
+
+use crate::schema::{
+    EgressHost, Executable, FileChange, Finding, Interrupted, Limits, NetConnection, RequestLimits,
+    RiskAcceptance, RiskBreakdown, SpellRequest, SpellResult, StopReason, SyscallSummary, Timings,
+    Usage, Waiver,
+};
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::Serialize;
+use serde_json::Value;
+
+pub const REQUEST_SCHEMA: &str = include_str!("../schemas/spell_request.schema.json");
+pub const RESULT_SCHEMA: &str = include_str!("../schemas/spell_result.schema.json");
+
+/// The bundled schemas by file name, as compiled into this binary.
+pub const BUNDLED: [(&str, &str); 2] = [
+    ("spell_request.schema.json", REQUEST_SCHEMA),
+    ("spell_result.schema.json", RESULT_SCHEMA),
+];
+
+/// The field names serde gives `T`, from its `Deserialize` impl.
+fn field_names<T: for<'de> Deserialize<'de>>() -> &'static [&'static str] {
+    struct Fields<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> Deserializer<'de> for Fields<'_> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("fields recorded"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(Fields(&mut fields));
+    fields
+}
+
+/// The JSON Schema type of a serialized value.
+fn json_type(v: &Value) -> &'static str {
+    match v {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Drift between the object schema at `node` (reached by `pointer`) and
+/// `T`. Types are taken from `T::default()`; fields it leaves out or
+/// serializes as `null` are checked by name only.
+fn compare<T>(pointer: &str, node: &Value, issues: &mut Vec<String>)
+where
+    T: Serialize + for<'de> Deserialize<'de> + Default,
+{
+    let ty = core::any::type_name::<T>()
+        .rsplit("::")
+        .next()
+        .unwrap_or("");
+    let Some(props) = node.get("properties").and_then(Value::as_object) else {
+        issues.push(format!("{}: no properties for {}", pointer, ty));
+        return;
+    };
+    let fields = field_names::<T>();
+    for f in fields {
+        if !props.contains_key(*f) {
+            issues.push(format!(
+                "{}/properties/{}: missing ({}.{})",
+                pointer, f, ty, f
+            ));
+        }
+    }
+    for p in props.keys() {
+        if !fields.contains(&p.as_str()) {
+            issues.push(format!(
+                "{}/properties/{}: {} has no such field",
+                pointer, p, ty
+            ));
+        }
+    }
+    let Ok(Value::Object(sample)) = serde_json::to_value(T::default()) else {
+        return;
+    };
+    for (f, v) in &sample {
+        let allowed = match props.get(f).and_then(|p| p.get("type")) {
+            Some(Value::String(t)) => vec![t.as_str()],
+            Some(Value::Array(ts)) => ts.iter().filter_map(Value::as_str).collect(),
+            _ => continue,
+        };
+        let actual = json_type(v);
+        let ok = actual == "null"
+            || allowed.contains(&actual)
+            || (actual == "integer" && allowed.contains(&"number"));
+        if !ok {
+            issues.push(format!(
+                "{}/properties/{}: type {:?}, but {}.{} is {}",
+                pointer,
+                f,
+                allowed.join("|"),
+                ty,
+                f,
+                actual
+            ));
+        }
+    }
+}
+
+/// Drift between the schema file `name` (one of [`BUNDLED`]) with contents
+/// `text` and the Rust types; empty when they agree.
+pub fn check(name: &str, text: &str) -> Vec<String> {
+    let root: Value = match serde_json::from_str(text) {
+        Ok(v) => v,
+        Err(e) => return vec![format!("{}: invalid JSON: {}", name, e)],
+    };
+    let at = |pointer: &str| root.pointer(pointer).unwrap_or(&Value::Null);
+    let mut issues = Vec::new();
+    match name {
+        "spell_request.schema.json" => {
+            compare::<SpellRequest>("", &root, &mut issues);
+            compare::<RequestLimits>("/properties/limits", at("/properties/limits"), &mut issues);
+            compare::<RiskAcceptance>(
+                "/properties/accept_risk/items",
+                at("/properties/accept_risk/items"),
+                &mut issues,
+            );
+        }
+        "spell_result.schema.json" => {
+            compare::<SpellResult>("", &root, &mut issues);
+            for (pointer, check) in [
+                (
+                    "/properties/timings",
+                    compare::<Timings> as fn(&str, &Value, &mut Vec<String>),
+                ),
+                ("/properties/limits", compare::<Limits>),
+                ("/properties/reason", compare::<StopReason>),
+                ("/properties/usage", compare::<Usage>),
+                ("/properties/interrupted", compare::<Interrupted>),
+                ("/properties/findings/items", compare::<Finding>),
+                ("/properties/risk_breakdown", compare::<RiskBreakdown>),
+                ("/properties/waivers/items", compare::<Waiver>),
+                ("/properties/syscalls", compare::<SyscallSummary>),
+                ("/properties/net_log/items", compare::<NetConnection>),
+                ("/properties/manifest/items", compare::<FileChange>),
+                ("/properties/executables/items", compare::<Executable>),
+                ("/properties/egress/items", compare::<EgressHost>),
+            ] {
+                check(pointer, at(pointer), &mut issues);
+            }
+        }
+        _ => return vec![format!("{}: not a bundled schema", name)],
+    }
+    issues
+        .into_iter()
+        .map(|i| format!("{}: {}", name, i))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundled_schemas_match_types() {
+        for (name, text) in BUNDLED {
+            assert_eq!(check(name, text), Vec::<String>::new());
+        }
+    }
+
+    #[test]
+    fn test_field_names() {
+        assert_eq!(
+            field_names::<RequestLimits>(),
+            ["cpu_ms", "memory_mb", "pids"]
+        );
+    }
+
+    #[test]
+    fn test_check_reports_drift() {
+        let mut schema: Value = serde_json::from_str(RESULT_SCHEMA).unwrap();
+        let props = schema["properties"].as_object_mut().unwrap();
+        props.remove("shell");
+        props.insert("stdout".into(), serde_json::json!({ "type": "string" }));
+        props["run_id"] = serde_json::json!({ "type": "integer" });
+        schema["properties"]["timings"]["properties"]
+            .as_object_mut()
+            .unwrap()
+            .remove("exec_ms");
+        let issues = check("spell_result.schema.json", &schema.to_string());
+        assert_eq!(
+            issues,
+            [
+                "spell_result.schema.json: /properties/shell: missing (SpellResult.shell)",
+                "spell_result.schema.json: /properties/stdout: SpellResult has no such field",
+                "spell_result.schema.json: /properties/run_id: type \"integer\", but SpellResult.run_id is string",
+                "spell_result.schema.json: /properties/timings/properties/exec_ms: missing (Timings.exec_ms)",
+            ]
+        );
+        assert_eq!(
+            check("other.json", "{}"),
+            ["other.json: not a bundled schema"]
+        );
+    }
+}
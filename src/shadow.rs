@@ -0,0 +1,119 @@
+//! Shadow policy: a second policy every request is also graded under.
+//!
+//! With `MAGICRUNE_SHADOW_POLICY=<path>` (or `consume --shadow-policy`),
+//! each request is evaluated in dry run under both the active policy and
+//! the shadow one (see `crate::compare`) before it is handled. Only the
+//! active policy decides what happens to the request; every way the shadow
+//! decision differs is logged and counted in the `shadow_*` metrics, so a
+//! policy change can be watched against production traffic before it is
+//! made active. The shadow file is reloaded when it changes, like the
+//! active one.
+
+use crate::compare::{self, CompareReport};
+use crate::engine::ExecOptions;
+use crate::keys::Keyring;
+use crate::policy::{Policy, PolicyStore};
+
+/// Environment variable read when `--shadow-policy` is not given.
+pub const SHADOW_ENV: &str = "MAGICRUNE_SHADOW_POLICY";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShadowPolicy {
+    path: String,
+}
+
+impl ShadowPolicy {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// The shadow policy at [`SHADOW_ENV`]; `None` when unset.
+    pub fn from_env() -> Option<Self> {
+        crate::ports::env::var(SHADOW_ENV)
+            .ok()
+            .filter(|p| !p.is_empty())
+            .map(Self::new)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Grade `raw` under `active` and the shadow policy, log and count the
+    /// differences, and return the comparison. Nothing is executed,
+    /// signed or charged to a quota.
+    pub async fn evaluate(&self, raw: &[u8], active: &Policy, opts: &ExecOptions) -> CompareReport {
+        let shadow = PolicyStore::get(&self.path);
+        let opts = ExecOptions {
+            dry_run: true,
+            interactive: false,
+            keyring: Keyring::default(),
+            quotas: None,
+            input_cache: None,
+            http_cache: None,
+            ..opts.clone()
+        };
+        let report = compare::compare(
+            raw,
+            &[
+                ("active".to_string(), active.clone()),
+                (self.path.clone(), (*shadow).clone()),
+            ],
+            &opts,
+        )
+        .await;
+        for d in &report.differences {
+            tracing::warn!(
+                run_id = %report.run_id,
+                shadow_policy = %self.path,
+                field = %d.field,
+                active = %d.baseline,
+                shadow = %d.value,
+                "shadow policy diverges"
+            );
+        }
+        let fields: Vec<&str> = report
+            .differences
+            .iter()
+            .map(|d| d.field.as_str())
+            .collect();
+        crate::metrics::observe_shadow(&fields);
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_evaluate_logs_and_counts_divergence() {
+        let path = std::env::temp_dir().join(format!("mr_shadow_{}.yml", std::process::id()));
+        std::fs::write(
+            &path,
+            "version: 1\ngrading:\n  thresholds:\n    green_max: 0\n    yellow_max: 1\n",
+        )
+        .unwrap();
+        let shadow = ShadowPolicy::new(path.to_string_lossy());
+        let raw = br#"{"cmd":"ssh build@example.com uptime"}"#;
+        let report = shadow
+            .evaluate(raw, &Policy::default(), &ExecOptions::default())
+            .await;
+        assert_eq!(report.outcomes[0].verdict, "yellow");
+        assert_eq!(report.outcomes[1].verdict, "red");
+        assert_eq!(report.differences.len(), 1);
+        assert!(
+            crate::metrics::render("m").contains("m_shadow_divergences_total{field=\"verdict\"}")
+        );
+
+        let report = shadow
+            .evaluate(
+                br#"{"cmd":"true"}"#,
+                &Policy::default(),
+                &ExecOptions::default(),
+            )
+            .await;
+        assert!(report.agree);
+        let _ = std::fs::remove_file(&path);
+    }
+}
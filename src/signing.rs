@@ -0,0 +1,226 @@
+//! Ed25519 signatures over results.
+//!
+//! A signed result carries a JWS with detached payload (RFC 7515 appendix F)
+//! in its `signature` field: `<b64url(header)>..<b64url(sig)>`, header
+//! `{"alg":"EdDSA","kid":<key id>}`. The payload is the result's compact JSON
+//! encoding with `signature` left out, so a result can be checked wherever it
+//! ends up without trusting the queue it came through.
+//!
+//! Requests may be signed by their producer: `key_id` names the key and
+//! `sig` is the base64url Ed25519 signature over the request's compact JSON
+//! with sorted keys and `sig` left out (`key_id` included).
+//!
+//! Loading keys and picking one by `kid` is `crate::keys`'s job.
+
+use crate::keys;
+use crate::schema::SpellResult;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as B64URL;
+use base64::Engine as _;
+use ed25519_dalek::{Signature, Signer, Verifier};
+use thiserror::Error;
+
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum SignatureError {
+    #[error("not signed")]
+    Missing,
+    #[error("malformed signature: {0}")]
+    Malformed(String),
+    #[error("signature does not match")]
+    Mismatch,
+    #[error("key: {0}")]
+    Key(String),
+}
+
+/// `b64url(payload)` for `result`.
+fn encoded_payload(result: &SpellResult) -> Result<String, SignatureError> {
+    let unsigned = SpellResult {
+        signature: String::new(),
+        ..result.clone()
+    };
+    let payload =
+        serde_json::to_vec(&unsigned).map_err(|e| SignatureError::Malformed(e.to_string()))?;
+    Ok(B64URL.encode(payload))
+}
+
+/// Set `result.signature`. Call it last: any later change breaks the signature.
+pub fn sign_result(key: &SigningKey, result: &mut SpellResult) -> Result<(), SignatureError> {
+    let header = serde_json::json!({
+        "alg": "EdDSA",
+        "kid": keys::key_id(&key.verifying_key()),
+    });
+    let header = B64URL.encode(header.to_string());
+    let input = format!("{}.{}", header, encoded_payload(result)?);
+    let sig = key.sign(input.as_bytes());
+    result.signature = format!("{}..{}", header, B64URL.encode(sig.to_bytes()));
+    Ok(())
+}
+
+/// Split `result.signature` into the encoded header, the decoded header and
+/// the encoded signature.
+fn parts(result: &SpellResult) -> Result<(&str, serde_json::Value, &str), SignatureError> {
+    if result.signature.is_empty() {
+        return Err(SignatureError::Missing);
+    }
+    let (header, sig) = result
+        .signature
+        .split_once("..")
+        .ok_or_else(|| SignatureError::Malformed("expected <header>..<signature>".into()))?;
+    let header_json: serde_json::Value = B64URL
+        .decode(header)
+        .ok()
+        .and_then(|h| serde_json::from_slice(&h).ok())
+        .ok_or_else(|| SignatureError::Malformed("bad JWS header".into()))?;
+    Ok((header, header_json, sig))
+}
+
+/// The `kid` the result was signed under, if the signature names one.
+pub fn signature_kid(result: &SpellResult) -> Result<Option<String>, SignatureError> {
+    let (_, header, _) = parts(result)?;
+    Ok(header
+        .get("kid")
+        .and_then(|k| k.as_str())
+        .map(str::to_string))
+}
+
+/// Check `result.signature` against `key`.
+pub fn verify_result(key: &VerifyingKey, result: &SpellResult) -> Result<(), SignatureError> {
+    let (header, header_json, sig) = parts(result)?;
+    if header_json.get("alg").and_then(|a| a.as_str()) != Some("EdDSA") {
+        return Err(SignatureError::Malformed("alg must be EdDSA".into()));
+    }
+    let sig = B64URL
+        .decode(sig)
+        .map_err(|e| SignatureError::Malformed(e.to_string()))
+        .and_then(|b| {
+            Signature::from_slice(&b).map_err(|e| SignatureError::Malformed(e.to_string()))
+        })?;
+    // Rebuild the input with the header exactly as it was sent.
+    let input = format!("{}.{}", header, encoded_payload(result)?);
+    key.verify(input.as_bytes(), &sig)
+        .map_err(|_| SignatureError::Mismatch)
+}
+
+/// [`verify_result`] on a result as raw JSON.
+pub fn verify_result_json(key: &VerifyingKey, raw: &[u8]) -> Result<SpellResult, SignatureError> {
+    let result: SpellResult =
+        serde_json::from_slice(raw).map_err(|e| SignatureError::Malformed(e.to_string()))?;
+    verify_result(key, &result)?;
+    Ok(result)
+}
+
+/// The bytes a request signature covers.
+fn request_payload(req: &serde_json::Map<String, serde_json::Value>) -> Vec<u8> {
+    let mut unsigned = req.clone();
+    unsigned.remove("sig");
+    // Map keys are kept sorted, so this is the canonical form.
+    serde_json::Value::Object(unsigned).to_string().into_bytes()
+}
+
+/// Set `key_id` and `sig` on a request.
+pub fn sign_request(key: &SigningKey, req: &mut serde_json::Map<String, serde_json::Value>) {
+    req.insert("key_id".into(), keys::key_id(&key.verifying_key()).into());
+    let sig = key.sign(&request_payload(req));
+    req.insert("sig".into(), B64URL.encode(sig.to_bytes()).into());
+}
+
+/// The `key_id` of a signed request and its decoded `sig`.
+pub fn request_signature(
+    req: &serde_json::Map<String, serde_json::Value>,
+) -> Result<(Option<&str>, Signature), SignatureError> {
+    let sig = req
+        .get("sig")
+        .and_then(|s| s.as_str())
+        .ok_or(SignatureError::Missing)?;
+    let sig = B64URL
+        .decode(sig)
+        .map_err(|e| SignatureError::Malformed(e.to_string()))
+        .and_then(|b| {
+            Signature::from_slice(&b).map_err(|e| SignatureError::Malformed(e.to_string()))
+        })?;
+    Ok((req.get("key_id").and_then(|k| k.as_str()), sig))
+}
+
+/// Check a request's `sig` against `key`.
+pub fn verify_request(
+    key: &VerifyingKey,
+    req: &serde_json::Map<String, serde_json::Value>,
+) -> Result<(), SignatureError> {
+    let (_, sig) = request_signature(req)?;
+    key.verify(&request_payload(req), &sig)
+        .map_err(|_| SignatureError::Mismatch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> SigningKey {
+        SigningKey::from_bytes(&[7u8; 32])
+    }
+
+    fn result() -> SpellResult {
+        SpellResult {
+            run_id: "r_1".into(),
+            verdict: "green".into(),
+            duration_ms: 12,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify_roundtrip() {
+        let mut res = result();
+        sign_result(&key(), &mut res).unwrap();
+        assert_eq!(
+            signature_kid(&res).unwrap(),
+            Some(keys::key_id(&key().verifying_key()))
+        );
+        let json = serde_json::to_vec(&res).unwrap();
+        let back = verify_result_json(&key().verifying_key(), &json).unwrap();
+        assert_eq!(back.run_id, "r_1");
+    }
+
+    #[test]
+    fn test_tampered_result_is_rejected() {
+        let mut res = result();
+        sign_result(&key(), &mut res).unwrap();
+        res.verdict = "red".into();
+        assert_eq!(
+            verify_result(&key().verifying_key(), &res),
+            Err(SignatureError::Mismatch)
+        );
+        let other = SigningKey::from_bytes(&[8u8; 32]).verifying_key();
+        res.verdict = "green".into();
+        assert_eq!(verify_result(&other, &res), Err(SignatureError::Mismatch));
+        assert_eq!(
+            verify_result(&other, &result()),
+            Err(SignatureError::Missing)
+        );
+    }
+
+    #[test]
+    fn test_request_signature() {
+        let mut req = serde_json::json!({"cmd": "echo hi", "seed": 1});
+        let req = req.as_object_mut().unwrap();
+        assert_eq!(
+            verify_request(&key().verifying_key(), req),
+            Err(SignatureError::Missing)
+        );
+        sign_request(&key(), req);
+        assert_eq!(
+            req["key_id"].as_str(),
+            Some(keys::key_id(&key().verifying_key()).as_str())
+        );
+        // Field order on the wire does not matter.
+        let reparsed: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(&serde_json::to_string_pretty(req).unwrap()).unwrap();
+        assert_eq!(verify_request(&key().verifying_key(), &reparsed), Ok(()));
+        req.insert("cmd".into(), "rm -rf /".into());
+        assert_eq!(
+            verify_request(&key().verifying_key(), req),
+            Err(SignatureError::Mismatch)
+        );
+    }
+}
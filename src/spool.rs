@@ -0,0 +1,274 @@
+//! Filesystem run queue for hosts without NATS.
+//!
+//! Layout under the spool root:
+//!
+//! - `pending/<id>.json`: requests waiting to run (`magicrune enqueue`)
+//! - `running/<id>.json`: claimed by a worker
+//! - `done/<id>.json`: results; the request is kept as `done/<id>.request.json`
+//! - `failed/<id>.json`: requests the engine rejected, with the error
+//!
+//! Every transition is a `rename(2)` within one filesystem, so a request is
+//! claimed by exactly one worker and a result never appears half-written.
+//! The id is the request's run id, which makes re-enqueueing a finished
+//! request a no-op.
+
+use crate::engine::{self, ExecOptions};
+use crate::policy::Policy;
+use crate::webhook;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const DIRS: [&str; 4] = ["pending", "running", "done", "failed"];
+
+#[derive(Debug, Clone)]
+pub struct Spool {
+    root: PathBuf,
+}
+
+/// A request claimed by this worker.
+#[derive(Debug)]
+pub struct Claim {
+    pub id: String,
+    pub path: PathBuf,
+}
+
+/// What a worker did with one claimed request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Processed {
+    Done { id: String, exit_code: i32 },
+    Failed { id: String, exit_code: i32 },
+}
+
+impl Spool {
+    /// Open (and create if needed) a spool rooted at `root`.
+    pub fn open(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        for d in DIRS {
+            fs::create_dir_all(root.join(d))?;
+        }
+        Ok(Self { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn path(&self, dir: &str, name: &str) -> PathBuf {
+        self.root.join(dir).join(name)
+    }
+
+    /// Write `contents` to `dir/name` via a dot-prefixed temp file and rename.
+    fn write_atomic(&self, dir: &str, name: &str, contents: &[u8]) -> io::Result<PathBuf> {
+        let tmp = self.path(dir, &format!(".{}.tmp", name));
+        fs::write(&tmp, contents)?;
+        let dst = self.path(dir, name);
+        fs::rename(&tmp, &dst)?;
+        Ok(dst)
+    }
+
+    /// Queue a raw request and return its id. A request that is already
+    /// queued, running or finished is not queued again.
+    pub fn enqueue(&self, raw: &[u8]) -> io::Result<String> {
+        serde_json::from_slice::<serde_json::Value>(raw)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let id = engine::compute_run_id(raw, None);
+        let name = format!("{}.json", id);
+        if DIRS.iter().any(|d| self.path(d, &name).exists()) {
+            return Ok(id);
+        }
+        self.write_atomic("pending", &name, raw)?;
+        Ok(id)
+    }
+
+    /// Claim the oldest pending request, if any.
+    pub fn claim(&self) -> io::Result<Option<Claim>> {
+        let mut pending: Vec<(std::time::SystemTime, PathBuf)> =
+            fs::read_dir(self.root.join("pending"))?
+                .filter_map(|e| e.ok())
+                .filter(|e| {
+                    let n = e.file_name();
+                    let n = n.to_string_lossy();
+                    !n.starts_with('.') && n.ends_with(".json")
+                })
+                .filter_map(|e| {
+                    let m = e.metadata().ok()?.modified().ok()?;
+                    Some((m, e.path()))
+                })
+                .collect();
+        pending.sort();
+        for (_, src) in pending {
+            let name = match src.file_name() {
+                Some(n) => n.to_string_lossy().into_owned(),
+                None => continue,
+            };
+            let dst = self.path("running", &name);
+            // Losing the race to another worker shows up as NotFound.
+            match fs::rename(&src, &dst) {
+                Ok(()) => {
+                    return Ok(Some(Claim {
+                        id: name.trim_end_matches(".json").to_string(),
+                        path: dst,
+                    }))
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Move requests left in `running/` by a crashed worker back to pending.
+    /// Only safe while no other worker is using this spool.
+    pub fn requeue_running(&self) -> io::Result<usize> {
+        let mut n = 0;
+        for e in fs::read_dir(self.root.join("running"))?.filter_map(|e| e.ok()) {
+            let name = e.file_name();
+            if name.to_string_lossy().starts_with('.') {
+                continue;
+            }
+            fs::rename(e.path(), self.root.join("pending").join(&name))?;
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    /// Run one claimed request through the engine and file the outcome.
+    pub async fn process(
+        &self,
+        claim: Claim,
+        policy: &Policy,
+        opts: &ExecOptions,
+    ) -> io::Result<Processed> {
+        let raw = fs::read(&claim.path)?;
+        let name = format!("{}.json", claim.id);
+        let processed = match engine::execute(&raw, policy, opts).await {
+            Ok(run) => {
+                let json = serde_json::to_vec_pretty(&run.result)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                self.write_atomic("done", &name, &json)?;
+                fs::rename(
+                    &claim.path,
+                    self.path("done", &format!("{}.request.json", claim.id)),
+                )?;
+                if let Some(url) = &run.callback_url {
+                    if let Err(e) = webhook::deliver(&opts.webhook, url, &run.result).await {
+                        tracing::warn!(run_id = %run.result.run_id, spool_id = %claim.id, error = %e, "spool: callback failed");
+                    }
+                }
+                Processed::Done {
+                    id: claim.id,
+                    exit_code: run.verdict.exit_code(),
+                }
+            }
+            Err(e) => {
+                let json = serde_json::json!({
+                    "error": e.to_string(),
+                    "exit_code": e.exit_code(),
+                    "request": serde_json::from_slice::<serde_json::Value>(&raw).ok(),
+                });
+                self.write_atomic("failed", &name, json.to_string().as_bytes())?;
+                fs::remove_file(&claim.path)?;
+                Processed::Failed {
+                    id: claim.id,
+                    exit_code: e.exit_code(),
+                }
+            }
+        };
+        Ok(processed)
+    }
+
+    /// Process requests until the spool is empty (`once`) or forever,
+    /// polling every `poll` when idle.
+    pub async fn run_worker(
+        &self,
+        policy: &Policy,
+        opts: &ExecOptions,
+        once: bool,
+        poll: Duration,
+    ) -> io::Result<Vec<Processed>> {
+        let mut processed = Vec::new();
+        loop {
+            match self.claim()? {
+                Some(claim) => {
+                    let p = self.process(claim, policy, opts).await?;
+                    if once {
+                        processed.push(p);
+                    }
+                }
+                None if once => return Ok(processed),
+                None => tokio::time::sleep(poll).await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_spool(tag: &str) -> Spool {
+        let root =
+            std::env::temp_dir().join(format!("magicrune_spool_{}_{}", tag, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        Spool::open(root).unwrap()
+    }
+
+    #[test]
+    fn test_enqueue_is_idempotent() {
+        let spool = temp_spool("enqueue");
+        let a = spool.enqueue(br#"{"cmd":""}"#).unwrap();
+        let b = spool.enqueue(br#"{"cmd":""}"#).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(
+            fs::read_dir(spool.root().join("pending")).unwrap().count(),
+            1
+        );
+        assert!(spool.enqueue(b"not json").is_err());
+        let _ = fs::remove_dir_all(spool.root());
+    }
+
+    #[test]
+    fn test_claim_is_exclusive() {
+        let spool = temp_spool("claim");
+        spool.enqueue(br#"{"cmd":""}"#).unwrap();
+        let first = spool.claim().unwrap();
+        assert!(first.is_some());
+        assert!(spool.claim().unwrap().is_none());
+        assert_eq!(spool.requeue_running().unwrap(), 1);
+        assert!(spool.claim().unwrap().is_some());
+        let _ = fs::remove_dir_all(spool.root());
+    }
+
+    #[test]
+    fn test_worker_files_results() {
+        let spool = temp_spool("worker");
+        let ok = spool.enqueue(br#"{"cmd":""}"#).unwrap();
+        let bad = spool.enqueue(br#"{"cmd":"curl http://x.test/"}"#).unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let processed = rt
+            .block_on(spool.run_worker(
+                &Policy::default(),
+                &ExecOptions::default(),
+                true,
+                Duration::from_millis(10),
+            ))
+            .unwrap();
+        assert_eq!(processed.len(), 2);
+        let result: serde_json::Value =
+            serde_json::from_slice(&fs::read(spool.path("done", &format!("{}.json", ok))).unwrap())
+                .unwrap();
+        assert_eq!(result["verdict"], "green");
+        assert!(spool.path("done", &format!("{}.request.json", ok)).exists());
+        let failed: serde_json::Value = serde_json::from_slice(
+            &fs::read(spool.path("failed", &format!("{}.json", bad))).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(failed["exit_code"], 3);
+        // Finished requests are not queued again.
+        spool.enqueue(br#"{"cmd":""}"#).unwrap();
+        assert!(spool.claim().unwrap().is_none());
+        let _ = fs::remove_dir_all(spool.root());
+    }
+}
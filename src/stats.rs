@@ -0,0 +1,325 @@
+//! Aggregates over the run ledger (`magicrune stats`).
+//!
+//! Reads the records of a [`Ledger`](crate::ledger::Ledger) and
+//! sums them up over a time range: how the verdicts split, which risk rules
+//! fire most, run durations (p50, p95, max) and the busiest tenants. Records
+//! written before runs were timestamped have no time; they count only when
+//! no range is given, and are reported as `undated` otherwise.
+
+use crate::ledger::RunRecord;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatsQuery {
+    /// Unix milliseconds, inclusive.
+    pub since_ms: Option<u64>,
+    /// Unix milliseconds, exclusive.
+    pub until_ms: Option<u64>,
+    pub tenant: Option<String>,
+    /// Length of the rule and tenant rankings.
+    pub top: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RuleCount {
+    pub rule: String,
+    /// Runs the rule fired in.
+    pub runs: u64,
+    /// Points it added over those runs.
+    pub points: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TenantCount {
+    pub tenant: String,
+    pub runs: u64,
+    pub red: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Durations {
+    pub p50_ms: u64,
+    pub p95_ms: u64,
+    pub max_ms: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Stats {
+    pub runs: u64,
+    /// Time of the first and last run counted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_ms: Option<u64>,
+    /// Records left out for having no time while a range was given.
+    pub undated: u64,
+    pub verdicts: BTreeMap<String, u64>,
+    pub top_rules: Vec<RuleCount>,
+    pub durations: Durations,
+    pub tenants: Vec<TenantCount>,
+}
+
+/// Aggregate the `records` that `q` selects.
+pub fn compute(records: &[RunRecord], q: &StatsQuery) -> Stats {
+    let ranged = q.since_ms.is_some() || q.until_ms.is_some();
+    let mut stats = Stats::default();
+    let mut rules: BTreeMap<&str, RuleCount> = BTreeMap::new();
+    let mut tenants: BTreeMap<&str, TenantCount> = BTreeMap::new();
+    let mut durations = Vec::new();
+    for r in records {
+        if q.tenant.as_ref().is_some_and(|t| *t != r.tenant) {
+            continue;
+        }
+        if ranged {
+            if r.at_ms == 0 {
+                stats.undated += 1;
+                continue;
+            }
+            if q.since_ms.is_some_and(|s| r.at_ms < s) || q.until_ms.is_some_and(|u| r.at_ms >= u) {
+                continue;
+            }
+        }
+        stats.runs += 1;
+        if r.at_ms > 0 {
+            stats.first_ms = Some(stats.first_ms.map_or(r.at_ms, |f| f.min(r.at_ms)));
+            stats.last_ms = Some(stats.last_ms.map_or(r.at_ms, |l| l.max(r.at_ms)));
+        }
+        *stats.verdicts.entry(r.verdict.clone()).or_default() += 1;
+        for (rule, points) in &r.rules {
+            let c = rules.entry(rule).or_insert_with(|| RuleCount {
+                rule: rule.clone(),
+                ..Default::default()
+            });
+            c.runs += 1;
+            c.points += u64::from(*points);
+        }
+        let t = tenants.entry(&r.tenant).or_insert_with(|| TenantCount {
+            tenant: r.tenant.clone(),
+            ..Default::default()
+        });
+        t.runs += 1;
+        t.red += u64::from(r.verdict == "red");
+        durations.push(r.duration_ms);
+    }
+    durations.sort_unstable();
+    stats.durations = Durations {
+        p50_ms: percentile(&durations, 50),
+        p95_ms: percentile(&durations, 95),
+        max_ms: durations.last().copied().unwrap_or(0),
+    };
+    stats.top_rules = rules.into_values().collect();
+    stats
+        .top_rules
+        .sort_by(|a, b| b.runs.cmp(&a.runs).then(b.points.cmp(&a.points)));
+    stats.top_rules.truncate(q.top);
+    stats.tenants = tenants.into_values().collect();
+    stats.tenants.sort_by_key(|t| std::cmp::Reverse(t.runs));
+    stats.tenants.truncate(q.top);
+    stats
+}
+
+/// Nearest-rank percentile of sorted `values`.
+fn percentile(values: &[u64], p: usize) -> u64 {
+    if values.is_empty() {
+        return 0;
+    }
+    let rank = (p * values.len()).div_ceil(100).max(1);
+    values[rank - 1]
+}
+
+/// The plain-text report.
+pub fn render(stats: &Stats) -> String {
+    let mut out = format!("runs: {}", stats.runs);
+    if let (Some(first), Some(last)) = (stats.first_ms, stats.last_ms) {
+        out.push_str(&format!(
+            " ({} .. {})",
+            crate::audit::rfc3339(first),
+            crate::audit::rfc3339(last)
+        ));
+    }
+    if stats.undated > 0 {
+        out.push_str(&format!(", {} undated left out", stats.undated));
+    }
+    out.push('\n');
+    let list = |items: Vec<String>| {
+        if items.is_empty() {
+            "-".to_string()
+        } else {
+            items.join(", ")
+        }
+    };
+    let verdicts = stats
+        .verdicts
+        .iter()
+        .map(|(v, n)| {
+            format!(
+                "{} {} ({:.1}%)",
+                v,
+                n,
+                *n as f64 * 100.0 / stats.runs as f64
+            )
+        })
+        .collect();
+    out.push_str(&format!("verdicts: {}\n", list(verdicts)));
+    let rules = stats
+        .top_rules
+        .iter()
+        .map(|r| format!("{} {}", r.rule, r.runs))
+        .collect();
+    out.push_str(&format!("top rules: {}\n", list(rules)));
+    out.push_str(&format!(
+        "duration: p50 {} ms, p95 {} ms, max {} ms\n",
+        stats.durations.p50_ms, stats.durations.p95_ms, stats.durations.max_ms
+    ));
+    let tenants = stats
+        .tenants
+        .iter()
+        .map(|t| format!("{} {} (red {})", t.tenant, t.runs, t.red))
+        .collect();
+    out.push_str(&format!("busiest tenants: {}\n", list(tenants)));
+    out
+}
+
+/// `30s`, `30m`, `24h` or `7d` in milliseconds.
+pub(crate) fn parse_age(v: &str) -> Option<u64> {
+    let unit = v.chars().last()?;
+    let ms = match unit {
+        's' => 1_000,
+        'm' => 60_000,
+        'h' => 3_600_000,
+        'd' => 86_400_000,
+        _ => return None,
+    };
+    let n: u64 = v[..v.len() - 1].parse().ok()?;
+    Some(n.saturating_mul(ms))
+}
+
+/// `--since` / `--until`: a time ago (`30m`, `24h`, `7d`) or a UTC date
+/// (`2024-01-31`), as Unix milliseconds.
+pub fn parse_time(v: &str, now_ms: u64) -> Result<u64, String> {
+    let bad = || {
+        format!(
+            "{:?} is not a time ago like 24h or a date like 2024-01-31",
+            v
+        )
+    };
+    if v.ends_with(|c: char| c.is_ascii_alphabetic()) {
+        let ago = parse_age(v).ok_or_else(bad)?;
+        return Ok(now_ms.saturating_sub(ago));
+    }
+    let parts: Vec<&str> = v.split('-').collect();
+    let [y, m, d] = parts[..] else {
+        return Err(bad());
+    };
+    let (y, m, d): (i64, i64, i64) = (
+        y.parse().map_err(|_| bad())?,
+        m.parse().map_err(|_| bad())?,
+        d.parse().map_err(|_| bad())?,
+    );
+    if y < 1970 || !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+        return Err(bad());
+    }
+    // Days since the epoch of a civil date (H. Hinnant's algorithm).
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    Ok(days as u64 * 86_400_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rec(run_id: &str, tenant: &str, verdict: &str, at_ms: u64, duration_ms: u64) -> RunRecord {
+        RunRecord {
+            run_id: run_id.into(),
+            tenant: tenant.into(),
+            verdict: verdict.into(),
+            at_ms,
+            duration_ms,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_compute() {
+        let mut records: Vec<RunRecord> = (0..20)
+            .map(|i| {
+                rec(
+                    &format!("r_{}", i),
+                    "acme",
+                    "green",
+                    1_000 + i,
+                    10 * (i + 1),
+                )
+            })
+            .collect();
+        records.push(RunRecord {
+            rules: [("ssh".to_string(), 30), ("net_intent".to_string(), 40)].into(),
+            ..rec("r_red", "beta", "red", 2_000, 5)
+        });
+        records.push(RunRecord {
+            rules: [("ssh".to_string(), 30)].into(),
+            ..rec("r_yellow", "beta", "yellow", 3_000, 5)
+        });
+        records.push(rec("r_old", "acme", "green", 0, 0));
+        let q = StatsQuery {
+            top: 5,
+            ..Default::default()
+        };
+        let all = compute(&records, &q);
+        assert_eq!(all.runs, 23);
+        assert_eq!(all.undated, 0);
+        assert_eq!(all.verdicts["green"], 21);
+        assert_eq!(all.top_rules[0].rule, "ssh");
+        assert_eq!(all.top_rules[0].points, 60);
+        assert_eq!(all.tenants[0].tenant, "acme");
+        assert_eq!(all.tenants[1].red, 1);
+        assert_eq!(all.durations.max_ms, 200);
+        assert_eq!(all.durations.p95_ms, 190);
+
+        let ranged = compute(
+            &records,
+            &StatsQuery {
+                since_ms: Some(1_500),
+                tenant: Some("beta".into()),
+                top: 1,
+                ..Default::default()
+            },
+        );
+        assert_eq!(ranged.runs, 2);
+        assert_eq!(
+            (ranged.first_ms, ranged.last_ms),
+            (Some(2_000), Some(3_000))
+        );
+        assert_eq!(ranged.top_rules.len(), 1);
+        let ranged = compute(
+            &records,
+            &StatsQuery {
+                until_ms: Some(1_010),
+                ..q
+            },
+        );
+        assert_eq!((ranged.runs, ranged.undated), (10, 1));
+        let text = render(&ranged);
+        assert!(text.starts_with(
+            "runs: 10 (1970-01-01T00:00:01.000Z .. 1970-01-01T00:00:01.009Z), 1 undated left out\n"
+        ));
+        assert!(text.contains("verdicts: green 10 (100.0%)\ntop rules: -\n"));
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("24h", 100_000_000), Ok(100_000_000 - 86_400_000));
+        assert_eq!(parse_time("1d", 0), Ok(0));
+        assert_eq!(parse_time("2024-01-01", 0), Ok(1_704_067_200_000));
+        assert_eq!(parse_time("1970-01-01", 0), Ok(0));
+        assert!(parse_time("3w", 0).is_err());
+        assert!(parse_time("2024-13-01", 0).is_err());
+        assert!(parse_time("yesterday", 0).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+//! `${VAR}` placeholders in requests.
+//!
+//! `cmd`, every `files[].path` and the string values of `env` may reference
+//! variables. Values come from the request's own `vars` map and from the
+//! caller (`exec --var key=value`), the caller winning. The request is
+//! rewritten before anything else reads it, so the run id is the hash of the
+//! final request; `vars` itself is dropped from it. `$${` stands for a
+//! literal `${`. A placeholder without a value is a schema error.
+
+use crate::error::MagicruneError;
+use crate::messages::{pointer_token, Code};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+/// Variable name to value.
+pub type Vars = BTreeMap<String, String>;
+
+fn fail(msg: String) -> MagicruneError {
+    MagicruneError::new(Code::Schema, &[&msg])
+}
+
+/// Parse a `key=value` argument.
+pub fn parse_var(arg: &str) -> Option<(String, String)> {
+    let (k, v) = arg.split_once('=')?;
+    is_name(k).then(|| (k.to_string(), v.to_string()))
+}
+
+fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// `raw` with its placeholders resolved; borrowed when it has none and no
+/// `vars`. Payloads that are not a JSON object are returned as they are for
+/// the caller to reject.
+pub fn render<'a>(raw: &'a [u8], vars: &Vars) -> Result<Cow<'a, [u8]>, MagicruneError> {
+    let needle = |n: &[u8]| raw.windows(n.len()).any(|w| w == n);
+    if !needle(b"${") && !needle(b"\"vars\"") {
+        return Ok(Cow::Borrowed(raw));
+    }
+    let Ok(serde_json::Value::Object(mut req)) = serde_json::from_slice(raw) else {
+        return Ok(Cow::Borrowed(raw));
+    };
+    let mut changed = false;
+    let mut all = Vars::new();
+    if let Some(own) = req.remove("vars") {
+        changed = true;
+        let serde_json::Value::Object(own) = own else {
+            return Err(fail("vars must be object".into()).at("/vars"));
+        };
+        for (k, v) in own {
+            let v = match v {
+                serde_json::Value::String(s) => s,
+                serde_json::Value::Number(_) | serde_json::Value::Bool(_) => v.to_string(),
+                _ => {
+                    return Err(fail("vars values must be string/number/bool".into())
+                        .at(format!("/vars/{}", pointer_token(&k))))
+                }
+            };
+            all.insert(k, v);
+        }
+    }
+    all.extend(vars.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+    // `field` names the value in messages, `pointer` locates it.
+    let mut apply =
+        |v: &mut serde_json::Value, field: &str, pointer: String| -> Result<(), MagicruneError> {
+            if let serde_json::Value::String(s) = v {
+                if s.contains("${") {
+                    *s = substitute(s, &all, field).map_err(|e| e.at(pointer))?;
+                    changed = true;
+                }
+            }
+            Ok(())
+        };
+    if let Some(cmd) = req.get_mut("cmd") {
+        apply(cmd, "cmd", "/cmd".into())?;
+    }
+    if let Some(serde_json::Value::Array(files)) = req.get_mut("files") {
+        for (i, f) in files.iter_mut().enumerate() {
+            if let Some(path) = f.get_mut("path") {
+                apply(
+                    path,
+                    &format!("files[{}].path", i),
+                    format!("/files/{}/path", i),
+                )?;
+            }
+        }
+    }
+    if let Some(serde_json::Value::Object(env)) = req.get_mut("env") {
+        for (k, v) in env.iter_mut() {
+            apply(
+                v,
+                &format!("env.{}", k),
+                format!("/env/{}", pointer_token(k)),
+            )?;
+        }
+    }
+    if !changed {
+        return Ok(Cow::Borrowed(raw));
+    }
+    serde_json::to_vec(&req)
+        .map(Cow::Owned)
+        .map_err(|e| MagicruneError::internal(format!("serialize: {}", e)))
+}
+
+/// Replace each `${NAME}` in `s`; `${` not followed by a name and `}` is
+/// kept as written.
+fn substitute(s: &str, vars: &Vars, field: &str) -> Result<String, MagicruneError> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(at) = rest.find("${") {
+        if rest[..at].ends_with('$') {
+            out.push_str(&rest[..at - 1]);
+            out.push_str("${");
+            rest = &rest[at + 2..];
+            continue;
+        }
+        out.push_str(&rest[..at]);
+        let after = &rest[at + 2..];
+        match after.find('}').map(|end| &after[..end]) {
+            Some(name) if is_name(name) => {
+                let Some(value) = vars.get(name) else {
+                    return Err(fail(format!(
+                        "unresolved variable ${{{}}} in {}",
+                        name, field
+                    )));
+                };
+                out.push_str(value);
+                rest = &after[name.len() + 1..];
+            }
+            _ => {
+                out.push_str("${");
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_str(raw: &str, vars: &[(&str, &str)]) -> Result<serde_json::Value, MagicruneError> {
+        let vars = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let out = render(raw.as_bytes(), &vars)?;
+        Ok(serde_json::from_slice(&out).unwrap())
+    }
+
+    #[test]
+    fn test_render_fields_and_precedence() {
+        let out = render_str(
+            r#"{"cmd":"cat ${DIR}/${NAME}","stdin":"${NAME}","files":[{"path":"${DIR}/${NAME}"}],"env":{"N":"${NAME}","K":1},"vars":{"DIR":"/tmp","NAME":"a"}}"#,
+            &[("NAME", "b")],
+        )
+        .unwrap();
+        assert_eq!(out["cmd"], "cat /tmp/b");
+        assert_eq!(out["files"][0]["path"], "/tmp/b");
+        assert_eq!(out["env"]["N"], "b");
+        assert_eq!(out["env"]["K"], 1);
+        // Only cmd, file paths and env are templated.
+        assert_eq!(out["stdin"], "${NAME}");
+        assert!(out.get("vars").is_none());
+    }
+
+    #[test]
+    fn test_render_escapes_and_errors() {
+        let out = render_str(r#"{"cmd":"echo $${HOME} ${1} ${X:-y}"}"#, &[]).unwrap();
+        assert_eq!(out["cmd"], "echo ${HOME} ${1} ${X:-y}");
+        match render_str(r#"{"env":{"P":"${MISSING}"}}"#, &[]) {
+            Err(MagicruneError::InvalidRequest(e)) => {
+                assert_eq!(e.text, "schema: unresolved variable ${MISSING} in env.P");
+                assert_eq!(e.pointer, "/env/P");
+            }
+            other => panic!("{:?}", other),
+        }
+        let raw = br#"{"cmd":"true"}"#;
+        assert!(matches!(
+            render(raw, &Vars::new()).unwrap(),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(parse_var("A_1=x=y"), Some(("A_1".into(), "x=y".into())));
+        assert_eq!(parse_var("1A=x"), None);
+    }
+}
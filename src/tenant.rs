@@ -0,0 +1,193 @@
+//! Tenants: the namespace a run belongs to.
+//!
+//! A run's tenant is, first match wins: the tenant the caller bound the run
+//! to ([`crate::ExecOptions::tenant`], from `MAGICRUNE_TENANT` or the subject
+//! a request arrived on), the request's `tenant` field, or
+//! [`DEFAULT_TENANT`]. A request naming a tenant other than the one it is
+//! bound to is rejected, so a consumer serving one team cannot be used to
+//! run under another team's name.
+//!
+//! The tenant labels the run's span and metrics, is stamped on the result,
+//! picks the quarantine folder (`quarantine/<tenant>/`), names remote scratch
+//! dirs and Kubernetes objects, keys ledger queries and is what
+//! [`TenantQuotas`] counts against.
+
+use crate::error::MagicruneError;
+use crate::messages::Code;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Tenant of requests that name none.
+pub const DEFAULT_TENANT: &str = "default";
+
+/// Where red runs and core dumps are kept, one folder per tenant.
+pub fn quarantine_dir(tenant: &str) -> std::path::PathBuf {
+    std::path::Path::new("quarantine").join(tenant)
+}
+
+/// Check a tenant name: 1–63 ASCII letters, digits, `-` or `_`, starting and
+/// ending with a letter or digit (valid as a path segment and a k8s label).
+pub fn check_name(name: &str) -> Result<(), String> {
+    let ok = !name.is_empty()
+        && name.len() <= 63
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.ends_with(|c: char| c.is_ascii_alphanumeric());
+    if ok {
+        Ok(())
+    } else {
+        Err(format!("invalid tenant name {:?}", name))
+    }
+}
+
+/// The tenant a run belongs to, given the tenant it is bound to (if any) and
+/// the request's `tenant` field (empty when absent).
+pub fn resolve(bound: Option<&str>, requested: &str) -> Result<String, MagicruneError> {
+    if !requested.is_empty() {
+        check_name(requested).map_err(|_| {
+            MagicruneError::new(Code::InvalidTenant, &[&format!("{:?}", requested)]).at("/tenant")
+        })?;
+    }
+    match bound {
+        Some(b) if !requested.is_empty() && requested != b => {
+            Err(MagicruneError::new(Code::TenantMismatch, &[&b, &requested]).at("/tenant"))
+        }
+        Some(b) => Ok(b.to_string()),
+        None if requested.is_empty() => Ok(DEFAULT_TENANT.to_string()),
+        None => Ok(requested.to_string()),
+    }
+}
+
+/// Tenant bound by a request subject of the form `run.req.<tenant>`. The
+/// shared `run.req.default` subject, shard subjects (see `crate::instance`)
+/// and wildcards bind none.
+pub fn from_subject(subject: &str) -> Option<String> {
+    if subject.rsplit('.').nth(1) == Some("shard") {
+        return None;
+    }
+    let last = subject.rsplit('.').next()?;
+    if last == DEFAULT_TENANT || last == "*" || last == ">" || check_name(last).is_err() {
+        return None;
+    }
+    Some(last.to_string())
+}
+
+/// Per-tenant run quota: at most `limit` runs per tenant in each fixed
+/// `window`. Tenants may get their own limit; 0 means unlimited.
+#[derive(Debug)]
+pub struct TenantQuotas {
+    limit: u64,
+    window: Duration,
+    overrides: HashMap<String, u64>,
+    used: Mutex<HashMap<String, (Instant, u64)>>,
+}
+
+impl TenantQuotas {
+    pub fn new(limit: u64, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            overrides: HashMap::new(),
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Give `tenant` its own limit.
+    pub fn with_limit(mut self, tenant: &str, limit: u64) -> Self {
+        self.overrides.insert(tenant.to_string(), limit);
+        self
+    }
+
+    /// Quotas from `MAGICRUNE_TENANT_QUOTA` (`<n>` for every tenant, or
+    /// `<n>,acme=<n>,...` with per-tenant limits) counted over
+    /// `MAGICRUNE_TENANT_QUOTA_WINDOW_SEC` (default 60); `None` when unset.
+    pub fn from_env() -> Option<Self> {
+        let spec = crate::ports::env::var("MAGICRUNE_TENANT_QUOTA")
+            .ok()
+            .filter(|s| !s.trim().is_empty())?;
+        let window = crate::ports::env::var("MAGICRUNE_TENANT_QUOTA_WINDOW_SEC")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(60);
+        let mut quotas = Self::new(0, Duration::from_secs(window));
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match part.split_once('=') {
+                Some((tenant, n)) => match n.trim().parse() {
+                    Ok(n) => quotas = quotas.with_limit(tenant.trim(), n),
+                    Err(_) => tracing::warn!(entry = part, "tenant quota: ignoring entry"),
+                },
+                None => match part.parse() {
+                    Ok(n) => quotas.limit = n,
+                    Err(_) => tracing::warn!(entry = part, "tenant quota: ignoring entry"),
+                },
+            }
+        }
+        Some(quotas)
+    }
+
+    pub fn limit_for(&self, tenant: &str) -> u64 {
+        self.overrides.get(tenant).copied().unwrap_or(self.limit)
+    }
+
+    /// Count one run against `tenant`; false if its quota is used up.
+    pub fn try_acquire(&self, tenant: &str) -> bool {
+        let limit = self.limit_for(tenant);
+        if limit == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut used = self.used.lock().unwrap();
+        let slot = used.entry(tenant.to_string()).or_insert((now, 0));
+        if now.duration_since(slot.0) >= self.window {
+            *slot = (now, 0);
+        }
+        if slot.1 >= limit {
+            return false;
+        }
+        slot.1 += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_binding() {
+        assert_eq!(resolve(None, "").unwrap(), DEFAULT_TENANT);
+        assert_eq!(resolve(None, "acme").unwrap(), "acme");
+        assert_eq!(resolve(Some("acme"), "").unwrap(), "acme");
+        assert_eq!(resolve(Some("acme"), "acme").unwrap(), "acme");
+        let err = resolve(Some("acme"), "beta").unwrap_err();
+        assert_eq!(err.exit_code(), 3);
+        let err = resolve(None, "../etc").unwrap_err();
+        assert_eq!(err.exit_code(), 1);
+    }
+
+    #[test]
+    fn test_from_subject() {
+        assert_eq!(from_subject("run.req.acme").as_deref(), Some("acme"));
+        assert_eq!(from_subject("run.req.default"), None);
+        assert_eq!(from_subject("run.req.*"), None);
+        assert_eq!(from_subject("run.req.>"), None);
+        assert_eq!(from_subject("run.req.shard.3"), None);
+    }
+
+    #[test]
+    fn test_quota_per_tenant() {
+        let q = TenantQuotas::new(2, Duration::from_secs(60)).with_limit("big", 0);
+        assert!(q.try_acquire("acme"));
+        assert!(q.try_acquire("acme"));
+        assert!(!q.try_acquire("acme"));
+        // Other tenants have their own budget.
+        assert!(q.try_acquire("beta"));
+        assert!((0..10).all(|_| q.try_acquire("big")));
+        let q = TenantQuotas::new(1, Duration::ZERO);
+        assert!(q.try_acquire("acme"));
+        assert!(q.try_acquire("acme"));
+    }
+}
@@ -0,0 +1,409 @@
+//! In-memory implementations of the ports, for running the engine
+//! hermetically.
+//!
+//! Each mock is an ordinary port implementation. [`MockEnvironmentPort`]
+//! takes effect on engine code once installed on the current thread with
+//! [`MockEnvironmentPort::install`], since the engine reads its settings
+//! through [`crate::ports::env::var`]. The installed environment is per
+//! thread, so a test that runs the engine on tokio's current-thread runtime
+//! (the `#[tokio::test]` default) sees it throughout.
+
+use crate::ports::env::{EnvError, InstalledEnv};
+use crate::ports::io::IoError;
+use crate::ports::{EnvironmentPort, FileSystemPort, NetworkPort, TimePort};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+/// Environment variables in a map, starting empty.
+#[derive(Debug, Default)]
+pub struct MockEnvironmentPort {
+    vars: Mutex<BTreeMap<String, String>>,
+    current_dir: String,
+    args: Vec<String>,
+}
+
+impl MockEnvironmentPort {
+    pub fn new() -> Self {
+        Self {
+            current_dir: "/".into(),
+            args: vec!["magicrune".into()],
+            ..Default::default()
+        }
+    }
+
+    pub fn with_var(self, key: &str, value: &str) -> Self {
+        self.set_var(key, value);
+        self
+    }
+
+    pub fn with_current_dir(mut self, dir: &str) -> Self {
+        self.current_dir = dir.into();
+        self
+    }
+
+    pub fn with_args(mut self, args: &[&str]) -> Self {
+        self.args = args.iter().map(|a| a.to_string()).collect();
+        self
+    }
+
+    /// Make this the environment engine code reads on this thread, until
+    /// the guard is dropped.
+    pub fn install(self) -> InstalledEnv {
+        crate::ports::env::install(Arc::new(self))
+    }
+}
+
+impl EnvironmentPort for MockEnvironmentPort {
+    fn get_var(&self, key: &str) -> Result<String, EnvError> {
+        self.vars
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .ok_or_else(|| EnvError::NotFound(key.to_string()))
+    }
+
+    fn set_var(&self, key: &str, value: &str) {
+        self.vars
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), value.to_string());
+    }
+
+    fn remove_var(&self, key: &str) {
+        self.vars.lock().unwrap().remove(key);
+    }
+
+    fn current_dir(&self) -> Result<String, EnvError> {
+        Ok(self.current_dir.clone())
+    }
+
+    fn args(&self) -> Vec<String> {
+        self.args.clone()
+    }
+}
+
+/// Files in a map, keyed by path.
+#[derive(Debug, Default)]
+pub struct MockFileSystemPort {
+    files: Mutex<BTreeMap<String, Vec<u8>>>,
+    read_only: bool,
+}
+
+impl MockFileSystemPort {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_file(self, path: &str, data: &[u8]) -> Self {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), data.to_vec());
+        self
+    }
+
+    /// Refuse writes and deletes with `PermissionDenied`.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Every file, as `(path, contents)`.
+    pub fn files(&self) -> Vec<(String, Vec<u8>)> {
+        self.files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(p, d)| (p.clone(), d.clone()))
+            .collect()
+    }
+
+    fn writable(&self, path: &str) -> Result<(), IoError> {
+        if self.read_only {
+            return Err(IoError::PermissionDenied(path.to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl FileSystemPort for MockFileSystemPort {
+    async fn read(&self, path: &str) -> Result<Vec<u8>, IoError> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| IoError::NotFound(path.to_string()))
+    }
+
+    async fn write(&self, path: &str, data: &[u8]) -> Result<(), IoError> {
+        self.writable(path)?;
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    async fn exists(&self, path: &str) -> Result<bool, IoError> {
+        Ok(self.files.lock().unwrap().contains_key(path))
+    }
+
+    async fn delete(&self, path: &str) -> Result<(), IoError> {
+        self.writable(path)?;
+        self.files
+            .lock()
+            .unwrap()
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| IoError::NotFound(path.to_string()))
+    }
+}
+
+/// A request seen by [`MockNetworkPort`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockRequest {
+    pub method: &'static str,
+    pub url: String,
+    pub body: Vec<u8>,
+}
+
+/// Canned responses by URL; every request is recorded, and a URL without a
+/// response fails as unreachable.
+#[derive(Debug, Default)]
+pub struct MockNetworkPort {
+    responses: HashMap<String, Result<Vec<u8>, String>>,
+    requests: Mutex<Vec<MockRequest>>,
+}
+
+impl MockNetworkPort {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_response(mut self, url: &str, body: &[u8]) -> Self {
+        self.responses.insert(url.to_string(), Ok(body.to_vec()));
+        self
+    }
+
+    pub fn with_failure(mut self, url: &str, error: &str) -> Self {
+        self.responses
+            .insert(url.to_string(), Err(error.to_string()));
+        self
+    }
+
+    /// Requests made so far, in order.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn respond(&self, method: &'static str, url: &str, body: &[u8]) -> Result<Vec<u8>, IoError> {
+        self.requests.lock().unwrap().push(MockRequest {
+            method,
+            url: url.to_string(),
+            body: body.to_vec(),
+        });
+        match self.responses.get(url) {
+            Some(Ok(body)) => Ok(body.clone()),
+            Some(Err(e)) => Err(IoError::OperationFailed(e.clone())),
+            None => Err(IoError::OperationFailed(format!("{}: unreachable", url))),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl NetworkPort for MockNetworkPort {
+    async fn http_get(&self, url: &str) -> Result<Vec<u8>, IoError> {
+        self.respond("GET", url, &[])
+    }
+
+    async fn http_post(&self, url: &str, body: &[u8]) -> Result<Vec<u8>, IoError> {
+        self.respond("POST", url, body)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Clock {
+    now_ms: u64,
+    sleepers: Vec<(u64, Waker)>,
+}
+
+/// A clock that only moves when told to. `sleep` returns once
+/// [`MockTimePort::advance`] has moved the clock past its deadline.
+#[derive(Debug, Default, Clone)]
+pub struct MockTimePort {
+    clock: Arc<Mutex<Clock>>,
+}
+
+impl MockTimePort {
+    pub fn new(start_millis: u64) -> Self {
+        let t = Self::default();
+        t.clock.lock().unwrap().now_ms = start_millis;
+        t
+    }
+
+    /// Move the clock forward, waking the sleeps that are due.
+    pub fn advance(&self, by: Duration) {
+        let mut c = self.clock.lock().unwrap();
+        c.now_ms += by.as_millis() as u64;
+        let now = c.now_ms;
+        let (due, waiting) = std::mem::take(&mut c.sleepers)
+            .into_iter()
+            .partition(|(deadline, _)| *deadline <= now);
+        c.sleepers = waiting;
+        drop(c);
+        for (_, waker) in due {
+            waker.wake();
+        }
+    }
+
+    /// Sleeps not yet due.
+    pub fn sleepers(&self) -> usize {
+        self.clock.lock().unwrap().sleepers.len()
+    }
+}
+
+struct Sleep {
+    clock: Arc<Mutex<Clock>>,
+    deadline: u64,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut c = self.clock.lock().unwrap();
+        if c.now_ms >= self.deadline {
+            return Poll::Ready(());
+        }
+        c.sleepers.push((self.deadline, cx.waker().clone()));
+        Poll::Pending
+    }
+}
+
+#[async_trait::async_trait]
+impl TimePort for MockTimePort {
+    fn now_millis(&self) -> u64 {
+        self.clock.lock().unwrap().now_ms
+    }
+
+    fn now_secs(&self) -> u64 {
+        self.now_millis() / 1000
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        let deadline = self.now_millis() + duration.as_millis() as u64;
+        Sleep {
+            clock: self.clock.clone(),
+            deadline,
+        }
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{self, ExecOptions};
+    use crate::policy::Policy;
+
+    #[tokio::test]
+    async fn test_installed_env_reaches_the_engine() {
+        let _env = MockEnvironmentPort::new()
+            .with_var("MAGICRUNE_DRY_RUN", "1")
+            .with_var("MAGICRUNE_TENANT", "acme")
+            .install();
+        let opts = ExecOptions::from_env();
+        assert!(opts.dry_run);
+        assert_eq!(opts.tenant.as_deref(), Some("acme"));
+        let run = engine::execute(br#"{"cmd":"echo hi"}"#, &Policy::default(), &opts)
+            .await
+            .unwrap();
+        assert_eq!(run.result.tenant, "acme");
+        assert_eq!(run.result.exit_code, 0);
+    }
+
+    #[test]
+    fn test_install_is_scoped() {
+        std::env::set_var("MAGICRUNE_TESTING_SCOPE", "process");
+        {
+            let _env = MockEnvironmentPort::new()
+                .with_var("MAGICRUNE_TESTING_SCOPE", "mock")
+                .install();
+            assert_eq!(
+                crate::ports::env::var("MAGICRUNE_TESTING_SCOPE").unwrap(),
+                "mock"
+            );
+            {
+                let _inner = MockEnvironmentPort::new().install();
+                assert!(crate::ports::env::var("MAGICRUNE_TESTING_SCOPE").is_err());
+            }
+            assert_eq!(
+                crate::ports::env::var("MAGICRUNE_TESTING_SCOPE").unwrap(),
+                "mock"
+            );
+        }
+        assert_eq!(
+            crate::ports::env::var("MAGICRUNE_TESTING_SCOPE").unwrap(),
+            "process"
+        );
+        std::env::remove_var("MAGICRUNE_TESTING_SCOPE");
+    }
+
+    #[tokio::test]
+    async fn test_mock_fs_and_network() {
+        let fs = MockFileSystemPort::new().with_file("/in.txt", b"data");
+        assert_eq!(fs.read("/in.txt").await.unwrap(), b"data");
+        fs.write("/out.txt", b"x").await.unwrap();
+        assert!(fs.exists("/out.txt").await.unwrap());
+        fs.delete("/in.txt").await.unwrap();
+        assert!(matches!(
+            fs.read("/in.txt").await,
+            Err(IoError::NotFound(_))
+        ));
+        let ro = MockFileSystemPort::new().read_only();
+        assert!(matches!(
+            ro.write("/a", b"").await,
+            Err(IoError::PermissionDenied(_))
+        ));
+
+        let net = MockNetworkPort::new()
+            .with_response("https://a.test/", b"ok")
+            .with_failure("https://b.test/", "reset");
+        assert_eq!(net.http_get("https://a.test/").await.unwrap(), b"ok");
+        assert!(net.http_post("https://b.test/", b"q").await.is_err());
+        assert!(net.http_get("https://c.test/").await.is_err());
+        let reqs = net.requests();
+        assert_eq!(reqs.len(), 3);
+        assert_eq!(
+            (reqs[1].method, reqs[1].body.as_slice()),
+            ("POST", &b"q"[..])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_time_moves_only_when_advanced() {
+        let time = MockTimePort::new(5_000);
+        assert_eq!((time.now_millis(), time.now_secs()), (5_000, 5));
+        let sleeper = {
+            let time = time.clone();
+            tokio::spawn(async move { time.sleep(Duration::from_secs(2)).await })
+        };
+        while time.sleepers() == 0 {
+            tokio::task::yield_now().await;
+        }
+        time.advance(Duration::from_secs(1));
+        tokio::task::yield_now().await;
+        assert!(!sleeper.is_finished());
+        time.advance(Duration::from_secs(1));
+        sleeper.await.unwrap();
+        assert_eq!(time.now_millis(), 7_000);
+        time.sleep(Duration::ZERO).await;
+    }
+}
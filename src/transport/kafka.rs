@@ -0,0 +1,275 @@
+//! Kafka transport (feature `kafka`, librdkafka via rdkafka).
+//!
+//! Requests are consumed from `topic` in consumer group `group` with auto
+//! commit off; [`Transport::ack`] commits the offset. Requests are keyed by
+//! the request's `tenant` (falling back to `policy_id`), so one tenant's runs
+//! land on one partition in order.
+//!
+//! With `transactional_id` set, each result is produced inside a transaction
+//! that also commits the request's offset (`send_offsets_to_transaction`), so
+//! a crash can never leave a published result with an uncommitted request or
+//! the other way round. The consumer reads with `isolation.level=read_committed`.
+//!
+//! URL: `kafka://host:9092[,host2:9092]?topic=..&group=..&result_topic=..&transactional_id=..`.
+
+use super::{Delivery, Transport, TransportError};
+use crate::jet::compute_msg_id;
+use async_trait::async_trait;
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::message::{Header, Headers, Message, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+use rdkafka::{Offset, TopicPartitionList};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const MSG_ID_HEADER: &str = "msg_id";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KafkaConfig {
+    /// Comma-separated bootstrap servers.
+    pub brokers: String,
+    pub topic: String,
+    pub result_topic: String,
+    pub group: String,
+    /// Enables transactional result produce when set.
+    pub transactional_id: Option<String>,
+    /// How long `consume` waits for a message.
+    pub poll_ms: u64,
+}
+
+impl KafkaConfig {
+    /// Parse a `kafka://` URL; query parameters `topic`, `result_topic`,
+    /// `group`, `transactional_id` and `poll_ms` override the defaults.
+    pub fn from_url(raw: &str) -> Result<Self, TransportError> {
+        let rest = raw
+            .strip_prefix("kafka://")
+            .ok_or_else(|| TransportError(format!("not a kafka url: {}", raw)))?;
+        let (brokers, query) = rest.split_once('?').unwrap_or((rest, ""));
+        if brokers.is_empty() {
+            return Err(TransportError("kafka url has no brokers".into()));
+        }
+        let mut cfg = KafkaConfig {
+            brokers: brokers.trim_end_matches('/').to_string(),
+            topic: "magicrune.req".into(),
+            result_topic: String::new(),
+            group: "magicrune".into(),
+            transactional_id: None,
+            poll_ms: 1000,
+        };
+        for (k, v) in url::form_urlencoded::parse(query.as_bytes()) {
+            match k.as_ref() {
+                "topic" => cfg.topic = v.into_owned(),
+                "result_topic" => cfg.result_topic = v.into_owned(),
+                "group" => cfg.group = v.into_owned(),
+                "transactional_id" => cfg.transactional_id = Some(v.into_owned()),
+                "poll_ms" => cfg.poll_ms = v.parse().unwrap_or(cfg.poll_ms),
+                _ => {}
+            }
+        }
+        if cfg.result_topic.is_empty() {
+            cfg.result_topic = format!("{}.res", cfg.topic.trim_end_matches(".req"));
+        }
+        Ok(cfg)
+    }
+}
+
+/// Partition key for a request: its `tenant`, else its `policy_id`.
+pub fn partition_key(payload: &[u8]) -> Option<String> {
+    let v: serde_json::Value = serde_json::from_slice(payload).ok()?;
+    ["tenant", "policy_id"]
+        .iter()
+        .find_map(|k| v.get(*k).and_then(|s| s.as_str()))
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+}
+
+pub struct KafkaTransport {
+    cfg: KafkaConfig,
+    consumer: StreamConsumer,
+    producer: FutureProducer,
+    /// Delivery id -> (partition, offset) awaiting ack.
+    inflight: Mutex<HashMap<String, (i32, i64)>>,
+    txn_open: AtomicBool,
+}
+
+impl KafkaTransport {
+    pub fn connect(cfg: KafkaConfig) -> Result<Self, TransportError> {
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", &cfg.brokers)
+            .set("group.id", &cfg.group)
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", "earliest")
+            .set("isolation.level", "read_committed")
+            .create()
+            .map_err(TransportError::new)?;
+        consumer
+            .subscribe(&[&cfg.topic])
+            .map_err(TransportError::new)?;
+        let mut pc = ClientConfig::new();
+        pc.set("bootstrap.servers", &cfg.brokers)
+            .set("enable.idempotence", "true");
+        if let Some(id) = &cfg.transactional_id {
+            pc.set("transactional.id", id);
+        }
+        let producer: FutureProducer = pc.create().map_err(TransportError::new)?;
+        if cfg.transactional_id.is_some() {
+            producer
+                .init_transactions(Duration::from_secs(30))
+                .map_err(TransportError::new)?;
+        }
+        Ok(Self {
+            cfg,
+            consumer,
+            producer,
+            inflight: Mutex::new(HashMap::new()),
+            txn_open: AtomicBool::new(false),
+        })
+    }
+
+    pub fn config(&self) -> &KafkaConfig {
+        &self.cfg
+    }
+
+    fn offsets(&self, partition: i32, offset: i64) -> Result<TopicPartitionList, TransportError> {
+        let mut tpl = TopicPartitionList::new();
+        tpl.add_partition_offset(&self.cfg.topic, partition, Offset::Offset(offset + 1))
+            .map_err(TransportError::new)?;
+        Ok(tpl)
+    }
+}
+
+#[async_trait]
+impl Transport for KafkaTransport {
+    async fn publish_request(&self, payload: &[u8]) -> Result<(), TransportError> {
+        let key = partition_key(payload);
+        let msg_id = compute_msg_id(payload);
+        let mut record =
+            FutureRecord::to(&self.cfg.topic)
+                .payload(payload)
+                .headers(OwnedHeaders::new().insert(Header {
+                    key: MSG_ID_HEADER,
+                    value: Some(msg_id.as_bytes()),
+                }));
+        if let Some(k) = &key {
+            record = record.key(k.as_str());
+        }
+        self.producer
+            .send(record, Duration::from_secs(10))
+            .await
+            .map_err(|(e, _)| TransportError::new(e))?;
+        Ok(())
+    }
+
+    async fn consume(&self) -> Result<Option<Delivery>, TransportError> {
+        let wait = Duration::from_millis(self.cfg.poll_ms);
+        let msg = match tokio::time::timeout(wait, self.consumer.recv()).await {
+            Err(_) => return Ok(None),
+            Ok(r) => r.map_err(TransportError::new)?,
+        };
+        let payload = msg.payload().unwrap_or_default().to_vec();
+        let msg_id = msg
+            .headers()
+            .and_then(|h| {
+                h.iter()
+                    .find(|h| h.key == MSG_ID_HEADER)
+                    .and_then(|h| h.value)
+                    .map(|v| String::from_utf8_lossy(v).into_owned())
+            })
+            .unwrap_or_else(|| compute_msg_id(&payload));
+        let id = format!("{}/{}/{}", msg.topic(), msg.partition(), msg.offset());
+        self.inflight
+            .lock()
+            .unwrap()
+            .insert(id.clone(), (msg.partition(), msg.offset()));
+        let queued_at_ms = msg.timestamp().to_millis().map(|ms| ms as u64);
+        Ok(Some(Delivery {
+            id,
+            msg_id,
+            payload,
+            queued_at_ms,
+        }))
+    }
+
+    async fn publish_result(&self, run_id: &str, result: &[u8]) -> Result<(), TransportError> {
+        if self.cfg.transactional_id.is_some() && !self.txn_open.swap(true, Ordering::SeqCst) {
+            self.producer
+                .begin_transaction()
+                .map_err(TransportError::new)?;
+        }
+        let record = FutureRecord::to(&self.cfg.result_topic)
+            .key(run_id)
+            .payload(result);
+        self.producer
+            .send(record, Duration::from_secs(10))
+            .await
+            .map_err(|(e, _)| TransportError::new(e))?;
+        Ok(())
+    }
+
+    async fn ack(&self, delivery: &Delivery) -> Result<(), TransportError> {
+        let Some((partition, offset)) = self.inflight.lock().unwrap().remove(&delivery.id) else {
+            return Ok(());
+        };
+        let tpl = self.offsets(partition, offset)?;
+        if self.txn_open.swap(false, Ordering::SeqCst) {
+            let meta = self
+                .consumer
+                .group_metadata()
+                .ok_or_else(|| TransportError("no consumer group metadata".into()))?;
+            let timeout = Duration::from_secs(30);
+            let committed = self
+                .producer
+                .send_offsets_to_transaction(&tpl, &meta, timeout)
+                .and_then(|_| self.producer.commit_transaction(timeout));
+            if let Err(e) = committed {
+                let _ = self.producer.abort_transaction(timeout);
+                return Err(TransportError::new(e));
+            }
+            return Ok(());
+        }
+        self.consumer
+            .commit(&tpl, CommitMode::Async)
+            .map_err(TransportError::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_url() {
+        let cfg = KafkaConfig::from_url("kafka://k1:9092,k2:9092").unwrap();
+        assert_eq!(cfg.brokers, "k1:9092,k2:9092");
+        assert_eq!(cfg.topic, "magicrune.req");
+        assert_eq!(cfg.result_topic, "magicrune.res");
+        assert!(cfg.transactional_id.is_none());
+
+        let cfg = KafkaConfig::from_url("kafka://k1:9092?topic=jobs&group=g&transactional_id=mr-1")
+            .unwrap();
+        assert_eq!(cfg.topic, "jobs");
+        assert_eq!(cfg.result_topic, "jobs.res");
+        assert_eq!(cfg.group, "g");
+        assert_eq!(cfg.transactional_id.as_deref(), Some("mr-1"));
+
+        assert!(KafkaConfig::from_url("redis://x").is_err());
+        assert!(KafkaConfig::from_url("kafka://").is_err());
+    }
+
+    #[test]
+    fn test_partition_key_prefers_tenant() {
+        assert_eq!(
+            partition_key(br#"{"tenant":"acme","policy_id":"default"}"#).as_deref(),
+            Some("acme")
+        );
+        assert_eq!(
+            partition_key(br#"{"policy_id":"default"}"#).as_deref(),
+            Some("default")
+        );
+        assert_eq!(partition_key(br#"{"cmd":""}"#), None);
+        assert_eq!(partition_key(b"nope"), None);
+    }
+}
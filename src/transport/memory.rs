@@ -0,0 +1,105 @@
+//! In-process transport: a queue in a mutex. Used by tests and embedders that
+//! want the consume loop without a broker.
+
+use super::{Delivery, Transport, TransportError};
+use crate::jet::compute_msg_id;
+use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+#[derive(Debug, Default)]
+struct State {
+    queue: VecDeque<Delivery>,
+    inflight: HashMap<String, Delivery>,
+    results: Vec<(String, Vec<u8>)>,
+    dead: Vec<Delivery>,
+    next_id: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryTransport {
+    state: Mutex<State>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Results published so far, as `(run_id, result json)`.
+    pub fn results(&self) -> Vec<(String, Vec<u8>)> {
+        self.state.lock().unwrap().results.clone()
+    }
+
+    /// Deliveries handed out but not acked yet.
+    pub fn unacked(&self) -> usize {
+        self.state.lock().unwrap().inflight.len()
+    }
+
+    /// Deliveries rejected without requeue.
+    pub fn dead_letters(&self) -> Vec<Delivery> {
+        self.state.lock().unwrap().dead.clone()
+    }
+
+    /// Put every unacked delivery back on the queue, as a broker would after
+    /// its ack timeout.
+    pub fn redeliver_unacked(&self) {
+        let mut s = self.state.lock().unwrap();
+        let pending: Vec<Delivery> = s.inflight.drain().map(|(_, d)| d).collect();
+        s.queue.extend(pending);
+    }
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    async fn publish_request(&self, payload: &[u8]) -> Result<(), TransportError> {
+        let mut s = self.state.lock().unwrap();
+        s.next_id += 1;
+        let id = s.next_id.to_string();
+        s.queue.push_back(Delivery {
+            id,
+            msg_id: compute_msg_id(payload),
+            payload: payload.to_vec(),
+            queued_at_ms: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_millis() as u64),
+        });
+        Ok(())
+    }
+
+    async fn consume(&self) -> Result<Option<Delivery>, TransportError> {
+        let mut s = self.state.lock().unwrap();
+        let d = s.queue.pop_front();
+        if let Some(d) = &d {
+            s.inflight.insert(d.id.clone(), d.clone());
+        }
+        Ok(d)
+    }
+
+    async fn publish_result(&self, run_id: &str, result: &[u8]) -> Result<(), TransportError> {
+        self.state
+            .lock()
+            .unwrap()
+            .results
+            .push((run_id.to_string(), result.to_vec()));
+        Ok(())
+    }
+
+    async fn ack(&self, delivery: &Delivery) -> Result<(), TransportError> {
+        self.state.lock().unwrap().inflight.remove(&delivery.id);
+        Ok(())
+    }
+
+    async fn nack(&self, delivery: &Delivery, requeue: bool) -> Result<(), TransportError> {
+        let mut s = self.state.lock().unwrap();
+        if let Some(d) = s.inflight.remove(&delivery.id) {
+            if requeue {
+                s.queue.push_back(d);
+            } else {
+                s.dead.push(d);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,648 @@
+//! Queue transports for the consumer.
+//!
+//! A [`Transport`] delivers raw request payloads and carries results back;
+//! [`serve`] is the broker-independent consume loop on top of it (dedupe,
+//! engine run, result publish and callback, ack). NATS keeps its own loop in the binary;
+//! other brokers plug in here.
+
+#[cfg(feature = "amqp")]
+pub mod amqp;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod memory;
+#[cfg(feature = "redis")]
+pub mod redis;
+pub mod sim;
+
+use crate::concurrency::{self, Concurrency, Limiter, Slot};
+use crate::engine::{self, ExecOptions};
+use crate::error::MagicruneError;
+use crate::instance;
+use crate::policy::Policy;
+use crate::schema::{self, SpellResult, EXPIRED_VERDICT, REJECTED_VERDICT};
+use crate::template;
+use crate::webhook;
+use async_trait::async_trait;
+use futures_util::future::{Fuse, FusedFuture, FutureExt};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("transport: {0}")]
+pub struct TransportError(pub String);
+
+impl TransportError {
+    pub fn new(e: impl std::fmt::Display) -> Self {
+        TransportError(e.to_string())
+    }
+}
+
+/// One request handed out by a transport.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    /// Broker-specific handle passed back to [`Transport::ack`].
+    pub id: String,
+    /// Dedupe key: the producer's message id, or the payload hash.
+    pub msg_id: String,
+    pub payload: Vec<u8>,
+    /// When the broker queued the message, in Unix ms; `None` when it does
+    /// not say. Relative `deadline_ms` count from here.
+    pub queued_at_ms: Option<u64>,
+}
+
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Queue a request (producer side).
+    async fn publish_request(&self, payload: &[u8]) -> Result<(), TransportError>;
+    /// Next request, or `None` if nothing arrived within the poll interval.
+    async fn consume(&self) -> Result<Option<Delivery>, TransportError>;
+    /// Publish the result for `run_id`.
+    async fn publish_result(&self, run_id: &str, result: &[u8]) -> Result<(), TransportError>;
+    /// Mark a delivery as processed so it is not redelivered.
+    async fn ack(&self, delivery: &Delivery) -> Result<(), TransportError>;
+    /// Reject a delivery: `requeue` hands it back for another attempt,
+    /// otherwise it is dead-lettered where the broker supports that. The
+    /// default drops rejected deliveries and leaves requeueing to the
+    /// broker's redelivery.
+    async fn nack(&self, delivery: &Delivery, requeue: bool) -> Result<(), TransportError> {
+        if requeue {
+            Ok(())
+        } else {
+            self.ack(delivery).await
+        }
+    }
+}
+
+/// Bounded window of recently seen message ids.
+#[derive(Debug)]
+pub struct Dedupe {
+    seen: HashSet<String>,
+    order: VecDeque<String>,
+    max: usize,
+}
+
+impl Dedupe {
+    pub fn new(max: usize) -> Self {
+        Self {
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+            max: max.max(1),
+        }
+    }
+
+    /// Record `id`; false if it was already in the window.
+    pub fn insert(&mut self, id: &str) -> bool {
+        if !self.seen.insert(id.to_string()) {
+            return false;
+        }
+        self.order.push_back(id.to_string());
+        if self.order.len() > self.max {
+            if let Some(old) = self.order.pop_front() {
+                self.seen.remove(&old);
+            }
+        }
+        true
+    }
+}
+
+/// Counters reported by [`serve`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServeStats {
+    pub total: u64,
+    pub dupe: u64,
+    pub red: u64,
+    /// Handed back for lack of the policy's runner labels.
+    pub handed_back: u64,
+}
+
+/// Pause after handing a request back, so that a broker redelivering it at
+/// once to this consumer does not spin.
+const HAND_BACK_PAUSE: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Connect to the transport named by `url`'s scheme.
+pub async fn connect(url: &str) -> Result<Box<dyn Transport>, TransportError> {
+    let scheme = url.split("://").next().unwrap_or_default();
+    match scheme {
+        #[cfg(feature = "redis")]
+        "redis" | "rediss" => {
+            let cfg = redis::RedisConfig::from_url(url)?;
+            tracing::info!(
+                stream = %cfg.stream,
+                group = %cfg.group,
+                consumer = %cfg.consumer,
+                "consume: redis"
+            );
+            Ok(Box::new(redis::RedisTransport::connect(cfg).await?))
+        }
+        #[cfg(feature = "amqp")]
+        "amqp" | "amqps" => {
+            let cfg = amqp::AmqpConfig::from_url(url)?;
+            tracing::info!(
+                queue = %cfg.queue,
+                dead_letter = %cfg.dead_letter_queue(),
+                "consume: amqp"
+            );
+            Ok(Box::new(amqp::AmqpTransport::connect(cfg).await?))
+        }
+        #[cfg(feature = "kafka")]
+        "kafka" => {
+            let cfg = kafka::KafkaConfig::from_url(url)?;
+            tracing::info!(
+                topic = %cfg.topic,
+                group = %cfg.group,
+                transactional = cfg.transactional_id.is_some(),
+                "consume: kafka"
+            );
+            Ok(Box::new(kafka::KafkaTransport::connect(cfg)?))
+        }
+        other => Err(TransportError(format!(
+            "unsupported transport: {} (not built in?)",
+            other
+        ))),
+    }
+}
+
+fn request_seed(payload: &[u8]) -> u64 {
+    serde_json::from_slice::<serde_json::Value>(payload)
+        .ok()
+        .and_then(|v| v.get("seed").and_then(|s| s.as_u64()))
+        .unwrap_or(0)
+}
+
+fn request_deadline(payload: &[u8]) -> Option<u64> {
+    serde_json::from_slice::<serde_json::Value>(payload)
+        .ok()
+        .and_then(|v| v.get("deadline_ms")?.as_u64())
+}
+
+fn request_callback(payload: &[u8]) -> Option<String> {
+    serde_json::from_slice::<serde_json::Value>(payload)
+        .ok()
+        .and_then(|v| v.get("callback_url")?.as_str().map(str::to_string))
+}
+
+/// Run id as the consumer reports it: the payload, its `${VAR}` placeholders
+/// resolved, hashed with its `seed` (0 when absent), so producers can
+/// predict it.
+pub fn consumer_run_id(payload: &[u8]) -> String {
+    let payload = template::render(payload, &template::Vars::new())
+        .unwrap_or(std::borrow::Cow::Borrowed(payload));
+    engine::compute_run_id(&payload, Some(request_seed(&payload)))
+}
+
+/// Handle one delivery: run it, deliver the result to the request's callback
+/// and publish it (unless the webhook config says the callback suffices).
+/// Returns the result, or `None` for payloads that cannot be a request at all
+/// (dropped).
+pub async fn handle(
+    transport: &dyn Transport,
+    delivery: &Delivery,
+    policy: &Policy,
+    opts: &ExecOptions,
+) -> Result<Option<SpellResult>, TransportError> {
+    let payload = match template::render(&delivery.payload, &opts.vars) {
+        Ok(p) => p,
+        Err(e) => {
+            tracing::warn!(msg_id = %delivery.msg_id, error = %e, "consume: dropping");
+            return Ok(None);
+        }
+    };
+    let seed = request_seed(&payload);
+    let run_id = engine::compute_run_id(&payload, Some(seed));
+    let opts = ExecOptions {
+        seed: Some(seed),
+        queued_at_ms: delivery.queued_at_ms,
+        ..opts.clone()
+    };
+    // Requests taken off the queue past their `deadline_ms` are answered
+    // without running; unauthenticated ones are left to `execute` to reject.
+    let late = request_deadline(&payload)
+        .and_then(|d| schema::overdue(d, delivery.queued_at_ms, opts.clock.now_millis()))
+        .filter(|_| {
+            engine::authenticate_request(&delivery.payload, policy, &opts.producer_keys).is_ok()
+        });
+    // `execute` resolves the placeholders again, from the original payload.
+    let result = match late {
+        Some(late) => {
+            tracing::warn!(run_id = %run_id, overdue_ms = late, "consume: expired");
+            let mut res = SpellResult {
+                run_id: run_id.clone(),
+                verdict: EXPIRED_VERDICT.into(),
+                instance_id: opts.instance_id.clone().unwrap_or_default(),
+                findings: vec![schema::expired_finding(late)],
+                ..Default::default()
+            };
+            opts.keyring
+                .sign_result(&mut res)
+                .map_err(TransportError::new)?;
+            res
+        }
+        None => match engine::execute(&delivery.payload, policy, &opts).await {
+            Ok(run) => run.result,
+            Err(MagicruneError::InvalidRequest(e)) => {
+                tracing::warn!(msg_id = %delivery.msg_id, error = %e, "consume: dropping");
+                return Ok(None);
+            }
+            // Rejected requests are answered red, as the NATS consumer does;
+            // unauthenticated ones get a verdict of their own. The reason is a
+            // finding, its catalog code the kind.
+            Err(e) => {
+                tracing::warn!(run_id = %run_id, policy_digest = %policy.digest, error = %e, "consume: rejected");
+                let verdict = match e {
+                    MagicruneError::Unauthenticated(_) => REJECTED_VERDICT,
+                    _ => "red",
+                };
+                let mut res = SpellResult {
+                    run_id: run_id.clone(),
+                    verdict: verdict.into(),
+                    risk_score: 80,
+                    instance_id: opts.instance_id.clone().unwrap_or_default(),
+                    findings: vec![e.finding()],
+                    ..Default::default()
+                };
+                opts.keyring
+                    .sign_result(&mut res)
+                    .map_err(TransportError::new)?;
+                res
+            }
+        },
+    };
+    let callback = request_callback(&payload);
+    if !webhook::dispatch(&opts.webhook, callback.as_deref(), &result).await {
+        return Ok(Some(result));
+    }
+    let bytes = serde_json::to_vec(&result).map_err(TransportError::new)?;
+    transport.publish_result(&run_id, &bytes).await?;
+    Ok(Some(result))
+}
+
+/// Consume until the transport fails or `max` deliveries were seen,
+/// running up to `concurrency` requests at once and, within
+/// `opts.memory_budget_mb`, holding back the next one until its memory fits.
+/// On a failure the runs in flight are finished and settled before it is
+/// returned.
+pub async fn serve(
+    transport: &dyn Transport,
+    policy: &Policy,
+    opts: &ExecOptions,
+    dedupe_max: usize,
+    max: Option<u64>,
+    concurrency: Concurrency,
+) -> Result<ServeStats, TransportError> {
+    let mut dedupe = Dedupe::new(dedupe_max);
+    let mut stats = ServeStats::default();
+    let limiter = concurrency.start();
+    let mut running = FuturesUnordered::new();
+    let intake = Fuse::terminated();
+    tokio::pin!(intake);
+    let budget = opts.memory_budget_mb.unwrap_or(u64::MAX);
+    let mut reserved = 0;
+    let mut held: Option<(Slot, Delivery, u64)> = None;
+    let mut failed = None;
+    let mut drained = false;
+    loop {
+        if held
+            .as_ref()
+            .is_some_and(|(_, _, mb)| running.is_empty() || reserved + mb <= budget)
+        {
+            let (slot, delivery, mb) = held.take().unwrap();
+            if failed.is_some() {
+                // Already failing, and its error is the one returned.
+                let _ = transport.nack(&delivery, true).await;
+            } else {
+                reserved += mb;
+                running.push(run(transport, delivery, mb, slot, policy, opts));
+            }
+        }
+        if intake.is_terminated()
+            && held.is_none()
+            && failed.is_none()
+            && !drained
+            && max.is_none_or(|m| stats.total < m)
+        {
+            intake.set(take(&limiter, transport).fuse());
+        }
+        if intake.is_terminated() && running.is_empty() {
+            break;
+        }
+        let step = tokio::select! {
+            (slot, next) = &mut intake, if !intake.is_terminated() => Step::Took(slot, next),
+            Some((delivery, mb, outcome)) = running.next(), if !running.is_empty() => {
+                Step::Ran(delivery, mb, outcome)
+            }
+        };
+        let settled = async {
+            let (slot, delivery) = match step {
+                Step::Ran(delivery, mb, outcome) => {
+                    reserved -= mb;
+                    return settle(transport, &delivery, *outcome, &mut stats).await;
+                }
+                Step::Took(slot, next) => match next? {
+                    Some(delivery) => (slot, delivery),
+                    None => {
+                        drained = max.is_some();
+                        return Ok(());
+                    }
+                },
+            };
+            if failed.is_some() {
+                // Taken as a run was failing: leave it to the next consumer.
+                return transport.nack(&delivery, true).await;
+            }
+            stats.total += 1;
+            // Before dedupe: a consumer with the labels has to see it as new.
+            let missing = instance::missing_labels(&policy.runner_labels, &opts.runner_labels);
+            if !missing.is_empty() {
+                stats.handed_back += 1;
+                tracing::warn!(
+                    msg_id = %delivery.msg_id,
+                    hint = %instance::routing_hint(&policy.runner_labels, &missing),
+                    "consume: handing back"
+                );
+                transport.nack(&delivery, true).await?;
+                opts.clock.sleep(HAND_BACK_PAUSE).await;
+                return Ok(());
+            }
+            if !dedupe.insert(&delivery.msg_id) {
+                stats.dupe += 1;
+                return transport.ack(&delivery).await;
+            }
+            let mb = concurrency::memory_mb_of(&delivery.payload, policy.limits.memory_mb);
+            if !running.is_empty() && reserved + mb > budget {
+                tracing::info!(
+                    msg_id = %delivery.msg_id,
+                    memory_mb = mb,
+                    reserved_mb = reserved,
+                    budget_mb = budget,
+                    "consume: waiting for memory"
+                );
+                held = Some((slot, delivery, mb));
+                return Ok(());
+            }
+            reserved += mb;
+            running.push(run(transport, delivery, mb, slot, policy, opts));
+            Ok(())
+        }
+        .await;
+        if let Err(e) = settled {
+            failed.get_or_insert(e);
+        }
+    }
+    match failed {
+        Some(e) => Err(e),
+        None => Ok(stats),
+    }
+}
+
+enum Step {
+    Took(Slot, Result<Option<Delivery>, TransportError>),
+    Ran(
+        Delivery,
+        u64,
+        Box<Result<Option<SpellResult>, TransportError>>,
+    ),
+}
+
+/// Run a delivery that reserved `mb` of the memory budget in `slot`.
+async fn run<'a>(
+    transport: &'a dyn Transport,
+    delivery: Delivery,
+    mb: u64,
+    slot: Slot,
+    policy: &'a Policy,
+    opts: &'a ExecOptions,
+) -> (
+    Delivery,
+    u64,
+    Box<Result<Option<SpellResult>, TransportError>>,
+) {
+    let outcome = Box::new(handle(transport, &delivery, policy, opts).await);
+    drop(slot);
+    (delivery, mb, outcome)
+}
+
+/// The next delivery, once a slot is free to run it.
+async fn take(
+    limiter: &Arc<Limiter>,
+    transport: &dyn Transport,
+) -> (Slot, Result<Option<Delivery>, TransportError>) {
+    let slot = limiter.acquire().await;
+    (slot, transport.consume().await)
+}
+
+/// Ack or nack a delivery after its run.
+async fn settle(
+    transport: &dyn Transport,
+    delivery: &Delivery,
+    outcome: Result<Option<SpellResult>, TransportError>,
+    stats: &mut ServeStats,
+) -> Result<(), TransportError> {
+    match outcome {
+        Ok(Some(res)) => {
+            if res.verdict == "red" {
+                stats.red += 1;
+            }
+            transport.ack(delivery).await
+        }
+        // Not a request at all: retrying cannot help.
+        Ok(None) => transport.nack(delivery, false).await,
+        Err(e) => {
+            transport.nack(delivery, true).await?;
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::memory::MemoryTransport;
+    use super::*;
+
+    #[test]
+    fn test_dedupe_window() {
+        let mut d = Dedupe::new(2);
+        assert!(d.insert("a"));
+        assert!(!d.insert("a"));
+        assert!(d.insert("b"));
+        assert!(d.insert("c"));
+        // "a" fell out of the window.
+        assert!(d.insert("a"));
+    }
+
+    #[test]
+    fn test_consumer_run_id_uses_seed() {
+        let a = consumer_run_id(br#"{"cmd":""}"#);
+        assert_eq!(a, engine::compute_run_id(br#"{"cmd":""}"#, Some(0)));
+        let b = consumer_run_id(br#"{"cmd":"","seed":7}"#);
+        assert_eq!(
+            b,
+            engine::compute_run_id(br#"{"cmd":"","seed":7}"#, Some(7))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve_over_memory_transport() {
+        let t = MemoryTransport::new();
+        t.publish_request(br#"{"cmd":""}"#).await.unwrap();
+        t.publish_request(br#"{"cmd":""}"#).await.unwrap();
+        t.publish_request(br#"{"cmd":"curl http://x.test/"}"#)
+            .await
+            .unwrap();
+        t.publish_request(b"not json").await.unwrap();
+        let stats = serve(
+            &t,
+            &Policy::default(),
+            &ExecOptions::default(),
+            16,
+            Some(4),
+            Concurrency::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            stats,
+            ServeStats {
+                total: 4,
+                dupe: 1,
+                red: 1,
+                handed_back: 0
+            }
+        );
+        let results = t.results();
+        assert_eq!(results.len(), 2);
+        let green = consumer_run_id(br#"{"cmd":""}"#);
+        let (_, bytes) = results.iter().find(|(id, _)| id == &green).unwrap();
+        // The memory queue stamps requests, so the wait is reported.
+        let green: SpellResult = serde_json::from_slice(bytes).unwrap();
+        assert!(green.queue_ms.is_some());
+        let red = consumer_run_id(br#"{"cmd":"curl http://x.test/"}"#);
+        let (_, bytes) = results.iter().find(|(id, _)| id == &red).unwrap();
+        let red: SpellResult = serde_json::from_slice(bytes).unwrap();
+        assert_eq!(red.findings[0].kind, "MR2004");
+        assert_eq!(t.unacked(), 0);
+        assert_eq!(t.dead_letters().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_serve_runs_concurrently() {
+        let t = MemoryTransport::new();
+        for seed in 0..5 {
+            let raw = format!(r#"{{"cmd":"","seed":{}}}"#, seed);
+            t.publish_request(raw.as_bytes()).await.unwrap();
+        }
+        let stats = serve(
+            &t,
+            &Policy::default(),
+            &ExecOptions::default(),
+            16,
+            Some(5),
+            Concurrency::Auto { min: 2, max: 4 },
+        )
+        .await
+        .unwrap();
+        assert_eq!((stats.total, stats.red), (5, 0));
+        assert_eq!(t.results().len(), 5);
+        assert_eq!(t.unacked(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_serve_holds_requests_over_memory_budget() {
+        let t = MemoryTransport::new();
+        for seed in 0..3 {
+            let raw = format!(
+                r#"{{"cmd":"","seed":{},"limits":{{"memory_mb":400}}}}"#,
+                seed
+            );
+            t.publish_request(raw.as_bytes()).await.unwrap();
+        }
+        // Room for one at a time: the others wait their turn, unacked.
+        let opts = ExecOptions {
+            memory_budget_mb: Some(600),
+            ..Default::default()
+        };
+        let stats = serve(
+            &t,
+            &Policy::default(),
+            &opts,
+            16,
+            Some(3),
+            Concurrency::Fixed(3),
+        )
+        .await
+        .unwrap();
+        assert_eq!((stats.total, stats.red), (3, 0));
+        assert_eq!(t.results().len(), 3);
+        assert_eq!(t.unacked(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_expired_request_is_not_run() {
+        let t = MemoryTransport::new();
+        let late = format!(
+            r#"{{"cmd":"curl http://x.test/","deadline_ms":{}}}"#,
+            schema::DEADLINE_ABSOLUTE_MS
+        );
+        t.publish_request(late.as_bytes()).await.unwrap();
+        t.publish_request(br#"{"cmd":"","deadline_ms":60000}"#)
+            .await
+            .unwrap();
+        let stats = serve(
+            &t,
+            &Policy::default(),
+            &ExecOptions::default(),
+            16,
+            Some(2),
+            Concurrency::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!((stats.total, stats.red), (2, 0));
+        let result = |raw: &[u8]| -> SpellResult {
+            let id = consumer_run_id(raw);
+            let (_, bytes) = t.results().into_iter().find(|(r, _)| r == &id).unwrap();
+            serde_json::from_slice(&bytes).unwrap()
+        };
+        let expired = result(late.as_bytes());
+        assert_eq!(expired.verdict, EXPIRED_VERDICT);
+        assert_eq!(expired.findings[0].kind, schema::DEADLINE);
+        assert!(expired.timings.is_none());
+        assert_eq!(
+            result(br#"{"cmd":"","deadline_ms":60000}"#).verdict,
+            "green"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve_hands_back_without_runner_labels() {
+        let t = MemoryTransport::new();
+        t.publish_request(br#"{"cmd":""}"#).await.unwrap();
+        let policy = Policy {
+            runner_labels: vec!["gpu".into()],
+            ..Default::default()
+        };
+        let stats = serve(
+            &t,
+            &policy,
+            &ExecOptions::default(),
+            16,
+            Some(1),
+            Concurrency::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!((stats.total, stats.handed_back), (1, 1));
+        assert!(t.results().is_empty());
+        // Requeued, and seen as new by a consumer that has the label.
+        let opts = ExecOptions {
+            runner_labels: vec!["gpu".into(), "eu-west".into()],
+            ..Default::default()
+        };
+        let stats = serve(&t, &policy, &opts, 16, Some(1), Concurrency::default())
+            .await
+            .unwrap();
+        assert_eq!((stats.handed_back, stats.dupe), (0, 0));
+        assert_eq!(t.results().len(), 1);
+    }
+}
@@ -0,0 +1,266 @@
+//! Redis Streams transport (feature `redis`).
+//!
+//! Requests are entries in a stream (`XADD <stream> * payload <bytes> msg_id <id>`)
+//! read through a consumer group, so several consumers share the work and
+//! unacked entries stay pending. Results go to `<stream>:res` with the run id
+//! as a field. Entries left pending by a dead consumer are taken over with
+//! `XAUTOCLAIM` once they have been idle for `claim_idle_ms`.
+//!
+//! URL: `redis://[user:pass@]host:port[/db]?stream=..&group=..&consumer=..`.
+
+use super::{Delivery, Transport, TransportError};
+use crate::jet::compute_msg_id;
+use async_trait::async_trait;
+use redis::aio::MultiplexedConnection;
+use redis::streams::{StreamReadOptions, StreamReadReply};
+use redis::{AsyncCommands, Value};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedisConfig {
+    /// Connection URL without the magicrune query parameters.
+    pub url: String,
+    pub stream: String,
+    pub group: String,
+    pub consumer: String,
+    /// How long `consume` blocks waiting for new entries.
+    pub block_ms: usize,
+    /// Idle time after which another consumer's pending entry is reclaimed.
+    pub claim_idle_ms: u64,
+}
+
+impl RedisConfig {
+    /// Parse a `redis://` / `rediss://` URL; query parameters `stream`,
+    /// `group`, `consumer`, `block_ms` and `claim_idle_ms` override the defaults.
+    pub fn from_url(raw: &str) -> Result<Self, TransportError> {
+        let mut url = url::Url::parse(raw).map_err(TransportError::new)?;
+        if url.scheme() != "redis" && url.scheme() != "rediss" {
+            return Err(TransportError(format!("not a redis url: {}", url.scheme())));
+        }
+        let mut cfg = RedisConfig {
+            url: String::new(),
+            stream: "magicrune:req".into(),
+            group: "magicrune".into(),
+            consumer: default_consumer(),
+            block_ms: 1000,
+            claim_idle_ms: 60_000,
+        };
+        for (k, v) in url.query_pairs() {
+            match k.as_ref() {
+                "stream" => cfg.stream = v.into_owned(),
+                "group" => cfg.group = v.into_owned(),
+                "consumer" => cfg.consumer = v.into_owned(),
+                "block_ms" => cfg.block_ms = v.parse().unwrap_or(cfg.block_ms),
+                "claim_idle_ms" => cfg.claim_idle_ms = v.parse().unwrap_or(cfg.claim_idle_ms),
+                _ => {}
+            }
+        }
+        url.set_query(None);
+        cfg.url = url.to_string();
+        Ok(cfg)
+    }
+
+    pub fn result_stream(&self) -> String {
+        format!("{}:res", self.stream)
+    }
+}
+
+fn default_consumer() -> String {
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "magicrune".into());
+    format!("{}-{}", host, std::process::id())
+}
+
+pub struct RedisTransport {
+    cfg: RedisConfig,
+    conn: MultiplexedConnection,
+}
+
+impl RedisTransport {
+    /// Connect and make sure the stream and consumer group exist.
+    pub async fn connect(cfg: RedisConfig) -> Result<Self, TransportError> {
+        let client = redis::Client::open(cfg.url.as_str()).map_err(TransportError::new)?;
+        let mut conn = client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(TransportError::new)?;
+        let created: redis::RedisResult<()> = conn
+            .xgroup_create_mkstream(&cfg.stream, &cfg.group, "0")
+            .await;
+        if let Err(e) = created {
+            // The group surviving a restart is the normal case.
+            if e.code() != Some("BUSYGROUP") {
+                return Err(TransportError::new(e));
+            }
+        }
+        Ok(Self { cfg, conn })
+    }
+
+    pub fn config(&self) -> &RedisConfig {
+        &self.cfg
+    }
+
+    /// Take over one entry another consumer left pending too long.
+    async fn autoclaim(&self) -> Result<Option<Delivery>, TransportError> {
+        let mut conn = self.conn.clone();
+        let reply: Value = redis::cmd("XAUTOCLAIM")
+            .arg(&self.cfg.stream)
+            .arg(&self.cfg.group)
+            .arg(&self.cfg.consumer)
+            .arg(self.cfg.claim_idle_ms)
+            .arg("0-0")
+            .arg("COUNT")
+            .arg(1)
+            .query_async(&mut conn)
+            .await
+            .map_err(TransportError::new)?;
+        // Reply: [next-cursor, [[id, [field, value, ...]], ...], (deleted ids)]
+        let Value::Array(parts) = reply else {
+            return Ok(None);
+        };
+        let Some(Value::Array(entries)) = parts.get(1) else {
+            return Ok(None);
+        };
+        for entry in entries {
+            if let Value::Array(pair) = entry {
+                if let (Some(id), Some(Value::Array(fields))) = (pair.first(), pair.get(1)) {
+                    let id: String = redis::from_redis_value(id).map_err(TransportError::new)?;
+                    let mut map = std::collections::HashMap::new();
+                    for kv in fields.chunks(2) {
+                        if let [k, v] = kv {
+                            let k: String =
+                                redis::from_redis_value(k).map_err(TransportError::new)?;
+                            map.insert(k, v.clone());
+                        }
+                    }
+                    return delivery_from_fields(id, &map).map(Some);
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn delivery_from_fields(
+    id: String,
+    fields: &std::collections::HashMap<String, Value>,
+) -> Result<Delivery, TransportError> {
+    let payload: Vec<u8> = match fields.get("payload") {
+        Some(v) => redis::from_redis_value(v).map_err(TransportError::new)?,
+        None => Vec::new(),
+    };
+    let msg_id = match fields.get("msg_id") {
+        Some(v) => redis::from_redis_value(v).map_err(TransportError::new)?,
+        None => compute_msg_id(&payload),
+    };
+    // Stream entry ids start with the ms they were added at.
+    let queued_at_ms = id.split('-').next().and_then(|ms| ms.parse().ok());
+    Ok(Delivery {
+        id,
+        msg_id,
+        payload,
+        queued_at_ms,
+    })
+}
+
+#[async_trait]
+impl Transport for RedisTransport {
+    async fn publish_request(&self, payload: &[u8]) -> Result<(), TransportError> {
+        let mut conn = self.conn.clone();
+        let _: String = conn
+            .xadd(
+                &self.cfg.stream,
+                "*",
+                &[
+                    ("payload", payload),
+                    ("msg_id", compute_msg_id(payload).as_bytes()),
+                ],
+            )
+            .await
+            .map_err(TransportError::new)?;
+        Ok(())
+    }
+
+    async fn consume(&self) -> Result<Option<Delivery>, TransportError> {
+        if let Some(d) = self.autoclaim().await? {
+            return Ok(Some(d));
+        }
+        let mut conn = self.conn.clone();
+        let opts = StreamReadOptions::default()
+            .group(&self.cfg.group, &self.cfg.consumer)
+            .count(1)
+            .block(self.cfg.block_ms);
+        let reply: Option<StreamReadReply> = conn
+            .xread_options(&[&self.cfg.stream], &[">"], &opts)
+            .await
+            .map_err(TransportError::new)?;
+        let Some(reply) = reply else {
+            return Ok(None);
+        };
+        for key in reply.keys {
+            if let Some(entry) = key.ids.into_iter().next() {
+                return delivery_from_fields(entry.id, &entry.map).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    async fn publish_result(&self, run_id: &str, result: &[u8]) -> Result<(), TransportError> {
+        let mut conn = self.conn.clone();
+        let _: String = conn
+            .xadd(
+                self.cfg.result_stream(),
+                "*",
+                &[("run_id", run_id.as_bytes()), ("result", result)],
+            )
+            .await
+            .map_err(TransportError::new)?;
+        Ok(())
+    }
+
+    async fn ack(&self, delivery: &Delivery) -> Result<(), TransportError> {
+        let mut conn = self.conn.clone();
+        let _: i64 = conn
+            .xack(&self.cfg.stream, &self.cfg.group, &[&delivery.id])
+            .await
+            .map_err(TransportError::new)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_url_defaults_and_overrides() {
+        let cfg = RedisConfig::from_url("redis://127.0.0.1:6379/2").unwrap();
+        assert_eq!(cfg.url, "redis://127.0.0.1:6379/2");
+        assert_eq!(cfg.stream, "magicrune:req");
+        assert_eq!(cfg.group, "magicrune");
+        assert_eq!(cfg.result_stream(), "magicrune:req:res");
+
+        let cfg = RedisConfig::from_url(
+            "redis://:secret@redis.internal:6380/0?stream=jobs&group=w&consumer=c1&block_ms=50",
+        )
+        .unwrap();
+        assert_eq!(cfg.url, "redis://:secret@redis.internal:6380/0");
+        assert_eq!(cfg.stream, "jobs");
+        assert_eq!(cfg.group, "w");
+        assert_eq!(cfg.consumer, "c1");
+        assert_eq!(cfg.block_ms, 50);
+    }
+
+    #[test]
+    fn test_from_url_rejects_other_schemes() {
+        assert!(RedisConfig::from_url("nats://127.0.0.1:4222").is_err());
+    }
+
+    #[test]
+    fn test_delivery_from_fields() {
+        let mut m = std::collections::HashMap::new();
+        m.insert("payload".to_string(), Value::BulkString(b"{}".to_vec()));
+        let d = delivery_from_fields("1-0".into(), &m).unwrap();
+        assert_eq!(d.payload, b"{}");
+        assert_eq!(d.msg_id, compute_msg_id(b"{}"));
+        assert_eq!(d.queued_at_ms, Some(1));
+    }
+}
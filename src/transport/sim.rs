@@ -0,0 +1,425 @@
+//! Simulated transport for testing the consume loop against a misbehaving
+//! broker, in process and without a server.
+//!
+//! [`SimTransport`] is a queue like [`super::memory::MemoryTransport`], but
+//! one that delivers out of order, delivers twice, loses acks and fails
+//! result publishes, as far as its [`SimConfig`] says. Every choice comes
+//! from a generator seeded by [`SimConfig::seed`], and time is a tick
+//! counter advanced by each `consume`, so a run replays exactly from its
+//! seed: a failing seed is a reproducible test case.
+//!
+//! A delivery whose ack was lost stays in flight and comes back once
+//! [`SimConfig::ack_wait`] ticks have passed, as after a broker's ack
+//! timeout. `consume` returns `None` only when nothing is queued or in
+//! flight, so [`super::serve`] with a `max` runs until the queue drains.
+
+use super::{Delivery, Transport, TransportError};
+use crate::jet::compute_msg_id;
+use async_trait::async_trait;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimConfig {
+    pub seed: u64,
+    /// Chance that a delivery is also queued again, as a duplicate.
+    pub duplicate: f64,
+    /// How far ahead of the queue head a delivery may be picked.
+    pub reorder: usize,
+    /// Chance that an ack is lost, so that the delivery comes back.
+    pub lose_ack: f64,
+    /// Chance that a result publish fails.
+    pub fail_publish: f64,
+    /// Ticks an unacked delivery stays in flight before it comes back.
+    pub ack_wait: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            duplicate: 0.0,
+            reorder: 0,
+            lose_ack: 0.0,
+            fail_publish: 0.0,
+            ack_wait: 8,
+        }
+    }
+}
+
+/// What the simulated broker did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SimStats {
+    pub delivered: u64,
+    pub duplicated: u64,
+    pub redelivered: u64,
+    pub acks_lost: u64,
+    pub publish_failures: u64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    rng: u64,
+    tick: u64,
+    next_id: u64,
+    queue: VecDeque<Delivery>,
+    /// Delivery id -> (delivery, tick it was handed out).
+    inflight: BTreeMap<String, (Delivery, u64)>,
+    results: Vec<(String, Vec<u8>)>,
+    dead: Vec<Delivery>,
+    trace: Vec<String>,
+    stats: SimStats,
+}
+
+impl State {
+    /// splitmix64
+    fn next(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ((self.next() >> 11) as f64) < p * (1u64 << 53) as f64
+    }
+
+    fn log(&mut self, event: String) {
+        self.trace.push(format!("{} {}", self.tick, event));
+    }
+
+    fn new_delivery(&mut self, msg_id: String, payload: Vec<u8>) -> Delivery {
+        self.next_id += 1;
+        Delivery {
+            id: self.next_id.to_string(),
+            msg_id,
+            payload,
+            queued_at_ms: None,
+        }
+    }
+
+    /// Requeue in-flight deliveries whose ack wait is over. With nothing
+    /// queued, skip ahead to the earliest one instead of idling.
+    fn expire(&mut self, ack_wait: u64) {
+        if self.queue.is_empty() {
+            if let Some(earliest) = self.inflight.values().map(|(_, t)| *t).min() {
+                self.tick = self.tick.max(earliest + ack_wait);
+            }
+        }
+        let now = self.tick;
+        let expired: Vec<String> = self
+            .inflight
+            .iter()
+            .filter(|(_, (_, t))| now >= t + ack_wait)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            let (d, _) = self.inflight.remove(&id).unwrap();
+            self.stats.redelivered += 1;
+            self.log(format!("redeliver {}", d.id));
+            self.queue.push_back(d);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SimTransport {
+    config: SimConfig,
+    state: Mutex<State>,
+}
+
+impl SimTransport {
+    pub fn new(config: SimConfig) -> Self {
+        let state = State {
+            rng: config.seed,
+            ..Default::default()
+        };
+        Self {
+            config,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Results published so far, as `(run_id, result json)`.
+    pub fn results(&self) -> Vec<(String, Vec<u8>)> {
+        self.state.lock().unwrap().results.clone()
+    }
+
+    /// Run ids with more than one published result.
+    pub fn duplicate_results(&self) -> Vec<String> {
+        let s = self.state.lock().unwrap();
+        let mut counts = BTreeMap::new();
+        for (run_id, _) in &s.results {
+            *counts.entry(run_id.as_str()).or_insert(0) += 1;
+        }
+        counts
+            .into_iter()
+            .filter(|(_, n)| *n > 1)
+            .map(|(run_id, _)| run_id.to_string())
+            .collect()
+    }
+
+    /// Deliveries queued or handed out but not acked yet.
+    pub fn pending(&self) -> usize {
+        let s = self.state.lock().unwrap();
+        s.queue.len() + s.inflight.len()
+    }
+
+    /// Deliveries rejected without requeue.
+    pub fn dead_letters(&self) -> Vec<Delivery> {
+        self.state.lock().unwrap().dead.clone()
+    }
+
+    pub fn stats(&self) -> SimStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Everything the broker did, one line per event; equal for equal seeds.
+    pub fn trace(&self) -> Vec<String> {
+        self.state.lock().unwrap().trace.clone()
+    }
+}
+
+#[async_trait]
+impl Transport for SimTransport {
+    async fn publish_request(&self, payload: &[u8]) -> Result<(), TransportError> {
+        let mut s = self.state.lock().unwrap();
+        let d = s.new_delivery(compute_msg_id(payload), payload.to_vec());
+        s.queue.push_back(d);
+        Ok(())
+    }
+
+    async fn consume(&self) -> Result<Option<Delivery>, TransportError> {
+        let mut s = self.state.lock().unwrap();
+        s.tick += 1;
+        s.expire(self.config.ack_wait);
+        if s.queue.is_empty() {
+            return Ok(None);
+        }
+        let window = (self.config.reorder + 1).min(s.queue.len()) as u64;
+        let i = (s.next() % window) as usize;
+        let d = s.queue.remove(i).unwrap();
+        if s.chance(self.config.duplicate) {
+            let dup = s.new_delivery(d.msg_id.clone(), d.payload.clone());
+            let at = (s.next() % (s.queue.len() as u64 + 1)) as usize;
+            s.stats.duplicated += 1;
+            s.log(format!("duplicate {} as {}", d.id, dup.id));
+            s.queue.insert(at, dup);
+        }
+        s.stats.delivered += 1;
+        s.log(format!("deliver {}", d.id));
+        let tick = s.tick;
+        s.inflight.insert(d.id.clone(), (d.clone(), tick));
+        Ok(Some(d))
+    }
+
+    async fn publish_result(&self, run_id: &str, result: &[u8]) -> Result<(), TransportError> {
+        let mut s = self.state.lock().unwrap();
+        if s.chance(self.config.fail_publish) {
+            s.stats.publish_failures += 1;
+            s.log(format!("publish {} failed", run_id));
+            return Err(TransportError("sim: publish failed".into()));
+        }
+        s.log(format!("publish {}", run_id));
+        s.results.push((run_id.to_string(), result.to_vec()));
+        Ok(())
+    }
+
+    async fn ack(&self, delivery: &Delivery) -> Result<(), TransportError> {
+        let mut s = self.state.lock().unwrap();
+        if s.chance(self.config.lose_ack) {
+            s.stats.acks_lost += 1;
+            s.log(format!("ack {} lost", delivery.id));
+            return Ok(());
+        }
+        s.log(format!("ack {}", delivery.id));
+        s.inflight.remove(&delivery.id);
+        Ok(())
+    }
+
+    async fn nack(&self, delivery: &Delivery, requeue: bool) -> Result<(), TransportError> {
+        let mut s = self.state.lock().unwrap();
+        if let Some((d, _)) = s.inflight.remove(&delivery.id) {
+            s.log(format!(
+                "nack {}{}",
+                d.id,
+                if requeue { " requeue" } else { "" }
+            ));
+            if requeue {
+                s.queue.push_back(d);
+            } else {
+                s.dead.push(d);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{consumer_run_id, serve};
+    use super::*;
+    use crate::concurrency::Concurrency;
+    use crate::engine::ExecOptions;
+    use crate::policy::Policy;
+
+    const REQUESTS: u64 = 8;
+
+    async fn load(t: &SimTransport) {
+        for i in 0..REQUESTS {
+            let req = format!(r#"{{"cmd":"","seed":{}}}"#, i);
+            t.publish_request(req.as_bytes()).await.unwrap();
+        }
+    }
+
+    fn run_ids() -> Vec<String> {
+        (0..REQUESTS)
+            .map(|i| consumer_run_id(format!(r#"{{"cmd":"","seed":{}}}"#, i).as_bytes()))
+            .collect()
+    }
+
+    fn published(t: &SimTransport) -> Vec<String> {
+        let mut ids: Vec<String> = t.results().into_iter().map(|(id, _)| id).collect();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    #[tokio::test]
+    async fn test_exactly_once_under_duplicates_reordering_and_lost_acks() {
+        let mut want = run_ids();
+        want.sort();
+        for seed in 0..16 {
+            let t = SimTransport::new(SimConfig {
+                seed,
+                duplicate: 0.3,
+                reorder: 4,
+                lose_ack: 0.2,
+                ..Default::default()
+            });
+            load(&t).await;
+            let stats = serve(
+                &t,
+                &Policy::default(),
+                &ExecOptions::default(),
+                64,
+                Some(1000),
+                Concurrency::default(),
+            )
+            .await
+            .unwrap();
+            let sim = t.stats();
+            assert_eq!(t.duplicate_results(), Vec::<String>::new(), "seed {}", seed);
+            assert_eq!(published(&t), want, "seed {}", seed);
+            assert_eq!(t.pending(), 0, "seed {}", seed);
+            assert_eq!(stats.total, sim.delivered, "seed {}", seed);
+            assert_eq!(stats.dupe, sim.delivered - REQUESTS, "seed {}", seed);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dedupe_window_too_small_lets_duplicates_through() {
+        let mut leaked = false;
+        for seed in 0..16 {
+            let t = SimTransport::new(SimConfig {
+                seed,
+                duplicate: 0.5,
+                reorder: 4,
+                ..Default::default()
+            });
+            load(&t).await;
+            serve(
+                &t,
+                &Policy::default(),
+                &ExecOptions::default(),
+                1,
+                Some(1000),
+                Concurrency::default(),
+            )
+            .await
+            .unwrap();
+            leaked |= !t.duplicate_results().is_empty();
+        }
+        assert!(leaked);
+    }
+
+    #[tokio::test]
+    async fn test_failed_publish_is_retried_after_restart() {
+        let mut want = run_ids();
+        want.sort();
+        let mut failures = 0;
+        for seed in 0..16 {
+            let t = SimTransport::new(SimConfig {
+                seed,
+                reorder: 2,
+                fail_publish: 0.25,
+                ..Default::default()
+            });
+            load(&t).await;
+            // A consumer that fails exits; its replacement starts afresh.
+            let mut restarts = 0;
+            while serve(
+                &t,
+                &Policy::default(),
+                &ExecOptions::default(),
+                64,
+                Some(1000),
+                Concurrency::default(),
+            )
+            .await
+            .is_err()
+            {
+                restarts += 1;
+                assert!(restarts < 100, "seed {}", seed);
+            }
+            failures += t.stats().publish_failures;
+            assert_eq!(t.duplicate_results(), Vec::<String>::new(), "seed {}", seed);
+            assert_eq!(published(&t), want, "seed {}", seed);
+            assert_eq!(t.pending(), 0, "seed {}", seed);
+        }
+        assert!(failures > 0);
+    }
+
+    #[tokio::test]
+    async fn test_same_seed_same_trace() {
+        let config = SimConfig {
+            seed: 42,
+            duplicate: 0.3,
+            reorder: 3,
+            lose_ack: 0.3,
+            ..Default::default()
+        };
+        let mut traces = Vec::new();
+        for _ in 0..2 {
+            let t = SimTransport::new(config.clone());
+            load(&t).await;
+            serve(
+                &t,
+                &Policy::default(),
+                &ExecOptions::default(),
+                64,
+                Some(1000),
+                Concurrency::default(),
+            )
+            .await
+            .unwrap();
+            traces.push(t.trace());
+        }
+        assert!(!traces[0].is_empty());
+        assert_eq!(traces[0], traces[1]);
+        let other = SimTransport::new(SimConfig { seed: 7, ..config });
+        load(&other).await;
+        serve(
+            &other,
+            &Policy::default(),
+            &ExecOptions::default(),
+            64,
+            Some(1000),
+            Concurrency::default(),
+        )
+        .await
+        .unwrap();
+        assert_ne!(other.trace(), traces[0]);
+    }
+}
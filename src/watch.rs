@@ -0,0 +1,160 @@
+//! `exec --watch`: re-run a request whenever it or its policy changes.
+//!
+//! Files are polled (mtime and size) rather than subscribed to, which works
+//! the same on every platform and across editors that save by renaming.
+//! After each run a single line summarises the verdict, and on later runs
+//! what changed since the previous one.
+
+use crate::schema::SpellResult;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// How often the watched files are checked.
+pub const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+type Stamp = Option<(SystemTime, u64)>;
+
+fn stamp(path: &std::path::Path) -> Stamp {
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// A set of files and the state they were last seen in.
+pub struct Watched {
+    files: Vec<(PathBuf, Stamp)>,
+}
+
+impl Watched {
+    pub fn new(paths: impl IntoIterator<Item = PathBuf>) -> Self {
+        Self {
+            files: paths
+                .into_iter()
+                .map(|p| {
+                    let s = stamp(&p);
+                    (p, s)
+                })
+                .collect(),
+        }
+    }
+
+    /// Paths that changed (appeared, disappeared or were modified) since the
+    /// last call.
+    pub fn changed(&mut self) -> Vec<PathBuf> {
+        let mut out = Vec::new();
+        for (path, last) in &mut self.files {
+            let now = stamp(path);
+            if now != *last {
+                *last = now;
+                out.push(path.clone());
+            }
+        }
+        out
+    }
+}
+
+/// One line for `next`; with `prev`, only what changed is spelled out as
+/// `old -> new`, plus findings gained (`+`) and lost (`-`).
+pub fn verdict_diff(prev: Option<&SpellResult>, next: &SpellResult) -> String {
+    let Some(prev) = prev else {
+        let mut line = format!(
+            "{} risk {} exit {}",
+            next.verdict, next.risk_score, next.exit_code
+        );
+        for f in &next.findings {
+            line.push_str(&format!(" {}:{}", f.kind, f.detail));
+        }
+        return line;
+    };
+    let field = |name: &str, a: String, b: String| {
+        if a == b {
+            format!("{} {}", name, b)
+        } else {
+            format!("{} {} -> {}", name, a, b)
+        }
+    };
+    let mut line = if prev.verdict == next.verdict {
+        next.verdict.clone()
+    } else {
+        format!("{} -> {}", prev.verdict, next.verdict)
+    };
+    line.push(' ');
+    line.push_str(&field(
+        "risk",
+        prev.risk_score.to_string(),
+        next.risk_score.to_string(),
+    ));
+    line.push(' ');
+    line.push_str(&field(
+        "exit",
+        prev.exit_code.to_string(),
+        next.exit_code.to_string(),
+    ));
+    let key = |f: &crate::schema::Finding| format!("{}:{}", f.kind, f.detail);
+    let before: Vec<String> = prev.findings.iter().map(key).collect();
+    let after: Vec<String> = next.findings.iter().map(key).collect();
+    for f in after.iter().filter(|f| !before.contains(f)) {
+        line.push_str(&format!(" +{}", f));
+    }
+    for f in before.iter().filter(|f| !after.contains(f)) {
+        line.push_str(&format!(" -{}", f));
+    }
+    let same = prev.verdict == next.verdict
+        && prev.risk_score == next.risk_score
+        && prev.exit_code == next.exit_code
+        && before == after;
+    if same {
+        line.push_str(" (unchanged)");
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Finding;
+
+    #[test]
+    fn test_verdict_diff() {
+        let green = SpellResult {
+            verdict: "green".into(),
+            ..Default::default()
+        };
+        assert_eq!(verdict_diff(None, &green), "green risk 0 exit 0");
+        assert_eq!(
+            verdict_diff(Some(&green), &green),
+            "green risk 0 exit 0 (unchanged)"
+        );
+        let yellow = SpellResult {
+            verdict: "yellow".into(),
+            risk_score: 30,
+            exit_code: 1,
+            findings: vec![Finding {
+                kind: "seccomp_denied".into(),
+                detail: "ptrace".into(),
+                count: 1,
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            verdict_diff(Some(&green), &yellow),
+            "green -> yellow risk 0 -> 30 exit 0 -> 1 +seccomp_denied:ptrace"
+        );
+        assert_eq!(
+            verdict_diff(Some(&yellow), &green),
+            "yellow -> green risk 30 -> 0 exit 1 -> 0 -seccomp_denied:ptrace"
+        );
+    }
+
+    #[test]
+    fn test_watched_reports_changes() {
+        let path = std::env::temp_dir().join(format!("mr_watch_{}.json", std::process::id()));
+        std::fs::write(&path, "{}").unwrap();
+        let mut w = Watched::new([path.clone()]);
+        assert!(w.changed().is_empty());
+        std::fs::write(&path, r#"{"cmd":"true"}"#).unwrap();
+        assert_eq!(w.changed(), std::slice::from_ref(&path));
+        assert!(w.changed().is_empty());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(w.changed(), [path]);
+    }
+}
@@ -0,0 +1,209 @@
+//! HTTP webhook delivery of results.
+//!
+//! A request with `callback_url` gets its `SpellResult` POSTed there as JSON,
+//! alongside the broker publication or, with `only`, instead of it. The body
+//! is signed with HMAC-SHA256 over the exact bytes sent:
+//!
+//! ```text
+//! X-Magicrune-Signature: sha256=<hex>
+//! X-Magicrune-Run-Id: <run_id>
+//! ```
+//!
+//! Connection errors, 429 and 5xx responses are retried with exponential
+//! backoff; any other status is final.
+
+use crate::schema::SpellResult;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::Duration;
+use thiserror::Error;
+
+pub const SIGNATURE_HEADER: &str = "X-Magicrune-Signature";
+pub const RUN_ID_HEADER: &str = "X-Magicrune-Run-Id";
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("webhook: {0}")]
+pub struct WebhookError(pub String);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookConfig {
+    /// HMAC key; unsigned deliveries omit the signature header.
+    pub secret: Option<String>,
+    /// Attempts after the first one.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each further one.
+    pub backoff_ms: u64,
+    /// Per-attempt timeout.
+    pub timeout_ms: u64,
+    /// Skip broker publication once the callback accepted the result.
+    pub only: bool,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            retries: 3,
+            backoff_ms: 500,
+            timeout_ms: 10_000,
+            only: false,
+        }
+    }
+}
+
+impl WebhookConfig {
+    /// `MAGICRUNE_WEBHOOK_SECRET`, `MAGICRUNE_WEBHOOK_RETRIES`,
+    /// `MAGICRUNE_WEBHOOK_BACKOFF_MS`, `MAGICRUNE_WEBHOOK_TIMEOUT_MS`,
+    /// `MAGICRUNE_WEBHOOK_ONLY=1`.
+    pub fn from_env() -> Self {
+        let d = Self::default();
+        let var = |k: &str| crate::ports::env::var(k).ok().filter(|v| !v.is_empty());
+        Self {
+            secret: var("MAGICRUNE_WEBHOOK_SECRET"),
+            retries: var("MAGICRUNE_WEBHOOK_RETRIES")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(d.retries),
+            backoff_ms: var("MAGICRUNE_WEBHOOK_BACKOFF_MS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(d.backoff_ms),
+            timeout_ms: var("MAGICRUNE_WEBHOOK_TIMEOUT_MS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(d.timeout_ms),
+            only: var("MAGICRUNE_WEBHOOK_ONLY").as_deref() == Some("1"),
+        }
+    }
+}
+
+/// Signature header value for `body`: `sha256=` + hex HMAC-SHA256.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(body);
+    let tag = mac.finalize().into_bytes();
+    let hex: String = tag.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256={}", hex)
+}
+
+/// Callback URLs must be absolute http(s) URLs.
+pub fn check_url(raw: &str) -> Result<url::Url, WebhookError> {
+    let url = url::Url::parse(raw).map_err(|e| WebhookError(format!("{}: {}", raw, e)))?;
+    match url.scheme() {
+        "http" | "https" if url.host().is_some() => Ok(url),
+        _ => Err(WebhookError(format!("not an http(s) url: {}", raw))),
+    }
+}
+
+fn retryable(status: u16) -> bool {
+    status == 429 || status >= 500
+}
+
+/// POST `result` to `url`, retrying per `cfg`.
+pub async fn deliver(
+    cfg: &WebhookConfig,
+    url: &str,
+    result: &SpellResult,
+) -> Result<(), WebhookError> {
+    let url = check_url(url)?;
+    let body = serde_json::to_vec(result).map_err(|e| WebhookError(e.to_string()))?;
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_millis(cfg.timeout_ms))
+        .build()
+        .map_err(|e| WebhookError(e.to_string()))?;
+    let mut backoff = Duration::from_millis(cfg.backoff_ms);
+    let mut attempt = 0;
+    loop {
+        let mut req = client
+            .post(url.clone())
+            .header("Content-Type", "application/json")
+            .header(RUN_ID_HEADER, &result.run_id)
+            .body(body.clone());
+        if let Some(secret) = &cfg.secret {
+            req = req.header(SIGNATURE_HEADER, sign(secret, &body));
+        }
+        let err = match req.send().await {
+            Ok(resp) if resp.status().is_success() => return Ok(()),
+            Ok(resp) if !retryable(resp.status().as_u16()) => {
+                return Err(WebhookError(format!("{} answered {}", url, resp.status())));
+            }
+            Ok(resp) => format!("{} answered {}", url, resp.status()),
+            Err(e) => e.to_string(),
+        };
+        if attempt >= cfg.retries {
+            return Err(WebhookError(format!(
+                "giving up after {} attempts: {}",
+                attempt + 1,
+                err
+            )));
+        }
+        attempt += 1;
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+}
+
+/// Deliver `result` to the request's callback, if it has one. Returns whether
+/// the result should still be published to the broker: always, unless `only`
+/// is set and the callback accepted it.
+pub async fn dispatch(
+    cfg: &WebhookConfig,
+    callback_url: Option<&str>,
+    result: &SpellResult,
+) -> bool {
+    let Some(url) = callback_url.filter(|u| !u.is_empty()) else {
+        return true;
+    };
+    match deliver(cfg, url, result).await {
+        Ok(()) => !cfg.only,
+        Err(e) => {
+            tracing::warn!(run_id = %result.run_id, error = %e, "callback failed");
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_matches_rfc4231() {
+        // RFC 4231 test case 2.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_check_url() {
+        assert!(check_url("https://hooks.example.com/magicrune").is_ok());
+        assert!(check_url("http://127.0.0.1:8080/cb").is_ok());
+        assert!(check_url("ftp://example.com/").is_err());
+        assert!(check_url("/relative").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_without_callback_keeps_broker() {
+        let cfg = WebhookConfig {
+            only: true,
+            ..Default::default()
+        };
+        assert!(dispatch(&cfg, None, &SpellResult::default()).await);
+        assert!(dispatch(&cfg, Some(""), &SpellResult::default()).await);
+    }
+
+    #[tokio::test]
+    async fn test_failed_callback_falls_back_to_broker() {
+        let cfg = WebhookConfig {
+            retries: 1,
+            backoff_ms: 1,
+            timeout_ms: 500,
+            only: true,
+            ..Default::default()
+        };
+        // Nothing listens on port 9 (discard) on the loopback in CI.
+        let url = "http://127.0.0.1:9/cb";
+        assert!(deliver(&cfg, url, &SpellResult::default()).await.is_err());
+        assert!(dispatch(&cfg, Some(url), &SpellResult::default()).await);
+    }
+}
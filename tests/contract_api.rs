@@ -41,6 +41,7 @@ fn test_sandbox_api_contract() {
         cpu_ms: 5000,
         memory_mb: 128,
         pids: 100,
+        ..Default::default()
     };
 
     // Verify all fields are accessible
@@ -57,6 +58,7 @@ async fn test_sandbox_exec_api_contract() {
         cpu_ms: 100,
         memory_mb: 16,
         pids: 10,
+        ..Default::default()
     };
 
     // Test exec_native contract
@@ -70,22 +72,26 @@ async fn test_sandbox_exec_api_contract() {
     assert_eq!(wasm_outcome.exit_code, 0);
 }
 
-#[test]
-fn test_ledger_api_contract() {
+#[tokio::test]
+async fn test_ledger_api_contract() {
     let ledger = InMemoryLedger::new();
 
     let record = RunRecord {
         run_id: "test-123".to_string(),
+        tenant: "default".to_string(),
+        instance_id: String::new(),
         verdict: "safe".to_string(),
         risk_score: 25,
         exit_code: 0,
+        cmd: String::new(),
+        ..Default::default()
     };
 
     // Test put contract
-    ledger.put(record.clone());
+    ledger.put(record.clone()).await;
 
     // Test get contract
-    let retrieved: Option<RunRecord> = ledger.get("test-123");
+    let retrieved: Option<RunRecord> = ledger.get("test-123").await;
     assert!(retrieved.is_some());
 
     let retrieved = retrieved.unwrap();
@@ -95,7 +101,7 @@ fn test_ledger_api_contract() {
     assert_eq!(retrieved.exit_code, 0);
 
     // Test get with non-existent ID
-    let not_found: Option<RunRecord> = ledger.get("nonexistent");
+    let not_found: Option<RunRecord> = ledger.get("nonexistent").await;
     assert!(not_found.is_none());
 }
 
@@ -152,6 +158,7 @@ fn test_schema_api_contract() {
         duration_ms: 100,
         stdout_trunc: false,
         sbom_attestation: "".to_string(),
+        ..Default::default()
     };
 
     let result_json = serde_json::to_string(&result).unwrap();
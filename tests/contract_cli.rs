@@ -130,3 +130,49 @@ fn test_cli_stdin_input() {
     // Should handle stdin input
     assert!(output.status.code().is_some());
 }
+
+#[test]
+fn test_cli_schema_check() {
+    let output = Command::new("cargo")
+        .args(["run", "--", "schema", "check", "--dir", "schemas"])
+        .output()
+        .expect("Failed to execute command");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(0), "{}", stdout);
+    assert!(stdout.contains("ok spell_request.schema.json"));
+    assert!(stdout.contains("ok spell_result.schema.json"));
+}
+
+#[test]
+fn test_cli_errors_json() {
+    let dir = std::env::temp_dir().join(format!("mr_errors_json_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let request = dir.join("net.json");
+    fs::write(&request, r#"{"cmd":"curl https://x.test/"}"#).unwrap();
+    let output = Command::new("cargo")
+        .args(["run", "--", "exec", "-f"])
+        .arg(&request)
+        .args(["--dry-run", "--errors", "json"])
+        .env_remove("MAGICRUNE_LANG")
+        .output()
+        .expect("Failed to execute command");
+    let _ = fs::remove_dir_all(&dir);
+
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // Logs share stderr; the report is the line that starts with its code.
+    let line = stderr
+        .lines()
+        .find(|l| l.starts_with(r#"{"code""#))
+        .expect("a JSON error report on stderr");
+    let report: serde_json::Value = serde_json::from_str(line).unwrap();
+    assert_eq!(report["code"], "MR2004");
+    assert_eq!(report["exit_code"], 3);
+    assert_eq!(report["pointer"], "/cmd");
+    assert_eq!(report["rule"], "capabilities.net.allow");
+    assert!(report["remediation"]
+        .as_str()
+        .unwrap()
+        .contains("allow_net"));
+}
@@ -23,7 +23,7 @@ fn jetstream_dedup_and_retry() {
         .args([
             "run",
             "--features",
-            "jet",
+            "jet,testing",
             "--bin",
             "magicrune",
             "--",
@@ -53,7 +53,7 @@ fn jetstream_dedup_and_retry() {
         .expect("run js_publish #1");
     assert!(st1.success(), "first publish should succeed");
 
-    // 2) Publish duplicate (same payload -> same Nats-Msg-Id) -> expect timeout / non-zero exit
+    // 2) Publish duplicate (same payload -> same Nats-Msg-Id) -> expect non-zero exit
     let st2 = Command::new("cargo")
         .args([
             "run",
@@ -122,17 +122,23 @@ fn jetstream_redelivery_with_skip_ack_once() {
         return;
     }
     let metrics = "target/tmp/metrics.json";
+    // The ack wait has to outlast the policy's wall_sec.
+    let policy = "target/tmp/short_wall.policy.yml";
+    std::fs::create_dir_all("target/tmp").unwrap();
+    let text = std::fs::read_to_string("policies/default.policy.yml").unwrap();
+    std::fs::write(policy, text.replace("wall_sec: 15", "wall_sec: 1")).unwrap();
     let mut consumer = Command::new("cargo")
         .args([
             "run",
             "--features",
-            "jet",
+            "jet,testing",
             "--bin",
             "magicrune",
             "--",
             "consume",
         ])
         .env("NATS_ACK_WAIT_SEC", "2")
+        .env("MAGICRUNE_POLICY", policy)
         .env("MAGICRUNE_TEST_SKIP_ACK_ONCE", "1")
         .env("MAGICRUNE_METRICS_FILE", metrics)
         .stdout(Stdio::null())
@@ -174,7 +180,7 @@ fn error_net_violation_dedup() {
         .args([
             "run",
             "--features",
-            "jet",
+            "jet,testing",
             "--bin",
             "magicrune",
             "--",
@@ -200,7 +206,7 @@ fn error_net_violation_dedup() {
         .status()
         .expect("pub1");
     assert!(st1.success());
-    // Duplicate should time out due to dedupe
+    // Duplicate is dropped by the stream dedupe
     let st2 = Command::new("cargo")
         .args([
             "run",
@@ -215,7 +221,7 @@ fn error_net_violation_dedup() {
         .stdout(Stdio::piped())
         .status()
         .expect("pub2");
-    // Duplicate should time out due to dedupe; accept any non-zero (1/3/20 etc.)
+    // Duplicate is dropped by the stream dedupe; accept any non-zero (1/3/20 etc.)
     assert!(
         !st2.success(),
         "Expected non-zero exit for duplicate payload, got {:?}",
@@ -235,7 +241,7 @@ fn error_fs_violation_dedup() {
         .args([
             "run",
             "--features",
-            "jet",
+            "jet,testing",
             "--bin",
             "magicrune",
             "--",
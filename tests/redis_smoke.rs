@@ -0,0 +1,45 @@
+#[cfg(feature = "redis")]
+mod redis_tests {
+    use magicrune::concurrency::Concurrency;
+    use magicrune::engine::ExecOptions;
+    use magicrune::policy::Policy;
+    use magicrune::transport::redis::{RedisConfig, RedisTransport};
+    use magicrune::transport::{serve, Transport};
+
+    #[tokio::test]
+    async fn redis_stream_round_trip() {
+        // Only enforce when explicitly requested; otherwise skip without Redis.
+        let require = std::env::var("MAGICRUNE_REQUIRE_REDIS").ok() == Some("1".to_string());
+        let base =
+            std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+        let stream = format!("magicrune:test:{}", std::process::id());
+        let cfg = RedisConfig::from_url(&format!("{}?stream={}&block_ms=200", base, stream))
+            .expect("url");
+        let t = match RedisTransport::connect(cfg).await {
+            Ok(t) => t,
+            Err(e) if !require => {
+                eprintln!(
+                    "Redis not reachable at {} ({}); skipping smoke test",
+                    base, e
+                );
+                return;
+            }
+            Err(e) => panic!("failed to connect to {}: {}", base, e),
+        };
+        let req = br#"{"cmd":"","seed":1}"#;
+        t.publish_request(req).await.unwrap();
+        t.publish_request(req).await.unwrap();
+        let stats = serve(
+            &t,
+            &Policy::default(),
+            &ExecOptions::default(),
+            16,
+            Some(2),
+            Concurrency::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(stats.total, 2);
+        assert_eq!(stats.dupe, 1);
+    }
+}